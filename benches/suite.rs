@@ -0,0 +1,165 @@
+//! Benchmarks of the main Tree operations over the reproducible datasets in
+//! `vart::testing::datasets`.
+//!
+//! Every operation runs over every dataset at 100k and 1M keys. Set
+//! `VART_BENCH_LARGE=1` to add 10M keys, which takes several GB of memory.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use vart::art::Tree;
+use vart::iter::ScanBuffer;
+use vart::testing::datasets::{Dataset, SplitMix64};
+use vart::{Key, VariableSizeKey};
+
+const SEED: u64 = 0x005E_ED0F_7A27;
+// Number of operations timed per iteration of the lookup benchmarks.
+const BATCH: usize = 1000;
+// Number of keys read by each range scan.
+const RANGE_LEN: usize = 100;
+
+fn sizes() -> Vec<usize> {
+    let mut sizes = vec![100_000, 1_000_000];
+    if std::env::var_os("VART_BENCH_LARGE").is_some() {
+        sizes.push(10_000_000);
+    }
+    sizes
+}
+
+fn build(keys: &[VariableSizeKey]) -> Tree<VariableSizeKey, u64> {
+    let mut tree = Tree::new();
+    for (i, key) in keys.iter().enumerate() {
+        tree.insert(key, i as u64, 0, 0).unwrap();
+    }
+    tree
+}
+
+// Picks `n` of the keys at random, with a fixed seed.
+fn sample(keys: &[VariableSizeKey], n: usize) -> Vec<VariableSizeKey> {
+    let mut rng = SplitMix64::new(SEED);
+    (0..n)
+        .map(|_| keys[rng.below(keys.len() as u64) as usize].clone())
+        .collect()
+}
+
+// Returns prefixes that select a small part of each dataset: the shared prefix of
+// the dataset followed by the next two bytes of sampled keys.
+fn prefixes(dataset: Dataset, keys: &[VariableSizeKey]) -> Vec<Vec<u8>> {
+    let len = dataset.common_prefix().len() + 2;
+    sample(keys, BATCH)
+        .iter()
+        .map(|key| key.as_slice()[..len.min(key.len())].to_vec())
+        .collect()
+}
+
+fn label(dataset: Dataset, size: usize) -> String {
+    format!("{}/{}", dataset.name(), size)
+}
+
+pub fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("suite_insert");
+    group.sample_size(10);
+    for size in sizes() {
+        for dataset in Dataset::ALL {
+            let keys = dataset.keys(size, SEED);
+            group.throughput(Throughput::Elements(size as u64));
+            group.bench_function(BenchmarkId::from_parameter(label(dataset, size)), |b| {
+                b.iter(|| build(&keys))
+            });
+        }
+    }
+    group.finish();
+}
+
+pub fn reads(c: &mut Criterion) {
+    for size in sizes() {
+        for dataset in Dataset::ALL {
+            let keys = dataset.keys(size, SEED);
+            let mut tree = build(&keys);
+            tree.set_max_active_snapshots(u64::MAX);
+            let hits = sample(&keys, BATCH);
+            let misses = dataset.missing_keys(BATCH, SEED);
+            let prefixes = prefixes(dataset, &keys);
+            let mut sorted = keys.clone();
+            sorted.sort();
+            let mut rng = SplitMix64::new(SEED);
+            let ranges: Vec<_> = (0..BATCH)
+                .map(|_| {
+                    let start = rng.below((size - RANGE_LEN) as u64) as usize;
+                    (sorted[start].clone(), sorted[start + RANGE_LEN].clone())
+                })
+                .collect();
+            drop(sorted);
+            let id = BenchmarkId::from_parameter(label(dataset, size));
+
+            let mut group = c.benchmark_group("suite_get_hit");
+            group.throughput(Throughput::Elements(BATCH as u64));
+            group.bench_function(id.clone(), |b| {
+                b.iter(|| {
+                    for key in &hits {
+                        black_box(tree.get(key, 0).unwrap());
+                    }
+                })
+            });
+            group.finish();
+
+            let mut group = c.benchmark_group("suite_get_miss");
+            group.throughput(Throughput::Elements(BATCH as u64));
+            group.bench_function(id.clone(), |b| {
+                b.iter(|| {
+                    for key in &misses {
+                        black_box(tree.get(key, 0).is_err());
+                    }
+                })
+            });
+            group.finish();
+
+            let mut group = c.benchmark_group("suite_range_scan");
+            group.throughput(Throughput::Elements((BATCH * RANGE_LEN) as u64));
+            group.bench_function(id.clone(), |b| {
+                b.iter(|| {
+                    for (start, end) in &ranges {
+                        black_box(tree.range(start..end).count());
+                    }
+                })
+            });
+            group.finish();
+
+            let mut group = c.benchmark_group("suite_prefix_scan");
+            group.throughput(Throughput::Elements(BATCH as u64));
+            let mut buffer = ScanBuffer::new();
+            group.bench_function(id.clone(), |b| {
+                b.iter(|| {
+                    for prefix in &prefixes {
+                        let mut scan = tree.scan_prefix_with(prefix, &mut buffer);
+                        let mut count = 0;
+                        while scan.next_entry().is_some() {
+                            count += 1;
+                        }
+                        black_box(count);
+                    }
+                })
+            });
+            group.finish();
+
+            let mut group = c.benchmark_group("suite_snapshot_read");
+            group.throughput(Throughput::Elements(BATCH as u64));
+            group.bench_function(id.clone(), |b| {
+                b.iter(|| {
+                    let snapshot = tree.create_snapshot().unwrap();
+                    for key in &hits {
+                        black_box(snapshot.get(key).unwrap());
+                    }
+                })
+            });
+            group.finish();
+
+            let mut group = c.benchmark_group("suite_iter");
+            group.sample_size(10);
+            group.throughput(Throughput::Elements(size as u64));
+            group.bench_function(id, |b| b.iter(|| black_box(tree.iter().count())));
+            group.finish();
+        }
+    }
+}
+
+criterion_group!(suite, insert, reads);
+criterion_main!(suite);
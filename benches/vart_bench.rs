@@ -1,3 +1,5 @@
+use std::collections::hash_map::RandomState;
+use std::str::FromStr;
 use std::time::Instant;
 
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
@@ -6,7 +8,7 @@ use rand::SeedableRng;
 use rand::{rngs::StdRng, Rng};
 
 use vart::art::Tree;
-use vart::FixedSizeKey;
+use vart::{FixedKey, FixedSizeKey, VariableSizeKey};
 
 fn seeded_rng(alter: u64) -> impl Rng {
     StdRng::seed_from_u64(0xEA3C47920F94A980 ^ alter)
@@ -45,6 +47,55 @@ pub fn rand_insert(c: &mut Criterion) {
     group.finish();
 }
 
+pub fn single_byte(c: &mut Criterion) {
+    let mut group = c.benchmark_group("single_byte");
+    group.throughput(Throughput::Elements(256));
+
+    // Single-byte keys take the root slot fast path, while two-byte keys with the
+    // same last byte go through the general descent.
+    group.bench_function("insert_u8", |b| {
+        b.iter(|| {
+            let mut tree = Tree::<FixedSizeKey<16>, _>::new();
+            for key in 0..=u8::MAX {
+                let _ = tree.insert(&key.into(), key, 0, 0);
+            }
+            tree
+        })
+    });
+    group.bench_function("insert_u16", |b| {
+        b.iter(|| {
+            let mut tree = Tree::<FixedSizeKey<16>, _>::new();
+            for key in 0..=u8::MAX {
+                let _ = tree.insert(&(key as u16).into(), key, 0, 0);
+            }
+            tree
+        })
+    });
+
+    let mut tree_u8 = Tree::<FixedSizeKey<16>, _>::new();
+    let mut tree_u16 = Tree::<FixedSizeKey<16>, _>::new();
+    for key in 0..=u8::MAX {
+        tree_u8.insert(&key.into(), key, 0, 0).unwrap();
+        tree_u16.insert(&(key as u16).into(), key, 0, 0).unwrap();
+    }
+    group.bench_function("get_u8", |b| {
+        b.iter(|| {
+            for key in 0..=u8::MAX {
+                let _ = criterion::black_box(tree_u8.get(&key.into(), 0));
+            }
+        })
+    });
+    group.bench_function("get_u16", |b| {
+        b.iter(|| {
+            for key in 0..=u8::MAX {
+                let _ = criterion::black_box(tree_u16.get(&(key as u16).into(), 0));
+            }
+        })
+    });
+
+    group.finish();
+}
+
 pub fn seq_delete(c: &mut Criterion) {
     let mut group = c.benchmark_group("seq_delete");
     group.throughput(Throughput::Elements(1));
@@ -151,6 +202,161 @@ pub fn seq_get(c: &mut Criterion) {
     group.finish();
 }
 
+pub fn miss_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("miss_get");
+    let keys = gen_keys(3, 2, 3);
+
+    group.throughput(Throughput::Elements(1));
+    {
+        let mut tree = Tree::<FixedSizeKey<16>, _>::new();
+        let mut indexed = Tree::<FixedSizeKey<16>, _>::with_hash_index(RandomState::new());
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(&key.into(), i, 0, 0).unwrap();
+            indexed.insert(&key.into(), i, 0, 0).unwrap();
+        }
+
+        // Keys sharing all but the last byte with a stored key, so that the
+        // trie has to descend all the way before missing
+        let misses: Vec<String> = keys
+            .iter()
+            .map(|key| key[..key.len() - 1].to_string() + &key[key.len() - 1..].to_uppercase())
+            .collect();
+
+        group.bench_function("art", |b| {
+            let mut rng = seeded_rng(0xE080D1A42C207DAF);
+            b.iter(|| {
+                let key = &misses[rng.gen_range(0..misses.len())];
+                let _ = criterion::black_box(tree.get(&key.into(), 0));
+            })
+        });
+        group.bench_function("art_hash_index", |b| {
+            let mut rng = seeded_rng(0xE080D1A42C207DAF);
+            b.iter(|| {
+                let key = &misses[rng.gen_range(0..misses.len())];
+                let _ = criterion::black_box(indexed.get(&key.into(), 0));
+            })
+        });
+    }
+
+    group.finish();
+}
+
+pub fn fixed_key_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fixed_key_get");
+    group.throughput(Throughput::Elements(1));
+
+    // 16-byte hashes, stored as fixed keys and as variable keys of the same bytes.
+    let mut rng = seeded_rng(0x5D1C6E2B49A7F380);
+    let keys: Vec<[u8; 16]> = (0..1_000_000).map(|_| rng.gen()).collect();
+    let fixed_keys: Vec<FixedKey<16>> = keys.iter().copied().map(FixedKey::new).collect();
+    let variable_keys: Vec<VariableSizeKey> = keys
+        .iter()
+        .map(|key| VariableSizeKey::from_slice(key))
+        .collect();
+
+    let mut fixed = Tree::<FixedKey<16>, _>::new();
+    let mut variable = Tree::<VariableSizeKey, _>::new();
+    for (i, (fixed_key, variable_key)) in fixed_keys.iter().zip(&variable_keys).enumerate() {
+        fixed.insert(fixed_key, i, 0, 0).unwrap();
+        variable.insert(variable_key, i, 0, 0).unwrap();
+    }
+
+    group.bench_function("fixed_key", |b| {
+        let mut rng = seeded_rng(0xE080D1A42C207DAF);
+        b.iter(|| {
+            let key = &fixed_keys[rng.gen_range(0..fixed_keys.len())];
+            let _ = criterion::black_box(fixed.get(key, 0));
+        })
+    });
+    group.bench_function("variable_size_key", |b| {
+        let mut rng = seeded_rng(0xE080D1A42C207DAF);
+        b.iter(|| {
+            let key = &variable_keys[rng.gen_range(0..variable_keys.len())];
+            let _ = criterion::black_box(variable.get(key, 0));
+        })
+    });
+
+    group.finish();
+}
+
+pub fn iter_all(c: &mut Criterion) {
+    let mut group = c.benchmark_group("iter_all");
+
+    let size = 1_000_000u64;
+    let mut tree = Tree::<FixedSizeKey<16>, _>::new();
+    for i in 0..size {
+        tree.insert(&i.into(), i, 0, 0).unwrap();
+    }
+    group.throughput(Throughput::Elements(size));
+    group.bench_function("iter_all_count", |b| {
+        b.iter(|| criterion::black_box(tree.iter_all_count()))
+    });
+    group.bench_function("iter", |b| {
+        b.iter(|| criterion::black_box(tree.iter().count()))
+    });
+
+    group.finish();
+}
+
+pub fn prefix_match(c: &mut Criterion) {
+    let mut group = c.benchmark_group("prefix_match");
+    let (routes, inputs) = gen_zipf_urls(1_000, 10_000);
+    let inputs: Vec<&[u8]> = inputs.iter().map(|input| input.as_bytes()).collect();
+
+    let mut tree = Tree::<VariableSizeKey, _>::new();
+    for (i, route) in routes.iter().enumerate() {
+        tree.insert(&VariableSizeKey::from_str(route).unwrap(), i, 0, 0)
+            .unwrap();
+    }
+
+    group.throughput(Throughput::Elements(inputs.len() as u64));
+    group.bench_function("longest_prefix_match", |b| {
+        b.iter(|| {
+            for input in &inputs {
+                let _ = criterion::black_box(tree.longest_prefix_match(input));
+            }
+        })
+    });
+    group.bench_function("match_prefixes_batch", |b| {
+        b.iter(|| criterion::black_box(tree.match_prefixes_batch(&inputs)))
+    });
+
+    group.finish();
+}
+
+// Generates nested URL route prefixes, and inputs below them with route popularity
+// following a Zipfian distribution.
+fn gen_zipf_urls(num_routes: usize, num_inputs: usize) -> (Vec<String>, Vec<String>) {
+    let mut rng = seeded_rng(0x5A1F0C3E9B7D2468);
+    let mut routes = vec!["/".to_string()];
+    while routes.len() < num_routes {
+        let parent = routes[rng.gen_range(0..routes.len())].clone();
+        routes.push(format!("{}seg{}/", parent, rng.gen_range(0..20)));
+    }
+    routes.sort();
+    routes.dedup();
+
+    // Cumulative weights for ranks 1..=n with exponent 1
+    let mut cdf = Vec::with_capacity(routes.len());
+    let mut total = 0.0;
+    for rank in 1..=routes.len() {
+        total += 1.0 / rank as f64;
+        cdf.push(total);
+    }
+    let mut ranked = routes.clone();
+    ranked.shuffle(&mut rng);
+
+    let inputs = (0..num_inputs)
+        .map(|_| {
+            let sample = rng.gen::<f64>() * total;
+            let rank = cdf.partition_point(|&w| w < sample).min(ranked.len() - 1);
+            format!("{}item{}", ranked[rank], rng.gen_range(0..1_000))
+        })
+        .collect();
+
+    (routes, inputs)
+}
+
 fn gen_keys(l1_prefix: usize, l2_prefix: usize, suffix: usize) -> Vec<String> {
     let mut keys = Vec::new();
     let chars: Vec<char> = ('a'..='z').collect();
@@ -175,6 +381,15 @@ fn gen_keys(l1_prefix: usize, l2_prefix: usize, suffix: usize) -> Vec<String> {
 }
 
 criterion_group!(delete_benches, seq_delete, rand_delete);
-criterion_group!(insert_benches, seq_insert, rand_insert);
-criterion_group!(read_benches, seq_get, rand_get, rand_get_str);
+criterion_group!(insert_benches, seq_insert, rand_insert, single_byte);
+criterion_group!(
+    read_benches,
+    seq_get,
+    rand_get,
+    rand_get_str,
+    miss_get,
+    fixed_key_get,
+    iter_all,
+    prefix_match
+);
 criterion_main!(insert_benches, read_benches);
@@ -0,0 +1,162 @@
+//! A versioned key-value store over `vart`, driven by a REPL on stdin.
+//!
+//! Run it with `cargo run --example versioned_kv` and type `help` for the
+//! commands. The store itself lives in `store.rs`, which the integration tests
+//! exercise as well.
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+mod store;
+
+use store::{Entry, KvError, KvStore, Page};
+
+const HELP: &str = "\
+get <key>                      latest value of a key
+put <key> <value>              write a value
+putif <key> <ts> <value>       write a value if the key was last written at <ts> (0: absent)
+del <key>                      delete a key
+history <key>                  every version of a key
+begin                          start a transaction
+tget|tput|tdel <txn> ...       get, put or delete within a transaction
+commit|abort <txn>             end a transaction
+scan <start> <limit>           first page of the keys from <start>
+next <cursor> <limit>          next page of a scan
+close <cursor>                 abandon a scan
+save|load <path>               write or read the latest values to or from a file
+status                         store statistics
+quit";
+
+fn show_entry(entry: &Entry) -> String {
+    format!(
+        "{} (version {}, ts {})",
+        String::from_utf8_lossy(&entry.value),
+        entry.version,
+        entry.ts
+    )
+}
+
+fn show_page(page: &Page) -> String {
+    let mut out: Vec<String> = page
+        .entries
+        .iter()
+        .map(|(key, value)| format!("{} = {}", key, String::from_utf8_lossy(value)))
+        .collect();
+    out.push(match page.cursor {
+        Some(cursor) => format!("-- more: next {} <limit>", cursor),
+        None => "-- end".to_string(),
+    });
+    out.join("\n")
+}
+
+fn arg<T: std::str::FromStr>(args: &[&str], i: usize) -> Result<T, String> {
+    args.get(i)
+        .ok_or_else(|| format!("missing argument {}", i + 1))?
+        .parse()
+        .map_err(|_| format!("bad argument {}", i + 1))
+}
+
+fn run(store: &mut KvStore, line: &str) -> Result<String, String> {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let kv = |err: KvError| err.to_string();
+    let out = match args.as_slice() {
+        [] => String::new(),
+        ["help"] => HELP.to_string(),
+        ["get", key] => match store.get(key).map_err(kv)? {
+            Some(entry) => show_entry(&entry),
+            None => "(absent)".to_string(),
+        },
+        ["put", key, value] => format!("ts {}", store.put(key, value.as_bytes()).map_err(kv)?),
+        ["putif", key, _, value] => {
+            let ts = store
+                .put_if(key, value.as_bytes(), arg(&args, 2)?)
+                .map_err(kv)?;
+            format!("ts {}", ts)
+        }
+        ["del", key] => match store.delete(key).map_err(kv)? {
+            true => "deleted".to_string(),
+            false => "(absent)".to_string(),
+        },
+        ["history", key] => {
+            let versions = store.history(key).map_err(kv)?;
+            let lines: Vec<String> = versions.iter().map(show_entry).collect();
+            lines.join("\n")
+        }
+        ["begin"] => format!("txn {}", store.begin().map_err(kv)?),
+        ["tget", _, key] => match store.txn_get(arg(&args, 1)?, key).map_err(kv)? {
+            Some(value) => String::from_utf8_lossy(&value).into_owned(),
+            None => "(absent)".to_string(),
+        },
+        ["tput", _, key, value] => {
+            store
+                .txn_put(arg(&args, 1)?, key, value.as_bytes())
+                .map_err(kv)?;
+            "ok".to_string()
+        }
+        ["tdel", _, key] => {
+            store.txn_delete(arg(&args, 1)?, key).map_err(kv)?;
+            "ok".to_string()
+        }
+        ["commit", _] => format!(
+            "committed at ts {}",
+            store.commit(arg(&args, 1)?).map_err(kv)?
+        ),
+        ["abort", _] => {
+            store.abort(arg(&args, 1)?).map_err(kv)?;
+            "aborted".to_string()
+        }
+        ["scan", start, _] => show_page(&store.scan(start, arg(&args, 2)?).map_err(kv)?),
+        ["next", _, _] => show_page(
+            &store
+                .next_page(arg(&args, 1)?, arg(&args, 2)?)
+                .map_err(kv)?,
+        ),
+        ["close", _] => {
+            store.close_cursor(arg(&args, 1)?).map_err(kv)?;
+            "closed".to_string()
+        }
+        ["save", path] => {
+            fs::write(path, store.save()).map_err(|err| err.to_string())?;
+            "saved".to_string()
+        }
+        ["load", path] => {
+            let bytes = fs::read(path).map_err(|err| err.to_string())?;
+            *store = KvStore::load(&bytes).map_err(kv)?;
+            "loaded".to_string()
+        }
+        ["status"] => {
+            let status = store.status();
+            format!(
+                "keys {}, version {}, clock {}, max depth {}, snapshots {}, txns {}, cursors {}",
+                status.keys,
+                status.version,
+                status.clock,
+                status.max_depth,
+                status.snapshots,
+                status.open_txns,
+                status.open_cursors
+            )
+        }
+        _ => return Err("unknown command, try help".to_string()),
+    };
+    Ok(out)
+}
+
+fn main() {
+    let mut store = KvStore::new();
+    let stdin = io::stdin();
+    print!("> ");
+    io::stdout().flush().unwrap();
+    for line in stdin.lock().lines() {
+        let line = line.unwrap();
+        if line.trim() == "quit" {
+            break;
+        }
+        match run(&mut store, &line) {
+            Ok(out) if out.is_empty() => {}
+            Ok(out) => println!("{}", out),
+            Err(err) => println!("error: {}", err),
+        }
+        print!("> ");
+        io::stdout().flush().unwrap();
+    }
+}
@@ -0,0 +1,354 @@
+//! The core of the versioned KV store, shared by the REPL in `main.rs` and the
+//! integration test in `tests/versioned_kv.rs`.
+//!
+//! Every write gets a timestamp from the store's clock and a version from the
+//! Tree. Transactions buffer their writes in a snapshot and detect write-write
+//! conflicts by rebasing it on the Tree at commit. Paginated scans hold a
+//! snapshot and a reader open between pages, so a scan sees the store as of its
+//! first page.
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Bound;
+use std::str::FromStr;
+
+use vart::art::Tree;
+use vart::frozen::{FrozenTree, OpenError};
+use vart::iter::IterationPointer;
+use vart::snapshot::Snapshot;
+use vart::{TrieError, VariableSizeKey};
+
+type Key = VariableSizeKey;
+type Value = Vec<u8>;
+
+#[derive(Debug, PartialEq)]
+pub enum KvError {
+    /// The transaction does not exist, or has been committed or aborted.
+    NoSuchTxn(u64),
+    /// The cursor does not exist, or its scan has finished.
+    NoSuchCursor(u64),
+    /// The keys of a transaction that were also written since it began. The
+    /// transaction has been aborted.
+    Conflict(Vec<String>),
+    /// A saved store could not be read.
+    Load(OpenError),
+    Trie(TrieError),
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvError::NoSuchTxn(id) => write!(f, "no open transaction {}", id),
+            KvError::NoSuchCursor(id) => write!(f, "no open cursor {}", id),
+            KvError::Conflict(keys) => write!(f, "conflict on {}", keys.join(", ")),
+            KvError::Load(err) => write!(f, "cannot load: {}", err),
+            KvError::Trie(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<TrieError> for KvError {
+    fn from(err: TrieError) -> Self {
+        KvError::Trie(err)
+    }
+}
+
+/// A stored value with the version and timestamp it was written at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub value: Value,
+    pub version: u64,
+    pub ts: u64,
+}
+
+/// One page of a scan.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Page {
+    pub entries: Vec<(String, Value)>,
+    /// The cursor to fetch the next page with, or `None` if the scan is done.
+    pub cursor: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Status {
+    pub keys: usize,
+    pub version: u64,
+    pub clock: u64,
+    pub max_depth: usize,
+    pub snapshots: usize,
+    pub open_txns: usize,
+    pub open_cursors: usize,
+}
+
+struct Txn {
+    snapshot: Snapshot<Key, Value>,
+    // The buffered writes, `None` being a delete, applied to the Tree at commit.
+    writes: BTreeMap<String, Option<Value>>,
+}
+
+struct Cursor {
+    snapshot: Snapshot<Key, Value>,
+    reader: IterationPointer<Key, Value>,
+    next: Bound<Key>,
+}
+
+pub struct KvStore {
+    tree: Tree<Key, Value>,
+    clock: u64,
+    next_id: u64,
+    txns: HashMap<u64, Txn>,
+    cursors: HashMap<u64, Cursor>,
+}
+
+fn key(name: &str) -> Key {
+    Key::from_str(name).unwrap()
+}
+
+fn display_key(key: &[u8]) -> String {
+    String::from_utf8_lossy(key.strip_suffix(&[0]).unwrap_or(key)).into_owned()
+}
+
+/// Deregisters a snapshot from the Tree it was taken from.
+fn release(tree: &mut Tree<Key, Value>, mut snapshot: Snapshot<Key, Value>) -> Result<(), KvError> {
+    snapshot.close()?;
+    tree.close_snapshot(snapshot.id())?;
+    Ok(())
+}
+
+impl Default for KvStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KvStore {
+    pub fn new() -> Self {
+        Self::from_tree(Tree::new(), 0)
+    }
+
+    fn from_tree(tree: Tree<Key, Value>, clock: u64) -> Self {
+        KvStore {
+            tree,
+            clock,
+            next_id: 1,
+            txns: HashMap::new(),
+            cursors: HashMap::new(),
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn new_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<Entry>, KvError> {
+        match self.tree.get(&key(name), 0) {
+            Ok((_, value, version, ts)) => Ok(Some(Entry { value, version, ts })),
+            Err(TrieError::KeyNotFound) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes `value` under `name`, returning the timestamp of the write.
+    pub fn put(&mut self, name: &str, value: &[u8]) -> Result<u64, KvError> {
+        let ts = self.tick();
+        self.tree.insert(&key(name), value.to_vec(), 0, ts)?;
+        Ok(ts)
+    }
+
+    /// Writes `value` under `name` only if its latest write was at `expected_ts`,
+    /// 0 standing for an absent key.
+    pub fn put_if(&mut self, name: &str, value: &[u8], expected_ts: u64) -> Result<u64, KvError> {
+        // A rejected write does not take a timestamp.
+        let ts = self.clock + 1;
+        self.tree
+            .insert_expect_ts(&key(name), value.to_vec(), ts, expected_ts)?;
+        self.clock = ts;
+        Ok(ts)
+    }
+
+    pub fn delete(&mut self, name: &str) -> Result<bool, KvError> {
+        Ok(self.tree.remove(&key(name))?)
+    }
+
+    /// Returns every version of a key, oldest first.
+    pub fn history(&self, name: &str) -> Result<Vec<Entry>, KvError> {
+        match self.tree.history(&key(name)) {
+            Ok(versions) => Ok(versions
+                .into_iter()
+                .map(|(value, version, ts)| Entry { value, version, ts })
+                .collect()),
+            Err(TrieError::KeyNotFound) => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn begin(&mut self) -> Result<u64, KvError> {
+        let snapshot = self.tree.create_snapshot()?;
+        let id = self.new_id();
+        self.txns.insert(
+            id,
+            Txn {
+                snapshot,
+                writes: BTreeMap::new(),
+            },
+        );
+        Ok(id)
+    }
+
+    fn txn(&mut self, id: u64) -> Result<&mut Txn, KvError> {
+        self.txns.get_mut(&id).ok_or(KvError::NoSuchTxn(id))
+    }
+
+    /// Reads a key as of the start of a transaction, with its own writes.
+    pub fn txn_get(&mut self, id: u64, name: &str) -> Result<Option<Value>, KvError> {
+        match self.txn(id)?.snapshot.get(&key(name)) {
+            Ok((value, _, _)) => Ok(Some(value)),
+            Err(TrieError::KeyNotFound) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn txn_put(&mut self, id: u64, name: &str, value: &[u8]) -> Result<(), KvError> {
+        let txn = self.txn(id)?;
+        // The timestamp is assigned at commit.
+        txn.snapshot.insert(&key(name), value.to_vec(), 0)?;
+        txn.writes.insert(name.to_string(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    pub fn txn_delete(&mut self, id: u64, name: &str) -> Result<(), KvError> {
+        let txn = self.txn(id)?;
+        txn.snapshot.remove(&key(name))?;
+        txn.writes.insert(name.to_string(), None);
+        Ok(())
+    }
+
+    /// Applies the writes of a transaction at a single timestamp, unless one of
+    /// its keys was written since it began.
+    pub fn commit(&mut self, id: u64) -> Result<u64, KvError> {
+        let mut txn = self.txns.remove(&id).ok_or(KvError::NoSuchTxn(id))?;
+        let conflicts = txn.snapshot.rebase(&self.tree)?;
+        release(&mut self.tree, txn.snapshot)?;
+        if !conflicts.is_empty() {
+            let keys = conflicts
+                .iter()
+                .map(|k| display_key(k.to_slice()))
+                .collect();
+            return Err(KvError::Conflict(keys));
+        }
+
+        let ts = self.tick();
+        for (name, value) in txn.writes {
+            match value {
+                Some(value) => {
+                    self.tree.insert(&key(&name), value, 0, ts)?;
+                }
+                None => {
+                    self.tree.remove(&key(&name))?;
+                }
+            }
+        }
+        Ok(ts)
+    }
+
+    pub fn abort(&mut self, id: u64) -> Result<(), KvError> {
+        let txn = self.txns.remove(&id).ok_or(KvError::NoSuchTxn(id))?;
+        release(&mut self.tree, txn.snapshot)
+    }
+
+    /// Returns the first page of the keys at or after `start`, and a cursor for
+    /// the next one if there are more.
+    pub fn scan(&mut self, start: &str, limit: usize) -> Result<Page, KvError> {
+        let mut snapshot = self.tree.create_snapshot()?;
+        let reader = match snapshot.new_reader() {
+            Ok(reader) => reader,
+            Err(TrieError::SnapshotEmpty) => {
+                release(&mut self.tree, snapshot)?;
+                return Ok(Page {
+                    entries: Vec::new(),
+                    cursor: None,
+                });
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let id = self.new_id();
+        let next = Bound::Included(Key::from_slice(start.as_bytes()));
+        self.cursors.insert(
+            id,
+            Cursor {
+                snapshot,
+                reader,
+                next,
+            },
+        );
+        self.next_page(id, limit)
+    }
+
+    /// Returns the next page of a scan. The cursor is closed with the last page.
+    pub fn next_page(&mut self, id: u64, limit: usize) -> Result<Page, KvError> {
+        let cursor = self.cursors.get_mut(&id).ok_or(KvError::NoSuchCursor(id))?;
+        // One more entry than the page tells whether the scan is done.
+        let mut entries: Vec<(Vec<u8>, Value)> = cursor
+            .reader
+            .range((cursor.next.clone(), Bound::Unbounded))
+            .take(limit + 1)
+            .map(|(key, value, _, _)| (key, value.clone()))
+            .collect();
+        let more = entries.len() > limit;
+        entries.truncate(limit);
+        if let Some((last, _)) = entries.last() {
+            cursor.next = Bound::Excluded(Key::from_slice(last));
+        }
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| (display_key(&key), value))
+            .collect();
+        if more {
+            return Ok(Page {
+                entries,
+                cursor: Some(id),
+            });
+        }
+        self.close_cursor(id)?;
+        Ok(Page {
+            entries,
+            cursor: None,
+        })
+    }
+
+    pub fn close_cursor(&mut self, id: u64) -> Result<(), KvError> {
+        let mut cursor = self.cursors.remove(&id).ok_or(KvError::NoSuchCursor(id))?;
+        cursor.snapshot.close_reader(cursor.reader.id())?;
+        release(&mut self.tree, cursor.snapshot)
+    }
+
+    /// Writes the latest version of every key. History is not saved.
+    pub fn save(&self) -> Vec<u8> {
+        self.tree.freeze()
+    }
+
+    pub fn load(bytes: &[u8]) -> Result<KvStore, KvError> {
+        let frozen = FrozenTree::open(bytes).map_err(KvError::Load)?;
+        let tree = Tree::thaw(&frozen).map_err(KvError::Load)?;
+        let clock = tree.iter().map(|(_, _, _, ts)| *ts).max().unwrap_or(0);
+        Ok(Self::from_tree(tree, clock))
+    }
+
+    pub fn status(&self) -> Status {
+        let depth = self.tree.depth_stats();
+        Status {
+            keys: depth.keys,
+            version: self.tree.version(),
+            clock: self.clock,
+            max_depth: depth.max_depth,
+            snapshots: self.tree.snapshot_count(),
+            open_txns: self.txns.len(),
+            open_cursors: self.cursors.len(),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! This module defines an arena-backed Tree that stores its values outside the
+//! trie and hands out stable indices to them.
+use crate::art::Tree;
+use crate::{KeyTrait, TrieError};
+
+/// A Trie whose values live in an arena next to it.
+///
+/// Every inserted value is appended to the arena, and the trie stores its index,
+/// the value's handle. Lookups return handles instead of cloning values, so
+/// values do not have to implement `Clone` and can be managed by the caller
+/// through `value` and `value_mut`.
+///
+/// The arena only grows: a handle stays valid, and keeps referring to the same
+/// value, for as long as the `ArenaTree` lives, even after the version it belongs
+/// to is overwritten or removed from the trie.
+pub struct ArenaTree<P: KeyTrait, V> {
+    tree: Tree<P, usize>,
+    values: Vec<V>,
+}
+
+impl<P: KeyTrait, V> ArenaTree<P, V> {
+    /// Creates an empty arena-backed Trie.
+    pub fn new() -> Self {
+        ArenaTree {
+            tree: Tree::new(),
+            values: Vec::new(),
+        }
+    }
+
+    /// Inserts a value into the arena and a handle to it into the Trie.
+    ///
+    /// The version and timestamp are handled as in `Tree::insert`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the handle of the inserted value.
+    ///
+    pub fn insert(&mut self, key: &P, value: V, version: u64, ts: u64) -> Result<usize, TrieError> {
+        let handle = self.values.len();
+        self.tree.insert(key, handle, version, ts)?;
+        self.values.push(value);
+        Ok(handle)
+    }
+
+    /// Returns the handle of the value of a key at the given version, or of its
+    /// latest value for a version of 0.
+    pub fn get_handle(&self, key: &P, version: u64) -> Result<usize, TrieError> {
+        self.tree.get(key, version).map(|(_, handle, _, _)| handle)
+    }
+
+    /// Returns the value behind a handle.
+    pub fn value(&self, handle: usize) -> Option<&V> {
+        self.values.get(handle)
+    }
+
+    /// Returns a mutable reference to the value behind a handle.
+    ///
+    /// The value is changed in place for every version of a key that refers
+    /// to the handle.
+    pub fn value_mut(&mut self, handle: usize) -> Option<&mut V> {
+        self.values.get_mut(handle)
+    }
+
+    /// Removes a key from the Trie. The values of the key stay in the arena.
+    pub fn remove(&mut self, key: &P) -> Result<bool, TrieError> {
+        self.tree.remove(key)
+    }
+
+    /// Returns the Trie of handles.
+    pub fn tree(&self) -> &Tree<P, usize> {
+        &self.tree
+    }
+
+    /// Returns the number of values in the arena.
+    pub fn arena_len(&self) -> usize {
+        self.values.len()
+    }
+}
+
+impl<P: KeyTrait, V> Default for ArenaTree<P, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ArenaTree;
+    use crate::VariableSizeKey;
+    use std::str::FromStr;
+
+    #[test]
+    fn handles_stay_valid_across_inserts() {
+        // Values that cannot be cloned are fine, since lookups return handles.
+        struct Blob(Vec<u8>);
+
+        let mut tree: ArenaTree<VariableSizeKey, Blob> = ArenaTree::new();
+        let key = |i: usize| VariableSizeKey::from_str(&format!("key{}", i)).unwrap();
+
+        let first = tree.insert(&key(0), Blob(vec![0]), 0, 0).unwrap();
+        let handles: Vec<usize> = (1..100)
+            .map(|i| tree.insert(&key(i), Blob(vec![i as u8]), 0, 0).unwrap())
+            .collect();
+
+        // Inserting other keys leaves existing handles untouched.
+        assert_eq!(tree.get_handle(&key(0), 0).unwrap(), first);
+        for (i, &handle) in handles.iter().enumerate() {
+            assert_eq!(tree.get_handle(&key(i + 1), 0).unwrap(), handle);
+            assert_eq!(tree.value(handle).unwrap().0, vec![i as u8 + 1]);
+        }
+
+        // A new version gets a new handle, and the old one still reads the old value.
+        let version = tree.tree().version();
+        let updated = tree.insert(&key(0), Blob(vec![42]), 0, 0).unwrap();
+        assert_ne!(updated, first);
+        assert_eq!(tree.get_handle(&key(0), 0).unwrap(), updated);
+        assert_eq!(tree.get_handle(&key(0), version).unwrap(), first);
+        assert_eq!(tree.value(first).unwrap().0, vec![0]);
+
+        tree.value_mut(updated).unwrap().0.push(43);
+        assert_eq!(tree.value(updated).unwrap().0, vec![42, 43]);
+
+        assert!(tree.remove(&key(0)).unwrap());
+        assert!(tree.get_handle(&key(0), 0).is_err());
+        assert_eq!(tree.value(updated).unwrap().0, vec![42, 43]);
+        assert_eq!(tree.arena_len(), 101);
+    }
+}
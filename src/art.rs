@@ -1,15 +1,55 @@
 use core::panic;
-use std::cmp::min;
+use std::borrow::Cow;
+use std::cmp::{min, Ordering};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::io::Read;
 use std::ops::RangeBounds;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-
-use hashbrown::HashSet;
-
-use crate::iter::{Iter, Range};
-use crate::node::{FlatNode, Node256, Node48, NodeTrait, TwigNode, Version};
-use crate::snapshot::Snapshot;
-use crate::{KeyTrait, TrieError};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, OnceLock};
+
+use crate::checksum::check_value;
+use crate::codec::{DecodeKey, DecodedIter, EncodeKey, ValueCodec};
+use crate::cost::{self, InsertCostEstimate};
+use crate::cursor::{self, ResumeError, ScanCursor};
+use crate::diff::{diff_nodes, same_content, Change, ChangeReader};
+use crate::domain::TsDomains;
+use crate::expiry::ExpiryTable;
+use crate::frozen::{self, FrozenTree, OpenError};
+use crate::hash_index::{HashIndex, Indexed};
+use crate::hook::IndexHook;
+use crate::ingest::{IngestPolicy, TsValidator};
+use crate::iter::{
+    ChangedSince, FilteredScan, Iter, LowMemoryIter, PrefixScan, ProjectedScan, Range, RecencyIter,
+    ScanBuffer, ScanDecision, ScanOptions,
+};
+use crate::lock::{PrefixLock, PrefixLockTable};
+use crate::namespace::SharedClock;
+use crate::node::{FlatNode, LeafValue, Node256, Node48, NodeTrait, TwigNode, Version};
+use crate::normalize::{normalize_key, KeyNormalization, KeyNormalizer};
+use crate::pin::{VersionPin, VersionPinTable};
+use crate::popularity::ReadFrequency;
+use crate::prepare::PreparedChain;
+use crate::pressure::{
+    CowWindow, DuplicateTsPolicy, InsertStats, OptionsPatch, Pressure, PressureTracker,
+    ReconfigureError, TreeOptions, VersioningStrategy,
+};
+use crate::record::{OpRecord, OpSink};
+use crate::sample::SampleView;
+use crate::snapshot::{
+    count_in, CloseOutcomes, OwnedSnapshot, ReadSet, Snapshot, SnapshotRegistry, SnapshotState,
+    StalenessSummary,
+};
+use crate::stats::{DepthStats, PrefixStats, PrefixStatsTable};
+use crate::strict::{InvariantCheck, InvariantChecks, InvariantViolation, PoisonReport};
+use crate::suffix::SuffixIndex;
+use crate::tombstone::Tombstones;
+use crate::transaction::Transaction;
+use crate::view::RefreshingView;
+use crate::{check_key_len, KeyTrait, TrieError};
 
 // Minimum and maximum number of children for Node4
 const NODE4MIN: usize = 2;
@@ -29,6 +69,20 @@ const NODE256MIN: usize = NODE48MAX + 1;
 // Maximum number of active snapshots
 pub(crate) const DEFAULT_MAX_ACTIVE_SNAPSHOTS: u64 = 10000;
 
+/// A type of inner node, used to force the layout of a Trie and to report how
+/// inserts change it.
+///
+/// See `Tree::with_forced_node_type`. Types are ordered by capacity. A Node1
+/// only results from removals, so forcing it keeps the layout adaptive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum NodeKind {
+    Node1,
+    Node4,
+    Node16,
+    Node48,
+    Node256,
+}
+
 /// A struct representing a node in an Adaptive Radix Trie.
 ///
 /// The `Node` struct encapsulates a single node within the adaptive radix trie structure.
@@ -43,10 +97,14 @@ pub(crate) const DEFAULT_MAX_ACTIVE_SNAPSHOTS: u64 = 10000;
 /// # Fields
 ///
 /// - `node_type`: The `NodeType` variant representing the type of the node, containing its
-///                specific structure and associated data.
+///   specific structure and associated data.
+/// - `count`: The number of keys below the node once a Tree built with `Tree::with_counts`
+///   has counted them, or 0. Nodes are copied rather than changed on write, so a copy
+///   starts uncounted while the subtrees it shares keep their counts.
 ///
 pub struct Node<P: KeyTrait + Clone, V: Clone> {
     pub(crate) node_type: NodeType<P, V>, // Type of the node
+    pub(crate) count: AtomicUsize,        // Cached number of keys below the node
 }
 
 impl<P: KeyTrait + Clone, V: Clone> Version for Node<P, V> {
@@ -60,6 +118,17 @@ impl<P: KeyTrait + Clone, V: Clone> Version for Node<P, V> {
             NodeType::Node256(n) => n.version(),
         }
     }
+
+    fn max_ts(&self) -> u64 {
+        match &self.node_type {
+            NodeType::Twig(twig) => twig.max_ts(),
+            NodeType::Node1(n) => n.max_ts(),
+            NodeType::Node4(n) => n.max_ts(),
+            NodeType::Node16(n) => n.max_ts(),
+            NodeType::Node48(n) => n.max_ts(),
+            NodeType::Node256(n) => n.max_ts(),
+        }
+    }
 }
 
 /// An enumeration representing different types of nodes in an Adaptive Radix Trie.
@@ -92,7 +161,26 @@ pub(crate) enum NodeType<P: KeyTrait + Clone, V: Clone> {
     Node256(Node256<P, Node<P, V>>),   // Node with 256 keys and 256 children
 }
 
+/// The values of each key that `Node::prune_recurse` keeps, besides the pinned
+/// ones.
+#[derive(Clone, Copy)]
+pub(crate) enum PruneRule {
+    /// Keeps the values at or after the version, and the value visible at it.
+    OlderThan(u64),
+    /// Keeps the given number of latest values, and at least one.
+    KeepLatest(usize),
+}
+
 impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
+    /// Wraps a node type into an uncounted node.
+    #[inline]
+    pub(crate) fn from_type(node_type: NodeType<P, V>) -> Self {
+        Node {
+            node_type,
+            count: AtomicUsize::new(0),
+        }
+    }
+
     /// Creates a new Twig node with a given prefix, key, value, and version.
     ///
     /// Constructs a new Twig node using the provided prefix, key, and value. The version
@@ -118,9 +206,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         twig.insert_mut(value, version, ts);
 
         // Return a new Node instance encapsulating the constructed Twig node.
-        Self {
-            node_type: NodeType::Twig(twig),
-        }
+        Self::from_type(NodeType::Twig(twig))
     }
 
     /// Creates a new inner Node4 node with the provided prefix.
@@ -143,9 +229,28 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         let flat_node = FlatNode::new(prefix);
 
         // Create a new Node4 instance with the constructed FlatNode.
-        Self {
-            node_type: NodeType::Node4(flat_node),
-        }
+        Self::from_type(NodeType::Node4(flat_node))
+    }
+
+    /// Creates an empty inner node of the `forced` type, or a Node4 if the layout
+    /// is adaptive.
+    fn new_inner(prefix: P, forced: Option<NodeKind>) -> Self {
+        let node_type = match forced {
+            None | Some(NodeKind::Node1) | Some(NodeKind::Node4) => return Node::new_node4(prefix),
+            Some(NodeKind::Node16) => NodeType::Node16(FlatNode::new(prefix)),
+            Some(NodeKind::Node48) => NodeType::Node48(Node48::new(prefix)),
+            Some(NodeKind::Node256) => NodeType::Node256(Node256::new(prefix)),
+        };
+        Self::from_type(node_type)
+    }
+
+    /// Returns whether the node is smaller than the `forced` type, so that it
+    /// may shrink. Nodes always may if the layout is adaptive.
+    fn above_forced(&self, forced: Option<NodeKind>) -> bool {
+        let Some(forced) = forced else {
+            return true;
+        };
+        self.kind().is_none_or(|kind| kind > forced)
     }
 
     /// Checks if the current node is full based on its type.
@@ -185,13 +290,20 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     ///
     #[inline]
     fn add_child(&self, key: u8, child: Node<P, V>) -> Self {
+        // Shrinking leaves nodes full, so grow those before adding.
+        if self.is_full() {
+            let mut grown = Self::from_type(self.node_type.clone());
+            grown.grow();
+            return grown.add_child(key, child);
+        }
+
         match &self.node_type {
             NodeType::Node1(n) => {
                 // Add the child node to the Node1 instance.
                 let node = NodeType::Node1(n.add_child(key, child));
 
                 // Create a new Node instance with the updated NodeType.
-                let mut new_node = Self { node_type: node };
+                let mut new_node = Self::from_type(node);
 
                 // Check if the node has become full and needs to be grown.
                 if new_node.is_full() {
@@ -205,7 +317,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
                 let node = NodeType::Node4(n.add_child(key, child));
 
                 // Create a new Node instance with the updated NodeType.
-                let mut new_node = Self { node_type: node };
+                let mut new_node = Self::from_type(node);
 
                 // Check if the node has become full and needs to be grown.
                 if new_node.is_full() {
@@ -219,7 +331,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
                 let node = NodeType::Node16(n.add_child(key, child));
 
                 // Create a new Node instance with the updated NodeType.
-                let mut new_node = Self { node_type: node };
+                let mut new_node = Self::from_type(node);
 
                 // Check if the node has become full and needs to be grown.
                 if new_node.is_full() {
@@ -233,7 +345,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
                 let node = NodeType::Node48(n.add_child(key, child));
 
                 // Create a new Node instance with the updated NodeType.
-                let mut new_node = Self { node_type: node };
+                let mut new_node = Self::from_type(node);
 
                 // Check if the node has become full and needs to be grown.
                 if new_node.is_full() {
@@ -247,7 +359,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
                 let node = NodeType::Node256(n.add_child(key, child));
 
                 // Create a new Node instance with the updated NodeType.
-                let mut new_node = Self { node_type: node };
+                let mut new_node = Self::from_type(node);
 
                 // Check if the node has become full and needs to be grown.
                 if new_node.is_full() {
@@ -313,7 +425,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     /// Returns an `Option` containing a reference to the found child node or `None` if not found.
     ///
     #[inline]
-    fn find_child(&self, key: u8) -> Option<&Arc<Node<P, V>>> {
+    pub(crate) fn find_child(&self, key: u8) -> Option<&Arc<Node<P, V>>> {
         // If there are no children, return None.
         if self.num_children() == 0 {
             return None;
@@ -348,32 +460,57 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
             NodeType::Node1(n) => {
                 // Replace the child node in the Node4 instance and update the NodeType.
                 let node = NodeType::Node1(n.replace_child(key, node));
-                Self { node_type: node }
+                Self::from_type(node)
             }
             NodeType::Node4(n) => {
                 // Replace the child node in the Node4 instance and update the NodeType.
                 let node = NodeType::Node4(n.replace_child(key, node));
-                Self { node_type: node }
+                Self::from_type(node)
             }
             NodeType::Node16(n) => {
                 // Replace the child node in the Node16 instance and update the NodeType.
                 let node = NodeType::Node16(n.replace_child(key, node));
-                Self { node_type: node }
+                Self::from_type(node)
             }
             NodeType::Node48(n) => {
                 // Replace the child node in the Node48 instance and update the NodeType.
                 let node = NodeType::Node48(n.replace_child(key, node));
-                Self { node_type: node }
+                Self::from_type(node)
             }
             NodeType::Node256(n) => {
                 // Replace the child node in the Node256 instance and update the NodeType.
                 let node = NodeType::Node256(n.replace_child(key, node));
-                Self { node_type: node }
+                Self::from_type(node)
             }
             NodeType::Twig(_) => panic!("Unexpected Twig node encountered in replace_child()"),
         }
     }
 
+    /// Returns the child slot for the given key, to update the child in place.
+    fn child_mut(&mut self, key: u8) -> Option<&mut Arc<Node<P, V>>> {
+        match &mut self.node_type {
+            NodeType::Node1(n) => n.child_mut(key),
+            NodeType::Node4(n) => n.child_mut(key),
+            NodeType::Node16(n) => n.child_mut(key),
+            NodeType::Node48(n) => n.child_mut(key),
+            NodeType::Node256(n) => n.child_mut(key),
+            NodeType::Twig(_) => None,
+        }
+    }
+
+    /// Sets the version of an inner node to the greatest version of its children,
+    /// after a child was updated in place.
+    fn refresh_version(&mut self) {
+        match &mut self.node_type {
+            NodeType::Node1(n) => n.refresh_version(),
+            NodeType::Node4(n) => n.refresh_version(),
+            NodeType::Node16(n) => n.refresh_version(),
+            NodeType::Node48(n) => n.refresh_version(),
+            NodeType::Node256(n) => n.refresh_version(),
+            NodeType::Twig(_) => {}
+        }
+    }
+
     /// Removes a child node with the specified key from the current node.
     ///
     /// Removes a child node with the provided key from the current node.
@@ -388,21 +525,21 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     /// Returns a new `Node` instance with the child node removed.
     ///
     #[inline]
-    fn delete_child(&self, key: u8) -> Self {
+    fn delete_child(&self, key: u8, forced: Option<NodeKind>) -> Self {
         match &self.node_type {
             NodeType::Node1(n) => {
                 // Delete the child node from the Node1 instance and update the NodeType.
                 let node = NodeType::Node1(n.delete_child(key));
 
-                Self { node_type: node }
+                Self::from_type(node)
             }
             NodeType::Node4(n) => {
                 // Delete the child node from the Node4 instance and update the NodeType.
                 let node = NodeType::Node4(n.delete_child(key));
-                let mut new_node = Self { node_type: node };
+                let mut new_node = Self::from_type(node);
 
                 // Check if the number of remaining children is below the threshold.
-                if new_node.num_children() < NODE4MIN {
+                if new_node.num_children() < NODE4MIN && new_node.above_forced(forced) {
                     new_node.shrink();
                 }
 
@@ -411,10 +548,10 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
             NodeType::Node16(n) => {
                 // Delete the child node from the Node16 instance and update the NodeType.
                 let node = NodeType::Node16(n.delete_child(key));
-                let mut new_node = Self { node_type: node };
+                let mut new_node = Self::from_type(node);
 
                 // Check if the number of remaining children is below the threshold.
-                if new_node.num_children() < NODE16MIN {
+                if new_node.num_children() < NODE16MIN && new_node.above_forced(forced) {
                     new_node.shrink();
                 }
 
@@ -423,10 +560,10 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
             NodeType::Node48(n) => {
                 // Delete the child node from the Node48 instance and update the NodeType.
                 let node = NodeType::Node48(n.delete_child(key));
-                let mut new_node = Self { node_type: node };
+                let mut new_node = Self::from_type(node);
 
                 // Check if the number of remaining children is below the threshold.
-                if new_node.num_children() < NODE48MIN {
+                if new_node.num_children() < NODE48MIN && new_node.above_forced(forced) {
                     new_node.shrink();
                 }
 
@@ -435,10 +572,10 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
             NodeType::Node256(n) => {
                 // Delete the child node from the Node256 instance and update the NodeType.
                 let node = NodeType::Node256(n.delete_child(key));
-                let mut new_node = Self { node_type: node };
+                let mut new_node = Self::from_type(node);
 
                 // Check if the number of remaining children is below the threshold.
-                if new_node.num_children() < NODE256MIN {
+                if new_node.num_children() < NODE256MIN && new_node.above_forced(forced) {
                     new_node.shrink();
                 }
 
@@ -471,6 +608,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     /// Returns `true` if the node type is an inner node, otherwise returns `false`.
     ///
     #[inline]
+    #[allow(dead_code)]
     pub(crate) fn is_inner(&self) -> bool {
         !self.is_twig()
     }
@@ -594,9 +732,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         };
 
         // Get the value from the TwigNode instance by the specified version.
-        let Some(val) = twig.get_leaf_by_version(version) else {
-            return None;
-        };
+        let val = twig.get_leaf_by_version(version)?;
 
         // Return the retrieved key, value, and version as a tuple.
         // TODO: should return copy of value or reference?
@@ -604,13 +740,29 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     }
 
     pub fn node_type_name(&self) -> String {
+        self.kind_name().to_string()
+    }
+
+    /// Returns the type of an inner node, or `None` for a twig.
+    pub(crate) fn kind(&self) -> Option<NodeKind> {
+        match &self.node_type {
+            NodeType::Node1(_) => Some(NodeKind::Node1),
+            NodeType::Node4(_) => Some(NodeKind::Node4),
+            NodeType::Node16(_) => Some(NodeKind::Node16),
+            NodeType::Node48(_) => Some(NodeKind::Node48),
+            NodeType::Node256(_) => Some(NodeKind::Node256),
+            NodeType::Twig(_) => None,
+        }
+    }
+
+    fn kind_name(&self) -> &'static str {
         match &self.node_type {
-            NodeType::Node1(_) => "Node1".to_string(),
-            NodeType::Node4(_) => "Node4".to_string(),
-            NodeType::Node16(_) => "Node16".to_string(),
-            NodeType::Node48(_) => "Node48".to_string(),
-            NodeType::Node256(_) => "Node256".to_string(),
-            NodeType::Twig(_) => "twig".to_string(),
+            NodeType::Node1(_) => "Node1",
+            NodeType::Node4(_) => "Node4",
+            NodeType::Node16(_) => "Node16",
+            NodeType::Node48(_) => "Node48",
+            NodeType::Node256(_) => "Node256",
+            NodeType::Twig(_) => "twig",
         }
     }
 
@@ -624,9 +776,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     ///
     fn clone_node(&self) -> Self {
         // Create a new instance with the same node type as the current node.
-        Self {
-            node_type: self.node_type.clone(),
-        }
+        Self::from_type(self.node_type.clone())
     }
 
     /// Inserts a key-value pair recursively into the node.
@@ -640,11 +790,16 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     /// - `value`: The value associated with the key.
     /// - `commit_version`: The version when the value was inserted.
     /// - `depth`: The depth of the insertion process.
+    /// - `policy`: What to do if the key already has a version at `ts`.
+    /// - `versioning`: How many versions of the key to keep.
     ///
     /// # Returns
     ///
-    /// Returns the updated node and the old value (if any) for the given key.
+    /// Returns the updated node and the old leaf (if any) for the given key. The
+    /// leaf is shared rather than its value cloned, so callers that discard it
+    /// never clone a value.
     ///
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
     pub(crate) fn insert_recurse(
         cur_node: &Arc<Node<P, V>>,
         key: &P,
@@ -652,7 +807,15 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         commit_version: u64,
         ts: u64,
         depth: usize,
-    ) -> Result<(Arc<Node<P, V>>, Option<V>), TrieError> {
+        policy: DuplicateTsPolicy,
+        versioning: VersioningStrategy,
+        forced: Option<NodeKind>,
+        stats: &mut InsertStats,
+    ) -> Result<(Arc<Node<P, V>>, Option<Arc<LeafValue<V>>>), TrieError> {
+        stats.check(|checks| cur_node.check_twig(key, depth, checks))?;
+        // Every path below replaces the current node with a modified copy.
+        stats.copy(Arc::strong_count(cur_node));
+
         // Obtain the current node's prefix and its length.
         let cur_node_prefix = cur_node.prefix().clone();
         let cur_node_prefix_len = cur_node.prefix().len();
@@ -675,21 +838,28 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         if let NodeType::Twig(ref twig) = &cur_node.node_type {
             if is_prefix_match && cur_node_prefix.len() == key_prefix.len() {
                 let old_val = twig.get_leaf_by_version(commit_version).unwrap();
-                let new_twig = twig.insert(value, commit_version, ts);
+                let new_twig = Node::insert_twig_value(
+                    twig,
+                    value,
+                    commit_version,
+                    ts,
+                    policy,
+                    versioning,
+                    stats,
+                )?;
                 return Ok((
-                    Arc::new(Node {
-                        node_type: NodeType::Twig(new_twig),
-                    }),
-                    Some(old_val.value.clone()),
+                    Arc::new(Node::from_type(NodeType::Twig(new_twig))),
+                    Some(old_val),
                 ));
             }
         }
 
         // If the prefixes don't match, create a new Node4 with the old node and a new Twig as children.
         if !is_prefix_match {
+            stats.prefix_split = true;
             let mut old_node = cur_node.clone_node();
             old_node.set_prefix(new_key);
-            let mut n4 = Node::new_node4(prefix);
+            let mut n4 = Node::new_inner(prefix, forced);
 
             let k1 = cur_node_prefix.at(longest_common_prefix);
             let k2 = key_prefix[longest_common_prefix];
@@ -701,6 +871,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
                 ts,
             );
             n4 = n4.add_child(k1, old_node).add_child(k2, new_twig);
+            stats.depth += 1;
             return Ok((Arc::new(n4), None));
         }
 
@@ -708,6 +879,9 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         let k = key_prefix[longest_common_prefix];
         let child_for_key = cur_node.find_child(k);
         if let Some(child) = child_for_key {
+            let child_depth = depth + longest_common_prefix;
+            stats.check(|checks| child.check_child_byte(k, key, child_depth, checks))?;
+            stats.depth += 1;
             match Node::insert_recurse(
                 child,
                 key,
@@ -715,6 +889,10 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
                 commit_version,
                 ts,
                 depth + longest_common_prefix,
+                policy,
+                versioning,
+                forced,
+                stats,
             ) {
                 Ok((new_child, old_value)) => {
                     let new_node = cur_node.replace_child(k, new_child);
@@ -735,9 +913,228 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
             ts,
         );
         let new_node = cur_node.add_child(k, new_twig);
+        stats.add_child(cur_node.kind(), new_node.kind());
+        stats.depth += 1;
         Ok((Arc::new(new_node), None))
     }
 
+    /// Adds a version to the values of a twig according to `policy`, keeping as
+    /// many of the latest versions as `versioning` does.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::DuplicateTimestamp` if the policy is `Reject` and the
+    /// twig already has a version at `ts`.
+    ///
+    fn insert_twig_value(
+        twig: &TwigNode<P, V>,
+        value: V,
+        commit_version: u64,
+        ts: u64,
+        policy: DuplicateTsPolicy,
+        versioning: VersioningStrategy,
+        stats: &mut InsertStats,
+    ) -> Result<TwigNode<P, V>, TrieError> {
+        let new_twig = match policy {
+            DuplicateTsPolicy::Stack => twig.insert(value, commit_version, ts),
+            _ if twig.count_ts(ts) == 0 => twig.insert(value, commit_version, ts),
+            DuplicateTsPolicy::Reject => return Err(TrieError::DuplicateTimestamp),
+            DuplicateTsPolicy::Replace => {
+                twig.retain(|leaf| leaf.ts != ts)
+                    .insert(value, commit_version, ts)
+            }
+        };
+        let new_twig = match versioning.limit() {
+            Some(limit) if new_twig.values.len() > limit => {
+                // Pinned versions are kept along with the latest ones, as
+                // `Tree::retain_versions` keeps them.
+                let first_kept = new_twig.values.len() - limit;
+                let mut index = 0;
+                new_twig.retain(|leaf| {
+                    index += 1;
+                    index > first_kept || stats.pinned.contains(&leaf.version)
+                })
+            }
+            _ => new_twig,
+        };
+        stats.replaced = twig.values.len() + 1 - new_twig.values.len();
+        Ok(new_twig)
+    }
+
+    /// Checks whether `key` can take the single-byte fast path below `root`.
+    ///
+    /// A single-byte key lives directly in a child slot of a root with an empty
+    /// prefix, so it can be read and written without descending the trie or
+    /// building partial keys.
+    #[inline]
+    fn is_root_slot(root: &Node<P, V>, key: &P) -> bool {
+        if key.len() != 1 || root.is_twig() || !root.prefix().is_empty() {
+            return false;
+        }
+        match root.find_child(key.at(0)) {
+            None => true,
+            Some(child) => matches!(&child.node_type, NodeType::Twig(twig) if &twig.key == key),
+        }
+    }
+
+    /// Inserts a key-value pair into the subtree in `slot`, updating in place the
+    /// inner nodes on the path that were copied within `window` and that nothing
+    /// else references.
+    ///
+    /// The path below the last node updated in place is copied by
+    /// `insert_recurse`, and the inner nodes of the copy join the window.
+    ///
+    /// # Returns
+    ///
+    /// Returns the subtree the copy replaced, to be dropped by the caller once the
+    /// insert is complete.
+    ///
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn insert_in_window(
+        slot: &mut Arc<Node<P, V>>,
+        key: &P,
+        value: V,
+        commit_version: u64,
+        ts: u64,
+        depth: usize,
+        policy: DuplicateTsPolicy,
+        versioning: VersioningStrategy,
+        forced: Option<NodeKind>,
+        window: &mut CowWindow,
+        stats: &mut InsertStats,
+    ) -> Result<Arc<Node<P, V>>, TrieError> {
+        let rest = &key.as_slice()[depth..];
+        let prefix_len = slot.prefix().len();
+        let descend = !slot.is_twig()
+            && window.holds(slot)
+            && rest.len() > prefix_len
+            && slot.prefix().longest_common_prefix(rest) == prefix_len
+            && slot.find_child(rest[prefix_len]).is_some();
+        if descend {
+            let k = rest[prefix_len];
+            let child = slot.find_child(k).expect("child exists");
+            stats.check(|checks| child.check_child_byte(k, key, depth + prefix_len, checks))?;
+            if let Some(node) = Arc::get_mut(slot) {
+                stats.reuse();
+                stats.depth += 1;
+                let child = node.child_mut(rest[prefix_len]).expect("child exists");
+                let old_node = Node::insert_in_window(
+                    child,
+                    key,
+                    value,
+                    commit_version,
+                    ts,
+                    depth + prefix_len,
+                    policy,
+                    versioning,
+                    forced,
+                    window,
+                    stats,
+                )?;
+                node.refresh_version();
+                // The node is changed in place, so its count is no longer known.
+                *node.count.get_mut() = 0;
+                return Ok(old_node);
+            }
+        }
+
+        let (new_node, _) = Node::insert_recurse(
+            slot,
+            key,
+            value,
+            commit_version,
+            ts,
+            depth,
+            policy,
+            versioning,
+            forced,
+            stats,
+        )?;
+        let old_node = std::mem::replace(slot, new_node);
+
+        // Every inner node on the path of the copy is new.
+        let mut node = &*slot;
+        let mut depth = depth;
+        while !node.is_twig() {
+            window.add(node);
+            depth += node.prefix().len();
+            match key.as_slice().get(depth).and_then(|&k| node.find_child(k)) {
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+        Ok(old_node)
+    }
+
+    /// Inserts a single-byte key directly into its child slot of the root.
+    ///
+    /// The caller must check `is_root_slot` first.
+    ///
+    /// # Returns
+    ///
+    /// Returns the updated root and the old leaf (if any) for the given key.
+    ///
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn insert_root_slot(
+        root: &Arc<Node<P, V>>,
+        key: &P,
+        value: V,
+        commit_version: u64,
+        ts: u64,
+        policy: DuplicateTsPolicy,
+        versioning: VersioningStrategy,
+        stats: &mut InsertStats,
+    ) -> Result<(Arc<Node<P, V>>, Option<Arc<LeafValue<V>>>), TrieError> {
+        stats.copy(Arc::strong_count(root));
+        stats.depth += 1;
+        let k = key.at(0);
+        if let Some(child) = root.find_child(k) {
+            stats.check(|checks| {
+                child.check_child_byte(k, key, 0, checks)?;
+                child.check_twig(key, 0, checks)
+            })?;
+        }
+        match root.find_child(k).map(|child| &child.node_type) {
+            Some(NodeType::Twig(twig)) => {
+                stats.copy(1);
+                let old_value = twig.get_leaf_by_version(commit_version);
+                let new_twig = Node::from_type(NodeType::Twig(Node::insert_twig_value(
+                    twig,
+                    value,
+                    commit_version,
+                    ts,
+                    policy,
+                    versioning,
+                    stats,
+                )?));
+                Ok((
+                    Arc::new(root.replace_child(k, Arc::new(new_twig))),
+                    old_value,
+                ))
+            }
+            _ => {
+                let new_twig = Node::new_twig(key.clone(), key.clone(), value, commit_version, ts);
+                let new_root = root.add_child(k, new_twig);
+                stats.add_child(root.kind(), new_root.kind());
+                Ok((Arc::new(new_root), None))
+            }
+        }
+    }
+
+    /// Retrieves the value of a single-byte key directly from its child slot of the root.
+    ///
+    /// The caller must check `is_root_slot` first.
+    #[inline]
+    fn get_root_slot(
+        root: &Node<P, V>,
+        key: &P,
+        version: u64,
+    ) -> Result<(P, V, u64, u64), TrieError> {
+        root.find_child(key.at(0))
+            .and_then(|twig| twig.get_value_by_version(version))
+            .ok_or(TrieError::KeyNotFound)
+    }
+
     /// Removes a key recursively from the node and its children.
     ///
     /// Recursively removes a key from the current node and its child nodes.
@@ -756,6 +1153,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         cur_node: &Arc<Node<P, V>>,
         key: &P,
         depth: usize,
+        forced: Option<NodeKind>,
     ) -> (Option<Arc<Node<P, V>>>, bool) {
         // Obtain the prefix of the current node.
         let prefix = cur_node.prefix().clone();
@@ -774,6 +1172,11 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
             return (None, true);
         }
 
+        // The key diverges within the prefix of the current node, so it is not stored below it.
+        if longest_common_prefix < prefix.len() {
+            return (Some(cur_node.clone()), false);
+        }
+
         // Determine the character at the common prefix position.
         let k = key_prefix[longest_common_prefix];
 
@@ -781,11 +1184,18 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         let child = cur_node.find_child(k);
         if let Some(child_node) = child {
             // Recursively attempt to remove the key from the child node.
-            let (_new_child, removed) =
-                Node::remove_recurse(child_node, key, depth + longest_common_prefix);
+            let (new_child, removed) =
+                Node::remove_recurse(child_node, key, depth + longest_common_prefix, forced);
             if removed {
                 // If the key was successfully removed from the child node, update the current node's child pointer.
-                let new_node = cur_node.delete_child(k);
+                let new_node = match new_child {
+                    Some(new_child) => cur_node.replace_child(k, new_child),
+                    None => cur_node.delete_child(k, forced),
+                };
+                // An inner node left without children is removed along with its last child.
+                if new_node.num_children() == 0 {
+                    return (None, true);
+                }
                 return (Some(Arc::new(new_node)), true);
             }
         }
@@ -794,6 +1204,195 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         (Some(cur_node.clone()), false)
     }
 
+    /// Removes the keys starting with any of `prefixes` from the subtree rooted
+    /// at `cur_node`, like `remove_recurse` for every key, collecting the twigs
+    /// removed.
+    ///
+    /// `prefixes` are sorted, none starts with another, and each follows the path
+    /// to `cur_node` for its first `depth` bytes.
+    ///
+    /// # Returns
+    ///
+    /// Returns the updated node, or `None` if no key is left below it.
+    ///
+    fn remove_prefixes_recurse(
+        cur_node: &Arc<Node<P, V>>,
+        prefixes: &[&[u8]],
+        depth: usize,
+        forced: Option<NodeKind>,
+        removed: &mut Vec<Arc<Node<P, V>>>,
+    ) -> Option<Arc<Node<P, V>>> {
+        if let NodeType::Twig(twig) = &cur_node.node_type {
+            let key = twig.key.as_slice();
+            if prefixes.iter().any(|prefix| key.starts_with(prefix)) {
+                removed.push(cur_node.clone());
+                return None;
+            }
+            return Some(cur_node.clone());
+        }
+
+        let node_prefix = cur_node.prefix();
+        let mut below = Vec::with_capacity(prefixes.len());
+        for prefix in prefixes {
+            let rest = &prefix[depth..];
+            let lcp = node_prefix.longest_common_prefix(rest);
+            if lcp == rest.len() {
+                // The prefix ends within the prefix of this node, so every key
+                // below starts with it.
+                Node::collect_twigs(cur_node, removed);
+                return None;
+            }
+            if lcp == node_prefix.len() {
+                below.push(*prefix);
+            }
+        }
+        if below.is_empty() {
+            return Some(cur_node.clone());
+        }
+
+        // The prefixes are sorted, so those under the same child are adjacent.
+        let depth = depth + node_prefix.len();
+        let mut new_node: Option<Node<P, V>> = None;
+        for group in below.chunk_by(|a, b| a[depth] == b[depth]) {
+            let k = group[0][depth];
+            let Some(child) = cur_node.find_child(k) else {
+                continue;
+            };
+            let before = removed.len();
+            let new_child = Node::remove_prefixes_recurse(child, group, depth, forced, removed);
+            if removed.len() == before {
+                continue;
+            }
+            let node = new_node.as_ref().unwrap_or(cur_node);
+            new_node = Some(match new_child {
+                Some(new_child) => node.replace_child(k, new_child),
+                None => node.delete_child(k, forced),
+            });
+        }
+
+        match new_node {
+            None => Some(cur_node.clone()),
+            // An inner node left without children is removed along with them.
+            Some(node) if node.num_children() == 0 => None,
+            Some(node) => Some(Arc::new(node)),
+        }
+    }
+
+    /// Collects the twigs of the subtree rooted at `node`, in key order.
+    fn collect_twigs(node: &Arc<Node<P, V>>, twigs: &mut Vec<Arc<Node<P, V>>>) {
+        if node.is_twig() {
+            twigs.push(node.clone());
+            return;
+        }
+        for (_, child) in node.iter() {
+            Node::collect_twigs(child, twigs);
+        }
+    }
+
+    /// Merges an inner node left with a single child into that child.
+    ///
+    /// The child takes the place of the node, with the node's prefix prepended to
+    /// its own, so the keys below it keep their paths while losing a level. A node
+    /// with any other number of children, or a twig, is returned unchanged. The
+    /// versions are unaffected, since an inner node's version is that of its
+    /// newest child.
+    fn collapse_single_child(self) -> Self {
+        if self.is_twig() || self.num_children() != 1 {
+            return self;
+        }
+        let (_, child) = self.next_child(0).expect("node has a child");
+        let mut prefix = self.prefix().as_slice().to_vec();
+        prefix.extend_from_slice(child.prefix().as_slice());
+        let mut merged = child.clone_node();
+        merged.set_prefix(P::from(prefix.as_slice()));
+        merged
+    }
+
+    /// Rebuilds the subtree rooted at `node` without single-child inner nodes.
+    ///
+    /// Only the paths leading to such nodes are copied; every other subtree is
+    /// shared with the original.
+    ///
+    /// # Returns
+    ///
+    /// Returns the new subtree along with the number of nodes merged away.
+    ///
+    pub(crate) fn compact(node: &Arc<Node<P, V>>) -> (Arc<Node<P, V>>, usize) {
+        if node.is_twig() {
+            return (node.clone(), 0);
+        }
+        let mut merged = 0;
+        let mut current: Option<Node<P, V>> = None;
+        for (key, child) in node.iter() {
+            let (new_child, count) = Node::compact(child);
+            if count > 0 {
+                let base = current.as_ref().unwrap_or(node);
+                current = Some(base.replace_child(key, new_child));
+                merged += count;
+            }
+        }
+        let current = current.unwrap_or_else(|| node.clone_node());
+        if current.num_children() == 1 {
+            return (Arc::new(current.collapse_single_child()), merged + 1);
+        }
+        if merged == 0 {
+            return (node.clone(), 0);
+        }
+        (Arc::new(current), merged)
+    }
+
+    /// Resolves a point read of `key` at `version` in the trie rooted at `root`.
+    ///
+    /// Every point read, through a Tree, a Snapshot or a reader, ends here, so
+    /// they all report a missing value the same way. A version of 0 reads the
+    /// latest version of the trie. The read fails with `TrieError::KeyNotFound`
+    /// when:
+    ///
+    /// - the trie is empty,
+    /// - the key diverges from the trie at an inner node or ends inside one,
+    /// - the key reaches a twig holding another key, or
+    /// - the key is present but has no version at or before `version`.
+    ///
+    /// Closed Trees and snapshots fail before reaching the trie, with
+    /// `TrieError::TreeAlreadyClosed` or `TrieError::SnapshotAlreadyClosed`.
+    ///
+    pub(crate) fn resolve_get(
+        root: Option<&Arc<Node<P, V>>>,
+        key: &P,
+        version: u64,
+    ) -> Result<(P, V, u64, u64), TrieError> {
+        Node::resolve_get_indexed(root, key, version, None)
+    }
+
+    /// Like `resolve_get`, but jumps straight to the twig of the key if `index`
+    /// has it, and descends the trie only if the indexed twig is stale.
+    pub(crate) fn resolve_get_indexed(
+        root: Option<&Arc<Node<P, V>>>,
+        key: &P,
+        version: u64,
+        index: Option<&HashIndex<P, V>>,
+    ) -> Result<(P, V, u64, u64), TrieError> {
+        let Some(root) = root else {
+            return Err(TrieError::KeyNotFound);
+        };
+        let version = match version {
+            0 => root.version(),
+            version => version,
+        };
+
+        // Single-byte keys are read straight from their slot of the root
+        if Node::is_root_slot(root, key) {
+            return Node::get_root_slot(root, key, version);
+        }
+        match index.map(|index| index.get(key)) {
+            Some(Indexed::Twig(twig)) => twig
+                .get_value_by_version(version)
+                .ok_or(TrieError::KeyNotFound),
+            Some(Indexed::Absent) => Err(TrieError::KeyNotFound),
+            Some(Indexed::Stale) | None => Node::get_recurse(root, key, version),
+        }
+    }
+
     /// Recursively searches for a key in the node and its children.
     ///
     /// Recursively searches for a key in the current node and its child nodes, considering versions.
@@ -852,1227 +1451,9540 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         }
     }
 
-    /// Returns an iterator that iterates over child nodes of the current node.
-    ///
-    /// This function provides an iterator that traverses through the child nodes of the current node,
-    /// returning tuples of keys and references to child nodes.
-    ///
-    /// # Returns
+    /// Finds the root of the smallest subtree holding every key that starts with `prefix`.
     ///
-    /// Returns a boxed iterator that yields tuples containing keys and references to child nodes.
-    ///
-    #[allow(dead_code)]
-    pub fn iter(&self) -> Box<dyn Iterator<Item = (u8, &Arc<Self>)> + '_> {
-        match &self.node_type {
-            NodeType::Node1(n) => Box::new(n.iter()),
-            NodeType::Node4(n) => Box::new(n.iter()),
-            NodeType::Node16(n) => Box::new(n.iter()),
-            NodeType::Node48(n) => Box::new(n.iter()),
-            NodeType::Node256(n) => Box::new(n.iter()),
-            NodeType::Twig(_) => Box::new(std::iter::empty()),
-        }
+    /// The returned node may be a twig node, in which case its key starts with
+    /// `prefix`. Returns `None` if no key starts with `prefix`.
+    pub(crate) fn find_prefix_subtree<'a>(
+        cur_node: &'a Arc<Node<P, V>>,
+        prefix: &[u8],
+    ) -> Option<&'a Arc<Node<P, V>>> {
+        Node::find_prefix_subtree_at(cur_node, prefix).map(|(node, _)| node)
     }
-}
 
-/// A struct representing an Adaptive Radix Trie.
-///
-/// The `Tree` struct encompasses the entire adaptive radix trie data structure.
-/// It manages the root node of the tree, maintains snapshots of the tree's state,
-/// and keeps track of various properties related to snapshot management.
-///
-/// # Type Parameters
-///
-/// - `P`: A type implementing the `KeyTrait` trait, defining the prefix traits for nodes.
-/// - `V`: The type of value associated with nodes.
-///
-/// # Fields
-///
-/// - `root`: An optional shared reference (using `Rc`) to the root node of the tree.
-/// - `snapshots`: A `HashSet` storing snapshots of the tree's state, mapped by snapshot IDs.
-/// - `max_snapshot_id`: An `AtomicU64` representing the maximum snapshot ID assigned.
-/// - `max_active_snapshots`: The maximum number of active snapshots allowed.
-///
-pub struct Tree<P: KeyTrait, V: Clone> {
-    /// An optional shared reference to the root node of the tree.
-    pub(crate) root: Option<Arc<Node<P, V>>>,
-    /// A mapping of snapshot IDs to their corresponding snapshots.
-    pub(crate) snapshots: HashSet<u64>,
-    /// An atomic value indicating the maximum snapshot ID assigned.
-    pub(crate) max_snapshot_id: AtomicU64,
-    /// The maximum number of active snapshots allowed.
-    pub(crate) max_active_snapshots: u64,
-    /// A flag indicating whether the tree is closed.
-    pub(crate) closed: bool,
-}
+    /// Returns the number of keys below `root` that start with `prefix`.
+    pub(crate) fn count_under(root: Option<&Arc<Node<P, V>>>, prefix: &[u8]) -> usize {
+        root.and_then(|root| Node::find_prefix_subtree(root, prefix))
+            .map_or(0, |node| node.count_twigs())
+    }
 
-pub struct KV<P, V> {
-    pub key: P,
-    pub value: V,
-    pub version: u64,
-    pub ts: u64,
-}
+    /// Like `find_prefix_subtree`, also returning the depth at which the prefix
+    /// of the subtree root starts.
+    fn find_prefix_subtree_at<'a>(
+        cur_node: &'a Arc<Node<P, V>>,
+        prefix: &[u8],
+    ) -> Option<(&'a Arc<Node<P, V>>, usize)> {
+        let mut cur_node = cur_node;
+        let mut depth = 0;
 
-impl<P: KeyTrait, V: Clone> KV<P, V> {
-    pub fn new(key: P, value: V, version: u64, timestamp: u64) -> Self {
-        KV {
-            key,
-            value,
-            version,
-            ts: timestamp,
+        loop {
+            if let NodeType::Twig(twig) = &cur_node.node_type {
+                return twig
+                    .key
+                    .as_slice()
+                    .starts_with(prefix)
+                    .then_some((cur_node, depth));
+            }
+
+            let rest = &prefix[depth..];
+            let node_prefix = cur_node.prefix();
+            let lcp = node_prefix.longest_common_prefix(rest);
+
+            // The prefix ends within the prefix of this node.
+            if lcp == rest.len() {
+                return Some((cur_node, depth));
+            }
+            if lcp != node_prefix.len() {
+                return None;
+            }
+
+            depth += node_prefix.len();
+            cur_node = cur_node.find_child(prefix[depth])?;
         }
     }
-}
 
-impl<P: KeyTrait + Clone, V: Clone> NodeType<P, V> {
-    fn clone(&self) -> Self {
-        match self {
-            // twig value not actually cloned
-            NodeType::Twig(twig) => NodeType::Twig(twig.clone()),
-            NodeType::Node1(n) => NodeType::Node1(n.clone()),
-            NodeType::Node4(n) => NodeType::Node4(n.clone()),
-            NodeType::Node16(n) => NodeType::Node16(n.clone()),
-            NodeType::Node48(n) => NodeType::Node48(n.clone()),
-            NodeType::Node256(n) => NodeType::Node256(n.clone()),
+    /// Returns a root for the keys starting with `prefix`: the root of the
+    /// subtree holding them, with its prefix extended to the whole path from the
+    /// root of the trie.
+    ///
+    /// The subtree root is copied if its path has to be extended, sharing its
+    /// children, so lookups and scans from the returned root work as from the
+    /// root of a trie holding only those keys. Returns `None` if no key starts
+    /// with `prefix`.
+    pub(crate) fn prefix_root(root: &Arc<Node<P, V>>, prefix: &[u8]) -> Option<Arc<Node<P, V>>> {
+        let (node, depth) = Node::find_prefix_subtree_at(root, prefix)?;
+        if depth == 0 {
+            return Some(node.clone());
         }
-    }
-}
 
-// Default implementation for the Tree struct
-impl<P: KeyTrait, V: Clone> Default for Tree<P, V> {
-    fn default() -> Self {
-        Tree::new()
+        let mut path = prefix[..depth].to_vec();
+        path.extend_from_slice(node.prefix().as_slice());
+        let mut node = node.clone_node();
+        node.set_prefix(P::from(path.as_slice()));
+        Some(Arc::new(node))
     }
-}
 
-impl<P: KeyTrait, V: Clone> Tree<P, V> {
-    pub fn new() -> Self {
-        Tree {
-            root: None,
-            max_snapshot_id: AtomicU64::new(0),
-            snapshots: HashSet::new(),
-            max_active_snapshots: DEFAULT_MAX_ACTIVE_SNAPSHOTS,
-            closed: false,
+    /// Returns the number of distinct bytes following `prefix` in the keys that
+    /// start with it. A key equal to `prefix` has no next byte.
+    pub(crate) fn distinct_next_bytes(root: &Arc<Node<P, V>>, prefix: &[u8]) -> usize {
+        let Some((node, depth)) = Node::find_prefix_subtree_at(root, prefix) else {
+            return 0;
+        };
+        match &node.node_type {
+            NodeType::Twig(twig) => usize::from(twig.key.as_slice().len() > prefix.len()),
+            // The prefix ends within the prefix of the subtree root, so every key
+            // continues with the same byte.
+            _ if depth + node.prefix().len() > prefix.len() => 1,
+            _ => node.num_children(),
         }
     }
 
-    pub fn set_max_active_snapshots(&mut self, max_active_snapshots: u64) {
-        self.max_active_snapshots = max_active_snapshots;
-    }
-
-    /// Inserts a new key-value pair with the specified version into the Trie.
-    ///
-    /// This function inserts a new key-value pair into the Trie. If the key already exists,
-    /// the previous value associated with the key is returned. The version `ts` is used to
-    /// ensure proper ordering of values for versioning.
-    ///
-    /// # Arguments
+    /// Finds the twig node holding the given key.
     ///
-    /// * `key`: A reference to the key to be inserted.
-    /// * `value`: The value to be associated with the key.
-    /// * `ts`: The version for the insertion, used for versioning.
+    /// Walks down from `cur_node` the same way as `get_recurse`, but returns the
+    /// twig node itself rather than one of its values.
     ///
     /// # Returns
     ///
-    /// Returns `Ok(None)` if the key did not exist previously. If the key already existed,
-    /// `Ok(Some(old_value))` is returned, where `old_value` is the previous value associated with the key.
-    ///
-    /// # Errors
-    ///
-    /// Returns an error if the given version is older than the root's current version.
+    /// Returns the twig node for the key, or `None` if the key is not present.
     ///
-    pub fn insert(
-        &mut self,
+    pub(crate) fn find_twig<'a>(
+        cur_node: &'a Arc<Node<P, V>>,
         key: &P,
-        value: V,
-        version: u64,
-        ts: u64,
-    ) -> Result<Option<V>, TrieError> {
-        // Check if the tree is already closed
-        self.is_closed()?;
+    ) -> Option<&'a Arc<Node<P, V>>> {
+        if let Some(len) = P::FIXED_LEN.filter(|len| *len == key.len()) {
+            return Self::find_twig_fixed(cur_node, key.as_slice(), len);
+        }
+        let mut cur_node = cur_node;
+        let mut depth = 0;
 
-        let (new_root, old_node) = match &self.root {
-            None => {
-                let mut commit_version = version;
-                if version == 0 {
-                    commit_version += 1;
-                }
-                (
-                    Arc::new(Node::new_twig(
-                        key.as_slice().into(),
-                        key.as_slice().into(),
-                        value,
-                        commit_version,
-                        ts,
-                    )),
-                    None,
-                )
+        loop {
+            let key_prefix = key.prefix_after(depth);
+            let key_prefix = key_prefix.as_slice();
+            let prefix = cur_node.prefix();
+            let lcp = prefix.longest_common_prefix(key_prefix);
+
+            if lcp != prefix.len() {
+                return None;
             }
-            Some(root) => {
-                // Check if the given version is older than the root's current version.
-                // If so, return an error and do not insert the new node.
-                let curr_version = root.version();
-                let mut commit_version = version;
-                if version == 0 {
-                    commit_version = curr_version + 1;
-                } else if curr_version >= version {
-                    return Err(TrieError::Other(
-                        "given version is older than root's current version".to_string(),
-                    ));
-                }
-                match Node::insert_recurse(root, key, value, commit_version, ts, 0) {
-                    Ok((new_node, old_node)) => (new_node, old_node),
-                    Err(err) => {
-                        return Err(err);
-                    }
-                }
+
+            if prefix.len() == key_prefix.len() {
+                return if cur_node.is_twig() {
+                    Some(cur_node)
+                } else {
+                    None
+                };
             }
-        };
 
-        self.root = Some(new_root);
-        Ok(old_node)
+            let k = key.at(depth + prefix.len());
+            depth += prefix.len();
+            cur_node = cur_node.find_child(k)?;
+        }
     }
 
-    pub fn bulk_insert(&mut self, kv_pairs: &[KV<P, V>]) -> Result<(), TrieError> {
-        // Check if the tree is already closed
-        self.is_closed()?;
+    /// Looks up the twig holding `key`, of the fixed length `len` of the key
+    /// type, below `cur_node`.
+    ///
+    /// Every stored key has the same length, so the twig is the node whose
+    /// prefix ends at `len`, and the descent compares the prefixes with the
+    /// bytes of the key in place rather than with copies of its remainder.
+    fn find_twig_fixed<'a>(
+        cur_node: &'a Arc<Node<P, V>>,
+        key: &[u8],
+        len: usize,
+    ) -> Option<&'a Arc<Node<P, V>>> {
+        let mut cur_node = cur_node;
+        let mut depth = 0;
 
-        let curr_version = self.version();
-        let mut new_version = 0;
+        while depth < len {
+            let prefix = cur_node.prefix().as_slice();
+            let end = depth + prefix.len();
+            if end > len || key[depth..end] != *prefix {
+                return None;
+            }
+            if end == len {
+                return cur_node.is_twig().then_some(cur_node);
+            }
+            cur_node = cur_node.find_child(key[end])?;
+            depth = end;
+        }
+        None
+    }
 
-        for kv in kv_pairs {
-            let k = kv.key.clone(); // Clone the key
-            let v = kv.value.clone(); // Clone the value
-            let mut t = kv.version;
+    /// Looks up the twig holding `key` below `cur_node` like `find_twig`, running
+    /// `checks` on every node visited on the way.
+    ///
+    /// The checks only look at what the descent loads anyway: the prefix of each
+    /// child against the byte it was found under, and the length of the prefix
+    /// and the two latest versions of a twig.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first violation found.
+    ///
+    pub(crate) fn find_twig_checked<'a>(
+        cur_node: &'a Arc<Node<P, V>>,
+        key: &P,
+        checks: InvariantChecks,
+    ) -> Result<Option<&'a Arc<Node<P, V>>>, InvariantViolation> {
+        let mut cur_node = cur_node;
+        let mut depth = 0;
 
-            if t == 0 {
-                // Zero-valued timestamps are associated with current time plus one
-                t = curr_version + 1;
-            } else if kv.version < curr_version {
-                return Err(TrieError::Other(
-                    "given version is older than root's current version".to_string(),
-                ));
+        loop {
+            let key_prefix = key.prefix_after(depth);
+            let key_prefix = key_prefix.as_slice();
+            let prefix = cur_node.prefix();
+            cur_node.check_twig(key, depth, checks)?;
+
+            let lcp = prefix.longest_common_prefix(key_prefix);
+            if lcp != prefix.len() {
+                return Ok(None);
+            }
+            if prefix.len() == key_prefix.len() {
+                return Ok(cur_node.is_twig().then_some(cur_node));
             }
 
-            // Create a new KV instance
-            let new_kv = KV {
-                key: k,
-                value: v,
-                version: t,
-                ts: kv.ts,
+            let k = key.at(depth + prefix.len());
+            depth += prefix.len();
+            let Some(child) = cur_node.find_child(k) else {
+                return Ok(None);
             };
+            child.check_child_byte(k, key, depth, checks)?;
+            cur_node = child;
+        }
+    }
 
-            // Insert the new KV instance using the insert function
-            // self.insert(&new_kv.key, new_kv.value, new_kv.version, new_kv.ts)?;
-            match &self.root {
-                None => {
-                    self.root = Some(Arc::new(Node::new_twig(
-                        new_kv.key.as_slice().into(),
-                        new_kv.key.as_slice().into(),
-                        new_kv.value,
-                        new_kv.version,
-                        new_kv.ts,
-                    )))
-                }
-                Some(root) => {
-                    match Node::insert_recurse(
-                        root,
-                        &new_kv.key,
-                        new_kv.value,
-                        new_kv.version,
-                        new_kv.ts,
-                        0,
-                    ) {
-                        Ok((new_node, _)) => {
-                            self.root = Some(new_node);
-                        }
-                        Err(err) => {
-                            return Err(err);
-                        }
-                    }
-                }
-            }
+    // Returns the violation of `check` by this node, reached at `depth` on the
+    // way to `key`.
+    fn violation(
+        &self,
+        check: InvariantCheck,
+        key: &P,
+        depth: usize,
+        reason: &'static str,
+    ) -> InvariantViolation {
+        let mut path = key.as_slice()[..depth.min(key.len())].to_vec();
+        path.extend_from_slice(self.prefix().as_slice());
+        InvariantViolation {
+            check,
+            path,
+            reason,
+        }
+    }
 
-            // Update new_version if necessary
-            if t > new_version {
-                new_version = t;
+    /// Runs the twig checks of `checks` on this node, reached at `depth` on the
+    /// way to `key`, if it is a twig.
+    pub(crate) fn check_twig(
+        &self,
+        key: &P,
+        depth: usize,
+        checks: InvariantChecks,
+    ) -> Result<(), InvariantViolation> {
+        let NodeType::Twig(twig) = &self.node_type else {
+            return Ok(());
+        };
+        if checks.prefix_lengths && depth + self.prefix().len() != twig.key.as_slice().len() {
+            return Err(self.violation(
+                InvariantCheck::PrefixLengths,
+                key,
+                depth,
+                "twig prefix does not end with its key",
+            ));
+        }
+        if checks.version_order {
+            let mut latest = twig.values.iter().rev();
+            let reason = match (latest.next(), latest.next()) {
+                (None, _) => Some("twig has no values"),
+                (Some(last), Some(prev)) if prev.version > last.version => {
+                    Some("twig values are not sorted by version")
+                }
+                _ => None,
+            };
+            if let Some(reason) = reason {
+                return Err(self.violation(InvariantCheck::VersionOrder, key, depth, reason));
             }
         }
-
         Ok(())
     }
 
-    pub fn remove(&mut self, key: &P) -> Result<bool, TrieError> {
-        // Check if the tree is already closed
-        self.is_closed()?;
+    /// Checks that the prefix of this node, found under the byte `k` at `depth`
+    /// on the way to `key`, starts with that byte.
+    pub(crate) fn check_child_byte(
+        &self,
+        k: u8,
+        key: &P,
+        depth: usize,
+        checks: InvariantChecks,
+    ) -> Result<(), InvariantViolation> {
+        if checks.child_bytes && self.prefix().as_slice().first() != Some(&k) {
+            return Err(self.violation(
+                InvariantCheck::ChildBytes,
+                key,
+                depth,
+                "child prefix does not start with its key byte",
+            ));
+        }
+        Ok(())
+    }
 
-        let (new_root, is_deleted) = match &self.root {
-            None => (None, false),
-            Some(root) => {
-                if root.is_twig() {
-                    (None, true)
-                } else {
-                    let (new_root, removed) = Node::remove_recurse(root, key, 0);
-                    if removed {
-                        (new_root, true)
-                    } else {
-                        (self.root.clone(), true)
-                    }
-                }
-            }
-        };
+    /// Appends the nodes visited while looking up `key` below `cur_node` to `path`,
+    /// each with the offset into the key where its prefix starts.
+    ///
+    /// Walks down the same way as `find_twig`, and returns true if the last node
+    /// appended is the twig node holding the key.
+    pub(crate) fn search_path<'a>(
+        cur_node: &'a Arc<Node<P, V>>,
+        key: &P,
+        path: &mut Vec<(&'a Arc<Node<P, V>>, usize)>,
+    ) -> bool {
+        let mut cur_node = cur_node;
+        let mut depth = 0;
 
-        self.root = new_root;
-        Ok(is_deleted)
-    }
+        loop {
+            path.push((cur_node, depth));
+            let key_prefix = key.prefix_after(depth);
+            let key_prefix = key_prefix.as_slice();
+            let prefix = cur_node.prefix();
+            let lcp = prefix.longest_common_prefix(key_prefix);
 
-    pub fn get(&self, key: &P, version: u64) -> Result<(P, V, u64, u64), TrieError> {
-        // Check if the tree is already closed
-        self.is_closed()?;
+            if lcp != prefix.len() {
+                return false;
+            }
 
-        if self.root.is_none() {
-            return Err(TrieError::Other("cannot read from empty tree".to_string()));
-        }
+            if prefix.len() == key_prefix.len() {
+                return cur_node.is_twig();
+            }
 
-        let root = self.root.as_ref().unwrap();
-        let mut commit_version = version;
-        if commit_version == 0 {
-            commit_version = root.version();
+            let k = key.at(depth + prefix.len());
+            depth += prefix.len();
+            match cur_node.find_child(k) {
+                Some(child) => cur_node = child,
+                None => return false,
+            }
         }
-
-        Node::get_recurse(root, key, commit_version)
     }
 
-    /// Retrieves the latest version of the Trie.
-    ///
-    /// This function returns the version of the latest version of the Trie. If the Trie is empty,
-    /// it returns `0`.
-    ///
-    /// # Returns
+    /// Recursively prunes old values from the twig nodes below the node.
     ///
-    /// Returns the version of the latest version of the Trie, or `0` if the Trie is empty.
+    /// Drops every value that `rule` does not keep, except the values pinned in `pins`.
+    /// Twig nodes are visited in key order, so the pins, sorted by key bytes, are
+    /// merge-joined against them with `cursor` pointing at the first pin that has not
+    /// been passed yet.
     ///
-    pub fn version(&self) -> u64 {
-        match &self.root {
-            None => 0,
-            Some(root) => root.version(),
-        }
-    }
-
-    /// Creates a new snapshot of the Trie.
+    /// # Parameters
     ///
-    /// This function creates a snapshot of the current state of the Trie. If successful, it returns
-    /// a `Snapshot` that can be used to interact with the newly created snapshot.
+    /// - `cur_node`: A reference to the current node.
+    /// - `rule`: The values of each key to keep.
+    /// - `pins`: The pinned `(key, version)` pairs, sorted by key bytes.
+    /// - `cursor`: The position of the merge-join in `pins`.
+    /// - `pruned_twigs`: Collects the twig nodes that were rewritten, along with the
+    ///   number of values pruned from each.
     ///
     /// # Returns
     ///
-    /// Returns a `Result` containing the `Snapshot` if the snapshot is created successfully,
-    /// or an `Err` with an appropriate error message if creation fails.
+    /// Returns the replacement for the current node, or `None` if nothing below it
+    /// changed, along with the number of pruned values.
     ///
-    pub fn create_snapshot(&mut self) -> Result<Snapshot<P, V>, TrieError> {
-        // Check if the tree is already closed
-        self.is_closed()?;
+    pub(crate) fn prune_recurse(
+        cur_node: &Arc<Node<P, V>>,
+        rule: PruneRule,
+        pins: &[(Vec<u8>, u64)],
+        cursor: &mut usize,
+        pruned_twigs: &mut Vec<(Arc<Node<P, V>>, usize)>,
+    ) -> (Option<Arc<Node<P, V>>>, usize) {
+        if let NodeType::Twig(twig) = &cur_node.node_type {
+            let key = twig.key.as_slice();
+
+            // Skip the pins for keys before this one, then collect the ones for it.
+            while *cursor < pins.len() && pins[*cursor].0.as_slice() < key {
+                *cursor += 1;
+            }
+            let start = *cursor;
+            while *cursor < pins.len() && pins[*cursor].0.as_slice() == key {
+                *cursor += 1;
+            }
+            let pinned = &pins[start..*cursor];
+
+            let is_pinned =
+                |leaf: &LeafValue<V>| pinned.iter().any(|(_, version)| *version == leaf.version);
+            let new_twig = match rule {
+                PruneRule::OlderThan(cutoff) => {
+                    let visible = twig.get_leaf_by_version(cutoff).map(|leaf| leaf.version);
+                    twig.retain(|leaf| {
+                        leaf.version >= cutoff || Some(leaf.version) == visible || is_pinned(leaf)
+                    })
+                }
+                PruneRule::KeepLatest(count) => {
+                    // Values are ordered by version, so the latest ones come last.
+                    let first_kept = twig.values.len().saturating_sub(count.max(1));
+                    let mut index = 0;
+                    twig.retain(|leaf| {
+                        index += 1;
+                        index > first_kept || is_pinned(leaf)
+                    })
+                }
+            };
 
-        if self.snapshots.len() >= self.max_active_snapshots as usize {
-            return Err(TrieError::Other(
-                "max number of snapshots reached".to_string(),
-            ));
-        }
+            let pruned = twig.values.len() - new_twig.values.len();
+            if pruned == 0 {
+                return (None, 0);
+            }
 
-        // Increment the snapshot ID atomically
-        let new_snapshot_id = self.max_snapshot_id.fetch_add(1, Ordering::SeqCst);
-        self.snapshots.insert(new_snapshot_id);
+            let new_node = Arc::new(Node::from_type(NodeType::Twig(new_twig)));
+            pruned_twigs.push((new_node.clone(), pruned));
+            return (Some(new_node), pruned);
+        }
 
-        let root = self.root.as_ref().cloned();
-        let version = self.root.as_ref().map_or(1, |root| root.version() + 1);
-        let new_snapshot = Snapshot::new(new_snapshot_id, root, version);
+        let mut new_node: Option<Node<P, V>> = None;
+        let mut pruned = 0;
+        for (k, child) in cur_node.iter() {
+            let (new_child, count) = Node::prune_recurse(child, rule, pins, cursor, pruned_twigs);
+            if let Some(new_child) = new_child {
+                let node = new_node.as_ref().unwrap_or(cur_node);
+                new_node = Some(node.replace_child(k, new_child));
+                pruned += count;
+            }
+        }
 
-        Ok(new_snapshot)
+        (new_node.map(Arc::new), pruned)
     }
 
-    /// Closes a snapshot and removes it from the list of active snapshots.
+    /// Checks whether the key stored in a twig node is a prefix of `input`.
     ///
-    /// This function takes a `snapshot_id` as an argument and closes the corresponding snapshot.
-    /// If the snapshot exists, it is removed from the active snapshots list. If the snapshot is not
-    /// found, an `Err` is returned with a `TrieError::SnapshotNotFound` variant.
+    /// A trailing NULL terminator on the stored key is ignored, so that keys created
+    /// with a terminator, such as `VariableSizeKey::from_str`, match inputs that
+    /// continue past them.
+    #[inline]
+    fn is_key_prefix_of(key: &[u8], input: &[u8]) -> bool {
+        match key.split_last() {
+            Some((0, stripped)) => input.starts_with(stripped),
+            _ => input.starts_with(key),
+        }
+    }
+
+    /// Returns the twig node under `cur_node` terminating the path at it, if it is
+    /// a prefix of `input`.
+    #[inline]
+    fn terminator_match<'a>(cur_node: &'a Node<P, V>, input: &[u8]) -> Option<&'a Node<P, V>> {
+        let child = cur_node.find_child(0)?;
+        match &child.node_type {
+            NodeType::Twig(twig) if Self::is_key_prefix_of(twig.key.as_slice(), input) => {
+                Some(child)
+            }
+            _ => None,
+        }
+    }
+
+    /// Finds the longest stored key that is a prefix of `input`.
     ///
-    /// # Arguments
+    /// # Parameters
     ///
-    /// * `snapshot_id` - The ID of the snapshot to be closed and removed.
+    /// - `cur_node`: A reference to the root node.
+    /// - `input`: The bytes to match stored keys against.
     ///
     /// # Returns
     ///
-    /// Returns `Ok(())` if the snapshot is successfully closed and removed. Returns an `Err`
-    /// with `TrieError::SnapshotNotFound` if the snapshot with the given ID is not found.
+    /// Returns the twig node holding the longest matching key, or `None` if no stored key matches.
     ///
-    pub(crate) fn close_snapshot(&mut self, snapshot_id: u64) -> Result<(), TrieError> {
-        // Check if the tree is already closed
-        self.is_closed()?;
+    pub(crate) fn longest_prefix_match<'a>(
+        cur_node: &'a Node<P, V>,
+        input: &[u8],
+    ) -> Option<&'a Node<P, V>> {
+        let mut cur_node = cur_node;
+        let mut depth = 0;
+        let mut best = None;
 
-        if self.snapshots.remove(&snapshot_id) {
-            Ok(())
-        } else {
-            Err(TrieError::SnapshotNotFound)
+        loop {
+            let prefix = cur_node.prefix().as_slice();
+            if let NodeType::Twig(twig) = &cur_node.node_type {
+                if Self::is_key_prefix_of(twig.key.as_slice(), input) {
+                    best = Some(cur_node);
+                }
+                return best;
+            }
+
+            if !input[depth..].starts_with(prefix) {
+                return best;
+            }
+            depth += prefix.len();
+
+            // A key ending right at this node matches every input continuing through it.
+            if let Some(matched) = Node::terminator_match(cur_node, input) {
+                best = Some(matched);
+            }
+
+            let Some(&k) = input.get(depth) else {
+                return best;
+            };
+            match cur_node.find_child(k) {
+                Some(child) => cur_node = child,
+                None => return best,
+            }
         }
     }
 
-    /// Returns the count of active snapshots.
+    /// Recursively finds the longest stored key that is a prefix of each of a batch of inputs.
     ///
-    /// This function returns the number of currently active snapshots in the Trie.
+    /// All inputs in `batch` follow the path to `cur_node`. They are sorted, so the inputs
+    /// continuing into the same child are contiguous and share a single descent.
     ///
-    /// # Returns
-    ///
-    /// Returns a `Result` containing the count of active snapshots if successful, or an `Err`
-    /// if there is an issue retrieving the snapshot count.
+    /// # Parameters
     ///
-    pub fn snapshot_count(&self) -> usize {
-        self.snapshots.len()
+    /// - `cur_node`: A reference to the current node.
+    /// - `inputs`: The inputs to match stored keys against.
+    /// - `batch`: The indexes into `inputs` of the inputs reaching this node, sorted by input.
+    /// - `depth`: The depth of the current node.
+    /// - `best`: The best match found so far for each input.
+    ///
+    pub(crate) fn match_prefixes_recurse<'a>(
+        cur_node: &'a Node<P, V>,
+        inputs: &[&[u8]],
+        batch: &[usize],
+        depth: usize,
+        best: &mut [Option<&'a Node<P, V>>],
+    ) {
+        if let NodeType::Twig(twig) = &cur_node.node_type {
+            for &i in batch {
+                if Self::is_key_prefix_of(twig.key.as_slice(), inputs[i]) {
+                    best[i] = Some(cur_node);
+                }
+            }
+            return;
+        }
+
+        let prefix = cur_node.prefix().as_slice();
+        let depth = depth + prefix.len();
+
+        // Group the inputs continuing through this node by their next byte.
+        let mut start = 0;
+        while start < batch.len() {
+            let input = inputs[batch[start]];
+            if !input[depth - prefix.len()..].starts_with(prefix) {
+                start += 1;
+                continue;
+            }
+            if let Some(matched) = Node::terminator_match(cur_node, input) {
+                best[batch[start]] = Some(matched);
+            }
+
+            let Some(&k) = input.get(depth) else {
+                start += 1;
+                continue;
+            };
+            let mut end = start + 1;
+            while end < batch.len() {
+                let next = inputs[batch[end]];
+                if next.get(depth) != Some(&k) || !next[depth - prefix.len()..].starts_with(prefix)
+                {
+                    break;
+                }
+                if let Some(matched) = Node::terminator_match(cur_node, next) {
+                    best[batch[end]] = Some(matched);
+                }
+                end += 1;
+            }
+
+            if let Some(child) = cur_node.find_child(k) {
+                Node::match_prefixes_recurse(child, inputs, &batch[start..end], depth, best);
+            }
+            start = end;
+        }
     }
 
-    /// Creates an iterator over the Trie's key-value pairs.
+    /// Returns an iterator that iterates over child nodes of the current node.
     ///
-    /// This function creates and returns an iterator that can be used to traverse the key-value pairs
-    /// stored in the Trie. The iterator starts from the root of the Trie.
+    /// This function provides an iterator that traverses through the child nodes of the current node,
+    /// returning tuples of keys and references to child nodes.
     ///
     /// # Returns
     ///
-    /// Returns an `Iter` instance that iterates over the key-value pairs in the Trie.
+    /// Returns a boxed iterator that yields tuples containing keys and references to child nodes.
     ///
-    pub fn iter(&self) -> Iter<P, V> {
-        Iter::new(self.root.as_ref())
+    #[allow(dead_code)]
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (u8, &Arc<Self>)> + '_> {
+        match &self.node_type {
+            NodeType::Node1(n) => Box::new(n.iter()),
+            NodeType::Node4(n) => Box::new(n.iter()),
+            NodeType::Node16(n) => Box::new(n.iter()),
+            NodeType::Node48(n) => Box::new(n.iter()),
+            NodeType::Node256(n) => Box::new(n.iter()),
+            NodeType::Twig(_) => Box::new(std::iter::empty()),
+        }
     }
 
-    /// Returns an iterator over a range of key-value pairs within the Trie.
-    ///
-    /// This function creates and returns an iterator that iterates over key-value pairs in the Trie,
-    /// starting from the provided `start_key` and following the specified `range` bounds. The iterator
-    /// iterates within the specified key range.
-    ///
-    /// # Arguments
+    /// Returns the first child stored in a slot at or after `from`.
     ///
-    /// * `range` - A range that specifies the bounds for iterating over key-value pairs.
+    /// Slots are ordered by key, so stepping through them with the returned slot
+    /// plus one visits the children in key order without allocating an iterator.
     ///
     /// # Returns
     ///
-    /// Returns a `Range` iterator instance that iterates over the key-value pairs within the given range.
-    /// If the Trie is empty, an empty `Range` iterator is returned.
+    /// Returns the slot of the child along with the child, or `None` if there are no
+    /// more children.
     ///
-    pub fn range<'a, R>(
-        &'a self,
-        range: R,
-    ) -> impl Iterator<Item = (Vec<u8>, &'a V, &'a u64, &'a u64)>
-    where
-        R: RangeBounds<P> + 'a,
-    {
-        // If the Trie is empty, return an empty Range iterator
-        if self.root.is_none() {
-            return Range::empty(range);
+    pub(crate) fn next_child(&self, from: usize) -> Option<(usize, &Arc<Self>)> {
+        match &self.node_type {
+            NodeType::Node1(n) => n.next_child(from),
+            NodeType::Node4(n) => n.next_child(from),
+            NodeType::Node16(n) => n.next_child(from),
+            NodeType::Node48(n) => n.next_child(from),
+            NodeType::Node256(n) => n.next_child(from),
+            NodeType::Twig(_) => None,
         }
-
-        let root = self.root.as_ref();
-        Range::new(root, range)
     }
 
-    fn is_closed(&self) -> Result<(), TrieError> {
-        if self.closed {
-            return Err(TrieError::SnapshotAlreadyClosed);
+    /// Returns the number of keys below the node, counting only the subtrees whose
+    /// count is not cached yet, and caching the counts it computes.
+    pub(crate) fn cached_count(&self) -> usize {
+        if self.is_twig() {
+            return 1;
         }
-        Ok(())
+        let cached = self.count.load(AtomicOrdering::Relaxed);
+        if cached != 0 {
+            return cached;
+        }
+        let count = self.iter().map(|(_, child)| child.cached_count()).sum();
+        self.count.store(count, AtomicOrdering::Relaxed);
+        count
     }
 
-    /// Closes the tree, preventing further modifications, and releases associated resources.
-    pub fn close(&mut self) -> Result<(), TrieError> {
-        // Check if the tree is already closed
-        self.is_closed()?;
-
-        // Check if there are any active readers for the snapshot
-        if self.snapshot_count() > 0 {
-            return Err(TrieError::SnapshotNotClosed);
+    /// Returns the number of twigs, and so of keys, in the subtree rooted at this node.
+    ///
+    /// The subtree is walked with an explicit stack of nodes, without visiting keys
+    /// or values.
+    pub(crate) fn count_twigs(&self) -> usize {
+        let mut count = 0;
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            if node.is_twig() {
+                count += 1;
+                continue;
+            }
+            let mut slot = 0;
+            while let Some((pos, child)) = node.next_child(slot) {
+                stack.push(child);
+                slot = pos + 1;
+            }
         }
-
-        // Mark the snapshot as closed
-        self.closed = true;
-
-        Ok(())
+        count
     }
-}
 
-/*
-    Test cases for Adaptive Radix Tree
-*/
+    /// Appends the keys below this node within Levenshtein distance `max_dist` of
+    /// `query` to `out`, with their latest values, in key order.
+    ///
+    /// `row` holds the edit distances between the bytes on the path to this node
+    /// and every prefix of `query`. It is extended by a row per byte of the
+    /// node's prefix, and the subtree is skipped as soon as no entry of the row
+    /// is within `max_dist`, since more bytes never lower the smallest entry.
+    pub(crate) fn collect_within_distance(
+        &self,
+        query: &[u8],
+        max_dist: usize,
+        row: &[usize],
+        out: &mut Vec<(P, V)>,
+    ) {
+        let mut row = row.to_vec();
+        for &byte in self.prefix().as_slice() {
+            let mut next = Vec::with_capacity(row.len());
+            next.push(row[0] + 1);
+            for (i, &q) in query.iter().enumerate() {
+                let substitute = row[i] + usize::from(q != byte);
+                next.push(substitute.min(row[i + 1] + 1).min(next[i] + 1));
+            }
+            row = next;
+            if row.iter().all(|&dist| dist > max_dist) {
+                return;
+            }
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::{Tree, KV};
-    use crate::{FixedSizeKey, VariableSizeKey};
-    use std::str::FromStr;
+        match &self.node_type {
+            NodeType::Twig(twig) => {
+                if row[query.len()] <= max_dist {
+                    if let Some(value) = twig.get_latest_value() {
+                        out.push((twig.key.clone(), value.clone()));
+                    }
+                }
+            }
+            _ => {
+                for (_, child) in self.iter() {
+                    child.collect_within_distance(query, max_dist, &row, out);
+                }
+            }
+        }
+    }
 
-    use std::fs::File;
-    use std::io::{self, BufRead, BufReader};
+    /// Returns the latest values of the keys in the subtree rooted at this node.
+    ///
+    /// Like `count_twigs`, the subtree is walked with an explicit stack, so the
+    /// values are borrowed without cloning keys. They come in no particular order.
+    pub(crate) fn latest_values(&self) -> impl Iterator<Item = &V> {
+        let mut stack = vec![self];
+        std::iter::from_fn(move || {
+            while let Some(node) = stack.pop() {
+                if let NodeType::Twig(twig) = &node.node_type {
+                    match twig.get_latest_value() {
+                        Some(value) => return Some(value),
+                        None => continue,
+                    }
+                }
+                let mut slot = 0;
+                while let Some((pos, child)) = node.next_child(slot) {
+                    stack.push(child);
+                    slot = pos + 1;
+                }
+            }
+            None
+        })
+    }
 
-    fn read_words_from_file(file_path: &str) -> io::Result<Vec<String>> {
-        let file = File::open(file_path)?;
-        let reader = BufReader::new(file);
-        let words: Vec<String> = reader.lines().filter_map(|line| line.ok()).collect();
-        Ok(words)
+    /// Adds the keys in the subtree rooted at this node to `counts`, grouped by
+    /// their first `prefix_len` bytes. Keys shorter than that are grouped by the
+    /// whole key.
+    ///
+    /// `path` holds the key bytes spelled by the prefixes of the ancestors, and is
+    /// restored before returning. Once the path is `prefix_len` bytes long all
+    /// keys below share a group, and the rest of the subtree is only counted,
+    /// through the cached counts if `cached` is set.
+    pub(crate) fn group_counts(
+        &self,
+        prefix_len: usize,
+        cached: bool,
+        path: &mut Vec<u8>,
+        counts: &mut HashMap<Vec<u8>, usize>,
+    ) {
+        let depth = path.len();
+        path.extend_from_slice(self.prefix().as_slice());
+        if path.len() >= prefix_len || self.is_twig() {
+            let group = &path[..prefix_len.min(path.len())];
+            let count = if cached {
+                self.cached_count()
+            } else {
+                self.count_twigs()
+            };
+            *counts.entry(group.to_vec()).or_default() += count;
+        } else {
+            let mut slot = 0;
+            while let Some((pos, child)) = self.next_child(slot) {
+                child.group_counts(prefix_len, cached, path, counts);
+                slot = pos + 1;
+            }
+        }
+        path.truncate(depth);
     }
 
-    #[test]
-    fn insert_search_delete_words() {
-        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
-        let file_path = "testdata/words.txt";
+    /// Checks the invariants of the subtree rooted at this node.
+    ///
+    /// `path` holds the key bytes spelled by the prefixes of the ancestors, and is
+    /// restored before returning. Every twig found is passed to `visit`.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn verify_recurse(
+        node: &Arc<Self>,
+        path: &mut Vec<u8>,
+        visit: &mut dyn FnMut(&TwigNode<P, V>) -> Result<(), TrieError>,
+    ) -> Result<(), TrieError> {
+        let invalid = |path: &[u8], reason| {
+            Err(TrieError::InvalidStructure {
+                path: path.to_vec(),
+                reason,
+            })
+        };
 
-        if let Ok(words) = read_words_from_file(file_path) {
-            // Insertion phase
-            for word in &words {
-                let key = &VariableSizeKey::from_str(word).unwrap();
-                tree.insert(key, 1, 0, 0);
+        let depth = path.len();
+        path.extend_from_slice(node.prefix().as_slice());
+        if let NodeType::Twig(twig) = &node.node_type {
+            if twig.key.as_slice() != path.as_slice() {
+                return invalid(path, "twig key does not match its path");
             }
-
-            // Search phase
-            for word in &words {
-                let key = VariableSizeKey::from_str(word).unwrap();
-                let (_, val, _, _) = tree.get(&key, 0).unwrap();
-                assert_eq!(val, 1);
+            if twig.values.is_empty() {
+                return invalid(path, "twig has no values");
             }
-
-            // Deletion phase
-            for word in &words {
-                let key = VariableSizeKey::from_str(word).unwrap();
-                assert!(tree.remove(&key).unwrap());
+            if twig
+                .values
+                .iter()
+                .zip(twig.values.iter().skip(1))
+                .any(|(a, b)| a.version > b.version)
+            {
+                return invalid(path, "twig values are not sorted by version");
             }
-        } else if let Err(err) = read_words_from_file(file_path) {
-            eprintln!("Error reading file: {}", err);
+            visit(twig)?;
+            path.truncate(depth);
+            return Ok(());
         }
 
-        assert_eq!(tree.version(), 0);
+        if node.num_children() == 0 {
+            return invalid(path, "inner node has no children");
+        }
+        let mut last_key = None;
+        for (k, child) in node.iter() {
+            if last_key.is_some_and(|last| last >= k) {
+                return invalid(path, "children are not sorted by key");
+            }
+            last_key = Some(k);
+            if child.prefix().len() == 0 || child.prefix().at(0) != k {
+                return invalid(path, "child prefix does not start with its key");
+            }
+            if child.version() > node.version() {
+                return invalid(path, "child is newer than its parent");
+            }
+            Node::verify_recurse(child, path, visit)?;
+        }
+        path.truncate(depth);
+        Ok(())
     }
 
-    #[test]
-    fn string_insert_delete() {
-        let mut tree = Tree::<VariableSizeKey, i32>::new();
+    /// Adds the keys of the subtree rooted at this node, found at `depth`, to `stats`.
+    pub(crate) fn depth_recurse(&self, depth: usize, stats: &mut DepthStats) {
+        if let NodeType::Twig(twig) = &self.node_type {
+            stats.keys += 1;
+            stats.max_depth = stats.max_depth.max(depth);
+            stats.total_depth += depth;
+            if depth > twig.key.len() + 1 {
+                stats.keys_deeper_than_length += 1;
+            }
+            return;
+        }
+        if self.num_children() == 1 {
+            stats.single_child_nodes += 1;
+        }
+        for (_, child) in self.iter() {
+            child.depth_recurse(depth + 1, stats);
+        }
+    }
 
-        // Insertion phase
-        let insert_words = [
-            "a", "aa", "aal", "aalii", "abc", "abcd", "abcde", "xyz", "axyz",
-        ];
+    /// Returns the child with the smallest key greater than `key`, or `None` if
+    /// there is none.
+    pub(crate) fn child_after(&self, key: u8) -> Option<&Arc<Self>> {
+        match &self.node_type {
+            NodeType::Node1(n) => n.child_after(key),
+            NodeType::Node4(n) => n.child_after(key),
+            NodeType::Node16(n) => n.child_after(key),
+            NodeType::Node48(n) => n.child_after(key),
+            NodeType::Node256(n) => n.child_after(key),
+            NodeType::Twig(_) => None,
+        }
+    }
 
-        for word in &insert_words {
-            tree.insert(&VariableSizeKey::from_str(word).unwrap(), 1, 0, 0);
+    /// Returns the child with the greatest key, or `None` if the node has no children.
+    pub(crate) fn last_child(&self) -> Option<&Arc<Self>> {
+        match &self.node_type {
+            NodeType::Node1(n) => n.last_child(),
+            NodeType::Node4(n) => n.last_child(),
+            NodeType::Node16(n) => n.last_child(),
+            NodeType::Node48(n) => n.last_child(),
+            NodeType::Node256(n) => n.last_child(),
+            NodeType::Twig(_) => None,
         }
+    }
 
-        // Deletion phase
-        for word in &insert_words {
-            assert!(tree
-                .remove(&VariableSizeKey::from_str(word).unwrap())
-                .unwrap());
+    /// Returns an iterator over the child slots of the current node.
+    ///
+    /// Unlike `iter`, this does not assume that every occupied key has a child,
+    /// and yields `None` for keys whose child slot is empty.
+    ///
+    /// # Returns
+    ///
+    /// Returns a boxed iterator that yields tuples containing keys and optional references to child nodes.
+    ///
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn iter_checked(&self) -> Box<dyn Iterator<Item = (u8, Option<&Arc<Self>>)> + '_> {
+        match &self.node_type {
+            NodeType::Node1(n) => Box::new(n.iter_checked()),
+            NodeType::Node4(n) => Box::new(n.iter_checked()),
+            NodeType::Node16(n) => Box::new(n.iter_checked()),
+            NodeType::Node48(n) => Box::new(n.iter_checked()),
+            NodeType::Node256(n) => Box::new(n.iter().map(|(k, child)| (k, Some(child)))),
+            NodeType::Twig(_) => Box::new(std::iter::empty()),
         }
     }
+}
 
-    #[test]
-    fn string_long() {
-        let mut tree = Tree::<VariableSizeKey, i32>::new();
+/// A struct representing an Adaptive Radix Trie.
+///
+/// The `Tree` struct encompasses the entire adaptive radix trie data structure.
+/// It manages the root node of the tree, maintains snapshots of the tree's state,
+/// and keeps track of various properties related to snapshot management.
+///
+/// # Type Parameters
+///
+/// - `P`: A type implementing the `KeyTrait` trait, defining the prefix traits for nodes.
+/// - `V`: The type of value associated with nodes.
+///
+/// # Fields
+///
+/// - `root`: An optional shared reference (using `Rc`) to the root node of the tree.
+/// - `snapshots`: The registry of active snapshots, shared with the snapshots themselves.
+/// - `prefix_locks`: The advisory locks held on key prefixes.
+/// - `hash_index`: An optional hash index from keys to their twig nodes.
+/// - `version_pins`: The versions protected from pruning.
+///
+pub struct Tree<P: KeyTrait, V: Clone> {
+    /// An optional shared reference to the root node of the tree.
+    pub(crate) root: Option<Arc<Node<P, V>>>,
+    /// The registry assigning snapshot IDs and tracking the active snapshots.
+    pub(crate) snapshots: Arc<SnapshotRegistry>,
+    /// A flag indicating whether the tree is closed.
+    pub(crate) closed: bool,
+    /// Advisory locks held on key prefixes by external writers.
+    pub(crate) prefix_locks: Arc<PrefixLockTable>,
+    /// An optional hash index used to skip the trie descent on lookups.
+    pub(crate) hash_index: Option<HashIndex<P, V>>,
+    /// Versions of individual keys protected from pruning.
+    pub(crate) version_pins: Arc<VersionPinTable>,
+    /// Counters of node copies made by recent inserts.
+    pub(crate) pressure: PressureTracker,
+    /// An optional sink receiving every mutation of the tree.
+    pub(crate) recorder: Option<Box<dyn OpSink<P, V>>>,
+    /// An optional hook notified of every key-value pair written or removed.
+    pub(crate) index_hook: Option<Box<dyn IndexHook<P, V>>>,
+    /// Optional statistics kept for the first segment of every key.
+    pub(crate) prefix_stats: Option<PrefixStatsTable>,
+    /// An optional index of the reversed keys, used for suffix scans.
+    pub(crate) suffix_index: Option<SuffixIndex>,
+    /// What inserts do with a timestamp the key already has a version at.
+    pub(crate) duplicate_ts_policy: DuplicateTsPolicy,
+    /// How many versions of each key inserts keep.
+    pub(crate) versioning: VersioningStrategy,
+    /// The clock handing out versions, if they are shared with other Trees.
+    pub(crate) clock: Option<Arc<SharedClock>>,
+    /// An optional hook applied to every key before it is used.
+    pub(crate) normalizer: Option<Arc<dyn KeyNormalizer>>,
+    /// The normalization the normalizer applies, if it was set by the options.
+    pub(crate) key_normalization: Option<KeyNormalization>,
+    /// Number of versions at which a key is reported as overloaded, if tracked.
+    pub(crate) version_warn_threshold: Option<usize>,
+    /// Keys holding at least `version_warn_threshold` versions.
+    pub(crate) overloaded_keys: BTreeSet<P>,
+    /// Compares a written value with the latest value of its key, if writes of
+    /// an unchanged value are skipped.
+    pub(crate) value_eq: Option<fn(&V, &V) -> bool>,
+    /// Checks a value read from the Trie against the checksum kept with it, if
+    /// value checksums are kept; see `TreeOptions::value_checksums`.
+    pub(crate) value_check: Option<fn(&V) -> bool>,
+    /// The type new inner nodes are created as and never shrink below, if the
+    /// layout is forced.
+    pub(crate) forced_node_type: Option<NodeKind>,
+    /// Read counts per key prefix, if tracked.
+    pub(crate) read_frequency: Option<Arc<ReadFrequency>>,
+    /// Whether snapshots count their reads in `read_frequency`.
+    pub(crate) count_snapshot_reads: bool,
+    /// The nodes recent inserts may update in place, if the Trie was built with
+    /// `TreeOptions::cow_batch_window`.
+    pub(crate) cow_window: Option<CowWindow>,
+    /// The last id handed out by `insert_external`, so that ids are not reused
+    /// once the Trie is emptied and its version starts over.
+    pub(crate) last_leaf_id: u64,
+    /// Deadlines after which the keys under a prefix read as absent.
+    pub(crate) expiry: ExpiryTable,
+    /// The tombstones of the removed keys, for reads of past timestamps.
+    pub(crate) tombstones: Tombstones,
+    /// The latest timestamp written to the Trie or passed to `advance_ts`,
+    /// against which expiry deadlines are checked.
+    pub(crate) current_ts: u64,
+    /// The latest timestamp written to each key segment, if the Trie keeps
+    /// timestamp domains.
+    pub(crate) ts_domains: Option<TsDomains>,
+    /// The invariant checks run on every operation, if the Trie is in strict
+    /// mode.
+    pub(crate) invariant_checks: Option<InvariantChecks>,
+    /// The first violation found by the checks, which fails every later
+    /// operation.
+    pub(crate) poison: OnceLock<PoisonReport>,
+    /// The oldest version at which reads still see every key as it was, raised
+    /// when values are pruned or keys removed.
+    pub(crate) servable_from: u64,
+    /// Whether counts of keys are cached in the nodes, as `Tree::with_counts`
+    /// sets.
+    pub(crate) cached_counts: bool,
+    /// How the ingest paths validate timestamps, if they do.
+    pub(crate) ingest_policy: Option<IngestPolicy>,
+    /// The largest timestamp the ingest paths accept, if limited.
+    pub(crate) max_valid_ts: Option<u64>,
+    /// The writes prepared with `prepare_insert` and not yet published.
+    pub(crate) prepared: PreparedChain<P, V>,
+}
 
-        // Insertion phase
-        let words_to_insert = [
-            ("amyelencephalia", 1),
-            ("amyelencephalic", 2),
-            ("amyelencephalous", 3),
-        ];
+/// A write inserted into a root built aside from the Trie by
+/// `Tree::build_writes`, with what the bookkeeping of the Trie needs once the
+/// root is swapped in.
+pub(crate) struct BuiltWrite<P, V> {
+    pub(crate) key: P,
+    pub(crate) version: u64,
+    pub(crate) ts: u64,
+    stats: InsertStats,
+    old_leaf: Option<Arc<LeafValue<V>>>,
+    // The value kept for the recorder, if the write is recorded.
+    pub(crate) recorded_value: Option<V>,
+    // The value kept for the index hook, if the Trie has one.
+    hooked_value: Option<V>,
+}
 
-        for (word, val) in &words_to_insert {
-            tree.insert(&VariableSizeKey::from_str(word).unwrap(), *val, 0, 0);
-        }
+pub struct KV<P, V> {
+    pub key: P,
+    pub value: V,
+    pub version: u64,
+    pub ts: u64,
+}
 
-        // Verification phase
-        for (word, expected_val) in &words_to_insert {
-            let (_, val, _, _) = tree
-                .get(&VariableSizeKey::from_str(word).unwrap(), 0)
-                .unwrap();
-            assert_eq!(val, *expected_val);
+/// A node on the path from the root of a Trie to a key, as reported by
+/// `Tree::path_of`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathSegment {
+    /// The type of the node, as returned by `Node::node_type_name`.
+    pub node_type: String,
+    /// The partial key stored in the node. Below the root it starts with the
+    /// byte the parent took to reach the node.
+    pub partial: Vec<u8>,
+    /// The offset into the key where `partial` starts.
+    pub depth: usize,
+    /// The key byte taken to the next node, or `None` for the twig node.
+    pub child: Option<u8>,
+    /// The number of children of the node.
+    pub num_children: usize,
+    /// Whether the node is also referenced by an open snapshot or reader.
+    pub shared: bool,
+}
+
+/// A value stored together with metadata about the write that produced it.
+///
+/// A Tree whose values are `WithMeta<V, M>` keeps an `M` with every version of
+/// every key, and can be written and read through `insert_with_meta` and
+/// `get_with_meta`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WithMeta<V, M> {
+    pub value: V,
+    pub meta: M,
+}
+
+impl<P: KeyTrait, V: Clone> KV<P, V> {
+    pub fn new(key: P, value: V, version: u64, timestamp: u64) -> Self {
+        KV {
+            key,
+            value,
+            version,
+            ts: timestamp,
         }
     }
+}
 
-    #[test]
-    fn root_set_get() {
-        let mut tree = Tree::<VariableSizeKey, i32>::new();
+/// The outcome of a write, as returned by `Tree::insert_with_outcome`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InsertOutcome<V> {
+    /// The previous latest value of the key, if any.
+    pub old_value: Option<V>,
+    /// Whether a new version was created. False if the write repeated the latest
+    /// value of the key on a Trie that skips such writes.
+    pub created: bool,
+    /// The version the value is stored under: the new version, or the version
+    /// of the latest value the write repeated.
+    pub version: u64,
+    /// The timestamp of that version.
+    pub ts: u64,
+    /// The number of nodes the write copied. A write that repeated the latest
+    /// value copies none.
+    pub nodes_copied: u64,
+}
 
-        // Insertion phase
-        let key = VariableSizeKey::from_str("abc").unwrap();
-        let value = 1;
-        tree.insert(&key, value, 0, 0);
+/// How a write changed the shape of a Trie, as returned by `Tree::insert_detailed`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InsertDetails<V> {
+    /// The previous latest value of the key, if any.
+    pub old_value: Option<V>,
+    /// The node that grew into a larger type to take the key, if any.
+    pub upgrade: Option<NodeUpgrade>,
+    /// Whether the prefix of a node was split to make room for the key.
+    pub prefix_split: bool,
+    /// Whether the key was new, so that a leaf was created for it.
+    pub new_leaf: bool,
+    /// The number of inner nodes above the leaf of the key.
+    pub depth: usize,
+}
 
-        // Verification phase
-        let (_, val, _ts, _) = tree.get(&key, 0).unwrap();
-        assert_eq!(val, value);
-    }
+/// A node that grew into a larger type, as reported by `Tree::insert_detailed`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeUpgrade {
+    /// The type of the node before.
+    pub from: NodeKind,
+    /// The type of the node after.
+    pub to: NodeKind,
+}
 
-    #[test]
-    fn string_duplicate_insert() {
-        let mut tree = Tree::<VariableSizeKey, i32>::new();
+/// Why `Tree::bulk_load` or `Tree::bulk_append` stopped at an entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BulkLoadReason {
+    /// The key sorts before the key of the previous entry, or before the
+    /// largest key of the Trie for the first entry of an append.
+    OutOfOrder,
+    /// The key equals the key of the previous entry, or the largest key of the
+    /// Trie for the first entry of an append.
+    Duplicate,
+    /// Inserting the entry failed.
+    Trie(TrieError),
+}
+
+impl fmt::Display for BulkLoadReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BulkLoadReason::OutOfOrder => write!(f, "key sorts before the previous key"),
+            BulkLoadReason::Duplicate => write!(f, "key repeats the previous key"),
+            BulkLoadReason::Trie(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// A bulk load stopped by an invalid entry, along with the Trie loaded so far.
+///
+/// The Trie holds every entry before `index` and nothing from it on, and is
+/// consistent, so the load can be resumed by fixing the input from `index` on
+/// and passing it to `Tree::bulk_append`.
+pub struct BulkLoadError<P: KeyTrait, V: Clone> {
+    /// The Trie with the entries loaded before the failure.
+    pub tree: Tree<P, V>,
+    /// The position of the failing entry in the input.
+    pub index: usize,
+    /// The key of the failing entry.
+    pub key: P,
+    pub reason: BulkLoadReason,
+}
+
+impl<P: KeyTrait, V: Clone> fmt::Debug for BulkLoadError<P, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BulkLoadError")
+            .field("index", &self.index)
+            .field("key", &self.key)
+            .field("reason", &self.reason)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<P: KeyTrait, V: Clone> fmt::Display for BulkLoadError<P, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Bulk load stopped at entry {} with key {:?}: {}",
+            self.index,
+            self.key.as_slice(),
+            self.reason
+        )
+    }
+}
+
+impl<P: KeyTrait, V: Clone> Error for BulkLoadError<P, V> {}
+
+impl<P: KeyTrait + Clone, V: Clone> NodeType<P, V> {
+    fn clone(&self) -> Self {
+        match self {
+            // twig value not actually cloned
+            NodeType::Twig(twig) => NodeType::Twig(twig.clone()),
+            NodeType::Node1(n) => NodeType::Node1(n.clone()),
+            NodeType::Node4(n) => NodeType::Node4(n.clone()),
+            NodeType::Node16(n) => NodeType::Node16(n.clone()),
+            NodeType::Node48(n) => NodeType::Node48(n.clone()),
+            NodeType::Node256(n) => NodeType::Node256(n.clone()),
+        }
+    }
+}
+
+/// Trees are equal when they hold the same keys with the same latest values.
+///
+/// Older versions, timestamps, snapshots and the shape of the nodes are not
+/// compared. Subtrees the two Trees share, such as between a Tree and a snapshot
+/// turned back into one, are skipped without being visited.
+impl<P: KeyTrait, V: Clone + PartialEq> PartialEq for Tree<P, V> {
+    fn eq(&self, other: &Self) -> bool {
+        same_content(self.root.as_ref(), other.root.as_ref())
+    }
+}
+
+impl<P: KeyTrait, V: Clone + Eq> Eq for Tree<P, V> {}
+
+// Default implementation for the Tree struct
+impl<P: KeyTrait, V: Clone> Default for Tree<P, V> {
+    fn default() -> Self {
+        Tree::new()
+    }
+}
+
+impl<P: KeyTrait, V: Clone> Tree<P, V> {
+    pub fn new() -> Self {
+        Tree {
+            root: None,
+            snapshots: Arc::new(SnapshotRegistry::new(DEFAULT_MAX_ACTIVE_SNAPSHOTS)),
+            closed: false,
+            prefix_locks: Arc::new(PrefixLockTable::new()),
+            hash_index: None,
+            version_pins: Arc::new(VersionPinTable::new()),
+            pressure: PressureTracker::new(TreeOptions::default()),
+            recorder: None,
+            index_hook: None,
+            prefix_stats: None,
+            suffix_index: None,
+            duplicate_ts_policy: DuplicateTsPolicy::default(),
+            versioning: VersioningStrategy::default(),
+            clock: None,
+            normalizer: None,
+            key_normalization: None,
+            version_warn_threshold: None,
+            overloaded_keys: BTreeSet::new(),
+            value_eq: None,
+            value_check: None,
+            forced_node_type: None,
+            read_frequency: None,
+            count_snapshot_reads: false,
+            cow_window: None,
+            last_leaf_id: 0,
+            expiry: ExpiryTable::new(),
+            tombstones: Tombstones::new(),
+            current_ts: 0,
+            ts_domains: None,
+            invariant_checks: None,
+            poison: OnceLock::new(),
+            servable_from: 0,
+            cached_counts: false,
+            ingest_policy: None,
+            max_valid_ts: None,
+            prepared: PreparedChain::default(),
+        }
+    }
+
+    /// Creates a new Trie with the given options.
+    ///
+    /// Invalid options are replaced with the nearest valid ones, as given by
+    /// `TreeOptions::clamped`; `try_with_options` rejects them instead.
+    pub fn with_options(options: TreeOptions) -> Self {
+        let options = options.clamped();
+        Tree {
+            snapshots: Arc::new(SnapshotRegistry::new(options.max_active_snapshots)),
+            hash_index: options
+                .hash_index
+                .then(|| HashIndex::new(RandomState::new())),
+            cached_counts: options.cached_counts,
+            forced_node_type: options.forced_node_type,
+            normalizer: options.key_normalization.map(KeyNormalization::normalizer),
+            key_normalization: options.key_normalization,
+            pressure: PressureTracker::new(options),
+            prefix_stats: options.prefix_stats_delimiter.map(PrefixStatsTable::new),
+            suffix_index: options.suffix_index.then(SuffixIndex::new),
+            duplicate_ts_policy: options.duplicate_ts_policy,
+            versioning: options.versioning,
+            version_warn_threshold: options.version_warn_threshold,
+            read_frequency: options
+                .read_frequency_depth
+                .map(|depth| Arc::new(ReadFrequency::new(depth))),
+            count_snapshot_reads: options.count_snapshot_reads,
+            cow_window: options.cow_batch_window.map(CowWindow::new),
+            invariant_checks: options.invariant_checks,
+            ingest_policy: options.ingest_policy,
+            max_valid_ts: options.max_valid_ts,
+            ts_domains: options.ts_domain_delimiter.map(TsDomains::new),
+            ..Tree::new()
+        }
+    }
+
+    /// Creates a new Trie with the given options, once they are validated.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of `TreeOptions::validate` if the options are invalid.
+    ///
+    pub fn try_with_options(options: TreeOptions) -> Result<Self, ReconfigureError> {
+        options.validate()?;
+        Ok(Tree::with_options(options))
+    }
+
+    /// Returns the options the Trie runs with, as built or last changed by
+    /// `reconfigure`.
+    ///
+    /// A normalizer passed to `with_normalizer` is not an option, so the
+    /// options report no key normalization for it.
+    pub fn options(&self) -> TreeOptions {
+        let pressure = self.pressure.options();
+        TreeOptions {
+            pressure_window: pressure.pressure_window,
+            pressure_medium: pressure.pressure_medium,
+            pressure_high: pressure.pressure_high,
+            prefix_stats_delimiter: self.prefix_stats.as_ref().map(|table| table.delimiter()),
+            suffix_index: self.suffix_index.is_some(),
+            duplicate_ts_policy: self.duplicate_ts_policy,
+            version_warn_threshold: self.version_warn_threshold,
+            read_frequency_depth: self.read_frequency.as_ref().map(|freq| freq.depth()),
+            count_snapshot_reads: self.count_snapshot_reads,
+            cow_batch_window: self.cow_window.as_ref().map(|window| window.limit()),
+            invariant_checks: self.invariant_checks,
+            max_valid_ts: self.max_valid_ts,
+            ingest_policy: self.ingest_policy,
+            ts_domain_delimiter: self.ts_domains.as_ref().map(|domains| domains.delimiter()),
+            versioning: self.versioning,
+            hash_index: self.hash_index.is_some(),
+            cached_counts: self.cached_counts,
+            forced_node_type: self.forced_node_type,
+            key_normalization: self.key_normalization,
+            max_active_snapshots: self.snapshots.max_active(),
+            value_checksums: self.value_check.is_some(),
+        }
+    }
+
+    /// Changes the options set in `patch` on the live Trie.
+    ///
+    /// See `OptionsPatch` for the options that can change at runtime. The
+    /// options are checked as a whole before any is changed, so on error the
+    /// Trie keeps running with its current options.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ReconfigureError::RequiresRebuild` if the patch changes an
+    /// option that is fixed when the Trie is built, the error of
+    /// `TreeOptions::validate` if the resulting options are invalid, and
+    /// `ReconfigureError::TreeAlreadyClosed` if the Trie is closed.
+    ///
+    pub fn reconfigure(&mut self, patch: OptionsPatch) -> Result<(), ReconfigureError> {
+        if self.closed {
+            return Err(ReconfigureError::TreeAlreadyClosed);
+        }
+        let current = self.options();
+        let options = patch.apply_to(current);
+        let fixed = [
+            (
+                "prefix_stats_delimiter",
+                options.prefix_stats_delimiter != current.prefix_stats_delimiter,
+            ),
+            ("suffix_index", options.suffix_index != current.suffix_index),
+            (
+                "read_frequency_depth",
+                options.read_frequency_depth != current.read_frequency_depth,
+            ),
+            (
+                "ts_domain_delimiter",
+                options.ts_domain_delimiter != current.ts_domain_delimiter,
+            ),
+            ("hash_index", options.hash_index != current.hash_index),
+            (
+                "cached_counts",
+                options.cached_counts != current.cached_counts,
+            ),
+            (
+                "forced_node_type",
+                options.forced_node_type != current.forced_node_type,
+            ),
+            (
+                "key_normalization",
+                options.key_normalization != current.key_normalization,
+            ),
+            (
+                "value_checksums",
+                options.value_checksums != current.value_checksums,
+            ),
+        ];
+        if let Some((option, _)) = fixed.iter().find(|(_, changed)| *changed) {
+            return Err(ReconfigureError::RequiresRebuild { option });
+        }
+        options.validate()?;
+
+        self.pressure.set_options(options);
+        self.duplicate_ts_policy = options.duplicate_ts_policy;
+        self.versioning = options.versioning;
+        self.count_snapshot_reads = options.count_snapshot_reads;
+        self.invariant_checks = options.invariant_checks;
+        self.max_valid_ts = options.max_valid_ts;
+        self.ingest_policy = options.ingest_policy;
+        self.snapshots.set_max_active(options.max_active_snapshots);
+        if options.cow_batch_window != current.cow_batch_window {
+            self.close_cow_window();
+            self.cow_window = options.cow_batch_window.map(CowWindow::new);
+        }
+        if options.version_warn_threshold != current.version_warn_threshold {
+            self.version_warn_threshold = options.version_warn_threshold;
+            self.recount_overloaded_keys();
+        }
+        Ok(())
+    }
+
+    /// Checks every key against the version warning threshold again.
+    fn recount_overloaded_keys(&mut self) {
+        self.overloaded_keys.clear();
+        let (Some(threshold), Some(root)) = (self.version_warn_threshold, &self.root) else {
+            return;
+        };
+        let mut twigs = Vec::new();
+        Node::collect_twigs(root, &mut twigs);
+        for twig in &twigs {
+            if let NodeType::Twig(twig) = &twig.node_type {
+                if twig.values.len() >= threshold {
+                    self.overloaded_keys.insert(twig.key.clone());
+                }
+            }
+        }
+    }
+
+    /// Creates a new Trie with a hash index over its keys.
+    ///
+    /// The index maps the hash of every key, computed with `hash_builder`, to the
+    /// twig node that stores it. Lookups go through the index instead of descending
+    /// the trie, which mostly pays off for misses in large keyspaces. The index is
+    /// maintained on every insert and removal, at the cost of extra memory and a
+    /// second descent per write.
+    ///
+    /// `TreeOptions::with_hash_index` keeps the index with the default hasher,
+    /// along with other options.
+    ///
+    pub fn with_hash_index<S: BuildHasher + Send + Sync + 'static>(hash_builder: S) -> Self {
+        Tree {
+            hash_index: Some(HashIndex::new(hash_builder)),
+            ..Tree::new()
+        }
+    }
+
+    /// Creates a new Trie that caches the number of keys below each inner node.
+    ///
+    /// Counts are computed when `count_prefix` or `entry_count_by_prefix` first
+    /// needs them, and kept in the nodes. Writes copy the nodes on the path to the
+    /// key, so the copies start uncounted while the subtrees they share keep their
+    /// counts, and counting again only visits the nodes written since. The cache
+    /// takes a word in every node whether it is used or not.
+    ///
+    pub fn with_counts() -> Self {
+        Tree::with_options(TreeOptions::default().with_counts())
+    }
+
+    /// Creates a new Trie whose inner nodes are all at least of the given type.
+    ///
+    /// New inner nodes are created as `kind` instead of the smallest type, and
+    /// never shrink below it when children are removed; they still grow into
+    /// larger types when full. Forcing `NodeKind::Node256` makes every inner node
+    /// a Node256. This is meant for measuring what adaptive node sizes contribute
+    /// to performance: the Trie behaves the same, but uses more memory. Snapshots
+    /// of the Trie keep the layout for their own writes.
+    ///
+    pub fn with_forced_node_type(kind: NodeKind) -> Self {
+        Tree::with_options(TreeOptions::default().force_node_type(kind))
+    }
+
+    /// Creates a new Trie that normalizes every key with `normalizer`.
+    ///
+    /// Keys are normalized on insert, lookup, removal and in range bounds, on
+    /// the Trie and on its snapshots, so keys with the same normalized form are
+    /// one key. Iterators and scans return keys in their normalized form, and
+    /// methods taking raw key bytes, such as `scan_prefix_with`, match them
+    /// against the normalized keys as they are. An `OwnedSnapshot` exported from
+    /// the Trie looks keys up as given.
+    ///
+    /// `TreeOptions::normalize_keys` sets a normalization that the options
+    /// name, so that it combines with other options and is kept by `freeze`.
+    ///
+    pub fn with_normalizer<N: KeyNormalizer + 'static>(normalizer: N) -> Self {
+        Tree {
+            normalizer: Some(Arc::new(normalizer)),
+            ..Tree::new()
+        }
+    }
+
+    /// Creates a new Trie that notifies `hook` of every key-value pair it writes
+    /// or removes.
+    ///
+    /// Inserts, updates, bulk inserts and bulk loads call `on_insert`, and
+    /// removals of keys and of prefixes call `on_remove` for every key removed.
+    /// Writes skipped because they repeat the latest value, writes made to a
+    /// snapshot, and versions dropped by pruning or expiry do not call the hook.
+    ///
+    pub fn with_index_hook<H: IndexHook<P, V> + 'static>(hook: H) -> Self {
+        Tree {
+            index_hook: Some(Box::new(hook)),
+            ..Tree::new()
+        }
+    }
+
+    /// Applies the normalizer of the Trie, if any, to `key`.
+    pub(crate) fn normalize<'k>(&self, key: &'k P) -> Cow<'k, P> {
+        normalize_key(self.normalizer.as_ref(), key)
+    }
+
+    /// Sets the number of snapshots that can be active at once, as
+    /// `TreeOptions::max_active_snapshots` does.
+    pub fn set_max_active_snapshots(&mut self, max_active_snapshots: u64) {
+        self.snapshots.set_max_active(max_active_snapshots);
+    }
+
+    /// Inserts a new key-value pair with the specified version into the Trie.
+    ///
+    /// This function inserts a new key-value pair into the Trie. If the key already exists,
+    /// the previous value associated with the key is returned. The version `ts` is used to
+    /// ensure proper ordering of values for versioning.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: A reference to the key to be inserted.
+    /// * `value`: The value to be associated with the key.
+    /// * `ts`: The version for the insertion, used for versioning.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(None)` if the key did not exist previously. If the key already existed,
+    /// `Ok(Some(old_value))` is returned, where `old_value` is the previous value associated with the key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the given version is older than the root's current version,
+    /// or `TrieError::PrefixLocked` if the key falls under a locked prefix.
+    ///
+    pub fn insert(
+        &mut self,
+        key: &P,
+        value: V,
+        version: u64,
+        ts: u64,
+    ) -> Result<Option<V>, TrieError> {
+        self.insert_with_owner(None, key, value, version, ts)
+            .map(|outcome| outcome.old_value)
+    }
+
+    /// Inserts a key-value pair like `insert`, and reports whether a version was
+    /// created.
+    ///
+    /// On a Trie built with `dedup_identical_versions`, a write of a value equal
+    /// to the latest value of its key leaves the Trie unchanged and returns the
+    /// version and timestamp of that latest value, with `created` unset. An
+    /// explicit version is still checked against the Trie's version.
+    ///
+    pub fn insert_with_outcome(
+        &mut self,
+        key: &P,
+        value: V,
+        version: u64,
+        ts: u64,
+    ) -> Result<InsertOutcome<V>, TrieError> {
+        self.insert_with_owner(None, key, value, version, ts)
+    }
+
+    /// Inserts a key-value pair on behalf of `owner`.
+    ///
+    /// Behaves like `insert`, except that keys under a prefix locked by `owner`
+    /// are writable. Keys under a prefix locked by another owner are rejected with
+    /// `TrieError::PrefixLocked`.
+    ///
+    pub fn insert_as(
+        &mut self,
+        owner: u64,
+        key: &P,
+        value: V,
+        version: u64,
+        ts: u64,
+    ) -> Result<Option<V>, TrieError> {
+        self.insert_with_owner(Some(owner), key, value, version, ts)
+            .map(|outcome| outcome.old_value)
+    }
+
+    /// Inserts a key-value pair only if the latest version of the key has the
+    /// timestamp `expected_current_ts`.
+    ///
+    /// This is a compare-and-swap on the timestamp of the key rather than on its
+    /// value: a writer that read the key at some timestamp only overwrites it if
+    /// no other write has landed since. An absent key has the timestamp 0. The
+    /// new value is stored at the next version of the Trie, with timestamp
+    /// `new_ts`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::TimestampConflict` with the current timestamp of the
+    /// key, or `None` if it is absent, when it does not match. Otherwise fails
+    /// like `insert`.
+    ///
+    pub fn insert_expect_ts(
+        &mut self,
+        key: &P,
+        value: V,
+        new_ts: u64,
+        expected_current_ts: u64,
+    ) -> Result<(), TrieError> {
+        let current = match self.lookup(key, 0, false) {
+            Ok((_, _, _, ts)) => Some(ts),
+            Err(TrieError::KeyNotFound) => None,
+            Err(err) => return Err(err),
+        };
+        if current.unwrap_or(0) != expected_current_ts {
+            return Err(TrieError::TimestampConflict {
+                expected: expected_current_ts,
+                current,
+            });
+        }
+        self.insert(key, value, 0, new_ts).map(|_| ())
+    }
+
+    /// Updates the latest value of a key in place.
+    ///
+    /// If the key exists, its latest value is cloned, `f` is applied to the copy
+    /// and the result is stored as a new version of the key at timestamp `ts`.
+    /// Older versions keep their values, so snapshots and versioned reads are
+    /// unaffected.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(true)` if the key was updated, or `Ok(false)` if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `insert`.
+    ///
+    pub fn update<F: FnOnce(&mut V)>(&mut self, key: &P, ts: u64, f: F) -> Result<bool, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        if self.root.is_none() {
+            return Ok(false);
+        }
+        let mut value = match self.lookup(key, 0, false) {
+            Ok((_, value, _, _)) => value,
+            Err(TrieError::KeyNotFound) => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        f(&mut value);
+        self.insert(key, value, 0, ts)?;
+        Ok(true)
+    }
+
+    /// Inserts a key-value pair like `insert`, and reports how the insert changed
+    /// the shape of the Trie: whether a node grew into a larger type, whether a
+    /// prefix was split, and how deep the key's leaf ended up.
+    ///
+    /// Meant for tuning bulk loads. The depth is counted by the insert descent;
+    /// only a write skipped by `dedup_identical_versions` looks the key up again.
+    ///
+    pub fn insert_detailed(
+        &mut self,
+        key: &P,
+        value: V,
+        version: u64,
+        ts: u64,
+    ) -> Result<InsertDetails<V>, TrieError> {
+        let mut stats = InsertStats::default();
+        let outcome = self.insert_with_stats(None, key, value, version, ts, &mut stats)?;
+
+        let depth = match outcome.created {
+            true => stats.depth,
+            false => {
+                let key = self.normalize(key);
+                let mut path = Vec::new();
+                let root = self.root.as_ref().expect("the key is present");
+                Node::search_path(root, key.as_ref(), &mut path);
+                path.len() - 1
+            }
+        };
+        Ok(InsertDetails {
+            new_leaf: outcome.old_value.is_none(),
+            old_value: outcome.old_value,
+            upgrade: stats.upgrade.map(|(from, to)| NodeUpgrade { from, to }),
+            prefix_split: stats.prefix_split,
+            depth,
+        })
+    }
+
+    /// Estimates the heap an insert of `key` would add, with a value holding
+    /// `value_size_hint` bytes on the heap, without making it.
+    ///
+    /// The estimate follows the path of the key and reports the leaf or version
+    /// the insert would create, the nodes a prefix split or a full node would add,
+    /// and the nodes it would copy, of which those shared with snapshots stay
+    /// alive. It neither allocates nor changes anything, and is meant to reject
+    /// oversized writes up front; see `InsertCostEstimate` for how close it is.
+    ///
+    pub fn estimate_insert_cost(&self, key: &P, value_size_hint: usize) -> InsertCostEstimate {
+        let key = self.normalize(key);
+        cost::estimate(
+            self.root.as_ref(),
+            key.as_ref(),
+            value_size_hint,
+            self.forced_node_type,
+            None,
+        )
+    }
+
+    /// Estimates the heap a batch of inserts would add, like
+    /// `estimate_insert_cost` for each key and value size hint, summed.
+    ///
+    /// A node on the paths of several keys is only priced once, since the
+    /// inserts after the first copy it again but free the previous copy. Keys are
+    /// estimated against the current Trie, so nodes one insert of the batch
+    /// creates for the next are not accounted for. Unlike a single estimate, this
+    /// allocates to remember the nodes seen.
+    ///
+    pub fn estimate_batch_cost<'k>(
+        &self,
+        entries: impl IntoIterator<Item = (&'k P, usize)>,
+    ) -> InsertCostEstimate
+    where
+        P: 'k,
+    {
+        let mut seen = HashSet::new();
+        let mut total = InsertCostEstimate::default();
+        for (key, value_size_hint) in entries {
+            let key = self.normalize(key);
+            total += cost::estimate(
+                self.root.as_ref(),
+                key.as_ref(),
+                value_size_hint,
+                self.forced_node_type,
+                Some(&mut seen),
+            );
+        }
+        total
+    }
+
+    fn insert_with_owner(
+        &mut self,
+        owner: Option<u64>,
+        key: &P,
+        value: V,
+        version: u64,
+        ts: u64,
+    ) -> Result<InsertOutcome<V>, TrieError> {
+        let mut stats = InsertStats::default();
+        self.insert_with_stats(owner, key, value, version, ts, &mut stats)
+    }
+
+    fn insert_with_stats(
+        &mut self,
+        owner: Option<u64>,
+        key: &P,
+        value: V,
+        version: u64,
+        ts: u64,
+        stats: &mut InsertStats,
+    ) -> Result<InsertOutcome<V>, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let key = self.normalize(key);
+        let key = key.as_ref();
+        check_key_len(key)?;
+
+        // Check if the key is locked by another writer
+        self.prefix_locks.check(key.as_slice(), owner)?;
+        // Strict mode checks the nodes the insert descends through.
+        stats.checks = self.invariant_checks;
+        stats.pinned = self.pinned_of(key);
+
+        if let Some((version_of, ts_of)) = self.repeated_version(self.root.as_ref(), key, &value) {
+            // Only an explicit version is checked: resolving version 0 would take
+            // a version from a shared clock.
+            if version != 0 {
+                self.commit_version(version)?;
+            }
+            return Ok(InsertOutcome {
+                old_value: Some(value),
+                created: false,
+                version: version_of,
+                ts: ts_of,
+                nodes_copied: 0,
+            });
+        }
+
+        let recorded_value = self.recorder.as_ref().map(|_| value.clone());
+        let hooked_value = self.index_hook.as_ref().map(|_| value.clone());
+        let commit_version = self.commit_version(version)?;
+        let in_window = self.cow_window.is_some()
+            && self
+                .root
+                .as_ref()
+                .is_some_and(|root| !Node::is_root_slot(root, key));
+        // Every branch swaps the new nodes in only once everything that can panic
+        // is done, and hands back the replaced nodes to drop after the bookkeeping.
+        let (old_root, old_value) = if in_window {
+            let root = self.root.as_mut().expect("the window needs a root");
+            // Nodes are updated in place, so the old value is cloned first.
+            let old_value = Node::find_twig(root, key)
+                .and_then(|twig| match &twig.node_type {
+                    NodeType::Twig(twig) => twig.get_leaf_by_version(commit_version),
+                    _ => None,
+                })
+                .map(|leaf| leaf.value.clone());
+            let old_node = Node::insert_in_window(
+                root,
+                key,
+                value,
+                commit_version,
+                ts,
+                0,
+                self.duplicate_ts_policy,
+                self.versioning,
+                self.forced_node_type,
+                self.cow_window.as_mut().expect("checked above"),
+                stats,
+            )
+            .map_err(|err| self.poison_on_violation(err, stats, key, "insert"))?;
+            (Some(old_node), old_value)
+        } else {
+            let (new_root, old_node) = match &self.root {
+                None => (
+                    Arc::new(Node::new_twig(
+                        key.as_slice().into(),
+                        key.as_slice().into(),
+                        value,
+                        commit_version,
+                        ts,
+                    )),
+                    None,
+                ),
+                Some(root) => {
+                    let (policy, versioning) = (self.duplicate_ts_policy, self.versioning);
+                    if Node::is_root_slot(root, key) {
+                        Node::insert_root_slot(
+                            root,
+                            key,
+                            value,
+                            commit_version,
+                            ts,
+                            policy,
+                            versioning,
+                            stats,
+                        )
+                        .map_err(|err| self.poison_on_violation(err, stats, key, "insert"))?
+                    } else {
+                        match Node::insert_recurse(
+                            root,
+                            key,
+                            value,
+                            commit_version,
+                            ts,
+                            0,
+                            policy,
+                            versioning,
+                            self.forced_node_type,
+                            stats,
+                        ) {
+                            Ok((new_node, old_node)) => (new_node, old_node),
+                            Err(err) => {
+                                return Err(self.poison_on_violation(err, stats, key, "insert"));
+                            }
+                        }
+                    }
+                }
+            };
+
+            let old_value = old_node.map(|leaf| leaf.value.clone());
+            (self.root.replace(new_root), old_value)
+        };
+
+        self.advance_clock(commit_version);
+        self.advance_ts_of(key.as_slice(), ts);
+        self.note_trimmed(stats.replaced, commit_version);
+        self.update_hash_index(key);
+        self.track_version_count(key);
+        self.pressure.record(ts, stats, old_value.is_some());
+        if let Some(prefix_stats) = self.prefix_stats.as_mut() {
+            prefix_stats.on_prune::<V>(key.as_slice(), stats.replaced);
+            prefix_stats.on_insert::<V>(key.as_slice(), old_value.is_none());
+        }
+        if let Some(suffix_index) = self.suffix_index.as_mut().filter(|_| old_value.is_none()) {
+            suffix_index.insert(key.as_slice());
+        }
+        if let Some(value) = recorded_value {
+            // The root version is the version the key was just written at.
+            let version = self.version();
+            self.record(OpRecord::Insert {
+                key: key.clone(),
+                value,
+                version,
+                ts,
+            });
+        }
+        if let (Some(hook), Some(value)) = (self.index_hook.as_mut(), hooked_value) {
+            hook.on_insert(key, &value, old_value.as_ref());
+        }
+        if let Some(window) = self.cow_window.as_mut() {
+            window.record_write();
+        }
+        drop(old_root);
+        Ok(InsertOutcome {
+            old_value,
+            created: true,
+            version: commit_version,
+            ts,
+            nodes_copied: stats.copied,
+        })
+    }
+
+    /// Returns the version and timestamp of the latest value of `key` under
+    /// `root`, if the Trie skips writes of an unchanged value and `value` equals
+    /// it.
+    fn repeated_version(
+        &self,
+        root: Option<&Arc<Node<P, V>>>,
+        key: &P,
+        value: &V,
+    ) -> Option<(u64, u64)> {
+        let value_eq = self.value_eq?;
+        match Node::resolve_get(root, key, 0) {
+            Ok((_, latest, version, ts)) if value_eq(&latest, value) => Some((version, ts)),
+            _ => None,
+        }
+    }
+
+    /// Returns the latest version written to the Trie, or to any Trie sharing its
+    /// clock.
+    pub(crate) fn latest_version(&self) -> u64 {
+        match &self.clock {
+            Some(clock) => clock.now(),
+            None => self.version(),
+        }
+    }
+
+    /// Resolves the version an insert is committed at: the version after the
+    /// latest one for a version of 0, or the given version if it is newer than
+    /// the latest one.
+    fn commit_version(&self, version: u64) -> Result<u64, TrieError> {
+        if version == 0 {
+            return Ok(match &self.clock {
+                Some(clock) => clock.tick(),
+                None => self.version() + 1,
+            });
+        }
+        if self.latest_version() >= version {
+            return Err(TrieError::Other(
+                "given version is older than root's current version".to_string(),
+            ));
+        }
+        Ok(version)
+    }
+
+    /// Moves a shared clock forward to a version that was just written.
+    fn advance_clock(&self, version: u64) {
+        if let Some(clock) = &self.clock {
+            clock.advance_to(version);
+        }
+    }
+
+    /// Returns the pinned versions of `key`, which inserts keep when the
+    /// versioning strategy drops the older versions of the key.
+    fn pinned_of(&self, key: &P) -> Vec<u64> {
+        match self.versioning.limit() {
+            Some(_) => self.version_pins.pinned_of(key.as_slice()),
+            None => Vec::new(),
+        }
+    }
+
+    /// Raises the oldest servable version past the versions of a key that a write
+    /// at `version` dropped to follow the versioning strategy.
+    fn note_trimmed(&mut self, replaced: usize, version: u64) {
+        if replaced > 0 && self.versioning != VersioningStrategy::KeepAll {
+            self.servable_from = self.servable_from.max(version);
+        }
+    }
+
+    /// Refreshes the hash index entry for `key` after a write.
+    fn update_hash_index(&mut self, key: &P) {
+        let (Some(index), Some(root)) = (self.hash_index.as_mut(), self.root.as_ref()) else {
+            return;
+        };
+        match Node::find_twig(root, key) {
+            Some(twig) => index.insert(twig),
+            None => index.remove(key),
+        }
+    }
+
+    /// Records whether `key` has crossed the version warning threshold after a
+    /// write, removal or pruning.
+    fn track_version_count(&mut self, key: &P) {
+        let Some(threshold) = self.version_warn_threshold else {
+            return;
+        };
+        let count = self
+            .root
+            .as_ref()
+            .and_then(|root| Node::find_twig(root, key))
+            .map_or(0, |twig| match &twig.node_type {
+                NodeType::Twig(twig) => twig.values.len(),
+                _ => 0,
+            });
+        if count < threshold {
+            self.overloaded_keys.remove(key);
+        } else if !self.overloaded_keys.contains(key) {
+            self.overloaded_keys.insert(key.clone());
+        }
+    }
+
+    /// Returns a validator for the timestamps of an ingest, if the Trie was
+    /// built to validate them.
+    pub(crate) fn ts_validator(&self) -> Option<TsValidator> {
+        if self.ingest_policy.is_none() && self.max_valid_ts.is_none() {
+            return None;
+        }
+        let validator = TsValidator::new(
+            self.ingest_policy.unwrap_or_default(),
+            self.max_valid_ts,
+            self.duplicate_ts_policy,
+        );
+        let validator = validator.with_clock(self.current_ts);
+        Some(match &self.ts_domains {
+            Some(domains) => validator.with_domains(domains.clone()),
+            None => validator,
+        })
+    }
+
+    /// Returns the latest timestamp among the versions of `key`, if present.
+    fn latest_ts_of(&self, key: &P) -> Option<u64> {
+        let twig = Node::find_twig(self.root.as_ref()?, key)?;
+        match &twig.node_type {
+            NodeType::Twig(twig) => twig.iter().map(|leaf| leaf.ts).max(),
+            _ => None,
+        }
+    }
+
+    /// Inserts several key-value pairs.
+    ///
+    /// On a Trie built with `TreeOptions::validate_ingest` or
+    /// `TreeOptions::max_valid_ts`, the timestamps of all pairs are validated
+    /// before any is inserted, and a pair whose timestamp is repaired is
+    /// inserted with the repaired one.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::InvalidTimestamp` for the first pair with an
+    /// invalid timestamp, with the Trie unchanged.
+    ///
+    pub fn bulk_insert(&mut self, kv_pairs: &[KV<P, V>]) -> Result<(), TrieError> {
+        self.bulk_insert_with(kv_pairs, false)
+    }
+
+    /// Inserts several key-value pairs like `bulk_insert`. If `atomic` is set, a
+    /// pair that fails to insert leaves the Trie unchanged instead of keeping
+    /// the pairs before it.
+    pub(crate) fn bulk_insert_with(
+        &mut self,
+        kv_pairs: &[KV<P, V>],
+        atomic: bool,
+    ) -> Result<(), TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let normalized: Vec<KV<P, V>>;
+        let kv_pairs = match &self.normalizer {
+            Some(_) => {
+                normalized = kv_pairs
+                    .iter()
+                    .map(|kv| {
+                        let key = self.normalize(&kv.key).into_owned();
+                        KV::new(key, kv.value.clone(), kv.version, kv.ts)
+                    })
+                    .collect();
+                &normalized[..]
+            }
+            None => kv_pairs,
+        };
+
+        let validated: Vec<KV<P, V>>;
+        let kv_pairs = match self.ts_validator() {
+            Some(mut validator) => {
+                validated = kv_pairs
+                    .iter()
+                    .enumerate()
+                    .map(|(position, kv)| {
+                        let ts = validator.check(kv.key.as_slice(), position, kv.ts, || {
+                            self.latest_ts_of(&kv.key)
+                        })?;
+                        Ok(KV::new(kv.key.clone(), kv.value.clone(), kv.version, ts))
+                    })
+                    .collect::<Result<_, TrieError>>()?;
+                &validated[..]
+            }
+            None => kv_pairs,
+        };
+
+        // Check if any of the keys is locked by another writer
+        for kv in kv_pairs {
+            check_key_len(&kv.key)?;
+            self.prefix_locks.check(kv.key.as_slice(), None)?;
+        }
+
+        let mut applied = self.recorder.as_ref().map(|_| Vec::new());
+        let result = self.bulk_insert_entries(kv_pairs, applied.as_mut(), atomic);
+        if let Some(entries) = applied.filter(|entries| !entries.is_empty()) {
+            self.record(OpRecord::BulkInsert { entries });
+        }
+        result
+    }
+
+    /// Builds a Trie from entries sorted by strictly increasing key.
+    ///
+    /// Each entry is inserted like with `insert`, so an entry with version 0 gets
+    /// the next version of the Trie. Unlike `bulk_insert`, the input is a stream
+    /// that is checked as it is consumed. To also validate the timestamps of
+    /// the entries, load them with `bulk_load_with_options`.
+    ///
+    /// # Errors
+    ///
+    /// Stops at the first entry whose key does not sort after the previous one,
+    /// or that fails to insert, and returns a `BulkLoadError` holding the Trie
+    /// built from the entries before it. Passing the rest of the input, fixed,
+    /// to `bulk_append` on that Trie gives the same Trie as loading the fixed
+    /// input in one pass.
+    ///
+    #[allow(clippy::result_large_err)]
+    pub fn bulk_load<I>(entries: I) -> Result<Self, BulkLoadError<P, V>>
+    where
+        I: IntoIterator<Item = KV<P, V>>,
+    {
+        Tree::new().bulk_append(entries)
+    }
+
+    /// Builds a Trie with `options` from entries sorted by strictly increasing
+    /// key.
+    ///
+    /// Behaves like `bulk_load`, except that the Trie is built with `options`,
+    /// so that with `TreeOptions::validate_ingest` or `TreeOptions::max_valid_ts`
+    /// the timestamp of each entry is validated as it is consumed, and a
+    /// repaired timestamp is loaded in its place.
+    ///
+    /// # Errors
+    ///
+    /// Fails like `bulk_load`, and with `TrieError::InvalidTimestamp` as the
+    /// reason for an entry whose timestamp is invalid.
+    ///
+    #[allow(clippy::result_large_err)]
+    pub fn bulk_load_with_options<I>(
+        options: TreeOptions,
+        entries: I,
+    ) -> Result<Self, BulkLoadError<P, V>>
+    where
+        I: IntoIterator<Item = KV<P, V>>,
+    {
+        Tree::with_options(options).bulk_append(entries)
+    }
+    /// Builds a Trie from entries sorted by key, folding the values of entries
+    /// with equal keys with `combine`.
+    ///
+    /// Behaves like `bulk_load`, except that the entries of a key are loaded as
+    /// one entry, whose value is the fold of their values in input order and
+    /// whose version and timestamp are those of the last of them. Entries out
+    /// of key order are sorted first, keeping the input order of equal keys, so
+    /// that every key is folded whether or not its entries are consecutive;
+    /// sorted input goes through in a single pass.
+    ///
+    /// # Errors
+    ///
+    /// Fails like `bulk_load` for an entry that fails to insert, with the index
+    /// of the first input entry of its key.
+    ///
+    #[allow(clippy::result_large_err)]
+    pub fn from_sorted_with<I, F>(entries: I, combine: F) -> Result<Self, BulkLoadError<P, V>>
+    where
+        I: IntoIterator<Item = KV<P, V>>,
+        F: Fn(V, V) -> V,
+    {
+        let mut entries: Vec<(usize, KV<P, V>)> = entries.into_iter().enumerate().collect();
+        if !entries.is_sorted_by(|(_, a), (_, b)| a.key <= b.key) {
+            // The sort is stable, so the entries of a key stay in input order.
+            entries.sort_by(|(_, a), (_, b)| a.key.cmp(&b.key));
+        }
+        let mut entries = entries.into_iter().peekable();
+        let runs = std::iter::from_fn(move || {
+            let (index, mut kv) = entries.next()?;
+            while let Some((_, next)) = entries.next_if(|(_, next)| next.key == kv.key) {
+                kv = KV::new(
+                    next.key,
+                    combine(kv.value, next.value),
+                    next.version,
+                    next.ts,
+                );
+            }
+            Some((index, kv))
+        });
+        Tree::new().append_indexed(runs)
+    }
+
+    /// Appends entries sorted by strictly increasing key, all sorting after the
+    /// largest key of the Trie.
+    ///
+    /// See `bulk_load`. The index of a failing entry counts from the start of
+    /// `sorted_tail`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `BulkLoadError` holding the Trie with the entries before the
+    /// failing one appended.
+    ///
+    #[allow(clippy::result_large_err)]
+    pub fn bulk_append<I>(self, sorted_tail: I) -> Result<Self, BulkLoadError<P, V>>
+    where
+        I: IntoIterator<Item = KV<P, V>>,
+    {
+        self.append_indexed(sorted_tail.into_iter().enumerate())
+    }
+
+    /// Appends entries like `bulk_append`, each with the index reported if it
+    /// fails.
+    #[allow(clippy::result_large_err)]
+    fn append_indexed<I>(mut self, sorted_tail: I) -> Result<Self, BulkLoadError<P, V>>
+    where
+        I: Iterator<Item = (usize, KV<P, V>)>,
+    {
+        let mut prev = self.last_key();
+        let mut validator = self.ts_validator();
+        for (index, kv) in sorted_tail {
+            let key = self.normalize(&kv.key).into_owned();
+            let order = prev
+                .as_ref()
+                .map(|prev| key.as_slice().cmp(prev.as_slice()));
+            let ts = match validator.as_mut() {
+                Some(validator) => {
+                    validator.check(key.as_slice(), index, kv.ts, || self.latest_ts_of(&key))
+                }
+                None => Ok(kv.ts),
+            };
+            let reason = match (order, ts) {
+                (Some(Ordering::Less), _) => Some(BulkLoadReason::OutOfOrder),
+                (Some(Ordering::Equal), _) => Some(BulkLoadReason::Duplicate),
+                (_, Err(err)) => Some(BulkLoadReason::Trie(err)),
+                (_, Ok(ts)) => self
+                    .insert(&key, kv.value, kv.version, ts)
+                    .err()
+                    .map(BulkLoadReason::Trie),
+            };
+            if let Some(reason) = reason {
+                return Err(BulkLoadError {
+                    tree: self,
+                    index,
+                    key,
+                    reason,
+                });
+            }
+            prev = Some(key);
+        }
+        Ok(self)
+    }
+
+    /// Inserts the key-value pairs of a `bulk_insert`, appending each applied
+    /// entry with its resolved version to `applied`.
+    #[allow(clippy::type_complexity)]
+    fn bulk_insert_entries(
+        &mut self,
+        kv_pairs: &[KV<P, V>],
+        applied: Option<&mut Vec<(P, V, u64, u64)>>,
+        atomic: bool,
+    ) -> Result<(), TrieError> {
+        let curr_version = self.latest_version();
+
+        // The new root is built aside and swapped in once all entries are in, so
+        // a panic while cloning a value leaves the Trie as it was. Unless the
+        // insert is atomic, an entry that fails with an error keeps the entries
+        // before it, as if they had been inserted one by one.
+        let mut root = self.root.clone();
+        let (writes, result) = self.build_writes(
+            &mut root,
+            kv_pairs,
+            curr_version,
+            applied.is_some(),
+            None,
+            "bulk_insert",
+        );
+        if atomic && result.is_err() {
+            return result;
+        }
+        self.commit_writes(root, writes, applied);
+        result
+    }
+
+    /// Inserts `kv_pairs` one after the other into `root`, a root built aside
+    /// from the Trie, an entry with version 0 getting the version after
+    /// `curr_version`.
+    ///
+    /// Within `window`, if given, the nodes an entry copies are updated in place
+    /// by the next entries instead of being copied again. The values of the
+    /// entries are kept for the recorder if `record` is set. A violation found by
+    /// the checks of strict mode poisons the Trie with `operation`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the writes to hand to `commit_writes` along with `root`, and the
+    /// error of the first entry that failed, which stops the build and leaves
+    /// `root` with the entries before it.
+    ///
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn build_writes(
+        &self,
+        root: &mut Option<Arc<Node<P, V>>>,
+        kv_pairs: &[KV<P, V>],
+        curr_version: u64,
+        record: bool,
+        mut window: Option<&mut CowWindow>,
+        operation: &'static str,
+    ) -> (Vec<BuiltWrite<P, V>>, Result<(), TrieError>) {
+        let mut writes = Vec::with_capacity(kv_pairs.len());
+        for kv in kv_pairs {
+            let mut t = kv.version;
+
+            if t == 0 {
+                // Zero-valued timestamps are associated with current time plus one
+                t = curr_version + 1;
+            } else if kv.version < curr_version {
+                let err = TrieError::Other(
+                    "given version is older than root's current version".to_string(),
+                );
+                return (writes, Err(err));
+            }
+
+            if self
+                .repeated_version(root.as_ref(), &kv.key, &kv.value)
+                .is_some()
+            {
+                continue;
+            }
+
+            let value = kv.value.clone();
+            let mut stats = InsertStats::default();
+            stats.checks = self.invariant_checks;
+            stats.pinned = self.pinned_of(&kv.key);
+            let inserted = match (root.as_mut(), window.as_deref_mut()) {
+                (None, _) => {
+                    *root = Some(Arc::new(Node::new_twig(
+                        kv.key.as_slice().into(),
+                        kv.key.as_slice().into(),
+                        value,
+                        t,
+                        kv.ts,
+                    )));
+                    Ok(None)
+                }
+                (Some(node), Some(window)) => {
+                    // Nodes are updated in place, so the old leaf is found first.
+                    let old_leaf =
+                        Node::find_twig(node, &kv.key).and_then(|twig| match &twig.node_type {
+                            NodeType::Twig(twig) => twig.get_leaf_by_version(t),
+                            _ => None,
+                        });
+                    Node::insert_in_window(
+                        node,
+                        &kv.key,
+                        value,
+                        t,
+                        kv.ts,
+                        0,
+                        self.duplicate_ts_policy,
+                        self.versioning,
+                        self.forced_node_type,
+                        window,
+                        &mut stats,
+                    )
+                    .map(|_| old_leaf)
+                }
+                (Some(node), None) => Node::insert_recurse(
+                    node,
+                    &kv.key,
+                    value,
+                    t,
+                    kv.ts,
+                    0,
+                    self.duplicate_ts_policy,
+                    self.versioning,
+                    self.forced_node_type,
+                    &mut stats,
+                )
+                .map(|(new_node, old_leaf)| {
+                    *node = new_node;
+                    old_leaf
+                }),
+            };
+            let old_leaf = match inserted {
+                Ok(old_leaf) => old_leaf,
+                Err(err) => {
+                    let err = self.poison_on_violation(err, &mut stats, &kv.key, operation);
+                    return (writes, Err(err));
+                }
+            };
+            writes.push(BuiltWrite {
+                key: kv.key.clone(),
+                version: t,
+                ts: kv.ts,
+                stats,
+                old_leaf,
+                recorded_value: record.then(|| kv.value.clone()),
+                hooked_value: self.index_hook.as_ref().map(|_| kv.value.clone()),
+            });
+        }
+        (writes, Ok(()))
+    }
+
+    /// Swaps in `root`, built by `build_writes`, and does the bookkeeping of each
+    /// of its `writes`, appending the recorded ones to `applied`.
+    pub(crate) fn commit_writes(
+        &mut self,
+        root: Option<Arc<Node<P, V>>>,
+        writes: Vec<BuiltWrite<P, V>>,
+        mut applied: Option<&mut Vec<(P, V, u64, u64)>>,
+    ) {
+        // Swap the root in and drop the old one only after the bookkeeping, so
+        // that a panicking drop of an old value cannot leave it half done.
+        let old_root = std::mem::replace(&mut self.root, root);
+        for write in writes {
+            let key = write.key;
+            let version_added = write.old_leaf.is_some();
+            self.note_trimmed(write.stats.replaced, write.version);
+            self.update_hash_index(&key);
+            self.track_version_count(&key);
+            self.pressure.record(write.ts, &write.stats, version_added);
+            if let Some(prefix_stats) = self.prefix_stats.as_mut() {
+                prefix_stats.on_prune::<V>(key.as_slice(), write.stats.replaced);
+                prefix_stats.on_insert::<V>(key.as_slice(), !version_added);
+            }
+            if let Some(suffix_index) = self.suffix_index.as_mut().filter(|_| !version_added) {
+                suffix_index.insert(key.as_slice());
+            }
+            self.advance_clock(write.version);
+            self.advance_ts_of(key.as_slice(), write.ts);
+            if let (Some(hook), Some(value)) = (self.index_hook.as_mut(), &write.hooked_value) {
+                hook.on_insert(&key, value, write.old_leaf.as_ref().map(|leaf| &leaf.value));
+            }
+            if let (Some(applied), Some(value)) = (applied.as_mut(), write.recorded_value) {
+                applied.push((key, value, write.version, write.ts));
+            }
+        }
+        drop(old_root);
+    }
+
+    /// Removes a key, with all of its versions, from the Trie.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(true)` if the key was removed, or `Ok(false)` if it was absent.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::TreeAlreadyClosed` if the Trie is closed, or
+    /// `TrieError::PrefixLocked` if the key falls under a locked prefix.
+    ///
+    pub fn remove(&mut self, key: &P) -> Result<bool, TrieError> {
+        self.remove_with_owner(None, key)
+    }
+
+    /// Removes a key on behalf of `owner`.
+    ///
+    /// Behaves like `remove`, except that keys under a prefix locked by `owner`
+    /// are writable.
+    ///
+    pub fn remove_as(&mut self, owner: u64, key: &P) -> Result<bool, TrieError> {
+        self.remove_with_owner(Some(owner), key)
+    }
+
+    fn remove_with_owner(&mut self, owner: Option<u64>, key: &P) -> Result<bool, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let key = self.normalize(key);
+        self.remove_normalized(owner, key.as_ref())
+    }
+
+    /// Removes a key that is already normalized.
+    fn remove_normalized(&mut self, owner: Option<u64>, key: &P) -> Result<bool, TrieError> {
+        // Check if the key is locked by another writer
+        self.prefix_locks.check(key.as_slice(), owner)?;
+
+        let removed_twig = self.find_twig_strict(key, "remove")?.cloned();
+
+        let latest_version = self.latest_version();
+        let (new_root, is_deleted) = match &self.root {
+            None => (None, false),
+            Some(root) => {
+                if root.is_twig() {
+                    // A root twig holding another key stays in place.
+                    match removed_twig {
+                        Some(_) => (None, true),
+                        None => (self.root.clone(), false),
+                    }
+                } else {
+                    let (new_root, removed) =
+                        Node::remove_recurse(root, key, 0, self.forced_node_type);
+                    if removed {
+                        (new_root, true)
+                    } else {
+                        (self.root.clone(), false)
+                    }
+                }
+            }
+        };
+
+        // Reads at earlier versions no longer see the removed key, and reads at
+        // later timestamps find its tombstone.
+        if is_deleted {
+            self.servable_from = self.servable_from.max(latest_version + 1);
+            self.tombstones
+                .bury(key.as_slice(), self.current_ts, latest_version);
+        }
+
+        // Drop the old root only after the bookkeeping, so that a panicking drop
+        // of a removed value cannot leave it half done.
+        let old_root = std::mem::replace(&mut self.root, new_root);
+        self.forget_removed(key, removed_twig.as_ref());
+        drop(old_root);
+        Ok(is_deleted)
+    }
+
+    /// Updates the indexes and statistics of the Trie after the removal of
+    /// `key`, whose twig is `removed_twig` if it was present, and records it.
+    fn forget_removed(&mut self, key: &P, removed_twig: Option<&Arc<Node<P, V>>>) {
+        // Keep the hash index in sync with the removal
+        if let Some(index) = self.hash_index.as_mut() {
+            if self.root.is_none() {
+                index.clear();
+            } else {
+                index.remove(key);
+            }
+        }
+        self.overloaded_keys.remove(key);
+
+        if let (Some(prefix_stats), Some(twig)) = (self.prefix_stats.as_mut(), removed_twig) {
+            if let NodeType::Twig(twig) = &twig.node_type {
+                prefix_stats.on_remove::<V>(key.as_slice(), twig.values.len());
+            }
+        }
+        if let Some(suffix_index) = self
+            .suffix_index
+            .as_mut()
+            .filter(|_| removed_twig.is_some())
+        {
+            suffix_index.remove(key.as_slice());
+        }
+
+        if self.recorder.is_some() {
+            self.record(OpRecord::Remove { key: key.clone() });
+        }
+        if let (Some(hook), Some(twig)) = (self.index_hook.as_mut(), removed_twig) {
+            if let NodeType::Twig(twig) = &twig.node_type {
+                if let Some(leaf) = twig.get_latest_leaf() {
+                    hook.on_remove(key, &leaf.value);
+                }
+            }
+        }
+    }
+
+    /// Removes every key starting with one of `prefixes`, with all of its
+    /// versions, in a single traversal of the Trie.
+    ///
+    /// The prefixes are sorted first, so that prefixes sharing a path are
+    /// removed in one descent, and prefixes covered by a shorter one are
+    /// skipped. Prefixes are matched against the raw key bytes, so a prefix of a
+    /// `VariableSizeKey` should not include the terminating NUL byte. Like an
+    /// insert, the removal moves `current_ts` forward to `ts`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of keys removed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::TreeAlreadyClosed` if the Trie is closed, or
+    /// `TrieError::PrefixLocked` if a key to remove falls under a locked prefix,
+    /// in which case nothing is removed.
+    ///
+    pub fn remove_prefixes(&mut self, prefixes: &[P], ts: u64) -> Result<usize, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let mut prefixes: Vec<P> = prefixes
+            .iter()
+            .map(|prefix| self.normalize(prefix).into_owned())
+            .collect();
+        prefixes.sort();
+        let mut disjoint: Vec<&[u8]> = Vec::with_capacity(prefixes.len());
+        for prefix in &prefixes {
+            if !disjoint
+                .last()
+                .is_some_and(|last| prefix.as_slice().starts_with(last))
+            {
+                disjoint.push(prefix.as_slice());
+            }
+        }
+        self.remove_disjoint_prefixes(&disjoint, ts)
+    }
+
+    /// Removes every key starting with one of `disjoint`, sorted prefixes none
+    /// of which starts with another, as `remove_prefixes` does.
+    fn remove_disjoint_prefixes(
+        &mut self,
+        disjoint: &[&[u8]],
+        ts: u64,
+    ) -> Result<usize, TrieError> {
+        let Some(root) = &self.root else {
+            return Ok(0);
+        };
+        let mut removed = Vec::new();
+        let new_root =
+            Node::remove_prefixes_recurse(root, disjoint, 0, self.forced_node_type, &mut removed);
+        if removed.is_empty() {
+            return Ok(0);
+        }
+        let keys: Vec<P> = removed
+            .iter()
+            .map(|twig| match &twig.node_type {
+                NodeType::Twig(twig) => twig.key.clone(),
+                _ => unreachable!("only twigs are removed"),
+            })
+            .collect();
+        for key in &keys {
+            self.prefix_locks.check(key.as_slice(), None)?;
+        }
+
+        // Reads at earlier versions no longer see the removed keys.
+        let latest_version = self.latest_version();
+        self.servable_from = self.servable_from.max(latest_version + 1);
+        let old_root = std::mem::replace(&mut self.root, new_root);
+        for (key, twig) in keys.iter().zip(&removed) {
+            self.advance_ts_of(key.as_slice(), ts);
+            self.tombstones
+                .bury(key.as_slice(), self.current_ts, latest_version);
+            self.forget_removed(key, Some(twig));
+        }
+        drop(old_root);
+        Ok(keys.len())
+    }
+
+    /// Retrieves the value of a key at the given version, with the version and
+    /// timestamp it was written at. A version of 0 reads the latest value.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::KeyNotFound` if the key has no value at `version`,
+    /// including when the Trie is empty, and `TrieError::TreeAlreadyClosed` if the
+    /// Trie is closed. Snapshots and readers report a missing key the same way.
+    /// With `TreeOptions::value_checksums`, a value that does not match its
+    /// checksum fails with `TrieError::ChecksumMismatch`.
+    ///
+    pub fn get(&self, key: &P, version: u64) -> Result<(P, V, u64, u64), TrieError> {
+        self.lookup(key, version, true)
+    }
+
+    /// Reads a key like `get`, counting the read towards `hot_prefixes` only if
+    /// `count_read` is set, so that the reads made by writes are not counted.
+    fn lookup(
+        &self,
+        key: &P,
+        version: u64,
+        count_read: bool,
+    ) -> Result<(P, V, u64, u64), TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let key = self.normalize(key);
+        let key = key.as_ref();
+
+        let found = match (&self.root, self.invariant_checks) {
+            // Strict mode descends the trie to check the nodes on the way
+            (Some(root), Some(checks)) => {
+                let version = match version {
+                    0 => root.version(),
+                    version => version,
+                };
+                Node::find_twig_checked(root, key, checks)
+                    .map_err(|violation| self.poison(violation, key, "get"))?
+                    .and_then(|twig| twig.get_value_by_version(version))
+                    .ok_or(TrieError::KeyNotFound)
+            }
+            // Jump straight to the twig node if the tree is indexed
+            _ => Node::resolve_get_indexed(
+                self.root.as_ref(),
+                key,
+                version,
+                self.hash_index.as_ref(),
+            ),
+        };
+        // Keys under an expired prefix read as absent until compaction reclaims
+        // them.
+        let found =
+            found.and_then(
+                |entry| match self.expiry.is_expired(key.as_slice(), self.current_ts) {
+                    true => Err(TrieError::KeyNotFound),
+                    false => Ok(entry),
+                },
+            );
+        let found = found.and_then(|entry| check_value(self.value_check, entry));
+        if let (Ok(_), Some(freq), true) = (&found, &self.read_frequency, count_read) {
+            freq.record(key.as_slice());
+        }
+        found
+    }
+
+    /// Returns every stored version of a key, oldest first, with the version and
+    /// timestamp each was written at.
+    ///
+    /// Versions dropped by `prune_versions_older_than` or `retain_versions` are not
+    /// returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::KeyNotFound` if the key is absent, or
+    /// `TrieError::TreeAlreadyClosed` if the Trie is closed.
+    ///
+    pub fn history(&self, key: &P) -> Result<Vec<(V, u64, u64)>, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let key = self.normalize(key);
+        let twig = self.find_twig_strict(key.as_ref(), "history")?;
+        let Some(NodeType::Twig(twig)) = twig.map(|node| &node.node_type) else {
+            return Err(TrieError::KeyNotFound);
+        };
+        if self.is_expired(twig.key.as_slice()) {
+            return Err(TrieError::KeyNotFound);
+        }
+        Ok(twig
+            .values
+            .iter()
+            .map(|leaf| (leaf.value.clone(), leaf.version, leaf.ts))
+            .collect())
+    }
+
+    /// Retrieves the latest version of the Trie.
+    ///
+    /// This function returns the version of the latest version of the Trie. If the Trie is empty,
+    /// it returns `0`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the version of the latest version of the Trie, or `0` if the Trie is empty.
+    ///
+    pub fn version(&self) -> u64 {
+        match &self.root {
+            None => 0,
+            Some(root) => root.version(),
+        }
+    }
+
+    /// Creates a new snapshot of the Trie.
+    ///
+    /// This function creates a snapshot of the current state of the Trie. If successful, it returns
+    /// a `Snapshot` that can be used to interact with the newly created snapshot.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the `Snapshot` if the snapshot is created successfully,
+    /// or an `Err` with an appropriate error message if creation fails.
+    ///
+    pub fn create_snapshot(&mut self) -> Result<Snapshot<P, V>, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        if self.snapshots.is_full() {
+            return Err(TrieError::Other(
+                "max number of snapshots reached".to_string(),
+            ));
+        }
+
+        self.close_cow_window();
+        let root = self.root.as_ref().cloned();
+        let version = self.root.as_ref().map_or(1, |root| root.version() + 1);
+        // Register the snapshot at the version it reads at
+        let mut new_snapshot = Snapshot::new(
+            root,
+            version,
+            self.version(),
+            self.current_ts,
+            self.snapshots.clone(),
+        );
+        let new_snapshot_id = new_snapshot.id;
+        new_snapshot.normalizer = self.normalizer.clone();
+        new_snapshot.value_eq = self.value_eq;
+        new_snapshot.value_check = self.value_check;
+        new_snapshot.forced_node_type = self.forced_node_type;
+        new_snapshot.ts_domains = self.ts_domains.clone();
+        new_snapshot.expiry = self.expiry.clone();
+        new_snapshot.tombstones = self.tombstones.clone();
+        if self.count_snapshot_reads {
+            new_snapshot.read_frequency = self.read_frequency.clone();
+        }
+        self.record(OpRecord::CreateSnapshot {
+            id: new_snapshot_id,
+        });
+
+        Ok(new_snapshot)
+    }
+
+    /// Creates a read view of the Trie at its current version, which can later be
+    /// refreshed to the Trie's latest version.
+    ///
+    /// The view is registered like a snapshot and counts towards the limit of
+    /// active snapshots until it is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the Trie is closed or has the maximum number of
+    /// active snapshots.
+    ///
+    pub fn refreshing_view(&mut self) -> Result<RefreshingView<P, V>, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        if self.snapshots.is_full() {
+            return Err(TrieError::Other(
+                "max number of snapshots reached".to_string(),
+            ));
+        }
+        self.close_cow_window();
+        Ok(RefreshingView::new(self))
+    }
+
+    /// Starts a transaction on the Trie at its current version.
+    ///
+    /// See `Transaction` for how its reads are isolated and its writes are
+    /// committed. Like a view, the transaction counts towards the limit of
+    /// active snapshots until it is committed or dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the Trie is closed or has the maximum number of
+    /// active snapshots.
+    ///
+    pub fn transaction(&mut self) -> Result<Transaction<P, V>, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        if self.snapshots.is_full() {
+            return Err(TrieError::Other(
+                "max number of snapshots reached".to_string(),
+            ));
+        }
+        self.close_cow_window();
+        Ok(Transaction::new(self))
+    }
+
+    /// Closes the copy-on-write window before the root is shared with a snapshot
+    /// or view, so the nodes they read are copied rather than updated in place
+    /// by the next inserts.
+    pub(crate) fn close_cow_window(&mut self) {
+        if let Some(window) = self.cow_window.as_mut() {
+            window.flush();
+        }
+    }
+
+    /// Creates a view of a deterministic sample of the keys of the Trie, at its
+    /// current version.
+    ///
+    /// A key is sampled if a hash of its bytes with `seed` falls below
+    /// `fraction`, which is clamped to `[0, 1]`, so the same seed and fraction
+    /// select the same keys however the Trie changes. Keys expired at the
+    /// current timestamp are left out of the view. The view is not registered
+    /// as a snapshot; see `SampleView`.
+    ///
+    pub fn sample_view(&self, fraction: f64, seed: u64) -> SampleView<P, V> {
+        let mut view = SampleView::new(
+            self.root.clone(),
+            self.version(),
+            fraction,
+            seed,
+            self.normalizer.clone(),
+        );
+        if !self.expiry.is_empty() {
+            view.expiry = Some((self.expiry.clone(), self.current_ts));
+        }
+        view
+    }
+
+    /// Exports the current state of the Trie as an `OwnedSnapshot`.
+    ///
+    /// Unlike `create_snapshot`, the result is copied out of the Trie and is not
+    /// registered as an active snapshot, so it does not count towards the
+    /// snapshot limit and stays readable after the Tree is dropped.
+    ///
+    pub fn export_owned_snapshot(&self) -> Result<OwnedSnapshot<P, V>, TrieError> {
+        // Check if the tree is already closed. A poisoned tree can still be
+        // exported, to salvage its data.
+        if self.closed {
+            return Err(TrieError::TreeAlreadyClosed);
+        }
+
+        let version = self.root.as_ref().map_or(0, |root| root.version());
+        Ok(OwnedSnapshot::from_entries(self.iter(), version))
+    }
+
+    /// Closes a snapshot and removes it from the list of active snapshots.
+    ///
+    /// This function takes a `snapshot_id` as an argument and closes the corresponding snapshot.
+    /// If the snapshot exists, it is removed from the active snapshots list. If the snapshot is not
+    /// found, an `Err` is returned with a `TrieError::SnapshotNotFound` variant.
+    ///
+    /// `Snapshot::close` only ends the snapshot's own reads and writes; until it
+    /// is also closed here, the snapshot counts towards the limit of active
+    /// snapshots and is accounted for by version pruning.
+    ///
+    /// # Arguments
+    ///
+    /// * `snapshot_id` - The ID of the snapshot to be closed and removed.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the snapshot is successfully closed and removed. Returns an `Err`
+    /// with `TrieError::SnapshotNotFound` if the snapshot with the given ID is not found.
+    ///
+    pub fn close_snapshot(&mut self, snapshot_id: u64) -> Result<(), TrieError> {
+        // Check if the tree is already closed. A poisoned tree can still be
+        // released.
+        if self.closed {
+            return Err(TrieError::TreeAlreadyClosed);
+        }
+
+        if self.snapshots.deregister(snapshot_id) {
+            self.record(OpRecord::CloseSnapshot { id: snapshot_id });
+            Ok(())
+        } else {
+            Err(TrieError::SnapshotNotFound)
+        }
+    }
+
+    /// Begins closing every active snapshot, like `Snapshot::begin_close`, and
+    /// returns the ID of each with the number of readers it still has open, or
+    /// the error it was left alone with.
+    ///
+    /// Together with `finish_close_all` this shuts the snapshots of the Trie down
+    /// without letting new readers in while the open ones drain.
+    ///
+    pub fn begin_close_all(&self) -> Result<CloseOutcomes<u64>, TrieError> {
+        if self.closed {
+            return Err(TrieError::TreeAlreadyClosed);
+        }
+
+        Ok(self
+            .snapshots
+            .gates()
+            .into_iter()
+            .map(|(id, gate)| (id, gate.begin_close()))
+            .collect())
+    }
+
+    /// Closes every active snapshot whose readers have drained, like
+    /// `Snapshot::finish_close`, and removes it as `close_snapshot` does. Returns
+    /// the ID of each snapshot with the outcome; one with readers still open
+    /// reports `TrieError::SnapshotReadersNotClosed` and stays active, so the
+    /// call can be repeated until every snapshot is closed.
+    ///
+    pub fn finish_close_all(&mut self) -> Result<CloseOutcomes<()>, TrieError> {
+        if self.closed {
+            return Err(TrieError::TreeAlreadyClosed);
+        }
+
+        let mut outcomes = Vec::new();
+        for (id, gate) in self.snapshots.gates() {
+            let outcome = match gate.close() {
+                // Closed by its owner already, but still registered.
+                Err(TrieError::SnapshotAlreadyClosed) => Ok(()),
+                outcome => outcome,
+            };
+            if outcome.is_ok() {
+                self.close_snapshot(id)?;
+            }
+            outcomes.push((id, outcome));
+        }
+        Ok(outcomes)
+    }
+
+    /// Returns the ID and state of every active snapshot, ordered by ID.
+    ///
+    /// A snapshot closed through `Snapshot::close` or `Snapshot::finish_close`
+    /// stays listed as closed until it is removed with `close_snapshot`.
+    pub fn snapshot_states(&self) -> Vec<(u64, SnapshotState)> {
+        self.snapshots
+            .gates()
+            .into_iter()
+            .map(|(id, gate)| (id, SnapshotState::of(&gate)))
+            .collect()
+    }
+
+    /// Returns the count of active snapshots.
+    ///
+    /// This function returns the number of currently active snapshots in the Trie.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the count of active snapshots if successful, or an `Err`
+    /// if there is an issue retrieving the snapshot count.
+    ///
+    pub fn snapshot_count(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// Returns the number of keys under each distinct `prefix_len`-byte prefix.
+    ///
+    /// Keys shorter than `prefix_len` bytes, including the terminator of a
+    /// `VariableSizeKey`, are counted under the whole key. The Trie is walked once,
+    /// and subtrees below the prefix length are counted without visiting their
+    /// keys.
+    ///
+    pub fn entry_count_by_prefix(&self, prefix_len: usize) -> HashMap<Vec<u8>, usize> {
+        let mut counts = HashMap::new();
+        if !self.expiry.is_empty() {
+            // Expired keys are not counted, so the keys are visited to check them.
+            for (key, ..) in self.iter() {
+                let group = &key[..prefix_len.min(key.len())];
+                *counts.entry(group.to_vec()).or_insert(0) += 1;
+            }
+            return counts;
+        }
+        if let Some(root) = &self.root {
+            root.group_counts(prefix_len, self.cached_counts, &mut Vec::new(), &mut counts);
+        }
+        counts
+    }
+
+    /// Returns the number of keys starting with `prefix`.
+    ///
+    /// The bytes of `prefix` are matched as they are, so a `VariableSizeKey`
+    /// prefix should be built without the terminating NULL byte. The subtree under
+    /// the prefix is walked without visiting keys, or not at all once its count is
+    /// cached in a Trie built with `Tree::with_counts`.
+    ///
+    pub fn count_prefix(&self, prefix: &[u8]) -> usize {
+        let subtree = self
+            .root
+            .as_ref()
+            .and_then(|root| Node::find_prefix_subtree(root, prefix));
+        let Some(node) = subtree else {
+            return 0;
+        };
+        if self.expiry.is_empty() {
+            return self.count_keys(node);
+        }
+        // Expired keys are not counted, so the keys are visited to check them.
+        let mut twigs = Vec::new();
+        Node::collect_twigs(node, &mut twigs);
+        twigs
+            .iter()
+            .filter(|twig| !Tree::twig_key(twig).is_some_and(|key| self.is_expired(key.as_slice())))
+            .count()
+    }
+
+    // Counts the keys below `node`, through the cached counts if they are kept.
+    fn count_keys(&self, node: &Node<P, V>) -> usize {
+        if self.cached_counts {
+            node.cached_count()
+        } else {
+            node.count_twigs()
+        }
+    }
+
+    /// Returns up to `top_k` key prefixes with the number of successful reads of
+    /// keys under them, most read first.
+    ///
+    /// Reads are counted by `get`, and by the snapshots and readers of the Trie
+    /// if it was built with `TreeOptions::count_snapshot_reads`. The counts of
+    /// the least read prefixes are approximate: a prefix evicted from the
+    /// counters hands its count down to the prefix taking its place. Nothing is
+    /// returned unless the Trie was built with
+    /// `TreeOptions::track_read_frequency`.
+    ///
+    pub fn hot_prefixes(&self, top_k: usize) -> Vec<(Vec<u8>, u64)> {
+        self.read_frequency
+            .as_ref()
+            .map_or_else(Vec::new, |freq| freq.hot_prefixes(top_k))
+    }
+
+    /// Forgets the reads counted for `hot_prefixes` so far.
+    pub fn reset_read_frequency(&self) {
+        if let Some(freq) = &self.read_frequency {
+            freq.reset();
+        }
+    }
+
+    /// Returns the number of keys in the Trie.
+    ///
+    /// The nodes are walked in place with an explicit stack, without yielding,
+    /// cloning or even visiting keys and values. The stack only grows with the
+    /// width and depth of the Trie, not with the number of keys, which makes this
+    /// a baseline for the cost of a traversal in benchmarks.
+    ///
+    pub fn iter_all_count(&self) -> usize {
+        self.root.as_ref().map_or(0, |root| root.count_twigs())
+    }
+
+    /// Returns the depth of the keys of the Trie, along with the inner nodes that
+    /// add to it without branching.
+    ///
+    /// This walks the whole Trie, and is meant for tests and tuning.
+    ///
+    pub fn depth_stats(&self) -> DepthStats {
+        let mut stats = DepthStats::default();
+        if let Some(root) = &self.root {
+            root.depth_recurse(1, &mut stats);
+        }
+        stats
+    }
+
+    /// Merges every inner node with a single child into that child, after
+    /// removing the keys under expired prefixes.
+    ///
+    /// Removals leave such nodes behind when they take all but one child of a
+    /// node, each adding a level to the keys below it. Apart from the expired
+    /// keys, the keys, values and versions are unchanged, and subtrees without
+    /// such nodes stay shared with the snapshots.
+    ///
+    /// Expired keys are removed like with `remove`, and the rules that expired
+    /// them are dropped, so keys written under those prefixes afterwards are
+    /// visible again. Keys under a prefix locked with `try_lock_prefix` are left
+    /// in place along with their rule, to be reclaimed by a later pass.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of nodes merged away.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::TreeAlreadyClosed` if the Trie is closed.
+    ///
+    pub fn compact(&mut self) -> Result<usize, TrieError> {
+        self.is_closed()?;
+        self.reclaim_expired();
+        let Some(root) = &self.root else {
+            return Ok(0);
+        };
+        let (new_root, merged) = Node::compact(root);
+        if merged > 0 {
+            self.root = Some(new_root);
+        }
+        Ok(merged)
+    }
+
+    /// Removes the keys under expired prefixes and drops the rules that expired
+    /// them, except where a key cannot be removed.
+    fn reclaim_expired(&mut self) {
+        if self.expiry.is_empty() {
+            return;
+        }
+        let now = self.current_ts;
+        let expired: Vec<P> = Iter::new(self.root.as_ref())
+            .filter(|(key, ..)| self.expiry.is_expired(key, now))
+            .map(|(key, ..)| P::from(key.as_slice()))
+            .collect();
+        let mut kept = Vec::new();
+        for key in expired {
+            if self.remove_normalized(None, &key).is_err() {
+                kept.push(key);
+            }
+        }
+        self.expiry.drop_expired(now, |prefix| {
+            kept.iter().any(|key| key.as_slice().starts_with(prefix))
+        });
+    }
+
+    /// Expires every key starting with `prefix` at the timestamp `deadline_ts`.
+    ///
+    /// Once `current_ts` reaches the deadline, `get`, `iter` and `range` treat the
+    /// keys under the prefix as absent, including keys written after the rule was
+    /// set, until `compact` removes them and drops the rule. A key is governed by
+    /// the rule on its longest prefix only, so a rule on a longer prefix overrides
+    /// the rules on the prefixes containing it, whether its deadline is earlier or
+    /// later. Setting a rule on a prefix that has one replaces its deadline.
+    ///
+    /// `prefix` is matched against the raw key bytes, so a prefix of a
+    /// `VariableSizeKey` should not include the terminating NUL byte. Every read
+    /// of the Trie, and of its sample views, skips expired keys; snapshots see
+    /// them until they are reclaimed, except in `Snapshot::iter_as_of`. The rules
+    /// are kept by `freeze` and `thaw`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the deadline the rule replaces, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::TreeAlreadyClosed` if the Trie is closed.
+    ///
+    pub fn expire_prefix_at(
+        &mut self,
+        prefix: &[u8],
+        deadline_ts: u64,
+    ) -> Result<Option<u64>, TrieError> {
+        self.is_closed()?;
+        Ok(self.expiry.set(prefix, deadline_ts))
+    }
+
+    /// Cancels the expiry rule on `prefix`, so that the keys it governed fall
+    /// under the rule on the next shorter prefix, if any.
+    ///
+    /// # Returns
+    ///
+    /// Returns the deadline of the cancelled rule, or `None` if `prefix` had no
+    /// rule.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::TreeAlreadyClosed` if the Trie is closed.
+    ///
+    pub fn cancel_expiry(&mut self, prefix: &[u8]) -> Result<Option<u64>, TrieError> {
+        self.is_closed()?;
+        Ok(self.expiry.cancel(prefix))
+    }
+
+    /// Returns the expiry rules as `(prefix, deadline_ts)` pairs, ordered by prefix.
+    pub fn expiry_rules(&self) -> Vec<(Vec<u8>, u64)> {
+        self.expiry
+            .rules()
+            .map(|(prefix, deadline)| (prefix.to_vec(), deadline))
+            .collect()
+    }
+
+    /// Returns the timestamp expiry deadlines are checked against: the latest
+    /// timestamp written to the Trie or passed to `advance_ts`.
+    pub fn current_ts(&self) -> u64 {
+        self.current_ts
+    }
+
+    /// Moves the current timestamp of the Trie forward to `ts`, so that rules
+    /// set with `expire_prefix_at` take effect without a write. Earlier
+    /// timestamps are ignored.
+    pub fn advance_ts(&mut self, ts: u64) {
+        self.current_ts = self.current_ts.max(ts);
+    }
+
+    /// Moves the current timestamp, and the counter of the domain of `key` if
+    /// the Trie keeps timestamp domains, forward to `ts`.
+    fn advance_ts_of(&mut self, key: &[u8], ts: u64) {
+        self.current_ts = self.current_ts.max(ts);
+        if let Some(domains) = self.ts_domains.as_mut() {
+            domains.advance(key, ts);
+        }
+    }
+
+    /// Returns the latest timestamp written to the keys of the domain
+    /// `segment`, the bytes before the first delimiter of its keys.
+    ///
+    /// Returns `None` if the Trie was not built with `TreeOptions::ts_domains`,
+    /// or nothing was written to the domain yet. Keys without a delimiter are in
+    /// no domain and only move `current_ts`.
+    pub fn ts_of_domain(&self, segment: &[u8]) -> Option<u64> {
+        self.ts_domains.as_ref()?.get(segment)
+    }
+
+    /// Returns the counter of every timestamp domain as `(segment, ts)` pairs,
+    /// ordered by segment.
+    pub fn ts_domains(&self) -> Vec<(Vec<u8>, u64)> {
+        self.ts_domains
+            .iter()
+            .flat_map(|domains| domains.iter())
+            .map(|(segment, ts)| (segment.to_vec(), ts))
+            .collect()
+    }
+
+    /// Replaces the keys of the Trie starting with `prefix` with those of
+    /// `other`, each with all of its versions, and adopts the counters of the
+    /// timestamp domains of `other` that the prefix covers.
+    ///
+    /// This is how writers owning disjoint parts of the keyspace exchange them:
+    /// each splices the subtree of the other's part into its own Trie. The
+    /// versions of a key are written in their order in `other`, at new versions
+    /// of this Trie but with their timestamps as they were, so the history of
+    /// every key is kept. A domain counter is adopted as the larger of the two,
+    /// so the counters of the domains outside of `prefix` are left alone, and a
+    /// counter ahead of the latest timestamp of its keys, such as after a
+    /// removal, carries over. Prefixes are matched against the raw key bytes,
+    /// as with `remove_prefixes`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of keys spliced in.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::TreeAlreadyClosed` if either Trie is closed,
+    /// `TrieError::PrefixLocked` if a key to remove or write falls under a
+    /// locked prefix, and the error of the first version that fails to insert,
+    /// such as one repeating a timestamp a Trie built with
+    /// `DuplicateTsPolicy::Reject` refuses. Nothing is changed on error.
+    ///
+    pub fn splice_subtree(
+        &mut self,
+        other: &Tree<P, V>,
+        prefix: &[u8],
+    ) -> Result<usize, TrieError> {
+        self.is_closed()?;
+        other.is_closed()?;
+
+        let subtree = other
+            .root
+            .as_ref()
+            .and_then(|root| Node::prefix_root(root, prefix));
+        let foreign: Vec<(P, Vec<(V, u64)>)> = ChangedSince::all(subtree.as_ref())
+            .map(|twig| {
+                let versions = twig.iter().map(|leaf| (leaf.value.clone(), leaf.ts));
+                (twig.key.clone(), versions.collect())
+            })
+            .collect();
+        // The versions of every key are built into a twig aside first, with the
+        // policies of this Trie, so that a version it refuses fails the splice
+        // before anything is removed. The keys are absent once the prefix is
+        // removed, so the writes below then succeed.
+        for (key, versions) in &foreign {
+            check_key_len(key)?;
+            self.prefix_locks.check(key.as_slice(), None)?;
+            let mut twig: Option<Arc<Node<P, V>>> = None;
+            for (version, (value, ts)) in (1..).zip(versions) {
+                twig = Some(match &twig {
+                    None => Arc::new(Node::new_twig(
+                        key.clone(),
+                        key.clone(),
+                        value.clone(),
+                        version,
+                        *ts,
+                    )),
+                    Some(node) => {
+                        Node::insert_recurse(
+                            node,
+                            key,
+                            value.clone(),
+                            version,
+                            *ts,
+                            0,
+                            self.duplicate_ts_policy,
+                            self.versioning,
+                            None,
+                            &mut InsertStats::default(),
+                        )?
+                        .0
+                    }
+                });
+            }
+        }
+
+        self.remove_disjoint_prefixes(&[prefix], 0)?;
+        for (key, versions) in &foreign {
+            for (value, ts) in versions {
+                self.insert(key, value.clone(), 0, *ts)?;
+            }
+        }
+        if let (Some(domains), Some(theirs)) = (self.ts_domains.as_mut(), &other.ts_domains) {
+            for (segment, ts) in theirs.iter() {
+                if domains.overlaps(segment, prefix) {
+                    domains.adopt(segment, ts);
+                }
+            }
+        }
+        Ok(foreign.len())
+    }
+
+    /// Returns the changes that turn the contents of `base` into the current
+    /// contents of the Trie, in key order.
+    ///
+    /// This is the changelog a replica holding `base` has to apply to catch up.
+    /// Changes are net, so a key written several times since `base` appears once
+    /// with its latest value. Subtrees the Trie still shares with `base` are
+    /// skipped, so the cost depends on the number of changes rather than the size
+    /// of the Trie.
+    ///
+    pub fn diff_since(&self, base: &Snapshot<P, V>) -> Vec<Change<P, V>> {
+        let mut changes = Vec::new();
+        diff_nodes(base.root.as_ref(), self.root.as_ref(), &mut changes);
+        changes
+    }
+
+    /// Returns the nodes on the path from the root of the Trie to `key`, or `None`
+    /// if the key is not in the Trie.
+    ///
+    /// The last segment is the twig node holding the key. A segment is marked as
+    /// shared when something besides its parent in the Trie holds a reference to
+    /// the node: an open snapshot or reader, or a node of one that was replaced in
+    /// the Trie since. The reference held by the hash index is accounted for.
+    ///
+    /// The flag is a reference count heuristic. A `PrefixScan` that is still open
+    /// holds references to the nodes it walks and makes them look shared, and
+    /// nodes below a shared node are only reported as shared if they are
+    /// referenced directly.
+    ///
+    pub fn path_of(&self, key: &P) -> Option<Vec<PathSegment>> {
+        let key = self.normalize(key);
+        let key = key.as_ref();
+        let mut path = Vec::new();
+        if !Node::search_path(self.root.as_ref()?, key, &mut path) {
+            return None;
+        }
+
+        let last = path.len() - 1;
+        let segments = path
+            .iter()
+            .enumerate()
+            .map(|(i, (node, depth))| {
+                let prefix = node.prefix();
+                PathSegment {
+                    node_type: node.node_type_name(),
+                    partial: prefix.as_slice().to_vec(),
+                    depth: *depth,
+                    child: (i != last).then(|| key.at(depth + prefix.len())),
+                    num_children: node.num_children(),
+                    shared: Arc::strong_count(node) > 1,
+                }
+            })
+            .collect();
+        Some(segments)
+    }
+
+    /// Returns the number of nodes the lookups of `a` and `b` pass through in
+    /// common, counting from the root.
+    ///
+    /// Writes to either key copy every node on its path, so this is the number of
+    /// nodes that updates to both keys copy only once when they land in the same
+    /// batch. Keys that are missing from the Trie count the nodes visited before
+    /// the lookup fails.
+    ///
+    pub fn common_path_len(&self, a: &P, b: &P) -> usize {
+        let Some(root) = self.root.as_ref() else {
+            return 0;
+        };
+        let (mut path_a, mut path_b) = (Vec::new(), Vec::new());
+        Node::search_path(root, self.normalize(a).as_ref(), &mut path_a);
+        Node::search_path(root, self.normalize(b).as_ref(), &mut path_b);
+        path_a
+            .iter()
+            .zip(&path_b)
+            .take_while(|((a, _), (b, _))| Arc::ptr_eq(a, b))
+            .count()
+    }
+
+    /// Returns the keys holding at least `TreeOptions::version_warn_threshold`
+    /// versions, in key order.
+    ///
+    /// Keys are added when a write brings them to the threshold, and dropped when
+    /// they are removed or pruned below it. Nothing is tracked without a threshold.
+    ///
+    pub fn overloaded_keys(&self) -> impl Iterator<Item = &P> {
+        self.overloaded_keys.iter()
+    }
+
+    /// Returns the keys of the Trie that an open snapshot reads stale values for.
+    ///
+    /// A key is stale when the Trie holds a version of it newer than the snapshot.
+    /// Subtrees without such versions are skipped, so the cost depends on the
+    /// number of changes rather than the size of the Trie. Keys removed from the
+    /// Trie since the snapshot was taken are not reported.
+    ///
+    /// # Returns
+    ///
+    /// Returns an iterator over the stale keys in key order, each with the
+    /// timestamp of its newest value in the Trie.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::SnapshotNotFound` if no open snapshot has the given ID.
+    ///
+    pub fn stale_keys_for_snapshot(
+        &self,
+        snapshot_id: u64,
+    ) -> Result<impl Iterator<Item = (Vec<u8>, u64)> + '_, TrieError> {
+        let since = self
+            .snapshots
+            .version_of(snapshot_id)
+            .ok_or(TrieError::SnapshotNotFound)?;
+        Ok(
+            ChangedSince::new(self.root.as_ref(), since).filter_map(|twig| {
+                let leaf = twig.get_latest_leaf()?;
+                Some((twig.key.as_slice().to_vec(), leaf.ts))
+            }),
+        )
+    }
+
+    /// Summarizes the writes to the Trie that an open snapshot cannot see.
+    ///
+    /// This visits the same nodes as `stale_keys_for_snapshot`, without copying
+    /// any keys.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::SnapshotNotFound` if no open snapshot has the given ID.
+    ///
+    pub fn staleness(&self, snapshot_id: u64) -> Result<StalenessSummary, TrieError> {
+        let since = self
+            .snapshots
+            .version_of(snapshot_id)
+            .ok_or(TrieError::SnapshotNotFound)?;
+        let mut summary = StalenessSummary::default();
+        for twig in ChangedSince::new(self.root.as_ref(), since) {
+            if let Some(leaf) = twig.get_latest_leaf() {
+                summary.stale_keys += 1;
+                summary.max_lag_ts = summary.max_lag_ts.max(leaf.ts);
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Returns a key written to the Trie after version `after_ts` that conflicts
+    /// with a read set, if there is any.
+    ///
+    /// `after_ts` is the version the reads were made at, such as the
+    /// `Tree::version` when the snapshot that recorded `read_set` was created. A
+    /// key conflicts if a value newer than `after_ts` was written to it and the
+    /// read set covers it. Removals are detected by counting the keys under
+    /// every key and prefix read, and within every range scanned, again: where
+    /// fewer keys are left than the snapshot held, the key, prefix or start of
+    /// the range is returned. Newer writes are found by skipping the subtrees
+    /// without newer versions, as `staleness` does, while counting the keys
+    /// again takes as long as reading them did.
+    ///
+    pub fn writes_intersect(&self, read_set: &ReadSet, after_ts: u64) -> Option<Vec<u8>> {
+        let root = self.root.as_ref();
+        let removed = read_set.find_removal(
+            |prefix| Node::count_under(root, prefix),
+            |range| count_in(root, range),
+        );
+        if removed.is_some() {
+            return removed;
+        }
+
+        ChangedSince::new(self.root.as_ref(), after_ts)
+            .map(|twig| twig.key.as_slice())
+            .find(|key| read_set.covers(key))
+            .map(<[u8]>::to_vec)
+    }
+
+    /// Returns the current write pressure of the Trie.
+    ///
+    /// Pressure is derived from the node copies made by recent inserts: copies of
+    /// nodes that are still shared with an open snapshot or reader are retained
+    /// instead of freed, so memory grows faster than the data. The level is
+    /// `High` once the fraction of retained copies reaches the threshold set in
+    /// `TreeOptions`, which writers can use as a signal to slow down. The counters
+    /// are kept over a window of insert timestamps, so the result only depends on
+    /// the sequence of writes.
+    ///
+    pub fn pressure(&self) -> Pressure {
+        self.pressure.pressure(self.snapshots.len())
+    }
+
+    /// Returns the oldest version still needed by an active snapshot.
+    ///
+    /// This is the smallest version among the versions the active snapshots read at,
+    /// or the current version of the Trie if there are no active snapshots. Values
+    /// newer than or visible at this version may still be read, so it is the safe
+    /// cutoff for `prune_versions_older_than`.
+    ///
+    pub fn min_pinned_version(&self) -> u64 {
+        self.snapshots
+            .min_version()
+            .unwrap_or_else(|| self.version())
+    }
+
+    /// Returns the oldest timestamp still needed by an active snapshot.
+    ///
+    /// This is the smallest among the current timestamps of the Trie when the
+    /// active snapshots were taken, or last rebased or refreshed, or the current
+    /// timestamp of the Trie if there are no active snapshots. It is the
+    /// timestamp counterpart of `min_pinned_version`, for collectors that reclaim
+    /// by timestamp.
+    ///
+    pub fn min_pinned_ts(&self) -> u64 {
+        self.snapshots.min_ts().unwrap_or(self.current_ts)
+    }
+
+    /// Creates an iterator over the Trie's key-value pairs.
+    ///
+    /// This function creates and returns an iterator that can be used to traverse the key-value pairs
+    /// stored in the Trie. The iterator starts from the root of the Trie.
+    ///
+    /// # Returns
+    ///
+    /// Returns an `Iter` instance that iterates over the key-value pairs in the Trie.
+    ///
+    pub fn iter(&self) -> Iter<'_, P, V> {
+        let iter = match self.expiry.is_empty() {
+            true => Iter::new(self.root.as_ref()),
+            false => Iter::live(self.root.as_ref(), &self.expiry, self.current_ts),
+        };
+        iter.checked(self.value_check)
+    }
+
+    /// Returns an iterator over the latest key-value pairs of the Trie, from the
+    /// most recently written key to the least.
+    ///
+    /// Keys are ordered by the timestamp of their latest version, newest first,
+    /// and keys sharing a timestamp by key, so the order is the same on every
+    /// run. The iterator is lazy: see `RecencyIter` for how it avoids visiting
+    /// the whole Trie for the first few keys.
+    ///
+    pub fn iter_by_recency(&self) -> RecencyIter<'_, P, V> {
+        RecencyIter::new(self.root.as_ref(), self.live())
+    }
+
+    /// Returns the expiry rules and the current timestamp for the scans to skip
+    /// expired keys with, or `None` if there are no rules.
+    pub(crate) fn live(&self) -> Option<(&ExpiryTable, u64)> {
+        (!self.expiry.is_empty()).then_some((&self.expiry, self.current_ts))
+    }
+
+    // Returns whether `key` is under an expired prefix.
+    fn is_expired(&self, key: &[u8]) -> bool {
+        self.expiry.is_expired(key, self.current_ts)
+    }
+
+    /// Returns the `k` most recently written key-value pairs of the Trie, in the
+    /// order of `iter_by_recency`.
+    ///
+    #[allow(clippy::type_complexity)]
+    pub fn recent(&self, k: usize) -> Vec<(Vec<u8>, &V, &u64, &u64)> {
+        self.iter_by_recency().take(k).collect()
+    }
+
+    /// Returns an iterator over the entries of the Trie with their keys decoded as
+    /// the composite key type `K`, in key order.
+    ///
+    /// Each entry holds the decoded key, the latest value and its version. Keys
+    /// that fail to decode, such as keys inserted without `EncodeKey`, are yielded
+    /// as errors in their place and the iteration goes on past them.
+    ///
+    pub fn iter_decoded<K: DecodeKey>(&self) -> DecodedIter<'_, K, V> {
+        DecodedIter::new(self.iter())
+    }
+
+    /// Returns an iterator over the decoded entries whose keys start with the
+    /// leading components `prefix`, such as `(tenant,)` for keys of type
+    /// `(u64, String)`.
+    ///
+    /// See `iter_decoded`. Like `scan_prefix_with`, the encoded prefix is matched
+    /// against the stored keys as it is, without normalizing it.
+    ///
+    pub fn scan_prefix_decoded<K: DecodeKey>(
+        &self,
+        prefix: &impl EncodeKey,
+    ) -> DecodedIter<'_, K, V> {
+        DecodedIter::with_prefix(self.root.as_ref(), prefix, self.live())
+    }
+
+    /// Returns an iterator over a range of key-value pairs within the Trie.
+    ///
+    /// This function creates and returns an iterator that iterates over key-value pairs in the Trie,
+    /// starting from the provided `start_key` and following the specified `range` bounds. The iterator
+    /// iterates within the specified key range.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - A range that specifies the bounds for iterating over key-value pairs.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Range` iterator instance that iterates over the key-value pairs within the given range.
+    /// If the Trie is empty, an empty `Range` iterator is returned.
+    ///
+    pub fn range<'a, R>(
+        &'a self,
+        range: R,
+    ) -> impl Iterator<Item = (Vec<u8>, &'a V, &'a u64, &'a u64)>
+    where
+        R: RangeBounds<P> + 'a,
+    {
+        let range = (
+            range
+                .start_bound()
+                .map(|key| self.normalize(key).into_owned()),
+            range
+                .end_bound()
+                .map(|key| self.normalize(key).into_owned()),
+        );
+
+        // If the Trie is empty, return an empty Range iterator
+        let entries = match self.root {
+            None => Range::empty(range),
+            Some(_) => Range::new(self.root.as_ref(), range),
+        };
+        entries.filter(move |(key, value, ..)| {
+            !self.expiry.is_expired(key, self.current_ts)
+                && self.value_check.is_none_or(|check| check(value))
+        })
+    }
+
+    /// Returns a scan over the keys starting with `prefix`, using `buffer` as its
+    /// working state.
+    ///
+    /// Unlike `range` and `iter`, which allocate their traversal state and a copy
+    /// of every key, the scan keeps its stack in `buffer` and returns the keys as
+    /// stored in the Trie. Reusing one buffer across many short scans amortizes
+    /// its allocations, so scans stop allocating once the buffer is warm.
+    ///
+    /// `prefix` is matched against the raw key bytes, so a prefix of a
+    /// `VariableSizeKey` should not include the terminating NUL byte.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::str::FromStr;
+    /// use vart::art::Tree;
+    /// use vart::iter::ScanBuffer;
+    /// use vart::VariableSizeKey;
+    ///
+    /// let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+    /// for (i, word) in ["apple", "apricot", "banana"].iter().enumerate() {
+    ///     tree.insert(&VariableSizeKey::from_str(word).unwrap(), i as i32, 0, 0)
+    ///         .unwrap();
+    /// }
+    ///
+    /// let mut buffer = ScanBuffer::new();
+    /// let mut scan = tree.scan_prefix_with(b"ap", &mut buffer);
+    /// let mut values = Vec::new();
+    /// while let Some((_, value, _, _)) = scan.next_entry() {
+    ///     values.push(*value);
+    /// }
+    /// assert_eq!(values, vec![0, 1]);
+    /// ```
+    ///
+    pub fn scan_prefix_with<'b>(
+        &'b self,
+        prefix: &[u8],
+        buffer: &'b mut ScanBuffer<P, V>,
+    ) -> PrefixScan<'b, P, V> {
+        PrefixScan::new(self.root.as_ref(), prefix, buffer, self.live())
+    }
+
+    /// Returns an iterator over the key-value pairs that `filter` accepts, in key order.
+    ///
+    /// Unlike filtering the output of `iter`, the filter is asked about every inner
+    /// node with the key prefix leading to it, and can skip the node's subtree
+    /// without visiting its keys, or yield all of them without being asked again.
+    /// Keys reached through `ScanDecision::Descend` are yielded only if the filter
+    /// returns `ScanDecision::Yield` for the full key.
+    ///
+    /// The prefix of an inner node can end partway through the keys below it, so
+    /// filters should skip a subtree only once the prefix rules out all of them.
+    ///
+    pub fn scan_with_filter<F: Fn(&P) -> ScanDecision>(
+        &self,
+        filter: F,
+    ) -> FilteredScan<'_, P, V, F> {
+        FilteredScan::new(self.root.as_ref(), filter, self.live())
+    }
+
+    /// Returns an iterator over the keys accepted by the options, each with its
+    /// latest value passed through the projection of the options, in key order.
+    ///
+    /// The projection sees each value in place, so values it maps to a smaller
+    /// output are never cloned, including those the value filter then drops.
+    pub fn scan<'f, O, B: ?Sized>(
+        &self,
+        options: ScanOptions<'f, P, V, O, B>,
+    ) -> ProjectedScan<'_, 'f, P, V, O, B> {
+        ProjectedScan::new(self.root.as_ref(), options, self.live())
+    }
+
+    /// Returns a cursor for a paginated scan over the whole Trie, read with
+    /// `scan_page`.
+    pub fn scan_cursor(&self) -> ScanCursor {
+        ScanCursor::new()
+    }
+
+    /// Returns up to `limit` entries following the position of `cursor`, in key
+    /// order, and moves the cursor past them.
+    ///
+    /// An unpinned cursor reads the latest value of each key, so pages see the
+    /// writes made between them. A cursor pinned by `resume_cursor` reads the
+    /// values visible at its version, and skips the keys that had none.
+    ///
+    pub fn scan_page(
+        &self,
+        cursor: &mut ScanCursor,
+        limit: usize,
+    ) -> Result<Vec<(P, V, u64, u64)>, TrieError> {
+        self.is_closed()?;
+
+        let mut page = Vec::new();
+        let Some(root) = &self.root else {
+            cursor.done = true;
+            return Ok(page);
+        };
+        while page.len() < limit && !cursor.done {
+            let twig = match &cursor.after {
+                None => LowMemoryIter::first_twig(root),
+                Some(after) => LowMemoryIter::successor(root, after),
+            };
+            let Some(twig) = twig else {
+                cursor.done = true;
+                break;
+            };
+            cursor.after = Some(twig.key.as_slice().to_vec());
+            let leaf = match cursor.pinned {
+                Some(version) => twig.get_leaf_by_version(version),
+                None => twig.get_latest_leaf().cloned(),
+            };
+            if let Some(leaf) = leaf {
+                if !self.expiry.is_expired(twig.key.as_slice(), self.current_ts) {
+                    page.push((twig.key.clone(), leaf.value.clone(), leaf.version, leaf.ts));
+                }
+            }
+        }
+        Ok(page)
+    }
+
+    /// Returns a token from which `resume_cursor` restores `cursor`, possibly in
+    /// another process.
+    ///
+    /// The token holds the position of the cursor along with the version and a
+    /// fingerprint of the current contents of the Trie, which takes a pass over
+    /// every key.
+    ///
+    pub fn cursor_token(&self, cursor: &ScanCursor) -> Vec<u8> {
+        let version = cursor.pinned.unwrap_or_else(|| self.version());
+        cursor::encode_token(cursor, self.fingerprint(), version)
+    }
+
+    /// Restores a cursor from a token returned by `cursor_token`, on this Trie or
+    /// on one thawed from a frozen copy of it.
+    ///
+    /// If the contents of the Trie are still those the token was taken on, the
+    /// cursor continues as it was. Otherwise the cursor is pinned to the version
+    /// of the token, so that the rest of the scan stays consistent with the pages
+    /// already read, as long as the Trie can still serve reads at that version.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ResumeError::Malformed` if the token was not written by
+    /// `cursor_token`, or `ResumeError::TreeAdvanced` if the Trie has changed and
+    /// values or keys visible at the version of the token have since been pruned
+    /// or removed. A Trie thawed from a frozen copy only serves reads from the
+    /// version it was frozen at.
+    ///
+    pub fn resume_cursor(&self, token: &[u8]) -> Result<ScanCursor, ResumeError> {
+        let (mut cursor, fingerprint, version) = cursor::decode_token(token)?;
+        if cursor.pinned.is_none() && fingerprint == self.fingerprint() {
+            return Ok(cursor);
+        }
+        if version < self.servable_from {
+            return Err(ResumeError::TreeAdvanced { version });
+        }
+        cursor.pinned = Some(version);
+        Ok(cursor)
+    }
+
+    // Returns the fingerprint of the latest entries of the Trie.
+    fn fingerprint(&self) -> u64 {
+        cursor::fingerprint(self.root.as_ref())
+    }
+
+    /// Pins the value of a key visible at the given version.
+    ///
+    /// A pinned value survives `prune_versions_older_than`, `retain_versions` and
+    /// the writes trimming the versions of the key to the versioning strategy
+    /// until the returned guard is dropped, so reads of the key at `version`
+    /// keep returning it. This is a lighter alternative to holding a snapshot
+    /// open just to keep a few historical values alive.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::KeyNotFound` if the key has no value at `version`.
+    ///
+    pub fn pin_version(&mut self, key: &P, version: u64) -> Result<VersionPin, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let key = self.normalize(key);
+        let key = key.as_ref();
+
+        let twig = self
+            .root
+            .as_ref()
+            .and_then(|root| Node::find_twig(root, key))
+            .ok_or(TrieError::KeyNotFound)?;
+        let (_, _, leaf_version, _) = twig
+            .get_value_by_version(version)
+            .ok_or(TrieError::KeyNotFound)?;
+
+        Ok(self.version_pins.pin(key.as_slice(), leaf_version))
+    }
+
+    /// Returns the pinned versions as `(key, version)` pairs, ordered by key.
+    pub fn pinned_versions(&self) -> Vec<(P, u64)> {
+        self.version_pins
+            .pinned()
+            .into_iter()
+            .map(|(key, version)| (P::from(key.as_slice()), version))
+            .collect()
+    }
+
+    /// Prunes values older than the given version.
+    ///
+    /// Every value with a version older than `version` is dropped, except the value of
+    /// each key visible at `version` and the values pinned with `pin_version`, so reads
+    /// at `version` or later are unaffected. The tombstones of the keys removed
+    /// by `version` are dropped too, so `Snapshot::iter_as_of` no longer
+    /// orders those removals against the writes of the keys. Snapshots
+    /// created before the call keep their own view of the Trie and are not
+    /// affected either.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of pruned values.
+    ///
+    pub fn prune_versions_older_than(&mut self, version: u64) -> Result<usize, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let pins = self.version_pins.pinned();
+        if self.recorder.is_some() {
+            self.record(OpRecord::Prune {
+                version,
+                pinned: pins.clone(),
+            });
+        }
+
+        self.servable_from = self.servable_from.max(version);
+        self.tombstones.drop_removed_by(version);
+        Ok(self.prune(PruneRule::OlderThan(version), &pins))
+    }
+
+    /// Caps the number of values kept for each key.
+    ///
+    /// Every key keeps its `max_per_key` latest values, and at least its latest one,
+    /// along with the values pinned with `pin_version`. Unlike
+    /// `prune_versions_older_than`, this bounds the versions of keys that are written
+    /// often without dropping the history of keys that are not. The tombstones of
+    /// removed keys are all dropped. Snapshots created before the call keep
+    /// their own view of the Trie and are not affected.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of pruned values.
+    ///
+    pub fn retain_versions(&mut self, max_per_key: usize) -> Result<usize, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let pins = self.version_pins.pinned();
+        if self.recorder.is_some() {
+            self.record(OpRecord::RetainVersions {
+                max_per_key,
+                pinned: pins.clone(),
+            });
+        }
+
+        self.servable_from = self.servable_from.max(self.latest_version());
+        self.tombstones.clear();
+        Ok(self.prune(PruneRule::KeepLatest(max_per_key), &pins))
+    }
+
+    // Prunes the values that `rule` does not keep and that are not in `pins`,
+    // returning how many were pruned.
+    fn prune(&mut self, rule: PruneRule, pins: &[(Vec<u8>, u64)]) -> usize {
+        let Some(root) = &self.root else {
+            return 0;
+        };
+
+        let mut pruned_twigs = Vec::new();
+        let (new_root, pruned) = Node::prune_recurse(root, rule, pins, &mut 0, &mut pruned_twigs);
+
+        // Drop the old root only after the bookkeeping, so that a panicking drop
+        // of a pruned value cannot leave it half done.
+        let old_root = match new_root {
+            Some(new_root) => self.root.replace(new_root),
+            None => None,
+        };
+
+        if let Some(prefix_stats) = self.prefix_stats.as_mut() {
+            for (twig, count) in &pruned_twigs {
+                if let NodeType::Twig(twig) = &twig.node_type {
+                    prefix_stats.on_prune::<V>(twig.key.as_slice(), *count);
+                }
+            }
+        }
+
+        if self.version_warn_threshold.is_some() {
+            for (twig, _) in &pruned_twigs {
+                if let NodeType::Twig(twig) = &twig.node_type {
+                    self.track_version_count(&twig.key);
+                }
+            }
+        }
+
+        // Point the hash index at the rewritten twig nodes
+        if let Some(index) = self.hash_index.as_mut() {
+            for (twig, _) in &pruned_twigs {
+                index.insert(twig);
+            }
+        }
+        drop(old_root);
+
+        pruned
+    }
+
+    /// Finds the longest stored key that is a prefix of `input`.
+    ///
+    /// A trailing NULL terminator on stored keys is ignored, so a key created from
+    /// `"/api/"` with `VariableSizeKey::from_str` matches the input `b"/api/users"`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the bytes of the matching key and its latest value, or `None` if no
+    /// stored key is a prefix of `input`.
+    ///
+    pub fn longest_prefix_match(&self, input: &[u8]) -> Option<(Vec<u8>, V)> {
+        let root = self.root.as_ref()?;
+        let entry = Node::longest_prefix_match(root, input).and_then(Tree::latest_entry)?;
+        self.live_prefix_match(entry, input)
+    }
+
+    // Returns `entry`, the longest key matching `input`, unless it has expired,
+    // in which case the next longest key that has not is looked for.
+    fn live_prefix_match(&self, entry: (Vec<u8>, V), input: &[u8]) -> Option<(Vec<u8>, V)> {
+        if !self.is_expired(&entry.0) {
+            return Some(entry);
+        }
+        let len = entry.0.strip_suffix(&[0]).unwrap_or(&entry.0).len();
+        self.longest_prefix_match(&input[..len.checked_sub(1)?])
+    }
+
+    /// Finds the longest stored key that is a prefix of each of the given inputs.
+    ///
+    /// This is the batched form of `longest_prefix_match`. The inputs are sorted and
+    /// matched in a single walk of the Trie, so inputs sharing a prefix share the work
+    /// of descending to it.
+    ///
+    /// # Returns
+    ///
+    /// Returns the bytes of the matching key and its latest value for each input, in the
+    /// order of `inputs`, or `None` for inputs no stored key is a prefix of.
+    ///
+    pub fn match_prefixes_batch(&self, inputs: &[&[u8]]) -> Vec<Option<(Vec<u8>, V)>> {
+        let mut best = vec![None; inputs.len()];
+        if let Some(root) = &self.root {
+            let mut batch: Vec<usize> = (0..inputs.len()).collect();
+            batch.sort_by_key(|&i| inputs[i]);
+            Node::match_prefixes_recurse(root, inputs, &batch, 0, &mut best);
+        }
+
+        best.into_iter()
+            .zip(inputs)
+            .map(|(twig, input)| {
+                let entry = twig.and_then(Tree::latest_entry)?;
+                self.live_prefix_match(entry, input)
+            })
+            .collect()
+    }
+
+    /// Returns the smallest key in the Trie without reading its value.
+    ///
+    /// # Returns
+    ///
+    /// Returns the first key in key order, or `None` if the Trie is empty.
+    ///
+    pub fn first_key(&self) -> Option<P> {
+        if !self.expiry.is_empty() {
+            return self.iter().next().map(|(key, ..)| P::from(key.as_slice()));
+        }
+        let mut node = self.root.as_ref()?;
+        while !node.is_twig() {
+            node = node.next_child(0)?.1;
+        }
+        Tree::twig_key(node)
+    }
+
+    /// Returns the largest key in the Trie without reading its value.
+    ///
+    /// # Returns
+    ///
+    /// Returns the last key in key order, or `None` if the Trie is empty.
+    ///
+    pub fn last_key(&self) -> Option<P> {
+        if !self.expiry.is_empty() {
+            return self.last_live_key(self.root.as_ref()?);
+        }
+        let mut node = self.root.as_ref()?;
+        while !node.is_twig() {
+            node = node.last_child()?;
+        }
+        Tree::twig_key(node)
+    }
+
+    // Returns the largest key below `node` that has not expired, visiting the
+    // children from the last one back.
+    fn last_live_key(&self, node: &Node<P, V>) -> Option<P> {
+        if node.is_twig() {
+            return Tree::twig_key(node).filter(|key| !self.is_expired(key.as_slice()));
+        }
+        let children: Vec<_> = node.iter().map(|(_, child)| child).collect();
+        children
+            .into_iter()
+            .rev()
+            .find_map(|child| self.last_live_key(child))
+    }
+
+    /// Returns the key of a twig node.
+    fn twig_key(node: &Node<P, V>) -> Option<P> {
+        let NodeType::Twig(twig) = &node.node_type else {
+            return None;
+        };
+        Some(twig.key.clone())
+    }
+
+    /// Returns the key and latest value of a twig node.
+    fn latest_entry(node: &Node<P, V>) -> Option<(Vec<u8>, V)> {
+        let NodeType::Twig(twig) = &node.node_type else {
+            return None;
+        };
+        let value = twig.get_latest_value()?;
+        Some((twig.key.as_slice().to_vec(), value.clone()))
+    }
+
+    /// Returns the statistics of the keys whose first segment is `segment`.
+    ///
+    /// Statistics are only kept for trees created with
+    /// `TreeOptions::track_prefix_stats`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the statistics of the segment, or `None` if it holds no keys or
+    /// statistics are not kept.
+    ///
+    pub fn prefix_stats(&self, segment: &[u8]) -> Option<PrefixStats> {
+        self.prefix_stats.as_ref()?.get(segment)
+    }
+
+    /// Returns an iterator over the statistics of every segment holding keys, in
+    /// segment order.
+    pub fn iter_prefix_stats(&self) -> impl Iterator<Item = (&[u8], &PrefixStats)> {
+        self.prefix_stats.iter().flat_map(|table| table.iter())
+    }
+
+    /// Recomputes the per-prefix statistics from the Trie and compares them with
+    /// the ones maintained incrementally.
+    ///
+    /// This walks the whole Trie and is meant for tests and debugging.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::PrefixStatsMismatch` with the first segment whose
+    /// statistics differ.
+    ///
+    pub fn verify_prefix_stats(&self) -> Result<(), TrieError> {
+        let Some(table) = &self.prefix_stats else {
+            return Ok(());
+        };
+
+        let mut expected = table.empty();
+        if let Some(root) = &self.root {
+            let mut stack = vec![root];
+            while let Some(node) = stack.pop() {
+                match &node.node_type {
+                    NodeType::Twig(twig) => {
+                        let key = twig.key.as_slice();
+                        expected.on_insert::<V>(key, true);
+                        for _ in 1..twig.values.len() {
+                            expected.on_insert::<V>(key, false);
+                        }
+                    }
+                    _ => stack.extend(node.iter().map(|(_, child)| child)),
+                }
+            }
+        }
+
+        match table.first_difference(&expected) {
+            Some(segment) => Err(TrieError::PrefixStatsMismatch { segment }),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns an iterator over the keys ending with `suffix` and their latest values.
+    ///
+    /// A trailing NULL terminator on stored keys is ignored, so a key created from
+    /// `"notes.tmp"` with `VariableSizeKey::from_str` ends with `b".tmp"`. Keys are
+    /// yielded in the order of their reversed bytes.
+    ///
+    /// With a suffix index, enabled by `TreeOptions::with_suffix_index`, only the
+    /// matching keys are visited. Without one, the whole Trie is scanned.
+    ///
+    pub fn scan_suffix(&self, suffix: &[u8]) -> impl Iterator<Item = (P, V)> + '_ {
+        let keys = match &self.suffix_index {
+            Some(index) => index.scan(suffix),
+            None => {
+                let mut keys: Vec<Vec<u8>> = self
+                    .iter()
+                    .map(|(key, _, _, _)| key)
+                    .filter(|key| key.strip_suffix(&[0]).unwrap_or(key).ends_with(suffix))
+                    .collect();
+                keys.sort_by(|a, b| a.iter().rev().cmp(b.iter().rev()));
+                keys
+            }
+        };
+
+        keys.into_iter().filter_map(move |key| {
+            let key = P::from(key.as_slice());
+            if self.is_expired(key.as_slice()) {
+                return None;
+            }
+            let twig = Node::find_twig(self.root.as_ref()?, &key)?;
+            let value = Tree::latest_entry(twig)?.1;
+            Some((key, value))
+        })
+    }
+
+    /// Checks the structural invariants of the Trie and of the indexes it keeps.
+    ///
+    /// Every inner node must have children, stored in increasing order of their
+    /// key byte, each with a prefix starting with that byte and no newer than the
+    /// node. Every twig must hold the key spelled by the prefixes on its path and
+    /// at least one value, with values sorted by version. The hash index, prefix
+    /// statistics and suffix index, if kept, must match the keys.
+    ///
+    /// This walks the whole Trie, and is meant for tests and debugging.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::InvalidStructure` with the key bytes leading to the
+    /// first node that breaks an invariant, or the error of the first index that
+    /// does not match the Trie.
+    ///
+    pub fn verify(&self) -> Result<(), TrieError> {
+        let Some(root) = &self.root else {
+            return match &self.hash_index {
+                Some(index) if index.len() > 0 => Err(TrieError::InvalidStructure {
+                    path: Vec::new(),
+                    reason: "hash index has keys of an empty tree",
+                }),
+                _ => Ok(()),
+            };
+        };
+
+        // The hash index may point at an older copy of a twig whose prefix has
+        // changed since, but it must hold the same values. An entry whose copy
+        // has been dropped is stale and is resolved through the tree instead.
+        let same_values =
+            |indexed: &Arc<Node<P, V>>, twig: &TwigNode<P, V>| match &indexed.node_type {
+                NodeType::Twig(indexed) => {
+                    indexed.values.len() == twig.values.len()
+                        && indexed
+                            .values
+                            .iter()
+                            .zip(twig.values.iter())
+                            .all(|(a, b)| Arc::ptr_eq(a, b))
+                }
+                _ => false,
+            };
+        let mut twigs = 0;
+        Node::verify_recurse(root, &mut Vec::new(), &mut |twig| {
+            twigs += 1;
+            match &self.hash_index {
+                Some(index)
+                    if !match index.get(&twig.key) {
+                        Indexed::Twig(indexed) => same_values(&indexed, twig),
+                        Indexed::Absent => false,
+                        Indexed::Stale => true,
+                    } =>
+                {
+                    Err(TrieError::InvalidStructure {
+                        path: twig.key.as_slice().to_vec(),
+                        reason: "hash index does not match the twig",
+                    })
+                }
+                _ => Ok(()),
+            }
+        })?;
+        if self
+            .hash_index
+            .as_ref()
+            .is_some_and(|index| index.len() != twigs)
+        {
+            return Err(TrieError::InvalidStructure {
+                path: Vec::new(),
+                reason: "hash index has keys missing from the tree",
+            });
+        }
+
+        self.verify_prefix_stats()?;
+        self.verify_suffix_index()
+    }
+
+    /// Compares the suffix index with the keys of the Trie.
+    ///
+    /// This walks the whole Trie and the whole index, and is meant for tests and
+    /// debugging.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::SuffixIndexMismatch` with the first key found in only
+    /// one of them.
+    ///
+    pub fn verify_suffix_index(&self) -> Result<(), TrieError> {
+        let Some(index) = &self.suffix_index else {
+            return Ok(());
+        };
+
+        let indexed = index.keys();
+        let mut keys = self.iter().map(|(key, _, _, _)| key);
+        for indexed_key in indexed {
+            match keys.next() {
+                Some(key) if key == indexed_key => continue,
+                Some(key) => {
+                    return Err(TrieError::SuffixIndexMismatch {
+                        key: key.min(indexed_key),
+                    })
+                }
+                None => return Err(TrieError::SuffixIndexMismatch { key: indexed_key }),
+            }
+        }
+        match keys.next() {
+            Some(key) => Err(TrieError::SuffixIndexMismatch { key }),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes an advisory lock on a key prefix for `owner`.
+    ///
+    /// While the lock is held, inserts and removals of keys starting with `prefix`
+    /// are rejected with `TrieError::PrefixLocked` unless they are made through
+    /// `insert_as`/`remove_as` by the same owner. The lock is released when the
+    /// returned guard is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::PrefixLocked` if a lock is already held on a prefix that
+    /// contains, or is contained by, `prefix`.
+    ///
+    pub fn try_lock_prefix(&mut self, prefix: &[u8], owner: u64) -> Result<PrefixLock, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        self.prefix_locks.lock(prefix, owner)
+    }
+
+    /// Starts recording every mutation of the Trie into `sink`.
+    ///
+    /// Inserts (with the version they were resolved to), bulk inserts, removals,
+    /// pruning, and snapshots created and closed on the Trie are passed to the
+    /// sink after they are applied. Writes made to a snapshot do not modify the
+    /// Trie and are not recorded. A sink set earlier is replaced.
+    ///
+    pub fn enable_recording<S: OpSink<P, V> + 'static>(&mut self, sink: S) {
+        self.recorder = Some(Box::new(sink));
+    }
+
+    pub(crate) fn record(&mut self, op: OpRecord<P, V>) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(op);
+        }
+    }
+
+    /// Builds a Trie by re-executing recorded operations.
+    ///
+    /// Every operation is applied with the versions captured when it was
+    /// recorded, and the replay checks that it gets the same result: inserts
+    /// must land at the recorded version and snapshots must get the recorded
+    /// IDs. Pruning pins the recorded versions for the duration of the prune.
+    /// Snapshots created during the replay stay registered until a recorded
+    /// close, so the snapshot count matches as well.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::ReplayDiverged` with the index of the first operation
+    /// whose outcome differs from the recording, or the error of an operation
+    /// that fails.
+    ///
+    pub fn replay<I: IntoIterator<Item = OpRecord<P, V>>>(ops: I) -> Result<Self, TrieError> {
+        Self::replay_with_options(TreeOptions::default(), ops)
+    }
+
+    /// Builds a Trie with the given options by re-executing recorded operations.
+    ///
+    /// Behaves like `replay`. The options should match those of the recorded
+    /// Trie: in particular, inserts sharing a timestamp are only applied the same
+    /// way under the same `DuplicateTsPolicy`.
+    ///
+    pub fn replay_with_options<I: IntoIterator<Item = OpRecord<P, V>>>(
+        options: TreeOptions,
+        ops: I,
+    ) -> Result<Self, TrieError> {
+        Tree::with_options(options).replay_into(ops)
+    }
+
+    /// Re-executes recorded operations on this Trie, which should be empty.
+    ///
+    /// Behaves like `replay`, for Tries configured beyond their options. On a
+    /// Trie built with `dedup_identical_versions`, recorded inserts repeating the
+    /// latest value of their key are skipped rather than reported as diverging.
+    ///
+    pub fn replay_into<I: IntoIterator<Item = OpRecord<P, V>>>(
+        self,
+        ops: I,
+    ) -> Result<Self, TrieError> {
+        let mut tree = self;
+        for (op, record) in ops.into_iter().enumerate() {
+            let diverged = TrieError::ReplayDiverged { op };
+            match record {
+                OpRecord::Insert {
+                    key,
+                    value,
+                    version,
+                    ts,
+                } => {
+                    let outcome = tree.insert_with_outcome(&key, value, version, ts)?;
+                    if outcome.created && tree.version() != version {
+                        return Err(diverged);
+                    }
+                }
+                OpRecord::BulkInsert { entries } => {
+                    let kv_pairs: Vec<KV<P, V>> = entries
+                        .into_iter()
+                        .map(|(key, value, version, ts)| KV::new(key, value, version, ts))
+                        .collect();
+                    tree.bulk_insert(&kv_pairs)?;
+                }
+                OpRecord::Remove { key } => {
+                    tree.remove(&key)?;
+                }
+                OpRecord::Prune { version, pinned } => {
+                    let _pins: Vec<VersionPin> = pinned
+                        .iter()
+                        .map(|(key, version)| tree.version_pins.pin(key, *version))
+                        .collect();
+                    tree.prune_versions_older_than(version)?;
+                }
+                OpRecord::RetainVersions {
+                    max_per_key,
+                    pinned,
+                } => {
+                    let _pins: Vec<VersionPin> = pinned
+                        .iter()
+                        .map(|(key, version)| tree.version_pins.pin(key, *version))
+                        .collect();
+                    tree.retain_versions(max_per_key)?;
+                }
+                OpRecord::CreateSnapshot { id } => {
+                    if tree.create_snapshot()?.id() != id {
+                        return Err(diverged);
+                    }
+                }
+                OpRecord::CloseSnapshot { id } => {
+                    tree.close_snapshot(id).map_err(|_| diverged)?;
+                }
+            }
+        }
+        Ok(tree)
+    }
+
+    /// Fails if the Trie is closed or poisoned.
+    pub(crate) fn is_closed(&self) -> Result<(), TrieError> {
+        if self.closed {
+            return Err(TrieError::TreeAlreadyClosed);
+        }
+        if let Some(report) = self.poison.get() {
+            return Err(TrieError::Poisoned {
+                first_violation: report.violation.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Looks up the twig holding `key`, which must be normalized, running the
+    /// invariant checks of strict mode on the way and poisoning the Trie if one
+    /// fails.
+    pub(crate) fn find_twig_strict(
+        &self,
+        key: &P,
+        operation: &'static str,
+    ) -> Result<Option<&Arc<Node<P, V>>>, TrieError> {
+        let Some(root) = &self.root else {
+            return Ok(None);
+        };
+        match self.invariant_checks {
+            None => Ok(Node::find_twig(root, key)),
+            Some(checks) => Node::find_twig_checked(root, key, checks)
+                .map_err(|violation| self.poison(violation, key, operation)),
+        }
+    }
+
+    /// Poisons the Trie with `violation`, unless it already is, and returns the
+    /// error carrying the first violation.
+    fn poison(&self, violation: InvariantViolation, key: &P, operation: &'static str) -> TrieError {
+        let report = self.poison.get_or_init(|| PoisonReport {
+            violation,
+            key: key.as_slice().to_vec(),
+            operation,
+            version: self.version(),
+        });
+        TrieError::Poisoned {
+            first_violation: report.violation.clone(),
+        }
+    }
+
+    /// Poisons the Trie with the violation found by the checks of an insert
+    /// that failed with `err`, if it failed on one, and returns the error.
+    pub(crate) fn poison_on_violation(
+        &self,
+        err: TrieError,
+        stats: &mut InsertStats,
+        key: &P,
+        operation: &'static str,
+    ) -> TrieError {
+        match stats.violation.take() {
+            Some(violation) => self.poison(violation, key, operation),
+            None => err,
+        }
+    }
+
+    /// Returns the details of the violation that poisoned the Trie, or `None` if
+    /// it is not poisoned.
+    ///
+    /// Only a Trie built with `TreeOptions::poison_on_corruption` or
+    /// `TreeOptions::invariant_checks` can be poisoned.
+    pub fn poison_report(&self) -> Option<&PoisonReport> {
+        self.poison.get()
+    }
+
+    /// Closes the tree, preventing further modifications, and releases associated resources.
+    pub fn close(&mut self) -> Result<(), TrieError> {
+        // Check if the tree is already closed. A poisoned tree can still be
+        // released.
+        if self.closed {
+            return Err(TrieError::TreeAlreadyClosed);
+        }
+
+        // Check if there are any active readers for the snapshot
+        if self.snapshot_count() > 0 {
+            return Err(TrieError::SnapshotNotClosed);
+        }
+
+        // Mark the snapshot as closed
+        self.closed = true;
+
+        Ok(())
+    }
+}
+
+impl<P: KeyTrait, V: Clone + PartialEq> Tree<P, V> {
+    /// Sets whether writes of a value equal to the latest value of its key are
+    /// skipped instead of creating a new version.
+    ///
+    /// Idempotent writers re-writing the same value then leave no run of
+    /// identical versions behind. A skipped write leaves the Trie untouched: the
+    /// version of the Trie does not advance, and the latest version keeps its
+    /// timestamp, so reads at any version see what they would have seen before
+    /// the write. `insert_with_outcome` reports whether a write was skipped. The
+    /// setting also applies to `bulk_insert`, to writes to snapshots taken
+    /// afterwards, and to `replay_into`. A key without a value is never a repeat,
+    /// so writing a removed key always creates a version.
+    ///
+    pub fn dedup_identical_versions(mut self, enabled: bool) -> Self {
+        self.value_eq = enabled.then_some(<V as PartialEq>::eq as fn(&V, &V) -> bool);
+        self
+    }
+}
+
+impl<P: KeyTrait> Tree<P, u64> {
+    /// Inserts a key whose value is stored outside the Trie, and returns the
+    /// leaf id generated for the write.
+    ///
+    /// The Trie then only indexes keys: the id is stored as the value of the new
+    /// version, and the caller maps it to the value in its own store. An id is
+    /// the version the write is committed at, so ids grow with every write,
+    /// including writes to other keys, and are never handed out twice by the
+    /// same Trie, or by Tries sharing its clock.
+    ///
+    /// # Errors
+    ///
+    /// Fails like `insert`.
+    ///
+    pub fn insert_external(&mut self, key: &P, ts: u64) -> Result<u64, TrieError> {
+        let id = self.latest_version().max(self.last_leaf_id) + 1;
+        self.insert(key, id, id, ts)?;
+        self.last_leaf_id = id;
+        Ok(id)
+    }
+
+    /// Returns the leaf id of the latest version of a key written at or before
+    /// the timestamp `ts`.
+    ///
+    /// Returns `None` if the key is absent, has no version that old, or the Trie
+    /// is closed.
+    ///
+    pub fn get_id(&self, key: &P, ts: u64) -> Option<u64> {
+        self.is_closed().ok()?;
+        let key = self.normalize(key);
+        let twig = Node::find_twig(self.root.as_ref()?, key.as_ref())?;
+        let NodeType::Twig(twig) = &twig.node_type else {
+            return None;
+        };
+        twig.iter()
+            .rev()
+            .find(|leaf| leaf.ts <= ts)
+            .map(|leaf| leaf.value)
+    }
+}
+
+impl<P: KeyTrait, V: Clone, M: Clone> Tree<P, WithMeta<V, M>> {
+    /// Inserts a value into the Trie at the next version, along with metadata
+    /// about the write.
+    ///
+    /// # Returns
+    ///
+    /// Returns the previous latest value of the key and its metadata, if any.
+    ///
+    pub fn insert_with_meta(
+        &mut self,
+        key: &P,
+        value: V,
+        ts: u64,
+        meta: M,
+    ) -> Result<Option<WithMeta<V, M>>, TrieError> {
+        self.insert(key, WithMeta { value, meta }, 0, ts)
+    }
+
+    /// Retrieves the value of a key at the given version along with the metadata
+    /// it was written with.
+    ///
+    /// A version of 0 reads the latest value, as with `get`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the value, its metadata, and the version and timestamp it was
+    /// written at.
+    ///
+    pub fn get_with_meta(&self, key: &P, version: u64) -> Result<(V, M, u64, u64), TrieError> {
+        let (_, entry, version, ts) = self.get(key, version)?;
+        Ok((entry.value, entry.meta, version, ts))
+    }
+}
+
+impl<P: KeyTrait, V: Clone + ValueCodec> Tree<P, V> {
+    /// Creates a new Trie with the given options, like `with_options`, along
+    /// with the options that read the values through their `ValueCodec`:
+    /// `TreeOptions::value_checksums`.
+    pub fn with_codec_options(options: TreeOptions) -> Self {
+        Tree {
+            value_check: options
+                .value_checksums
+                .then_some(V::is_intact as fn(&V) -> bool),
+            ..Tree::with_options(options)
+        }
+    }
+
+    /// Writes the latest value of every key of the Trie in the flat format read by
+    /// `FrozenTree`, with its version and timestamp.
+    ///
+    /// The buffer can be stored or memory-mapped, and opened with
+    /// `FrozenTree::open` without decoding it. The options of the Trie are
+    /// written along, for `thaw` to restore.
+    ///
+    pub fn freeze(&self) -> Vec<u8> {
+        frozen::write(
+            self.iter()
+                .map(|(key, value, version, ts)| (key, value, *version, *ts)),
+            self.version(),
+            self.expiry.rules(),
+            self.ts_domains.iter().flat_map(|domains| domains.iter()),
+            &self.options(),
+        )
+    }
+
+    /// Applies the changes read from `reader`, written by `serialize_delta`, in
+    /// the order they were written.
+    ///
+    /// Inserted values keep the timestamp they were recorded with, while their
+    /// versions are assigned by the Trie as for an insert at version 0, since a
+    /// changelog from `diff_since` is in key order rather than version order.
+    /// Removing a key that is absent is not an error, so a log can be replayed
+    /// over a Trie that already holds part of it. On a Trie that validates
+    /// ingest timestamps, each inserted timestamp is validated, or repaired,
+    /// against the previous timestamp of its key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::CorruptChangelog` with the offset of the first record
+    /// that is cut short or malformed, such as one torn by a crash, after
+    /// applying every record before it, and `TrieError::InvalidTimestamp` with
+    /// the index of the first record with an invalid timestamp. Errors of the
+    /// inserts and removes are returned as is.
+    ///
+    pub fn apply_wal<R: Read>(&mut self, reader: R) -> Result<(), TrieError> {
+        self.is_closed()?;
+        let mut changes = ChangeReader::new(reader);
+        let mut validator = self.ts_validator();
+        let mut position = 0;
+        while let Some(change) = changes.next_change::<P, V>()? {
+            match change {
+                Change::Insert { key, value, ts, .. } => {
+                    let ts = match validator.as_mut() {
+                        Some(validator) => validator
+                            .check(key.as_slice(), position, ts, || self.latest_ts_of(&key))?,
+                        None => ts,
+                    };
+                    self.insert(&key, value, 0, ts)?;
+                }
+                Change::Remove { key } => {
+                    self.remove(&key)?;
+                    if let Some(validator) = validator.as_mut() {
+                        validator.forget(key.as_slice());
+                    }
+                }
+            }
+            position += 1;
+        }
+        Ok(())
+    }
+
+    /// Builds a Trie from the entries of a frozen tree.
+    ///
+    /// Each key holds a single version, with the version and timestamp it had
+    /// when frozen. Unlike the frozen tree, the whole buffer is decoded up front.
+    /// The expiry rules, the options and the timestamp domains of the frozen
+    /// tree are restored, and the current timestamp is the latest timestamp of
+    /// its entries. Buffers written
+    /// before options were kept are thawed with the default options.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error met while reading the frozen tree, or
+    /// `OpenError::Unsorted` if its keys are not in strictly increasing order.
+    ///
+    pub fn thaw(frozen: &FrozenTree<V>) -> Result<Self, OpenError> {
+        let options = frozen.options()?.unwrap_or_default();
+        let mut tree = Tree::with_codec_options(options);
+        let mut root: Option<Arc<Node<P, V>>> = None;
+        let mut prev: Option<&[u8]> = None;
+        let mut current_ts = 0;
+        for (index, entry) in frozen.iter().enumerate() {
+            let (key, value, version, ts) = entry?;
+            if prev.is_some_and(|prev| prev >= key) {
+                return Err(OpenError::Unsorted { index });
+            }
+            prev = Some(key);
+            current_ts = current_ts.max(ts);
+            if let Some(domains) = tree.ts_domains.as_mut() {
+                domains.advance(key, ts);
+            }
+            if let Some(prefix_stats) = tree.prefix_stats.as_mut() {
+                prefix_stats.on_insert::<V>(key, true);
+            }
+            if let Some(suffix_index) = tree.suffix_index.as_mut() {
+                suffix_index.insert(key);
+            }
+            let key = P::from(key);
+            root = Some(match &root {
+                None => Arc::new(Node::new_twig(key.clone(), key, value, version, ts)),
+                Some(node) => {
+                    let mut stats = InsertStats::default();
+                    Node::insert_recurse(
+                        node,
+                        &key,
+                        value,
+                        version,
+                        ts,
+                        0,
+                        DuplicateTsPolicy::Stack,
+                        VersioningStrategy::KeepAll,
+                        tree.forced_node_type,
+                        &mut stats,
+                    )
+                    .expect("inserting with the Stack policy cannot fail")
+                    .0
+                }
+            });
+        }
+        for rule in frozen.expiry_rules()? {
+            tree.expiry.set(&rule.0, rule.1);
+        }
+        if let Some(domains) = tree.ts_domains.as_mut() {
+            for (segment, ts) in frozen.ts_domains()? {
+                domains.adopt(&segment, ts);
+            }
+        }
+        tree.root = root;
+        tree.current_ts = current_ts;
+        // Only the latest value of each key was frozen.
+        tree.servable_from = frozen.version();
+        tree.recount_overloaded_keys();
+        Ok(tree)
+    }
+}
+
+/*
+    Test cases for Adaptive Radix Tree
+*/
+
+#[cfg(test)]
+// The older tests insert without checking the result.
+#[allow(unused_must_use)]
+mod tests {
+    use super::{Node, NodeType, Tree, WithMeta, KV};
+    use crate::iter::{IterationPointer, ScanBuffer, TraversalFault};
+    use crate::node::{TwigNode, Version};
+    use crate::pressure::{Pressure, PressureLevel, TreeOptions};
+    use crate::{FixedSizeKey, Key, TrieError, VariableSizeKey};
+    use std::collections::hash_map::RandomState;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use std::fs::File;
+    use std::io::{self, BufRead, BufReader};
+
+    fn read_words_from_file(file_path: &str) -> io::Result<Vec<String>> {
+        let file = File::open(file_path)?;
+        let reader = BufReader::new(file);
+        let words: Vec<String> = reader.lines().map_while(Result::ok).collect();
+        Ok(words)
+    }
+
+    #[test]
+    fn insert_search_delete_words() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        let file_path = "testdata/words.txt";
+
+        if let Ok(words) = read_words_from_file(file_path) {
+            // Insertion phase
+            for word in &words {
+                let key = &VariableSizeKey::from_str(word).unwrap();
+                tree.insert(key, 1, 0, 0).unwrap();
+            }
+
+            // Search phase
+            for word in &words {
+                let key = VariableSizeKey::from_str(word).unwrap();
+                let (_, val, _, _) = tree.get(&key, 0).unwrap();
+                assert_eq!(val, 1);
+            }
+
+            // Deletion phase
+            for word in &words {
+                let key = VariableSizeKey::from_str(word).unwrap();
+                assert!(tree.remove(&key).unwrap());
+            }
+        } else if let Err(err) = read_words_from_file(file_path) {
+            eprintln!("Error reading file: {}", err);
+        }
+
+        assert_eq!(tree.version(), 0);
+    }
+
+    #[test]
+    fn string_insert_delete() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+        // Insertion phase
+        let insert_words = [
+            "a", "aa", "aal", "aalii", "abc", "abcd", "abcde", "xyz", "axyz",
+        ];
+
+        for word in &insert_words {
+            tree.insert(&VariableSizeKey::from_str(word).unwrap(), 1, 0, 0)
+                .unwrap();
+        }
+
+        // Deletion phase
+        for word in &insert_words {
+            assert!(tree
+                .remove(&VariableSizeKey::from_str(word).unwrap())
+                .unwrap());
+        }
+    }
+
+    #[test]
+    fn string_long() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+        // Insertion phase
+        let words_to_insert = [
+            ("amyelencephalia", 1),
+            ("amyelencephalic", 2),
+            ("amyelencephalous", 3),
+        ];
+
+        for (word, val) in &words_to_insert {
+            tree.insert(&VariableSizeKey::from_str(word).unwrap(), *val, 0, 0)
+                .unwrap();
+        }
+
+        // Verification phase
+        for (word, expected_val) in &words_to_insert {
+            let (_, val, _, _) = tree
+                .get(&VariableSizeKey::from_str(word).unwrap(), 0)
+                .unwrap();
+            assert_eq!(val, *expected_val);
+        }
+    }
+
+    #[test]
+    fn root_set_get() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+        // Insertion phase
+        let key = VariableSizeKey::from_str("abc").unwrap();
+        let value = 1;
+        tree.insert(&key, value, 0, 0);
+
+        // Verification phase
+        let (_, val, _ts, _) = tree.get(&key, 0).unwrap();
+        assert_eq!(val, value);
+    }
+
+    #[test]
+    fn string_duplicate_insert() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+        // First insertion
+        let key = VariableSizeKey::from_str("abc").unwrap();
+        let value = 1;
+        let result = tree.insert(&key, value, 0, 0).expect("Failed to insert");
+        assert!(result.is_none());
+
+        // Second insertion (duplicate)
+        let result = tree.insert(&key, value, 0, 0).expect("Failed to insert");
+        assert!(result.is_some());
+    }
+
+    // Inserting a single value into the tree and removing it should result in a nil tree root.
+    #[test]
+    fn remove_prefixes_removes_subtrees() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+        let keys = [
+            "ns1/a", "ns1/b", "ns1/c/d", "ns10/a", "ns2/a", "ns2/b", "ns3/a", "ns", "other",
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            let key = VariableSizeKey::from_str(key).unwrap();
+            tree.insert(&key, i as i32, 0, 0).unwrap();
+        }
+        let snapshot = tree.create_snapshot().unwrap();
+
+        let prefixes = ["ns3/", "ns1/", "ns1/c/", "missing/"]
+            .map(|prefix| VariableSizeKey::from_slice(prefix.as_bytes()));
+        assert_eq!(tree.remove_prefixes(&prefixes, 5).unwrap(), 4);
+        assert_eq!(tree.current_ts(), 5);
+
+        let left: Vec<Vec<u8>> = tree.iter().map(|(key, ..)| key).collect();
+        let expected: Vec<Vec<u8>> = ["ns", "ns10/a", "ns2/a", "ns2/b", "other"]
+            .iter()
+            .map(|key| VariableSizeKey::from_str(key).unwrap().as_slice().to_vec())
+            .collect();
+        assert_eq!(left, expected);
+        tree.verify().unwrap();
+        assert_eq!(snapshot.count().unwrap(), keys.len());
+
+        // Keys under a locked prefix are not removed, and neither is anything else.
+        let _lock = tree.try_lock_prefix(b"ns2/b", 1).unwrap();
+        let prefixes = [b"ns".as_slice(), b"other"].map(VariableSizeKey::from_slice);
+        assert!(matches!(
+            tree.remove_prefixes(&prefixes, 6),
+            Err(TrieError::PrefixLocked { .. })
+        ));
+        assert_eq!(tree.iter().count(), expected.len());
+
+        // An empty prefix removes every key.
+        drop(_lock);
+        let everything = [VariableSizeKey::from_slice(b"")];
+        assert_eq!(tree.remove_prefixes(&everything, 6).unwrap(), 5);
+        assert!(tree.root.is_none());
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+        // Insertion
+        let key = VariableSizeKey::from_str("test").unwrap();
+        let value = 1;
+        tree.insert(&key, value, 0, 0);
+
+        // Removal
+        assert!(tree.remove(&key).unwrap());
+
+        // Verification
+        assert!(tree.get(&key, 0).is_err());
+    }
+
+    #[test]
+    fn inserting_keys_with_common_prefix() {
+        let key1 = VariableSizeKey::from_str("foo").unwrap();
+        let key2 = VariableSizeKey::from_str("foo2").unwrap();
+
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+        // Insertion
+        tree.insert(&key1, 1, 0, 0);
+        tree.insert(&key2, 1, 0, 0);
+
+        // Removal
+        assert!(tree.remove(&key1).unwrap());
+
+        // Root verification
+        if let Some(root) = &tree.root {
+            assert_eq!(root.node_type_name(), "Node1");
+        } else {
+            panic!("Tree root is None");
+        }
+    }
+
+    // Inserting Two values into the tree and removing one of them
+    // should result in a tree root of type twig
+    #[test]
+    fn insert2_and_remove1_and_root_should_be_node1() {
+        let key1 = VariableSizeKey::from_str("test1").unwrap();
+        let key2 = VariableSizeKey::from_str("test2").unwrap();
+
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+        // Insertion
+        tree.insert(&key1, 1, 0, 0);
+        tree.insert(&key2, 1, 0, 0);
+
+        // Removal
+        assert!(tree.remove(&key1).unwrap());
+
+        // Root verification
+        if let Some(root) = &tree.root {
+            assert_eq!(root.node_type_name(), "Node1");
+        } else {
+            panic!("Tree root is None");
+        }
+    }
+
+    #[test]
+    fn removing_a_deep_key_keeps_its_siblings() {
+        // The removal rebuilds every node on the path with the updated child,
+        // rather than dropping the whole child subtree, in a Tree as in a
+        // snapshot.
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        for (i, k) in ["abc1", "abc2", "abc3", "abd", "b"].iter().enumerate() {
+            tree.insert(&key(k), i as i32, 0, 0).unwrap();
+        }
+        let mut snap = tree.create_snapshot().unwrap();
+        assert!(tree.remove(&key("abc1")).unwrap());
+        assert!(snap.remove(&key("abc1")).unwrap());
+        for k in ["abc2", "abc3", "abd", "b"] {
+            assert!(tree.get(&key(k), 0).is_ok(), "{} was dropped", k);
+            assert!(snap.get(&key(k)).is_ok(), "{} was dropped", k);
+        }
+        assert!(tree.get(&key("abc1"), 0).is_err());
+        assert!(snap.get(&key("abc1")).is_err());
+        assert_eq!(tree.iter().count(), 4);
+        tree.verify().unwrap();
+    }
+
+    // // Inserting Two values into a tree and deleting them both
+    // // should result in a nil tree root
+    // // This tests the expansion of the root into a NODE4 and
+    // // successfully collapsing into a twig and then nil upon successive removals
+    // #[test]
+    // fn insert2_and_remove2_and_root_should_be_nil() {
+    //     let key1 = &VariableSizeKey::from_str("test1");
+    //     let key2 = &VariableSizeKey::from_str("test2");
+
+    //     let mut tree = Tree::<VariableSizeKey, i32>::new();
+    //     tree.insert(key1, 1, 0, 0);
+    //     tree.insert(key2, 1, 0);
+
+    //     assert_eq!(tree.remove(key1), true);
+    //     assert_eq!(tree.remove(key2), true);
+
+    //     assert!(tree.root.is_none());
+    // }
+
+    #[test]
+    fn insert_into_nodes_left_full_by_shrinking() {
+        // Removing one child from a node just above a shrink threshold leaves a
+        // full node of the smaller type, which must grow again on the next insert.
+        for (count, shrunk, grown) in [
+            (2, "Node1", "Node4"),
+            (5, "Node4", "Node16"),
+            (17, "Node16", "Node48"),
+        ] {
+            let mut tree = Tree::<VariableSizeKey, i32>::new();
+            for i in 0..count {
+                let key = VariableSizeKey::from_slice(&[1, i, 0]);
+                tree.insert(&key, 1, 0, 0).unwrap();
+            }
+            assert!(tree
+                .remove(&VariableSizeKey::from_slice(&[1, 0, 0]))
+                .unwrap());
+            assert_eq!(tree.root.as_ref().unwrap().node_type_name(), shrunk);
+
+            let key = VariableSizeKey::from_slice(&[1, 100, 0]);
+            tree.insert(&key, 2, 0, 0).unwrap();
+            assert_eq!(tree.root.as_ref().unwrap().node_type_name(), grown);
+            assert_eq!(tree.get(&key, 0).unwrap().1, 2);
+        }
+    }
+
+    #[test]
+    fn insert_detailed_reports_node_upgrades() {
+        use super::NodeKind;
+
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+        let mut upgrades = Vec::new();
+        for i in 0..=255u8 {
+            let key = VariableSizeKey::from_slice(&[1, i, 0]);
+            let details = tree.insert_detailed(&key, 1, 0, 0).unwrap();
+            assert!(details.new_leaf);
+            // The second key splits the prefix of the first leaf, and every key
+            // after it is added to the node the split created.
+            assert_eq!(details.prefix_split, i == 1);
+            assert_eq!(details.depth, (i > 0) as usize);
+            if let Some(upgrade) = details.upgrade {
+                upgrades.push((i, upgrade.from, upgrade.to));
+            }
+        }
+        // Nodes grow as soon as they fill up: on the 4th, 16th and 48th child.
+        assert_eq!(
+            upgrades,
+            vec![
+                (3, NodeKind::Node4, NodeKind::Node16),
+                (15, NodeKind::Node16, NodeKind::Node48),
+                (47, NodeKind::Node48, NodeKind::Node256),
+            ]
+        );
+
+        // Overwriting a key changes nothing in the shape of the Trie.
+        let key = VariableSizeKey::from_slice(&[1, 7, 0]);
+        let details = tree.insert_detailed(&key, 2, 0, 0).unwrap();
+        assert_eq!(details.old_value, Some(1));
+        assert!(!details.new_leaf && !details.prefix_split);
+        assert_eq!(details.upgrade, None);
+        assert_eq!(details.depth, 1);
+
+        // The depth counted by the descent matches a lookup of the key, for
+        // updates, splits and new leaves at every level.
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+        for (i, k) in ["a", "ab", "abc", "abd", "b", "abcde", "abce", "ab", "a"]
+            .iter()
+            .enumerate()
+        {
+            let key = VariableSizeKey::from_str(k).unwrap();
+            let details = tree.insert_detailed(&key, i as i32, 0, 0).unwrap();
+            let mut path = Vec::new();
+            Node::search_path(tree.root.as_ref().unwrap(), &key, &mut path);
+            assert_eq!(details.depth, path.len() - 1, "{}", k);
+        }
+    }
+
+    // Inserting Five values into a tree and deleting one of them
+    // should result in a tree root of type NODE4
+    // This tests the expansion of the root into a NODE16 and
+    // successfully collapsing into a NODE4 upon successive removals
+    #[test]
+    fn insert5_and_remove1_and_root_should_be_node4() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+        // Insertion
+        for i in 0..5u32 {
+            let key = VariableSizeKey::from_slice(&i.to_be_bytes());
+            tree.insert(&key, 1, 0, 0);
+        }
+
+        // Removal
+        let key_to_remove = VariableSizeKey::from_slice(&1u32.to_be_bytes());
+        assert!(tree.remove(&key_to_remove).unwrap());
+
+        // Root verification
+        if let Some(root) = &tree.root {
+            assert!(root.is_inner());
+            assert_eq!(root.node_type_name(), "Node4");
+        } else {
+            panic!("Tree root is None");
+        }
+    }
+
+    //     // Inserting Five values into a tree and deleting all of them
+    //     // should result in a tree root of type nil
+    //     // This tests the expansion of the root into a NODE16 and
+    //     // successfully collapsing into a NODE4, twig, then nil
+    //     #[test]
+    //     fn insert5_and_remove5_and_root_should_be_nil() {
+    //         let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+    //         for i in 0..5u32 {
+    //             let key = &VariableSizeKey::from_slice(&i.to_be_bytes());
+    //             tree.insert(key, 1);
+    //         }
+
+    //         for i in 0..5u32 {
+    //             let key = &VariableSizeKey::from_slice(&i.to_be_bytes());
+    //             tree.remove(key);
+    //         }
+
+    //         assert!(tree.root.is_none());
+    //     }
+
+    // Inserting 17 values into a tree and deleting one of them should
+    // result in a tree root of type NODE16
+    // This tests the expansion of the root into a NODE48, and
+    // successfully collapsing into a NODE16
+    #[test]
+    fn insert17_and_remove1_and_root_should_be_node16() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+        // Insertion
+        for i in 0..17u32 {
+            let key = VariableSizeKey::from_slice(&i.to_be_bytes());
+            tree.insert(&key, 1, 0, 0);
+        }
+
+        // Removal
+        let key_to_remove = VariableSizeKey::from_slice(&2u32.to_be_bytes());
+        assert!(tree.remove(&key_to_remove).unwrap());
+
+        // Root verification
+        if let Some(root) = &tree.root {
+            assert!(root.is_inner());
+            assert_eq!(root.node_type_name(), "Node16");
+        } else {
+            panic!("Tree root is None");
+        }
+    }
+
+    #[test]
+    fn insert17_and_root_should_be_node48() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+        // Insertion
+        for i in 0..17u32 {
+            let key = VariableSizeKey::from_slice(&i.to_be_bytes());
+            tree.insert(&key, 1, 0, 0);
+        }
+
+        // Root verification
+        if let Some(root) = &tree.root {
+            assert!(root.is_inner());
+            assert_eq!(root.node_type_name(), "Node48");
+        } else {
+            panic!("Tree root is None");
+        }
+    }
+
+    // // Inserting 17 values into a tree and removing them all should
+    // // result in a tree of root type nil
+    // // This tests the expansion of the root into a NODE48, and
+    // // successfully collapsing into a NODE16, NODE4, twig, and then nil
+    // #[test]
+    // fn insert17_and_remove17_and_root_should_be_nil() {
+    //     let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+    //     for i in 0..17u32 {
+    //         let key = VariableSizeKey::from_slice(&i.to_be_bytes());
+    //         tree.insert(&key, 1);
+    //     }
+
+    //     for i in 0..17u32 {
+    //         let key = VariableSizeKey::from_slice(&i.to_be_bytes());
+    //         tree.remove(&key);
+    //     }
+
+    //     assert!(tree.root.is_none());
+    // }
+
+    // Inserting 49 values into a tree and removing one of them should
+    // result in a tree root of type NODE48
+    // This tests the expansion of the root into a NODE256, and
+    // successfully collapasing into a NODE48
+    #[test]
+    fn insert49_and_remove1_and_root_should_be_node48() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+        // Insertion
+        for i in 0..49u32 {
+            let key = VariableSizeKey::from_slice(&i.to_be_bytes());
+            tree.insert(&key, 1, 0, 0);
+        }
+
+        // Removal
+        let key_to_remove = VariableSizeKey::from_slice(&2u32.to_be_bytes());
+        assert!(tree.remove(&key_to_remove).unwrap());
+
+        // Root verification
+        if let Some(root) = &tree.root {
+            assert!(root.is_inner());
+            assert_eq!(root.node_type_name(), "Node48");
+        } else {
+            panic!("Tree root is None");
+        }
+    }
+
+    #[test]
+    fn insert49_and_root_should_be_node248() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+        // Insertion
+        for i in 0..49u32 {
+            let key = VariableSizeKey::from_slice(&i.to_be_bytes());
+            tree.insert(&key, 1, 0, 0);
+        }
+
+        // Root verification
+        if let Some(root) = &tree.root {
+            assert!(root.is_inner());
+            assert_eq!(root.node_type_name(), "Node256");
+        } else {
+            panic!("Tree root is None");
+        }
+    }
+
+    //     // // Inserting 49 values into a tree and removing all of them should
+    //     // // result in a nil tree root
+    //     // // This tests the expansion of the root into a NODE256, and
+    //     // // successfully collapsing into a Node48, Node16, Node4, twig, and finally nil
+    //     // #[test]
+    //     // fn insert49_and_remove49_and_root_should_be_nil() {
+    //     //     let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+    //     //     for i in 0..49u32 {
+    //     //         let key = &VariableSizeKey::from_slice(&i.to_be_bytes());
+    //     //         tree.insert(key, 1);
+    //     //     }
+
+    //     //     for i in 0..49u32 {
+    //     //         let key = VariableSizeKey::from_slice(&i.to_be_bytes());
+    //     //         assert_eq!(tree.remove(&key), true);
+    //     //     }
+
+    //     //     assert!(tree.root.is_none());
+    //     // }
+
+    #[derive(Debug, Clone, PartialEq)]
+    #[allow(clippy::upper_case_acronyms)]
+    struct KVT {
+        k: Vec<u8>,   // Key
+        version: u64, // version
+    }
+
+    #[test]
+    #[allow(clippy::explicit_counter_loop)]
+    fn timed_insertion() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+
+        let kvts = vec![
+            KVT {
+                k: b"key1_0".to_vec(),
+                version: 0,
+            },
+            KVT {
+                k: b"key2_0".to_vec(),
+                version: 0,
+            },
+            KVT {
+                k: b"key3_0".to_vec(),
+                version: 0,
+            },
+            KVT {
+                k: b"key4_0".to_vec(),
+                version: 0,
+            },
+            KVT {
+                k: b"key5_0".to_vec(),
+                version: 0,
+            },
+            KVT {
+                k: b"key6_0".to_vec(),
+                version: 0,
+            },
+        ];
+
+        // Insertion
+        for (idx, kvt) in kvts.iter().enumerate() {
+            let ts = if kvt.version == 0 {
+                idx as u64 + 1
+            } else {
+                kvt.version
+            };
+            assert!(tree
+                .insert(&VariableSizeKey::from(kvt.k.clone()), 1, ts, 0)
+                .is_ok());
+        }
+
+        // Verification
+        let mut curr_version = 1;
+        for kvt in &kvts {
+            let key = VariableSizeKey::from(kvt.k.clone());
+            let (_, val, version, _ts) = tree.get(&key, 0).unwrap();
+            assert_eq!(val, 1);
+
+            if kvt.version == 0 {
+                assert_eq!(curr_version, version);
+            } else {
+                assert_eq!(kvt.version, version);
+            }
+
+            curr_version += 1;
+        }
+
+        // Root's version should match the greatest inserted version
+        assert_eq!(kvts.len() as u64, tree.version());
+    }
+
+    #[test]
+    fn timed_insertion_update_same_key() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+
+        let key1 = &VariableSizeKey::from_str("key_1").unwrap();
+
+        // insert key1 with version 0
+        assert!(tree.insert(key1, 1, 0, 1).is_ok());
+        // update key1 with version 0
+        assert!(tree.insert(key1, 1, 0, 3).is_ok());
+
+        // get key1 should return version 2 as the same key was inserted and updated
+        let (_, val, version, ts) = tree.get(key1, 0).unwrap();
+        assert_eq!(val, 1);
+        assert_eq!(version, 2);
+        assert_eq!(ts, 3);
+
+        // update key1 with older version should fail
+        assert!(tree.insert(key1, 1, 1, 0).is_err());
+        assert_eq!(tree.version(), 2);
+
+        // update key1 with newer version should pass
+        assert!(tree.insert(key1, 1, 8, 5).is_ok());
+        let (_, val, version, ts) = tree.get(key1, 0).unwrap();
+        assert_eq!(val, 1);
+        assert_eq!(version, 8);
+        assert_eq!(ts, 5);
+
+        assert_eq!(tree.version(), 8);
+    }
+
+    #[test]
+    fn timed_insertion_update_non_increasing_version() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+
+        let key1 = VariableSizeKey::from_str("key_1").unwrap();
+        let key2 = VariableSizeKey::from_str("key_2").unwrap();
+
+        // Initial insertion
+        assert!(tree.insert(&key1, 1, 10, 0).is_ok());
+        let initial_version_key1 = tree.version();
+
+        // Attempt update with non-increasing version
+        assert!(tree.insert(&key1, 1, 2, 0).is_err());
+        assert_eq!(initial_version_key1, tree.version());
+        let (_, val, version, _) = tree.get(&key1, 0).unwrap();
+        assert_eq!(val, 1);
+        assert_eq!(version, 10);
+
+        // Insert another key
+        assert!(tree.insert(&key2, 1, 15, 0).is_ok());
+        let initial_version_key2 = tree.version();
+
+        // Attempt update with non-increasing version for the second key
+        assert!(tree.insert(&key2, 1, 11, 0).is_err());
+        assert_eq!(initial_version_key2, tree.version());
+        let (_, val, version, _ts) = tree.get(&key2, 0).unwrap();
+        assert_eq!(val, 1);
+        assert_eq!(version, 15);
+
+        // Check if the max version of the tree is the max of the two inserted versions
+        assert_eq!(tree.version(), 15);
+    }
+
+    #[test]
+    fn timed_insertion_update_equal_to_root_version() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+
+        let key1 = VariableSizeKey::from_str("key_1").unwrap();
+        let key2 = VariableSizeKey::from_str("key_2").unwrap();
+
+        // Initial insertion
+        assert!(tree.insert(&key1, 1, 10, 0).is_ok());
+        let initial_version = tree.version();
+
+        // Attempt update with version equal to root's version
+        assert!(tree.insert(&key2, 1, initial_version, 0).is_err());
+    }
+
+    #[test]
+    fn timed_deletion_check_root_ts() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        let key1 = VariableSizeKey::from_str("key_1").unwrap();
+        let key2 = VariableSizeKey::from_str("key_2").unwrap();
+
+        // Initial insertions
+        assert!(tree.insert(&key1, 1, 0, 0).is_ok());
+        assert!(tree.insert(&key2, 1, 0, 0).is_ok());
+        assert_eq!(tree.version(), 2);
+
+        // Deletions
+        assert!(tree.remove(&key1).unwrap());
+        assert!(tree.remove(&key2).unwrap());
+        assert_eq!(tree.version(), 0);
+    }
+
+    fn from_be_bytes_key(k: &[u8]) -> u64 {
+        let padded_k = if k.len() < 8 {
+            let mut new_k = vec![0; 8];
+            new_k[8 - k.len()..].copy_from_slice(k);
+            new_k
+        } else {
+            k.to_vec()
+        };
+
+        let k_slice = &padded_k[..8];
+        u64::from_be_bytes(k_slice.try_into().unwrap())
+    }
+
+    #[test]
+    fn iter_seq_u16() {
+        let mut tree = Tree::<FixedSizeKey<16>, u16>::new();
+
+        // Insertion
+        for i in 0..u16::MAX {
+            let key: FixedSizeKey<16> = i.into();
+            tree.insert(&key, i, 0, i as u64);
+        }
+
+        // Iteration and verification
+        let mut len = 0usize;
+        let mut expected = 0u16;
+
+        let tree_iter = tree.iter();
+        for tree_entry in tree_iter {
+            let k = from_be_bytes_key(&tree_entry.0);
+            assert_eq!(expected as u64, k);
+            let ts = tree_entry.3;
+            assert_eq!(expected as u64, *ts);
+            expected = expected.wrapping_add(1);
+            len += 1;
+        }
+
+        // Final assertion
+        assert_eq!(len, u16::MAX as usize);
+    }
+
+    #[test]
+    fn iter_seq_u8() {
+        let mut tree: Tree<FixedSizeKey<32>, u8> = Tree::<FixedSizeKey<32>, u8>::new();
+
+        // Insertion
+        for i in 0..u8::MAX {
+            let key: FixedSizeKey<32> = i.into();
+            tree.insert(&key, i, 0, 0);
+        }
+
+        // Iteration and verification
+        let mut len = 0usize;
+        let mut expected = 0u8;
+
+        let tree_iter = tree.iter();
+        for tree_entry in tree_iter {
+            let k = from_be_bytes_key(&tree_entry.0);
+            assert_eq!(expected as u64, k);
+            expected = expected.wrapping_add(1);
+            len += 1;
+        }
+
+        // Final assertion
+        assert_eq!(len, u8::MAX as usize);
+    }
+
+    #[test]
+    fn range_seq_u8() {
+        let mut tree: Tree<FixedSizeKey<8>, u8> = Tree::<FixedSizeKey<8>, u8>::new();
+
+        let max = u8::MAX;
+        // Insertion
+        for i in 0..=max {
+            let key: FixedSizeKey<8> = i.into();
+            tree.insert(&key, i, 0, 0);
+        }
+
+        // Test inclusive range
+        let start_key: FixedSizeKey<8> = 5u8.into();
+        let end_key: FixedSizeKey<8> = max.into();
+        let mut len = 0usize;
+        for _ in tree.range(start_key..=end_key) {
+            len += 1;
+        }
+        assert_eq!(len, max as usize - 4);
+
+        // Test exclusive range
+        let start_key: FixedSizeKey<8> = 5u8.into();
+        let end_key: FixedSizeKey<8> = max.into();
+        let mut len = 0usize;
+        for _ in tree.range(start_key..end_key) {
+            len += 1;
+        }
+        assert_eq!(len, max as usize - 5);
+
+        // Test range with different start and end keys
+        let start_key: FixedSizeKey<8> = 3u8.into();
+        let end_key: FixedSizeKey<8> = 7u8.into();
+        let mut len = 0usize;
+        for _ in tree.range(start_key..=end_key) {
+            len += 1;
+        }
+        assert_eq!(len, 5);
+
+        // Test range with all keys
+        let start_key: FixedSizeKey<8> = 0u8.into();
+        let end_key: FixedSizeKey<8> = max.into();
+        let mut len = 0usize;
+        for _ in tree.range(start_key..=end_key) {
+            len += 1;
+        }
+        assert_eq!(len, 256);
+    }
+
+    #[test]
+    fn range_seq_u16() {
+        let mut tree: Tree<FixedSizeKey<16>, u16> = Tree::<FixedSizeKey<16>, u16>::new();
+
+        let max = u16::MAX;
+        // Insertion
+        for i in 0..=max {
+            let key: FixedSizeKey<16> = i.into();
+            tree.insert(&key, i, 0, 0);
+        }
+
+        let mut len = 0usize;
+        let start_key: FixedSizeKey<16> = 0u8.into();
+        let end_key: FixedSizeKey<16> = max.into();
+
+        for _ in tree.range(start_key..=end_key) {
+            len += 1;
+        }
+        assert_eq!(len, max as usize + 1);
+    }
+
+    #[test]
+    fn same_key_with_versions() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+        // Insertions
+        let key1 = VariableSizeKey::from_str("abc").unwrap();
+        let key2 = VariableSizeKey::from_str("efg").unwrap();
+        tree.insert(&key1, 1, 0, 0);
+        tree.insert(&key1, 2, 10, 0);
+        tree.insert(&key2, 3, 11, 0);
+
+        // Versioned retrievals and assertions
+        let (_, val, _, _) = tree.get(&key1, 1).unwrap();
+        assert_eq!(val, 1);
+        let (_, val, _, _) = tree.get(&key1, 10).unwrap();
+        assert_eq!(val, 2);
+        let (_, val, _, _) = tree.get(&key2, 11).unwrap();
+        assert_eq!(val, 3);
+
+        // Iteration and verification
+        let mut len = 0;
+        let tree_iter = tree.iter();
+        for _ in tree_iter {
+            len += 1;
+        }
+        assert_eq!(len, 2);
+    }
+
+    #[test]
+    fn bulk_insert() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        let curr_version = tree.version();
+        // Create a vector of KV<P, V>
+        let kv_pairs = vec![
+            KV {
+                key: VariableSizeKey::from_str("key_1").unwrap(),
+                value: 1,
+                version: 0,
+                ts: 0,
+            },
+            KV {
+                key: VariableSizeKey::from_str("key_2").unwrap(),
+                value: 1,
+                version: 2,
+                ts: 0,
+            },
+            KV {
+                key: VariableSizeKey::from_str("key_3").unwrap(),
+                value: 1,
+                version: curr_version + 1,
+                ts: 0,
+            },
+            KV {
+                key: VariableSizeKey::from_str("key_4").unwrap(),
+                value: 1,
+                version: curr_version + 1,
+                ts: 0,
+            },
+            KV {
+                key: VariableSizeKey::from_str("key_5").unwrap(),
+                value: 1,
+                version: curr_version + 2,
+                ts: 0,
+            },
+            KV {
+                key: VariableSizeKey::from_str("key_6").unwrap(),
+                value: 1,
+                version: 0,
+                ts: 0,
+            },
+        ];
+
+        assert!(tree.bulk_insert(&kv_pairs).is_ok());
+        assert!(tree.version() == curr_version + 2);
+
+        for kv in kv_pairs {
+            let (_, val, version, _) = tree.get(&kv.key, 0).unwrap();
+            assert_eq!(val, kv.value);
+            if kv.version == 0 {
+                assert_eq!(version, curr_version + 1);
+            } else {
+                assert_eq!(version, kv.version);
+            }
+        }
+        assert!(tree
+            .insert(&VariableSizeKey::from_str("key_7").unwrap(), 1, 0, 0)
+            .is_ok());
+        assert!(tree.version() == curr_version + 3);
+    }
+
+    #[test]
+    fn prefix_lock_rejects_other_writers() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+        let locked = VariableSizeKey::from_str("user/1").unwrap();
+        let unlocked = VariableSizeKey::from_str("group/1").unwrap();
+
+        let lock = tree.try_lock_prefix(b"user/", 1).unwrap();
+        assert_eq!(lock.prefix(), b"user/");
+        assert_eq!(lock.owner(), 1);
+
+        // Writers without the lock are rejected under the prefix
+        assert!(matches!(
+            tree.insert(&locked, 1, 0, 0),
+            Err(TrieError::PrefixLocked { owner: 1 })
+        ));
+        assert!(matches!(
+            tree.insert_as(2, &locked, 1, 0, 0),
+            Err(TrieError::PrefixLocked { owner: 1 })
+        ));
+        assert!(tree.insert(&unlocked, 1, 0, 0).is_ok());
+
+        // The owner can write under its own prefix
+        assert!(tree.insert_as(1, &locked, 1, 0, 0).is_ok());
+        assert!(matches!(
+            tree.remove(&locked),
+            Err(TrieError::PrefixLocked { owner: 1 })
+        ));
+        assert!(tree.remove_as(1, &locked).unwrap());
+
+        // Dropping the guard releases the lock
+        drop(lock);
+        assert!(tree.insert(&locked, 1, 0, 0).is_ok());
+        assert!(tree.try_lock_prefix(b"user/", 2).is_ok());
+    }
+
+    #[test]
+    fn prefix_lock_nested_and_overlapping() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+
+        let outer = tree.try_lock_prefix(b"a/", 1).unwrap();
+        assert!(tree.try_lock_prefix(b"a/b/", 2).is_err());
+        assert!(tree.try_lock_prefix(b"a", 2).is_err());
+        let sibling = tree.try_lock_prefix(b"b/", 2).unwrap();
+
+        // Once the outer lock is gone, nested prefixes can be locked separately
+        drop(outer);
+        let inner = tree.try_lock_prefix(b"a/b/", 2).unwrap();
+        let _other = tree.try_lock_prefix(b"a/c/", 3).unwrap();
+        assert!(matches!(
+            tree.try_lock_prefix(b"a/", 1),
+            Err(TrieError::PrefixLocked { .. })
+        ));
+
+        let key = VariableSizeKey::from_str("a/b/x").unwrap();
+        assert!(tree.insert_as(3, &key, 1, 0, 0).is_err());
+        assert!(tree.insert_as(2, &key, 1, 0, 0).is_ok());
+
+        // Bulk inserts touching a locked prefix are rejected as a whole
+        let kv_pairs = vec![
+            KV::new(VariableSizeKey::from_str("c/1").unwrap(), 1, 0, 0),
+            KV::new(VariableSizeKey::from_str("b/1").unwrap(), 1, 0, 0),
+        ];
+        assert!(tree.bulk_insert(&kv_pairs).is_err());
+        assert!(tree.get(&kv_pairs[0].key, 0).is_err());
+
+        drop(sibling);
+        drop(inner);
+        assert!(tree.bulk_insert(&kv_pairs).is_ok());
+    }
+
+    #[test]
+    fn cached_counts_follow_inserts_and_removes() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        use std::collections::HashMap;
+        use std::sync::atomic::Ordering as AtomicOrdering;
+
+        // Checks that every counted node holds the number of keys below it.
+        fn check(node: &Arc<Node<VariableSizeKey, u64>>) {
+            let cached = node.count.load(AtomicOrdering::Relaxed);
+            assert!(cached == 0 || cached == node.count_twigs());
+            for (_, child) in node.iter() {
+                check(child);
+            }
+        }
+
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut tree: Tree<VariableSizeKey, u64> = Tree::with_counts();
+        let mut keys = Vec::new();
+        for round in 0..20 {
+            for _ in 0..200 {
+                let len = rng.gen_range(1..6);
+                let word: String = (0..len).map(|_| rng.gen_range('a'..='d')).collect();
+                let key = VariableSizeKey::from_str(&word).unwrap();
+                tree.insert(&key, round, 0, 0).unwrap();
+                keys.push(key);
+            }
+            for _ in 0..80 {
+                let key = &keys[rng.gen_range(0..keys.len())];
+                tree.remove(key).unwrap();
+            }
+            let snapshot = tree.create_snapshot().unwrap();
+
+            let all: Vec<Vec<u8>> = tree.iter().map(|(k, _, _, _)| k).collect();
+            for prefix in ["", "a", "ab", "abc", "d", "dd", "ca"] {
+                let expected = all
+                    .iter()
+                    .filter(|k| k.starts_with(prefix.as_bytes()))
+                    .count();
+                assert_eq!(tree.count_prefix(prefix.as_bytes()), expected, "{}", prefix);
+            }
+            assert_eq!(
+                tree.entry_count_by_prefix(2),
+                tree.root
+                    .as_ref()
+                    .map(|root| {
+                        let mut counts = HashMap::new();
+                        root.group_counts(2, false, &mut Vec::new(), &mut counts);
+                        counts
+                    })
+                    .unwrap_or_default()
+            );
+            if let Some(root) = &tree.root {
+                assert_eq!(root.count.load(AtomicOrdering::Relaxed), all.len());
+                check(root);
+            }
+            // Counts cached through the Trie are shared with the snapshot.
+            assert_eq!(snapshot.count().unwrap(), all.len());
+        }
+    }
+
+    #[test]
+    fn hash_index_matches_trie_lookups() {
+        let mut plain = Tree::<VariableSizeKey, i32>::new();
+        let mut indexed = Tree::<VariableSizeKey, i32>::with_hash_index(RandomState::new());
+        let words = read_words_from_file("testdata/words.txt").unwrap();
+        let words = &words[..2000];
+
+        for (i, word) in words.iter().enumerate() {
+            let key = VariableSizeKey::from_str(word).unwrap();
+            plain.insert(&key, i as i32, 0, 0).unwrap();
+            indexed.insert(&key, i as i32, 0, 0).unwrap();
+        }
+
+        // Overwrite and remove some keys so that the index has to follow the writes
+        for (i, word) in words.iter().enumerate().step_by(3) {
+            let key = VariableSizeKey::from_str(word).unwrap();
+            if i % 2 == 0 {
+                plain.insert(&key, -(i as i32), 0, 0).unwrap();
+                indexed.insert(&key, -(i as i32), 0, 0).unwrap();
+            } else {
+                assert!(plain.remove(&key).unwrap());
+                assert!(indexed.remove(&key).unwrap());
+            }
+        }
+        assert_eq!(
+            indexed.hash_index.as_ref().unwrap().len(),
+            plain.iter().count()
+        );
+
+        let misses = ["", "zzzzzz", "aardvarks!", "word that is not there"];
+        let keys = words.iter().map(|w| w.as_str()).chain(misses);
+        for word in keys {
+            let key = VariableSizeKey::from_str(word).unwrap();
+            for version in [0, 1, words.len() as u64, plain.version()] {
+                let expected = plain.get(&key, version).map(|(_, v, ver, ts)| (v, ver, ts));
+                let actual = indexed
+                    .get(&key, version)
+                    .map(|(_, v, ver, ts)| (v, ver, ts));
+                assert_eq!(expected.ok(), actual.ok(), "key {:?}", word);
+            }
+        }
+    }
+
+    #[test]
+    fn pinned_versions_survive_pruning() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+        let key = VariableSizeKey::from_str("key").unwrap();
+        let other = VariableSizeKey::from_str("other").unwrap();
+
+        // Versions 1..=5 of `key` and 6..=7 of `other`
+        for version in 1..=5 {
+            tree.insert(&key, version as i32, version, 0).unwrap();
+        }
+        tree.insert(&other, 6, 6, 0).unwrap();
+        tree.insert(&other, 7, 7, 0).unwrap();
+
+        // Pin the value visible at version 3
+        let pin = tree.pin_version(&key, 3).unwrap();
+        assert_eq!(pin.version(), 3);
+        assert_eq!(tree.pinned_versions(), vec![(key.clone(), 3)]);
+        assert!(tree.pin_version(&key, 0).is_err());
+
+        // Prune everything but the latest values
+        assert_eq!(tree.prune_versions_older_than(u64::MAX).unwrap(), 4);
+
+        // The pinned version and the latest one survive
+        assert_eq!(tree.get(&key, 3).unwrap().1, 3);
+        assert_eq!(tree.get(&key, 4).unwrap().1, 3);
+        assert_eq!(tree.get(&key, 0).unwrap().1, 5);
+        assert!(tree.get(&key, 2).is_err());
+        assert!(tree.get(&other, 6).is_err());
+        assert_eq!(tree.get(&other, 0).unwrap().1, 7);
+
+        // Without the pin, the next prune removes it too
+        drop(pin);
+        assert!(tree.pinned_versions().is_empty());
+        assert_eq!(tree.prune_versions_older_than(u64::MAX).unwrap(), 1);
+        assert!(tree.get(&key, 3).is_err());
+        assert_eq!(tree.get(&key, 0).unwrap().1, 5);
+        assert_eq!(tree.prune_versions_older_than(u64::MAX).unwrap(), 0);
+    }
+
+    #[test]
+    fn prune_versions_keeps_newer_versions() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+        let keys: Vec<VariableSizeKey> = (0..20)
+            .map(|i| VariableSizeKey::from_str(&format!("key_{}", i)).unwrap())
+            .collect();
+
+        let mut version = 0;
+        for round in 0..3 {
+            for key in &keys {
+                version += 1;
+                tree.insert(key, round, version, 0).unwrap();
+            }
+        }
+
+        // The first round is older than the cutoff, but only the first key has a
+        // newer value visible at the cutoff
+        let cutoff = keys.len() as u64 + 1;
+        assert_eq!(tree.prune_versions_older_than(cutoff).unwrap(), 1);
+        assert!(tree.get(&keys[0], 1).is_err());
+
+        // Reads at or after the cutoff see the same values as before
+        for (i, key) in keys.iter().enumerate() {
+            let expected = if i == 0 { 1 } else { 0 };
+            assert_eq!(tree.get(key, cutoff).unwrap().1, expected);
+            assert_eq!(tree.get(key, cutoff + i as u64).unwrap().1, 1);
+            assert_eq!(tree.get(key, 0).unwrap().1, 2);
+        }
+    }
+
+    #[test]
+    fn retain_versions_keeps_latest_per_key() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+        let key = VariableSizeKey::from_str("key").unwrap();
+        let other = VariableSizeKey::from_str("other").unwrap();
+        for i in 1..=10 {
+            tree.insert(&key, i, i as u64, 0).unwrap();
+        }
+        tree.insert(&other, 0, 11, 0).unwrap();
+
+        let pin = tree.pin_version(&key, 2).unwrap();
+        assert_eq!(tree.retain_versions(3).unwrap(), 6);
+        let versions: Vec<u64> = tree
+            .history(&key)
+            .unwrap()
+            .into_iter()
+            .map(|(_, version, _)| version)
+            .collect();
+        assert_eq!(versions, vec![2, 8, 9, 10]);
+        assert_eq!(tree.get(&other, 0).unwrap().1, 0);
+
+        // Once unpinned, the value is pruned, and every key keeps its latest value.
+        drop(pin);
+        assert_eq!(tree.retain_versions(0).unwrap(), 3);
+        assert_eq!(tree.history(&key).unwrap().len(), 1);
+        assert_eq!(tree.get(&key, 0).unwrap().1, 10);
+    }
+
+    #[test]
+    fn lossy_iter_matches_iter_on_intact_tree() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+        let words = read_words_from_file("testdata/words.txt").unwrap();
+        for (i, word) in words.iter().take(5000).enumerate() {
+            let key = VariableSizeKey::from_str(word).unwrap();
+            tree.insert(&key, i as i32, 0, 0).unwrap();
+        }
 
-        // First insertion
-        let key = VariableSizeKey::from_str("abc").unwrap();
-        let value = 1;
-        let result = tree.insert(&key, value, 0, 0).expect("Failed to insert");
-        assert!(result.is_none());
+        let reader = IterationPointer::new(tree.root.clone().unwrap(), 0);
+        let strict: Vec<(Vec<u8>, i32)> = reader.iter().map(|(k, v, _, _)| (k, *v)).collect();
+        let lossy: Vec<(Vec<u8>, i32)> = reader
+            .iter_lossy()
+            .map(|item| item.map(|(k, v, _, _)| (k, *v)).unwrap())
+            .collect();
+        assert_eq!(strict.len(), 5000);
+        assert_eq!(strict, lossy);
+    }
 
-        // Second insertion (duplicate)
-        let result = tree.insert(&key, value, 0, 0).expect("Failed to insert");
-        assert!(result.is_some());
+    #[test]
+    fn lossy_iter_reports_faults_and_continues() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+        for (i, word) in ["apple", "apricot", "banana", "cherry", "date", "elder"]
+            .iter()
+            .enumerate()
+        {
+            let key = VariableSizeKey::from_str(word).unwrap();
+            tree.insert(&key, i as i32, 0, 0).unwrap();
+        }
+        let root = tree.root.clone().unwrap();
+
+        // A twig whose version list is empty
+        let NodeType::Twig(banana) = &root.find_child(b'b').unwrap().node_type else {
+            panic!("expected a twig");
+        };
+        let empty = TwigNode::new(banana.prefix.clone(), banana.key.clone());
+
+        // A twig whose key does not match its path
+        let NodeType::Twig(cherry) = &root.find_child(b'c').unwrap().node_type else {
+            panic!("expected a twig");
+        };
+        let mut mismatched = cherry.clone();
+        mismatched.key = VariableSizeKey::from_str("cherri").unwrap();
+
+        let mut corrupted = root
+            .replace_child(b'b', Arc::new(Node::from_type(NodeType::Twig(empty))))
+            .replace_child(b'c', Arc::new(Node::from_type(NodeType::Twig(mismatched))));
+
+        // A key without a child node
+        match &mut corrupted.node_type {
+            NodeType::Node16(n) => n.clear_child_slot(b'd'),
+            _ => panic!("expected a Node16 root"),
+        }
+
+        let reader = IterationPointer::new(Arc::new(corrupted), 0);
+        let items: Vec<Result<(Vec<u8>, i32), TraversalFault>> = reader
+            .iter_lossy()
+            .map(|item| item.map(|(k, v, _, _)| (k, *v)))
+            .collect();
+
+        assert_eq!(
+            items,
+            vec![
+                Ok((b"apple\0".to_vec(), 0)),
+                Ok((b"apricot\0".to_vec(), 1)),
+                Err(TraversalFault::EmptyTwig {
+                    key: b"banana\0".to_vec()
+                }),
+                Err(TraversalFault::KeyMismatch {
+                    path: b"cherry\0".to_vec(),
+                    key: b"cherri\0".to_vec()
+                }),
+                Err(TraversalFault::MissingChild {
+                    path: Vec::new(),
+                    key: b'd'
+                }),
+                Ok((b"elder\0".to_vec(), 5)),
+            ]
+        );
     }
 
-    // Inserting a single value into the tree and removing it should result in a nil tree root.
     #[test]
-    fn insert_and_remove() {
+    fn match_prefixes_batch_nested_routes() {
         let mut tree = Tree::<VariableSizeKey, i32>::new();
+        let routes = ["/", "/api/", "/api/v1/", "/static/"];
+        for (i, route) in routes.iter().enumerate() {
+            tree.insert(&VariableSizeKey::from_str(route).unwrap(), i as i32, 0, 0)
+                .unwrap();
+        }
 
-        // Insertion
-        let key = VariableSizeKey::from_str("test").unwrap();
-        let value = 1;
-        tree.insert(&key, value, 0, 0);
+        let inputs: [&[u8]; 8] = [
+            b"/api/v1/users",
+            b"/api/v2/users",
+            b"/other",
+            b"",
+            b"api",
+            b"/api/",
+            b"/api",
+            b"/api/v1/users",
+        ];
+        let expected = [
+            Some(2),
+            Some(1),
+            Some(0),
+            None,
+            None,
+            Some(1),
+            Some(0),
+            Some(2),
+        ];
 
-        // Removal
-        assert!(tree.remove(&key).unwrap());
+        let batch = tree.match_prefixes_batch(&inputs);
+        assert_eq!(batch.len(), inputs.len());
+        for ((input, expected), matched) in inputs.iter().zip(expected).zip(batch) {
+            let expected = expected.map(|i| {
+                let key = VariableSizeKey::from_str(routes[i]).unwrap();
+                (key.as_slice().to_vec(), i as i32)
+            });
+            assert_eq!(matched, expected, "input {:?}", input);
+            assert_eq!(tree.longest_prefix_match(input), expected);
+        }
 
-        // Verification
-        assert!(tree.get(&key, 0).is_err());
+        // Without the root entry, inputs outside the other routes match nothing
+        assert!(tree
+            .remove(&VariableSizeKey::from_str("/").unwrap())
+            .unwrap());
+        assert_eq!(tree.match_prefixes_batch(&[b"/other", b"/api/x"])[0], None);
+        assert_eq!(tree.longest_prefix_match(b"/other"), None);
+        assert_eq!(
+            tree.match_prefixes_batch(&[b"/other", b"/api/x"])[1]
+                .as_ref()
+                .map(|(_, v)| *v),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn match_prefixes_batch_matches_single_lookups() {
+        let mut tree = Tree::<VariableSizeKey, i32>::new();
+        let words = read_words_from_file("testdata/words.txt").unwrap();
+        for (i, word) in words.iter().step_by(7).take(3000).enumerate() {
+            tree.insert(&VariableSizeKey::from_str(word).unwrap(), i as i32, 0, 0)
+                .unwrap();
+        }
+
+        let inputs: Vec<String> = words
+            .iter()
+            .take(21000)
+            .map(|word| format!("{}ish", word))
+            .collect();
+        let inputs: Vec<&[u8]> = inputs.iter().map(|input| input.as_bytes()).collect();
+
+        let batch = tree.match_prefixes_batch(&inputs);
+        let mut matched = 0;
+        for (input, result) in inputs.iter().zip(batch) {
+            assert_eq!(result, tree.longest_prefix_match(input));
+            matched += result.is_some() as usize;
+        }
+        assert!(matched >= 3000);
+    }
+
+    #[test]
+    fn single_byte_keys_fill_root_slots() {
+        let mut tree = Tree::<FixedSizeKey<8>, u16>::new();
+        for b in 0..=u8::MAX {
+            assert!(tree.insert(&b.into(), b as u16, 0, 0).unwrap().is_none());
+        }
+        assert_eq!(tree.root.as_ref().unwrap().node_type_name(), "Node256");
+        assert_eq!(tree.version(), 256);
+
+        for b in 0..=u8::MAX {
+            let (key, val, version, _) = tree.get(&b.into(), 0).unwrap();
+            assert_eq!(key.as_slice(), &[b]);
+            assert_eq!(val, b as u16);
+            assert_eq!(version, b as u64 + 1);
+        }
+
+        // Updates keep the older versions readable
+        assert_eq!(tree.insert(&7u8.into(), 700, 0, 0).unwrap(), Some(7));
+        assert_eq!(tree.get(&7u8.into(), 0).unwrap().1, 700);
+        assert_eq!(tree.get(&7u8.into(), 8).unwrap().1, 7);
+        assert!(tree.get(&7u8.into(), 1).is_err());
+
+        let keys: Vec<Vec<u8>> = tree.iter().map(|(k, _, _, _)| k).collect();
+        let expected: Vec<Vec<u8>> = (0..=u8::MAX).map(|b| vec![b]).collect();
+        assert_eq!(keys, expected);
+
+        // Removed keys are no longer found on the fast path
+        assert!(tree.remove(&9u8.into()).unwrap());
+        assert!(tree.get(&9u8.into(), 0).is_err());
+        assert!(tree.insert(&9u8.into(), 9, 0, 0).unwrap().is_none());
+        assert_eq!(tree.get(&9u8.into(), 0).unwrap().1, 9);
+    }
+
+    fn run_pressure_workload(open_snapshots: usize) -> Pressure {
+        let mut tree: Tree<FixedSizeKey<16>, u64> = Tree::new();
+        let mut snapshots = Vec::new();
+        for i in 0..1000u64 {
+            // Spread the keys over the keyspace so that inserts touch different paths.
+            let key: FixedSizeKey<16> = i.wrapping_mul(0x9e37_79b9_7f4a_7c15).into();
+            tree.insert(&key, i, 0, i).unwrap();
+            if open_snapshots > 0 && i % 10 == 9 {
+                snapshots.push(tree.create_snapshot().unwrap());
+                if snapshots.len() > open_snapshots {
+                    let snapshot = snapshots.remove(0);
+                    tree.close_snapshot(snapshot.id()).unwrap();
+                }
+            }
+        }
+        tree.pressure()
+    }
+
+    #[test]
+    fn pressure_rises_with_open_snapshots() {
+        let idle = run_pressure_workload(0);
+        let busy = run_pressure_workload(5);
+
+        assert_eq!(idle.level, PressureLevel::Low);
+        assert_eq!(idle.nodes_retained, 0);
+        assert_eq!(idle.snapshots, 0);
+        assert_eq!(idle.inserts, 1000);
+        assert!(idle.copies_per_insert() > 1.0);
+
+        assert_eq!(busy.snapshots, 5);
+        assert!(busy.retained_per_insert() > idle.retained_per_insert());
+        assert!(busy.level > idle.level);
+    }
+
+    #[test]
+    fn pressure_window_decays() {
+        let options = TreeOptions {
+            pressure_window: 100,
+            ..TreeOptions::default()
+        };
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::with_options(options);
+        let _snapshot = {
+            tree.insert(&VariableSizeKey::from_str("a").unwrap(), 1, 0, 0)
+                .unwrap();
+            tree.insert(&VariableSizeKey::from_str("b").unwrap(), 1, 0, 0)
+                .unwrap();
+            tree.create_snapshot().unwrap()
+        };
+        tree.insert(&VariableSizeKey::from_str("c").unwrap(), 1, 0, 10)
+            .unwrap();
+        assert_eq!(tree.pressure().inserts, 3);
+        assert!(tree.pressure().nodes_retained > 0);
+
+        // The next window still includes the previous one.
+        tree.insert(&VariableSizeKey::from_str("a").unwrap(), 2, 0, 150)
+            .unwrap();
+        assert_eq!(tree.pressure().inserts, 4);
+        assert_eq!(tree.pressure().versions_added, 1);
+
+        // Two windows later, only the latest insert is counted.
+        tree.insert(&VariableSizeKey::from_str("d").unwrap(), 1, 0, 350)
+            .unwrap();
+        assert_eq!(tree.pressure().inserts, 1);
+        assert_eq!(tree.pressure().versions_added, 0);
+    }
+
+    #[test]
+    fn update_in_place() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        let counter = VariableSizeKey::from_str("counter").unwrap();
+        let missing = VariableSizeKey::from_str("missing").unwrap();
+        tree.insert(&counter, 1, 0, 0).unwrap();
+
+        for ts in 1..=3 {
+            assert!(tree.update(&counter, ts, |v| *v += 1).unwrap());
+        }
+        assert!(!tree.update(&missing, 4, |v| *v += 1).unwrap());
+        assert!(tree.get(&missing, 0).is_err());
+
+        let (_, value, version, ts) = tree.get(&counter, 0).unwrap();
+        assert_eq!((value, version, ts), (4, 4, 3));
+        // Older versions keep their values.
+        assert_eq!(tree.get(&counter, 1).unwrap().1, 1);
+        assert_eq!(tree.get(&counter, 2).unwrap().1, 2);
+    }
+
+    #[test]
+    fn first_and_last_key() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut tree: Tree<VariableSizeKey, usize> = Tree::new();
+        assert!(tree.first_key().is_none());
+        assert!(tree.last_key().is_none());
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut keys = Vec::new();
+        for i in 0..500 {
+            let len = rng.gen_range(1..6);
+            let bytes: Vec<u8> = (0..len).map(|_| rng.gen_range(1..=255)).collect();
+            let key = VariableSizeKey::from_slice_with_termination(&bytes);
+            tree.insert(&key, i, 0, 0).unwrap();
+            keys.push(key);
+        }
+        keys.sort();
+        keys.dedup();
+
+        // Removing the smallest and largest keys shrinks the nodes along both edges.
+        for _ in 0..20 {
+            assert_eq!(tree.first_key().as_ref(), keys.first());
+            assert_eq!(tree.last_key().as_ref(), keys.last());
+            assert!(tree.remove(&keys.remove(0)).unwrap());
+            assert!(tree.remove(&keys.pop().unwrap()).unwrap());
+        }
+
+        let key = VariableSizeKey::from_str("only").unwrap();
+        let mut tree: Tree<VariableSizeKey, usize> = Tree::new();
+        tree.insert(&key, 1, 0, 0).unwrap();
+        assert_eq!(tree.first_key(), Some(key.clone()));
+        assert_eq!(tree.last_key(), Some(key));
+    }
+
+    #[test]
+    fn prefix_stats_track_mutations() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let options = TreeOptions::default().track_prefix_stats(b'/');
+        let mut tree: Tree<VariableSizeKey, u64> = Tree::with_options(options);
+        let key = |tenant: u64, item: u64| {
+            VariableSizeKey::from_str(&format!("t{}/{}", tenant, item)).unwrap()
+        };
+
+        // A single key at the root is kept when another key is removed.
+        tree.insert(&key(0, 0), 0, 0, 0).unwrap();
+        tree.remove(&key(0, 1)).unwrap();
+        assert_eq!(tree.prefix_stats(b"t0").unwrap().keys, 1);
+        tree.verify_prefix_stats().unwrap();
+
+        let mut rng = StdRng::seed_from_u64(11);
+        for round in 0..20u64 {
+            for _ in 0..50 {
+                let k = key(rng.gen_range(0..5), rng.gen_range(0..40));
+                match rng.gen_range(0..4) {
+                    0 => {
+                        tree.remove(&k).unwrap();
+                    }
+                    _ => {
+                        tree.insert(&k, round, 0, 0).unwrap();
+                    }
+                }
+            }
+            let kvs: Vec<_> = (0..5)
+                .map(|item| KV::new(key(round % 5, item), round, 0, 0))
+                .collect();
+            tree.bulk_insert(&kvs).unwrap();
+            if round % 5 == 4 {
+                tree.prune_versions_older_than(tree.version()).unwrap();
+            }
+            tree.verify_prefix_stats().unwrap();
+        }
+
+        for tenant in 0..5 {
+            let prefix = format!("t{}/", tenant);
+            let keys = tree
+                .iter()
+                .filter(|(k, _, _, _)| k.starts_with(prefix.as_bytes()))
+                .count() as u64;
+            let stats = tree.prefix_stats(format!("t{}", tenant).as_bytes());
+            assert_eq!(stats.map_or(0, |stats| stats.keys), keys);
+        }
+        assert_eq!(
+            tree.iter_prefix_stats().map(|(_, s)| s.keys).sum::<u64>(),
+            tree.iter().count() as u64
+        );
+
+        // Stats are not kept unless enabled.
+        let mut plain: Tree<VariableSizeKey, u64> = Tree::new();
+        plain.insert(&key(0, 0), 0, 0, 0).unwrap();
+        assert!(plain.prefix_stats(b"t0").is_none());
+        assert_eq!(plain.iter_prefix_stats().count(), 0);
+    }
+
+    #[test]
+    fn stale_keys_for_snapshot() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        let key = |i: i32| VariableSizeKey::from_str(&format!("key{:03}", i)).unwrap();
+        for i in 0..200 {
+            tree.insert(&key(i), i, 0, i as u64).unwrap();
+        }
+        let snap = tree.create_snapshot().unwrap();
+        assert_eq!(tree.staleness(snap.id()).unwrap().stale_keys, 0);
+
+        // Update a known subset, some keys twice, and add a new key.
+        let mut expected = Vec::new();
+        for (n, i) in [3, 50, 51, 120, 199, 50].into_iter().enumerate() {
+            tree.insert(&key(i), -i, 0, 1000 + n as u64).unwrap();
+        }
+        tree.insert(&key(500), 500, 0, 2000).unwrap();
+        for (i, ts) in [
+            (3, 1000),
+            (50, 1005),
+            (51, 1002),
+            (120, 1003),
+            (199, 1004),
+            (500, 2000),
+        ] {
+            expected.push((key(i).as_slice().to_vec(), ts));
+        }
+
+        let stale: Vec<_> = tree.stale_keys_for_snapshot(snap.id()).unwrap().collect();
+        assert_eq!(stale, expected);
+        let summary = tree.staleness(snap.id()).unwrap();
+        assert_eq!(summary.stale_keys, expected.len());
+        assert_eq!(summary.max_lag_ts, 2000);
+
+        // A snapshot taken now sees everything.
+        let current = tree.create_snapshot().unwrap();
+        assert_eq!(
+            tree.stale_keys_for_snapshot(current.id()).unwrap().count(),
+            0
+        );
+
+        assert!(matches!(
+            tree.staleness(1000),
+            Err(TrieError::SnapshotNotFound)
+        ));
+        assert!(tree.stale_keys_for_snapshot(1000).is_err());
+    }
+
+    #[test]
+    fn remove_key_diverging_within_node_prefix() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        for (i, word) in ["f25.log", "f25.tmp", "f26"].iter().enumerate() {
+            tree.insert(&VariableSizeKey::from_str(word).unwrap(), i as i32, 0, 0)
+                .unwrap();
+        }
+
+        // "f25tmp" diverges from the "5." prefix of the node holding the "f25." keys,
+        // but its next byte matches the child holding "f25.tmp".
+        assert!(!tree
+            .remove(&VariableSizeKey::from_str("f25tmp").unwrap())
+            .unwrap());
+        assert_eq!(tree.iter().count(), 3);
+        let key = VariableSizeKey::from_str("f25.tmp").unwrap();
+        assert_eq!(tree.get(&key, 0).unwrap().1, 1);
+        tree.verify().unwrap();
+
+        // The same holds for a key that ends within the prefix
+        assert!(!tree.remove(&VariableSizeKey::from_slice(b"f25")).unwrap());
+        assert_eq!(tree.iter().count(), 3);
+        tree.verify().unwrap();
+    }
+
+    #[test]
+    fn suffix_index_matches_brute_force() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let options = TreeOptions::default().with_suffix_index();
+        let mut tree: Tree<VariableSizeKey, u64> = Tree::with_options(options);
+        let mut plain: Tree<VariableSizeKey, u64> = Tree::new();
+        let exts = [".tmp", ".log", "tmp", ".txt", ""];
+        let key = |rng: &mut StdRng| {
+            let name = format!("dir{}/f{}", rng.gen_range(0..4), rng.gen_range(0..30));
+            let ext = exts[rng.gen_range(0..exts.len())];
+            VariableSizeKey::from_str(&format!("{}{}", name, ext)).unwrap()
+        };
+
+        let mut rng = StdRng::seed_from_u64(3);
+        for round in 0..30 {
+            for _ in 0..40 {
+                let k = key(&mut rng);
+                if rng.gen_bool(0.3) {
+                    tree.remove(&k).unwrap();
+                    plain.remove(&k).unwrap();
+                } else {
+                    tree.insert(&k, round, 0, 0).unwrap();
+                    plain.insert(&k, round, 0, 0).unwrap();
+                }
+            }
+            let kvs: Vec<_> = (0..3)
+                .map(|_| KV::new(key(&mut rng), round, 0, 0))
+                .collect();
+            tree.bulk_insert(&kvs).unwrap();
+            plain.bulk_insert(&kvs).unwrap();
+            tree.verify_suffix_index().unwrap();
+        }
+
+        for suffix in [".tmp", "tmp", "p", "1.log", "dir0/f1", "", ".none"] {
+            let indexed: Vec<_> = tree.scan_suffix(suffix.as_bytes()).collect();
+            let scanned: Vec<_> = plain.scan_suffix(suffix.as_bytes()).collect();
+            assert_eq!(indexed, scanned);
+            let expected = plain
+                .iter()
+                .filter(|(k, _, _, _)| k[..k.len() - 1].ends_with(suffix.as_bytes()))
+                .count();
+            assert_eq!(indexed.len(), expected);
+        }
+    }
+
+    #[test]
+    fn metadata_per_version() {
+        let mut tree: Tree<VariableSizeKey, WithMeta<i32, &str>> = Tree::new();
+        let key = VariableSizeKey::from_str("balance").unwrap();
+        assert!(tree
+            .insert_with_meta(&key, 10, 100, "alice")
+            .unwrap()
+            .is_none());
+        let old = tree.insert_with_meta(&key, 20, 200, "bob").unwrap();
+        assert_eq!(old.map(|entry| entry.meta), Some("alice"));
+
+        assert_eq!(tree.get_with_meta(&key, 0).unwrap(), (20, "bob", 2, 200));
+        assert_eq!(tree.get_with_meta(&key, 1).unwrap(), (10, "alice", 1, 100));
+        assert!(tree
+            .get_with_meta(&VariableSizeKey::from_str("missing").unwrap(), 0)
+            .is_err());
+    }
+
+    #[test]
+    fn scan_prefix_with_matches_filtered_iter() {
+        let mut tree: Tree<VariableSizeKey, usize> = Tree::new();
+        let mut words = Vec::new();
+        for a in ["", "a", "ab", "abc", "b", "user/"] {
+            for i in 0..40 {
+                words.push(format!("{}{}", a, i));
+            }
+        }
+        for (i, word) in words.iter().enumerate() {
+            tree.insert(&VariableSizeKey::from_str(word).unwrap(), i, 0, 0)
+                .unwrap();
+        }
+
+        let mut buffer = ScanBuffer::new();
+        for prefix in ["", "a", "ab", "abc1", "abc19", "user/3", "x", "1"] {
+            let expected: Vec<(Vec<u8>, usize)> = tree
+                .iter()
+                .filter(|(k, _, _, _)| k.starts_with(prefix.as_bytes()))
+                .map(|(k, v, _, _)| (k, *v))
+                .collect();
+
+            let mut actual = Vec::new();
+            let mut scan = tree.scan_prefix_with(prefix.as_bytes(), &mut buffer);
+            while let Some((key, value, _, _)) = scan.next_entry() {
+                actual.push((key.to_vec(), *value));
+            }
+            assert_eq!(actual, expected, "prefix {:?}", prefix);
+        }
+        assert!(buffer.capacity() > 0);
+
+        // Readers scan the same way.
+        let mut snapshot = tree.create_snapshot().unwrap();
+        let reader = snapshot.new_reader().unwrap();
+        let mut scan = reader.scan_prefix_with(b"user/", &mut buffer);
+        let mut count = 0;
+        while scan.next_entry().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 40);
+        drop(scan);
+
+        // An empty tree yields nothing.
+        let empty: Tree<VariableSizeKey, usize> = Tree::new();
+        assert!(empty
+            .scan_prefix_with(b"", &mut buffer)
+            .next_entry()
+            .is_none());
+    }
+
+    fn assert_same_structure(a: &Node<FixedSizeKey<16>, u64>, b: &Node<FixedSizeKey<16>, u64>) {
+        assert_eq!(a.node_type_name(), b.node_type_name());
+        assert_eq!(a.prefix(), b.prefix());
+        assert_eq!(a.version(), b.version());
+        match (&a.node_type, &b.node_type) {
+            (NodeType::Twig(x), NodeType::Twig(y)) => {
+                assert_eq!(x.key, y.key);
+                let history = |twig: &TwigNode<FixedSizeKey<16>, u64>| -> Vec<(u64, u64, u64)> {
+                    twig.iter().map(|l| (l.value, l.version, l.ts)).collect()
+                };
+                assert_eq!(history(x), history(y));
+            }
+            _ => {
+                assert_eq!(a.num_children(), b.num_children());
+                for ((ka, ca), (kb, cb)) in a.iter().zip(b.iter()) {
+                    assert_eq!(ka, kb);
+                    assert_same_structure(ca, cb);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn index_hook_keeps_an_inverted_index_in_lockstep() {
+        use crate::hook::IndexHook;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        use std::collections::{HashMap, HashSet};
+        use std::sync::Mutex;
+
+        // Maps every value to the keys holding it as their latest value.
+        type Inverted = Arc<Mutex<HashMap<u64, HashSet<Vec<u8>>>>>;
+        struct InvertedIndex(Inverted);
+
+        impl IndexHook<VariableSizeKey, u64> for InvertedIndex {
+            fn on_insert(&mut self, key: &VariableSizeKey, value: &u64, old: Option<&u64>) {
+                let mut index = self.0.lock().unwrap();
+                if let Some(old) = old {
+                    let keys = index.get_mut(old).unwrap();
+                    assert!(keys.remove(key.as_slice()));
+                    if keys.is_empty() {
+                        index.remove(old);
+                    }
+                }
+                assert!(index
+                    .entry(*value)
+                    .or_default()
+                    .insert(key.as_slice().to_vec()));
+            }
+
+            fn on_remove(&mut self, key: &VariableSizeKey, value: &u64) {
+                let mut index = self.0.lock().unwrap();
+                let keys = index.get_mut(value).unwrap();
+                assert!(keys.remove(key.as_slice()));
+                if keys.is_empty() {
+                    index.remove(value);
+                }
+            }
+        }
+
+        let inverted = Inverted::default();
+        let mut tree = Tree::with_index_hook(InvertedIndex(inverted.clone()));
+        let mut rng = StdRng::seed_from_u64(7);
+        let key = |n: u64| VariableSizeKey::from_str(&format!("k{}/{}", n % 4, n)).unwrap();
+        for i in 1..=2000u64 {
+            let n = rng.gen_range(0..200u64);
+            match rng.gen_range(0..100) {
+                0..=49 => {
+                    tree.insert(&key(n), rng.gen_range(0..10), 0, i).unwrap();
+                }
+                50..=59 => {
+                    tree.update(&key(n), i, |v| *v = (*v + 1) % 10).unwrap();
+                }
+                60..=79 => {
+                    tree.remove(&key(n)).unwrap();
+                }
+                80..=97 => {
+                    let mut batch: Vec<KV<VariableSizeKey, u64>> = (0..5)
+                        .map(|_| KV::new(key(rng.gen_range(0..200)), rng.gen_range(0..10), 0, i))
+                        .collect();
+                    batch.sort_by(|a, b| a.key.as_slice().cmp(b.key.as_slice()));
+                    batch.dedup_by(|a, b| a.key == b.key);
+                    tree.bulk_insert(&batch).unwrap();
+                }
+                _ => {
+                    let prefix = format!("k{}/", n % 4);
+                    tree.remove_prefixes(&[VariableSizeKey::from_slice(prefix.as_bytes())], i)
+                        .unwrap();
+                }
+            }
+        }
+
+        let mut expected: HashMap<u64, HashSet<Vec<u8>>> = HashMap::new();
+        for (key, value, ..) in tree.iter() {
+            expected.entry(*value).or_default().insert(key);
+        }
+        assert!(!expected.is_empty());
+        assert_eq!(*inverted.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn replay_recorded_workload() {
+        use crate::record::{OpLog, OpRecord};
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let log = OpLog::new();
+        let mut tree: Tree<FixedSizeKey<16>, u64> = Tree::new();
+        tree.enable_recording(log.clone());
+
+        let mut snapshots = Vec::new();
+        let mut pins = Vec::new();
+        for i in 0..2000u64 {
+            let key: FixedSizeKey<16> = rng.gen_range(0..300u64).into();
+            match rng.gen_range(0..100) {
+                0..=59 => {
+                    tree.insert(&key, i, 0, i).unwrap();
+                }
+                60..=69 => {
+                    tree.update(&key, i, |v| *v += 1).unwrap();
+                }
+                70..=79 => {
+                    tree.remove(&key).unwrap();
+                }
+                80..=84 => {
+                    let batch: Vec<KV<FixedSizeKey<16>, u64>> = (0..5)
+                        .map(|j| KV::new(rng.gen_range(0..300u64).into(), i + j, 0, i))
+                        .collect();
+                    tree.bulk_insert(&batch).unwrap();
+                }
+                85..=89 => {
+                    if let Ok(pin) = tree.pin_version(&key, 0) {
+                        pins.push(pin);
+                    }
+                }
+                90..=94 => {
+                    if snapshots.len() < 4 {
+                        snapshots.push(tree.create_snapshot().unwrap().id());
+                    } else {
+                        tree.close_snapshot(snapshots.remove(0)).unwrap();
+                    }
+                }
+                _ => {
+                    tree.prune_versions_older_than(tree.version().saturating_sub(50))
+                        .unwrap();
+                    pins.truncate(pins.len() / 2);
+                }
+            }
+        }
+
+        let records = log.records();
+        assert!(records
+            .iter()
+            .all(|op| !matches!(op, OpRecord::Insert { version: 0, .. })));
+
+        let replayed = Tree::replay(records).unwrap();
+        tree.verify().unwrap();
+        replayed.verify().unwrap();
+        assert_eq!(replayed.version(), tree.version());
+        assert_eq!(replayed.snapshot_count(), tree.snapshot_count());
+        assert_same_structure(tree.root.as_ref().unwrap(), replayed.root.as_ref().unwrap());
+    }
+
+    #[test]
+    fn replay_detects_divergence() {
+        use crate::record::OpRecord;
+
+        let key: FixedSizeKey<16> = 1u64.into();
+        let ops = vec![
+            OpRecord::Insert {
+                key: key.clone(),
+                value: 1,
+                version: 1,
+                ts: 0,
+            },
+            OpRecord::CreateSnapshot { id: 0 },
+            OpRecord::CloseSnapshot { id: 0 },
+            // Snapshot 0 is already closed.
+            OpRecord::CloseSnapshot { id: 0 },
+        ];
+        assert!(matches!(
+            Tree::<FixedSizeKey<16>, u64>::replay(ops),
+            Err(TrieError::ReplayDiverged { op: 3 })
+        ));
+    }
+
+    mod fragile {
+        use std::cell::Cell;
+
+        thread_local! {
+            static CLONES_LEFT: Cell<usize> = const { Cell::new(usize::MAX) };
+            static PANIC_ON_DROP: Cell<bool> = const { Cell::new(false) };
+        }
+
+        /// A value whose clones start panicking after a set number of calls, and
+        /// whose next drop can be made to panic.
+        #[derive(Debug, PartialEq)]
+        pub(super) struct Fragile(pub(super) u64);
+
+        impl Fragile {
+            pub(super) fn fail_after_clones(n: usize) {
+                CLONES_LEFT.with(|left| left.set(n));
+            }
+
+            pub(super) fn clones_left() -> usize {
+                CLONES_LEFT.with(|left| left.get())
+            }
+
+            pub(super) fn fail_next_drop() {
+                PANIC_ON_DROP.with(|armed| armed.set(true));
+            }
+
+            pub(super) fn reset() {
+                Fragile::fail_after_clones(usize::MAX);
+                PANIC_ON_DROP.with(|armed| armed.set(false));
+            }
+        }
+
+        impl Clone for Fragile {
+            fn clone(&self) -> Self {
+                CLONES_LEFT.with(|left| match left.get() {
+                    0 => panic!("clone of Fragile({}) failed", self.0),
+                    n => left.set(n - 1),
+                });
+                Fragile(self.0)
+            }
+        }
+
+        impl Drop for Fragile {
+            fn drop(&mut self) {
+                if PANIC_ON_DROP.with(|armed| armed.replace(false)) {
+                    panic!("drop of Fragile({}) failed", self.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mutations_survive_panicking_values() {
+        use crate::hash_index::HashIndex;
+        use fragile::Fragile;
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let key = |i: u64| VariableSizeKey::from_str(&format!("key{:03}", i)).unwrap();
+        let build = || {
+            Fragile::reset();
+            let options = TreeOptions::default()
+                .track_prefix_stats(b'1')
+                .with_suffix_index();
+            let mut tree: Tree<VariableSizeKey, Fragile> = Tree {
+                hash_index: Some(HashIndex::new(RandomState::new())),
+                ..Tree::with_options(options)
+            };
+            for i in 0..200 {
+                tree.insert(&key(i), Fragile(i), 0, i).unwrap();
+            }
+            tree
+        };
+        let contents = |tree: &Tree<VariableSizeKey, Fragile>| -> Vec<(Vec<u8>, u64, u64)> {
+            tree.iter()
+                .map(|(k, v, version, _)| (k, v.0, *version))
+                .collect()
+        };
+        let check = |tree: &Tree<VariableSizeKey, Fragile>, expected: &[(Vec<u8>, u64, u64)]| {
+            Fragile::reset();
+            tree.verify().unwrap();
+            assert_eq!(contents(tree), expected);
+            for (k, value, _) in expected {
+                let key = VariableSizeKey::from_slice(k);
+                assert_eq!(tree.get(&key, 0).unwrap().1, Fragile(*value));
+            }
+        };
+
+        // A panicking clone leaves the Trie as it was before the insert.
+        let mut tree = build();
+        let before = contents(&tree);
+        Fragile::fail_after_clones(0);
+        assert!(catch_unwind(AssertUnwindSafe(|| tree.insert(
+            &key(5),
+            Fragile(500),
+            0,
+            0
+        )))
+        .is_err());
+        check(&tree, &before);
+
+        // Likewise for a batch, wherever in the batch the clone panics.
+        for n in 0..10 {
+            let batch: Vec<KV<VariableSizeKey, Fragile>> = (0..10)
+                .map(|i| KV::new(key(i * 37), Fragile(1000 + i), 0, 0))
+                .collect();
+            Fragile::fail_after_clones(n);
+            assert!(catch_unwind(AssertUnwindSafe(|| tree.bulk_insert(&batch))).is_err());
+            check(&tree, &before);
+        }
+
+        Fragile::fail_after_clones(0);
+        assert!(catch_unwind(AssertUnwindSafe(|| tree.update(&key(7), 0, |v| v.0 += 1))).is_err());
+        check(&tree, &before);
+
+        // A panicking drop of a removed value happens once the removal is complete.
+        Fragile::fail_next_drop();
+        assert!(catch_unwind(AssertUnwindSafe(|| tree.remove(&key(9)))).is_err());
+        let removed: Vec<_> = before.iter().filter(|(_, v, _)| *v != 9).cloned().collect();
+        check(&tree, &removed);
+        assert!(tree.get(&key(9), 0).is_err());
+
+        // The same holds for values dropped by pruning.
+        let mut tree = build();
+        for i in 0..50 {
+            tree.insert(&key(i), Fragile(i + 1000), 0, 0).unwrap();
+        }
+        let latest = contents(&tree);
+        let version = tree.version();
+        Fragile::fail_next_drop();
+        assert!(
+            catch_unwind(AssertUnwindSafe(|| tree.prune_versions_older_than(version))).is_err()
+        );
+        check(&tree, &latest);
+        assert!(tree.get(&key(0), 1).is_err());
+        Fragile::reset();
+    }
+
+    #[test]
+    fn diff_since_snapshot() {
+        use crate::diff::Change;
+
+        let key = |i: u64| VariableSizeKey::from_str(&format!("key{:04}", i)).unwrap();
+        let mut tree: Tree<VariableSizeKey, u64> = Tree::new();
+        for i in 0..1000 {
+            tree.insert(&key(i), i, 0, i).unwrap();
+        }
+        let base = tree.create_snapshot().unwrap();
+        assert!(tree.diff_since(&base).is_empty());
+
+        // Updates, new keys (one of them splitting an existing node), removals,
+        // and a key both inserted and removed.
+        tree.insert(&key(10), 100, 0, 2000).unwrap();
+        tree.insert(&key(10), 101, 0, 2001).unwrap();
+        tree.insert(&key(5000), 5000, 0, 2002).unwrap();
+        tree.insert(&VariableSizeKey::from_str("k").unwrap(), 7, 0, 2003)
+            .unwrap();
+        tree.insert(&key(6000), 6000, 0, 2004).unwrap();
+        tree.remove(&key(6000)).unwrap();
+        for i in (500..520).step_by(3) {
+            tree.remove(&key(i)).unwrap();
+        }
+        // The key removed last was the newest one, so the Trie is back at the
+        // version of the insert before it.
+        let version = tree.version();
+
+        let mut expected = vec![
+            Change::Insert {
+                key: VariableSizeKey::from_str("k").unwrap(),
+                value: 7,
+                version,
+                ts: 2003,
+            },
+            Change::Insert {
+                key: key(10),
+                value: 101,
+                version: version - 2,
+                ts: 2001,
+            },
+        ];
+        expected.extend(
+            (500..520)
+                .step_by(3)
+                .map(|i| Change::Remove { key: key(i) }),
+        );
+        expected.push(Change::Insert {
+            key: key(5000),
+            value: 5000,
+            version: version - 1,
+            ts: 2002,
+        });
+        assert_eq!(tree.diff_since(&base), expected);
+
+        // Applying the changelog to the base brings it up to date.
+        let mut replica = base.clone_independent().unwrap().into_tree();
+        for change in tree.diff_since(&base) {
+            match change {
+                Change::Insert { key, value, .. } => {
+                    replica.insert(&key, value, 0, 0).unwrap();
+                }
+                Change::Remove { key } => {
+                    replica.remove(&key).unwrap();
+                }
+            }
+        }
+        let entries = |tree: &Tree<VariableSizeKey, u64>| -> Vec<(Vec<u8>, u64)> {
+            tree.iter().map(|(k, v, _, _)| (k, *v)).collect()
+        };
+        assert_eq!(entries(&replica), entries(&tree));
+    }
+
+    #[test]
+    fn apply_wal_replays_serialized_changes() {
+        use crate::diff::{serialize_delta, Change};
+
+        let key = |i: u64| VariableSizeKey::from_str(&format!("key{:04}", i)).unwrap();
+        let mut tree: Tree<VariableSizeKey, u64> = Tree::new();
+        let empty = tree.create_snapshot().unwrap();
+        for i in 0..200 {
+            tree.insert(&key(i), i, 0, 1000 + i).unwrap();
+        }
+        let mut wal = Vec::new();
+        serialize_delta(&tree.diff_since(&empty), &mut wal).unwrap();
+
+        let base = tree.create_snapshot().unwrap();
+        for i in (0..200).step_by(7) {
+            tree.insert(&key(i), i * 10, 0, 5000 + i).unwrap();
+        }
+        for i in (3..200).step_by(11) {
+            tree.remove(&key(i)).unwrap();
+        }
+        let changes = tree.diff_since(&base);
+        let last_len = {
+            let mut last = Vec::new();
+            serialize_delta(&changes[changes.len() - 1..], &mut last).unwrap();
+            last.len()
+        };
+        serialize_delta(&changes, &mut wal).unwrap();
+
+        let entries = |tree: &Tree<VariableSizeKey, u64>| -> Vec<(Vec<u8>, u64, u64)> {
+            tree.iter().map(|(k, v, _, ts)| (k, *v, *ts)).collect()
+        };
+        let mut replica: Tree<VariableSizeKey, u64> = Tree::new();
+        replica.apply_wal(wal.as_slice()).unwrap();
+        assert_eq!(entries(&replica), entries(&tree));
+
+        // A record torn at the end of the log is reported after applying the
+        // records before it.
+        let torn = &wal[..wal.len() - 3];
+        let mut replica: Tree<VariableSizeKey, u64> = Tree::new();
+        assert_eq!(
+            replica.apply_wal(torn),
+            Err(TrieError::CorruptChangelog {
+                offset: (wal.len() - last_len) as u64,
+                reason: "truncated record",
+            })
+        );
+        let torn_key = match &changes[changes.len() - 1] {
+            Change::Insert { key, .. } | Change::Remove { key } => key.as_slice().to_vec(),
+        };
+        let without_torn = |tree: &Tree<VariableSizeKey, u64>| {
+            let mut entries = entries(tree);
+            entries.retain(|(k, _, _)| *k != torn_key);
+            entries
+        };
+        assert_eq!(without_torn(&replica), without_torn(&tree));
+        assert_ne!(entries(&replica), entries(&tree));
+    }
+
+    #[test]
+    fn cursor_tokens_resume_across_freeze_and_thaw() {
+        use crate::cursor::ResumeError;
+        use crate::frozen::FrozenTree;
+
+        let key = |i: u64| VariableSizeKey::from_str(&format!("key{:04}", i)).unwrap();
+        let mut tree: Tree<VariableSizeKey, u64> = Tree::new();
+        for i in 0..100 {
+            tree.insert(&key(i), i, 0, i).unwrap();
+        }
+        let expected: Vec<(Vec<u8>, u64)> = tree.iter().map(|(k, v, _, _)| (k, *v)).collect();
+
+        let mut cursor = tree.scan_cursor();
+        let mut seen: Vec<(Vec<u8>, u64)> = tree
+            .scan_page(&mut cursor, 30)
+            .unwrap()
+            .into_iter()
+            .map(|(k, v, _, _)| (k.as_slice().to_vec(), v))
+            .collect();
+        let token = tree.cursor_token(&cursor);
+
+        // The process restarts with a thawed copy, which resumes the cursor as is.
+        let frozen = tree.freeze();
+        let mut restored: Tree<VariableSizeKey, u64> =
+            Tree::thaw(&FrozenTree::open(&frozen).unwrap()).unwrap();
+        assert_eq!(restored.resume_cursor(&token).unwrap(), cursor);
+
+        // After new writes, the cursor is pinned to the version of the token and
+        // continues without duplicates or gaps.
+        restored.insert(&key(10), 1000, 0, 200).unwrap();
+        restored.insert(&key(50), 5000, 0, 201).unwrap();
+        restored.insert(&key(5000), 5000, 0, 202).unwrap();
+        let mut cursor = restored.resume_cursor(&token).unwrap();
+        assert_eq!(cursor.pinned_version(), Some(tree.version()));
+        while !cursor.is_done() {
+            let page = restored.scan_page(&mut cursor, 30).unwrap();
+            seen.extend(
+                page.into_iter()
+                    .map(|(k, v, _, _)| (k.as_slice().to_vec(), v)),
+            );
+            let token = restored.cursor_token(&cursor);
+            cursor = restored.resume_cursor(&token).unwrap();
+        }
+        assert_eq!(seen, expected);
+
+        // Once pruning drops the values visible at the version, resuming fails.
+        let mut cursor = restored.resume_cursor(&token).unwrap();
+        restored.scan_page(&mut cursor, 10).unwrap();
+        let token = restored.cursor_token(&cursor);
+        restored
+            .prune_versions_older_than(restored.version())
+            .unwrap();
+        assert_eq!(
+            restored.resume_cursor(&token),
+            Err(ResumeError::TreeAdvanced {
+                version: tree.version()
+            })
+        );
+        assert_eq!(
+            restored.resume_cursor(&token[..10]),
+            Err(ResumeError::Malformed)
+        );
     }
 
     #[test]
-    fn inserting_keys_with_common_prefix() {
-        let key1 = VariableSizeKey::from_str("foo").unwrap();
-        let key2 = VariableSizeKey::from_str("foo2").unwrap();
-
-        let mut tree = Tree::<VariableSizeKey, i32>::new();
+    fn trees_compare_by_content() {
+        let key = |i: u64| VariableSizeKey::from_str(&format!("key{}", i)).unwrap();
+        let mut a: Tree<VariableSizeKey, u64> = Tree::new();
+        for i in 0..500 {
+            a.insert(&key(i), i, 0, i).unwrap();
+        }
 
-        // Insertion
-        tree.insert(&key1, 1, 0, 0);
-        tree.insert(&key2, 1, 0, 0);
+        // Another insert order, older values and timestamps, and a key that was
+        // removed again do not matter.
+        let mut b: Tree<VariableSizeKey, u64> = Tree::new();
+        b.insert(&key(1000), 0, 0, 0).unwrap();
+        for i in (0..500).rev() {
+            b.insert(&key(i), i + 1, 0, 7).unwrap();
+            b.insert(&key(i), i, 0, 8).unwrap();
+        }
+        assert!(b.remove(&key(1000)).unwrap());
+        assert!(a == b);
+        assert!(b == a);
+
+        // A Tree sharing its nodes with `a` is equal to it.
+        let c = a.create_snapshot().unwrap().into_tree();
+        assert!(a == c);
+
+        b.insert(&key(250), 0, 0, 9).unwrap();
+        assert!(a != b);
+        b.insert(&key(250), 250, 0, 10).unwrap();
+        assert!(a == b);
+        assert!(b.remove(&key(499)).unwrap());
+        assert!(a != b);
+        assert!(a != Tree::new());
+        assert!(Tree::<VariableSizeKey, u64>::new() == Tree::new());
+    }
 
-        // Removal
-        assert!(tree.remove(&key1).unwrap());
+    #[test]
+    fn path_of_reports_node_segments() {
+        use std::collections::hash_map::RandomState;
+
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let words = ["apple", "apply", "apricot", "banana", "band", "bandana"];
+        for mut tree in [
+            Tree::<VariableSizeKey, usize>::new(),
+            Tree::with_hash_index(RandomState::new()),
+        ] {
+            for (i, word) in words.iter().enumerate() {
+                tree.insert(&key(word), i, 0, 0).unwrap();
+            }
 
-        // Root verification
-        if let Some(root) = &tree.root {
-            assert_eq!(root.node_type_name(), "Node1");
-        } else {
-            panic!("Tree root is None");
+            for word in words {
+                let word = key(word);
+                let path = tree.path_of(&word).unwrap();
+                let twig = path.last().unwrap();
+                assert_eq!(twig.node_type, "twig");
+                assert_eq!((twig.child, twig.num_children), (None, 0));
+                // The partials tile the key, and every child byte taken is the
+                // first byte of the next partial.
+                let mut bytes = Vec::new();
+                for (segment, next) in path.iter().zip(path.iter().skip(1)) {
+                    assert_eq!(segment.depth, bytes.len());
+                    bytes.extend_from_slice(&segment.partial);
+                    assert_eq!(segment.child, Some(next.partial[0]));
+                    assert!(segment.num_children > 1);
+                }
+                bytes.extend_from_slice(&twig.partial);
+                assert_eq!(bytes, word.as_slice());
+                assert!(path.iter().all(|segment| !segment.shared));
+                assert_eq!(tree.common_path_len(&word, &word), path.len());
+            }
+            assert!(tree.path_of(&key("app")).is_none());
+            assert!(tree.path_of(&key("cherry")).is_none());
+
+            // Keys share the nodes above the byte they differ at.
+            assert_eq!(tree.common_path_len(&key("apple"), &key("apply")), 3);
+            assert_eq!(tree.common_path_len(&key("apple"), &key("apricot")), 2);
+            assert_eq!(tree.common_path_len(&key("apple"), &key("band")), 1);
+            // The lookup of a missing key ends at the twig it would split.
+            assert_eq!(tree.common_path_len(&key("apple"), &key("applesauce")), 4);
+
+            // A snapshot holds the root, and keeps the nodes the next write
+            // replaces referenced.
+            let mut snapshot = tree.create_snapshot().unwrap();
+            let shared = |tree: &Tree<VariableSizeKey, usize>, word| {
+                let path = tree.path_of(&key(word)).unwrap();
+                path.iter()
+                    .map(|segment| segment.shared)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(shared(&tree, "apple"), vec![true, false, false, false]);
+            tree.insert(&key("apple"), 10, 0, 0).unwrap();
+            assert_eq!(shared(&tree, "apple"), vec![false, false, false, false]);
+            assert_eq!(shared(&tree, "apricot"), vec![false, false, true]);
+            assert_eq!(shared(&tree, "band"), vec![false, true, false, false]);
+            snapshot.close().unwrap();
+            tree.close_snapshot(snapshot.id()).unwrap();
+            drop(snapshot);
+            assert_eq!(shared(&tree, "band"), vec![false, false, false, false]);
         }
     }
 
-    // Inserting Two values into the tree and removing one of them
-    // should result in a tree root of type twig
     #[test]
-    fn insert2_and_remove1_and_root_should_be_node1() {
-        let key1 = VariableSizeKey::from_str("test1").unwrap();
-        let key2 = VariableSizeKey::from_str("test2").unwrap();
+    fn from_sorted_with_combines_equal_keys() {
+        use super::KV;
+
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let input = |order: [&'static str; 3]| {
+            order
+                .into_iter()
+                .zip(1..)
+                .map(move |(k, v)| KV::new(key(k), v, 0, v as u64))
+        };
 
-        let mut tree = Tree::<VariableSizeKey, i32>::new();
+        // The entries of `k` are summed, although `j` sorts before them.
+        let tree = Tree::from_sorted_with(input(["k", "k", "j"]), |a, b| a + b).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.iter().count(), 2);
+        assert_eq!(tree.get(&key("k"), 0).unwrap().1, 3);
+        assert_eq!(tree.get(&key("j"), 0).unwrap().1, 3);
+
+        // Entries of a key that are not consecutive are folded in input order.
+        let tree = Tree::from_sorted_with(input(["k", "j", "k"]), |a, b| a * 10 + b).unwrap();
+        assert_eq!(tree.get(&key("k"), 0).unwrap().1, 13);
+
+        let tree = Tree::from_sorted_with(input(["j", "k", "k"]), |a, b| a + b).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.iter().count(), 2);
+        assert_eq!(tree.get(&key("j"), 0).unwrap().1, 1);
+        // The combined entry keeps the timestamp of the last entry of the key.
+        let (_, value, _, ts) = tree.get(&key("k"), 0).unwrap();
+        assert_eq!((value, ts), (5, 3));
+    }
 
-        // Insertion
-        tree.insert(&key1, 1, 0, 0);
-        tree.insert(&key2, 1, 0, 0);
+    #[test]
+    fn bulk_load_resumes_after_invalid_entries() {
+        use super::{BulkLoadReason, KV};
+
+        // Sorted keys, with runs sharing long prefixes.
+        let mut keys: Vec<String> = (0..300).map(|i| format!("k{:04}", i * 7)).collect();
+        keys.extend((0..50).map(|i| format!("run/shared/prefix/{:03}", i)));
+        keys.extend((0..50).map(|i| format!("z{:03}", i)));
+        keys.sort();
+        let entry = |i: usize, key: &str| {
+            KV::new(
+                VariableSizeKey::from_str(key).unwrap(),
+                i as u32,
+                0,
+                i as u64,
+            )
+        };
+        let input: Vec<KV<VariableSizeKey, u32>> =
+            keys.iter().enumerate().map(|(i, k)| entry(i, k)).collect();
+        let clean = Tree::bulk_load(
+            input
+                .iter()
+                .map(|kv| KV::new(kv.key.clone(), kv.value, kv.version, kv.ts)),
+        )
+        .unwrap();
+        clean.verify().unwrap();
+        let contents = |tree: &Tree<VariableSizeKey, u32>| -> Vec<(Vec<u8>, u32, u64, u64)> {
+            tree.iter()
+                .map(|(k, v, version, ts)| (k, *v, *version, *ts))
+                .collect()
+        };
+        assert_eq!(contents(&clean).len(), keys.len());
+
+        let run = keys.iter().position(|k| k.starts_with("run/")).unwrap() + 20;
+        let failures = [
+            (1, BulkLoadReason::Duplicate),
+            (200, BulkLoadReason::OutOfOrder),
+            (run, BulkLoadReason::OutOfOrder),
+            (run + 1, BulkLoadReason::Duplicate),
+            (keys.len() - 1, BulkLoadReason::OutOfOrder),
+        ];
+        for (at, reason) in failures {
+            let bad = match reason {
+                BulkLoadReason::Duplicate => keys[at - 1].clone(),
+                _ => format!("{}!", &keys[at - 1][..keys[at - 1].len() - 1]),
+            };
+            let corrupted = input.iter().enumerate().map(|(i, kv)| match i == at {
+                true => entry(i, &bad),
+                false => KV::new(kv.key.clone(), kv.value, kv.version, kv.ts),
+            });
+            let err = match Tree::bulk_load(corrupted) {
+                Err(err) => err,
+                Ok(_) => panic!("corrupted input at {} loaded", at),
+            };
+            assert_eq!((err.index, &err.reason), (at, &reason));
+            assert_eq!(err.key, VariableSizeKey::from_str(&bad).unwrap());
+            err.tree.verify().unwrap();
+            assert_eq!(err.tree.iter().count(), at);
+
+            // Resuming with the fixed tail gives the clean load.
+            let tail = input[at..]
+                .iter()
+                .map(|kv| KV::new(kv.key.clone(), kv.value, kv.version, kv.ts));
+            let resumed = err.tree.bulk_append(tail).unwrap();
+            resumed.verify().unwrap();
+            assert_eq!(contents(&resumed), contents(&clean));
+        }
 
-        // Removal
-        assert!(tree.remove(&key1).unwrap());
+        // An appended tail has to start after the largest key of the Trie.
+        let half = Tree::bulk_load(
+            input[..100]
+                .iter()
+                .map(|kv| KV::new(kv.key.clone(), kv.value, kv.version, kv.ts)),
+        )
+        .unwrap();
+        let err = match half.bulk_append([entry(0, &keys[99])]) {
+            Err(err) => err,
+            Ok(_) => panic!("appended the largest key again"),
+        };
+        assert_eq!((err.index, err.reason), (0, BulkLoadReason::Duplicate));
+        let err = match err.tree.bulk_append([entry(0, &keys[50])]) {
+            Err(err) => err,
+            Ok(_) => panic!("appended a key before the largest one"),
+        };
+        assert_eq!((err.index, err.reason), (0, BulkLoadReason::OutOfOrder));
+        assert_eq!(err.tree.iter().count(), 100);
+    }
 
-        // Root verification
-        if let Some(root) = &tree.root {
-            assert_eq!(root.node_type_name(), "Node1");
-        } else {
-            panic!("Tree root is None");
+    #[test]
+    fn cow_batch_window_reduces_copies_of_clustered_writes() {
+        let key = |name: String| VariableSizeKey::from_str(&name).unwrap();
+        let run = |options: TreeOptions| {
+            let mut tree: Tree<VariableSizeKey, usize> = Tree::with_options(options);
+            for i in 0..500 {
+                tree.insert(&key(format!("other/{:04}", i)), i, 0, 0)
+                    .unwrap();
+            }
+            // Every round of clustered writes follows a new snapshot, which
+            // shares the nodes written by the rounds before.
+            let mut snapshots = Vec::new();
+            let mut copies = Vec::new();
+            for round in 0..20 {
+                snapshots.push(tree.create_snapshot().unwrap());
+                for i in 0..50 {
+                    let name = format!("tenant/0042/user/{:04}", round * 50 + i);
+                    let outcome = tree.insert_with_outcome(&key(name), i, 0, 0).unwrap();
+                    copies.push(outcome.nodes_copied);
+                }
+            }
+            tree.verify().unwrap();
+            for (round, snap) in snapshots.iter().enumerate() {
+                assert_eq!(snap.count().unwrap(), 500 + round * 50);
+            }
+            (tree.pressure(), copies)
+        };
+
+        let (plain, plain_copies) = run(TreeOptions::default());
+        let (batched, batched_copies) = run(TreeOptions::default().cow_batch_window(64));
+        assert_eq!(plain.nodes_reused, 0);
+        assert!(batched.nodes_reused > 0);
+        let (plain_total, batched_total): (u64, u64) =
+            (plain_copies.iter().sum(), batched_copies.iter().sum());
+        assert!(
+            batched_total * 2 < plain_total,
+            "{} copies with the window, {} without",
+            batched_total,
+            plain_total
+        );
+        // The first write after a snapshot copies its whole path either way.
+        for round in 0..20 {
+            assert_eq!(batched_copies[round * 50], plain_copies[round * 50]);
         }
     }
 
-    // // Inserting Two values into a tree and deleting them both
-    // // should result in a nil tree root
-    // // This tests the expansion of the root into a NODE4 and
-    // // successfully collapsing into a twig and then nil upon successive removals
-    // #[test]
-    // fn insert2_and_remove2_and_root_should_be_nil() {
-    //     let key1 = &VariableSizeKey::from_str("test1");
-    //     let key2 = &VariableSizeKey::from_str("test2");
-
-    //     let mut tree = Tree::<VariableSizeKey, i32>::new();
-    //     tree.insert(key1, 1, 0, 0);
-    //     tree.insert(key2, 1, 0);
+    #[test]
+    fn cow_batch_window_keeps_snapshots_isolated() {
+        use crate::iter::Iter;
+        use crate::snapshot::Snapshot;
+        use crate::view::RefreshingView;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        use std::collections::BTreeMap;
+
+        type Model = BTreeMap<Vec<u8>, u64>;
+        let key = |i: u64| VariableSizeKey::from_str(&format!("k/{}/{:03}", i % 7, i)).unwrap();
+        let entries = |iter: Iter<'_, VariableSizeKey, u64>| -> Vec<(Vec<u8>, u64)> {
+            iter.map(|(k, v, _, _)| (k, *v)).collect()
+        };
+        let contents = |model: &Model| -> Vec<(Vec<u8>, u64)> {
+            model.iter().map(|(k, v)| (k.clone(), *v)).collect()
+        };
 
-    //     assert_eq!(tree.remove(key1), true);
-    //     assert_eq!(tree.remove(key2), true);
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut tree: Tree<VariableSizeKey, u64> =
+            Tree::with_options(TreeOptions::default().cow_batch_window(16));
+        let mut model = Model::new();
+        // Open snapshots with what they read, and the writes made through them.
+        let mut snapshots: Vec<(Snapshot<VariableSizeKey, u64>, Model, Model)> = Vec::new();
+        let mut view: Option<(RefreshingView<VariableSizeKey, u64>, Model)> = None;
+
+        for step in 1..4000u64 {
+            match rng.gen_range(0..100) {
+                0..=69 => {
+                    let k = key(rng.gen_range(0..400));
+                    tree.insert(&k, step, 0, step).unwrap();
+                    model.insert(k.as_slice().to_vec(), step);
+                }
+                70..=73 => {
+                    let k = key(rng.gen_range(0..400));
+                    if tree.remove(&k).unwrap() {
+                        model.remove(k.as_slice());
+                    }
+                }
+                74..=78 if snapshots.len() < 20 => {
+                    let snap = tree.create_snapshot().unwrap();
+                    snapshots.push((snap, model.clone(), Model::new()));
+                }
+                79..=81 if !snapshots.is_empty() => {
+                    let i = rng.gen_range(0..snapshots.len());
+                    let (mut snap, _, _) = snapshots.swap_remove(i);
+                    snap.close().unwrap();
+                    tree.close_snapshot(snap.id).unwrap();
+                }
+                82..=87 if !snapshots.is_empty() => {
+                    let i = rng.gen_range(0..snapshots.len());
+                    let (snap, seen, own) = &mut snapshots[i];
+                    let k = key(rng.gen_range(0..400));
+                    snap.insert(&k, step, step).unwrap();
+                    seen.insert(k.as_slice().to_vec(), step);
+                    own.insert(k.as_slice().to_vec(), step);
+                }
+                88..=89 if !snapshots.is_empty() => {
+                    // A rebase shares the root of the Trie without closing the
+                    // window.
+                    let i = rng.gen_range(0..snapshots.len());
+                    let (snap, seen, own) = &mut snapshots[i];
+                    snap.rebase(&tree).unwrap();
+                    *seen = model.clone();
+                    seen.extend(own.iter().map(|(k, v)| (k.clone(), *v)));
+                }
+                90..=94 => match view.as_mut() {
+                    None => view = Some((tree.refreshing_view().unwrap(), model.clone())),
+                    Some((view, seen)) => {
+                        view.refresh(&mut tree).unwrap();
+                        *seen = model.clone();
+                    }
+                },
+                _ if !snapshots.is_empty() => {
+                    // A reader shares the root of its snapshot.
+                    let i = rng.gen_range(0..snapshots.len());
+                    let (snap, seen, _) = &mut snapshots[i];
+                    let reader = snap.new_reader().unwrap();
+                    assert_eq!(
+                        reader
+                            .iter()
+                            .map(|(k, v, _, _)| (k, *v))
+                            .collect::<Vec<_>>(),
+                        contents(seen)
+                    );
+                    snap.close_reader(reader.id()).unwrap();
+                }
+                _ => {}
+            }
 
-    //     assert!(tree.root.is_none());
-    // }
+            if step % 50 == 0 {
+                tree.verify().unwrap();
+                assert_eq!(entries(tree.iter()), contents(&model));
+                for (snap, seen, _) in &snapshots {
+                    for (k, v) in seen.iter().take(20) {
+                        let found = snap.get(&VariableSizeKey::from_slice(k)).unwrap();
+                        assert_eq!(found.0, *v);
+                    }
+                    assert_eq!(snap.count().unwrap(), seen.len());
+                }
+                if let Some((view, seen)) = &view {
+                    assert_eq!(entries(view.read().iter()), contents(seen));
+                }
+            }
+        }
+        assert!(tree.pressure().nodes_reused > 0);
+    }
 
-    // Inserting Five values into a tree and deleting one of them
-    // should result in a tree root of type NODE4
-    // This tests the expansion of the root into a NODE16 and
-    // successfully collapsing into a NODE4 upon successive removals
     #[test]
-    fn insert5_and_remove1_and_root_should_be_node4() {
-        let mut tree = Tree::<VariableSizeKey, i32>::new();
-
-        // Insertion
-        for i in 0..5u32 {
-            let key = VariableSizeKey::from_slice(&i.to_be_bytes());
-            tree.insert(&key, 1, 0, 0);
+    fn cow_batch_window_survives_panicking_values() {
+        use fragile::Fragile;
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        Fragile::reset();
+        let key = |i: u64| VariableSizeKey::from_str(&format!("key{:03}", i)).unwrap();
+        let mut tree: Tree<VariableSizeKey, Fragile> =
+            Tree::with_options(TreeOptions::default().cow_batch_window(1000));
+        for i in 0..100 {
+            tree.insert(&key(i), Fragile(i), 0, i).unwrap();
         }
+        assert!(tree.pressure().nodes_reused > 0);
+        let before: Vec<(Vec<u8>, u64, u64)> = tree
+            .iter()
+            .map(|(k, v, version, _)| (k, v.0, *version))
+            .collect();
+
+        // Cloning the old value fails before any node is updated in place.
+        Fragile::fail_after_clones(0);
+        let insert = catch_unwind(AssertUnwindSafe(|| {
+            tree.insert(&key(5), Fragile(500), 0, 0)
+        }));
+        assert!(insert.is_err());
+        Fragile::reset();
+        tree.verify().unwrap();
+        let after: Vec<(Vec<u8>, u64, u64)> = tree
+            .iter()
+            .map(|(k, v, version, _)| (k, v.0, *version))
+            .collect();
+        assert_eq!(after, before);
+    }
 
-        // Removal
-        let key_to_remove = VariableSizeKey::from_slice(&1u32.to_be_bytes());
-        assert!(tree.remove(&key_to_remove).unwrap());
+    #[test]
+    fn hot_prefixes_follow_a_skewed_workload() {
+        use crate::pressure::TreeOptions;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let tenants = ["t0/", "t1/", "t2/", "t3/", "t4/", "t5/", "t6/", "t7/"];
+        let mut tree: Tree<VariableSizeKey, u32> =
+            Tree::with_options(TreeOptions::default().track_read_frequency(3));
+        for tenant in tenants {
+            for i in 0..20u32 {
+                let key = VariableSizeKey::from_str(&format!("{}{}", tenant, i)).unwrap();
+                tree.insert(&key, i, 0, 0).unwrap();
+            }
+        }
 
-        // Root verification
-        if let Some(root) = &tree.root {
-            assert!(root.is_inner());
-            assert_eq!(root.node_type_name(), "Node4");
-        } else {
-            panic!("Tree root is None");
+        // Tenant i is read about half as often as tenant i - 1.
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0..20_000 {
+            let tenant = (0..tenants.len() - 1)
+                .find(|_| rng.gen_bool(0.5))
+                .unwrap_or(tenants.len() - 1);
+            let key = format!("{}{}", tenants[tenant], rng.gen_range(0..20));
+            tree.get(&VariableSizeKey::from_str(&key).unwrap(), 0)
+                .unwrap();
         }
+        // Misses and writes are not reads.
+        let missing = VariableSizeKey::from_str("t7/missing").unwrap();
+        for _ in 0..20_000 {
+            assert!(tree.get(&missing, 0).is_err());
+        }
+        tree.update(&VariableSizeKey::from_str("t7/0").unwrap(), 0, |v| *v += 1)
+            .unwrap();
+
+        let hot = tree.hot_prefixes(3);
+        let names: Vec<&[u8]> = hot.iter().map(|(prefix, _)| prefix.as_slice()).collect();
+        assert_eq!(names, vec![&b"t0/"[..], b"t1/", b"t2/"]);
+        assert!(hot[0].1 > 9_000 && hot[1].1 > 4_000 && hot[2].1 > 2_000);
+        let total: u64 = tree.hot_prefixes(usize::MAX).iter().map(|(_, n)| n).sum();
+        assert_eq!(total, 20_000);
+
+        // Snapshot reads are only counted if configured.
+        let key = VariableSizeKey::from_str("t5/1").unwrap();
+        tree.reset_read_frequency();
+        let snap = tree.create_snapshot().unwrap();
+        snap.get(&key).unwrap();
+        assert!(tree.hot_prefixes(1).is_empty());
+
+        let mut tree: Tree<VariableSizeKey, u32> = Tree::with_options(
+            TreeOptions::default()
+                .track_read_frequency(3)
+                .count_snapshot_reads(),
+        );
+        tree.insert(&key, 1, 0, 0).unwrap();
+        let mut snap = tree.create_snapshot().unwrap();
+        snap.get(&key).unwrap();
+        let reader = snap.new_reader().unwrap();
+        reader.get(&key).unwrap();
+        tree.get(&key, 0).unwrap();
+        assert_eq!(tree.hot_prefixes(1), vec![(b"t5/".to_vec(), 3)]);
+        assert!(Tree::<VariableSizeKey, u32>::new()
+            .hot_prefixes(1)
+            .is_empty());
     }
 
-    //     // Inserting Five values into a tree and deleting all of them
-    //     // should result in a tree root of type nil
-    //     // This tests the expansion of the root into a NODE16 and
-    //     // successfully collapsing into a NODE4, twig, then nil
-    //     #[test]
-    //     fn insert5_and_remove5_and_root_should_be_nil() {
-    //         let mut tree = Tree::<VariableSizeKey, i32>::new();
+    #[test]
+    fn history_lists_versions_oldest_first() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        let key = VariableSizeKey::from_str("k").unwrap();
+        let other = VariableSizeKey::from_str("other").unwrap();
+        assert_eq!(tree.history(&key), Err(TrieError::KeyNotFound));
+
+        tree.insert(&key, 1, 0, 10).unwrap();
+        tree.insert(&other, 9, 0, 15).unwrap();
+        tree.insert(&key, 2, 0, 20).unwrap();
+        assert_eq!(tree.history(&key).unwrap(), vec![(1, 1, 10), (2, 3, 20)]);
+        assert_eq!(tree.history(&other).unwrap(), vec![(9, 2, 15)]);
+
+        tree.remove(&key).unwrap();
+        assert_eq!(tree.history(&key), Err(TrieError::KeyNotFound));
+    }
 
-    //         for i in 0..5u32 {
-    //             let key = &VariableSizeKey::from_slice(&i.to_be_bytes());
-    //             tree.insert(key, 1);
-    //         }
+    #[test]
+    fn insert_expect_ts() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        let key = VariableSizeKey::from_str("counter").unwrap();
+
+        // An absent key only matches an expected timestamp of 0.
+        assert_eq!(
+            tree.insert_expect_ts(&key, 1, 10, 5),
+            Err(TrieError::TimestampConflict {
+                expected: 5,
+                current: None
+            })
+        );
+        tree.insert_expect_ts(&key, 1, 10, 0).unwrap();
+        assert_eq!(tree.get(&key, 0).unwrap().3, 10);
+
+        // The write lands only against the latest timestamp.
+        tree.insert_expect_ts(&key, 2, 20, 10).unwrap();
+        assert_eq!(
+            tree.insert_expect_ts(&key, 3, 30, 10),
+            Err(TrieError::TimestampConflict {
+                expected: 10,
+                current: Some(20)
+            })
+        );
+        assert_eq!(
+            tree.insert_expect_ts(&key, 3, 30, 0),
+            Err(TrieError::TimestampConflict {
+                expected: 0,
+                current: Some(20)
+            })
+        );
+        let (_, value, version, ts) = tree.get(&key, 0).unwrap();
+        assert_eq!((value, version, ts), (2, 2, 20));
+    }
 
-    //         for i in 0..5u32 {
-    //             let key = &VariableSizeKey::from_slice(&i.to_be_bytes());
-    //             tree.remove(key);
-    //         }
+    #[test]
+    fn dedup_identical_versions() {
+        use super::InsertOutcome;
+        use crate::record::OpRecord;
+
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let versions_of = |tree: &Tree<VariableSizeKey, i32>, k: &str| match &Node::find_twig(
+            tree.root.as_ref().unwrap(),
+            &key(k),
+        )
+        .unwrap()
+        .node_type
+        {
+            NodeType::Twig(twig) => twig.values.len(),
+            _ => unreachable!(),
+        };
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new().dedup_identical_versions(true);
+
+        // Repeated identical writes create one version, and report the version
+        // and timestamp of the write they repeat.
+        let outcome = tree.insert_with_outcome(&key("k"), 1, 0, 10).unwrap();
+        assert_eq!(
+            outcome,
+            InsertOutcome {
+                old_value: None,
+                created: true,
+                version: 1,
+                ts: 10,
+                nodes_copied: 0,
+            }
+        );
+        for ts in 11..15 {
+            let outcome = tree.insert_with_outcome(&key("k"), 1, 0, ts).unwrap();
+            assert!(!outcome.created);
+            assert_eq!((outcome.version, outcome.ts), (1, 10));
+        }
+        assert_eq!(tree.version(), 1);
+        assert_eq!(versions_of(&tree, "k"), 1);
+        // An explicit version is still checked.
+        assert!(tree.insert(&key("k"), 1, 1, 20).is_err());
+
+        // A different value breaks the run, and repeating the old value afterwards
+        // is a new version again.
+        assert_eq!(tree.insert(&key("k"), 2, 0, 20).unwrap(), Some(1));
+        assert!(
+            tree.insert_with_outcome(&key("k"), 1, 0, 30)
+                .unwrap()
+                .created
+        );
+        assert_eq!(versions_of(&tree, "k"), 3);
+        assert_eq!(tree.get(&key("k"), 1).unwrap().1, 1);
+        assert_eq!(tree.get(&key("k"), 2).unwrap().1, 2);
+        assert_eq!(tree.get(&key("k"), 0).unwrap(), (key("k"), 1, 3, 30));
+
+        // A removed key has no value to repeat.
+        tree.remove(&key("k")).unwrap();
+        assert!(
+            tree.insert_with_outcome(&key("k"), 1, 0, 40)
+                .unwrap()
+                .created
+        );
 
-    //         assert!(tree.root.is_none());
-    //     }
+        // Batches skip entries repeating the Trie or earlier entries.
+        let version = tree.version();
+        tree.bulk_insert(&[
+            KV::new(key("k"), 1, 0, 50),
+            KV::new(key("j"), 5, 0, 50),
+            KV::new(key("j"), 5, 0, 51),
+        ])
+        .unwrap();
+        assert_eq!(
+            tree.get(&key("j"), 0).unwrap(),
+            (key("j"), 5, version + 1, 50)
+        );
+        assert_eq!(versions_of(&tree, "j"), 1);
+        assert_eq!(versions_of(&tree, "k"), 1);
+
+        // So do snapshots.
+        let mut snap = tree.create_snapshot().unwrap();
+        snap.insert(&key("j"), 5, 60).unwrap();
+        assert_eq!(snap.get(&key("j")).unwrap(), (5, version + 1, 50));
+        snap.insert(&key("j"), 6, 60).unwrap();
+        assert_eq!(snap.get(&key("j")).unwrap().0, 6);
+        snap.close().unwrap();
+        tree.close_snapshot(snap.id()).unwrap();
+
+        // Replaying a log of repeated writes skips them, where a Trie without the
+        // setting keeps them all.
+        let log: Vec<OpRecord<VariableSizeKey, i32>> = (1..=3)
+            .map(|version| OpRecord::Insert {
+                key: key("r"),
+                value: 7,
+                version,
+                ts: version,
+            })
+            .collect();
+        let replayed = Tree::new()
+            .dedup_identical_versions(true)
+            .replay_into(log.clone())
+            .unwrap();
+        assert_eq!(versions_of(&replayed, "r"), 1);
+        let replayed = Tree::replay(log).unwrap();
+        assert_eq!(versions_of(&replayed, "r"), 3);
+    }
 
-    // Inserting 17 values into a tree and deleting one of them should
-    // result in a tree root of type NODE16
-    // This tests the expansion of the root into a NODE48, and
-    // successfully collapsing into a NODE16
     #[test]
-    fn insert17_and_remove1_and_root_should_be_node16() {
-        let mut tree = Tree::<VariableSizeKey, i32>::new();
+    fn depth_is_optimal_for_nested_keys() {
+        // The depth of a trie where every inner node branches: one level for the
+        // node holding the common prefix of `keys`, plus the deepest branch.
+        fn optimal_depth(keys: &[Vec<u8>], depth: usize) -> usize {
+            if keys.len() == 1 {
+                return 1;
+            }
+            let lcp = (depth..)
+                .find(|&i| keys.iter().any(|k| k.get(i) != keys[0].get(i)))
+                .unwrap();
+            let mut deepest = 0;
+            let mut rest = keys;
+            while let Some(first) = rest.first() {
+                let end = rest.iter().take_while(|k| k[lcp] == first[lcp]).count();
+                deepest = deepest.max(optimal_depth(&rest[..end], lcp + 1));
+                rest = &rest[end..];
+            }
+            1 + deepest
+        }
 
-        // Insertion
-        for i in 0..17u32 {
-            let key = VariableSizeKey::from_slice(&i.to_be_bytes());
-            tree.insert(&key, 1, 0, 0);
+        fn check(tree: &Tree<VariableSizeKey, usize>) {
+            tree.verify().unwrap();
+            let mut keys: Vec<Vec<u8>> = tree.iter().map(|(key, _, _, _)| key).collect();
+            keys.sort();
+            let stats = tree.depth_stats();
+            assert_eq!(stats.keys, keys.len());
+            assert_eq!(stats.single_child_nodes, 0);
+            assert_eq!(stats.keys_deeper_than_length, 0);
+            let optimal = if keys.is_empty() {
+                0
+            } else {
+                optimal_depth(&keys, 0)
+            };
+            assert_eq!(stats.max_depth, optimal);
         }
 
-        // Removal
-        let key_to_remove = VariableSizeKey::from_slice(&2u32.to_be_bytes());
-        assert!(tree.remove(&key_to_remove).unwrap());
+        let chain = ["a", "ab", "abc", "abcd", "abcde", "abd", "b", "bc"];
+        let key = |k: &str| VariableSizeKey::from_str(k).unwrap();
+        let orders: [Vec<&str>; 2] = [
+            // Each shared prefix inserted before its extensions.
+            chain.to_vec(),
+            // Each shared prefix inserted after its extensions.
+            chain.iter().rev().copied().collect(),
+        ];
+        for order in orders {
+            let mut tree = Tree::<VariableSizeKey, usize>::new();
+            for (i, k) in order.iter().enumerate() {
+                tree.insert(&key(k), i, 0, 0).unwrap();
+                check(&tree);
+            }
+            // Removing a prefix, or its extensions, leaves nodes with a single
+            // child, which a compaction merges away.
+            let mut merged = 0;
+            for k in ["abc", "ab", "abd", "b", "abcde", "a"] {
+                assert!(tree.remove(&key(k)).unwrap());
+                tree.verify().unwrap();
+                merged += tree.compact().unwrap();
+                check(&tree);
+            }
+            assert!(merged > 0);
+            assert_eq!(tree.compact().unwrap(), 0);
+        }
 
-        // Root verification
-        if let Some(root) = &tree.root {
-            assert!(root.is_inner());
-            assert_eq!(root.node_type_name(), "Node16");
-        } else {
-            panic!("Tree root is None");
+        // A single-child node above the root is merged away by a compaction.
+        let mut tree = Tree::<VariableSizeKey, usize>::new();
+        for (i, k) in ["ab", "ac"].iter().enumerate() {
+            tree.insert(&key(k), i, 0, 0).unwrap();
         }
+        let root = tree.root.take().unwrap();
+        let wrapper = Node::new_inner(VariableSizeKey::from_slice(&[]), None)
+            .add_child(b'a', root.as_ref().clone_node());
+        tree.root = Some(Arc::new(wrapper));
+        assert_eq!(tree.depth_stats().single_child_nodes, 1);
+        assert_eq!(tree.compact().unwrap(), 1);
+        check(&tree);
+        assert_eq!(tree.get(&key("ac"), 0).unwrap().1, 1);
     }
 
     #[test]
-    fn insert17_and_root_should_be_node48() {
-        let mut tree = Tree::<VariableSizeKey, i32>::new();
-
-        // Insertion
-        for i in 0..17u32 {
-            let key = VariableSizeKey::from_slice(&i.to_be_bytes());
-            tree.insert(&key, 1, 0, 0);
+    fn forced_node_types_stay_correct() {
+        use super::NodeKind;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        use std::collections::BTreeMap;
+
+        fn inner_types(node: &Node<VariableSizeKey, u32>, types: &mut Vec<String>) {
+            if !node.is_twig() {
+                types.push(node.node_type_name());
+                for (_, child) in node.iter() {
+                    inner_types(child, types);
+                }
+            }
         }
 
-        // Root verification
-        if let Some(root) = &tree.root {
-            assert!(root.is_inner());
-            assert_eq!(root.node_type_name(), "Node48");
-        } else {
-            panic!("Tree root is None");
+        let kinds = [
+            None,
+            Some(NodeKind::Node1),
+            Some(NodeKind::Node4),
+            Some(NodeKind::Node16),
+            Some(NodeKind::Node48),
+            Some(NodeKind::Node256),
+        ];
+        for kind in kinds {
+            let mut tree: Tree<VariableSizeKey, u32> = match kind {
+                Some(kind) => Tree::with_forced_node_type(kind),
+                None => Tree::new(),
+            };
+            let mut expected = BTreeMap::new();
+            let mut rng = StdRng::seed_from_u64(17);
+            for i in 0..3000u32 {
+                let len = rng.gen_range(1..4);
+                let key: Vec<u8> = (0..len).map(|_| rng.gen_range(b'a'..=b'z')).collect();
+                let key = VariableSizeKey::from_slice_with_termination(&key);
+                if rng.gen_bool(0.3) {
+                    tree.remove(&key).unwrap();
+                    expected.remove(&key);
+                } else {
+                    tree.insert(&key, i, 0, 0).unwrap();
+                    expected.insert(key, i);
+                }
+            }
+            tree.verify().unwrap();
+            let entries: Vec<_> = tree
+                .iter()
+                .map(|(k, v, _, _)| (VariableSizeKey::from_slice(&k), *v))
+                .collect();
+            assert_eq!(entries, expected.into_iter().collect::<Vec<_>>());
+
+            let mut types = Vec::new();
+            inner_types(tree.root.as_ref().unwrap(), &mut types);
+            match kind {
+                Some(NodeKind::Node256) => assert!(types.iter().all(|t| t == "Node256")),
+                Some(NodeKind::Node48) => {
+                    assert!(types.iter().all(|t| t == "Node48" || t == "Node256"))
+                }
+                Some(NodeKind::Node16) => {
+                    assert!(types.iter().all(|t| t != "Node1" && t != "Node4"))
+                }
+                Some(NodeKind::Node4) => assert!(types.iter().all(|t| t != "Node1")),
+                None | Some(NodeKind::Node1) => {
+                    assert!(types.iter().any(|t| t == "Node4" || t == "Node1"))
+                }
+            }
         }
     }
 
-    // // Inserting 17 values into a tree and removing them all should
-    // // result in a tree of root type nil
-    // // This tests the expansion of the root into a NODE48, and
-    // // successfully collapsing into a NODE16, NODE4, twig, and then nil
-    // #[test]
-    // fn insert17_and_remove17_and_root_should_be_nil() {
-    //     let mut tree = Tree::<VariableSizeKey, i32>::new();
-
-    //     for i in 0..17u32 {
-    //         let key = VariableSizeKey::from_slice(&i.to_be_bytes());
-    //         tree.insert(&key, 1);
-    //     }
-
-    //     for i in 0..17u32 {
-    //         let key = VariableSizeKey::from_slice(&i.to_be_bytes());
-    //         tree.remove(&key);
-    //     }
-
-    //     assert!(tree.root.is_none());
-    // }
-
-    // Inserting 49 values into a tree and removing one of them should
-    // result in a tree root of type NODE48
-    // This tests the expansion of the root into a NODE256, and
-    // successfully collapasing into a NODE48
     #[test]
-    fn insert49_and_remove1_and_root_should_be_node48() {
-        let mut tree = Tree::<VariableSizeKey, i32>::new();
-
-        // Insertion
-        for i in 0..49u32 {
-            let key = VariableSizeKey::from_slice(&i.to_be_bytes());
-            tree.insert(&key, 1, 0, 0);
-        }
+    fn million_version_key() {
+        use crate::node::VERSIONS_TAIL_LEN;
+        use fragile::Fragile;
 
-        // Removal
-        let key_to_remove = VariableSizeKey::from_slice(&2u32.to_be_bytes());
-        assert!(tree.remove(&key_to_remove).unwrap());
+        const VERSIONS: u64 = 1_000_000;
+        let twig_of = |tree: &Tree<VariableSizeKey, Fragile>, key| match &Node::find_twig(
+            tree.root.as_ref().unwrap(),
+            key,
+        )
+        .unwrap()
+        .node_type
+        {
+            NodeType::Twig(twig) => twig.clone(),
+            _ => unreachable!(),
+        };
 
-        // Root verification
-        if let Some(root) = &tree.root {
-            assert!(root.is_inner());
-            assert_eq!(root.node_type_name(), "Node48");
-        } else {
-            panic!("Tree root is None");
+        let hot = VariableSizeKey::from_str("hot").unwrap();
+        let cold = VariableSizeKey::from_str("cold").unwrap();
+        let mut tree: Tree<VariableSizeKey, Fragile> =
+            Tree::with_options(TreeOptions::default().with_version_warn_threshold(1000));
+        tree.insert(&cold, Fragile(0), 0, 0).unwrap();
+
+        // Every write clones only the value it returns, however long the history.
+        Fragile::fail_after_clones(usize::MAX);
+        for i in 1..=VERSIONS {
+            tree.insert(&hot, Fragile(i), 0, i).unwrap();
+            if i == 999 {
+                assert_eq!(tree.overloaded_keys().count(), 0);
+            }
+        }
+        assert_eq!(usize::MAX - Fragile::clones_left(), VERSIONS as usize - 1);
+        assert_eq!(tree.overloaded_keys().collect::<Vec<_>>(), vec![&hot]);
+
+        let twig = twig_of(&tree, &hot);
+        assert_eq!(twig.values.len(), VERSIONS as usize);
+        assert!(twig.values.chunk_lens().count() <= 20);
+
+        // Version `v + 1` holds value `v`, in every chunk and across boundaries.
+        let mut boundary = 0;
+        for len in twig.values.chunk_lens() {
+            boundary += len as u64;
+            for version in boundary..=boundary + 2 {
+                let (_, value, found, ts) = tree.get(&hot, version + 1).unwrap();
+                assert_eq!(
+                    (value.0, found, ts),
+                    (
+                        version.min(VERSIONS),
+                        version.min(VERSIONS) + 1,
+                        version.min(VERSIONS)
+                    )
+                );
+            }
+        }
+        assert_eq!(tree.get(&hot, 2).unwrap().1, Fragile(1));
+        assert!(tree.get(&hot, 1).is_err());
+        assert!(boundary > VERSIONS - VERSIONS_TAIL_LEN as u64);
+        drop(twig);
+
+        // Pruning rebuilds the history and drops the key below the threshold.
+        let cutoff = VERSIONS - 400;
+        assert_eq!(
+            tree.prune_versions_older_than(cutoff).unwrap(),
+            cutoff as usize - 2
+        );
+        assert!(tree.get(&hot, cutoff - 1).is_err());
+        assert_eq!(tree.get(&hot, cutoff).unwrap().1, Fragile(cutoff - 1));
+        assert_eq!(twig_of(&tree, &hot).values.len(), 402);
+        assert_eq!(tree.overloaded_keys().count(), 0);
+        for i in 0..600 {
+            tree.insert(&hot, Fragile(i), 0, 0).unwrap();
         }
+        assert_eq!(tree.overloaded_keys().count(), 1);
+        assert!(tree.remove(&hot).unwrap());
+        assert_eq!(tree.overloaded_keys().count(), 0);
+        Fragile::reset();
     }
 
     #[test]
-    fn insert49_and_root_should_be_node248() {
-        let mut tree = Tree::<VariableSizeKey, i32>::new();
+    fn normalized_keys() {
+        use crate::normalize::AsciiLowercase;
+
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::with_normalizer(AsciiLowercase);
+        tree.insert(&key("Hello"), 1, 0, 0).unwrap();
+        assert_eq!(tree.get(&key("hello"), 0).unwrap().1, 1);
+        assert_eq!(tree.get(&key("HELLO"), 0).unwrap().1, 1);
+
+        // Differently cased spellings are versions of one key.
+        assert_eq!(tree.insert(&key("HeLLo"), 2, 0, 0).unwrap(), Some(1));
+        tree.bulk_insert(&[KV::new(key("World"), 3, 0, 0)]).unwrap();
+        let keys: Vec<Vec<u8>> = tree.iter().map(|(k, _, _, _)| k).collect();
+        assert_eq!(keys, vec![b"hello\0".to_vec(), b"world\0".to_vec()]);
+
+        // Range bounds are normalized too.
+        assert_eq!(tree.range(key("HELLO")..key("WORLD")).count(), 1);
+        assert_eq!(tree.range(key("HELLO")..=key("WORLD")).count(), 2);
+
+        let mut snapshot = tree.create_snapshot().unwrap();
+        assert_eq!(snapshot.get(&key("WORLD")).unwrap().0, 3);
+        assert!(snapshot.remove(&key("WORLD")).unwrap());
+        assert!(snapshot.get(&key("world")).is_err());
+
+        assert!(tree.remove(&key("HELLO")).unwrap());
+        assert!(tree.get(&key("hello"), 0).is_err());
+    }
 
-        // Insertion
-        for i in 0..49u32 {
-            let key = VariableSizeKey::from_slice(&i.to_be_bytes());
-            tree.insert(&key, 1, 0, 0);
+    #[test]
+    fn duplicate_ts_policies() {
+        use crate::pressure::DuplicateTsPolicy;
+        use crate::record::OpLog;
+
+        // Returns the (value, version, ts) of every version of `key`.
+        fn versions(
+            tree: &Tree<VariableSizeKey, u64>,
+            key: &VariableSizeKey,
+        ) -> Vec<(u64, u64, u64)> {
+            let twig = Node::find_twig(tree.root.as_ref().unwrap(), key).unwrap();
+            match &twig.node_type {
+                NodeType::Twig(twig) => twig
+                    .iter()
+                    .map(|leaf| (leaf.value, leaf.version, leaf.ts))
+                    .collect(),
+                _ => unreachable!(),
+            }
         }
 
-        // Root verification
-        if let Some(root) = &tree.root {
-            assert!(root.is_inner());
-            assert_eq!(root.node_type_name(), "Node256");
-        } else {
-            panic!("Tree root is None");
+        let key = VariableSizeKey::from_str("key").unwrap();
+        let other = VariableSizeKey::from_str("other").unwrap();
+        for policy in [
+            DuplicateTsPolicy::Reject,
+            DuplicateTsPolicy::Replace,
+            DuplicateTsPolicy::Stack,
+        ] {
+            let options = TreeOptions::default()
+                .with_duplicate_ts_policy(policy)
+                .track_prefix_stats(b'/');
+            let mut tree: Tree<VariableSizeKey, u64> = Tree::with_options(options);
+            let log = OpLog::new();
+            tree.enable_recording(log.clone());
+
+            tree.insert(&other, 0, 0, 10).unwrap();
+            tree.insert(&key, 1, 0, 10).unwrap();
+            let second = tree.insert(&key, 2, 0, 10);
+            let batch = tree.bulk_insert(&[KV::new(key.clone(), 3, 0, 10)]);
+            tree.insert(&key, 4, 0, 20).unwrap();
+
+            let expected = match policy {
+                DuplicateTsPolicy::Reject => {
+                    assert!(matches!(second, Err(TrieError::DuplicateTimestamp)));
+                    assert!(matches!(batch, Err(TrieError::DuplicateTimestamp)));
+                    vec![(1, 2, 10), (4, 3, 20)]
+                }
+                DuplicateTsPolicy::Replace => {
+                    assert_eq!(second.unwrap(), Some(1));
+                    assert!(batch.is_ok());
+                    vec![(3, 4, 10), (4, 5, 20)]
+                }
+                DuplicateTsPolicy::Stack => {
+                    assert!(second.is_ok() && batch.is_ok());
+                    vec![(1, 2, 10), (2, 3, 10), (3, 4, 10), (4, 5, 20)]
+                }
+            };
+            assert_eq!(versions(&tree, &key), expected, "{:?}", policy);
+
+            // The latest version wins, and the last value at timestamp 10 is the
+            // one read just below the version at timestamp 20.
+            let (latest, at_ts_10) = (expected[expected.len() - 1], expected[expected.len() - 2]);
+            assert_eq!(tree.get(&key, 0).unwrap().1, latest.0);
+            assert_eq!(tree.get(&key, latest.1 - 1).unwrap().1, at_ts_10.0);
+            // Replaced versions are gone, so reads at them fall back to older ones.
+            let at_version_2 = tree.get(&key, 2).map(|(_, value, _, _)| value).ok();
+            let expected_at_2 = (policy != DuplicateTsPolicy::Replace).then_some(1);
+            assert_eq!(at_version_2, expected_at_2, "{:?}", policy);
+            tree.verify_prefix_stats().unwrap();
+
+            // An exported snapshot sees the latest value.
+            let owned = tree.export_owned_snapshot().unwrap();
+            assert_eq!(owned.get(&key).unwrap(), (latest.0, latest.1, latest.2));
+
+            // Replaying the log with the same options rebuilds the same versions.
+            let replayed = Tree::replay_with_options(options, log.records()).unwrap();
+            assert_eq!(versions(&replayed, &key), expected, "{:?}", policy);
+            assert_eq!(replayed.version(), tree.version());
         }
     }
 
-    //     // // Inserting 49 values into a tree and removing all of them should
-    //     // // result in a nil tree root
-    //     // // This tests the expansion of the root into a NODE256, and
-    //     // // successfully collapsing into a Node48, Node16, Node4, twig, and finally nil
-    //     // #[test]
-    //     // fn insert49_and_remove49_and_root_should_be_nil() {
-    //     //     let mut tree = Tree::<VariableSizeKey, i32>::new();
-
-    //     //     for i in 0..49u32 {
-    //     //         let key = &VariableSizeKey::from_slice(&i.to_be_bytes());
-    //     //         tree.insert(key, 1);
-    //     //     }
-
-    //     //     for i in 0..49u32 {
-    //     //         let key = VariableSizeKey::from_slice(&i.to_be_bytes());
-    //     //         assert_eq!(tree.remove(&key), true);
-    //     //     }
+    #[test]
+    fn external_ids_are_stable_and_resolve_by_ts() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, u64> = Tree::new();
+
+        let a1 = tree.insert_external(&key("a"), 100).unwrap();
+        let b1 = tree.insert_external(&key("b"), 110).unwrap();
+        let a2 = tree.insert_external(&key("a"), 120).unwrap();
+        assert!(a1 < b1 && b1 < a2);
+
+        // Writes to other keys leave the ids of a key alone.
+        for i in 0..50 {
+            tree.insert_external(&key(&format!("c{}", i)), 130 + i)
+                .unwrap();
+        }
+        assert_eq!(tree.get_id(&key("a"), u64::MAX), Some(a2));
+        assert_eq!(tree.get_id(&key("a"), 119), Some(a1));
+        assert_eq!(tree.get_id(&key("a"), 120), Some(a2));
+        assert_eq!(tree.get_id(&key("a"), 99), None);
+        assert_eq!(tree.get_id(&key("b"), 200), Some(b1));
+        assert_eq!(tree.get_id(&key("c7"), 137), Some(a2 + 8));
+        assert_eq!(tree.get_id(&key("d"), 200), None);
+
+        // Emptying the Trie does not hand out the same ids again.
+        let last = tree.get_id(&key("c49"), u64::MAX).unwrap();
+        let keys: Vec<_> = tree.iter().map(|(k, ..)| k).collect();
+        for k in keys {
+            let k = VariableSizeKey::from_slice(&k);
+            tree.remove(&k).unwrap();
+        }
+        assert_eq!(tree.version(), 0);
+        assert_eq!(tree.insert_external(&key("a"), 300).unwrap(), last + 1);
+    }
 
-    //     //     assert!(tree.root.is_none());
-    //     // }
+    #[test]
+    fn expired_prefixes_hide_and_compaction_reclaims_them() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let keys = |tree: &Tree<VariableSizeKey, i32>| -> Vec<String> {
+            tree.iter()
+                .map(|(k, ..)| String::from_utf8(k[..k.len() - 1].to_vec()).unwrap())
+                .collect()
+        };
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        for (i, k) in [
+            "sess/1/a",
+            "sess/1/b",
+            "sess/1/keep/x",
+            "sess/2/a",
+            "user/1",
+        ]
+        .iter()
+        .enumerate()
+        {
+            tree.insert(&key(k), i as i32, 0, 10).unwrap();
+        }
+        tree.expire_prefix_at(b"sess/", 100).unwrap();
+        // The longer prefix wins over the shorter one, with a later deadline.
+        tree.expire_prefix_at(b"sess/1/keep/", 200).unwrap();
+        assert_eq!(tree.expire_prefix_at(b"sess/2/", 50).unwrap(), None);
+        assert_eq!(tree.cancel_expiry(b"sess/2/").unwrap(), Some(50));
+        assert_eq!(
+            tree.expiry_rules(),
+            vec![(b"sess/".to_vec(), 100), (b"sess/1/keep/".to_vec(), 200)]
+        );
 
-    #[derive(Debug, Clone, PartialEq)]
-    struct KVT {
-        k: Vec<u8>,   // Key
-        version: u64, // version
+        // Reads flip from visible to absent as the timestamp reaches the deadline.
+        tree.advance_ts(99);
+        assert_eq!(tree.get(&key("sess/2/a"), 0).unwrap().1, 3);
+        assert_eq!(keys(&tree).len(), 5);
+        tree.insert(&key("user/2"), 5, 0, 100).unwrap();
+        assert_eq!(tree.current_ts(), 100);
+        assert!(matches!(
+            tree.get(&key("sess/2/a"), 0),
+            Err(TrieError::KeyNotFound)
+        ));
+        assert_eq!(tree.get(&key("sess/1/keep/x"), 0).unwrap().1, 2);
+        assert_eq!(keys(&tree), vec!["sess/1/keep/x", "user/1", "user/2"]);
+        assert_eq!(tree.range(key("sess/")..key("user/2")).count(), 2);
+
+        // Earlier timestamps do not bring the keys back.
+        tree.advance_ts(50);
+        tree.insert(&key("sess/3/a"), 6, 0, 60).unwrap();
+        assert!(tree.get(&key("sess/3/a"), 0).is_err());
+
+        // The rules survive freezing and thawing.
+        let bytes = tree.freeze();
+        let thawed =
+            Tree::<VariableSizeKey, i32>::thaw(&crate::frozen::FrozenTree::open(&bytes).unwrap())
+                .unwrap();
+        assert_eq!(thawed.expiry_rules(), tree.expiry_rules());
+        assert_eq!(keys(&thawed), keys(&tree));
+
+        // Compaction removes the expired keys and drops their rule only.
+        let before = tree.iter_all_count();
+        tree.compact().unwrap();
+        assert_eq!(before, 7);
+        assert_eq!(tree.iter_all_count(), 3);
+        assert_eq!(tree.expiry_rules(), vec![(b"sess/1/keep/".to_vec(), 200)]);
+        tree.insert(&key("sess/4/a"), 7, 0, 110).unwrap();
+        assert_eq!(tree.get(&key("sess/4/a"), 0).unwrap().1, 7);
+
+        tree.advance_ts(200);
+        assert_eq!(keys(&tree), vec!["sess/4/a", "user/1", "user/2"]);
+        tree.compact().unwrap();
+        assert!(tree.expiry_rules().is_empty());
+        assert_eq!(tree.iter_all_count(), 3);
     }
 
     #[test]
-    fn timed_insertion() {
-        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
-
-        let kvts = vec![
-            KVT {
-                k: b"key1_0".to_vec(),
-                version: 0,
-            },
-            KVT {
-                k: b"key2_0".to_vec(),
-                version: 0,
-            },
-            KVT {
-                k: b"key3_0".to_vec(),
-                version: 0,
-            },
-            KVT {
-                k: b"key4_0".to_vec(),
-                version: 0,
-            },
-            KVT {
-                k: b"key5_0".to_vec(),
-                version: 0,
-            },
-            KVT {
-                k: b"key6_0".to_vec(),
-                version: 0,
-            },
-        ];
+    fn strict_mode_poisons_on_planted_corruption() {
+        use crate::node::{LeafValue, Versions};
+        use crate::strict::{InvariantCheck, InvariantChecks};
+
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        // Every key is a twig right under the root, at the byte of its letter.
+        let build = |options: TreeOptions| {
+            let mut tree: Tree<VariableSizeKey, u64> = Tree::with_options(options);
+            for (i, k) in ["a1", "b1", "c1"].iter().enumerate() {
+                tree.insert(&key(k), i as u64, 0, 0).unwrap();
+            }
+            tree
+        };
+        // Replaces the twig under `byte` with a corrupted copy.
+        let plant = |tree: &mut Tree<VariableSizeKey, u64>, byte: u8, check: InvariantCheck| {
+            let root = tree.root.clone().unwrap();
+            let mut twig = root.find_child(byte).unwrap().clone_node();
+            match check {
+                InvariantCheck::ChildBytes => twig.set_prefix(key("z1")),
+                InvariantCheck::PrefixLengths => {
+                    twig.set_prefix(VariableSizeKey::from_slice(&[byte, b'1']))
+                }
+                InvariantCheck::VersionOrder => {
+                    let NodeType::Twig(twig) = &mut twig.node_type else {
+                        unreachable!()
+                    };
+                    twig.values = Versions::from_sorted(vec![
+                        Arc::new(LeafValue::new(10, 5, 0)),
+                        Arc::new(LeafValue::new(11, 3, 0)),
+                    ]);
+                }
+            }
+            tree.root = Some(Arc::new(root.replace_child(byte, Arc::new(twig))));
+        };
 
-        // Insertion
-        for (idx, kvt) in kvts.iter().enumerate() {
-            let ts = if kvt.version == 0 {
-                idx as u64 + 1
-            } else {
-                kvt.version
+        for (byte, check) in [
+            (b'a', InvariantCheck::ChildBytes),
+            (b'b', InvariantCheck::PrefixLengths),
+            (b'c', InvariantCheck::VersionOrder),
+        ] {
+            let target = key(&format!("{}1", byte as char));
+            let mut tree = build(TreeOptions::default().poison_on_corruption(true));
+            plant(&mut tree, byte, check);
+            assert!(tree.poison_report().is_none());
+
+            // The first read reaching the corruption poisons the tree.
+            let first_violation = match tree.get(&target, 0) {
+                Err(TrieError::Poisoned { first_violation }) => first_violation,
+                other => panic!("{:?}: expected poisoning, got {:?}", check, other),
             };
-            assert!(tree
-                .insert(&VariableSizeKey::from(kvt.k.clone()), 1, ts, 0)
-                .is_ok());
-        }
-
-        // Verification
-        let mut curr_version = 1;
-        for kvt in &kvts {
-            let key = VariableSizeKey::from(kvt.k.clone());
-            let (_, val, version, _ts) = tree.get(&key, 0).unwrap();
-            assert_eq!(val, 1);
+            assert_eq!(first_violation.check, check);
+            assert_eq!(
+                first_violation.path[0],
+                if check == InvariantCheck::ChildBytes {
+                    b'z'
+                } else {
+                    byte
+                }
+            );
+            let report = tree.poison_report().unwrap().clone();
+            assert_eq!(report.violation, first_violation);
+            assert_eq!(
+                (report.key.as_slice(), report.operation),
+                (target.as_slice(), "get")
+            );
 
-            if kvt.version == 0 {
-                assert_eq!(curr_version, version);
-            } else {
-                assert_eq!(kvt.version, version);
+            // Every later operation fails fast with the first violation, even on
+            // healthy keys.
+            let poisoned = Err(TrieError::Poisoned {
+                first_violation: first_violation.clone(),
+            });
+            assert_eq!(tree.get(&key("a1"), 0).map(|_| ()), poisoned);
+            assert_eq!(tree.insert(&key("d1"), 3, 0, 0).map(|_| ()), poisoned);
+            assert_eq!(tree.remove(&key("b1")).map(|_| ()), poisoned);
+            assert_eq!(tree.history(&key("c1")).map(|_| ()), poisoned);
+            assert_eq!(tree.create_snapshot().map(|_| ()), poisoned);
+
+            // The data can still be salvaged.
+            assert_eq!(tree.export_owned_snapshot().unwrap().len(), 3);
+            assert!(!tree.freeze().is_empty());
+            assert!(tree.close().is_ok());
+
+            // Writes check the path to their key as well.
+            let mut tree = build(TreeOptions::default().poison_on_corruption(true));
+            plant(&mut tree, byte, check);
+            assert!(matches!(
+                tree.insert(&target, 7, 0, 0),
+                Err(TrieError::Poisoned { .. })
+            ));
+            assert_eq!(tree.poison_report().unwrap().operation, "insert");
+            assert_eq!(tree.export_owned_snapshot().unwrap().len(), 3);
+            for operation in ["bulk_insert", "remove"] {
+                let mut tree = build(TreeOptions::default().poison_on_corruption(true));
+                plant(&mut tree, byte, check);
+                let result = match operation {
+                    "remove" => tree.remove(&target).map(|_| ()),
+                    _ => tree.bulk_insert(&[KV::new(target.clone(), 7, 0, 0)]),
+                };
+                assert!(matches!(result, Err(TrieError::Poisoned { .. })));
+                assert_eq!(tree.poison_report().unwrap().operation, operation);
             }
 
-            curr_version += 1;
+            // A disabled check lets the same corruption through.
+            let mut checks = InvariantChecks::all();
+            match check {
+                InvariantCheck::ChildBytes => checks.child_bytes = false,
+                InvariantCheck::PrefixLengths => checks.prefix_lengths = false,
+                InvariantCheck::VersionOrder => checks.version_order = false,
+            }
+            let mut tree = build(TreeOptions::default().invariant_checks(checks));
+            plant(&mut tree, byte, check);
+            assert!(!matches!(
+                tree.get(&target, 0),
+                Err(TrieError::Poisoned { .. })
+            ));
+            assert!(tree.poison_report().is_none());
+            assert!(tree
+                .get(&key(if byte == b'a' { "b1" } else { "a1" }), 0)
+                .is_ok());
         }
 
-        // Root's version should match the greatest inserted version
-        assert_eq!(kvts.len() as u64, tree.version());
+        // Without strict mode the corruption goes unnoticed.
+        let mut tree = build(TreeOptions::default());
+        plant(&mut tree, b'a', InvariantCheck::ChildBytes);
+        assert_eq!(
+            tree.get(&key("a1"), 0).map(|_| ()),
+            Err(TrieError::KeyNotFound)
+        );
+        assert!(tree.insert(&key("d1"), 3, 0, 0).is_ok());
     }
 
     #[test]
-    fn timed_insertion_update_same_key() {
-        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+    fn bulk_insert_validates_timestamps() {
+        use crate::ingest::{IngestPolicy, TsViolation};
 
-        let key1 = &VariableSizeKey::from_str("key_1").unwrap();
+        let key = |k: &str| VariableSizeKey::from_str(k).unwrap();
+        let invalid = |k: &str, position, ts, violation| TrieError::InvalidTimestamp {
+            key: key(k).as_slice().to_vec(),
+            position,
+            ts,
+            violation,
+        };
+        let mut tree: Tree<VariableSizeKey, i32> =
+            Tree::with_options(TreeOptions::default().max_valid_ts(1000));
+        tree.insert(&key("a"), 1, 0, 10).unwrap();
+
+        let batch = [KV::new(key("b"), 2, 0, 5), KV::new(key("a"), 3, 0, 9)];
+        assert_eq!(
+            tree.bulk_insert(&batch),
+            Err(invalid(
+                "a",
+                1,
+                9,
+                TsViolation::NotIncreasing { previous: 10 }
+            ))
+        );
+        // Nothing from a rejected batch is inserted.
+        assert!(tree.get(&key("b"), 0).is_err());
+        assert_eq!(
+            tree.bulk_insert(&[KV::new(key("c"), 4, 0, 1001)]),
+            Err(invalid(
+                "c",
+                0,
+                1001,
+                TsViolation::AboveCeiling { ceiling: 1000 }
+            ))
+        );
+        assert_eq!(
+            tree.bulk_insert(&[KV::new(key("c"), 4, 0, 0)]),
+            Err(invalid("c", 0, 0, TsViolation::Zero))
+        );
+        tree.bulk_insert(&[KV::new(key("c"), 4, 0, 1000)]).unwrap();
+
+        let mut tree: Tree<VariableSizeKey, i32> =
+            Tree::with_options(TreeOptions::default().validate_ingest(IngestPolicy::repair()));
+        tree.insert(&key("a"), 1, 0, 10).unwrap();
+        tree.bulk_insert(&batch).unwrap();
+        tree.bulk_insert(&[KV::new(key("c"), 4, 0, 0)]).unwrap();
+        assert_eq!(tree.get(&key("a"), 0).unwrap().3, 11);
+        assert_eq!(tree.get(&key("b"), 0).unwrap().3, 5);
+        assert_eq!(tree.get(&key("c"), 0).unwrap().3, 12);
+    }
 
-        // insert key1 with version 0
-        assert!(tree.insert(key1, 1, 0, 1).is_ok());
-        // update key1 with version 0
-        assert!(tree.insert(key1, 1, 0, 3).is_ok());
+    #[test]
+    fn ts_domains_survive_subtree_exchange() {
+        let key = |k: &str| VariableSizeKey::from_str(k).unwrap();
+        let prefix = |p: &str| VariableSizeKey::from_slice(p.as_bytes());
+        let ts_of = |tree: &Tree<VariableSizeKey, i32>, k: &str| -> Vec<u64> {
+            let history = tree.history(&key(k)).unwrap();
+            assert!(history.windows(2).all(|pair| pair[0].1 < pair[1].1));
+            history.into_iter().map(|(_, _, ts)| ts).collect()
+        };
+        let options = TreeOptions::default().ts_domains(b'/');
+        let mut eu: Tree<VariableSizeKey, i32> = Tree::with_options(options);
+        let mut us: Tree<VariableSizeKey, i32> = Tree::with_options(options);
+
+        // Each writer numbers the writes to its own domain, at timestamps the
+        // other writer's domain has long passed.
+        for ts in 1..=5 {
+            eu.insert(&key("eu/a"), ts as i32, 0, ts).unwrap();
+        }
+        eu.insert(&key("eu/b"), 0, 0, 6).unwrap();
+        eu.remove_prefixes(&[prefix("eu/b")], 9).unwrap();
+        for ts in 100..=103 {
+            us.insert(&key("us/a"), ts as i32, 0, ts).unwrap();
+        }
+        assert_eq!(eu.ts_of_domain(b"eu"), Some(9));
+        assert_eq!(eu.ts_of_domain(b"us"), None);
+        assert_eq!(us.ts_of_domain(b"us"), Some(103));
 
-        // get key1 should return version 2 as the same key was inserted and updated
-        let (_, val, version, ts) = tree.get(key1, 0).unwrap();
-        assert_eq!(val, 1);
-        assert_eq!(version, 2);
-        assert_eq!(ts, 3);
+        for _ in 0..2 {
+            assert_eq!(eu.splice_subtree(&us, b"us/").unwrap(), 1);
+            assert_eq!(us.splice_subtree(&eu, b"eu/").unwrap(), 1);
+        }
+        for tree in [&eu, &us] {
+            assert_eq!(
+                tree.ts_domains(),
+                vec![(b"eu".to_vec(), 9), (b"us".to_vec(), 103)]
+            );
+            assert_eq!(ts_of(tree, "eu/a"), vec![1, 2, 3, 4, 5]);
+            assert_eq!(ts_of(tree, "us/a"), vec![100, 101, 102, 103]);
+            assert!(tree.get(&key("eu/b"), 0).is_err());
+        }
+        assert_eq!(eu.current_ts(), 103);
+
+        // Later writes move only their own domain, and the next exchange
+        // carries them over without touching the receiver's domain.
+        eu.insert(&key("eu/a"), 10, 0, 10).unwrap();
+        us.insert(&key("us/a"), 104, 0, 104).unwrap();
+        us.insert(&key("us/b"), 0, 0, 105).unwrap();
+        us.insert(&key("global"), 0, 0, 500).unwrap();
+        assert_eq!(us.ts_of_domain(b"us"), Some(105));
+        assert_eq!(us.ts_of_domain(b"global"), None);
+        assert_eq!(us.current_ts(), 500);
+        assert_eq!(eu.splice_subtree(&us, b"us/").unwrap(), 2);
+        us.splice_subtree(&eu, b"eu/").unwrap();
+        for tree in [&eu, &us] {
+            assert_eq!(tree.ts_of_domain(b"eu"), Some(10));
+            assert_eq!(tree.ts_of_domain(b"us"), Some(105));
+            assert_eq!(ts_of(tree, "eu/a"), vec![1, 2, 3, 4, 5, 10]);
+            assert_eq!(ts_of(tree, "us/a"), vec![100, 101, 102, 103, 104]);
+        }
+        assert!(eu.get(&key("global"), 0).is_err());
+
+        // Snapshots keep the counters they were taken with.
+        let snapshot = eu.create_snapshot().unwrap();
+        eu.insert(&key("eu/a"), 20, 0, 20).unwrap();
+        assert_eq!(snapshot.ts_of_domain(b"eu"), Some(10));
+        assert_eq!(eu.ts_of_domain(b"eu"), Some(20));
+
+        // Freezing keeps counters ahead of the timestamps of their keys.
+        eu.remove_prefixes(&[prefix("eu/a")], 30).unwrap();
+        let bytes = eu.freeze();
+        let thawed =
+            Tree::<VariableSizeKey, i32>::thaw(&crate::frozen::FrozenTree::open(&bytes).unwrap())
+                .unwrap();
+        assert_eq!(thawed.options(), options);
+        assert_eq!(
+            thawed.ts_domains(),
+            vec![(b"eu".to_vec(), 30), (b"us".to_vec(), 105)]
+        );
+    }
 
-        // update key1 with older version should fail
-        assert!(tree.insert(key1, 1, 1, 0).is_err());
-        assert_eq!(tree.version(), 2);
+    #[test]
+    fn ts_domains_assign_zero_timestamps_per_domain() {
+        use crate::ingest::IngestPolicy;
+
+        let key = |k: &str| VariableSizeKey::from_str(k).unwrap();
+        let options = TreeOptions::default()
+            .ts_domains(b'/')
+            .validate_ingest(IngestPolicy::repair());
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::with_options(options);
+        tree.insert(&key("eu/a"), 1, 0, 5).unwrap();
+        tree.insert(&key("us/a"), 2, 0, 100).unwrap();
+        tree.bulk_insert(&[
+            KV::new(key("eu/b"), 3, 0, 0),
+            KV::new(key("eu/c"), 4, 0, 0),
+            KV::new(key("none"), 5, 0, 0),
+            KV::new(key("us/b"), 6, 0, 0),
+        ])
+        .unwrap();
+        assert_eq!(tree.get(&key("eu/b"), 0).unwrap().3, 6);
+        assert_eq!(tree.get(&key("eu/c"), 0).unwrap().3, 7);
+        // A key in no domain follows the whole Trie, which does not move the
+        // counter of any domain.
+        assert_eq!(tree.get(&key("none"), 0).unwrap().3, 101);
+        assert_eq!(tree.get(&key("us/b"), 0).unwrap().3, 101);
+        assert_eq!(tree.ts_of_domain(b"eu"), Some(7));
+        assert_eq!(tree.ts_of_domain(b"us"), Some(101));
+        assert_eq!(tree.current_ts(), 101);
+    }
 
-        // update key1 with newer version should pass
-        assert!(tree.insert(key1, 1, 8, 5).is_ok());
-        let (_, val, version, ts) = tree.get(key1, 0).unwrap();
-        assert_eq!(val, 1);
-        assert_eq!(version, 8);
-        assert_eq!(ts, 5);
+    #[test]
+    fn bulk_loads_validate_timestamps() {
+        use super::BulkLoadReason;
+        use crate::ingest::{IngestPolicy, TsViolation};
+
+        let key = |k: &str| VariableSizeKey::from_str(k).unwrap();
+        let options = TreeOptions::default().validate_ingest(IngestPolicy::strict());
+        let Err(err) = Tree::<VariableSizeKey, i32>::bulk_load_with_options(
+            options,
+            vec![
+                KV::new(key("a"), 1, 0, 1),
+                KV::new(key("b"), 2, 0, 0),
+                KV::new(key("c"), 3, 0, 3),
+            ],
+        ) else {
+            panic!("a zero timestamp was accepted");
+        };
+        assert_eq!(err.index, 1);
+        assert_eq!(
+            err.reason,
+            BulkLoadReason::Trie(TrieError::InvalidTimestamp {
+                key: key("b").as_slice().to_vec(),
+                position: 1,
+                ts: 0,
+                violation: TsViolation::Zero,
+            })
+        );
 
-        assert_eq!(tree.version(), 8);
+        // The load resumes from the rejected entry once it is fixed.
+        let tree = err
+            .tree
+            .bulk_append(vec![KV::new(key("b"), 2, 0, 2), KV::new(key("c"), 3, 0, 3)])
+            .unwrap();
+        let timestamps: Vec<u64> = tree.iter().map(|(_, _, _, ts)| *ts).collect();
+        assert_eq!(timestamps, vec![1, 2, 3]);
     }
 
     #[test]
-    fn timed_insertion_update_non_increasing_version() {
-        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
-
-        let key1 = VariableSizeKey::from_str("key_1").unwrap();
-        let key2 = VariableSizeKey::from_str("key_2").unwrap();
+    fn apply_wal_validates_timestamps() {
+        use crate::diff::serialize_delta;
+        use crate::ingest::{IngestPolicy, TsViolation};
+
+        let key = VariableSizeKey::from_str("a").unwrap();
+        let mut source: Tree<VariableSizeKey, i32> = Tree::new();
+        let mut wal = Vec::new();
+        let mut log = |source: &mut Tree<VariableSizeKey, i32>, write: Option<u64>| {
+            let base = source.create_snapshot().unwrap();
+            match write {
+                Some(ts) => source.insert(&key, ts as i32, 0, ts).map(|_| ()).unwrap(),
+                None => assert!(source.remove(&key).unwrap()),
+            }
+            serialize_delta(&source.diff_since(&base), &mut wal).unwrap();
+        };
+        // The key goes back in time, then is removed and written again.
+        log(&mut source, Some(5));
+        log(&mut source, Some(3));
+        log(&mut source, None);
+        log(&mut source, Some(1));
+
+        let mut strict: Tree<VariableSizeKey, i32> =
+            Tree::with_options(TreeOptions::default().validate_ingest(IngestPolicy::strict()));
+        assert_eq!(
+            strict.apply_wal(wal.as_slice()),
+            Err(TrieError::InvalidTimestamp {
+                key: key.as_slice().to_vec(),
+                position: 1,
+                ts: 3,
+                violation: TsViolation::NotIncreasing { previous: 5 },
+            })
+        );
+        assert_eq!(strict.get(&key, 0).unwrap().3, 5);
 
-        // Initial insertion
-        assert!(tree.insert(&key1, 1, 10, 0).is_ok());
-        let initial_version_key1 = tree.version();
+        let mut repaired: Tree<VariableSizeKey, i32> =
+            Tree::with_options(TreeOptions::default().validate_ingest(IngestPolicy::repair()));
+        repaired.apply_wal(&wal[..]).unwrap();
+        // A key written again after its removal starts a new history.
+        assert_eq!(repaired.get(&key, 0).unwrap().3, 1);
+    }
 
-        // Attempt update with non-increasing version
-        assert!(tree.insert(&key1, 1, 2, 0).is_err());
-        assert_eq!(initial_version_key1, tree.version());
-        let (_, val, version, _) = tree.get(&key1, 0).unwrap();
-        assert_eq!(val, 1);
-        assert_eq!(version, 10);
+    #[test]
+    fn fixed_key_lookups_match_variable_keys() {
+        use crate::testing::check_key_impl;
+        use crate::FixedKey;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(16);
+        // Few leading bytes, so that the keys share long prefixes.
+        let mut random_key = || -> [u8; 16] {
+            let mut bytes = [0u8; 16];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = if i < 6 {
+                    rng.gen_range(0..3)
+                } else {
+                    rng.gen()
+                };
+            }
+            bytes
+        };
+        let keys: Vec<[u8; 16]> = (0..3000).map(|_| random_key()).collect();
+        let misses: Vec<[u8; 16]> = (0..1000).map(|_| random_key()).collect();
+
+        let sample: Vec<FixedKey<16>> = keys[..100].iter().copied().map(FixedKey::new).collect();
+        let report = check_key_impl(&sample);
+        assert!(report.is_ok(), "{}", report);
+
+        let mut fixed: Tree<FixedKey<16>, usize> = Tree::new();
+        let mut variable: Tree<VariableSizeKey, usize> = Tree::new();
+        for (i, key) in keys.iter().enumerate() {
+            fixed.insert(&FixedKey::new(*key), i, 0, 0).unwrap();
+            variable
+                .insert(&VariableSizeKey::from_slice(key), i, 0, 0)
+                .unwrap();
+        }
+        fixed.verify().unwrap();
+
+        for key in keys.iter().chain(&misses) {
+            let found = fixed.get(&FixedKey::new(*key), 0).map(|(_, v, _, _)| v);
+            let expected = variable
+                .get(&VariableSizeKey::from_slice(key), 0)
+                .map(|(_, v, _, _)| v);
+            assert_eq!(found, expected);
+        }
+        // Shorter keys never match, as no stored key is shorter.
+        assert_eq!(
+            fixed.get(&FixedKey::from(&keys[0][..8]), 0).map(|_| ()),
+            Err(TrieError::KeyNotFound)
+        );
 
-        // Insert another key
-        assert!(tree.insert(&key2, 1, 15, 0).is_ok());
-        let initial_version_key2 = tree.version();
+        let fixed_keys: Vec<Vec<u8>> = fixed.iter().map(|(k, _, _, _)| k).collect();
+        let variable_keys: Vec<Vec<u8>> = variable.iter().map(|(k, _, _, _)| k).collect();
+        assert_eq!(fixed_keys, variable_keys);
+    }
 
-        // Attempt update with non-increasing version for the second key
-        assert!(tree.insert(&key2, 1, 11, 0).is_err());
-        assert_eq!(initial_version_key2, tree.version());
-        let (_, val, version, _ts) = tree.get(&key2, 0).unwrap();
-        assert_eq!(val, 1);
-        assert_eq!(version, 15);
+    /// Builds a Trie over all two-byte keys with inner nodes forced to Node256,
+    /// then removes all but one key in a hundred, leaving wide sparse nodes.
+    fn sparse_node256_tree() -> (Tree<VariableSizeKey, usize>, Vec<VariableSizeKey>) {
+        let mut tree = Tree::with_forced_node_type(super::NodeKind::Node256);
+        let keys: Vec<VariableSizeKey> = (0..=255u8)
+            .flat_map(|a| (0..=255u8).map(move |b| VariableSizeKey::from_slice(&[a, b])))
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(key, i, 0, 0).unwrap();
+        }
+        let mut kept = Vec::new();
+        for (i, key) in keys.into_iter().enumerate() {
+            if i % 100 == 0 {
+                kept.push(key);
+            } else {
+                tree.remove(&key).unwrap();
+            }
+        }
+        (tree, kept)
+    }
 
-        // Check if the max version of the tree is the max of the two inserted versions
-        assert_eq!(tree.version(), 15);
+    #[test]
+    fn iterator_steps_per_entry_are_bounded_by_depth() {
+        let (tree, kept) = sparse_node256_tree();
+        let bound = 2 * tree.depth_stats().max_depth;
+
+        let mut iter = tree.iter();
+        let mut seen = 0;
+        while let Some((key, ..)) = iter.next() {
+            assert_eq!(key, kept[seen].as_slice());
+            assert!(
+                iter.work_counter() <= bound,
+                "{} steps",
+                iter.work_counter()
+            );
+            seen += 1;
+        }
+        assert!(iter.work_counter() <= bound);
+        assert_eq!(seen, kept.len());
     }
 
     #[test]
-    fn timed_insertion_update_equal_to_root_version() {
-        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+    fn range_steps_per_entry_are_bounded_across_gaps() {
+        use crate::iter::Range;
+        use std::ops::Bound;
+
+        // A range starting halfway is positioned without walking the keys
+        // before its start.
+        let (mut tree, kept) = sparse_node256_tree();
+        let bound = 2 * tree.depth_stats().max_depth + 1;
+        let mid = &kept[kept.len() / 2];
+        {
+            let mut range = Range::new(tree.root.as_ref(), mid.clone()..);
+            for key in &kept[kept.len() / 2..] {
+                assert_eq!(range.next().unwrap().0, key.as_slice());
+                assert!(
+                    range.work_counter() <= bound,
+                    "{} steps",
+                    range.work_counter()
+                );
+            }
+            assert!(range.next().is_none());
+        }
 
-        let key1 = VariableSizeKey::from_str("key_1").unwrap();
-        let key2 = VariableSizeKey::from_str("key_2").unwrap();
+        // Only the first and last keys are left on either side of a huge gap.
+        for key in &kept[1..kept.len() - 1] {
+            tree.remove(key).unwrap();
+        }
+        let first = kept[0].clone();
+        let last = kept[kept.len() - 1].clone();
+
+        // A range starting just after the first key skips the gap without
+        // visiting it.
+        let start = VariableSizeKey::from_slice(&[0, 1]);
+        let mut range = Range::new(tree.root.as_ref(), start.clone()..);
+        assert_eq!(range.next().unwrap().0, last.as_slice());
+        assert!(
+            range.work_counter() <= bound,
+            "{} steps",
+            range.work_counter()
+        );
+        assert!(range.next().is_none());
+
+        let after_first = (Bound::Excluded(first.clone()), Bound::Unbounded);
+        let mut range = Range::new(tree.root.as_ref(), after_first);
+        assert_eq!(range.next().unwrap().0, last.as_slice());
+        assert!(
+            range.work_counter() <= bound,
+            "{} steps",
+            range.work_counter()
+        );
 
-        // Initial insertion
-        assert!(tree.insert(&key1, 1, 10, 0).is_ok());
-        let initial_version = tree.version();
+        let mut range = Range::new(tree.root.as_ref(), first.clone()..=last.clone());
+        assert_eq!(range.next().unwrap().0, first.as_slice());
+        assert_eq!(range.next().unwrap().0, last.as_slice());
+        assert!(
+            range.work_counter() <= bound,
+            "{} steps",
+            range.work_counter()
+        );
+        assert!(range.next().is_none());
 
-        // Attempt update with version equal to root's version
-        assert!(tree.insert(&key2, 1, initial_version, 0).is_err());
+        let entries: Vec<Vec<u8>> = tree.range(start..last.clone()).map(|(k, ..)| k).collect();
+        assert!(entries.is_empty());
     }
 
     #[test]
-    fn timed_deletion_check_root_ts() {
-        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
-        let key1 = VariableSizeKey::from_str("key_1").unwrap();
-        let key2 = VariableSizeKey::from_str("key_2").unwrap();
+    fn next_with_budget_resumes_where_it_stopped() {
+        use std::task::Poll;
 
-        // Initial insertions
-        assert!(tree.insert(&key1, 1, 0, 0).is_ok());
-        assert!(tree.insert(&key2, 1, 0, 0).is_ok());
-        assert_eq!(tree.version(), 2);
+        let (tree, kept) = sparse_node256_tree();
+        let mut iter = tree.iter();
+        let mut keys = Vec::new();
+        let mut pending = 0;
+        loop {
+            match iter.next_with_budget(1) {
+                Poll::Pending => pending += 1,
+                Poll::Ready(Some((key, ..))) => {
+                    assert!(iter.work_counter() <= 1);
+                    keys.push(key);
+                }
+                Poll::Ready(None) => break,
+            }
+        }
+        assert!(pending > 0);
+        let expected: Vec<Vec<u8>> = kept.iter().map(|key| key.as_slice().to_vec()).collect();
+        assert_eq!(keys, expected);
+    }
 
-        // Deletions
-        assert!(tree.remove(&key1).unwrap());
-        assert!(tree.remove(&key2).unwrap());
-        assert_eq!(tree.version(), 0);
+    #[test]
+    fn transaction_commit_fails_on_conflicting_write() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        let a = VariableSizeKey::from_str("a").unwrap();
+        let b = VariableSizeKey::from_str("b").unwrap();
+        tree.insert(&a, 1, 0, 0).unwrap();
+        tree.insert(&b, 2, 0, 0).unwrap();
+
+        let mut txn = tree.transaction().unwrap();
+        assert_eq!(tree.snapshot_count(), 1);
+        let value = txn.get(&a).unwrap();
+        txn.insert(&b, value + 10, 1);
+        // The transaction reads its own writes.
+        assert_eq!(txn.get(&b).unwrap(), 11);
+
+        // A write to a key the transaction read makes the commit fail.
+        tree.insert(&a, 5, 0, 1).unwrap();
+        assert_eq!(
+            txn.commit(&mut tree),
+            Err(TrieError::TransactionConflict {
+                key: a.as_slice().to_vec()
+            })
+        );
+        assert_eq!(tree.get(&b, 0).unwrap().1, 2);
+        assert_eq!(tree.snapshot_count(), 0);
+
+        // So does a removal, or the insertion of a key read as absent.
+        let c = VariableSizeKey::from_str("c").unwrap();
+        let mut txn = tree.transaction().unwrap();
+        assert_eq!(txn.get(&c), Err(TrieError::KeyNotFound));
+        txn.remove(&a);
+        tree.insert(&c, 3, 0, 2).unwrap();
+        assert!(matches!(
+            txn.commit(&mut tree),
+            Err(TrieError::TransactionConflict { .. })
+        ));
+        assert_eq!(tree.get(&a, 0).unwrap().1, 5);
+    }
+
+    #[test]
+    fn transaction_commit_applies_writes_without_conflict() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        let a = VariableSizeKey::from_str("a").unwrap();
+        let b = VariableSizeKey::from_str("b").unwrap();
+        let c = VariableSizeKey::from_str("c").unwrap();
+        tree.insert(&a, 1, 0, 0).unwrap();
+        tree.insert(&b, 2, 0, 0).unwrap();
+
+        let mut txn = tree.transaction().unwrap();
+        let value = txn.get(&a).unwrap();
+        txn.insert(&c, value + 2, 1);
+        txn.remove(&b);
+        assert_eq!(txn.get(&b), Err(TrieError::KeyNotFound));
+
+        // Writes to keys the transaction did not read do not conflict.
+        tree.insert(&b, 20, 0, 1).unwrap();
+        txn.commit(&mut tree).unwrap();
+
+        assert_eq!(tree.get(&a, 0).unwrap().1, 1);
+        assert_eq!(tree.get(&b, 0), Err(TrieError::KeyNotFound));
+        assert_eq!(tree.get(&c, 0).unwrap().1, 3);
+        assert_eq!(tree.snapshot_count(), 0);
+
+        // A transaction dropped without committing leaves the Trie as it is.
+        let mut txn = tree.transaction().unwrap();
+        txn.insert(&a, 100, 2);
+        drop(txn);
+        assert_eq!(tree.get(&a, 0).unwrap().1, 1);
+        assert_eq!(tree.snapshot_count(), 0);
+    }
+
+    #[test]
+    fn transaction_commit_is_atomic() {
+        use crate::pressure::DuplicateTsPolicy;
+
+        let options = TreeOptions::default().with_duplicate_ts_policy(DuplicateTsPolicy::Reject);
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::with_options(options);
+        let a = VariableSizeKey::from_str("a").unwrap();
+        let b = VariableSizeKey::from_str("b").unwrap();
+        let c = VariableSizeKey::from_str("c").unwrap();
+        tree.insert(&b, 1, 0, 5).unwrap();
+        tree.insert(&c, 2, 0, 5).unwrap();
+        let version = tree.version();
+
+        // The write to b is rejected, so neither the write to a, sorted before
+        // it, nor the removal of c is applied.
+        let mut txn = tree.transaction().unwrap();
+        txn.insert(&a, 3, 7);
+        txn.insert(&b, 4, 5);
+        txn.remove(&c);
+        assert_eq!(txn.commit(&mut tree), Err(TrieError::DuplicateTimestamp));
+        assert_eq!(tree.get(&a, 0), Err(TrieError::KeyNotFound));
+        assert_eq!(tree.get(&b, 0).unwrap().1, 1);
+        assert_eq!(tree.get(&c, 0).unwrap().1, 2);
+        assert_eq!(tree.version(), version);
     }
 
-    fn from_be_bytes_key(k: &[u8]) -> u64 {
-        let padded_k = if k.len() < 8 {
-            let mut new_k = vec![0; 8];
-            new_k[8 - k.len()..].copy_from_slice(k);
-            new_k
-        } else {
-            k.to_vec()
+    #[test]
+    fn options_report_the_configuration() {
+        use crate::ingest::IngestPolicy;
+        use crate::pressure::{DuplicateTsPolicy, ReconfigureError, VersioningStrategy};
+
+        let tree: Tree<VariableSizeKey, i32> = Tree::new();
+        assert_eq!(tree.options(), TreeOptions::default());
+
+        let options = TreeOptions::default()
+            .track_prefix_stats(b'/')
+            .with_suffix_index()
+            .with_duplicate_ts_policy(DuplicateTsPolicy::Reject)
+            .with_version_warn_threshold(3)
+            .track_read_frequency(2)
+            .count_snapshot_reads()
+            .cow_batch_window(8)
+            .poison_on_corruption(true)
+            .max_valid_ts(1000)
+            .validate_ingest(IngestPolicy::repair());
+        let tree: Tree<VariableSizeKey, i32> = Tree::try_with_options(options).unwrap();
+        assert_eq!(tree.options(), options);
+
+        let conflicting = TreeOptions {
+            pressure_medium: 0.6,
+            pressure_high: 0.4,
+            ..TreeOptions::default()
         };
+        assert!(matches!(
+            Tree::<VariableSizeKey, i32>::try_with_options(conflicting),
+            Err(ReconfigureError::Conflict { .. })
+        ));
+        let invalid = TreeOptions::default().track_read_frequency(17);
+        assert!(matches!(
+            Tree::<VariableSizeKey, i32>::try_with_options(invalid),
+            Err(ReconfigureError::Invalid {
+                option: "read_frequency_depth",
+                ..
+            })
+        ));
+        // Building without validation clamps the depth instead
+        let clamped = Tree::<VariableSizeKey, i32>::with_options(invalid);
+        assert_eq!(clamped.options().read_frequency_depth, Some(16));
+        let unpaired = TreeOptions::default().count_snapshot_reads();
+        assert!(matches!(
+            unpaired.validate(),
+            Err(ReconfigureError::Conflict { .. })
+        ));
+        // and runs with valid options whatever it is given.
+        let invalid = TreeOptions {
+            pressure_window: 0,
+            pressure_medium: 0.9,
+            pressure_high: f64::NAN,
+            versioning: VersioningStrategy::KeepN(0),
+            ..unpaired
+        };
+        let clamped = Tree::<VariableSizeKey, i32>::with_options(invalid);
+        assert_eq!(clamped.options().validate(), Ok(()));
+        assert_eq!(clamped.options(), invalid.clamped());
+        assert_eq!(clamped.options().versioning, VersioningStrategy::KeepN(1));
+    }
 
-        let k_slice = &padded_k[..8];
-        u64::from_be_bytes(k_slice.try_into().unwrap())
+    #[test]
+    fn reconfigure_changes_runtime_options_only() {
+        use super::NodeKind;
+        use crate::ingest::IngestPolicy;
+        use crate::normalize::KeyNormalization;
+        use crate::pressure::{DuplicateTsPolicy, OptionsPatch, ReconfigureError};
+        use crate::strict::InvariantChecks;
+
+        let runtime = [
+            OptionsPatch::default().pressure_window(10),
+            OptionsPatch::default().pressure_thresholds(0.1, 0.9),
+            OptionsPatch::default().duplicate_ts_policy(DuplicateTsPolicy::Replace),
+            OptionsPatch::default().version_warn_threshold(Some(2)),
+            OptionsPatch::default().cow_batch_window(Some(4)),
+            OptionsPatch::default().invariant_checks(Some(InvariantChecks::all())),
+            OptionsPatch::default().max_valid_ts(Some(50)),
+            OptionsPatch::default().ingest_policy(Some(IngestPolicy::strict())),
+            OptionsPatch::default().max_active_snapshots(3),
+        ];
+        for patch in runtime {
+            let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+            tree.reconfigure(patch).unwrap();
+            assert_eq!(tree.options(), patch.apply_to(TreeOptions::default()));
+        }
+        let mut tree: Tree<VariableSizeKey, i32> =
+            Tree::with_options(TreeOptions::default().track_read_frequency(2));
+        let patch = OptionsPatch::default().count_snapshot_reads(true);
+        tree.reconfigure(patch).unwrap();
+        assert!(tree.options().count_snapshot_reads);
+
+        let fixed = [
+            (
+                OptionsPatch::default().prefix_stats_delimiter(Some(b'/')),
+                "prefix_stats_delimiter",
+            ),
+            (OptionsPatch::default().suffix_index(true), "suffix_index"),
+            (
+                OptionsPatch::default().read_frequency_depth(Some(4)),
+                "read_frequency_depth",
+            ),
+            (OptionsPatch::default().hash_index(true), "hash_index"),
+            (OptionsPatch::default().cached_counts(true), "cached_counts"),
+            (
+                OptionsPatch::default().forced_node_type(Some(NodeKind::Node256)),
+                "forced_node_type",
+            ),
+            (
+                OptionsPatch::default().key_normalization(Some(KeyNormalization::AsciiLowercase)),
+                "key_normalization",
+            ),
+        ];
+        for (patch, option) in fixed {
+            let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+            assert_eq!(
+                tree.reconfigure(patch.duplicate_ts_policy(DuplicateTsPolicy::Reject)),
+                Err(ReconfigureError::RequiresRebuild { option })
+            );
+            // Nothing changes when the patch is rejected.
+            assert_eq!(tree.options(), TreeOptions::default());
+        }
+
+        // Setting a fixed option to its current value is not a change.
+        let mut tree: Tree<VariableSizeKey, i32> =
+            Tree::with_options(TreeOptions::default().with_suffix_index());
+        tree.reconfigure(OptionsPatch::default().suffix_index(true))
+            .unwrap();
+
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        assert!(matches!(
+            tree.reconfigure(OptionsPatch::default().pressure_thresholds(0.8, 0.2)),
+            Err(ReconfigureError::Conflict { .. })
+        ));
+        assert_eq!(tree.options(), TreeOptions::default());
     }
 
     #[test]
-    fn iter_seq_u16() {
-        let mut tree = Tree::<FixedSizeKey<16>, u16>::new();
+    fn reconfigured_options_take_effect() {
+        use crate::pressure::{DuplicateTsPolicy, OptionsPatch};
 
-        // Insertion
-        for i in 0..u16::MAX {
-            let key: FixedSizeKey<16> = i.into();
-            tree.insert(&key, i, 0, i as u64);
+        let key = VariableSizeKey::from_str("key").unwrap();
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        for ts in 1..=3 {
+            tree.insert(&key, ts as i32, 0, ts).unwrap();
         }
 
-        // Iteration and verification
-        let mut len = 0usize;
-        let mut expected = 0u16;
+        // Keys already holding enough versions are reported once the
+        // threshold is set.
+        tree.reconfigure(OptionsPatch::default().version_warn_threshold(Some(3)))
+            .unwrap();
+        assert_eq!(tree.overloaded_keys().collect::<Vec<_>>(), vec![&key]);
+        tree.reconfigure(OptionsPatch::default().version_warn_threshold(None))
+            .unwrap();
+        assert_eq!(tree.overloaded_keys().count(), 0);
+
+        tree.insert(&key, 4, 0, 3).unwrap();
+        tree.reconfigure(OptionsPatch::default().duplicate_ts_policy(DuplicateTsPolicy::Reject))
+            .unwrap();
+        assert_eq!(
+            tree.insert(&key, 5, 0, 3),
+            Err(TrieError::DuplicateTimestamp)
+        );
 
-        let tree_iter = tree.iter();
-        for tree_entry in tree_iter {
-            let k = from_be_bytes_key(&tree_entry.0);
-            assert_eq!(expected as u64, k);
-            let ts = tree_entry.3;
-            assert_eq!(expected as u64, *ts);
-            expected = expected.wrapping_add(1);
-            len += 1;
+        tree.reconfigure(OptionsPatch::default().max_valid_ts(Some(10)))
+            .unwrap();
+        let late = KV::new(key.clone(), 6, 0, 11);
+        assert!(matches!(
+            tree.bulk_insert(&[late]),
+            Err(TrieError::InvalidTimestamp { .. })
+        ));
+        tree.reconfigure(OptionsPatch::default().max_valid_ts(None))
+            .unwrap();
+        tree.bulk_insert(&[KV::new(key.clone(), 6, 0, 11)]).unwrap();
+    }
+
+    #[test]
+    fn writes_intersect_detects_write_skew_and_phantoms() {
+        use crate::snapshot::ReadTrackGranularity;
+
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, bool> = Tree::new();
+        tree.insert(&key("oncall/alice"), true, 0, 0).unwrap();
+        tree.insert(&key("oncall/bob"), true, 0, 0).unwrap();
+        let on_call = (key("oncall/"), key("oncall0"));
+
+        // Two transactions each check that someone else is on call, then take
+        // themselves off call. Each one alone keeps a doctor on call.
+        let start = tree.version();
+        let mut first = tree.create_snapshot().unwrap();
+        let mut second = tree.create_snapshot().unwrap();
+        for snap in [&mut first, &mut second] {
+            snap.enable_read_tracking(ReadTrackGranularity::ExactKeys);
+            let range = snap
+                .range_guarded(on_call.0.clone()..on_call.1.clone())
+                .unwrap();
+            assert_eq!(range.filter(|(_, on, _, _)| **on).count(), 2);
         }
 
-        // Final assertion
-        assert_eq!(len, u16::MAX as usize);
+        let reads = first.read_set().unwrap();
+        assert_eq!(tree.writes_intersect(&reads, start), None);
+        tree.insert(&key("oncall/alice"), false, 0, 0).unwrap();
+
+        let reads = second.read_set().unwrap();
+        assert_eq!(
+            tree.writes_intersect(&reads, start),
+            Some(key("oncall/alice").as_slice().to_vec())
+        );
+
+        // A key inserted into a scanned range conflicts although it was never read.
+        let start = tree.version();
+        let mut scan = tree.create_snapshot().unwrap();
+        scan.enable_read_tracking(ReadTrackGranularity::ExactKeys);
+        let range = scan.range_guarded(on_call.0.clone()..on_call.1).unwrap();
+        assert_eq!(range.count(), 2);
+        let reads = scan.read_set().unwrap();
+        tree.insert(&key("other/dave"), true, 0, 0).unwrap();
+        assert_eq!(tree.writes_intersect(&reads, start), None);
+        tree.insert(&key("oncall/carol"), true, 0, 0).unwrap();
+        assert_eq!(
+            tree.writes_intersect(&reads, start),
+            Some(key("oncall/carol").as_slice().to_vec())
+        );
+
+        // Keys read by `get` and `contains` conflict when inserted or removed.
+        let start = tree.version();
+        let mut point = tree.create_snapshot().unwrap();
+        point.enable_read_tracking(ReadTrackGranularity::ExactKeys);
+        assert!(point.get(&key("oncall/erin")).is_err());
+        assert!(point.contains(&key("oncall/bob")).unwrap());
+        let reads = point.read_set().unwrap();
+        assert_eq!(reads.len(), 2);
+        tree.remove(&key("oncall/bob")).unwrap();
+        assert_eq!(
+            tree.writes_intersect(&reads, start),
+            Some(key("oncall/bob").as_slice().to_vec())
+        );
+        tree.insert(&key("oncall/bob"), true, 0, 0).unwrap();
+        tree.insert(&key("oncall/erin"), true, 0, 0).unwrap();
+        assert_eq!(
+            tree.writes_intersect(&reads, start),
+            Some(key("oncall/bob").as_slice().to_vec())
+        );
     }
 
     #[test]
-    fn iter_seq_u8() {
-        let mut tree: Tree<FixedSizeKey<32>, u8> = Tree::<FixedSizeKey<32>, u8>::new();
+    fn prefix_read_tracking_bounds_the_read_set() {
+        use crate::snapshot::ReadTrackGranularity;
+
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, usize> = Tree::new();
+        let n = 10_000;
+        for i in 0..n {
+            tree.insert(&key(&format!("{:02}/{i:05}", i % 16)), i, 0, 0)
+                .unwrap();
+        }
 
-        // Insertion
-        for i in 0..u8::MAX {
-            let key: FixedSizeKey<32> = i.into();
-            tree.insert(&key, i, 0, 0);
+        let start = tree.version();
+        let mut exact = tree.create_snapshot().unwrap();
+        let mut prefixed = tree.create_snapshot().unwrap();
+        exact.enable_read_tracking(ReadTrackGranularity::ExactKeys);
+        prefixed.enable_read_tracking(ReadTrackGranularity::PrefixAt(3));
+        for snap in [&exact, &prefixed] {
+            let range = snap.range_guarded(..).unwrap();
+            assert_eq!(range.count(), n);
         }
 
-        // Iteration and verification
-        let mut len = 0usize;
-        let mut expected = 0u8;
+        // Every key scanned is recorded exactly, along with the range.
+        assert_eq!(exact.read_set().unwrap().len(), n + 1);
+        let reads = prefixed.read_set().unwrap();
+        assert_eq!(reads.granularity(), ReadTrackGranularity::PrefixAt(3));
+        assert_eq!(reads.len(), 16 + 1);
+
+        assert_eq!(tree.writes_intersect(&reads, start), None);
+        tree.insert(&key("07/99999"), 0, 0, 0).unwrap();
+        assert_eq!(
+            tree.writes_intersect(&reads, start),
+            Some(key("07/99999").as_slice().to_vec())
+        );
+    }
 
-        let tree_iter = tree.iter();
-        for tree_entry in tree_iter {
-            let k = from_be_bytes_key(&tree_entry.0);
-            assert_eq!(expected as u64, k);
-            expected = expected.wrapping_add(1);
-            len += 1;
+    #[test]
+    fn iter_by_recency_orders_keys_by_latest_ts() {
+        let key = |i: u64| VariableSizeKey::from_str(&format!("key{:05}", i)).unwrap();
+        let mut tree: Tree<VariableSizeKey, u64> = Tree::new();
+        for i in 0..10000 {
+            tree.insert(&key(i), i, 0, 1000 + (i * 7919) % 3000)
+                .unwrap();
+        }
+        // Rewrites at older timestamps lower the bounds of their subtrees, and
+        // removals drop keys from them.
+        for i in (0..10000).step_by(3) {
+            tree.insert(&key(i), i, 0, (i * 31) % 1000).unwrap();
+        }
+        for i in (1..10000).step_by(5) {
+            tree.remove(&key(i)).unwrap();
         }
 
-        // Final assertion
-        assert_eq!(len, u8::MAX as usize);
+        let mut expected: Vec<(Vec<u8>, u64, u64)> = tree
+            .iter()
+            .map(|(key, value, _, ts)| (key, *value, *ts))
+            .collect();
+        expected.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        let by_recency = |entries: Vec<(Vec<u8>, &u64, &u64, &u64)>| {
+            entries
+                .into_iter()
+                .map(|(key, value, _, ts)| (key, *value, *ts))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(by_recency(tree.iter_by_recency().collect()), expected);
+        assert_eq!(by_recency(tree.recent(100)), expected[..100]);
+
+        // Readers order the keys of their snapshot, unaffected by later writes.
+        let mut snap = tree.create_snapshot().unwrap();
+        let reader = snap.new_reader().unwrap();
+        tree.insert(&key(20000), 0, 0, 9999).unwrap();
+        assert_eq!(by_recency(reader.recent(10)), expected[..10]);
+        assert_eq!(tree.recent(1)[0].0, key(20000).as_slice());
+        snap.close_reader(reader.id).unwrap();
+
+        // The first keys are found without visiting the whole tree.
+        let mut recency = tree.iter_by_recency();
+        assert_eq!(recency.by_ref().take(10).count(), 10);
+        assert!(recency.nodes_visited() < 1000);
+        recency.by_ref().for_each(drop);
+        assert!(recency.nodes_visited() > 8000);
     }
 
     #[test]
-    fn range_seq_u8() {
-        let mut tree: Tree<FixedSizeKey<8>, u8> = Tree::<FixedSizeKey<8>, u8>::new();
+    fn versioning_strategy_caps_the_versions_kept_per_key() {
+        use crate::frozen::FrozenTree;
+        use crate::pressure::{OptionsPatch, ReconfigureError, VersioningStrategy};
+
+        let key = |i: u64| VariableSizeKey::from_str(&format!("key{:03}", i)).unwrap();
+        let build = |versioning: VersioningStrategy| {
+            let options = TreeOptions::default().with_versioning(versioning);
+            let mut tree: Tree<VariableSizeKey, u64> = Tree::with_options(options);
+            for round in 0..50 {
+                for i in 0..100 {
+                    tree.insert(&key(i), round, 0, 1 + round).unwrap();
+                }
+            }
+            tree
+        };
+        let total_versions = |tree: &Tree<VariableSizeKey, u64>| {
+            let mut twigs = Vec::new();
+            Node::collect_twigs(tree.root.as_ref().unwrap(), &mut twigs);
+            twigs
+                .iter()
+                .map(|twig| match &twig.node_type {
+                    NodeType::Twig(twig) => twig.values.len(),
+                    _ => 0,
+                })
+                .sum::<usize>()
+        };
+        let versions_of =
+            |tree: &Tree<VariableSizeKey, u64>, k: &VariableSizeKey| match Node::find_twig(
+                tree.root.as_ref().unwrap(),
+                k,
+            )
+            .map(|n| &n.node_type)
+            {
+                Some(NodeType::Twig(twig)) => twig.values.len(),
+                _ => 0,
+            };
 
-        let max = u8::MAX;
-        // Insertion
-        for i in 0..=max {
-            let key: FixedSizeKey<8> = i.into();
-            tree.insert(&key, i, 0, 0);
-        }
+        // Overwrites under KeepLatest leave a single version behind every key.
+        let latest = build(VersioningStrategy::KeepLatest);
+        assert_eq!(total_versions(&latest), 100);
+        assert_eq!(versions_of(&latest, &key(7)), 1);
+        assert_eq!(latest.get(&key(7), 0).unwrap().1, 49);
+        let first_round_version = 7 + 1;
+        assert!(latest.get(&key(7), first_round_version).is_err());
+
+        // KeepAll retains the whole history, readable at any older version.
+        let all = build(VersioningStrategy::KeepAll);
+        assert_eq!(total_versions(&all), 100 * 50);
+        assert_eq!(versions_of(&all, &key(7)), 50);
+        assert_eq!(all.get(&key(7), first_round_version).unwrap().1, 0);
+
+        let last_three = build(VersioningStrategy::KeepN(3));
+        assert_eq!(total_versions(&last_three), 100 * 3);
+        let kept: Vec<u64> = match Node::find_twig(last_three.root.as_ref().unwrap(), &key(7))
+            .map(|n| &n.node_type)
+        {
+            Some(NodeType::Twig(twig)) => twig.iter().map(|leaf| leaf.value).collect(),
+            _ => Vec::new(),
+        };
+        assert_eq!(kept, [47, 48, 49]);
 
-        // Test inclusive range
-        let start_key: FixedSizeKey<8> = 5u8.into();
-        let end_key: FixedSizeKey<8> = max.into();
-        let mut len = 0usize;
-        for _ in tree.range(start_key..=end_key) {
-            len += 1;
-        }
-        assert_eq!(len, max as usize - 4);
+        // Snapshots taken before an overwrite keep what it dropped.
+        let mut tree: Tree<VariableSizeKey, u64> = Tree::with_options(
+            TreeOptions::default().with_versioning(VersioningStrategy::KeepLatest),
+        );
+        tree.insert(&key(1), 1, 0, 1).unwrap();
+        let snap = tree.create_snapshot().unwrap();
+        tree.insert(&key(1), 2, 0, 2).unwrap();
+        assert_eq!(snap.get(&key(1)).unwrap().0, 1);
+        assert_eq!(tree.get(&key(1), 0).unwrap().1, 2);
+
+        // The strategy changes at runtime, persists, and rejects keeping nothing.
+        tree.reconfigure(OptionsPatch::default().versioning(VersioningStrategy::KeepN(2)))
+            .unwrap();
+        assert_eq!(tree.options().versioning, VersioningStrategy::KeepN(2));
+        let frozen = tree.freeze();
+        let thawed: Tree<VariableSizeKey, u64> =
+            Tree::thaw(&FrozenTree::open(&frozen).unwrap()).unwrap();
+        assert_eq!(thawed.options().versioning, VersioningStrategy::KeepN(2));
+        assert!(matches!(
+            tree.reconfigure(OptionsPatch::default().versioning(VersioningStrategy::KeepN(0))),
+            Err(ReconfigureError::Invalid {
+                option: "versioning",
+                ..
+            })
+        ));
+    }
 
-        // Test exclusive range
-        let start_key: FixedSizeKey<8> = 5u8.into();
-        let end_key: FixedSizeKey<8> = max.into();
-        let mut len = 0usize;
-        for _ in tree.range(start_key..end_key) {
-            len += 1;
+    #[test]
+    fn shrunk_nodes_grow_before_adding_a_child() {
+        // Removing children shrinks a node to the smallest type that holds the
+        // rest, which leaves it full: the next insert has to grow it first.
+        let key = |b: u8| VariableSizeKey::from_slice(&[b'k', b]);
+        for width in [2u8, 5, 17, 49] {
+            let mut tree: Tree<VariableSizeKey, u8> = Tree::new();
+            for b in 0..width {
+                tree.insert(&key(b), b, 0, 0).unwrap();
+            }
+            for b in 1..width {
+                assert!(tree.remove(&key(b)).unwrap());
+            }
+            for b in 100..100 + width {
+                tree.insert(&key(b), b, 0, 0).unwrap();
+            }
+            for b in (0..1).chain(100..100 + width) {
+                assert_eq!(tree.get(&key(b), 0).unwrap().1, b);
+            }
+            assert_eq!(tree.iter().count(), 1 + width as usize);
+            tree.verify().unwrap();
         }
-        assert_eq!(len, max as usize - 5);
+    }
 
-        // Test range with different start and end keys
-        let start_key: FixedSizeKey<8> = 3u8.into();
-        let end_key: FixedSizeKey<8> = 7u8.into();
-        let mut len = 0usize;
-        for _ in tree.range(start_key..=end_key) {
-            len += 1;
+    #[test]
+    fn hash_index_does_not_share_twigs() {
+        let mut tree = Tree::<VariableSizeKey, i32>::with_hash_index(RandomState::new());
+        for i in 0..100 {
+            let key = VariableSizeKey::from_str(&format!("key{i}")).unwrap();
+            tree.insert(&key, i, 0, 0).unwrap();
         }
-        assert_eq!(len, 5);
 
-        // Test range with all keys
-        let start_key: FixedSizeKey<8> = 0u8.into();
-        let end_key: FixedSizeKey<8> = max.into();
-        let mut len = 0usize;
-        for _ in tree.range(start_key..=end_key) {
-            len += 1;
+        // The index holds weak references, so twigs are owned by the tree alone
+        let mut twigs = Vec::new();
+        Node::collect_twigs(tree.root.as_ref().unwrap(), &mut twigs);
+        assert_eq!(twigs.len(), 100);
+        for twig in &twigs {
+            assert_eq!(Arc::strong_count(twig), 2);
         }
-        assert_eq!(len, 256);
+        drop(twigs);
+
+        let key = VariableSizeKey::from_str("key7").unwrap();
+        assert!(!tree.path_of(&key).unwrap().iter().any(|seg| seg.shared));
+        assert_eq!(tree.get(&key, 0).unwrap().1, 7);
+        tree.verify().unwrap();
     }
 
     #[test]
-    fn range_seq_u16() {
-        let mut tree: Tree<FixedSizeKey<16>, u16> = Tree::<FixedSizeKey<16>, u16>::new();
+    fn update_on_an_empty_or_closed_tree() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        let key = VariableSizeKey::from_str("counter").unwrap();
 
-        let max = u16::MAX;
-        // Insertion
-        for i in 0..=max {
-            let key: FixedSizeKey<16> = i.into();
-            tree.insert(&key, i, 0, 0);
-        }
+        // An empty Trie has no key to update, and the closure is not called
+        assert!(!tree.update(&key, 1, |_| unreachable!()).unwrap());
+        assert!(tree.get(&key, 0).is_err());
+        assert_eq!(tree.version(), 0);
 
-        let mut len = 0usize;
-        let start_key: FixedSizeKey<16> = 0u8.into();
-        let end_key: FixedSizeKey<16> = max.into();
+        tree.insert(&key, 1, 0, 0).unwrap();
+        assert!(tree.close().is_ok());
+        assert!(matches!(
+            tree.update(&key, 2, |v| *v += 1),
+            Err(TrieError::TreeAlreadyClosed)
+        ));
+    }
 
-        for _ in tree.range(start_key..=end_key) {
-            len += 1;
-        }
-        assert_eq!(len, max as usize + 1);
+    #[test]
+    fn removing_the_last_child_drops_the_inner_node() {
+        // A single-child node, as older removals left behind, is left without
+        // children by removing that child, and must be dropped rather than kept
+        // empty.
+        let key = VariableSizeKey::from_str("key").unwrap();
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        tree.insert(&key, 1, 0, 0).unwrap();
+        let twig = tree.root.take().unwrap();
+        let wrapper = Node::new_inner(VariableSizeKey::from_slice(&[]), None)
+            .add_child(b'k', twig.as_ref().clone_node());
+        tree.root = Some(Arc::new(wrapper));
+
+        assert!(tree.remove(&key).unwrap());
+        assert!(tree.root.is_none());
+        assert_eq!(tree.iter().count(), 0);
+        tree.verify().unwrap();
+
+        tree.insert(&key, 2, 0, 0).unwrap();
+        assert_eq!(tree.get(&key, 0).unwrap().1, 2);
+        tree.verify().unwrap();
     }
 
     #[test]
-    fn same_key_with_versions() {
-        let mut tree = Tree::<VariableSizeKey, i32>::new();
+    fn indexed_gets_resolve_like_trie_gets() {
+        let mut tree = Tree::<VariableSizeKey, i32>::with_hash_index(RandomState::new());
+        let key = VariableSizeKey::from_str("logs/1").unwrap();
+        tree.insert(&key, 1, 0, 5).unwrap();
+        tree.insert(&key, 2, 0, 6).unwrap();
+
+        // Point the index at a copy of the twig that is dropped right away
+        let twig = Node::find_twig(tree.root.as_ref().unwrap(), &key).unwrap();
+        let copy = Arc::new(twig.as_ref().clone_node());
+        tree.hash_index.as_mut().unwrap().insert(&copy);
+        drop(copy);
+
+        // A stale entry falls back to the trie, and the read is resolved the same
+        assert_eq!(tree.get(&key, 0).unwrap().1, 2);
+        assert_eq!(tree.get(&key, 1).unwrap().1, 1);
+        assert!(tree
+            .get(&VariableSizeKey::from_str("logs/2").unwrap(), 0)
+            .is_err());
 
-        // Insertions
-        let key1 = VariableSizeKey::from_str("abc").unwrap();
-        let key2 = VariableSizeKey::from_str("efg").unwrap();
-        tree.insert(&key1, 1, 0, 0);
-        tree.insert(&key1, 2, 10, 0);
-        tree.insert(&key2, 3, 11, 0);
+        // Expiry applies to the fallback as well
+        tree.expire_prefix_at(b"logs/", 10).unwrap();
+        tree.advance_ts(10);
+        assert!(matches!(tree.get(&key, 0), Err(TrieError::KeyNotFound)));
+    }
 
-        // Versioned retrievals and assertions
-        let (_, val, _, _) = tree.get(&key1, 1).unwrap();
-        assert_eq!(val, 1);
-        let (_, val, _, _) = tree.get(&key1, 10).unwrap();
-        assert_eq!(val, 2);
-        let (_, val, _, _) = tree.get(&key2, 11).unwrap();
-        assert_eq!(val, 3);
+    #[test]
+    fn remove_returns_false_for_absent_keys() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        assert!(!tree.remove(&key("a")).unwrap());
+
+        // A root twig holding another key
+        tree.insert(&key("a"), 1, 0, 0).unwrap();
+        assert!(!tree.remove(&key("b")).unwrap());
+
+        // A key missing below an inner node
+        tree.insert(&key("b"), 2, 0, 0).unwrap();
+        let version = tree.version();
+        assert!(!tree.remove(&key("c")).unwrap());
+        assert_eq!(tree.version(), version);
+
+        assert!(tree.remove(&key("a")).unwrap());
+        assert!(!tree.remove(&key("a")).unwrap());
+        assert_eq!(tree.iter().count(), 1);
+    }
 
-        // Iteration and verification
-        let mut len = 0;
-        let tree_iter = tree.iter();
-        for _ in tree_iter {
-            len += 1;
+    #[test]
+    fn expired_keys_are_hidden_from_every_read() {
+        use crate::codec::EncodeKey;
+
+        let mut tree: Tree<VariableSizeKey, u64> = Tree::new();
+        let keys = [(1u64, "a"), (1, "b"), (2, "a")];
+        for (i, (id, name)) in keys.iter().enumerate() {
+            let key = (*id, name.to_string()).encode_key();
+            tree.insert(&key, i as u64, 0, 5).unwrap();
         }
-        assert_eq!(len, 2);
+        let mut expired = Vec::new();
+        (1u64,).encode_prefix(&mut expired);
+        tree.expire_prefix_at(&expired, 10).unwrap();
+        let snap = tree.create_snapshot().unwrap();
+        tree.advance_ts(10);
+
+        let live = (2u64, "a".to_string()).encode_key();
+        let gone = (1u64, "a".to_string()).encode_key();
+        let mut buffer = ScanBuffer::new();
+        let mut scan = tree.scan_prefix_with(&[], &mut buffer);
+        assert_eq!(scan.next_entry().unwrap().0, live.as_slice());
+        assert!(scan.next_entry().is_none());
+        drop(scan);
+        assert!(tree
+            .scan_prefix_with(&expired, &mut buffer)
+            .next_entry()
+            .is_none());
+        assert_eq!(tree.first_key(), Some(live.clone()));
+        assert_eq!(tree.last_key(), Some(live.clone()));
+        assert!(matches!(tree.history(&gone), Err(TrieError::KeyNotFound)));
+        assert_eq!(tree.count_prefix(&expired), 0);
+        assert_eq!(tree.count_prefix(&[]), 1);
+        assert_eq!(tree.entry_count_by_prefix(0).values().sum::<usize>(), 1);
+        assert_eq!(tree.iter_decoded::<(u64, String)>().count(), 1);
+        assert_eq!(
+            tree.scan_prefix_decoded::<(u64, String)>(&(1u64,)).count(),
+            0
+        );
+        assert_eq!(
+            tree.scan_with_filter(|_| crate::iter::ScanDecision::Yield)
+                .count(),
+            1
+        );
+        assert_eq!(tree.scan(crate::iter::ScanOptions::new()).count(), 1);
+        assert_eq!(tree.longest_prefix_match(gone.as_slice()), None);
+        assert_eq!(tree.scan_suffix(b"").count(), 1);
+        assert_eq!(tree.export_owned_snapshot().unwrap().len(), 1);
+
+        let view = tree.sample_view(1.0, 0);
+        assert!(matches!(view.get(&gone), Err(TrieError::KeyNotFound)));
+        assert_eq!(view.get(&live).unwrap().0, 2);
+        assert_eq!(view.iter().count(), 1);
+
+        // Snapshots keep the expired keys, except when read as of a later time.
+        assert_eq!(snap.get(&gone).unwrap().0, 0);
+        assert_eq!(snap.iter_as_of(9).unwrap().count(), 3);
+        assert_eq!(snap.iter_as_of(10).unwrap().count(), 1);
     }
 
     #[test]
-    fn bulk_insert() {
-        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
-        let curr_version = tree.version();
-        // Create a vector of KV<P, V>
-        let kv_pairs = vec![
-            KV {
-                key: VariableSizeKey::from_str("key_1").unwrap(),
-                value: 1,
-                version: 0,
-                ts: 0,
-            },
-            KV {
-                key: VariableSizeKey::from_str("key_2").unwrap(),
-                value: 1,
-                version: 2,
-                ts: 0,
-            },
-            KV {
-                key: VariableSizeKey::from_str("key_3").unwrap(),
-                value: 1,
-                version: curr_version + 1,
-                ts: 0,
-            },
-            KV {
-                key: VariableSizeKey::from_str("key_4").unwrap(),
-                value: 1,
-                version: curr_version + 1,
-                ts: 0,
-            },
-            KV {
-                key: VariableSizeKey::from_str("key_5").unwrap(),
-                value: 1,
-                version: curr_version + 2,
-                ts: 0,
-            },
-            KV {
-                key: VariableSizeKey::from_str("key_6").unwrap(),
-                value: 1,
-                version: 0,
-                ts: 0,
-            },
+    fn fixed_key_writes_reject_other_lengths() {
+        use crate::FixedKey;
+
+        let short = FixedKey::<4>::from(&[1u8, 2][..]);
+        let full = FixedKey::new([1u8, 2, 3, 4]);
+        let invalid = Err(TrieError::InvalidKeyLength {
+            expected: 4,
+            found: 2,
+        });
+        let mut tree: Tree<FixedKey<4>, i32> = Tree::new();
+        assert_eq!(tree.insert(&short, 1, 0, 0).map(|_| ()), invalid);
+        tree.insert(&full, 2, 0, 0).unwrap();
+        assert_eq!(tree.insert(&short, 1, 0, 0).map(|_| ()), invalid);
+        assert_eq!(
+            tree.bulk_insert(&[KV::new(full, 3, 0, 0), KV::new(short, 4, 0, 0)]),
+            invalid
+        );
+        let mut snap = tree.create_snapshot().unwrap();
+        assert_eq!(snap.insert(&short, 5, 0), invalid);
+        assert!(snap.close().is_ok());
+
+        // Nothing but the full key was written.
+        assert_eq!(tree.iter().count(), 1);
+        assert_eq!(tree.get(&full, 0).unwrap().1, 2);
+        tree.verify().unwrap();
+    }
+
+    #[test]
+    fn every_snapshot_read_is_tracked() {
+        use crate::iter::{PatternByte, ScanDecision, ScanOptions};
+        use crate::snapshot::{ReadTrackGranularity, Snapshot};
+
+        type Read = fn(&mut Snapshot<VariableSizeKey, u64>);
+        let read_key = VariableSizeKey::from_str("k/1").unwrap();
+        let reads: [(&str, Read); 14] = [
+            ("scan_filtered", |snap| {
+                let _ = snap.scan_filtered(|_| ScanDecision::Yield).unwrap();
+            }),
+            ("scan", |snap| {
+                let _ = snap.scan(ScanOptions::new()).unwrap();
+            }),
+            ("glob_iter", |snap| {
+                let _ = snap.glob_iter(&PatternByte::parse_glob(b"k*")).unwrap();
+            }),
+            ("keys", |snap| {
+                let _ = snap.keys().unwrap();
+            }),
+            ("iter_as_of", |snap| {
+                let _ = snap.iter_as_of(0).unwrap();
+            }),
+            ("count", |snap| {
+                snap.count().unwrap();
+            }),
+            ("sum_prefix", |snap| {
+                snap.sum_prefix(&VariableSizeKey::from_slice(b"k/"))
+                    .unwrap();
+            }),
+            ("distinct_next_bytes", |snap| {
+                snap.distinct_next_bytes(&VariableSizeKey::from_slice(b"k/"))
+                    .unwrap();
+            }),
+            ("find_within_distance", |snap| {
+                let key = VariableSizeKey::from_str("k/0").unwrap();
+                snap.find_within_distance(&key, 1).unwrap();
+            }),
+            ("group_counts", |snap| {
+                snap.group_counts(2).unwrap();
+            }),
+            ("range_page", |snap| {
+                let start = VariableSizeKey::from_str("k/").unwrap();
+                let end = VariableSizeKey::from_str("k0").unwrap();
+                snap.range_page(&start, &end, None, 10).unwrap();
+            }),
+            ("range_guarded", |snap| {
+                let _ = snap.range_guarded(..).unwrap();
+            }),
+            ("new_reader", |snap| {
+                snap.new_reader().unwrap();
+            }),
+            ("new_prefix_reader", |snap| {
+                snap.new_prefix_reader(b"k/").unwrap();
+            }),
         ];
 
-        assert!(tree.bulk_insert(&kv_pairs).is_ok());
-        assert!(tree.version() == curr_version + 2);
+        for (name, read) in reads {
+            let build = || {
+                let mut tree: Tree<VariableSizeKey, u64> = Tree::new();
+                for i in 0..4u64 {
+                    let key = VariableSizeKey::from_str(&format!("k/{i}")).unwrap();
+                    tree.insert(&key, i, 0, 0).unwrap();
+                }
+                tree
+            };
+            let mut tree = build();
+            let start = tree.version();
+            let mut snap = tree.create_snapshot().unwrap();
+            snap.enable_read_tracking(ReadTrackGranularity::ExactKeys);
+            read(&mut snap);
+            let reads = snap.read_set().unwrap();
+            assert!(!reads.is_empty(), "{name}");
+            assert!(reads.covers(read_key.as_slice()), "{name}");
+
+            // A key inserted where the read reached conflicts with it, and so
+            // does a key removed from there.
+            let key = VariableSizeKey::from_str("k/9").unwrap();
+            tree.insert(&key, 9, 0, 0).unwrap();
+            assert!(tree.writes_intersect(&reads, start).is_some(), "{name}");
+            let mut tree = build();
+            let key = VariableSizeKey::from_str("k/2").unwrap();
+            tree.remove(&key).unwrap();
+            assert!(tree.writes_intersect(&reads, start).is_some(), "{name}");
+        }
+    }
 
-        for kv in kv_pairs {
-            let (_, val, version, _) = tree.get(&kv.key, 0).unwrap();
-            assert_eq!(val, kv.value);
-            if kv.version == 0 {
-                assert_eq!(version, curr_version + 1);
-            } else {
-                assert_eq!(version, kv.version);
-            }
+    #[test]
+    fn prefix_read_tracking_detects_removals() {
+        use crate::snapshot::ReadTrackGranularity;
+
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, usize> = Tree::new();
+        for i in 0..64 {
+            tree.insert(&key(&format!("{:02}/{i:02}", i % 4)), i, 0, 0)
+                .unwrap();
         }
-        assert!(tree
-            .insert(&VariableSizeKey::from_str("key_7").unwrap(), 1, 0, 0)
-            .is_ok());
-        assert!(tree.version() == curr_version + 3);
+
+        let start = tree.version();
+        let mut snap = tree.create_snapshot().unwrap();
+        snap.enable_read_tracking(ReadTrackGranularity::PrefixAt(3));
+        assert!(snap.contains(&key("01/05")).unwrap());
+        let reads = snap.read_set().unwrap();
+        assert_eq!(reads.len(), 1);
+
+        // Removing a key under another prefix does not conflict, while removing
+        // any key under the prefix read does, as the read stands for all of them.
+        tree.remove(&key("02/06")).unwrap();
+        assert_eq!(tree.writes_intersect(&reads, start), None);
+        tree.remove(&key("01/09")).unwrap();
+        assert_eq!(tree.writes_intersect(&reads, start), Some(b"01/".to_vec()));
+    }
+
+    #[test]
+    fn splice_subtree_changes_nothing_on_error() {
+        use crate::pressure::DuplicateTsPolicy;
+
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let options = TreeOptions::default().with_duplicate_ts_policy(DuplicateTsPolicy::Reject);
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::with_options(options);
+        tree.insert(&key("us/a"), 1, 0, 1).unwrap();
+        tree.insert(&key("us/b"), 2, 0, 2).unwrap();
+
+        // The other Trie stacks two versions of `us/b` at one timestamp, which
+        // the Trie refuses after `us/a` would have been written.
+        let mut other: Tree<VariableSizeKey, i32> = Tree::new();
+        other.insert(&key("us/a"), 10, 0, 10).unwrap();
+        other.insert(&key("us/b"), 20, 0, 20).unwrap();
+        other.insert(&key("us/b"), 21, 0, 20).unwrap();
+
+        let version = tree.version();
+        assert_eq!(
+            tree.splice_subtree(&other, b"us/"),
+            Err(TrieError::DuplicateTimestamp)
+        );
+        assert_eq!(tree.version(), version);
+        let entries: Vec<_> = tree.iter().map(|(_, value, ..)| *value).collect();
+        assert_eq!(entries, vec![1, 2]);
+        assert_eq!(tree.history(&key("us/a")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn versioning_strategy_keeps_pinned_versions() {
+        use crate::pressure::VersioningStrategy;
+
+        let key = VariableSizeKey::from_str("key").unwrap();
+        let options = TreeOptions::default().with_versioning(VersioningStrategy::KeepN(2));
+        let mut tree: Tree<VariableSizeKey, u64> = Tree::with_options(options);
+        tree.insert(&key, 1, 0, 1).unwrap();
+        let v1 = tree.version();
+        let pin = tree.pin_version(&key, v1).unwrap();
+
+        // The pinned version is kept on top of the two latest ones, by single
+        // writes and bulk writes alike.
+        tree.insert(&key, 2, 0, 2).unwrap();
+        tree.insert(&key, 3, 0, 3).unwrap();
+        tree.bulk_insert(&[KV::new(key.clone(), 4, 0, 4)]).unwrap();
+        assert_eq!(tree.get(&key, v1).unwrap().1, 1);
+        let values: Vec<u64> = tree
+            .history(&key)
+            .unwrap()
+            .into_iter()
+            .map(|(value, ..)| value)
+            .collect();
+        assert_eq!(values, [1, 3, 4]);
+
+        // Once unpinned, the next write trims the key back to the limit.
+        drop(pin);
+        tree.insert(&key, 5, 0, 5).unwrap();
+        assert_eq!(tree.get(&key, v1), Err(TrieError::KeyNotFound));
+        assert_eq!(tree.history(&key).unwrap().len(), 2);
     }
 }
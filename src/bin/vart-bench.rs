@@ -0,0 +1,127 @@
+//! Prints a summary table of the main Tree operations over the reproducible
+//! datasets in `vart::testing::datasets`.
+//!
+//! Usage: `cargo run --release --features bench --bin vart-bench [keys]`
+//!
+//! The numbers are single runs without warm-up or statistics, meant for quick
+//! comparisons between builds on the same machine. Use the criterion suite in
+//! `benches/suite.rs` for careful measurements.
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use vart::art::Tree;
+use vart::iter::ScanBuffer;
+use vart::testing::datasets::{Dataset, SplitMix64};
+use vart::{Key, VariableSizeKey};
+
+const SEED: u64 = 0x005E_ED0F_7A27;
+const OPS: usize = 100_000;
+const RANGE_LEN: usize = 100;
+
+// Times `f`, which performs `ops` operations, and returns nanoseconds per operation.
+fn per_op(ops: usize, f: impl FnOnce()) -> f64 {
+    let start = Instant::now();
+    f();
+    let elapsed: Duration = start.elapsed();
+    elapsed.as_nanos() as f64 / ops.max(1) as f64
+}
+
+fn sample(keys: &[VariableSizeKey], n: usize, rng: &mut SplitMix64) -> Vec<VariableSizeKey> {
+    (0..n)
+        .map(|_| keys[rng.below(keys.len() as u64) as usize].clone())
+        .collect()
+}
+
+fn run(dataset: Dataset, size: usize) -> [f64; 7] {
+    let keys = dataset.keys(size, SEED);
+    let mut rng = SplitMix64::new(SEED);
+    let hits = sample(&keys, OPS, &mut rng);
+    let misses = dataset.missing_keys(OPS, SEED);
+    let mut sorted = keys.clone();
+    sorted.sort();
+    let ranges: Vec<_> = (0..OPS / RANGE_LEN)
+        .map(|_| {
+            let start = rng.below(size.saturating_sub(RANGE_LEN).max(1) as u64) as usize;
+            let end = (start + RANGE_LEN).min(size - 1);
+            (sorted[start].clone(), sorted[end].clone())
+        })
+        .collect();
+    let prefix_len = dataset.common_prefix().len() + 2;
+    let prefixes: Vec<Vec<u8>> = sample(&keys, OPS / RANGE_LEN, &mut rng)
+        .iter()
+        .map(|key| key.as_slice()[..prefix_len.min(key.len())].to_vec())
+        .collect();
+
+    let mut tree = Tree::new();
+    let insert = per_op(size, || {
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(key, i as u64, 0, 0).unwrap();
+        }
+    });
+    tree.set_max_active_snapshots(u64::MAX);
+
+    let get_hit = per_op(hits.len(), || {
+        for key in &hits {
+            black_box(tree.get(key, 0).unwrap());
+        }
+    });
+    let get_miss = per_op(misses.len(), || {
+        for key in &misses {
+            black_box(tree.get(key, 0).is_err());
+        }
+    });
+    let range = per_op(ranges.len(), || {
+        for (start, end) in &ranges {
+            black_box(tree.range(start..end).count());
+        }
+    });
+    let mut buffer = ScanBuffer::new();
+    let prefix = per_op(prefixes.len(), || {
+        for prefix in &prefixes {
+            let mut scan = tree.scan_prefix_with(prefix, &mut buffer);
+            while black_box(scan.next_entry()).is_some() {}
+        }
+    });
+    let snapshots = OPS / RANGE_LEN;
+    let snapshot = per_op(snapshots, || {
+        for chunk in hits.chunks(RANGE_LEN).take(snapshots) {
+            let snapshot = tree.create_snapshot().unwrap();
+            for key in chunk {
+                black_box(snapshot.get(key).unwrap());
+            }
+        }
+    });
+    let iter = per_op(size, || {
+        black_box(tree.iter().count());
+    });
+
+    [insert, get_hit, get_miss, range, prefix, snapshot, iter]
+}
+
+fn main() {
+    let size = match std::env::args().nth(1) {
+        Some(arg) => arg.parse().expect("the number of keys must be an integer"),
+        None => 100_000,
+    };
+    assert!(size > 1, "at least two keys are needed");
+
+    println!("vart-bench: {} keys per dataset, ns per operation", size);
+    println!(
+        "{:<14} {:>10} {:>10} {:>10} {:>12} {:>12} {:>12} {:>10}",
+        "dataset", "insert", "get hit", "get miss", "range/100", "prefix", "snap+100get", "iter"
+    );
+    for dataset in Dataset::ALL {
+        let [insert, hit, miss, range, prefix, snapshot, iter] = run(dataset, size);
+        println!(
+            "{:<14} {:>10.1} {:>10.1} {:>10.1} {:>12.1} {:>12.1} {:>12.1} {:>10.1}",
+            dataset.name(),
+            insert,
+            hit,
+            miss,
+            range,
+            prefix,
+            snapshot,
+            iter
+        );
+    }
+}
@@ -0,0 +1,416 @@
+//! This module defines checksummed values, which let a Tree detect values that
+//! were corrupted in memory, such as by a bit flip, instead of returning them.
+use crate::art::Tree;
+use crate::codec::{DecodeError, ValueCodec};
+use crate::iter::ChangedSince;
+use crate::{KeyTrait, TrieError};
+
+// The length of the encoded checksum, which precedes the encoded value.
+const CHECKSUM_LEN: usize = 4;
+
+/// Returns the 32-bit FNV-1a hash of `bytes`.
+///
+/// Every step of the hash is a bijection of its state, so any change to a
+/// single byte, including a flipped bit, changes the hash.
+pub(crate) fn checksum(bytes: &[u8]) -> u32 {
+    const OFFSET: u32 = 0x811c_9dc5;
+    const PRIME: u32 = 0x0100_0193;
+    bytes.iter().fold(OFFSET, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(PRIME)
+    })
+}
+
+/// The offset basis of the 64-bit FNV-1a hash, which `fnv1a_64` hashes start
+/// from.
+pub(crate) const FNV64_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Feeds `bytes` into the 64-bit FNV-1a hash `hash`, and returns the new hash.
+///
+/// Unlike the hashers of the standard library, which are seeded at random, the
+/// hash of the same bytes is the same in every process.
+pub(crate) fn fnv1a_64(hash: u64, bytes: &[u8]) -> u64 {
+    const PRIME: u64 = 0x0100_0000_01b3;
+    bytes
+        .iter()
+        .fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(PRIME))
+}
+
+/// Checks the value of an entry read from a Trie with `check`, the value check
+/// of the Trie, if it has one.
+///
+/// Returns `TrieError::ChecksumMismatch` with the key and the timestamp of the
+/// value in place of a value that does not match its checksum.
+pub(crate) fn check_value<P: KeyTrait, V>(
+    check: Option<fn(&V) -> bool>,
+    entry: (P, V, u64, u64),
+) -> Result<(P, V, u64, u64), TrieError> {
+    match check.is_none_or(|check| check(&entry.1)) {
+        true => Ok(entry),
+        false => Err(TrieError::ChecksumMismatch {
+            key: entry.0.as_slice().to_vec(),
+            ts: entry.3,
+        }),
+    }
+}
+
+/// A value stored together with a checksum of its bytes, taken when it was
+/// written.
+///
+/// A Tree whose values are `Checksummed<V>` keeps a checksum with every version
+/// of every key, written by `insert_checksummed` and verified by
+/// `get_verified`, `iter_verified` and `scrub`. Trees of other value types carry
+/// no checksums. The checksum is encoded along with the value, so frozen copies
+/// and changelogs hold the checksums taken at write time, and values corrupted
+/// on the way are detected once loaded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checksummed<V> {
+    value: V,
+    checksum: u32,
+}
+
+impl<V: AsRef<[u8]>> Checksummed<V> {
+    /// Wraps a value with the checksum of its bytes.
+    pub fn new(value: V) -> Self {
+        let checksum = checksum(value.as_ref());
+        Checksummed { value, checksum }
+    }
+
+    /// Returns whether the value still matches its checksum.
+    pub fn is_intact(&self) -> bool {
+        checksum(self.value.as_ref()) == self.checksum
+    }
+
+    /// Returns the value if it matches its checksum.
+    pub fn verified(&self) -> Option<&V> {
+        self.is_intact().then_some(&self.value)
+    }
+}
+
+impl<V> Checksummed<V> {
+    /// Returns the value without verifying it.
+    pub fn value(&self) -> &V {
+        &self.value
+    }
+
+    /// Returns the checksum taken when the value was written.
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+}
+
+impl<V: ValueCodec + AsRef<[u8]>> ValueCodec for Checksummed<V> {
+    fn encode_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.checksum.to_le_bytes());
+        self.value.encode_value(out);
+    }
+
+    /// Decodes the value along with the checksum stored with it, which is not
+    /// recomputed, so that a value corrupted since it was encoded is detected.
+    fn decode_value(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let Some((checksum, value)) = bytes.split_first_chunk::<CHECKSUM_LEN>() else {
+            return Err(DecodeError::UnexpectedEnd {
+                offset: bytes.len(),
+            });
+        };
+        let value = V::decode_value(value).map_err(|err| match err {
+            DecodeError::UnexpectedEnd { offset } => DecodeError::UnexpectedEnd {
+                offset: offset + CHECKSUM_LEN,
+            },
+            DecodeError::InvalidEscape { offset } => DecodeError::InvalidEscape {
+                offset: offset + CHECKSUM_LEN,
+            },
+            DecodeError::InvalidUtf8 { offset } => DecodeError::InvalidUtf8 {
+                offset: offset + CHECKSUM_LEN,
+            },
+            DecodeError::TrailingBytes { offset } => DecodeError::TrailingBytes {
+                offset: offset + CHECKSUM_LEN,
+            },
+        })?;
+        Ok(Checksummed {
+            value,
+            checksum: u32::from_le_bytes(*checksum),
+        })
+    }
+
+    fn is_intact(&self) -> bool {
+        Checksummed::is_intact(self)
+    }
+}
+
+/// The outcome of `Tree::scrub`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// The number of versions checked, over all keys.
+    pub versions_checked: usize,
+    /// The key and timestamp of every version that does not match its checksum,
+    /// in key order.
+    pub mismatches: Vec<(Vec<u8>, u64)>,
+}
+
+impl ScrubReport {
+    /// Returns whether every version matched its checksum.
+    pub fn is_clean(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl<P: KeyTrait, V: Clone + AsRef<[u8]>> Tree<P, Checksummed<V>> {
+    /// Inserts a value into the Trie at the next version, along with the
+    /// checksum of its bytes.
+    ///
+    /// # Returns
+    ///
+    /// Returns the previous latest value of the key, if any, unverified.
+    ///
+    pub fn insert_checksummed(
+        &mut self,
+        key: &P,
+        value: V,
+        ts: u64,
+    ) -> Result<Option<Checksummed<V>>, TrieError> {
+        self.insert(key, Checksummed::new(value), 0, ts)
+    }
+
+    /// Retrieves the value of a key at the given version, after checking it
+    /// against its checksum.
+    ///
+    /// A version of 0 reads the latest value, as with `get`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::ChecksumMismatch` with the key and the timestamp of
+    /// the value if the value does not match its checksum, and otherwise fails
+    /// like `get`.
+    ///
+    pub fn get_verified(&self, key: &P, version: u64) -> Result<(V, u64, u64), TrieError> {
+        let (key, entry, version, ts) = self.get(key, version)?;
+        match entry.is_intact() {
+            true => Ok((entry.value, version, ts)),
+            false => Err(TrieError::ChecksumMismatch {
+                key: key.as_slice().to_vec(),
+                ts,
+            }),
+        }
+    }
+
+    /// Returns an iterator over the latest value of every key, in key order,
+    /// checking each value against its checksum.
+    ///
+    /// A value that does not match its checksum is yielded as
+    /// `TrieError::ChecksumMismatch`, and the iteration carries on with the next
+    /// key.
+    ///
+    pub fn iter_verified(
+        &self,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, &V, u64, u64), TrieError>> + '_ {
+        self.iter()
+            .map(|(key, entry, version, ts)| match entry.is_intact() {
+                true => Ok((key, &entry.value, *version, *ts)),
+                false => Err(TrieError::ChecksumMismatch { key, ts: *ts }),
+            })
+    }
+
+    /// Checks every version of every key against its checksum.
+    ///
+    /// Unlike `iter_verified`, this also checks the older versions of the keys,
+    /// so corruption is found before a read at an older version runs into it.
+    ///
+    pub fn scrub(&self) -> ScrubReport {
+        let mut report = ScrubReport::default();
+        for twig in ChangedSince::all(self.root.as_ref()) {
+            for leaf in twig.iter() {
+                report.versions_checked += 1;
+                if !leaf.value.is_intact() {
+                    report
+                        .mismatches
+                        .push((twig.key.as_slice().to_vec(), leaf.ts));
+                }
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, Checksummed};
+    use crate::art::Tree;
+    use crate::frozen::FrozenTree;
+    use crate::pressure::{OptionsPatch, ReconfigureError, TreeOptions};
+    use crate::{Key, TrieError, VariableSizeKey};
+    use std::str::FromStr;
+
+    impl Checksummed<Vec<u8>> {
+        // Flips a bit of the value, as a memory error would, keeping the
+        // checksum.
+        fn flip_bit(mut self, bit: usize) -> Self {
+            self.value[bit / 8] ^= 1 << (bit % 8);
+            self
+        }
+    }
+
+    #[test]
+    fn checksums_detect_flipped_bits() {
+        let value = b"some value".to_vec();
+        let base = checksum(&value);
+        for bit in 0..value.len() * 8 {
+            let flipped = Checksummed::new(value.clone()).flip_bit(bit);
+            assert_eq!(flipped.checksum(), base);
+            assert!(!flipped.is_intact());
+            assert_eq!(flipped.verified(), None);
+        }
+        assert_eq!(Checksummed::new(value.clone()).verified(), Some(&value));
+    }
+
+    #[test]
+    fn corrupt_values_are_detected_on_read_and_scrub() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, Checksummed<Vec<u8>>> = Tree::new();
+        tree.insert_checksummed(&key("a"), b"a1".to_vec(), 1)
+            .unwrap();
+        tree.insert_checksummed(&key("b"), b"b1".to_vec(), 2)
+            .unwrap();
+        tree.insert_checksummed(&key("c"), b"c1".to_vec(), 3)
+            .unwrap();
+        assert!(tree.scrub().is_clean());
+        assert_eq!(tree.scrub().versions_checked, 3);
+
+        // A corrupted older version of `a` is only found by the scrub, and a
+        // corrupted latest version of `b` by every read.
+        let old = tree.version();
+        let corrupt = Checksummed::new(b"a2".to_vec()).flip_bit(3);
+        tree.insert(&key("a"), corrupt, 0, 4).unwrap();
+        tree.insert_checksummed(&key("a"), b"a3".to_vec(), 5)
+            .unwrap();
+        let corrupt = Checksummed::new(b"b2".to_vec()).flip_bit(12);
+        tree.insert(&key("b"), corrupt, 0, 6).unwrap();
+
+        assert_eq!(tree.get_verified(&key("a"), 0).unwrap().0, b"a3");
+        assert_eq!(tree.get_verified(&key("a"), old).unwrap().0, b"a1");
+        assert_eq!(
+            tree.get_verified(&key("a"), old + 1),
+            Err(TrieError::ChecksumMismatch {
+                key: key("a").as_slice().to_vec(),
+                ts: 4
+            })
+        );
+        let mismatch = TrieError::ChecksumMismatch {
+            key: key("b").as_slice().to_vec(),
+            ts: 6,
+        };
+        assert_eq!(tree.get_verified(&key("b"), 0), Err(mismatch.clone()));
+
+        let entries: Vec<_> = tree.iter_verified().collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1], Err(mismatch));
+        assert!(entries[0].is_ok() && entries[2].is_ok());
+
+        let report = tree.scrub();
+        assert_eq!(report.versions_checked, 6);
+        assert_eq!(
+            report.mismatches,
+            vec![
+                (key("a").as_slice().to_vec(), 4),
+                (key("b").as_slice().to_vec(), 6)
+            ]
+        );
+    }
+
+    #[test]
+    fn exported_checksums_detect_corruption_after_import() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, Checksummed<Vec<u8>>> = Tree::new();
+        tree.insert_checksummed(&key("a"), b"alpha".to_vec(), 1)
+            .unwrap();
+        tree.insert_checksummed(&key("b"), b"bravo".to_vec(), 2)
+            .unwrap();
+
+        let mut bytes = tree.freeze();
+        let thawed =
+            Tree::<VariableSizeKey, Checksummed<Vec<u8>>>::thaw(&FrozenTree::open(&bytes).unwrap())
+                .unwrap();
+        assert!(thawed.scrub().is_clean());
+        assert_eq!(thawed.get_verified(&key("b"), 0).unwrap().0, b"bravo");
+
+        // Flip a bit of the exported value of `b`: its stored checksum no
+        // longer matches once imported.
+        let at = bytes.windows(5).position(|w| w == b"bravo").unwrap();
+        bytes[at + 2] ^= 0x10;
+        let thawed =
+            Tree::<VariableSizeKey, Checksummed<Vec<u8>>>::thaw(&FrozenTree::open(&bytes).unwrap())
+                .unwrap();
+        assert_eq!(
+            thawed.scrub().mismatches,
+            vec![(key("b").as_slice().to_vec(), 2)]
+        );
+        assert!(matches!(
+            thawed.get_verified(&key("b"), 0),
+            Err(TrieError::ChecksumMismatch { ts: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn value_checksums_check_every_read() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let build = |options: TreeOptions| {
+            let mut tree: Tree<VariableSizeKey, Checksummed<Vec<u8>>> =
+                Tree::with_codec_options(options);
+            tree.insert_checksummed(&key("a"), b"a1".to_vec(), 1)
+                .unwrap();
+            let corrupt = Checksummed::new(b"b1".to_vec()).flip_bit(5);
+            tree.insert(&key("b"), corrupt, 0, 2).unwrap();
+            tree.insert_checksummed(&key("c"), b"c1".to_vec(), 3)
+                .unwrap();
+            tree
+        };
+        let mismatch = TrieError::ChecksumMismatch {
+            key: key("b").as_slice().to_vec(),
+            ts: 2,
+        };
+        let keys = |entries: Vec<Vec<u8>>| {
+            entries
+                .into_iter()
+                .map(|key| String::from_utf8(key).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // Without the option, the corrupt value is returned as it is.
+        let tree = build(TreeOptions::default());
+        assert!(!tree.options().value_checksums);
+        assert!(tree.get(&key("b"), 0).is_ok());
+        assert_eq!(tree.iter().count(), 3);
+
+        let mut tree = build(TreeOptions::default().value_checksums(true));
+        assert!(tree.options().value_checksums);
+        assert_eq!(tree.get(&key("a"), 0).unwrap().1.value(), b"a1");
+        assert_eq!(tree.get(&key("b"), 0), Err(mismatch.clone()));
+        let expected = vec!["a\0".to_string(), "c\0".to_string()];
+        assert_eq!(keys(tree.iter().map(|entry| entry.0).collect()), expected);
+        assert_eq!(
+            keys(tree.range(..).map(|entry| entry.0).collect()),
+            expected
+        );
+        assert_eq!(
+            tree.scrub().mismatches,
+            vec![(key("b").as_slice().to_vec(), 2)]
+        );
+
+        let snap = tree.create_snapshot().unwrap();
+        assert_eq!(snap.get(&key("b")), Err(mismatch));
+        let (page, _) = snap.range_page(&key("a"), &key("d"), None, 10).unwrap();
+        let page = page.into_iter().map(|(key, _)| key.as_slice().to_vec());
+        assert_eq!(keys(page.collect()), expected);
+
+        assert_eq!(
+            tree.reconfigure(OptionsPatch::default().value_checksums(false)),
+            Err(ReconfigureError::RequiresRebuild {
+                option: "value_checksums"
+            })
+        );
+        let thawed = Tree::<VariableSizeKey, Checksummed<Vec<u8>>>::thaw(
+            &FrozenTree::open(&tree.freeze()).unwrap(),
+        )
+        .unwrap();
+        assert!(thawed.options().value_checksums);
+    }
+}
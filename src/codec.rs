@@ -0,0 +1,444 @@
+//! This module defines an order-preserving encoding of composite keys, such as a
+//! tenant id followed by a name, and the decoding of stored keys back into their
+//! components during iteration, along with the encoding of values written to a
+//! frozen tree.
+use std::collections::Bound;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use crate::art::Node;
+use crate::expiry::ExpiryTable;
+use crate::iter::Range;
+use crate::{KeyTrait, VariableSizeKey};
+
+/// An error decoding the bytes of a key into its components.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The key ends in the middle of a component, or before its terminator.
+    UnexpectedEnd { offset: usize },
+    /// A byte string component holds a NULL byte that is not a valid escape.
+    InvalidEscape { offset: usize },
+    /// A string component is not valid UTF-8.
+    InvalidUtf8 { offset: usize },
+    /// The key has bytes left after its last component and terminator.
+    TrailingBytes { offset: usize },
+}
+
+impl Error for DecodeError {}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd { offset } => {
+                write!(f, "Key ends unexpectedly at offset {}", offset)
+            }
+            DecodeError::InvalidEscape { offset } => {
+                write!(f, "Invalid escape sequence at offset {}", offset)
+            }
+            DecodeError::InvalidUtf8 { offset } => {
+                write!(f, "Invalid UTF-8 in the component at offset {}", offset)
+            }
+            DecodeError::TrailingBytes { offset } => {
+                write!(f, "Unexpected bytes after the key at offset {}", offset)
+            }
+        }
+    }
+}
+
+/// A single component of a composite key.
+///
+/// Encodings are self-delimiting and preserve order: comparing the encoded bytes
+/// of two values compares the values. Integers are stored big-endian, with the
+/// sign bit flipped for signed ones. Strings and byte strings are stored with
+/// each NULL byte escaped as `0x00 0xFF` and end with `0x00 0x01`.
+pub trait KeyComponent: Sized {
+    /// Appends the encoding of the component to `out`.
+    fn encode_to(&self, out: &mut Vec<u8>);
+
+    /// Decodes a component starting at `*offset` in `bytes`, and moves `offset`
+    /// past it.
+    fn decode_from(bytes: &[u8], offset: &mut usize) -> Result<Self, DecodeError>;
+}
+
+macro_rules! int_component {
+    ($($ty:ty => $flip:expr),*) => {
+        $(
+            impl KeyComponent for $ty {
+                fn encode_to(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&(*self ^ $flip).to_be_bytes());
+                }
+
+                fn decode_from(bytes: &[u8], offset: &mut usize) -> Result<Self, DecodeError> {
+                    const LEN: usize = std::mem::size_of::<$ty>();
+                    let raw = bytes
+                        .get(*offset..*offset + LEN)
+                        .ok_or(DecodeError::UnexpectedEnd { offset: bytes.len() })?;
+                    *offset += LEN;
+                    Ok(<$ty>::from_be_bytes(raw.try_into().unwrap()) ^ $flip)
+                }
+            }
+        )*
+    };
+}
+
+int_component!(u8 => 0, u16 => 0, u32 => 0, u64 => 0, i32 => i32::MIN, i64 => i64::MIN);
+
+impl KeyComponent for Vec<u8> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        for &byte in self {
+            out.push(byte);
+            if byte == 0 {
+                out.push(0xFF);
+            }
+        }
+        out.extend_from_slice(&[0x00, 0x01]);
+    }
+
+    fn decode_from(bytes: &[u8], offset: &mut usize) -> Result<Self, DecodeError> {
+        let mut value = Vec::new();
+        loop {
+            match bytes.get(*offset) {
+                None => return Err(DecodeError::UnexpectedEnd { offset: *offset }),
+                Some(0) => match bytes.get(*offset + 1) {
+                    Some(0xFF) => value.push(0),
+                    Some(0x01) => {
+                        *offset += 2;
+                        return Ok(value);
+                    }
+                    Some(_) => return Err(DecodeError::InvalidEscape { offset: *offset }),
+                    None => {
+                        return Err(DecodeError::UnexpectedEnd {
+                            offset: *offset + 1,
+                        })
+                    }
+                },
+                Some(&byte) => {
+                    value.push(byte);
+                    *offset += 1;
+                    continue;
+                }
+            }
+            *offset += 2;
+        }
+    }
+}
+
+impl KeyComponent for String {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.as_bytes().to_vec().encode_to(out)
+    }
+
+    fn decode_from(bytes: &[u8], offset: &mut usize) -> Result<Self, DecodeError> {
+        let start = *offset;
+        String::from_utf8(Vec::<u8>::decode_from(bytes, offset)?)
+            .map_err(|_| DecodeError::InvalidUtf8 { offset: start })
+    }
+}
+
+/// A composite key, or its leading components, that can be encoded into key bytes.
+pub trait EncodeKey {
+    /// Appends the encoded components to `out`, without the key terminator.
+    ///
+    /// Components are self-delimiting, so the encoding of leading components is a
+    /// prefix of the encoding of every key that starts with them.
+    fn encode_prefix(&self, out: &mut Vec<u8>);
+
+    /// Returns the key with the encoded components, ending in a NULL terminator.
+    fn encode_key(&self) -> VariableSizeKey {
+        let mut out = Vec::new();
+        self.encode_prefix(&mut out);
+        VariableSizeKey::key(&out)
+    }
+}
+
+/// A composite key type whose stored bytes can be decoded back into components.
+pub trait DecodeKey {
+    /// The type of the decoded key.
+    type Decoded;
+
+    /// Decodes the bytes of a key written by `EncodeKey::encode_key`.
+    fn decode(bytes: &[u8]) -> Result<Self::Decoded, DecodeError>;
+}
+
+macro_rules! tuple_key {
+    ($($name:ident)+) => {
+        impl<$($name: KeyComponent),+> EncodeKey for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_prefix(&self, out: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_to(out);)+
+            }
+        }
+
+        impl<$($name: KeyComponent),+> DecodeKey for ($($name,)+) {
+            type Decoded = Self;
+
+            fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+                let mut offset = 0;
+                let key = ($($name::decode_from(bytes, &mut offset)?,)+);
+                match bytes.get(offset) {
+                    Some(0) if offset + 1 == bytes.len() => Ok(key),
+                    Some(_) => Err(DecodeError::TrailingBytes { offset }),
+                    None => Err(DecodeError::UnexpectedEnd { offset }),
+                }
+            }
+        }
+    };
+}
+
+tuple_key!(A);
+tuple_key!(A B);
+tuple_key!(A B C);
+tuple_key!(A B C D);
+
+/// An entry of a decoded iteration: the decoded key, its latest value and the
+/// version the value was written at.
+pub type DecodedEntry<K, V> = Result<(<K as DecodeKey>::Decoded, V, u64), DecodeError>;
+
+/// A value that can be written to and read back from a byte buffer, such as a
+/// frozen tree.
+///
+/// Unlike key components, value encodings need not preserve order or delimit
+/// themselves: `decode_value` is given exactly the bytes `encode_value` wrote.
+pub trait ValueCodec: Sized {
+    /// Appends the encoding of the value to `out`.
+    fn encode_value(&self, out: &mut Vec<u8>);
+
+    /// Decodes a value from the bytes written by `encode_value`.
+    fn decode_value(bytes: &[u8]) -> Result<Self, DecodeError>;
+
+    /// Returns whether the value still matches the checksum kept with it.
+    ///
+    /// Trees built with `TreeOptions::value_checksums` check every value they
+    /// read with this. Values that keep no checksum are always intact.
+    fn is_intact(&self) -> bool {
+        true
+    }
+}
+
+macro_rules! int_value {
+    ($($ty:ty),*) => {
+        $(
+            impl ValueCodec for $ty {
+                fn encode_value(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode_value(bytes: &[u8]) -> Result<Self, DecodeError> {
+                    const LEN: usize = std::mem::size_of::<$ty>();
+                    match bytes.len() {
+                        LEN => Ok(<$ty>::from_le_bytes(bytes.try_into().unwrap())),
+                        len if len < LEN => Err(DecodeError::UnexpectedEnd { offset: len }),
+                        _ => Err(DecodeError::TrailingBytes { offset: LEN }),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+int_value!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl ValueCodec for Vec<u8> {
+    fn encode_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl ValueCodec for String {
+    fn encode_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<Self, DecodeError> {
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8 { offset: 0 })
+    }
+}
+
+type Entries<'a, V> = Box<dyn Iterator<Item = (Vec<u8>, &'a V, &'a u64, &'a u64)> + 'a>;
+
+/// An iterator over entries of the Trie with their keys decoded as the composite
+/// key type `K`.
+pub struct DecodedIter<'a, K, V> {
+    entries: Entries<'a, V>,
+    _marker: PhantomData<fn() -> K>,
+}
+
+impl<'a, K: DecodeKey, V: Clone> DecodedIter<'a, K, V> {
+    /// Decodes the keys of `entries`.
+    pub(crate) fn new<I>(entries: I) -> Self
+    where
+        I: Iterator<Item = (Vec<u8>, &'a V, &'a u64, &'a u64)> + 'a,
+    {
+        DecodedIter {
+            entries: Box::new(entries),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Decodes the keys below `root` that start with the encoded `prefix`,
+    /// skipping the keys expired under the rules and timestamp in `live`.
+    pub(crate) fn with_prefix<P: KeyTrait + 'a>(
+        root: Option<&'a Arc<Node<P, V>>>,
+        prefix: &impl EncodeKey,
+        live: Option<(&'a ExpiryTable, u64)>,
+    ) -> Self {
+        let mut bytes = Vec::new();
+        prefix.encode_prefix(&mut bytes);
+        let start = P::from(bytes.as_slice());
+        Self::new(
+            Range::new(root, (Bound::Included(start), Bound::Unbounded))
+                .take_while(move |(key, _, _, _)| key.starts_with(&bytes))
+                .filter(move |(key, ..)| {
+                    !live.is_some_and(|(rules, now)| rules.is_expired(key, now))
+                }),
+        )
+    }
+}
+
+impl<'a, K: DecodeKey, V: Clone> Iterator for DecodedIter<'a, K, V> {
+    type Item = DecodedEntry<K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value, version, _) = self.entries.next()?;
+        Some(K::decode(&key).map(|key| (key, value.clone(), *version)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DecodeError, DecodeKey, EncodeKey};
+    use crate::art::Tree;
+    use crate::VariableSizeKey;
+
+    type Name = (u64, String);
+
+    fn names() -> Vec<Name> {
+        let mut names = Vec::new();
+        for tenant in [0, 1, 255, 256, u64::MAX] {
+            for name in ["", "a", "a\0", "a\0b", "ab", "b"] {
+                names.push((tenant, name.to_string()));
+            }
+        }
+        names
+    }
+
+    #[test]
+    fn composite_keys_round_trip() {
+        let mut tree: Tree<VariableSizeKey, usize> = Tree::new();
+        let names = names();
+        // Insert out of order; iteration follows the order of the components.
+        for (i, name) in names.iter().enumerate().rev() {
+            tree.insert(&name.encode_key(), i, 0, 0).unwrap();
+        }
+
+        let decoded: Vec<_> = tree
+            .iter_decoded::<Name>()
+            .map(|entry| entry.unwrap())
+            .collect();
+        let expected: Vec<_> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.clone(), i, (names.len() - i) as u64))
+            .collect();
+        assert_eq!(decoded, expected);
+
+        // Signed integers, byte strings and more components keep their order too.
+        let keys = [
+            (-5i64, vec![0u8, 0], 1u8, "x".to_string()),
+            (-5, vec![0, 0, 0], 0, "x".to_string()),
+            (-5, vec![1], 0, "".to_string()),
+            (0, vec![], 7, "y".to_string()),
+            (3, vec![0xFF], 0, "z".to_string()),
+        ];
+        let mut wide: Tree<VariableSizeKey, ()> = Tree::new();
+        for key in keys.iter().rev() {
+            wide.insert(&key.encode_key(), (), 0, 0).unwrap();
+        }
+        let decoded: Vec<_> = wide
+            .iter_decoded::<(i64, Vec<u8>, u8, String)>()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(decoded, keys);
+    }
+
+    #[test]
+    fn partial_prefix_scans() {
+        let mut tree: Tree<VariableSizeKey, usize> = Tree::new();
+        for (i, name) in names().iter().enumerate() {
+            tree.insert(&name.encode_key(), i, 0, 0).unwrap();
+        }
+
+        // The tenant id alone selects all names of the tenant.
+        let tenant: Vec<_> = tree
+            .scan_prefix_decoded::<Name>(&(255u64,))
+            .map(|entry| entry.unwrap().0 .1)
+            .collect();
+        assert_eq!(tenant, vec!["", "a", "a\0", "a\0b", "ab", "b"]);
+        assert_eq!(tree.scan_prefix_decoded::<Name>(&(2u64,)).count(), 0);
+
+        // Both components select a single key, which does not match longer
+        // names starting with the same bytes.
+        let exact: Vec<_> = tree
+            .scan_prefix_decoded::<Name>(&(1u64, "a".to_string()))
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(exact, vec![(1, "a".to_string())]);
+
+        // Snapshots and their readers scan the same way.
+        let mut snap = tree.create_snapshot().unwrap();
+        let reader = snap.new_reader().unwrap();
+        let from_snapshot: Vec<_> = snap
+            .scan_prefix_decoded::<Name>(&(u64::MAX,))
+            .unwrap()
+            .collect();
+        let from_reader: Vec<_> = reader.scan_prefix_decoded::<Name>(&(u64::MAX,)).collect();
+        assert_eq!(from_snapshot.len(), 6);
+        assert_eq!(from_snapshot, from_reader);
+        assert_eq!(
+            snap.iter_decoded::<Name>().unwrap().count(),
+            reader.iter_decoded::<Name>().count()
+        );
+        snap.close_reader(reader.id).unwrap();
+        snap.close().unwrap();
+        assert!(snap.iter_decoded::<Name>().is_err());
+    }
+
+    #[test]
+    fn malformed_keys_are_yielded_as_errors() {
+        let mut tree: Tree<VariableSizeKey, usize> = Tree::new();
+        tree.insert(&(1u64, "a".to_string()).encode_key(), 0, 0, 0)
+            .unwrap();
+        tree.insert(&(3u64, "c".to_string()).encode_key(), 2, 0, 0)
+            .unwrap();
+
+        // Raw keys inserted without the encoding sort between the valid ones: one
+        // is missing its name, the other has a NULL byte that is not an escape.
+        let tenant = 2u64.to_be_bytes();
+        tree.insert(&VariableSizeKey::key(&tenant), 1, 0, 0)
+            .unwrap();
+        let bad_escape = [&tenant[..], &[b'b', 0, 7]].concat();
+        tree.insert(&VariableSizeKey::key(&bad_escape), 1, 0, 0)
+            .unwrap();
+
+        let entries: Vec<_> = tree.iter_decoded::<Name>().collect();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0], Ok(((1, "a".to_string()), 0, 1)));
+        assert_eq!(entries[1], Err(DecodeError::UnexpectedEnd { offset: 9 }));
+        assert_eq!(entries[2], Err(DecodeError::InvalidEscape { offset: 9 }));
+        assert_eq!(entries[3], Ok(((3, "c".to_string()), 2, 2)));
+
+        // A key with more components than the schema is rejected too.
+        let longer = (1u64, "a".to_string(), 9u8).encode_key();
+        assert_eq!(
+            Name::decode(longer.to_slice()),
+            Err(DecodeError::TrailingBytes { offset: 11 })
+        );
+    }
+}
@@ -0,0 +1,326 @@
+//! This module estimates the heap an insert adds to a Tree before it is made,
+//! for admission control.
+//!
+//! The estimate follows the path the insert would take without changing or
+//! allocating anything, and prices the nodes it would create and copy from the
+//! sizes of the node types. It does not count the memory of optional indexes,
+//! such as the hash index or the prefix statistics.
+//!
+//! The same sizes measure the heap a trie holds beyond another trie it shares
+//! nodes with, such as a snapshot taken from it.
+use std::cmp::min;
+use std::collections::HashSet;
+use std::mem::size_of;
+use std::ops::AddAssign;
+use std::sync::Arc;
+
+use crate::art::{Node, NodeKind, NodeType};
+use crate::node::{LeafValue, TwigNode, VERSIONS_TAIL_LEN};
+use crate::KeyTrait;
+
+// The reference counts in front of the data of an `Arc`.
+const ARC_HEADER: usize = 2 * size_of::<usize>();
+// A child slot or a version slot holds a single `Arc` pointer.
+const SLOT: usize = size_of::<usize>();
+// The capacity a version list starts with when its first version is pushed.
+const FIRST_TAIL_CAPACITY: usize = 4;
+
+/// The approximate heap cost of an insert, as returned by
+/// `Tree::estimate_insert_cost`.
+///
+/// Summed over a workload, `heap_bytes` is within a factor of 2 of the heap the
+/// inserts actually add.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InsertCostEstimate {
+    /// Bytes of the new version of the value, including the value's own heap
+    /// as hinted, and of the leaf created for a new key.
+    pub leaf_bytes: usize,
+    /// The number of nodes the insert creates: the leaf of a new key, the inner
+    /// node of a prefix split, and a node grown into a larger type.
+    pub new_nodes: usize,
+    /// Bytes of the inner nodes the insert creates, counting only the extra
+    /// size of a node grown into a larger type.
+    pub new_node_bytes: usize,
+    /// Bytes of the copies of the nodes on the path of the insert.
+    pub copy_bytes: usize,
+    /// Bytes of the copies whose originals are shared, by a snapshot for
+    /// instance, and so are kept alive next to them rather than freed.
+    pub retained_bytes: usize,
+}
+
+impl InsertCostEstimate {
+    /// Returns the heap the insert is expected to add to the Tree once the
+    /// nodes it replaces are freed.
+    pub fn heap_bytes(&self) -> usize {
+        self.leaf_bytes + self.new_node_bytes + self.retained_bytes
+    }
+
+    /// Returns the heap the insert is expected to allocate, counting copies
+    /// that replace nodes it frees.
+    pub fn allocated_bytes(&self) -> usize {
+        self.leaf_bytes + self.new_node_bytes + self.copy_bytes
+    }
+
+    fn copy(&mut self, bytes: usize, shared: bool) {
+        self.copy_bytes += bytes;
+        if shared {
+            self.retained_bytes += bytes;
+        }
+    }
+}
+
+impl AddAssign for InsertCostEstimate {
+    fn add_assign(&mut self, other: Self) {
+        self.leaf_bytes += other.leaf_bytes;
+        self.new_nodes += other.new_nodes;
+        self.new_node_bytes += other.new_node_bytes;
+        self.copy_bytes += other.copy_bytes;
+        self.retained_bytes += other.retained_bytes;
+    }
+}
+
+/// Returns the number of child slots of an inner node.
+fn width<P: KeyTrait, V: Clone>(node_type: &NodeType<P, V>) -> usize {
+    match node_type {
+        NodeType::Node1(_) => 1,
+        NodeType::Node4(_) => 4,
+        NodeType::Node16(_) => 16,
+        NodeType::Node48(_) => 48,
+        NodeType::Node256(_) => 256,
+        NodeType::Twig(_) => 0,
+    }
+}
+
+/// Returns the number of child slots of the node type a node of `width` slots
+/// grows into.
+fn grown_width(width: usize) -> usize {
+    match width {
+        1 => 4,
+        4 => 16,
+        16 => 48,
+        _ => 256,
+    }
+}
+
+fn kind_width(kind: Option<NodeKind>) -> usize {
+    match kind {
+        None | Some(NodeKind::Node1) | Some(NodeKind::Node4) => 4,
+        Some(NodeKind::Node16) => 16,
+        Some(NodeKind::Node48) => 48,
+        Some(NodeKind::Node256) => 256,
+    }
+}
+
+fn inner_bytes<P: KeyTrait, V: Clone>(width: usize, prefix_len: usize) -> usize {
+    ARC_HEADER + size_of::<Node<P, V>>() + width * SLOT + P::heap_size(prefix_len)
+}
+
+fn twig_bytes<P: KeyTrait, V: Clone>(
+    prefix_len: usize,
+    key_len: usize,
+    tail_capacity: usize,
+    chunks: usize,
+) -> usize {
+    ARC_HEADER
+        + size_of::<Node<P, V>>()
+        + P::heap_size(prefix_len)
+        + P::heap_size(key_len)
+        + tail_capacity * SLOT
+        + chunks * 2 * SLOT
+}
+
+/// Returns the bytes of a copy of `twig` with one more version.
+fn twig_copy_bytes<P: KeyTrait, V: Clone>(twig: &TwigNode<P, V>) -> usize {
+    let chunks = twig.values.chunk_lens().count();
+    let tail = twig.values.len() - twig.values.chunk_lens().sum::<usize>();
+    if tail + 1 >= VERSIONS_TAIL_LEN {
+        // The tail is sealed into a new chunk.
+        let chunk = ARC_HEADER + (tail + 1) * SLOT;
+        twig_bytes::<P, V>(twig.prefix.len(), twig.key.len(), 0, chunks + 1) + chunk
+    } else {
+        // A cloned tail is exactly full, so the new version doubles it.
+        let capacity = (2 * tail).max(FIRST_TAIL_CAPACITY);
+        twig_bytes::<P, V>(twig.prefix.len(), twig.key.len(), capacity, chunks)
+    }
+}
+
+/// Returns the bytes of a copy of `node` as it is, as made for a prefix split.
+fn clone_bytes<P: KeyTrait, V: Clone>(node: &Node<P, V>) -> usize {
+    match &node.node_type {
+        NodeType::Twig(twig) => {
+            let chunks = twig.values.chunk_lens().count();
+            let tail = twig.values.len() - twig.values.chunk_lens().sum::<usize>();
+            twig_bytes::<P, V>(twig.prefix.len(), twig.key.len(), tail, chunks)
+        }
+        node_type => inner_bytes::<P, V>(width(node_type), node.prefix().len()),
+    }
+}
+
+/// Estimates the cost of inserting `key` with a value holding `value_size_hint`
+/// bytes on the heap into the trie rooted at `root`, the way
+/// `Node::insert_recurse` would.
+///
+/// Nodes in `seen` are not priced again, and the nodes copied are added to it.
+pub(crate) fn estimate<P: KeyTrait, V: Clone>(
+    root: Option<&Arc<Node<P, V>>>,
+    key: &P,
+    value_size_hint: usize,
+    forced: Option<NodeKind>,
+    mut seen: Option<&mut HashSet<*const Node<P, V>>>,
+) -> InsertCostEstimate {
+    let leaf_bytes = ARC_HEADER + size_of::<LeafValue<V>>() + value_size_hint;
+    let mut estimate = InsertCostEstimate::default();
+    let key_bytes = key.as_slice();
+    let new_twig = |prefix_len| {
+        leaf_bytes + twig_bytes::<P, V>(prefix_len, key_bytes.len(), FIRST_TAIL_CAPACITY, 0)
+    };
+
+    let Some(mut cur_node) = root else {
+        estimate.leaf_bytes = new_twig(key_bytes.len());
+        estimate.new_nodes = 1;
+        return estimate;
+    };
+
+    let mut depth = 0;
+    // Whether an inner node on the path so far is shared, as in `InsertStats`.
+    let mut path_shared = false;
+    loop {
+        if !cur_node.is_twig() && Arc::strong_count(cur_node) > 1 {
+            path_shared = true;
+        }
+        // A node the batch already copied is copied again, but the previous
+        // copy is freed.
+        let priced = match seen.as_deref_mut() {
+            Some(seen) => !seen.insert(Arc::as_ptr(cur_node)),
+            None => false,
+        };
+
+        let rest = &key_bytes[depth..];
+        let prefix = cur_node.prefix();
+        let lcp = prefix.longest_common_prefix(rest);
+        let is_prefix_match = min(prefix.len(), rest.len()) == lcp;
+
+        if let NodeType::Twig(twig) = &cur_node.node_type {
+            if is_prefix_match && prefix.len() == rest.len() {
+                if !priced {
+                    estimate.copy(twig_copy_bytes(twig), path_shared);
+                }
+                // The new version takes a slot of the version list.
+                estimate.leaf_bytes = leaf_bytes + SLOT;
+                return estimate;
+            }
+        }
+
+        if !is_prefix_match {
+            // The node is copied below a new inner node, next to a new twig.
+            if !priced {
+                estimate.copy(clone_bytes(cur_node), path_shared);
+            }
+            estimate.new_nodes = 2;
+            estimate.new_node_bytes = inner_bytes::<P, V>(kind_width(forced), lcp);
+            estimate.leaf_bytes = new_twig(rest.len() - lcp);
+            return estimate;
+        }
+
+        let width = width(&cur_node.node_type);
+        if !priced {
+            estimate.copy(inner_bytes::<P, V>(width, prefix.len()), path_shared);
+        }
+        match cur_node.find_child(rest[lcp]) {
+            Some(child) => {
+                depth += lcp;
+                cur_node = child;
+            }
+            None => {
+                estimate.new_nodes = 1;
+                estimate.leaf_bytes = new_twig(rest.len() - lcp);
+                // Nodes grow as soon as they fill up, except a Node256.
+                if width < 256 && cur_node.num_children() + 1 >= width {
+                    estimate.new_nodes += 1;
+                    estimate.new_node_bytes = (grown_width(width) - width) * SLOT;
+                }
+                return estimate;
+            }
+        }
+    }
+}
+
+/// Returns the bytes of the nodes and values of the trie rooted at `node` that
+/// the trie rooted at `base` does not share.
+///
+/// Subtrees shared by both tries are skipped without being visited. Where both
+/// tries have an inner node with the same prefix, or a twig of the same key, at
+/// the same position, their children or values are matched pairwise; anywhere
+/// else the nodes and values of the `base` subtree are gathered to be looked up.
+pub(crate) fn unique_bytes<P: KeyTrait, V: Clone>(
+    node: &Arc<Node<P, V>>,
+    base: Option<&Arc<Node<P, V>>>,
+) -> usize {
+    let mut shared = Shared::new();
+    let Some(base) = base else {
+        return shared.unique_bytes(node);
+    };
+    match (&node.node_type, &base.node_type) {
+        _ if Arc::ptr_eq(base, node) => 0,
+        (NodeType::Twig(twig), NodeType::Twig(base_twig)) if twig.key == base_twig.key => {
+            shared.values.extend(base_twig.iter().map(Arc::as_ptr));
+            shared.unique_bytes(node)
+        }
+        _ if !node.is_twig() && !base.is_twig() && node.prefix() == base.prefix() => {
+            clone_bytes(node)
+                + node
+                    .iter()
+                    .map(|(k, child)| unique_bytes(child, base.find_child(k)))
+                    .sum::<usize>()
+        }
+        _ => {
+            shared.gather(base);
+            shared.unique_bytes(node)
+        }
+    }
+}
+
+// The nodes and values of a subtree, by address.
+struct Shared<P: KeyTrait, V: Clone> {
+    nodes: HashSet<*const Node<P, V>>,
+    values: HashSet<*const LeafValue<V>>,
+}
+
+impl<P: KeyTrait, V: Clone> Shared<P, V> {
+    fn new() -> Self {
+        Shared {
+            nodes: HashSet::new(),
+            values: HashSet::new(),
+        }
+    }
+
+    fn gather(&mut self, node: &Arc<Node<P, V>>) {
+        self.nodes.insert(Arc::as_ptr(node));
+        match &node.node_type {
+            NodeType::Twig(twig) => self.values.extend(twig.iter().map(Arc::as_ptr)),
+            _ => node.iter().for_each(|(_, child)| self.gather(child)),
+        }
+    }
+
+    fn unique_bytes(&self, node: &Arc<Node<P, V>>) -> usize {
+        if self.nodes.contains(&Arc::as_ptr(node)) {
+            return 0;
+        }
+        match &node.node_type {
+            NodeType::Twig(twig) => {
+                let new_values = twig
+                    .iter()
+                    .filter(|leaf| !self.values.contains(&Arc::as_ptr(leaf)))
+                    .count();
+                clone_bytes(node) + new_values * (ARC_HEADER + size_of::<LeafValue<V>>())
+            }
+            _ => {
+                clone_bytes(node)
+                    + node
+                        .iter()
+                        .map(|(_, child)| self.unique_bytes(child))
+                        .sum::<usize>()
+            }
+        }
+    }
+}
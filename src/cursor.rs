@@ -0,0 +1,192 @@
+//! This module defines the cursors of paginated scans, and the tokens that let a
+//! scan be resumed by another process, such as one that thawed a frozen copy of
+//! the Tree, or a range read of a snapshot be continued by a later request.
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::art::Node;
+use crate::checksum::{fnv1a_64, FNV64_OFFSET};
+use crate::iter::Iter;
+use crate::KeyTrait;
+
+const TOKEN_MAGIC: [u8; 4] = *b"VCUR";
+const PAGE_MAGIC: [u8; 4] = *b"VPAG";
+
+/// The position of a paginated scan, as returned by `Tree::scan_cursor` and
+/// advanced by `Tree::scan_page`.
+///
+/// A cursor reads the latest value of each key until it is pinned to a version,
+/// which `Tree::resume_cursor` does when the Tree has changed since the cursor
+/// token was taken.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScanCursor {
+    // The last key returned, after which the next page starts.
+    pub(crate) after: Option<Vec<u8>>,
+    // The version the cursor reads at, if pinned.
+    pub(crate) pinned: Option<u64>,
+    pub(crate) done: bool,
+}
+
+impl ScanCursor {
+    pub(crate) fn new() -> Self {
+        ScanCursor {
+            after: None,
+            pinned: None,
+            done: false,
+        }
+    }
+
+    /// Returns the last key returned by the scan, or `None` before the first page.
+    pub fn last_key(&self) -> Option<&[u8]> {
+        self.after.as_deref()
+    }
+
+    /// Returns the version the scan reads at, or `None` if it reads the latest
+    /// values.
+    pub fn pinned_version(&self) -> Option<u64> {
+        self.pinned
+    }
+
+    /// Returns true once the scan has returned its last key.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+/// The position of a paginated range read, returned by `Snapshot::range_page`
+/// with every page but the last: the last key of the page, after which the
+/// next page starts.
+///
+/// A token refers to no state kept by the snapshot, so it can be handed to a
+/// client as bytes with `to_bytes`, and read back with `from_bytes` by the
+/// request that fetches the next page.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PageToken {
+    after: Vec<u8>,
+}
+
+impl PageToken {
+    pub(crate) fn new(after: Vec<u8>) -> Self {
+        PageToken { after }
+    }
+
+    /// Returns the last key of the page the token was returned with.
+    pub fn last_key(&self) -> &[u8] {
+        &self.after
+    }
+
+    /// Encodes the token as bytes, which `from_bytes` reads back.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&PAGE_MAGIC[..], &self.after].concat()
+    }
+
+    /// Decodes a token encoded by `to_bytes`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ResumeError::Malformed` if the bytes are not a page token.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ResumeError> {
+        match bytes.strip_prefix(&PAGE_MAGIC[..]) {
+            Some(after) => Ok(PageToken::new(after.to_vec())),
+            None => Err(ResumeError::Malformed),
+        }
+    }
+}
+
+/// An error resuming a scan from a cursor token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ResumeError {
+    /// The token was not written by `Tree::cursor_token`, or by
+    /// `PageToken::to_bytes`.
+    Malformed,
+    /// The Tree has changed since the token was taken, and no longer serves reads
+    /// at the version the cursor was consistent with. The scan has to be resolved
+    /// again against a snapshot taken at that version.
+    TreeAdvanced { version: u64 },
+}
+
+impl Error for ResumeError {}
+
+impl fmt::Display for ResumeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResumeError::Malformed => write!(f, "Malformed cursor token"),
+            ResumeError::TreeAdvanced { version } => write!(
+                f,
+                "Tree has advanced; resolve the cursor against a snapshot at version {}",
+                version
+            ),
+        }
+    }
+}
+
+/// Returns a fingerprint of the latest entries of the trie rooted at `root`.
+///
+/// The fingerprint covers the key, version and timestamp of the latest value of
+/// every key, which a write always changes, and is computed with FNV-1a so that
+/// it is the same in every process, including for a Tree thawed from a frozen
+/// copy. Values are not read.
+pub(crate) fn fingerprint<P: KeyTrait, V: Clone>(root: Option<&Arc<Node<P, V>>>) -> u64 {
+    let mut hash = FNV64_OFFSET;
+    for (key, _, version, ts) in Iter::new(root) {
+        hash = fnv1a_64(hash, &(key.len() as u32).to_le_bytes());
+        hash = fnv1a_64(hash, &key);
+        hash = fnv1a_64(hash, &version.to_le_bytes());
+        hash = fnv1a_64(hash, &ts.to_le_bytes());
+    }
+    hash
+}
+
+// Flags of a cursor token.
+const DONE: u8 = 1;
+const PINNED: u8 = 2;
+const HAS_LAST_KEY: u8 = 4;
+
+/// Encodes a cursor token: the magic, the fingerprint and version of the Tree,
+/// the pinned version, the flags of the cursor, and its last key.
+pub(crate) fn encode_token(cursor: &ScanCursor, fingerprint: u64, version: u64) -> Vec<u8> {
+    let mut token = TOKEN_MAGIC.to_vec();
+    token.extend_from_slice(&fingerprint.to_le_bytes());
+    token.extend_from_slice(&version.to_le_bytes());
+    token.extend_from_slice(&cursor.pinned.unwrap_or(0).to_le_bytes());
+    let mut flags = 0;
+    if cursor.done {
+        flags |= DONE;
+    }
+    if cursor.pinned.is_some() {
+        flags |= PINNED;
+    }
+    if cursor.after.is_some() {
+        flags |= HAS_LAST_KEY;
+    }
+    token.push(flags);
+    if let Some(after) = &cursor.after {
+        token.extend_from_slice(after);
+    }
+    token
+}
+
+/// Decodes a cursor token into the cursor, and the fingerprint and version of
+/// the Tree it was taken on.
+pub(crate) fn decode_token(token: &[u8]) -> Result<(ScanCursor, u64, u64), ResumeError> {
+    const HEADER_LEN: usize = 4 + 8 * 3 + 1;
+    if token.len() < HEADER_LEN || token[..4] != TOKEN_MAGIC {
+        return Err(ResumeError::Malformed);
+    }
+    let word = |at: usize| u64::from_le_bytes(token[at..at + 8].try_into().unwrap());
+    let (fingerprint, version, pinned) = (word(4), word(12), word(20));
+    let flags = token[28];
+    let after = &token[HEADER_LEN..];
+    if flags & !(DONE | PINNED | HAS_LAST_KEY) != 0
+        || (flags & HAS_LAST_KEY == 0 && !after.is_empty())
+    {
+        return Err(ResumeError::Malformed);
+    }
+    let cursor = ScanCursor {
+        after: (flags & HAS_LAST_KEY != 0).then(|| after.to_vec()),
+        pinned: (flags & PINNED != 0).then_some(pinned),
+        done: flags & DONE != 0,
+    };
+    Ok((cursor, fingerprint, version))
+}
@@ -0,0 +1,354 @@
+//! This module defines the changelog computed between two versions of a trie,
+//! for replicating a Tree from one of its snapshots, and its serialized form.
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use crate::art::{Node, NodeType};
+use crate::codec::ValueCodec;
+use crate::node::TwigNode;
+use crate::{KeyTrait, TrieError};
+
+const INSERT_TAG: u8 = 0;
+const REMOVE_TAG: u8 = 1;
+
+/// A change to a key between two versions of a trie.
+///
+/// Changes are net: a key written several times appears once, with the value
+/// it ends up with, and a key inserted and then removed does not appear.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change<P, V> {
+    /// A key that was added, or whose latest value changed, along with its new
+    /// latest value and the version and timestamp it was written at.
+    Insert {
+        key: P,
+        value: V,
+        version: u64,
+        ts: u64,
+    },
+    /// A key that was removed.
+    Remove { key: P },
+}
+
+/// Appends to `changes` the changes turning the trie rooted at `base` into the
+/// trie rooted at `current`, in key order.
+///
+/// The tries are walked with `zip_twigs`, so subtrees shared by both are
+/// skipped without being visited.
+pub(crate) fn diff_nodes<P: KeyTrait, V: Clone>(
+    base: Option<&Arc<Node<P, V>>>,
+    current: Option<&Arc<Node<P, V>>>,
+    changes: &mut Vec<Change<P, V>>,
+) {
+    zip_twigs(base, current, &mut |base, current| match (base, current) {
+        (None, None) => {}
+        (Some(base), None) => changes.push(Change::Remove {
+            key: base.key.clone(),
+        }),
+        (base, Some(current)) => {
+            // Values are shared between versions of a twig, so an unchanged
+            // latest value is the same allocation.
+            let latest = base.and_then(|base| base.get_latest_leaf());
+            let unchanged = match (latest, current.get_latest_leaf()) {
+                (Some(b), Some(c)) => Arc::ptr_eq(b, c),
+                _ => false,
+            };
+            if !unchanged {
+                push_insert(current, changes);
+            }
+        }
+    });
+}
+
+/// Calls `visit` with the twigs of every key of the tries rooted at `base` and
+/// `current` that are not shared by both, in key order, each paired with the
+/// twig of the same key in the other trie, or `None` if it holds no such key.
+///
+/// Subtrees shared by both tries are skipped without being visited. Where both
+/// tries have an inner node with the same prefix at the same position, their
+/// children are compared pairwise; anywhere else the twigs of both subtrees are
+/// merged by key.
+pub(crate) fn zip_twigs<'a, P: KeyTrait, V: Clone>(
+    base: Option<&'a Arc<Node<P, V>>>,
+    current: Option<&'a Arc<Node<P, V>>>,
+    visit: &mut impl FnMut(Option<&'a TwigNode<P, V>>, Option<&'a TwigNode<P, V>>),
+) {
+    match (base, current) {
+        (None, None) => {}
+        (Some(base), Some(current)) if Arc::ptr_eq(base, current) => {}
+        (Some(base), Some(current))
+            if !base.is_twig() && !current.is_twig() && base.prefix() == current.prefix() =>
+        {
+            let mut base_children = base.iter().peekable();
+            let mut current_children = current.iter().peekable();
+            loop {
+                let (b, c) = match (base_children.peek(), current_children.peek()) {
+                    (None, None) => break,
+                    (Some((kb, _)), Some((kc, _))) if kb == kc => {
+                        (base_children.next(), current_children.next())
+                    }
+                    (Some((kb, _)), Some((kc, _))) if kb < kc => (base_children.next(), None),
+                    (Some(_), None) => (base_children.next(), None),
+                    _ => (None, current_children.next()),
+                };
+                zip_twigs(b.map(|(_, n)| n), c.map(|(_, n)| n), visit);
+            }
+        }
+        (base, current) => {
+            let mut base_twigs = Vec::new();
+            let mut current_twigs = Vec::new();
+            if let Some(base) = base {
+                collect_twigs(base, &mut base_twigs);
+            }
+            if let Some(current) = current {
+                collect_twigs(current, &mut current_twigs);
+            }
+            merge_twigs(&base_twigs, &current_twigs, visit);
+        }
+    }
+}
+
+/// Returns true if the tries rooted at `a` and `b` hold the same keys with the
+/// same latest values.
+///
+/// Subtrees shared by both tries are equal without being visited, and inner nodes
+/// with the same prefix and child keys are compared child by child; anywhere else
+/// the twigs of both subtrees are compared in key order.
+pub(crate) fn same_content<P: KeyTrait, V: Clone + PartialEq>(
+    a: Option<&Arc<Node<P, V>>>,
+    b: Option<&Arc<Node<P, V>>>,
+) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some(a), Some(b)) if Arc::ptr_eq(a, b) => true,
+        (Some(a), Some(b))
+            if !a.is_twig()
+                && !b.is_twig()
+                && a.prefix() == b.prefix()
+                && a.iter().map(|(k, _)| k).eq(b.iter().map(|(k, _)| k)) =>
+        {
+            a.iter()
+                .zip(b.iter())
+                .all(|((_, a), (_, b))| same_content(Some(a), Some(b)))
+        }
+        (a, b) => {
+            let mut a_twigs = Vec::new();
+            let mut b_twigs = Vec::new();
+            if let Some(a) = a {
+                collect_twigs(a, &mut a_twigs);
+            }
+            if let Some(b) = b {
+                collect_twigs(b, &mut b_twigs);
+            }
+            a_twigs.len() == b_twigs.len()
+                && a_twigs.iter().zip(&b_twigs).all(|(a, b)| {
+                    a.key == b.key
+                        && match (a.get_latest_leaf(), b.get_latest_leaf()) {
+                            (Some(a), Some(b)) => Arc::ptr_eq(a, b) || a.value == b.value,
+                            (a, b) => a.is_none() && b.is_none(),
+                        }
+                })
+        }
+    }
+}
+
+// Appends the twigs below `node` to `twigs`, in key order.
+fn collect_twigs<'a, P: KeyTrait, V: Clone>(
+    node: &'a Node<P, V>,
+    twigs: &mut Vec<&'a TwigNode<P, V>>,
+) {
+    match &node.node_type {
+        NodeType::Twig(twig) => twigs.push(twig),
+        _ => {
+            for (_, child) in node.iter() {
+                collect_twigs(child, twigs);
+            }
+        }
+    }
+}
+
+// Merges two key-ordered lists of twigs, calling `visit` with the twigs of
+// every key.
+fn merge_twigs<'a, P: KeyTrait, V: Clone>(
+    base: &[&'a TwigNode<P, V>],
+    current: &[&'a TwigNode<P, V>],
+    visit: &mut impl FnMut(Option<&'a TwigNode<P, V>>, Option<&'a TwigNode<P, V>>),
+) {
+    let (mut i, mut j) = (0, 0);
+    while i < base.len() || j < current.len() {
+        let order = match (base.get(i), current.get(j)) {
+            (Some(b), Some(c)) => b.key.cmp(&c.key),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            _ => std::cmp::Ordering::Greater,
+        };
+        match order {
+            std::cmp::Ordering::Less => {
+                visit(Some(base[i]), None);
+                i += 1;
+            }
+            std::cmp::Ordering::Greater => {
+                visit(None, Some(current[j]));
+                j += 1;
+            }
+            std::cmp::Ordering::Equal => {
+                visit(Some(base[i]), Some(current[j]));
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+}
+
+fn push_insert<P: KeyTrait, V: Clone>(twig: &TwigNode<P, V>, changes: &mut Vec<Change<P, V>>) {
+    if let Some(leaf) = twig.get_latest_leaf() {
+        changes.push(Change::Insert {
+            key: twig.key.clone(),
+            value: leaf.value.clone(),
+            version: leaf.version,
+            ts: leaf.ts,
+        });
+    }
+}
+
+/// Writes `changes` to `out` in the format read by `Tree::apply_wal`.
+///
+/// Each change is a record of its own, so records written by successive calls
+/// can be appended to the same log. A record is a tag byte, followed by the key
+/// length as a little-endian `u32` and the key bytes; an insert then has its
+/// version and timestamp as little-endian `u64`s, and the length of the encoded
+/// value as a `u32` followed by the value.
+pub fn serialize_delta<P: KeyTrait, V: ValueCodec, W: Write>(
+    changes: &[Change<P, V>],
+    mut out: W,
+) -> io::Result<()> {
+    let mut record = Vec::new();
+    let mut value_bytes = Vec::new();
+    for change in changes {
+        record.clear();
+        let key = match change {
+            Change::Insert { key, .. } => {
+                record.push(INSERT_TAG);
+                key
+            }
+            Change::Remove { key } => {
+                record.push(REMOVE_TAG);
+                key
+            }
+        };
+        record.extend_from_slice(&(key.as_slice().len() as u32).to_le_bytes());
+        record.extend_from_slice(key.as_slice());
+        if let Change::Insert {
+            value, version, ts, ..
+        } = change
+        {
+            record.extend_from_slice(&version.to_le_bytes());
+            record.extend_from_slice(&ts.to_le_bytes());
+            value_bytes.clear();
+            value.encode_value(&mut value_bytes);
+            record.extend_from_slice(&(value_bytes.len() as u32).to_le_bytes());
+            record.extend_from_slice(&value_bytes);
+        }
+        out.write_all(&record)?;
+    }
+    Ok(())
+}
+
+/// Reads back the records written by `serialize_delta`, one at a time.
+pub(crate) struct ChangeReader<R> {
+    reader: R,
+    // The offset of the next record.
+    offset: u64,
+    // The offset up to which the current record has been read.
+    read: u64,
+}
+
+impl<R: Read> ChangeReader<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        ChangeReader {
+            reader,
+            offset: 0,
+            read: 0,
+        }
+    }
+
+    /// Returns the next change, or `None` at the end of the log.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::CorruptChangelog` with the offset of the record if
+    /// it is cut short or malformed, and `TrieError::Other` if reading fails.
+    pub(crate) fn next_change<P: KeyTrait, V: ValueCodec>(
+        &mut self,
+    ) -> Result<Option<Change<P, V>>, TrieError> {
+        let mut tag = [0u8];
+        loop {
+            match self.reader.read(&mut tag) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(TrieError::Other(err.to_string())),
+            }
+        }
+        self.read = self.offset + 1;
+
+        let key_len = u32::from_le_bytes(self.read_array()?) as usize;
+        let key = P::from(self.read_vec(key_len)?.as_slice());
+        let change = match tag[0] {
+            INSERT_TAG => {
+                let version = u64::from_le_bytes(self.read_array()?);
+                let ts = u64::from_le_bytes(self.read_array()?);
+                let value_len = u32::from_le_bytes(self.read_array()?) as usize;
+                let value = V::decode_value(&self.read_vec(value_len)?)
+                    .map_err(|_| self.corrupt("cannot decode value"))?;
+                Change::Insert {
+                    key,
+                    value,
+                    version,
+                    ts,
+                }
+            }
+            REMOVE_TAG => Change::Remove { key },
+            _ => return Err(self.corrupt("unknown record tag")),
+        };
+        self.offset = self.read;
+        Ok(Some(change))
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], TrieError> {
+        let mut bytes = [0u8; N];
+        self.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn read_vec(&mut self, len: usize) -> Result<Vec<u8>, TrieError> {
+        let mut bytes = Vec::new();
+        let read = (&mut self.reader)
+            .take(len as u64)
+            .read_to_end(&mut bytes)
+            .map_err(|err| TrieError::Other(err.to_string()))?;
+        self.read += read as u64;
+        if read < len {
+            return Err(self.corrupt("truncated record"));
+        }
+        Ok(bytes)
+    }
+
+    fn read_exact(&mut self, bytes: &mut [u8]) -> Result<(), TrieError> {
+        match self.reader.read_exact(bytes) {
+            Ok(()) => {
+                self.read += bytes.len() as u64;
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(self.corrupt("truncated record"))
+            }
+            Err(err) => Err(TrieError::Other(err.to_string())),
+        }
+    }
+
+    fn corrupt(&self, reason: &'static str) -> TrieError {
+        TrieError::CorruptChangelog {
+            offset: self.offset,
+            reason,
+        }
+    }
+}
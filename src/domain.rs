@@ -0,0 +1,83 @@
+//! This module defines the timestamp domains of a Tree: separate timestamp
+//! counters kept for the first segment of its keys, so that writers owning
+//! disjoint segments number their writes independently.
+use std::collections::BTreeMap;
+
+/// The latest timestamp written to each domain, keyed by segment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TsDomains {
+    delimiter: u8,
+    counters: BTreeMap<Vec<u8>, u64>,
+}
+
+impl TsDomains {
+    pub(crate) fn new(delimiter: u8) -> Self {
+        TsDomains {
+            delimiter,
+            counters: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the delimiter ending the segments.
+    pub(crate) fn delimiter(&self) -> u8 {
+        self.delimiter
+    }
+
+    /// Returns the domain of `key`, the bytes before its first delimiter, or
+    /// `None` if it has no delimiter.
+    ///
+    /// Unlike the segments of prefix statistics, a key without a delimiter is
+    /// in no domain, and follows the counter of the whole Tree.
+    pub(crate) fn domain_of<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        let end = key.iter().position(|&b| b == self.delimiter)?;
+        Some(&key[..end])
+    }
+
+    /// Returns the counter of the domain `segment`, if it has one.
+    pub(crate) fn get(&self, segment: &[u8]) -> Option<u64> {
+        self.counters.get(segment).copied()
+    }
+
+    /// Returns the timestamp zero timestamps of `key` are assigned after: the
+    /// counter of its domain, or `global` for a key in no domain.
+    pub(crate) fn clock_of(&self, key: &[u8], global: u64) -> u64 {
+        match self.domain_of(key) {
+            Some(segment) => self.get(segment).unwrap_or(0),
+            None => global,
+        }
+    }
+
+    /// Moves the counter of the domain of `key`, if it is in one, forward to
+    /// `ts`.
+    pub(crate) fn advance(&mut self, key: &[u8], ts: u64) {
+        if let Some(segment) = self.domain_of(key) {
+            self.adopt(segment, ts);
+        }
+    }
+
+    /// Moves the counter of the domain `segment` forward to `ts`. Earlier
+    /// timestamps are ignored.
+    pub(crate) fn adopt(&mut self, segment: &[u8], ts: u64) {
+        match self.counters.get_mut(segment) {
+            Some(counter) => *counter = (*counter).max(ts),
+            None => {
+                self.counters.insert(segment.to_vec(), ts);
+            }
+        }
+    }
+
+    /// Returns whether the keys of the domain `segment` can start with
+    /// `prefix`.
+    pub(crate) fn overlaps(&self, segment: &[u8], prefix: &[u8]) -> bool {
+        match self.domain_of(prefix) {
+            Some(domain) => domain == segment,
+            None => segment.starts_with(prefix),
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&[u8], u64)> {
+        self.counters
+            .iter()
+            .map(|(segment, ts)| (segment.as_slice(), *ts))
+    }
+}
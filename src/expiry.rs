@@ -0,0 +1,149 @@
+//! This module defines the prefix expiry table used by the Tree to hide whole
+//! parts of the keyspace once their deadline has passed.
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use crate::iter::ScanDecision;
+
+/// A table of expiry deadlines keyed by prefix.
+///
+/// A key is governed by the longest prefix in the table that it starts with, so
+/// a rule on a longer prefix takes precedence over the rules on the prefixes
+/// containing it, whichever deadline is later. Prefixes are kept in a
+/// `BTreeMap` so that the governing rule is found with ordered range probes
+/// instead of one lookup per prefix length of the key.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ExpiryTable {
+    rules: BTreeMap<Vec<u8>, u64>,
+}
+
+impl ExpiryTable {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Sets the deadline of `prefix`, returning the deadline it replaces.
+    pub(crate) fn set(&mut self, prefix: &[u8], deadline: u64) -> Option<u64> {
+        self.rules.insert(prefix.to_vec(), deadline)
+    }
+
+    /// Removes the rule on `prefix`, returning its deadline.
+    pub(crate) fn cancel(&mut self, prefix: &[u8]) -> Option<u64> {
+        self.rules.remove(prefix)
+    }
+
+    /// Returns the rules as `(prefix, deadline)` pairs, ordered by prefix.
+    pub(crate) fn rules(&self) -> impl Iterator<Item = (&[u8], u64)> {
+        self.rules
+            .iter()
+            .map(|(prefix, deadline)| (prefix.as_slice(), *deadline))
+    }
+
+    /// Drops the rules whose deadline is at or before `now` and for which
+    /// `keep` returns false, returning how many were dropped.
+    pub(crate) fn drop_expired<F: Fn(&[u8]) -> bool>(&mut self, now: u64, keep: F) -> usize {
+        let before = self.rules.len();
+        self.rules
+            .retain(|prefix, deadline| *deadline > now || keep(prefix));
+        before - self.rules.len()
+    }
+
+    /// Returns the deadline of the rule governing `key`: the longest prefix of
+    /// `key` in the table.
+    pub(crate) fn deadline(&self, key: &[u8]) -> Option<u64> {
+        // The greatest rule not above `key` is its longest prefix in the table
+        // if it is a prefix at all. Otherwise every prefix of `key` in the table
+        // is also a prefix of what the two have in common, so the probe moves
+        // there.
+        let mut bound = key;
+        loop {
+            let (prefix, deadline) = self
+                .rules
+                .range::<[u8], _>((Bound::Unbounded, Bound::Included(bound)))
+                .next_back()?;
+            if key.starts_with(prefix) {
+                return Some(*deadline);
+            }
+            let common = prefix.iter().zip(bound).take_while(|(a, b)| a == b).count();
+            bound = &key[..common];
+        }
+    }
+
+    /// Returns true if `key` is governed by a rule whose deadline is at or
+    /// before `now`.
+    #[inline]
+    pub(crate) fn is_expired(&self, key: &[u8], now: u64) -> bool {
+        !self.is_empty() && self.deadline(key).is_some_and(|deadline| deadline <= now)
+    }
+
+    /// Returns true if a rule sits strictly below `prefix`.
+    fn has_nested(&self, prefix: &[u8]) -> bool {
+        self.rules
+            .range::<[u8], _>((Bound::Excluded(prefix), Bound::Unbounded))
+            .next()
+            .is_some_and(|(nested, _)| nested.starts_with(prefix))
+    }
+
+    /// Decides how a scan treats the keys strictly below the inner node at
+    /// `path`: skipped if they are all expired, yielded if none of them are,
+    /// and decided further down if a rule below `path` may govern some of them.
+    pub(crate) fn decide_subtree(&self, path: &[u8], now: u64) -> ScanDecision {
+        if self.has_nested(path) {
+            ScanDecision::Descend
+        } else if self.is_expired(path, now) {
+            ScanDecision::SkipSubtree
+        } else {
+            ScanDecision::Yield
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ExpiryTable;
+    use crate::iter::ScanDecision;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn deadline_follows_the_longest_prefix() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let word = |rng: &mut StdRng, max: usize| -> Vec<u8> {
+            let len = rng.gen_range(0..=max);
+            (0..len).map(|_| rng.gen_range(b'a'..=b'c')).collect()
+        };
+        for _ in 0..50 {
+            let mut table = ExpiryTable::new();
+            for deadline in 0..rng.gen_range(0..10) {
+                table.set(&word(&mut rng, 4), deadline);
+            }
+            for _ in 0..50 {
+                let key = word(&mut rng, 6);
+                let expected = (0..=key.len())
+                    .rev()
+                    .find_map(|len| table.rules.get(&key[..len]).copied());
+                assert_eq!(table.deadline(&key), expected, "{:?}", key);
+            }
+        }
+
+        let mut table = ExpiryTable::new();
+        table.set(b"sess/", 10);
+        table.set(b"sess/1/keep/", 20);
+        assert_eq!(table.decide_subtree(b"sess/1", 15), ScanDecision::Descend);
+        assert_eq!(
+            table.decide_subtree(b"sess/2", 15),
+            ScanDecision::SkipSubtree
+        );
+        assert_eq!(
+            table.decide_subtree(b"sess/1/keep/a", 15),
+            ScanDecision::Yield
+        );
+        assert_eq!(table.decide_subtree(b"other", 15), ScanDecision::Yield);
+        assert_eq!(table.drop_expired(15, |_| false), 1);
+        assert_eq!(table.cancel(b"sess/1/keep/"), Some(20));
+        assert!(table.is_empty());
+    }
+}
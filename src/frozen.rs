@@ -0,0 +1,820 @@
+//! This module defines a read-only tree that is read in place from a flat byte
+//! buffer, such as a memory-mapped file, so that opening it takes constant time
+//! however many keys it holds.
+//!
+//! The buffer is position independent: it refers to its own parts by offsets
+//! from its start, never by address. It holds a header, a table with the offset
+//! of every entry in key order, and the entries themselves:
+//!
+//! ```text
+//! header:  magic (8 bytes) | entry count (u64) | tree version (u64)
+//! table:   entry offset (u64), one per entry, in key order
+//! options: pressure window (u64) | pressure medium (f64) | pressure high (f64)
+//!          | presence flags (u16) | prefix stats delimiter (u8)
+//!          | duplicate ts policy (u8) | version warn threshold (u64)
+//!          | read frequency depth (u64) | cow batch window (u64)
+//!          | invariant checks (u8) | max valid ts (u64)
+//!          | ingest zero ts (u8) | ingest on violation (u8)
+//!          | ts domain delimiter (u8) | versioning strategy (u8)
+//!          | versions kept (u64) | forced node type (u8)
+//!          | key normalization (u8) | max active snapshots (u64)
+//! rules:   rule count (u64) | rule, one per expiry rule, in prefix order
+//! rule:    prefix length (u32) | prefix | deadline ts (u64)
+//! domains: domain count (u64) | domain, one per ts domain, in segment order
+//! domain:  segment length (u32) | segment | latest ts (u64)
+//! entry:   key length (u32) | key | version (u64) | ts (u64)
+//!          | value length (u32) | value
+//! ```
+//!
+//! The options are those of the Tree the buffer was frozen from, in a section
+//! of fixed length where an optional setting takes its slot whether it is set
+//! or not, as told by the presence flags. Buffers written before options,
+//! expiry rules, timestamp domains, versioning strategies or the options of
+//! the node layout were kept lack the
+//! later sections, and the last slots of the options section, and are told
+//! apart by the last byte of their magic.
+//!
+//! All integers are little-endian. Lookups binary search the table, and scans
+//! walk it, decoding only the entries they return. Every access is bounds
+//! checked, so a corrupt or truncated buffer produces errors rather than
+//! undefined behavior or panics. A buffer whose entries are out of order reads
+//! wrong results but stays safe, and `Tree::thaw` rejects it.
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+use crate::art::{NodeKind, DEFAULT_MAX_ACTIVE_SNAPSHOTS};
+use crate::codec::{DecodeError, ValueCodec};
+use crate::ingest::{IngestPolicy, OnViolation, ZeroTs};
+use crate::normalize::KeyNormalization;
+use crate::pressure::{DuplicateTsPolicy, TreeOptions, VersioningStrategy};
+use crate::strict::InvariantChecks;
+
+const MAGIC: [u8; 8] = *b"VARTFRZ\x06";
+// The magic of buffers without the node layout options in their options section.
+const MAGIC_V5: [u8; 8] = *b"VARTFRZ\x05";
+// The magic of buffers without a versioning strategy in their options section.
+const MAGIC_V4: [u8; 8] = *b"VARTFRZ\x04";
+// The magic of buffers without a domains section.
+const MAGIC_V3: [u8; 8] = *b"VARTFRZ\x03";
+// The magic of buffers without an options section.
+const MAGIC_V2: [u8; 8] = *b"VARTFRZ\x02";
+// The magic of buffers without an options or rules section.
+const MAGIC_V1: [u8; 8] = *b"VARTFRZ\x01";
+const HEADER_LEN: usize = 24;
+const OPTIONS_LEN: usize = 83;
+// The length of the options section of buffers without the node layout options.
+const OPTIONS_LEN_V5: usize = 73;
+// The length of the options section of buffers without a versioning strategy.
+const OPTIONS_LEN_V4: usize = 64;
+// The length of the options section of buffers without a domains section.
+const OPTIONS_LEN_V3: usize = 63;
+
+/// An error opening or reading a frozen tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OpenError {
+    /// The buffer does not start with the header of a frozen tree, or was written
+    /// in an unsupported version of the format.
+    BadHeader,
+    /// A length or offset read at `offset` points outside the buffer.
+    OutOfBounds { offset: usize },
+    /// The value of the entry at `index` cannot be decoded.
+    Value { index: usize, error: DecodeError },
+    /// The key of the entry at `index` does not sort after the key before it.
+    Unsorted { index: usize },
+    /// The options section at `offset` holds options no Tree can run with.
+    BadOptions { offset: usize },
+}
+
+impl Error for OpenError {}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpenError::BadHeader => write!(f, "Buffer is not a frozen tree"),
+            OpenError::OutOfBounds { offset } => {
+                write!(f, "Frozen tree data at offset {} is out of bounds", offset)
+            }
+            OpenError::Value { index, error } => {
+                write!(f, "Cannot decode the value of entry {}: {}", index, error)
+            }
+            OpenError::Unsorted { index } => {
+                write!(f, "Frozen tree entry {} is out of key order", index)
+            }
+            OpenError::BadOptions { offset } => {
+                write!(f, "Frozen tree options at offset {} are invalid", offset)
+            }
+        }
+    }
+}
+
+/// Writes the entries, the expiry rules and the timestamp domains, all given in
+/// key order, and the options of the Tree in the frozen tree format.
+pub(crate) fn write<'a, 'r, 'd, V: ValueCodec + 'a>(
+    entries: impl Iterator<Item = (Vec<u8>, &'a V, u64, u64)>,
+    version: u64,
+    rules: impl Iterator<Item = (&'r [u8], u64)>,
+    domains: impl Iterator<Item = (&'d [u8], u64)>,
+    options: &TreeOptions,
+) -> Vec<u8> {
+    let mut table = Vec::new();
+    let mut rule_count = 0u64;
+    let mut data = Vec::new();
+    write_options(&mut data, options);
+    data.extend_from_slice(&0u64.to_le_bytes());
+    for (prefix, deadline) in rules {
+        data.extend_from_slice(&(prefix.len() as u32).to_le_bytes());
+        data.extend_from_slice(prefix);
+        data.extend_from_slice(&deadline.to_le_bytes());
+        rule_count += 1;
+    }
+    data[OPTIONS_LEN..OPTIONS_LEN + 8].copy_from_slice(&rule_count.to_le_bytes());
+    let domain_count_at = data.len();
+    let mut domain_count = 0u64;
+    data.extend_from_slice(&0u64.to_le_bytes());
+    for (segment, ts) in domains {
+        data.extend_from_slice(&(segment.len() as u32).to_le_bytes());
+        data.extend_from_slice(segment);
+        data.extend_from_slice(&ts.to_le_bytes());
+        domain_count += 1;
+    }
+    data[domain_count_at..domain_count_at + 8].copy_from_slice(&domain_count.to_le_bytes());
+    let mut value = Vec::new();
+    for (key, v, version, ts) in entries {
+        table.push(data.len());
+        data.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        data.extend_from_slice(&key);
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(&ts.to_le_bytes());
+        value.clear();
+        v.encode_value(&mut value);
+        data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        data.extend_from_slice(&value);
+    }
+
+    let data_start = HEADER_LEN + table.len() * 8;
+    let mut out = Vec::with_capacity(data_start + data.len());
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&(table.len() as u64).to_le_bytes());
+    out.extend_from_slice(&version.to_le_bytes());
+    for offset in table {
+        out.extend_from_slice(&((data_start + offset) as u64).to_le_bytes());
+    }
+    out.extend_from_slice(&data);
+    out
+}
+
+// The bits of the presence flags of the options section.
+const HAS_PREFIX_STATS: u16 = 1;
+const SUFFIX_INDEX: u16 = 1 << 1;
+const HAS_VERSION_WARN: u16 = 1 << 2;
+const HAS_READ_FREQUENCY: u16 = 1 << 3;
+const COUNT_SNAPSHOT_READS: u16 = 1 << 4;
+const HAS_COW_WINDOW: u16 = 1 << 5;
+const HAS_INVARIANT_CHECKS: u16 = 1 << 6;
+const HAS_MAX_VALID_TS: u16 = 1 << 7;
+const HAS_INGEST_POLICY: u16 = 1 << 8;
+const HAS_TS_DOMAINS: u16 = 1 << 9;
+const HASH_INDEX: u16 = 1 << 10;
+const CACHED_COUNTS: u16 = 1 << 11;
+const HAS_FORCED_NODE_TYPE: u16 = 1 << 12;
+const HAS_KEY_NORMALIZATION: u16 = 1 << 13;
+const VALUE_CHECKSUMS: u16 = 1 << 14;
+
+// The node types in the order of their codes in the options section.
+const NODE_KINDS: [NodeKind; 5] = [
+    NodeKind::Node1,
+    NodeKind::Node4,
+    NodeKind::Node16,
+    NodeKind::Node48,
+    NodeKind::Node256,
+];
+
+fn write_options(out: &mut Vec<u8>, options: &TreeOptions) {
+    let flag = |set: bool, bit: u16| if set { bit } else { 0 };
+    let flags = flag(options.prefix_stats_delimiter.is_some(), HAS_PREFIX_STATS)
+        | flag(options.suffix_index, SUFFIX_INDEX)
+        | flag(options.version_warn_threshold.is_some(), HAS_VERSION_WARN)
+        | flag(options.read_frequency_depth.is_some(), HAS_READ_FREQUENCY)
+        | flag(options.count_snapshot_reads, COUNT_SNAPSHOT_READS)
+        | flag(options.cow_batch_window.is_some(), HAS_COW_WINDOW)
+        | flag(options.invariant_checks.is_some(), HAS_INVARIANT_CHECKS)
+        | flag(options.max_valid_ts.is_some(), HAS_MAX_VALID_TS)
+        | flag(options.ingest_policy.is_some(), HAS_INGEST_POLICY)
+        | flag(options.ts_domain_delimiter.is_some(), HAS_TS_DOMAINS)
+        | flag(options.hash_index, HASH_INDEX)
+        | flag(options.cached_counts, CACHED_COUNTS)
+        | flag(options.forced_node_type.is_some(), HAS_FORCED_NODE_TYPE)
+        | flag(options.key_normalization.is_some(), HAS_KEY_NORMALIZATION)
+        | flag(options.value_checksums, VALUE_CHECKSUMS);
+    let checks = options.invariant_checks.unwrap_or(InvariantChecks::none());
+    let ingest = options.ingest_policy.unwrap_or_default();
+
+    out.extend_from_slice(&options.pressure_window.to_le_bytes());
+    out.extend_from_slice(&options.pressure_medium.to_le_bytes());
+    out.extend_from_slice(&options.pressure_high.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.push(options.prefix_stats_delimiter.unwrap_or(0));
+    out.push(match options.duplicate_ts_policy {
+        DuplicateTsPolicy::Reject => 0,
+        DuplicateTsPolicy::Replace => 1,
+        DuplicateTsPolicy::Stack => 2,
+    });
+    for count in [
+        options.version_warn_threshold,
+        options.read_frequency_depth,
+        options.cow_batch_window,
+    ] {
+        out.extend_from_slice(&(count.unwrap_or(0) as u64).to_le_bytes());
+    }
+    out.push(
+        checks.version_order as u8
+            | (checks.child_bytes as u8) << 1
+            | (checks.prefix_lengths as u8) << 2,
+    );
+    out.extend_from_slice(&options.max_valid_ts.unwrap_or(0).to_le_bytes());
+    out.push(match ingest.zero_ts {
+        ZeroTs::Reject => 0,
+        ZeroTs::Assign => 1,
+    });
+    out.push(match ingest.on_violation {
+        OnViolation::Reject => 0,
+        OnViolation::Renumber => 1,
+    });
+    out.push(options.ts_domain_delimiter.unwrap_or(0));
+    let (strategy, n) = match options.versioning {
+        VersioningStrategy::KeepAll => (0, 0),
+        VersioningStrategy::KeepLatest => (1, 0),
+        VersioningStrategy::KeepN(n) => (2, n as u64),
+    };
+    out.push(strategy);
+    out.extend_from_slice(&n.to_le_bytes());
+    let forced = options.forced_node_type.unwrap_or(NodeKind::Node1);
+    out.push(NODE_KINDS.iter().position(|kind| *kind == forced).unwrap() as u8);
+    out.push(match options.key_normalization {
+        None | Some(KeyNormalization::AsciiLowercase) => 0,
+    });
+    out.extend_from_slice(&options.max_active_snapshots.to_le_bytes());
+}
+
+/// Reads the options section of `len` bytes starting at `start`.
+fn read_options(bytes: &[u8], start: usize, len: usize) -> Result<TreeOptions, OpenError> {
+    let section = start
+        .checked_add(len)
+        .and_then(|end| bytes.get(start..end))
+        .ok_or(OpenError::OutOfBounds { offset: start })?;
+    let bad = OpenError::BadOptions { offset: start };
+    let u64_at = |at: usize| u64::from_le_bytes(section[at..at + 8].try_into().unwrap());
+    let usize_at = |at: usize| usize::try_from(u64_at(at)).map_err(|_| bad.clone());
+    let flags = u16::from_le_bytes(section[24..26].try_into().unwrap());
+    let has = |bit: u16| flags & bit != 0;
+    let checks = section[52];
+
+    let options = TreeOptions {
+        pressure_window: u64_at(0),
+        pressure_medium: f64::from_bits(u64_at(8)),
+        pressure_high: f64::from_bits(u64_at(16)),
+        prefix_stats_delimiter: has(HAS_PREFIX_STATS).then_some(section[26]),
+        suffix_index: has(SUFFIX_INDEX),
+        duplicate_ts_policy: match section[27] {
+            0 => DuplicateTsPolicy::Reject,
+            1 => DuplicateTsPolicy::Replace,
+            2 => DuplicateTsPolicy::Stack,
+            _ => return Err(bad),
+        },
+        version_warn_threshold: has(HAS_VERSION_WARN).then(|| usize_at(28)).transpose()?,
+        read_frequency_depth: has(HAS_READ_FREQUENCY).then(|| usize_at(36)).transpose()?,
+        count_snapshot_reads: has(COUNT_SNAPSHOT_READS),
+        cow_batch_window: has(HAS_COW_WINDOW).then(|| usize_at(44)).transpose()?,
+        invariant_checks: has(HAS_INVARIANT_CHECKS).then_some(InvariantChecks {
+            version_order: checks & 1 != 0,
+            child_bytes: checks & 1 << 1 != 0,
+            prefix_lengths: checks & 1 << 2 != 0,
+        }),
+        max_valid_ts: has(HAS_MAX_VALID_TS).then(|| u64_at(53)),
+        ingest_policy: match (section[61], section[62]) {
+            _ if !has(HAS_INGEST_POLICY) => None,
+            (zero_ts @ 0..=1, on_violation @ 0..=1) => Some(IngestPolicy {
+                zero_ts: [ZeroTs::Reject, ZeroTs::Assign][zero_ts as usize],
+                on_violation: [OnViolation::Reject, OnViolation::Renumber][on_violation as usize],
+            }),
+            _ => return Err(bad),
+        },
+        ts_domain_delimiter: match section.get(63) {
+            Some(delimiter) if has(HAS_TS_DOMAINS) => Some(*delimiter),
+            _ => None,
+        },
+        versioning: match section.get(64) {
+            None | Some(0) => VersioningStrategy::KeepAll,
+            Some(1) => VersioningStrategy::KeepLatest,
+            Some(2) => VersioningStrategy::KeepN(usize_at(65)?),
+            Some(_) => return Err(bad),
+        },
+        hash_index: has(HASH_INDEX),
+        cached_counts: has(CACHED_COUNTS),
+        forced_node_type: match section.get(73) {
+            Some(kind) if has(HAS_FORCED_NODE_TYPE) => {
+                Some(*NODE_KINDS.get(*kind as usize).ok_or(bad.clone())?)
+            }
+            _ => None,
+        },
+        key_normalization: match section.get(74) {
+            Some(0) if has(HAS_KEY_NORMALIZATION) => Some(KeyNormalization::AsciiLowercase),
+            Some(_) if has(HAS_KEY_NORMALIZATION) => return Err(bad),
+            _ => None,
+        },
+        max_active_snapshots: match section.len() {
+            OPTIONS_LEN => u64_at(75),
+            _ => DEFAULT_MAX_ACTIVE_SNAPSHOTS,
+        },
+        value_checksums: has(VALUE_CHECKSUMS),
+    };
+    // Options that would not validate can only come from a corrupt buffer.
+    options.validate().map_err(|_| bad)?;
+    Ok(options)
+}
+
+/// A read-only tree over a buffer written by `Tree::freeze`.
+///
+/// Opening the tree only checks the header; entries are located and their
+/// values decoded when a read reaches them. Reads return the latest value of
+/// each key along with its version and timestamp, and fail with an `OpenError`
+/// where the buffer turns out to be corrupt.
+pub struct FrozenTree<'a, V> {
+    bytes: &'a [u8],
+    len: usize,
+    version: u64,
+    // The offset and length of the options section, if the buffer has one.
+    options: Option<(usize, usize)>,
+    // The offset of the rules section, if the buffer has one.
+    rules: Option<usize>,
+    // Whether the rules section is followed by a domains section.
+    domains: bool,
+    _marker: PhantomData<fn() -> V>,
+}
+
+/// An entry of a frozen tree, with its value still encoded.
+struct RawEntry<'a> {
+    key: &'a [u8],
+    version: u64,
+    ts: u64,
+    value: &'a [u8],
+}
+
+impl<'a, V: ValueCodec> FrozenTree<'a, V> {
+    /// Opens the frozen tree stored in `bytes`.
+    ///
+    /// This checks the header and that the offset table fits in the buffer, and
+    /// takes the same time whatever the size of the tree.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OpenError::BadHeader` if the buffer does not hold a frozen tree,
+    /// or `OpenError::OutOfBounds` if it is too short for its offset table.
+    pub fn open(bytes: &'a [u8]) -> Result<Self, OpenError> {
+        let magics = [MAGIC, MAGIC_V5, MAGIC_V4, MAGIC_V3, MAGIC_V2, MAGIC_V1];
+        if bytes.len() < HEADER_LEN || !magics.iter().any(|m| bytes[..8] == *m) {
+            return Err(OpenError::BadHeader);
+        }
+        let options_len = match &bytes[..8] {
+            magic if *magic == MAGIC_V5 => OPTIONS_LEN_V5,
+            magic if *magic == MAGIC_V4 => OPTIONS_LEN_V4,
+            magic if *magic == MAGIC_V3 => OPTIONS_LEN_V3,
+            _ => OPTIONS_LEN,
+        };
+        let has_options = [MAGIC, MAGIC_V5, MAGIC_V4, MAGIC_V3]
+            .iter()
+            .any(|m| bytes[..8] == *m);
+        let len = read_u64(bytes, 8)?;
+        let version = read_u64(bytes, 16)?;
+        let table_end = usize::try_from(len)
+            .ok()
+            .and_then(|len| len.checked_mul(8))
+            .and_then(|table| table.checked_add(HEADER_LEN))
+            .filter(|&end| end <= bytes.len())
+            .ok_or(OpenError::OutOfBounds { offset: 8 })?;
+        Ok(FrozenTree {
+            bytes,
+            len: (table_end - HEADER_LEN) / 8,
+            version,
+            options: has_options.then_some((table_end, options_len)),
+            rules: match has_options {
+                true => Some(table_end + options_len),
+                false => (bytes[..8] == MAGIC_V2).then_some(table_end),
+            },
+            domains: [MAGIC, MAGIC_V5, MAGIC_V4].iter().any(|m| bytes[..8] == *m),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Returns the number of keys in the tree.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the tree holds no keys.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the version of the Tree the frozen tree was written from.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns the expiry rules of the Tree the frozen tree was written from, as
+    /// `(prefix, deadline_ts)` pairs ordered by prefix.
+    ///
+    /// The frozen tree holds the keys that were visible when it was written, and
+    /// does not apply the rules to its own reads.
+    pub fn expiry_rules(&self) -> Result<Vec<(Vec<u8>, u64)>, OpenError> {
+        match self.rules {
+            Some(start) => read_pairs(self.bytes, start).map(|(rules, _)| rules),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Returns the timestamp domains of the Tree the frozen tree was written
+    /// from, as `(segment, ts)` pairs ordered by segment.
+    ///
+    /// The buffer keeps the counters of the domains, which may be ahead of the
+    /// latest timestamps of their keys. Buffers written before domains were
+    /// kept have none.
+    pub fn ts_domains(&self) -> Result<Vec<(Vec<u8>, u64)>, OpenError> {
+        let Some(start) = self.rules.filter(|_| self.domains) else {
+            return Ok(Vec::new());
+        };
+        let (_, end) = read_pairs(self.bytes, start)?;
+        read_pairs(self.bytes, end).map(|(domains, _)| domains)
+    }
+
+    /// Returns the options of the Tree the frozen tree was written from, or
+    /// `None` if the buffer was written before options were kept.
+    pub fn options(&self) -> Result<Option<TreeOptions>, OpenError> {
+        self.options
+            .map(|(start, len)| read_options(self.bytes, start, len))
+            .transpose()
+    }
+
+    /// Returns the latest value of `key` with its version and timestamp, or
+    /// `None` if the key is not in the tree.
+    pub fn get(&self, key: &[u8]) -> Result<Option<(V, u64, u64)>, OpenError> {
+        let index = self.lower_bound(key, false)?;
+        if index == self.len {
+            return Ok(None);
+        }
+        let entry = self.raw_entry(index)?;
+        if entry.key != key {
+            return Ok(None);
+        }
+        let value = decode(index, entry.value)?;
+        Ok(Some((value, entry.version, entry.ts)))
+    }
+
+    /// Returns an iterator over the entries of the tree in key order.
+    pub fn iter(&self) -> FrozenIter<'a, V> {
+        FrozenIter::new(self, 0, self.len)
+    }
+
+    /// Returns an iterator over the entries whose keys fall within `range`, in
+    /// key order.
+    pub fn range<'k, R: RangeBounds<&'k [u8]>>(
+        &self,
+        range: R,
+    ) -> Result<FrozenIter<'a, V>, OpenError> {
+        let start = match range.start_bound() {
+            Bound::Included(key) => self.lower_bound(key, false)?,
+            Bound::Excluded(key) => self.lower_bound(key, true)?,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => self.lower_bound(key, true)?,
+            Bound::Excluded(key) => self.lower_bound(key, false)?,
+            Bound::Unbounded => self.len,
+        };
+        Ok(FrozenIter::new(self, start, end.max(start)))
+    }
+
+    /// Returns an iterator over the entries whose keys start with `prefix`, in
+    /// key order.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<FrozenIter<'a, V>, OpenError> {
+        let start = self.lower_bound(prefix, false)?;
+        // The keys with the prefix end before the first key greater than every
+        // extension of it: the prefix with its last byte below 0xFF incremented.
+        let end = match prefix.iter().rposition(|&byte| byte != 0xFF) {
+            Some(pos) => {
+                let mut upper = prefix[..=pos].to_vec();
+                upper[pos] += 1;
+                self.lower_bound(&upper, false)?
+            }
+            None => self.len,
+        };
+        Ok(FrozenIter::new(self, start, end.max(start)))
+    }
+
+    /// Returns the index of the first entry whose key is not less than `key`, or
+    /// greater than `key` if `after` is set.
+    fn lower_bound(&self, key: &[u8], after: bool) -> Result<usize, OpenError> {
+        let (mut lo, mut hi) = (0, self.len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let probe = self.raw_entry(mid)?.key;
+            if probe < key || (after && probe == key) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(lo)
+    }
+
+    /// Locates the entry at `index`, which must be below `len`.
+    fn raw_entry(&self, index: usize) -> Result<RawEntry<'a>, OpenError> {
+        let bytes = self.bytes;
+        let slot = HEADER_LEN + index * 8;
+        let mut offset = usize::try_from(read_u64(bytes, slot)?)
+            .map_err(|_| OpenError::OutOfBounds { offset: slot })?;
+        let key = read_slice(bytes, &mut offset)?;
+        let version = read_u64(bytes, offset)?;
+        let ts = read_u64(bytes, offset + 8)?;
+        offset += 16;
+        let value = read_slice(bytes, &mut offset)?;
+        Ok(RawEntry {
+            key,
+            version,
+            ts,
+            value,
+        })
+    }
+}
+
+// Reads a section of `(bytes, u64)` pairs at `start`, such as the expiry rules,
+// and returns them along with the offset past the section.
+#[allow(clippy::type_complexity)]
+fn read_pairs(bytes: &[u8], start: usize) -> Result<(Vec<(Vec<u8>, u64)>, usize), OpenError> {
+    let count = read_u64(bytes, start)?;
+    let mut offset = start + 8;
+    let mut pairs = Vec::new();
+    for _ in 0..count {
+        let slice = read_slice(bytes, &mut offset)?;
+        pairs.push((slice.to_vec(), read_u64(bytes, offset)?));
+        offset += 8;
+    }
+    Ok((pairs, offset))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, OpenError> {
+    offset
+        .checked_add(8)
+        .and_then(|end| bytes.get(offset..end))
+        .map(|raw| u64::from_le_bytes(raw.try_into().unwrap()))
+        .ok_or(OpenError::OutOfBounds { offset })
+}
+
+// Reads a slice prefixed with its u32 length at `*offset`, and moves `offset`
+// past it.
+fn read_slice<'a>(bytes: &'a [u8], offset: &mut usize) -> Result<&'a [u8], OpenError> {
+    let start = *offset;
+    let len = offset
+        .checked_add(4)
+        .and_then(|end| bytes.get(start..end))
+        .map(|raw| u32::from_le_bytes(raw.try_into().unwrap()) as usize)
+        .ok_or(OpenError::OutOfBounds { offset: start })?;
+    let slice = (start + 4)
+        .checked_add(len)
+        .and_then(|end| bytes.get(start + 4..end))
+        .ok_or(OpenError::OutOfBounds { offset: start })?;
+    *offset = start + 4 + len;
+    Ok(slice)
+}
+
+fn decode<V: ValueCodec>(index: usize, bytes: &[u8]) -> Result<V, OpenError> {
+    V::decode_value(bytes).map_err(|error| OpenError::Value { index, error })
+}
+
+/// An iterator over the entries of a frozen tree.
+///
+/// Keys are borrowed from the buffer, and values are decoded as they are
+/// returned. The iterator ends after the first error it returns.
+pub struct FrozenIter<'a, V> {
+    tree: FrozenTree<'a, V>,
+    next: usize,
+    end: usize,
+}
+
+impl<'a, V: ValueCodec> FrozenIter<'a, V> {
+    fn new(tree: &FrozenTree<'a, V>, next: usize, end: usize) -> Self {
+        FrozenIter {
+            tree: FrozenTree {
+                bytes: tree.bytes,
+                len: tree.len,
+                version: tree.version,
+                options: tree.options,
+                rules: tree.rules,
+                domains: tree.domains,
+                _marker: PhantomData,
+            },
+            next,
+            end,
+        }
+    }
+}
+
+impl<'a, V: ValueCodec> Iterator for FrozenIter<'a, V> {
+    type Item = Result<(&'a [u8], V, u64, u64), OpenError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.end {
+            return None;
+        }
+        let index = self.next;
+        let entry = self.tree.raw_entry(index).and_then(|entry| {
+            Ok((
+                entry.key,
+                decode(index, entry.value)?,
+                entry.version,
+                entry.ts,
+            ))
+        });
+        self.next = if entry.is_ok() { index + 1 } else { self.end };
+        Some(entry)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.end - self.next))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrozenTree, OpenError};
+    use crate::art::Tree;
+    use crate::VariableSizeKey;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn random_key(rng: &mut StdRng) -> Vec<u8> {
+        // A small alphabet makes keys share prefixes.
+        let len = rng.gen_range(1..8);
+        (0..len).map(|_| rng.gen_range(b'a'..=b'd')).collect()
+    }
+
+    fn random_tree(seed: u64) -> Tree<VariableSizeKey, String> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut tree = Tree::new();
+        for i in 0..rng.gen_range(0..300) {
+            let key = VariableSizeKey::from_slice_with_termination(&random_key(&mut rng));
+            tree.insert(&key, format!("value{}", i), 0, rng.gen())
+                .unwrap();
+        }
+        tree
+    }
+
+    fn entries<'a>(
+        iter: impl Iterator<Item = Result<(&'a [u8], String, u64, u64), OpenError>>,
+    ) -> Vec<(Vec<u8>, String, u64, u64)> {
+        iter.map(|entry| {
+            let (key, value, version, ts) = entry.unwrap();
+            (key.to_vec(), value, version, ts)
+        })
+        .collect()
+    }
+
+    #[test]
+    fn frozen_reads_match_the_tree() {
+        for seed in 0..20 {
+            let tree = random_tree(seed);
+            let bytes = tree.freeze();
+            let frozen = FrozenTree::<String>::open(&bytes).unwrap();
+            assert_eq!(frozen.len(), tree.iter().count());
+            assert_eq!(frozen.version(), tree.version());
+
+            let expected: Vec<_> = tree
+                .iter()
+                .map(|(key, value, version, ts)| (key, value.clone(), *version, *ts))
+                .collect();
+            assert_eq!(entries(frozen.iter()), expected);
+
+            let mut rng = StdRng::seed_from_u64(seed + 100);
+            for _ in 0..50 {
+                let key = VariableSizeKey::from_slice_with_termination(&random_key(&mut rng));
+                let found = frozen.get(key.to_slice()).unwrap();
+                let want = tree
+                    .get(&key, 0)
+                    .ok()
+                    .map(|(_, v, version, ts)| (v, version, ts));
+                assert_eq!(found, want);
+
+                // Prefix scans take raw bytes, without the terminator.
+                let prefix = &key.to_slice()[..rng.gen_range(0..key.to_slice().len())];
+                let scanned = entries(frozen.scan_prefix(prefix).unwrap());
+                let want: Vec<_> = expected
+                    .iter()
+                    .filter(|(k, ..)| k.starts_with(prefix))
+                    .cloned()
+                    .collect();
+                assert_eq!(scanned, want);
+
+                let other = VariableSizeKey::from_slice_with_termination(&random_key(&mut rng));
+                let (lo, hi) = if key <= other {
+                    (key, other)
+                } else {
+                    (other, key)
+                };
+                let ranged = entries(frozen.range(lo.to_slice()..hi.to_slice()).unwrap());
+                let want: Vec<_> = tree
+                    .range(lo..hi)
+                    .map(|(key, value, version, ts)| (key, value.clone(), *version, *ts))
+                    .collect();
+                assert_eq!(ranged, want);
+            }
+
+            let thawed = Tree::<VariableSizeKey, String>::thaw(&frozen).unwrap();
+            assert!(thawed == tree);
+            assert_eq!(thawed.version(), tree.version());
+        }
+    }
+
+    #[test]
+    fn corrupt_buffers_do_not_panic() {
+        let bytes = random_tree(1).freeze();
+        assert_eq!(
+            FrozenTree::<String>::open(&bytes[..10]).err(),
+            Some(OpenError::BadHeader)
+        );
+
+        let mut rng = StdRng::seed_from_u64(5);
+        for round in 0..500 {
+            let mut corrupt = bytes.clone();
+            if round % 2 == 0 {
+                corrupt.truncate(rng.gen_range(24..bytes.len()));
+            } else {
+                for _ in 0..rng.gen_range(1..4) {
+                    let at = rng.gen_range(8..corrupt.len());
+                    corrupt[at] ^= 1 << rng.gen_range(0..8);
+                }
+            }
+            let Ok(frozen) = FrozenTree::<String>::open(&corrupt) else {
+                continue;
+            };
+            let _ = frozen.iter().count();
+            let _ = frozen.get(b"abc\0");
+            let _ = frozen.scan_prefix(b"b").map(|iter| iter.count());
+            let _ = frozen.range(&b"a"[..]..&b"c"[..]).map(|iter| iter.count());
+            let _ = Tree::<VariableSizeKey, String>::thaw(&frozen);
+        }
+    }
+
+    #[test]
+    fn thawed_tree_keeps_the_options() {
+        use crate::art::NodeKind;
+        use crate::ingest::IngestPolicy;
+        use crate::normalize::KeyNormalization;
+        use crate::pressure::{DuplicateTsPolicy, TreeOptions};
+
+        let options = TreeOptions {
+            pressure_window: 64,
+            ..TreeOptions::default()
+        }
+        .track_prefix_stats(b'b')
+        .with_suffix_index()
+        .with_duplicate_ts_policy(DuplicateTsPolicy::Replace)
+        .with_version_warn_threshold(1)
+        .track_read_frequency(3)
+        .count_snapshot_reads()
+        .cow_batch_window(16)
+        .poison_on_corruption(true)
+        .max_valid_ts(u64::MAX - 1)
+        .validate_ingest(IngestPolicy::repair())
+        .ts_domains(b'c')
+        .with_hash_index()
+        .with_counts()
+        .force_node_type(NodeKind::Node16)
+        .normalize_keys(KeyNormalization::AsciiLowercase)
+        .max_active_snapshots(3);
+        let mut tree: Tree<VariableSizeKey, String> = Tree::with_options(options);
+        for (i, key) in ["abc", "abd", "bcd", "ca"].iter().enumerate() {
+            let key = VariableSizeKey::from_slice_with_termination(key.as_bytes());
+            tree.insert(&key, format!("value{}", i), 0, i as u64 + 1)
+                .unwrap();
+        }
+
+        let bytes = tree.freeze();
+        let frozen = FrozenTree::<String>::open(&bytes).unwrap();
+        assert_eq!(frozen.options().unwrap(), Some(options));
+        let thawed = Tree::<VariableSizeKey, String>::thaw(&frozen).unwrap();
+        assert_eq!(thawed.options(), options);
+        thawed.verify_prefix_stats().unwrap();
+        thawed.verify_suffix_index().unwrap();
+        assert_eq!(thawed.overloaded_keys().count(), 4);
+        assert!(thawed == tree);
+        let key = VariableSizeKey::from_slice_with_termination(b"ABD");
+        assert_eq!(thawed.get(&key, 0).unwrap().1, "value1");
+
+        // A Tree with the default options thaws with them.
+        let bytes = random_tree(3).freeze();
+        let frozen = FrozenTree::<String>::open(&bytes).unwrap();
+        let thawed = Tree::<VariableSizeKey, String>::thaw(&frozen).unwrap();
+        assert_eq!(thawed.options(), TreeOptions::default());
+    }
+}
@@ -0,0 +1,376 @@
+//! This module defines the reader gate used by a Snapshot to count its active
+//! readers and refuse to close while any of them is registered.
+//!
+//! The reader count and the closing and closed flags share a single atomic word. Registering
+//! a reader and closing the snapshot are both read-modify-write operations on
+//! that word, and all RMWs on one location are totally ordered, so exactly one
+//! of two racing calls observes the other. A reader that registers after the
+//! snapshot was closed sees the closed bit and backs out again, and `close`
+//! only succeeds on a word that holds no readers at all. The closing bit turns
+//! readers away the same way, while leaving the readers already counted to
+//! drain before the gate closes. Neither outcome needs
+//! a stronger ordering than `Relaxed`; the `Acquire`/`Release` pairs below only
+//! order the accesses readers made to the snapshot before deregistering with
+//! the release of its resources by `close`.
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicU64, Ordering};
+#[cfg(not(loom))]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::TrieError;
+
+// Set in the gate word once the snapshot is closed. The low bits count readers.
+const CLOSED: u64 = 1 << 63;
+// Set in the gate word once closing has begun, turning new readers away.
+const CLOSING: u64 = 1 << 62;
+// The bits of the gate word counting readers.
+const READERS: u64 = CLOSING - 1;
+
+pub(crate) struct ReaderGate {
+    state: AtomicU64,
+}
+
+impl ReaderGate {
+    pub(crate) fn new() -> Self {
+        ReaderGate {
+            state: AtomicU64::new(0),
+        }
+    }
+
+    /// Registers a reader and returns the number of active readers including it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::SnapshotAlreadyClosed` if the gate was closed before
+    /// the reader could register, or `TrieError::SnapshotClosing` if closing had
+    /// begun.
+    pub(crate) fn register(&self) -> Result<u64, TrieError> {
+        // Relaxed: the increment is ordered against `close` and `begin_close` by
+        // the modification order of `state` alone. Readers take their own
+        // reference to the root before they register, so there is nothing for
+        // them to acquire here.
+        let prev = self.state.fetch_add(1, Ordering::Relaxed);
+        if prev & (CLOSED | CLOSING) != 0 {
+            // The gate closed first. Undo the increment; the flags stay set
+            // because the count never drops below what other readers added.
+            self.state.fetch_sub(1, Ordering::Relaxed);
+            return Err(if prev & CLOSED != 0 {
+                TrieError::SnapshotAlreadyClosed
+            } else {
+                TrieError::SnapshotClosing
+            });
+        }
+        Ok((prev & READERS) + 1)
+    }
+
+    /// Turns away every reader registering from now on, and returns the number
+    /// of readers still registered, which `close` waits for.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::SnapshotAlreadyClosed` if the gate is closed.
+    pub(crate) fn begin_close(&self) -> Result<u64, TrieError> {
+        // Relaxed: like `register`, this is ordered against the readers by the
+        // modification order of `state`, so a reader either is in the count
+        // returned or sees the closing bit.
+        let prev = self.state.fetch_or(CLOSING, Ordering::Relaxed);
+        if prev & CLOSED != 0 {
+            return Err(TrieError::SnapshotAlreadyClosed);
+        }
+        Ok(prev & READERS)
+    }
+
+    /// Deregisters a reader. Does nothing if no reader is registered.
+    pub(crate) fn deregister(&self) {
+        // Release: everything the reader did with the snapshot happens-before a
+        // `close` whose Acquire CAS observes the count this decrement produced.
+        let _ = self
+            .state
+            .fetch_update(Ordering::Release, Ordering::Relaxed, |state| {
+                (state & READERS > 0).then(|| state - 1)
+            });
+    }
+
+    /// Returns the number of registered readers.
+    pub(crate) fn active(&self) -> u64 {
+        // Relaxed: the count is a snapshot for reporting and may be stale by the
+        // time it is returned; it synchronizes with nothing.
+        self.state.load(Ordering::Relaxed) & READERS
+    }
+
+    /// Returns true if closing has begun and the gate is not closed yet.
+    pub(crate) fn is_closing(&self) -> bool {
+        // Relaxed: like `active`, this is for reporting only.
+        self.state.load(Ordering::Relaxed) & (CLOSED | CLOSING) == CLOSING
+    }
+
+    /// Returns true if the gate has been closed.
+    pub(crate) fn is_closed(&self) -> bool {
+        // Acquire: the CAS in `close` continues the release sequence of every
+        // Release decrement before it, so a caller that sees the bit also sees
+        // the accesses of the readers that deregistered.
+        self.state.load(Ordering::Acquire) & CLOSED != 0
+    }
+
+    /// Closes the gate if no readers are registered, whether or not closing has
+    /// begun.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::SnapshotAlreadyClosed` if the gate is already closed,
+    /// or `TrieError::SnapshotReadersNotClosed` if readers are still registered.
+    pub(crate) fn close(&self) -> Result<(), TrieError> {
+        // Acquire on success: synchronizes with the Release decrement of every
+        // reader that deregistered, so their accesses happen-before the close.
+        // A reader registering concurrently either lands before the CAS, making
+        // it fail, or after it, and then observes the closed bit.
+        match self
+            .state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+                (state & (CLOSED | READERS) == 0).then_some(CLOSED)
+            }) {
+            Ok(_) => Ok(()),
+            Err(state) if state & CLOSED != 0 => Err(TrieError::SnapshotAlreadyClosed),
+            Err(_) => Err(TrieError::SnapshotReadersNotClosed),
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::ReaderGate;
+    use crate::TrieError;
+
+    #[test]
+    fn close_waits_for_readers() {
+        let gate = ReaderGate::new();
+        assert_eq!(gate.register().unwrap(), 1);
+        assert_eq!(gate.register().unwrap(), 2);
+        assert!(matches!(
+            gate.close(),
+            Err(TrieError::SnapshotReadersNotClosed)
+        ));
+
+        gate.deregister();
+        gate.deregister();
+        // Extra deregistrations do not underflow into the closed bit.
+        gate.deregister();
+        assert_eq!(gate.active(), 0);
+        assert!(!gate.is_closed());
+
+        gate.close().unwrap();
+        assert!(gate.is_closed());
+        assert!(matches!(
+            gate.register(),
+            Err(TrieError::SnapshotAlreadyClosed)
+        ));
+        assert!(matches!(
+            gate.close(),
+            Err(TrieError::SnapshotAlreadyClosed)
+        ));
+        assert_eq!(gate.active(), 0);
+    }
+
+    #[test]
+    fn begin_close_turns_readers_away() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+
+        for _ in 0..20 {
+            let gate = Arc::new(ReaderGate::new());
+            let registered = Arc::new(AtomicU64::new(0));
+            let readers: Vec<_> = (0..4)
+                .map(|_| {
+                    let gate = gate.clone();
+                    let registered = registered.clone();
+                    thread::spawn(move || loop {
+                        match gate.register() {
+                            Ok(_) => {
+                                registered.fetch_add(1, Ordering::Relaxed);
+                                thread::yield_now();
+                            }
+                            Err(err) => {
+                                assert!(matches!(err, TrieError::SnapshotClosing));
+                                return;
+                            }
+                        }
+                    })
+                })
+                .collect();
+            thread::yield_now();
+            let draining = gate.begin_close().unwrap();
+            for reader in readers {
+                reader.join().unwrap();
+            }
+
+            // Every reader that got in was counted by `begin_close`, and none
+            // got in after it.
+            assert_eq!(registered.load(Ordering::Relaxed), draining);
+            assert_eq!(gate.active(), draining);
+            assert!(gate.is_closing());
+            if draining > 0 {
+                assert!(matches!(
+                    gate.close(),
+                    Err(TrieError::SnapshotReadersNotClosed)
+                ));
+            }
+            for _ in 0..draining {
+                gate.deregister();
+            }
+            gate.close().unwrap();
+            assert!(gate.is_closed() && !gate.is_closing());
+            assert!(matches!(
+                gate.begin_close(),
+                Err(TrieError::SnapshotAlreadyClosed)
+            ));
+        }
+    }
+
+    #[test]
+    fn racing_closes_and_reader_closes_stay_consistent() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+
+        for _ in 0..20 {
+            let gate = Arc::new(ReaderGate::new());
+            let closes = Arc::new(AtomicUsize::new(0));
+
+            let readers: Vec<_> = (0..4)
+                .map(|_| {
+                    let gate = gate.clone();
+                    thread::spawn(move || {
+                        // Readers give up after a while, so that closes get a
+                        // chance to find the gate empty.
+                        for _ in 0..1000 {
+                            match gate.register() {
+                                Ok(active) => {
+                                    // While registered the gate cannot close.
+                                    assert!(active >= 1);
+                                    assert!(!gate.is_closed());
+                                    thread::yield_now();
+                                    assert!(!gate.is_closed());
+                                    gate.deregister();
+                                }
+                                Err(err) => {
+                                    assert!(matches!(err, TrieError::SnapshotAlreadyClosed));
+                                    assert!(gate.is_closed());
+                                    return;
+                                }
+                            }
+                        }
+                    })
+                })
+                .collect();
+            let closers: Vec<_> = (0..2)
+                .map(|_| {
+                    let gate = gate.clone();
+                    let closes = closes.clone();
+                    thread::spawn(move || loop {
+                        match gate.close() {
+                            Ok(()) => {
+                                closes.fetch_add(1, Ordering::Relaxed);
+                                return;
+                            }
+                            Err(TrieError::SnapshotAlreadyClosed) => return,
+                            Err(TrieError::SnapshotReadersNotClosed) => thread::yield_now(),
+                            Err(err) => panic!("unexpected error {}", err),
+                        }
+                    })
+                })
+                .collect();
+            for thread in readers.into_iter().chain(closers) {
+                thread.join().unwrap();
+            }
+
+            // Exactly one close won, and every reader that got in has left.
+            assert_eq!(closes.load(Ordering::Relaxed), 1);
+            assert!(gate.is_closed());
+            assert_eq!(gate.active(), 0);
+        }
+    }
+}
+
+// Run with `RUSTFLAGS="--cfg loom" cargo test --release --lib gate::loom_tests`.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::ReaderGate;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    #[test]
+    fn register_races_close() {
+        loom::model(|| {
+            let gate = Arc::new(ReaderGate::new());
+
+            let reader = {
+                let gate = gate.clone();
+                thread::spawn(move || gate.register().is_ok())
+            };
+            let closed = gate.close().is_ok();
+            let registered = reader.join().unwrap();
+
+            // Either the reader got in and close refused, or close won and the
+            // reader was turned away. Never both, and never neither.
+            assert!(registered != closed);
+            assert_eq!(gate.active(), registered as u64);
+            assert_eq!(gate.is_closed(), closed);
+        });
+    }
+
+    #[test]
+    fn close_after_reader_leaves() {
+        loom::model(|| {
+            let gate = Arc::new(ReaderGate::new());
+            gate.register().unwrap();
+
+            let reader = {
+                let gate = gate.clone();
+                thread::spawn(move || gate.deregister())
+            };
+            let closed = gate.close().is_ok();
+            reader.join().unwrap();
+
+            // A reader is never lost: either close saw it leave, or it failed
+            // and the count has since dropped to zero.
+            assert_eq!(gate.active(), 0);
+            assert_eq!(gate.is_closed(), closed);
+            if !closed {
+                gate.close().unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn concurrent_readers_are_counted() {
+        loom::model(|| {
+            let gate = Arc::new(ReaderGate::new());
+
+            let readers: Vec<_> = (0..2)
+                .map(|_| {
+                    let gate = gate.clone();
+                    thread::spawn(move || {
+                        let registered = gate.register().is_ok();
+                        if registered {
+                            gate.deregister();
+                        }
+                        registered
+                    })
+                })
+                .collect();
+            let closed = gate.close().is_ok();
+            for reader in readers {
+                reader.join().unwrap();
+            }
+
+            // Readers turned away by the closed bit leave no trace in the count,
+            // and readers that got in have all left again.
+            assert_eq!(gate.active(), 0);
+            assert_eq!(gate.is_closed(), closed);
+            if !closed {
+                gate.close().unwrap();
+            }
+        });
+    }
+}
@@ -0,0 +1,98 @@
+//! This module defines the optional hash index that lets lookups on a Tree jump
+//! straight to the twig node holding a key instead of descending the trie.
+use std::hash::BuildHasher;
+use std::sync::{Arc, Weak};
+
+use hashbrown::HashMap;
+
+use crate::art::{Node, NodeType};
+use crate::KeyTrait;
+
+type KeyHasher = Box<dyn Fn(&[u8]) -> u64 + Send + Sync>;
+type Bucket<P, V> = Vec<(P, Weak<Node<P, V>>)>;
+
+/// A hash index mapping the hash of a key to the twig node that stores it.
+///
+/// The index holds weak references to the twigs of the tree, so that it does
+/// not keep replaced twigs alive or make the twigs of the tree look shared to
+/// the paths that update nodes in place. It must be refreshed whenever a write
+/// replaces the twig for a key; a twig dropped without a refresh is reported
+/// as stale, for the caller to find it in the tree instead. Keys whose hashes
+/// collide share a bucket and are told apart by comparing the full key.
+pub(crate) struct HashIndex<P: KeyTrait, V: Clone> {
+    hasher: KeyHasher,
+    buckets: HashMap<u64, Bucket<P, V>>,
+}
+
+/// The result of looking a key up in a `HashIndex`.
+pub(crate) enum Indexed<P: KeyTrait, V: Clone> {
+    /// The twig node of the key.
+    Twig(Arc<Node<P, V>>),
+    /// The key is not indexed.
+    Absent,
+    /// The key is indexed, but its twig has been dropped since.
+    Stale,
+}
+
+impl<P: KeyTrait, V: Clone> HashIndex<P, V> {
+    /// Creates an empty index hashing keys with the given hash builder.
+    pub(crate) fn new<S: BuildHasher + Send + Sync + 'static>(hash_builder: S) -> Self {
+        Self {
+            hasher: Box::new(move |key| hash_builder.hash_one(key)),
+            buckets: HashMap::new(),
+        }
+    }
+
+    fn twig_key(node: &Node<P, V>) -> &P {
+        match &node.node_type {
+            NodeType::Twig(twig) => &twig.key,
+            _ => panic!("Unexpected inner node encountered in hash index"),
+        }
+    }
+
+    /// Returns the twig node for `key`, if the key is present.
+    pub(crate) fn get(&self, key: &P) -> Indexed<P, V> {
+        let hash = (self.hasher)(key.as_slice());
+        let entry = self
+            .buckets
+            .get(&hash)
+            .and_then(|bucket| bucket.iter().find(|(k, _)| k == key));
+        match entry {
+            None => Indexed::Absent,
+            Some((_, twig)) => twig.upgrade().map_or(Indexed::Stale, Indexed::Twig),
+        }
+    }
+
+    /// Records `twig` as the current twig node for its key, replacing any older one.
+    pub(crate) fn insert(&mut self, twig: &Arc<Node<P, V>>) {
+        let key = Self::twig_key(twig);
+        let hash = (self.hasher)(key.as_slice());
+        let bucket = self.buckets.entry(hash).or_default();
+        match bucket.iter().position(|(k, _)| k == key) {
+            Some(pos) => bucket[pos].1 = Arc::downgrade(twig),
+            None => bucket.push((key.clone(), Arc::downgrade(twig))),
+        }
+    }
+
+    /// Drops the entry for `key`.
+    pub(crate) fn remove(&mut self, key: &P) {
+        let hash = (self.hasher)(key.as_slice());
+        if let Some(bucket) = self.buckets.get_mut(&hash) {
+            bucket.retain(|(k, _)| k != key);
+            if bucket.is_empty() {
+                self.buckets.remove(&hash);
+            }
+        }
+    }
+
+    /// Drops all entries.
+    pub(crate) fn clear(&mut self) {
+        self.buckets.clear();
+    }
+
+    /// Returns the number of indexed keys.
+    #[allow(dead_code)]
+    pub(crate) fn len(&self) -> usize {
+        self.buckets.values().map(Vec::len).sum()
+    }
+}
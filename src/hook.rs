@@ -0,0 +1,17 @@
+//! This module defines the hook a Tree notifies of its mutations, so that
+//! indexes built outside of it, such as an inverted index from values to keys,
+//! are kept in lockstep with it.
+
+/// Receives every key-value pair a Tree writes or removes.
+///
+/// A Tree built with `Tree::with_index_hook` calls the hook after each
+/// mutation is applied, in the order the mutations are applied, so an index
+/// maintained by the hook reflects the latest value of every key.
+pub trait IndexHook<P, V>: Send + Sync {
+    /// Called after `value` became the latest value of `key`, with the latest
+    /// value it replaced, if the key was present.
+    fn on_insert(&mut self, key: &P, value: &V, old: Option<&V>);
+
+    /// Called after `key` was removed, with the latest value it held.
+    fn on_remove(&mut self, key: &P, value: &V);
+}
@@ -0,0 +1,299 @@
+//! This module validates the timestamps of entries written through the ingest
+//! paths of a Tree, such as `Tree::bulk_insert`, `Tree::bulk_load` and
+//! `Tree::apply_wal`, which take timestamps produced outside of the Tree.
+//!
+//! A malformed timestamp is otherwise stored as is and only shows up later as a
+//! confusing read, so a Tree built with `TreeOptions::validate_ingest` or
+//! `TreeOptions::max_valid_ts` checks each entry before writing it, and either
+//! rejects it or repairs its timestamp.
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::domain::TsDomains;
+use crate::pressure::DuplicateTsPolicy;
+use crate::TrieError;
+
+/// What ingest does with an entry whose timestamp is zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ZeroTs {
+    /// Fail the entry with `TsViolation::Zero`.
+    #[default]
+    Reject,
+    /// Give the entry the timestamp after the latest one seen by the ingest.
+    Assign,
+}
+
+/// What ingest does with an entry whose timestamp does not follow the previous
+/// timestamp of its key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnViolation {
+    /// Fail the entry with `TsViolation::NotIncreasing`.
+    #[default]
+    Reject,
+    /// Renumber the entry to the timestamp after the previous one of its key.
+    Renumber,
+}
+
+/// How ingest paths validate the timestamps of their entries.
+///
+/// The default is strict, rejecting zero timestamps and histories going back
+/// in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IngestPolicy {
+    pub zero_ts: ZeroTs,
+    pub on_violation: OnViolation,
+}
+
+impl IngestPolicy {
+    /// Rejects every entry with a zero or out of order timestamp.
+    pub fn strict() -> Self {
+        IngestPolicy::default()
+    }
+
+    /// Assigns timestamps to the entries with a zero one, and renumbers those
+    /// out of order, as recovery from a damaged log may prefer.
+    pub fn repair() -> Self {
+        IngestPolicy {
+            zero_ts: ZeroTs::Assign,
+            on_violation: OnViolation::Renumber,
+        }
+    }
+}
+
+/// Why the timestamp of an entry was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TsViolation {
+    /// The timestamp is zero, and zero timestamps are rejected.
+    Zero,
+    /// The timestamp, as given or repaired, is above the largest valid one.
+    AboveCeiling { ceiling: u64 },
+    /// The timestamp is below the previous timestamp of the key, or equal to it
+    /// while duplicate timestamps are rejected.
+    NotIncreasing { previous: u64 },
+}
+
+impl fmt::Display for TsViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TsViolation::Zero => write!(f, "timestamp is zero"),
+            TsViolation::AboveCeiling { ceiling } => {
+                write!(f, "timestamp is above the ceiling {}", ceiling)
+            }
+            TsViolation::NotIncreasing { previous } => {
+                write!(f, "timestamp does not follow the previous {}", previous)
+            }
+        }
+    }
+}
+
+/// Checks the timestamps of a stream of entries, key by key.
+///
+/// The validator remembers the latest timestamp of every key it accepts, and
+/// asks for the latest timestamp a key already has the first time it sees it.
+pub struct TsValidator {
+    policy: IngestPolicy,
+    ceiling: u64,
+    allow_equal: bool,
+    // The latest timestamp seen, from which zero timestamps are assigned.
+    clock: u64,
+    // The latest timestamp seen in each domain, if the Tree keeps domains.
+    domains: Option<TsDomains>,
+    latest: HashMap<Vec<u8>, Option<u64>>,
+}
+
+impl TsValidator {
+    /// Creates a validator applying `policy`, accepting timestamps up to
+    /// `max_valid_ts`, and allowing a key to repeat a timestamp unless
+    /// `duplicate_ts_policy` rejects it.
+    pub fn new(
+        policy: IngestPolicy,
+        max_valid_ts: Option<u64>,
+        duplicate_ts_policy: DuplicateTsPolicy,
+    ) -> Self {
+        TsValidator {
+            policy,
+            ceiling: max_valid_ts.unwrap_or(u64::MAX),
+            allow_equal: duplicate_ts_policy != DuplicateTsPolicy::Reject,
+            clock: 0,
+            domains: None,
+            latest: HashMap::new(),
+        }
+    }
+
+    /// Assigns zero timestamps after `ts` at the earliest.
+    pub fn with_clock(mut self, ts: u64) -> Self {
+        self.clock = ts;
+        self
+    }
+
+    /// Assigns zero timestamps of keys in a domain after the counter of their
+    /// domain, rather than after the clock.
+    pub(crate) fn with_domains(mut self, domains: TsDomains) -> Self {
+        self.domains = Some(domains);
+        self
+    }
+
+    /// Checks the timestamp of the entry at `position` of the input, and
+    /// returns the timestamp to write it with.
+    ///
+    /// `existing` returns the latest timestamp `key` already has, and is only
+    /// called the first time the key is seen.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::InvalidTimestamp` naming the key, the position and
+    /// the violation. A rejected entry is not remembered.
+    ///
+    pub fn check(
+        &mut self,
+        key: &[u8],
+        position: usize,
+        ts: u64,
+        existing: impl FnOnce() -> Option<u64>,
+    ) -> Result<u64, TrieError> {
+        let previous = match self.latest.get(key) {
+            Some(previous) => *previous,
+            None => existing(),
+        };
+        let fail = |violation| TrieError::InvalidTimestamp {
+            key: key.to_vec(),
+            position,
+            ts,
+            violation,
+        };
+        // The timestamp after the previous one of the key, if there is one.
+        let next = |previous: Option<u64>| previous.map_or(Some(1), |p| p.checked_add(1));
+
+        let accepted = if ts == 0 {
+            match self.policy.zero_ts {
+                ZeroTs::Reject => return Err(fail(TsViolation::Zero)),
+                ZeroTs::Assign => {
+                    let clock = match &self.domains {
+                        Some(domains) => domains.clock_of(key, self.clock),
+                        None => self.clock,
+                    };
+                    next(Some(clock.max(previous.unwrap_or(0))))
+                }
+            }
+        } else {
+            match previous {
+                Some(previous) if ts < previous || (ts == previous && !self.allow_equal) => {
+                    match self.policy.on_violation {
+                        OnViolation::Reject => {
+                            return Err(fail(TsViolation::NotIncreasing { previous }))
+                        }
+                        OnViolation::Renumber => next(Some(previous)),
+                    }
+                }
+                _ => Some(ts),
+            }
+        };
+        let ceiling = self.ceiling;
+        let accepted = accepted
+            .filter(|accepted| *accepted <= ceiling)
+            .ok_or_else(|| fail(TsViolation::AboveCeiling { ceiling }))?;
+
+        self.clock = self.clock.max(accepted);
+        if let Some(domains) = self.domains.as_mut() {
+            domains.advance(key, accepted);
+        }
+        self.latest.insert(key.to_vec(), Some(accepted));
+        Ok(accepted)
+    }
+
+    /// Records that `key` was removed, so that its next timestamp is not
+    /// checked against the ones before the removal.
+    pub fn forget(&mut self, key: &[u8]) {
+        self.latest.insert(key.to_vec(), None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn violation(result: Result<u64, TrieError>) -> TsViolation {
+        match result {
+            Err(TrieError::InvalidTimestamp { violation, .. }) => violation,
+            other => panic!("expected an invalid timestamp, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn strict_rejects_zero_and_out_of_order() {
+        let mut validator =
+            TsValidator::new(IngestPolicy::strict(), None, DuplicateTsPolicy::Reject);
+        assert_eq!(validator.check(b"a", 0, 5, || None), Ok(5));
+        assert_eq!(
+            validator.check(b"a", 1, 3, || None),
+            Err(TrieError::InvalidTimestamp {
+                key: b"a".to_vec(),
+                position: 1,
+                ts: 3,
+                violation: TsViolation::NotIncreasing { previous: 5 },
+            })
+        );
+        assert_eq!(
+            violation(validator.check(b"a", 2, 5, || None)),
+            TsViolation::NotIncreasing { previous: 5 }
+        );
+        assert_eq!(
+            violation(validator.check(b"b", 3, 0, || None)),
+            TsViolation::Zero
+        );
+        // Keys are checked against their own history only.
+        assert_eq!(validator.check(b"b", 4, 1, || None), Ok(1));
+        assert_eq!(validator.check(b"a", 5, 6, || None), Ok(6));
+    }
+
+    #[test]
+    fn seeds_keys_from_existing_history() {
+        let mut validator =
+            TsValidator::new(IngestPolicy::strict(), None, DuplicateTsPolicy::Stack);
+        assert_eq!(
+            violation(validator.check(b"a", 0, 9, || Some(10))),
+            TsViolation::NotIncreasing { previous: 10 }
+        );
+        // Stacked duplicates are allowed.
+        assert_eq!(validator.check(b"a", 1, 10, || Some(10)), Ok(10));
+        // The existing history is only asked for once.
+        assert_eq!(validator.check(b"a", 2, 11, || unreachable!()), Ok(11));
+
+        validator.forget(b"a");
+        assert_eq!(validator.check(b"a", 3, 2, || unreachable!()), Ok(2));
+    }
+
+    #[test]
+    fn repair_assigns_and_renumbers() {
+        let mut validator =
+            TsValidator::new(IngestPolicy::repair(), None, DuplicateTsPolicy::Reject)
+                .with_clock(100);
+        assert_eq!(validator.check(b"a", 0, 0, || None), Ok(101));
+        assert_eq!(validator.check(b"a", 1, 50, || None), Ok(102));
+        assert_eq!(validator.check(b"b", 2, 0, || Some(200)), Ok(201));
+        assert_eq!(validator.check(b"c", 3, 7, || None), Ok(7));
+        assert_eq!(validator.check(b"c", 4, 7, || None), Ok(8));
+    }
+
+    #[test]
+    fn ceiling_applies_to_repaired_timestamps() {
+        let mut validator =
+            TsValidator::new(IngestPolicy::repair(), Some(10), DuplicateTsPolicy::Reject);
+        assert_eq!(
+            violation(validator.check(b"a", 0, u64::MAX, || None)),
+            TsViolation::AboveCeiling { ceiling: 10 }
+        );
+        assert_eq!(validator.check(b"a", 1, 10, || None), Ok(10));
+        assert_eq!(
+            violation(validator.check(b"a", 2, 3, || None)),
+            TsViolation::AboveCeiling { ceiling: 10 }
+        );
+
+        let mut validator =
+            TsValidator::new(IngestPolicy::repair(), None, DuplicateTsPolicy::Reject);
+        assert_eq!(
+            violation(validator.check(b"a", 0, 1, || Some(u64::MAX))),
+            TsViolation::AboveCeiling { ceiling: u64::MAX }
+        );
+    }
+}
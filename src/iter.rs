@@ -1,15 +1,34 @@
-use std::collections::{Bound, VecDeque};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, Bound, VecDeque};
+use std::error::Error;
+use std::fmt;
 use std::ops::RangeBounds;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
 
 use crate::art::{Node, NodeType};
-use crate::KeyTrait;
+use crate::codec::{DecodeKey, DecodedIter, EncodeKey};
+use crate::expiry::ExpiryTable;
+use crate::node::{TwigNode, Version};
+use crate::normalize::{normalize_key, KeyNormalizer};
+use crate::popularity::ReadFrequency;
+use crate::snapshot::ReadSet;
+use crate::{KeyTrait, TrieError};
 
 // TODO: need to add more tests for snapshot readers
 /// A structure representing a pointer for iterating over the Trie's key-value pairs.
+///
+/// The pointer holds the root it was created with, so it keeps reading the Trie as
+/// of its creation: keys inserted or removed through the snapshot afterwards are
+/// not visible to it.
 pub struct IterationPointer<P: KeyTrait, V: Clone> {
     pub(crate) id: u64,
     root: Arc<Node<P, V>>,
+    /// The key normalizer of the snapshot the pointer was opened on.
+    pub(crate) normalizer: Option<Arc<dyn KeyNormalizer>>,
+    /// The read counters of the snapshot the pointer was opened on, if it
+    /// counts its reads.
+    pub(crate) read_frequency: Option<Arc<ReadFrequency>>,
 }
 
 impl<P: KeyTrait, V: Clone> IterationPointer<P, V> {
@@ -21,7 +40,32 @@ impl<P: KeyTrait, V: Clone> IterationPointer<P, V> {
     /// * `id` - The ID of the snapshot.
     ///
     pub fn new(root: Arc<Node<P, V>>, id: u64) -> IterationPointer<P, V> {
-        IterationPointer { id, root }
+        IterationPointer {
+            id,
+            root,
+            normalizer: None,
+            read_frequency: None,
+        }
+    }
+
+    /// Returns the ID of the reader, to be passed to `Snapshot::close_reader`.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Retrieves the latest value of the given key, with the version and timestamp
+    /// it was written at.
+    ///
+    /// Fails with `TrieError::KeyNotFound` under the same conditions as
+    /// `Tree::get`.
+    ///
+    pub fn get(&self, key: &P) -> Result<(V, u64, u64), TrieError> {
+        let key = normalize_key(self.normalizer.as_ref(), key);
+        let found = Node::resolve_get(Some(&self.root), key.as_ref(), 0);
+        if let (Ok(_), Some(freq)) = (&found, &self.read_frequency) {
+            freq.record(key.as_slice());
+        }
+        found.map(|(_, value, version, ts)| (value, version, ts))
     }
 
     /// Returns an iterator over the key-value pairs within the Trie.
@@ -30,10 +74,122 @@ impl<P: KeyTrait, V: Clone> IterationPointer<P, V> {
     ///
     /// Returns an Iter iterator instance.
     ///
-    pub fn iter(&self) -> Iter<P, V> {
+    pub fn iter(&self) -> Iter<'_, P, V> {
         Iter::new(Some(&self.root))
     }
 
+    /// Returns an iterator over the key-value pairs within the Trie, from the
+    /// most recently written key to the least.
+    ///
+    /// See `Tree::iter_by_recency`.
+    pub fn iter_by_recency(&self) -> RecencyIter<'_, P, V> {
+        RecencyIter::new(Some(&self.root), None)
+    }
+
+    /// Returns the `k` most recently written key-value pairs within the Trie,
+    /// in the order of `iter_by_recency`.
+    #[allow(clippy::type_complexity)]
+    pub fn recent(&self, k: usize) -> Vec<(Vec<u8>, &V, &u64, &u64)> {
+        self.iter_by_recency().take(k).collect()
+    }
+
+    /// Returns an iteration over the key-value pairs within the Trie that reuses
+    /// `buf` for the keys.
+    ///
+    /// Each call to `next_entry` clears `buf`, copies the next key into it and
+    /// returns the key as a slice of `buf`, so a hot loop over a large Trie does
+    /// not allocate a `Vec` per key the way `iter` does.
+    ///
+    pub fn iter_into<'b>(&self, buf: &'b mut Vec<u8>) -> IterInto<'_, 'b, P, V> {
+        IterInto {
+            state: IterState::new(&self.root),
+            buf,
+        }
+    }
+
+    /// Returns an iterator over the key-value pairs within the Trie in batches.
+    ///
+    /// Each batch holds up to `chunk_size` entries in key order; only the last
+    /// batch may be shorter. Keys and values are copied out of the Trie.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    ///
+    pub fn iter_chunked(&self, chunk_size: usize) -> impl Iterator<Item = Vec<(P, V)>> + '_ {
+        assert!(chunk_size > 0, "chunk size must be greater than zero");
+
+        let mut iter = self.iter();
+        std::iter::from_fn(move || {
+            let chunk: Vec<(P, V)> = iter
+                .by_ref()
+                .take(chunk_size)
+                .map(|(key, value, _, _)| (P::from(key.as_slice()), value.clone()))
+                .collect();
+            if chunk.is_empty() {
+                None
+            } else {
+                Some(chunk)
+            }
+        })
+    }
+
+    /// Returns an iterator over the key-value pairs within the Trie that keeps no
+    /// path stack.
+    ///
+    /// `iter` holds an iterator for every node on the path to its position, so
+    /// its memory grows with the depth of the Trie. This iterator only remembers
+    /// the last key it yielded and finds each following key with a fresh descent
+    /// from the root, so its memory stays constant at the cost of that descent.
+    ///
+    pub fn iter_low_memory(&self) -> LowMemoryIter<'_, P, V> {
+        LowMemoryIter::new(&self.root)
+    }
+
+    /// Returns an iterator over the key-value pairs within the Trie that can be
+    /// suspended and resumed later, such as by a background scan that yields to
+    /// other work.
+    ///
+    /// The iterator holds its own reference to the root of the pointer, so it
+    /// keeps reading the same state of the Trie across suspensions whatever is
+    /// written in between. Values are cloned out of the Trie.
+    ///
+    pub fn iter_suspendable(&self) -> SuspendableIter<P, V> {
+        SuspendableIter::new(Some(self.root.clone()), None)
+    }
+
+    /// Returns an iterator over the entries within the Trie with their keys
+    /// decoded as the composite key type `K`.
+    ///
+    /// See `Tree::iter_decoded`.
+    ///
+    pub fn iter_decoded<K: DecodeKey>(&self) -> DecodedIter<'_, K, V> {
+        DecodedIter::new(self.iter())
+    }
+
+    /// Returns an iterator over the decoded entries within the Trie whose keys
+    /// start with the leading components `prefix`.
+    ///
+    /// See `Tree::scan_prefix_decoded`.
+    ///
+    pub fn scan_prefix_decoded<K: DecodeKey>(
+        &self,
+        prefix: &impl EncodeKey,
+    ) -> DecodedIter<'_, K, V> {
+        DecodedIter::with_prefix(Some(&self.root), prefix, None)
+    }
+
+    /// Returns a fault-tolerant iterator over the key-value pairs within the Trie.
+    ///
+    /// Unlike `iter`, this checks the structure of the Trie while traversing it.
+    /// Inconsistencies are yielded as `TraversalFault` items, and the traversal
+    /// moves on to the next sibling subtree instead of panicking, so every intact
+    /// entry is still visited.
+    ///
+    pub fn iter_lossy(&self) -> LossyIter<'_, P, V> {
+        LossyIter::new(&self.root)
+    }
+
     pub fn range<'a, R>(
         &'a self,
         range: R,
@@ -43,10 +199,24 @@ impl<P: KeyTrait, V: Clone> IterationPointer<P, V> {
     {
         Range::new(Some(&self.root), range)
     }
+
+    /// Returns a scan over the keys starting with `prefix`, using `buffer` as its
+    /// working state.
+    ///
+    /// See `Tree::scan_prefix_with`.
+    ///
+    pub fn scan_prefix_with<'b>(
+        &self,
+        prefix: &[u8],
+        buffer: &'b mut ScanBuffer<P, V>,
+    ) -> PrefixScan<'b, P, V> {
+        PrefixScan::new(Some(&self.root), prefix, buffer, None)
+    }
 }
 
 /// An iterator over the nodes in the Trie.
 struct NodeIter<'a, P: KeyTrait, V: Clone> {
+    #[allow(clippy::type_complexity)]
     node: Box<dyn Iterator<Item = (u8, &'a Arc<Node<P, V>>)> + 'a>,
 }
 
@@ -76,9 +246,24 @@ impl<'a, P: KeyTrait, V: Clone> Iterator for NodeIter<'a, P, V> {
 }
 
 /// An iterator over key-value pairs in the Trie.
+///
+/// The iterator holds the path from the root to its position, so a call to
+/// `next` takes at most `2 * max_depth` steps (see `DepthStats`), each moving
+/// one node to its next child: it pops the exhausted nodes above the last key
+/// and descends to the next one, and never revisits children it has passed.
+/// Finding the next child within a node scans at most its fanout. Keys under
+/// expired prefixes are left out one by one when no rule covers their whole
+/// subtree, which is not bounded; `next_with_budget` lets a caller yield in
+/// between.
 pub struct Iter<'a, P: KeyTrait + 'a, V: Clone> {
-    inner: Box<dyn Iterator<Item = (Vec<u8>, &'a V, &'a u64, &'a u64)> + 'a>,
-    _marker: std::marker::PhantomData<P>,
+    inner: IterInner<'a, P, V>,
+    // Skips the values that fail it, if set; see `Tree::value_check`.
+    check: Option<fn(&V) -> bool>,
+}
+
+enum IterInner<'a, P: KeyTrait + 'a, V: Clone> {
+    All(IterState<'a, P, V>),
+    Live(LiveIter<'a, P, V>),
 }
 
 impl<'a, P: KeyTrait + 'a, V: Clone> Iter<'a, P, V> {
@@ -89,15 +274,68 @@ impl<'a, P: KeyTrait + 'a, V: Clone> Iter<'a, P, V> {
     /// * `node` - An optional reference to the root node of the Trie.
     ///
     pub(crate) fn new(node: Option<&'a Arc<Node<P, V>>>) -> Self {
-        match node {
-            Some(node) => Self {
-                inner: Box::new(IterState::new(node)),
-                _marker: Default::default(),
-            },
-            None => Self {
-                inner: Box::new(std::iter::empty()),
-                _marker: Default::default(),
-            },
+        let state = match node {
+            Some(node) => IterState::new(node),
+            None => IterState::empty(),
+        };
+        Self {
+            inner: IterInner::All(state),
+            check: None,
+        }
+    }
+
+    /// Creates an Iter that leaves out the keys `rules` has expired at `now`.
+    pub(crate) fn live(
+        node: Option<&'a Arc<Node<P, V>>>,
+        rules: &'a ExpiryTable,
+        now: u64,
+    ) -> Self {
+        Self {
+            inner: IterInner::Live(LiveIter::new(node, rules, now)),
+            check: None,
+        }
+    }
+
+    /// Leaves out the values that fail `check`, if set.
+    pub(crate) fn checked(mut self, check: Option<fn(&V) -> bool>) -> Self {
+        self.check = check;
+        self
+    }
+
+    /// Returns the next entry like `next`, unless finding it takes more than
+    /// `max_steps` steps, in which case `Poll::Pending` is returned.
+    ///
+    /// The iterator stays where it stopped, so calling this again carries on
+    /// the search rather than starting it over.
+    #[allow(clippy::type_complexity)]
+    pub fn next_with_budget(
+        &mut self,
+        max_steps: usize,
+    ) -> Poll<Option<(Vec<u8>, &'a V, &'a u64, &'a u64)>> {
+        loop {
+            let entry = match &mut self.inner {
+                IterInner::All(state) => {
+                    state.steps = 0;
+                    state.advance(max_steps).map(|leaf| {
+                        leaf.map(|leaf| (leaf.0.as_slice().to_vec(), leaf.1, leaf.2, leaf.3))
+                    })
+                }
+                IterInner::Live(live) => live.advance(max_steps),
+            };
+            match (&entry, self.check) {
+                (Poll::Ready(Some((_, value, ..))), Some(check)) if !check(value) => continue,
+                _ => return entry,
+            }
+        }
+    }
+
+    /// Returns the number of steps the last call to `next` or
+    /// `next_with_budget` took.
+    #[cfg(any(test, debug_assertions))]
+    pub fn work_counter(&self) -> usize {
+        match &self.inner {
+            IterInner::All(state) => state.steps,
+            IterInner::Live(live) => live.steps,
         }
     }
 }
@@ -106,7 +344,282 @@ impl<'a, P: KeyTrait + 'a, V: Clone> Iterator for Iter<'a, P, V> {
     type Item = (Vec<u8>, &'a V, &'a u64, &'a u64);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next()
+        match self.next_with_budget(usize::MAX) {
+            Poll::Ready(entry) => entry,
+            Poll::Pending => unreachable!("an unbounded budget is never exhausted"),
+        }
+    }
+}
+
+/// An iterator over key-value pairs in the Trie that seeks every key from the
+/// root instead of holding the path to its position.
+pub struct LowMemoryIter<'a, P: KeyTrait, V: Clone> {
+    root: &'a Arc<Node<P, V>>,
+    // The key yielded last, borrowed from its twig, or `None` before the first one.
+    last: Option<&'a [u8]>,
+    done: bool,
+}
+
+impl<'a, P: KeyTrait, V: Clone> LowMemoryIter<'a, P, V> {
+    pub(crate) fn new(root: &'a Arc<Node<P, V>>) -> Self {
+        LowMemoryIter {
+            root,
+            last: None,
+            done: false,
+        }
+    }
+
+    // Returns the twig with the smallest key in the subtree.
+    pub(crate) fn first_twig(mut node: &'a Node<P, V>) -> Option<&'a TwigNode<P, V>> {
+        loop {
+            match &node.node_type {
+                NodeType::Twig(twig) => return Some(twig),
+                _ => node = node.next_child(0)?.1,
+            }
+        }
+    }
+
+    // Returns the twig with the smallest key greater than `last`.
+    //
+    // The descent follows `last` and remembers only the deepest subtree seen so
+    // far whose keys are all greater than `last`: the next sibling after the
+    // branch taken. Where the path to `last` ends, the successor is the first key
+    // of that subtree.
+    pub(crate) fn successor(root: &'a Node<P, V>, last: &[u8]) -> Option<&'a TwigNode<P, V>> {
+        let mut node = root;
+        let mut depth = 0;
+        let mut greater: Option<&'a Node<P, V>> = None;
+        loop {
+            if let NodeType::Twig(twig) = &node.node_type {
+                if twig.key.as_slice() > last {
+                    return Some(twig);
+                }
+                return greater.and_then(Self::first_twig);
+            }
+
+            let prefix = node.prefix().as_slice();
+            let rest = &last[depth.min(last.len())..];
+            let common = prefix.len().min(rest.len());
+            match prefix[..common].cmp(&rest[..common]) {
+                Ordering::Less => return greater.and_then(Self::first_twig),
+                Ordering::Greater => return Self::first_twig(node),
+                // `last` ends within the path to this node, so every key below is
+                // an extension of it.
+                Ordering::Equal if rest.len() <= prefix.len() => return Self::first_twig(node),
+                Ordering::Equal => {}
+            }
+
+            depth += prefix.len();
+            let k = last[depth];
+            if let Some(sibling) = node.child_after(k) {
+                greater = Some(sibling);
+            }
+            match node.find_child(k) {
+                Some(child) => node = child,
+                None => return greater.and_then(Self::first_twig),
+            }
+        }
+    }
+}
+
+impl<'a, P: KeyTrait, V: Clone> Iterator for LowMemoryIter<'a, P, V> {
+    type Item = (Vec<u8>, &'a V, &'a u64, &'a u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let twig = match self.last {
+                None => Self::first_twig(self.root),
+                Some(last) => Self::successor(self.root, last),
+            };
+            let Some(twig) = twig else {
+                self.done = true;
+                break;
+            };
+            self.last = Some(twig.key.as_slice());
+            if let Some(leaf) = twig.get_latest_leaf() {
+                return Some((
+                    twig.key.as_slice().to_vec(),
+                    &leaf.value,
+                    &leaf.version,
+                    &leaf.ts,
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// An iterator over key-value pairs in the Trie that owns the root it reads,
+/// as returned by `IterationPointer::iter_suspendable`.
+///
+/// Unlike `Iter`, the path to its position is a stack of owned node references
+/// rather than of per-node iterators, so that `suspend` can let go of it and
+/// `SuspendedScan::resume` can rebuild it with a single descent.
+pub struct SuspendableIter<P: KeyTrait, V: Clone> {
+    root: Option<Arc<Node<P, V>>>,
+    // The inner nodes on the path to the position, each with the key byte of the
+    // child the path takes below it.
+    stack: Vec<(Arc<Node<P, V>>, u8)>,
+    // The twig to yield next, if the position is right before it.
+    pending: Option<Arc<Node<P, V>>>,
+    // The key the iteration continues after.
+    after: Option<Vec<u8>>,
+    // The twig yielded last, whose key takes over from `after`.
+    current: Option<Arc<Node<P, V>>>,
+}
+
+impl<P: KeyTrait, V: Clone> SuspendableIter<P, V> {
+    fn new(root: Option<Arc<Node<P, V>>>, after: Option<Vec<u8>>) -> Self {
+        let mut iter = SuspendableIter {
+            root,
+            stack: Vec::new(),
+            pending: None,
+            after,
+            current: None,
+        };
+        if let Some(root) = iter.root.clone() {
+            let after = iter.after.take();
+            iter.pending = match &after {
+                None => iter.first_from(root),
+                Some(after) => iter.seek_after(root, after),
+            };
+            iter.after = after;
+        }
+        iter
+    }
+
+    /// Suspends the iteration, releasing the path to its position but keeping
+    /// the root it reads and the last key it yielded.
+    pub fn suspend(self) -> SuspendedScan<P, V> {
+        let after = match &self.current {
+            Some(twig) => match &twig.node_type {
+                NodeType::Twig(twig) => Some(twig.key.as_slice().to_vec()),
+                _ => unreachable!("only twigs are yielded"),
+            },
+            None => self.after,
+        };
+        SuspendedScan {
+            root: self.root,
+            after,
+        }
+    }
+
+    // Returns the twig with the smallest key below `node`, pushing the path to it.
+    fn descend(&mut self, mut node: Arc<Node<P, V>>) -> Option<Arc<Node<P, V>>> {
+        loop {
+            if node.is_twig() {
+                return Some(node);
+            }
+            let child = node.next_child(0)?.1.clone();
+            // A child's prefix starts with the key byte it is stored under.
+            self.stack.push((node, child.prefix().at(0)));
+            node = child;
+        }
+    }
+
+    // Returns the first twig at or after `node` in key order.
+    fn first_from(&mut self, node: Arc<Node<P, V>>) -> Option<Arc<Node<P, V>>> {
+        self.descend(node).or_else(|| self.advance())
+    }
+
+    // Returns the twig after the subtree the path ends in.
+    fn advance(&mut self) -> Option<Arc<Node<P, V>>> {
+        while let Some((node, k)) = self.stack.pop() {
+            if let Some(child) = node.child_after(k).cloned() {
+                self.stack.push((node, child.prefix().at(0)));
+                if let Some(twig) = self.descend(child) {
+                    return Some(twig);
+                }
+            }
+        }
+        None
+    }
+
+    // Returns the twig with the smallest key greater than `last`, pushing the
+    // path to it, like `LowMemoryIter::successor`.
+    fn seek_after(&mut self, root: Arc<Node<P, V>>, last: &[u8]) -> Option<Arc<Node<P, V>>> {
+        let mut node = root;
+        let mut depth = 0;
+        loop {
+            if let NodeType::Twig(twig) = &node.node_type {
+                if twig.key.as_slice() > last {
+                    return Some(node);
+                }
+                return self.advance();
+            }
+
+            let prefix = node.prefix().as_slice();
+            let rest = &last[depth.min(last.len())..];
+            let common = prefix.len().min(rest.len());
+            match prefix[..common].cmp(&rest[..common]) {
+                Ordering::Less => return self.advance(),
+                Ordering::Greater => return self.first_from(node),
+                // `last` ends within the path to this node, so every key below is
+                // an extension of it.
+                Ordering::Equal if rest.len() <= prefix.len() => return self.first_from(node),
+                Ordering::Equal => {}
+            }
+
+            depth += prefix.len();
+            let k = last[depth];
+            let child = node.find_child(k).cloned();
+            self.stack.push((node, k));
+            match child {
+                Some(child) => node = child,
+                None => return self.advance(),
+            }
+        }
+    }
+}
+
+impl<P: KeyTrait, V: Clone> Iterator for SuspendableIter<P, V> {
+    type Item = (Vec<u8>, V, u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = match self.pending.take() {
+                Some(node) => node,
+                None => self.advance()?,
+            };
+            let NodeType::Twig(twig) = &node.node_type else {
+                unreachable!("the path ends in twigs");
+            };
+            let entry = twig.get_latest_leaf().map(|leaf| {
+                (
+                    twig.key.as_slice().to_vec(),
+                    leaf.value.clone(),
+                    leaf.version,
+                    leaf.ts,
+                )
+            });
+            self.current = Some(node);
+            if entry.is_some() {
+                return entry;
+            }
+        }
+    }
+}
+
+/// A suspended `SuspendableIter`: the root it reads and the last key it
+/// yielded.
+///
+/// The root stays alive while the scan is suspended, so resuming continues
+/// with exactly the entries the uninterrupted iteration would have yielded.
+pub struct SuspendedScan<P: KeyTrait, V: Clone> {
+    root: Option<Arc<Node<P, V>>>,
+    after: Option<Vec<u8>>,
+}
+
+impl<P: KeyTrait, V: Clone> SuspendedScan<P, V> {
+    /// Returns the last key yielded before the scan was suspended, or `None` if
+    /// it yielded none.
+    pub fn last_key(&self) -> Option<&[u8]> {
+        self.after.as_deref()
+    }
+
+    /// Resumes the iteration after the last key it yielded.
+    pub fn resume(self) -> SuspendableIter<P, V> {
+        SuspendableIter::new(self.root, self.after)
     }
 }
 
@@ -114,6 +627,8 @@ impl<'a, P: KeyTrait + 'a, V: Clone> Iterator for Iter<'a, P, V> {
 struct IterState<'a, P: KeyTrait + 'a, V: Clone> {
     iters: Vec<NodeIter<'a, P, V>>,
     leafs: VecDeque<(&'a P, &'a V, &'a u64, &'a u64)>,
+    /// The number of steps taken by the current or last call to `next`.
+    steps: usize,
 }
 
 impl<'a, P: KeyTrait + 'a, V: Clone> IterState<'a, P, V> {
@@ -136,71 +651,194 @@ impl<'a, P: KeyTrait + 'a, V: Clone> IterState<'a, P, V> {
             iters.push(NodeIter::new(node.iter()));
         }
 
-        Self { iters, leafs }
+        Self {
+            iters,
+            leafs,
+            steps: 0,
+        }
     }
 
     pub fn empty() -> Self {
         Self {
             iters: Vec::new(),
             leafs: VecDeque::new(),
+            steps: 0,
         }
     }
 
+    /// Creates the state of a scan over `range`, positioned at its start.
+    ///
+    /// The start key is looked up from `node`, and only the children after the
+    /// path to it are kept at every level, so the subtrees before the start are
+    /// never visited.
     fn forward_scan<R>(node: &'a Node<P, V>, range: &R) -> Self
     where
         R: RangeBounds<P>,
     {
-        let mut leafs = VecDeque::new();
-        let mut iters = Vec::new();
-        if let NodeType::Twig(twig) = &node.node_type {
-            if range.contains(&twig.key) {
-                let val = twig.get_latest_leaf();
-                if let Some(v) = val {
-                    leafs.push_back((&twig.key, &v.value, &v.version, &v.ts));
+        let start = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => key.as_slice(),
+            Bound::Unbounded => &[],
+        };
+        let mut state = Self::empty();
+        let mut node = node;
+        let mut depth = 0;
+        loop {
+            if let NodeType::Twig(twig) = &node.node_type {
+                if range.contains(&twig.key) {
+                    if let Some(v) = twig.get_latest_leaf() {
+                        state
+                            .leafs
+                            .push_back((&twig.key, &v.value, &v.version, &v.ts));
+                    }
                 }
+                break;
+            }
+
+            let prefix = node.prefix().as_slice();
+            let rest = &start[depth..];
+            let lcp = prefix.iter().zip(rest).take_while(|(a, b)| a == b).count();
+            if lcp == rest.len() {
+                // Every key below starts with the start key, so none is before it.
+                state.iters.push(NodeIter::new(node.iter()));
+                break;
+            }
+            if lcp < prefix.len() {
+                // The keys below diverge from the start key within the prefix.
+                if prefix[lcp] > rest[lcp] {
+                    state.iters.push(NodeIter::new(node.iter()));
+                }
+                break;
             }
-        } else {
-            iters.push(NodeIter::new(node.iter()));
-        }
 
-        Self { iters, leafs }
+            depth += prefix.len();
+            let k = start[depth];
+            state.iters.push(NodeIter::new(
+                node.iter().skip_while(move |(key, _)| *key <= k),
+            ));
+            match node.find_child(k) {
+                Some(child) if child.is_twig() => {
+                    state.iters.push(NodeIter::new(std::iter::once((k, child))));
+                    break;
+                }
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+        state
     }
 }
 
-impl<'a, P: KeyTrait + 'a, V: Clone> Iterator for IterState<'a, P, V> {
-    type Item = (Vec<u8>, &'a V, &'a u64, &'a u64);
-
-    fn next(&mut self) -> Option<Self::Item> {
+impl<'a, P: KeyTrait + 'a, V: Clone> IterState<'a, P, V> {
+    /// Moves to the next leaf in key order, borrowing its key from the twig
+    /// node, unless that takes more than `budget` steps.
+    ///
+    /// A step moves the innermost node iterator to its next child, then either
+    /// pops the iterator if it is exhausted or descends into the child. The
+    /// steps add up in `steps` until the caller resets it.
+    fn advance(&mut self, budget: usize) -> Poll<Option<(&'a P, &'a V, &'a u64, &'a u64)>> {
+        if let Some(leaf) = self.leafs.pop_front() {
+            return Poll::Ready(Some(leaf));
+        }
         while let Some(node) = self.iters.last_mut() {
-            let e = node.next();
-            match e {
+            if self.steps >= budget {
+                return Poll::Pending;
+            }
+            self.steps += 1;
+            match node.next() {
                 None => {
                     self.iters.pop();
                 }
-                Some(other) => {
-                    if let NodeType::Twig(twig) = &other.1.node_type {
-                        let val = twig.get_latest_leaf();
-                        if let Some(v) = val {
-                            self.leafs
-                                .push_back((&twig.key, &v.value, &v.version, &v.ts));
+                Some((_, child)) => {
+                    if let NodeType::Twig(twig) = &child.node_type {
+                        if let Some(v) = twig.get_latest_leaf() {
+                            return Poll::Ready(Some((&twig.key, &v.value, &v.version, &v.ts)));
                         }
-                        break;
                     } else {
-                        self.iters.push(NodeIter::new(other.1.iter()));
+                        self.iters.push(NodeIter::new(child.iter()));
                     }
                 }
             }
         }
+        Poll::Ready(None)
+    }
+
+    /// Returns the next leaf in key order, borrowing its key from the twig node.
+    fn next_leaf(&mut self) -> Option<(&'a P, &'a V, &'a u64, &'a u64)> {
+        self.steps = 0;
+        match self.advance(usize::MAX) {
+            Poll::Ready(leaf) => leaf,
+            Poll::Pending => unreachable!("an unbounded budget is never exhausted"),
+        }
+    }
+}
 
-        self.leafs
-            .pop_front()
+impl<'a, P: KeyTrait + 'a, V: Clone> Iterator for IterState<'a, P, V> {
+    type Item = (Vec<u8>, &'a V, &'a u64, &'a u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_leaf()
             .map(|leaf| (leaf.0.as_slice().to_vec(), leaf.1, leaf.2, leaf.3))
     }
 }
 
+/// An iteration over key-value pairs in the Trie that writes each key into a
+/// caller-supplied buffer instead of allocating it.
+///
+/// The returned keys borrow the buffer, which is overwritten on every step, so
+/// this is advanced with `next_entry` rather than through the `Iterator` trait.
+pub struct IterInto<'a, 'b, P: KeyTrait + 'a, V: Clone> {
+    state: IterState<'a, P, V>,
+    buf: &'b mut Vec<u8>,
+}
+
+impl<'a, 'b, P: KeyTrait + 'a, V: Clone> IterInto<'a, 'b, P, V> {
+    /// Returns the next key and value in key order.
+    pub fn next_entry(&mut self) -> Option<(&[u8], &'a V)> {
+        let (key, value, _, _) = self.state.next_leaf()?;
+        self.buf.clear();
+        self.buf.extend_from_slice(key.as_slice());
+        Some((self.buf.as_slice(), value))
+    }
+}
+
+/// An iterator over the keys in the Trie, in key order.
+///
+/// Each key is cloned from its twig, without going through a byte vector or
+/// touching the values.
+pub struct Keys<'a, P: KeyTrait + 'a, V: Clone> {
+    state: IterState<'a, P, V>,
+}
+
+impl<'a, P: KeyTrait + 'a, V: Clone> Keys<'a, P, V> {
+    pub(crate) fn new(node: Option<&'a Arc<Node<P, V>>>) -> Self {
+        let state = match node {
+            Some(node) => IterState::new(node),
+            None => IterState::empty(),
+        };
+        Self { state }
+    }
+}
+
+impl<'a, P: KeyTrait + 'a, V: Clone> Iterator for Keys<'a, P, V> {
+    type Item = P;
+
+    fn next(&mut self) -> Option<P> {
+        self.state.next_leaf().map(|(key, _, _, _)| key.clone())
+    }
+}
+
+/// An iterator over the key-value pairs of the Trie within a range of keys.
+///
+/// The scan starts at the path to the start of the range, so like `Iter` a call
+/// to `next` takes at most `2 * max_depth` steps, plus one for a start key that
+/// is excluded from the range.
 pub struct Range<'a, K: KeyTrait, V: Clone, R> {
     forward: IterState<'a, K, V>,
     range: R,
+    // The read set of the snapshot scanned, which records the keys yielded, and
+    // the root of the snapshot the keys under them are counted in.
+    #[allow(clippy::type_complexity)]
+    reads: Option<(&'a Mutex<ReadSet>, Option<&'a Arc<Node<K, V>>>)>,
 }
 
 impl<'a, K: KeyTrait, V: Clone, R> Range<'a, K, V, R>
@@ -212,6 +850,7 @@ where
         Self {
             forward: IterState::empty(),
             range,
+            reads: None,
         }
     }
 
@@ -223,53 +862,1063 @@ where
             Self {
                 forward: IterState::forward_scan(node, &range),
                 range,
+                reads: None,
             }
         } else {
             Self {
                 forward: IterState::empty(),
                 range,
+                reads: None,
+            }
+        }
+    }
+
+    /// Records the keys yielded by the range in `reads`, counting the keys
+    /// under them below `root`.
+    pub(crate) fn recording(
+        mut self,
+        reads: &'a Mutex<ReadSet>,
+        root: Option<&'a Arc<Node<K, V>>>,
+    ) -> Self {
+        self.reads = Some((reads, root));
+        self
+    }
+
+    /// Returns the next entry like `next`, unless finding it takes more than
+    /// `max_steps` steps, in which case `Poll::Pending` is returned.
+    ///
+    /// See `Iter::next_with_budget`.
+    #[allow(clippy::type_complexity)]
+    pub fn next_with_budget(
+        &mut self,
+        max_steps: usize,
+    ) -> Poll<Option<(Vec<u8>, &'a V, &'a u64, &'a u64)>> {
+        self.forward.steps = 0;
+        loop {
+            let leaf = match self.forward.advance(max_steps) {
+                Poll::Ready(leaf) => leaf,
+                Poll::Pending => return Poll::Pending,
+            };
+            let Some(leaf) = leaf else {
+                return Poll::Ready(None);
+            };
+            if self.range.contains(leaf.0) {
+                if let Some((reads, root)) = self.reads {
+                    reads
+                        .lock()
+                        .unwrap()
+                        .record_key(leaf.0.as_slice(), |prefix| Node::count_under(root, prefix));
+                }
+                return Poll::Ready(Some((leaf.0.as_slice().to_vec(), leaf.1, leaf.2, leaf.3)));
+            }
+            match self.range.end_bound() {
+                Bound::Included(k) if leaf.0 > k => self.forward.iters.clear(),
+                Bound::Excluded(k) if leaf.0 >= k => self.forward.iters.clear(),
+                _ => {}
             }
         }
     }
+
+    /// Returns the number of steps the last call to `next` or
+    /// `next_with_budget` took.
+    #[cfg(any(test, debug_assertions))]
+    pub fn work_counter(&self) -> usize {
+        self.forward.steps
+    }
 }
 
 impl<'a, K: 'a + KeyTrait, V: Clone, R: RangeBounds<K>> Iterator for Range<'a, K, V, R> {
     type Item = (Vec<u8>, &'a V, &'a u64, &'a u64);
 
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some(node) = self.forward.iters.last_mut() {
-            let e = node.next();
-            match e {
-                Some(other) => {
-                    if let NodeType::Twig(twig) = &other.1.node_type {
-                        if self.range.contains(&twig.key) {
-                            let val = twig.get_latest_leaf();
-                            if let Some(v) = val {
-                                self.forward
-                                    .leafs
-                                    .push_back((&twig.key, &v.value, &v.version, &v.ts));
-                            }
-                            break;
-                        } else {
-                            match self.range.end_bound() {
-                                Bound::Included(k) if &twig.key > k => self.forward.iters.clear(),
-                                Bound::Excluded(k) if &twig.key >= k => self.forward.iters.clear(),
-                                _ => {}
-                            }
+        match self.next_with_budget(usize::MAX) {
+            Poll::Ready(entry) => entry,
+            Poll::Pending => unreachable!("an unbounded budget is never exhausted"),
+        }
+    }
+}
+
+/// Reusable working state for prefix scans.
+///
+/// A `ScanBuffer` owns the traversal stack of a `PrefixScan`. The stack is
+/// cleared between scans but keeps its capacity, which grows to the deepest
+/// traversal seen, so a buffer reused across scans stops allocating once it has
+/// warmed up. Between scans the buffer holds no references to the Trie.
+pub struct ScanBuffer<P: KeyTrait, V: Clone> {
+    // Nodes on the path being traversed, with the next child slot to visit.
+    stack: Vec<(Arc<Node<P, V>>, usize)>,
+}
+
+impl<P: KeyTrait, V: Clone> ScanBuffer<P, V> {
+    /// Creates an empty buffer.
+    pub fn new() -> Self {
+        Self { stack: Vec::new() }
+    }
+
+    /// Creates a buffer with room for traversals up to `depth` nodes deep.
+    pub fn with_capacity(depth: usize) -> Self {
+        Self {
+            stack: Vec::with_capacity(depth),
+        }
+    }
+
+    /// Returns the depth of traversal the buffer can hold without allocating.
+    pub fn capacity(&self) -> usize {
+        self.stack.capacity()
+    }
+}
+
+impl<P: KeyTrait, V: Clone> Default for ScanBuffer<P, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A scan over the key-value pairs whose keys start with a prefix.
+///
+/// The scan keeps its traversal state in a borrowed `ScanBuffer` and yields
+/// entries that borrow from the scan itself, so it is advanced with
+/// `next_entry` rather than through the `Iterator` trait. Keys are returned as
+/// stored in the Trie, without copying.
+pub struct PrefixScan<'b, P: KeyTrait, V: Clone> {
+    stack: &'b mut Vec<(Arc<Node<P, V>>, usize)>,
+    // The expiry rules of the Tree and its current timestamp, if expired keys
+    // are skipped.
+    live: Option<(&'b ExpiryTable, u64)>,
+}
+
+impl<'b, P: KeyTrait, V: Clone> PrefixScan<'b, P, V> {
+    pub(crate) fn new(
+        root: Option<&Arc<Node<P, V>>>,
+        prefix: &[u8],
+        buffer: &'b mut ScanBuffer<P, V>,
+        live: Option<(&'b ExpiryTable, u64)>,
+    ) -> Self {
+        let stack = &mut buffer.stack;
+        stack.clear();
+        if let Some(node) = root.and_then(|root| Node::find_prefix_subtree(root, prefix)) {
+            stack.push((node.clone(), 0));
+        }
+        Self { stack, live }
+    }
+
+    /// Returns the next key-value pair in key order, along with its version and timestamp.
+    pub fn next_entry(&mut self) -> Option<(&[u8], &V, u64, u64)> {
+        // The slot of the twig to yield within the node on top of the stack, or
+        // `None` if the twig is the top of the stack itself.
+        let live = self.live;
+        let is_live = |twig: &TwigNode<P, V>| {
+            twig.get_latest_leaf().is_some()
+                && !live.is_some_and(|(rules, now)| rules.is_expired(twig.key.as_slice(), now))
+        };
+        let slot = loop {
+            let (node, pos) = self.stack.last_mut()?;
+            if let NodeType::Twig(twig) = &node.node_type {
+                if *pos == 0 && is_live(twig) {
+                    *pos = 1;
+                    break None;
+                }
+                self.stack.pop();
+                continue;
+            }
+
+            match node.next_child(*pos) {
+                Some((slot, child)) => {
+                    *pos = slot + 1;
+                    if let NodeType::Twig(twig) = &child.node_type {
+                        if is_live(twig) {
+                            break Some(slot);
                         }
                     } else {
-                        self.forward.iters.push(NodeIter::new(other.1.iter()));
+                        let child = child.clone();
+                        self.stack.push((child, 0));
                     }
                 }
                 None => {
-                    self.forward.iters.pop();
+                    self.stack.pop();
                 }
             }
+        };
+
+        let (node, _) = self.stack.last()?;
+        let twig = match slot {
+            Some(slot) => node.next_child(slot)?.1,
+            None => node,
+        };
+        match &twig.node_type {
+            NodeType::Twig(twig) => {
+                let leaf = twig.get_latest_leaf()?;
+                Some((twig.key.as_slice(), &leaf.value, leaf.version, leaf.ts))
+            }
+            _ => None,
         }
+    }
+}
 
-        self.forward
-            .leafs
-            .pop_front()
-            .map(|leaf| (leaf.0.as_slice().to_vec(), leaf.1, leaf.2, leaf.3))
+impl<'b, P: KeyTrait, V: Clone> Drop for PrefixScan<'b, P, V> {
+    fn drop(&mut self) {
+        // Release the nodes, keeping the capacity for the next scan.
+        self.stack.clear();
+    }
+}
+
+/// An iterator over the twig nodes holding a version newer than `since`.
+///
+/// Every node carries the newest version below it, so subtrees without newer
+/// versions are skipped without being visited.
+pub(crate) struct ChangedSince<'a, P: KeyTrait, V: Clone> {
+    stack: Vec<(&'a Arc<Node<P, V>>, usize)>,
+    since: u64,
+}
+
+impl<'a, P: KeyTrait, V: Clone> ChangedSince<'a, P, V> {
+    pub(crate) fn new(root: Option<&'a Arc<Node<P, V>>>, since: u64) -> Self {
+        let stack = root
+            .filter(|root| root.version() > since)
+            .map(|root| (root, 0))
+            .into_iter()
+            .collect();
+        ChangedSince { stack, since }
+    }
+
+    /// Returns an iterator over every twig node, in key order.
+    pub(crate) fn all(root: Option<&'a Arc<Node<P, V>>>) -> Self {
+        // Every twig holds a version newer than 0, so none is skipped.
+        ChangedSince::new(root, 0)
+    }
+}
+
+impl<'a, P: KeyTrait, V: Clone> Iterator for ChangedSince<'a, P, V> {
+    type Item = &'a TwigNode<P, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (node, pos) = self.stack.last_mut()?;
+            if let NodeType::Twig(twig) = &node.node_type {
+                self.stack.pop();
+                return Some(twig);
+            }
+
+            match node.next_child(*pos) {
+                Some((slot, child)) => {
+                    *pos = slot + 1;
+                    if child.version() > self.since {
+                        self.stack.push((child, 0));
+                    }
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+/// An iterator over the latest key-value pairs of a Trie, from the most
+/// recently written key to the least: in decreasing order of the timestamp of
+/// their latest version, and in key order among keys sharing a timestamp.
+///
+/// Every node carries a bound on the timestamps of the latest versions below
+/// it, so the traversal is best-first: the subtrees met so far wait in a
+/// priority queue ordered by their bound, and a subtree is only opened once no
+/// key waiting in the queue can come before the keys it holds. Taking the
+/// first few keys visits the paths to them and the children along those paths,
+/// rather than the whole Trie.
+pub struct RecencyIter<'a, P: KeyTrait, V: Clone> {
+    queue: BinaryHeap<Pending<'a, P, V>>,
+    // The expiry rules of the Tree and its current timestamp, if expired keys
+    // are skipped.
+    live: Option<(&'a ExpiryTable, u64)>,
+    visited: usize,
+}
+
+// A subtree or twig waiting in the queue of a `RecencyIter`, with the bound on
+// its timestamps and the smallest key it can hold: the path to its children,
+// or the key of a twig.
+struct Pending<'a, P: KeyTrait, V: Clone> {
+    ts: u64,
+    key: Vec<u8>,
+    node: &'a Arc<Node<P, V>>,
+}
+
+impl<P: KeyTrait, V: Clone> Pending<'_, P, V> {
+    fn is_twig(&self) -> bool {
+        matches!(self.node.node_type, NodeType::Twig(_))
+    }
+}
+
+impl<P: KeyTrait, V: Clone> PartialEq for Pending<'_, P, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<P: KeyTrait, V: Clone> Eq for Pending<'_, P, V> {}
+
+impl<P: KeyTrait, V: Clone> PartialOrd for Pending<'_, P, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// The queue pops the greatest entry: the newest, then the smallest key, and a
+// subtree before a twig, since the keys of a subtree can equal its bound.
+impl<P: KeyTrait, V: Clone> Ord for Pending<'_, P, V> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.ts
+            .cmp(&other.ts)
+            .then_with(|| other.key.cmp(&self.key))
+            .then_with(|| other.is_twig().cmp(&self.is_twig()))
+    }
+}
+
+impl<'a, P: KeyTrait, V: Clone> RecencyIter<'a, P, V> {
+    pub(crate) fn new(
+        root: Option<&'a Arc<Node<P, V>>>,
+        live: Option<(&'a ExpiryTable, u64)>,
+    ) -> Self {
+        let mut iter = RecencyIter {
+            queue: BinaryHeap::new(),
+            live,
+            visited: 0,
+        };
+        if let Some(root) = root {
+            iter.push(root, Vec::new());
+        }
+        iter
+    }
+
+    // Queues `node`, reached by the path `path`.
+    fn push(&mut self, node: &'a Arc<Node<P, V>>, mut path: Vec<u8>) {
+        match &node.node_type {
+            NodeType::Twig(twig) => path = twig.key.as_slice().to_vec(),
+            _ => path.extend_from_slice(node.prefix().as_slice()),
+        }
+        self.queue.push(Pending {
+            ts: node.max_ts(),
+            key: path,
+            node,
+        });
+    }
+
+    /// Returns the number of nodes the iterator has taken out of its queue so
+    /// far, twigs included.
+    pub fn nodes_visited(&self) -> usize {
+        self.visited
+    }
+}
+
+impl<'a, P: KeyTrait, V: Clone> Iterator for RecencyIter<'a, P, V> {
+    type Item = (Vec<u8>, &'a V, &'a u64, &'a u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(Pending { key, node, .. }) = self.queue.pop() {
+            self.visited += 1;
+            let NodeType::Twig(twig) = &node.node_type else {
+                let mut pos = 0;
+                while let Some((slot, child)) = node.next_child(pos) {
+                    self.push(child, key.clone());
+                    pos = slot + 1;
+                }
+                continue;
+            };
+            let Some(leaf) = twig.get_latest_leaf() else {
+                continue;
+            };
+            if self
+                .live
+                .is_some_and(|(rules, now)| rules.is_expired(&key, now))
+            {
+                continue;
+            }
+            return Some((key, &leaf.value, &leaf.version, &leaf.ts));
+        }
+        None
+    }
+}
+
+/// The decision of a scan filter for a node of the Trie.
+///
+/// See `Tree::scan_with_filter`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanDecision {
+    /// Visits the children of the node and asks the filter about each of them.
+    /// A key the filter descends into is not yielded.
+    Descend,
+    /// Skips the node along with everything below it.
+    SkipSubtree,
+    /// Yields every key below the node without asking the filter again.
+    Yield,
+}
+
+// A node on the path of a `FilteredScan`.
+struct ScanFrame<'a, P: KeyTrait, V: Clone> {
+    node: &'a Arc<Node<P, V>>,
+    // The next child slot to visit.
+    pos: usize,
+    // Length of the path up to and including the prefix of the node.
+    path_len: usize,
+    // Whether the filter yielded the whole subtree.
+    yield_all: bool,
+}
+
+/// A scan that lets a filter prune whole subtrees of the Trie.
+///
+/// The filter is called with the key prefix reconstructed at each inner node and
+/// with the full key at each twig, in key order. Subtrees it skips are never
+/// visited. Each yielded key comes with its latest value.
+pub struct FilteredScan<'a, P: KeyTrait, V: Clone, F> {
+    stack: Vec<ScanFrame<'a, P, V>>,
+    // A root twig accepted by the filter, yielded before anything else.
+    root_twig: Option<&'a TwigNode<P, V>>,
+    path: Vec<u8>,
+    filter: F,
+    // The expiry rules and current timestamp of a Trie, when expired keys are
+    // skipped.
+    live: Option<(&'a ExpiryTable, u64)>,
+}
+
+impl<'a, P: KeyTrait, V: Clone, F: Fn(&P) -> ScanDecision> FilteredScan<'a, P, V, F> {
+    pub(crate) fn new(
+        root: Option<&'a Arc<Node<P, V>>>,
+        filter: F,
+        live: Option<(&'a ExpiryTable, u64)>,
+    ) -> Self {
+        let mut scan = FilteredScan {
+            stack: Vec::new(),
+            root_twig: None,
+            path: Vec::new(),
+            filter,
+            live,
+        };
+        if let Some(root) = root {
+            match &root.node_type {
+                NodeType::Twig(twig) => {
+                    if (scan.filter)(&twig.key) == ScanDecision::Yield {
+                        scan.root_twig = Some(twig);
+                    }
+                }
+                _ => scan.visit_inner(root, 0, false),
+            }
+        }
+        scan
+    }
+
+    // Asks the filter about an inner node whose parent path is `path_len` long,
+    // and pushes it unless the filter skips it.
+    fn visit_inner(&mut self, node: &'a Arc<Node<P, V>>, path_len: usize, yield_all: bool) {
+        self.path.truncate(path_len);
+        self.path.extend_from_slice(node.prefix().as_slice());
+        let decision = if yield_all {
+            ScanDecision::Yield
+        } else {
+            (self.filter)(&P::from(self.path.as_slice()))
+        };
+        if decision != ScanDecision::SkipSubtree {
+            self.stack.push(ScanFrame {
+                node,
+                pos: 0,
+                path_len: self.path.len(),
+                yield_all: decision == ScanDecision::Yield,
+            });
+        }
+    }
+}
+
+impl<'a, P: KeyTrait, V: Clone, F: Fn(&P) -> ScanDecision> FilteredScan<'a, P, V, F> {
+    // Returns the latest value of `twig`, unless its key has expired.
+    fn live_value(&self, twig: &'a TwigNode<P, V>) -> Option<&'a V> {
+        if let Some((rules, now)) = self.live {
+            if rules.is_expired(twig.key.as_slice(), now) {
+                return None;
+            }
+        }
+        twig.get_latest_value()
+    }
+
+    // Returns the next key accepted by the filter with a reference to its latest
+    // value, leaving it to the caller to decide what to clone.
+    fn next_entry(&mut self) -> Option<(&'a P, &'a V)> {
+        if let Some(twig) = self.root_twig.take() {
+            if let Some(value) = self.live_value(twig) {
+                return Some((&twig.key, value));
+            }
+        }
+
+        loop {
+            let frame = self.stack.last_mut()?;
+            let Some((slot, child)) = frame.node.next_child(frame.pos) else {
+                self.stack.pop();
+                continue;
+            };
+            frame.pos = slot + 1;
+            let (path_len, yield_all) = (frame.path_len, frame.yield_all);
+
+            match &child.node_type {
+                NodeType::Twig(twig) => {
+                    if !yield_all && (self.filter)(&twig.key) != ScanDecision::Yield {
+                        continue;
+                    }
+                    if let Some(value) = self.live_value(twig) {
+                        return Some((&twig.key, value));
+                    }
+                }
+                _ => self.visit_inner(child, path_len, yield_all),
+            }
+        }
+    }
+}
+
+impl<'a, P: KeyTrait, V: Clone, F: Fn(&P) -> ScanDecision> Iterator for FilteredScan<'a, P, V, F> {
+    type Item = (P, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry()
+            .map(|(key, value)| (key.clone(), value.clone()))
+    }
+}
+
+// The key filter, projection and value filter of a `ScanOptions`. The
+// projection hands the value filter a borrowed form of its output, and only
+// builds the output once the filter keeps it.
+type KeyFilter<'f, P> = Box<dyn Fn(&P) -> ScanDecision + 'f>;
+type Projection<'f, V, O, B> = Box<dyn Fn(&V, &dyn Fn(&B) -> bool) -> Option<O> + 'f>;
+type ValueFilter<'f, B> = Box<dyn Fn(&B) -> bool + 'f>;
+
+/// The options of a projected scan, see `Tree::scan` and `Snapshot::scan`.
+///
+/// A projected scan prunes the Trie with a key filter like
+/// `Tree::scan_with_filter`, maps the latest value of each remaining key
+/// through a projection while it is still borrowed from the Trie, and yields
+/// the projected values that pass the value filter. Only the projection decides
+/// whether a full value is cloned, so a scan reading one field of large values
+/// never copies the rest of them.
+///
+/// The value filter sees projected values as `B`, which is the output type
+/// itself except for `project_raw`, whose filter sees the bytes before they are
+/// copied out of the Trie.
+pub struct ScanOptions<'f, P, V, O, B: ?Sized = O> {
+    keys: KeyFilter<'f, P>,
+    project: Projection<'f, V, O, B>,
+    filter: Option<ValueFilter<'f, B>>,
+}
+
+impl<'f, P, V: Clone + 'f> ScanOptions<'f, P, V, V> {
+    /// Returns options that scan every key and yield a clone of each value.
+    pub fn new() -> Self {
+        ScanOptions {
+            keys: Box::new(|_| ScanDecision::Yield),
+            project: Box::new(|value, keep| keep(value).then(|| value.clone())),
+            filter: None,
+        }
+    }
+}
+
+impl<'f, P, V: Clone + 'f> Default for ScanOptions<'f, P, V, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'f, P, V: 'f, O, B: ?Sized> ScanOptions<'f, P, V, O, B> {
+    /// Prunes the scan with a key filter, called as described in
+    /// `Tree::scan_with_filter`.
+    pub fn keys<F: Fn(&P) -> ScanDecision + 'f>(mut self, filter: F) -> Self {
+        self.keys = Box::new(filter);
+        self
+    }
+
+    /// Yields `project(value)` in place of each value.
+    ///
+    /// The projection replaces the previous one along with the value filter,
+    /// which was written against the previous output type.
+    pub fn project<O2, F: Fn(&V) -> O2 + 'f>(self, project: F) -> ScanOptions<'f, P, V, O2> {
+        ScanOptions {
+            keys: self.keys,
+            project: Box::new(move |value, keep| {
+                let projected = project(value);
+                keep(&projected).then_some(projected)
+            }),
+            filter: None,
+        }
+    }
+
+    /// Yields the bytes returned by `project` for the encoded bytes of each
+    /// value, or the full value converted to bytes for the values it returns
+    /// `None` for.
+    ///
+    /// The value filter sees the bytes of a declined value in place, so only
+    /// the declined values it keeps are cloned. Like `project`, this replaces
+    /// the previous projection and value filter.
+    pub fn project_raw<F>(self, project: F) -> ScanOptions<'f, P, V, Vec<u8>, [u8]>
+    where
+        V: AsRef<[u8]> + Clone + Into<Vec<u8>>,
+        F: Fn(&[u8]) -> Option<Vec<u8>> + 'f,
+    {
+        ScanOptions {
+            keys: self.keys,
+            project: Box::new(move |value: &V, keep| match project(value.as_ref()) {
+                Some(projected) => keep(&projected).then_some(projected),
+                None => keep(value.as_ref()).then(|| value.clone().into()),
+            }),
+            filter: None,
+        }
+    }
+
+    /// Yields only the projected values for which `filter` returns true.
+    pub fn filter<F: Fn(&B) -> bool + 'f>(mut self, filter: F) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+}
+
+/// A scan yielding keys with their projected latest values, see `ScanOptions`.
+pub struct ProjectedScan<'a, 'f, P: KeyTrait, V: Clone, O, B: ?Sized = O> {
+    scan: FilteredScan<'a, P, V, KeyFilter<'f, P>>,
+    project: Projection<'f, V, O, B>,
+    filter: Option<ValueFilter<'f, B>>,
+}
+
+impl<'a, 'f, P: KeyTrait, V: Clone, O, B: ?Sized> ProjectedScan<'a, 'f, P, V, O, B> {
+    pub(crate) fn new(
+        root: Option<&'a Arc<Node<P, V>>>,
+        options: ScanOptions<'f, P, V, O, B>,
+        live: Option<(&'a ExpiryTable, u64)>,
+    ) -> Self {
+        ProjectedScan {
+            scan: FilteredScan::new(root, options.keys, live),
+            project: options.project,
+            filter: options.filter,
+        }
+    }
+}
+
+impl<'a, 'f, P: KeyTrait, V: Clone, O, B: ?Sized> Iterator for ProjectedScan<'a, 'f, P, V, O, B> {
+    type Item = (P, O);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let filter = &self.filter;
+        let keep = |projected: &B| filter.as_ref().is_none_or(|filter| filter(projected));
+        loop {
+            let (key, value) = self.scan.next_entry()?;
+            if let Some(projected) = (self.project)(value, &keep) {
+                return Some((key.clone(), projected));
+            }
+        }
+    }
+}
+
+/// A byte of a pattern matched by `Snapshot::glob_iter`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PatternByte {
+    /// Matches this byte.
+    Literal(u8),
+    /// Matches any single byte, like `?` in a glob.
+    Any,
+    /// Matches any run of bytes, including an empty one, like `*` in a glob.
+    AnyRun,
+}
+
+impl PatternByte {
+    /// Parses a glob in which `?` and `*` are wildcards and every other byte is
+    /// a literal.
+    pub fn parse_glob(glob: &[u8]) -> Vec<PatternByte> {
+        glob.iter()
+            .map(|&byte| match byte {
+                b'?' => PatternByte::Any,
+                b'*' => PatternByte::AnyRun,
+                byte => PatternByte::Literal(byte),
+            })
+            .collect()
+    }
+}
+
+// The states of the automaton of a pattern are the positions in the pattern,
+// the pattern being matched once its end is reached. A set of states is kept
+// sorted, and closed over the `AnyRun`s it can skip.
+fn skip_any_runs(pattern: &[PatternByte], states: &mut Vec<usize>) {
+    let mut i = 0;
+    while i < states.len() {
+        let state = states[i];
+        if pattern.get(state) == Some(&PatternByte::AnyRun) && !states.contains(&(state + 1)) {
+            states.push(state + 1);
+        }
+        i += 1;
+    }
+    states.sort_unstable();
+}
+
+// Returns the states reached from `states` after matching `bytes`, empty once
+// no extension of the bytes matched so far can match the pattern.
+fn step_states(pattern: &[PatternByte], states: &[usize], bytes: &[u8]) -> Vec<usize> {
+    let mut states = states.to_vec();
+    for &byte in bytes {
+        let mut next = Vec::with_capacity(states.len());
+        for &state in &states {
+            let target = match pattern.get(state) {
+                Some(PatternByte::Literal(literal)) if *literal == byte => state + 1,
+                Some(PatternByte::Any) => state + 1,
+                Some(PatternByte::AnyRun) => state,
+                _ => continue,
+            };
+            if !next.contains(&target) {
+                next.push(target);
+            }
+        }
+        skip_any_runs(pattern, &mut next);
+        states = next;
+        if states.is_empty() {
+            break;
+        }
+    }
+    states
+}
+
+// A node on the path of a `GlobIter`, with the states reached at the end of
+// its prefix.
+struct GlobFrame<'a, P: KeyTrait, V: Clone> {
+    node: &'a Arc<Node<P, V>>,
+    // The next child slot to visit.
+    pos: usize,
+    states: Vec<usize>,
+}
+
+/// An iterator over the keys of the Trie matching a pattern of `PatternByte`s,
+/// with their latest values, in key order.
+///
+/// The trie is walked with the set of pattern positions reached along the path,
+/// and a subtree is skipped as soon as no position is left.
+pub struct GlobIter<'a, P: KeyTrait, V: Clone> {
+    pattern: Vec<PatternByte>,
+    stack: Vec<GlobFrame<'a, P, V>>,
+    // A root twig matching the pattern, yielded before anything else.
+    root_twig: Option<&'a TwigNode<P, V>>,
+}
+
+impl<'a, P: KeyTrait, V: Clone> GlobIter<'a, P, V> {
+    pub(crate) fn new(root: Option<&'a Arc<Node<P, V>>>, pattern: &[PatternByte]) -> Self {
+        let mut start = vec![0];
+        skip_any_runs(pattern, &mut start);
+        let mut iter = GlobIter {
+            pattern: pattern.to_vec(),
+            stack: Vec::new(),
+            root_twig: None,
+        };
+        if let Some(root) = root {
+            match &root.node_type {
+                NodeType::Twig(twig) => {
+                    if Self::matches(pattern, &start, twig) {
+                        iter.root_twig = Some(twig);
+                    }
+                }
+                _ => {
+                    let states = step_states(pattern, &start, root.prefix().as_slice());
+                    iter.push(root, states);
+                }
+            }
+        }
+        iter
+    }
+
+    // Pushes an inner node with the states reached at the end of its prefix,
+    // unless no key below it can match.
+    fn push(&mut self, node: &'a Arc<Node<P, V>>, states: Vec<usize>) {
+        if !states.is_empty() {
+            self.stack.push(GlobFrame {
+                node,
+                pos: 0,
+                states,
+            });
+        }
+    }
+
+    // Returns true if the key of `twig` matches, given the states reached at the
+    // start of its prefix. A trailing NULL terminator is ignored.
+    fn matches(pattern: &[PatternByte], states: &[usize], twig: &TwigNode<P, V>) -> bool {
+        let mut prefix = twig.prefix.as_slice();
+        if twig.key.as_slice().last() == Some(&0) {
+            prefix = &prefix[..prefix.len().saturating_sub(1)];
+        }
+        step_states(pattern, states, prefix).contains(&pattern.len())
+    }
+}
+
+impl<'a, P: KeyTrait, V: Clone> Iterator for GlobIter<'a, P, V> {
+    type Item = (P, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(twig) = self.root_twig.take() {
+            if let Some(value) = twig.get_latest_value() {
+                return Some((twig.key.clone(), value.clone()));
+            }
+        }
+
+        loop {
+            let frame = self.stack.last_mut()?;
+            let Some((slot, child)) = frame.node.next_child(frame.pos) else {
+                self.stack.pop();
+                continue;
+            };
+            frame.pos = slot + 1;
+
+            match &child.node_type {
+                NodeType::Twig(twig) => {
+                    if !Self::matches(&self.pattern, &frame.states, twig) {
+                        continue;
+                    }
+                    if let Some(value) = twig.get_latest_value() {
+                        return Some((twig.key.clone(), value.clone()));
+                    }
+                }
+                _ => {
+                    let states =
+                        step_states(&self.pattern, &frame.states, child.prefix().as_slice());
+                    self.push(child, states);
+                }
+            }
+        }
+    }
+}
+
+/// An iteration over the latest values of the Trie that leaves out the keys
+/// under expired prefixes.
+///
+/// The rules are consulted with the path to every inner node, and a subtree is
+/// skipped without being visited once the rules say every key below it has
+/// expired. Subtrees no rule reaches into are walked without asking again.
+pub(crate) struct LiveIter<'a, P: KeyTrait, V: Clone> {
+    stack: Vec<ScanFrame<'a, P, V>>,
+    root_twig: Option<&'a TwigNode<P, V>>,
+    path: Vec<u8>,
+    rules: &'a ExpiryTable,
+    now: u64,
+    /// The number of steps the last call to `advance` took.
+    steps: usize,
+}
+
+impl<'a, P: KeyTrait, V: Clone> LiveIter<'a, P, V> {
+    pub(crate) fn new(root: Option<&'a Arc<Node<P, V>>>, rules: &'a ExpiryTable, now: u64) -> Self {
+        let mut iter = LiveIter {
+            stack: Vec::new(),
+            root_twig: None,
+            path: Vec::new(),
+            rules,
+            now,
+            steps: 0,
+        };
+        if let Some(root) = root {
+            match &root.node_type {
+                NodeType::Twig(twig) => {
+                    if !rules.is_expired(twig.key.as_slice(), now) {
+                        iter.root_twig = Some(twig);
+                    }
+                }
+                _ => iter.visit_inner(root, 0, false),
+            }
+        }
+        iter
+    }
+
+    fn visit_inner(&mut self, node: &'a Arc<Node<P, V>>, path_len: usize, yield_all: bool) {
+        self.path.truncate(path_len);
+        self.path.extend_from_slice(node.prefix().as_slice());
+        let decision = if yield_all {
+            ScanDecision::Yield
+        } else {
+            self.rules.decide_subtree(&self.path, self.now)
+        };
+        if decision != ScanDecision::SkipSubtree {
+            self.stack.push(ScanFrame {
+                node,
+                pos: 0,
+                path_len: self.path.len(),
+                yield_all: decision == ScanDecision::Yield,
+            });
+        }
+    }
+}
+
+impl<'a, P: KeyTrait, V: Clone> LiveIter<'a, P, V> {
+    /// Moves to the next live entry in key order, unless that takes more than
+    /// `budget` steps, each moving an inner node to its next child.
+    #[allow(clippy::type_complexity)]
+    fn advance(&mut self, budget: usize) -> Poll<Option<(Vec<u8>, &'a V, &'a u64, &'a u64)>> {
+        let leaf = |twig: &'a TwigNode<P, V>| {
+            twig.get_latest_leaf().map(|leaf| {
+                (
+                    twig.key.as_slice().to_vec(),
+                    &leaf.value,
+                    &leaf.version,
+                    &leaf.ts,
+                )
+            })
+        };
+        self.steps = 0;
+        if let Some(twig) = self.root_twig.take() {
+            if let Some(entry) = leaf(twig) {
+                return Poll::Ready(Some(entry));
+            }
+        }
+
+        loop {
+            let Some(frame) = self.stack.last_mut() else {
+                return Poll::Ready(None);
+            };
+            if self.steps >= budget {
+                return Poll::Pending;
+            }
+            self.steps += 1;
+            let Some((slot, child)) = frame.node.next_child(frame.pos) else {
+                self.stack.pop();
+                continue;
+            };
+            frame.pos = slot + 1;
+            let (path_len, yield_all) = (frame.path_len, frame.yield_all);
+
+            match &child.node_type {
+                NodeType::Twig(twig) => {
+                    if !yield_all && self.rules.is_expired(twig.key.as_slice(), self.now) {
+                        continue;
+                    }
+                    if let Some(entry) = leaf(twig) {
+                        return Poll::Ready(Some(entry));
+                    }
+                }
+                _ => self.visit_inner(child, path_len, yield_all),
+            }
+        }
+    }
+}
+
+/// An inconsistency found in the Trie by `LossyIter`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraversalFault {
+    /// The key stored in a twig node differs from the key reconstructed from the
+    /// prefixes on the path to it, or a child's prefix does not start with the
+    /// key byte it is stored under.
+    KeyMismatch { path: Vec<u8>, key: Vec<u8> },
+    /// An inner node has a key without a child node.
+    MissingChild { path: Vec<u8>, key: u8 },
+    /// A twig node holds no values.
+    EmptyTwig { key: Vec<u8> },
+}
+
+impl Error for TraversalFault {}
+
+impl fmt::Display for TraversalFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraversalFault::KeyMismatch { path, key } => {
+                write!(f, "Key {:?} does not match its path {:?}", key, path)
+            }
+            TraversalFault::MissingChild { path, key } => {
+                write!(f, "Missing child for key {} under path {:?}", key, path)
+            }
+            TraversalFault::EmptyTwig { key } => write!(f, "Twig for key {:?} has no values", key),
+        }
+    }
+}
+
+type LossyItem<'a, V> = Result<(Vec<u8>, &'a V, &'a u64, &'a u64), TraversalFault>;
+
+/// A fault-tolerant iterator over key-value pairs in the Trie.
+///
+/// Each level of the traversal keeps the key bytes reconstructed from the node
+/// prefixes on the path to it, which are checked against the keys stored in the
+/// twig nodes.
+pub struct LossyIter<'a, P: KeyTrait + 'a, V: Clone> {
+    root: Option<&'a Arc<Node<P, V>>>,
+    #[allow(clippy::type_complexity)]
+    stack: Vec<(
+        Vec<u8>,
+        Box<dyn Iterator<Item = (u8, Option<&'a Arc<Node<P, V>>>)> + 'a>,
+    )>,
+}
+
+impl<'a, P: KeyTrait + 'a, V: Clone> LossyIter<'a, P, V> {
+    pub(crate) fn new(root: &'a Arc<Node<P, V>>) -> Self {
+        Self {
+            root: Some(root),
+            stack: Vec::new(),
+        }
+    }
+
+    /// Visits `node`, reached through `slot` from the node at `parent_path`.
+    ///
+    /// Inner nodes are pushed on the stack and yield nothing; twig nodes yield
+    /// their latest value or a fault.
+    fn visit(
+        &mut self,
+        node: &'a Arc<Node<P, V>>,
+        parent_path: &[u8],
+        slot: Option<u8>,
+    ) -> Option<LossyItem<'a, V>> {
+        let prefix = node.prefix().as_slice();
+        let mut path = Vec::with_capacity(parent_path.len() + prefix.len());
+        path.extend_from_slice(parent_path);
+        path.extend_from_slice(prefix);
+
+        if let Some(slot) = slot {
+            if prefix.first() != Some(&slot) {
+                return Some(Err(TraversalFault::KeyMismatch {
+                    path,
+                    key: node.prefix().as_slice().to_vec(),
+                }));
+            }
+        }
+
+        match &node.node_type {
+            NodeType::Twig(twig) => {
+                let key = twig.key.as_slice();
+                let Some(leaf) = twig.get_latest_leaf() else {
+                    return Some(Err(TraversalFault::EmptyTwig { key: key.to_vec() }));
+                };
+                if path != key {
+                    return Some(Err(TraversalFault::KeyMismatch {
+                        path,
+                        key: key.to_vec(),
+                    }));
+                }
+                Some(Ok((path, &leaf.value, &leaf.version, &leaf.ts)))
+            }
+            _ => {
+                self.stack.push((path, node.iter_checked()));
+                None
+            }
+        }
+    }
+}
+
+impl<'a, P: KeyTrait + 'a, V: Clone> Iterator for LossyIter<'a, P, V> {
+    type Item = LossyItem<'a, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root) = self.root.take() {
+            if let Some(item) = self.visit(root, &[], None) {
+                return Some(item);
+            }
+        }
+
+        while let Some((path, children)) = self.stack.last_mut() {
+            match children.next() {
+                None => {
+                    self.stack.pop();
+                }
+                Some((key, None)) => {
+                    return Some(Err(TraversalFault::MissingChild {
+                        path: path.clone(),
+                        key,
+                    }));
+                }
+                Some((key, Some(child))) => {
+                    let path = path.clone();
+                    if let Some(item) = self.visit(child, &path, Some(key)) {
+                        return Some(item);
+                    }
+                }
+            }
+        }
+
+        None
     }
 }
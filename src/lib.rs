@@ -1,8 +1,39 @@
 // #[allow(warnings)]
+pub mod arena;
 pub mod art;
+pub mod checksum;
+pub mod codec;
+pub mod cost;
+pub mod cursor;
+pub mod diff;
+mod domain;
+mod expiry;
+pub mod frozen;
+mod gate;
+mod hash_index;
+pub mod hook;
+pub mod ingest;
 pub mod iter;
+pub mod lock;
+pub mod map;
+pub mod namespace;
 pub mod node;
+pub mod normalize;
+mod persist;
+pub mod pin;
+mod popularity;
+pub mod prepare;
+pub mod pressure;
+pub mod record;
+pub mod sample;
 pub mod snapshot;
+pub mod stats;
+pub mod strict;
+mod suffix;
+pub mod testing;
+mod tombstone;
+pub mod transaction;
+pub mod view;
 
 use std::cmp::{Ord, Ordering, PartialOrd};
 use std::error::Error;
@@ -11,6 +42,9 @@ use std::fmt::Debug;
 use std::mem::MaybeUninit;
 use std::str::FromStr;
 
+use crate::ingest::TsViolation;
+use crate::strict::InvariantViolation;
+
 // "Partial" in the Adaptive Radix Tree paper refers to "partial keys", a technique employed
 // for prefix compression in this data structure. Instead of storing entire keys in the nodes,
 // ART nodes often only store partial keys, which are the differing prefixes of the keys.
@@ -23,8 +57,53 @@ pub trait Key {
     fn prefix_after(&self, start: usize) -> Self;
     fn longest_common_prefix(&self, slice: &[u8]) -> usize;
     fn as_slice(&self) -> &[u8];
+
+    /// The length of every key of the type stored in a Trie, if it is fixed.
+    ///
+    /// Lookups of a key of that length then descend over its bytes directly,
+    /// and are bounded by the length. Fragments of keys, such as the prefixes
+    /// of nodes, can still be shorter.
+    const FIXED_LEN: Option<usize> = None;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of bytes a key of `len` bytes holds on the heap, used
+    /// by `Tree::estimate_insert_cost`. Keys stored inline hold none.
+    fn heap_size(len: usize) -> usize
+    where
+        Self: Sized,
+    {
+        let _ = len;
+        0
+    }
 }
 
+/// The bound on key types stored in the Trie.
+///
+/// It is implemented for every type that implements `Key` along with the
+/// conversion and comparison traits below. The Trie works purely on the byte
+/// representation returned by `as_slice`, so a custom key type must keep all of
+/// its methods consistent with those bytes:
+///
+/// * `len()` is the length of `as_slice()` and `at(i)` is `as_slice()[i]`.
+/// * `K::from(k.as_slice())` gives back a key equal to `k`.
+/// * `prefix_before(n)` and `prefix_after(n)` return keys whose bytes are
+///   `as_slice()[..n]` and `as_slice()[n..]`, for every `n` up to `len()`, so
+///   that the two parts concatenate back to the key.
+/// * `longest_common_prefix(other)` is the length of the common prefix of
+///   `as_slice()` and `other`, which makes it symmetric between two keys and
+///   equal to `len()` for the key's own bytes.
+/// * `Ord` and `PartialEq` agree with the order and equality of the bytes;
+///   iteration and range scans return keys in byte order.
+/// * No stored key is a proper prefix of another. Variable length keys get this
+///   by ending in a NUL terminator (see `VariableSizeKey::from_str`), fixed
+///   length keys by construction.
+///
+/// Breaking any of these does not produce an error: lookups miss and iteration
+/// skips or misorders keys. `testing::check_key_impl` checks an implementation
+/// against this contract for a set of sample keys.
 pub trait KeyTrait:
     Key + Clone + PartialEq + PartialOrd + Ord + Debug + for<'a> From<&'a [u8]>
 {
@@ -34,6 +113,18 @@ impl<T: Key + Clone + PartialOrd + PartialEq + Ord + Debug + for<'a> From<&'a [u
 {
 }
 
+/// Fails with `TrieError::InvalidKeyLength` if keys of the type of `key` have a
+/// fixed length that `key` does not have.
+pub(crate) fn check_key_len<K: Key>(key: &K) -> Result<(), TrieError> {
+    match K::FIXED_LEN {
+        Some(expected) if key.len() != expected => Err(TrieError::InvalidKeyLength {
+            expected,
+            found: key.len(),
+        }),
+        _ => Ok(()),
+    }
+}
+
 /*
     Key trait implementations
 */
@@ -206,6 +297,98 @@ impl<const N: usize> From<&String> for FixedSizeKey<N> {
     }
 }
 
+/// A key of exactly `N` bytes, such as a hash, checked at compile time.
+///
+/// Since no key of the same length can be a prefix of another, the bytes are
+/// stored as they are, without a terminator, and the Trie can bound its
+/// descent by `N`. Keys built with `From<&[u8]>` may be shorter, as the Trie
+/// does for the fragments of keys it keeps in its nodes, but writes of keys of
+/// any other length than `N` fail with `TrieError::InvalidKeyLength`.
+#[derive(Clone, Copy, Debug, Eq)]
+pub struct FixedKey<const N: usize> {
+    content: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> FixedKey<N> {
+    pub fn new(content: [u8; N]) -> Self {
+        Self { content, len: N }
+    }
+}
+
+impl<const N: usize> PartialEq for FixedKey<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<const N: usize> PartialOrd for FixedKey<N> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<const N: usize> Ord for FixedKey<N> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_slice().cmp(other.as_slice())
+    }
+}
+
+impl<const N: usize> Key for FixedKey<N> {
+    const FIXED_LEN: Option<usize> = Some(N);
+
+    fn as_slice(&self) -> &[u8] {
+        &self.content[..self.len]
+    }
+
+    fn prefix_before(&self, length: usize) -> Self {
+        assert!(length <= self.len);
+        Self::from(&self.content[..length])
+    }
+
+    fn prefix_after(&self, start: usize) -> Self {
+        assert!(start <= self.len);
+        Self::from(&self.content[start..self.len])
+    }
+
+    #[inline(always)]
+    fn at(&self, pos: usize) -> u8 {
+        assert!(pos < self.len);
+        self.content[pos]
+    }
+
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn longest_common_prefix(&self, key: &[u8]) -> usize {
+        self.as_slice()
+            .iter()
+            .zip(key)
+            .take_while(|&(a, &b)| *a == b)
+            .count()
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for FixedKey<N> {
+    fn from(content: [u8; N]) -> Self {
+        Self::new(content)
+    }
+}
+
+impl<const N: usize> From<&[u8]> for FixedKey<N> {
+    fn from(src: &[u8]) -> Self {
+        assert!(src.len() <= N, "data length is greater than array length");
+        let mut content = [0; N];
+        content[..src.len()].copy_from_slice(src);
+        Self {
+            content,
+            len: src.len(),
+        }
+    }
+}
+
 // A VariableSizeKey is a variable-length datatype with NULL byte appended to it.
 #[derive(Clone, PartialEq, PartialOrd, Ord, Eq, Debug)]
 pub struct VariableSizeKey {
@@ -308,6 +491,10 @@ impl Key for VariableSizeKey {
     fn as_slice(&self) -> &[u8] {
         &self.data[..self.data.len()]
     }
+
+    fn heap_size(len: usize) -> usize {
+        len
+    }
 }
 
 /*
@@ -319,6 +506,12 @@ pub struct BitSet<const SIZE: usize> {
     bits: [bool; SIZE],
 }
 
+impl<const SIZE: usize> Default for BitSet<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<const SIZE: usize> BitSet<SIZE> {
     pub fn new() -> Self {
         Self {
@@ -534,19 +727,308 @@ impl<X, const WIDTH: usize> Drop for BitArray<X, WIDTH> {
 }
 
 // Define a custom error enum representing different error cases for the Trie
-#[derive(Clone, Debug)]
+/// An error returned by the Trie.
+///
+/// Every variant has a stable numeric code, returned by `code`, for reporting
+/// errors across process boundaries. See `TrieErrorKind` for the numbering. The
+/// enum is non-exhaustive, so matches on it need a wildcard arm; mapping the
+/// `category` instead keeps new variants from being handled as unknown errors.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum TrieError {
     IllegalArguments,
     NotFound,
     KeyNotFound,
     SnapshotNotFound,
     SnapshotEmpty,
+    NotSampled,
+    ReaderNotFound,
     SnapshotNotClosed,
     SnapshotAlreadyClosed,
+    SnapshotClosing,
     SnapshotReadersNotClosed,
     TreeAlreadyClosed,
     FixedSizeKeyLengthExceeded,
+    InvalidKeyLength {
+        expected: usize,
+        found: usize,
+    },
+    PrefixLocked {
+        owner: u64,
+    },
+    ReplayDiverged {
+        op: usize,
+    },
+    PrefixStatsMismatch {
+        segment: Vec<u8>,
+    },
+    SuffixIndexMismatch {
+        key: Vec<u8>,
+    },
+    DuplicateTimestamp,
+    TimestampConflict {
+        expected: u64,
+        current: Option<u64>,
+    },
+    InvalidStructure {
+        path: Vec<u8>,
+        reason: &'static str,
+    },
+    Poisoned {
+        first_violation: InvariantViolation,
+    },
+    CorruptChangelog {
+        offset: u64,
+        reason: &'static str,
+    },
+    InvalidTimestamp {
+        key: Vec<u8>,
+        position: usize,
+        ts: u64,
+        violation: TsViolation,
+    },
+    TransactionConflict {
+        key: Vec<u8>,
+    },
+    PublishOutOfOrder {
+        expected: u64,
+        found: u64,
+    },
+    ChecksumMismatch {
+        key: Vec<u8>,
+        ts: u64,
+    },
+    CorruptFile {
+        reason: String,
+    },
+    FileChecksumMismatch {
+        expected: u32,
+        found: u32,
+    },
     Other(String),
+    Io {
+        kind: std::io::ErrorKind,
+        message: String,
+    },
+}
+
+impl TrieError {
+    /// Returns the kind of the error, without its details.
+    pub fn kind(&self) -> TrieErrorKind {
+        match self {
+            TrieError::IllegalArguments => TrieErrorKind::IllegalArguments,
+            TrieError::NotFound => TrieErrorKind::NotFound,
+            TrieError::KeyNotFound => TrieErrorKind::KeyNotFound,
+            TrieError::SnapshotNotFound => TrieErrorKind::SnapshotNotFound,
+            TrieError::SnapshotEmpty => TrieErrorKind::SnapshotEmpty,
+            TrieError::NotSampled => TrieErrorKind::NotSampled,
+            TrieError::ReaderNotFound => TrieErrorKind::ReaderNotFound,
+            TrieError::SnapshotNotClosed => TrieErrorKind::SnapshotNotClosed,
+            TrieError::SnapshotAlreadyClosed => TrieErrorKind::SnapshotAlreadyClosed,
+            TrieError::SnapshotClosing => TrieErrorKind::SnapshotClosing,
+            TrieError::SnapshotReadersNotClosed => TrieErrorKind::SnapshotReadersNotClosed,
+            TrieError::TreeAlreadyClosed => TrieErrorKind::TreeAlreadyClosed,
+            TrieError::FixedSizeKeyLengthExceeded => TrieErrorKind::FixedSizeKeyLengthExceeded,
+            TrieError::InvalidKeyLength { .. } => TrieErrorKind::InvalidKeyLength,
+            TrieError::PrefixLocked { .. } => TrieErrorKind::PrefixLocked,
+            TrieError::ReplayDiverged { .. } => TrieErrorKind::ReplayDiverged,
+            TrieError::PrefixStatsMismatch { .. } => TrieErrorKind::PrefixStatsMismatch,
+            TrieError::SuffixIndexMismatch { .. } => TrieErrorKind::SuffixIndexMismatch,
+            TrieError::DuplicateTimestamp => TrieErrorKind::DuplicateTimestamp,
+            TrieError::TimestampConflict { .. } => TrieErrorKind::TimestampConflict,
+            TrieError::InvalidStructure { .. } => TrieErrorKind::InvalidStructure,
+            TrieError::Poisoned { .. } => TrieErrorKind::Poisoned,
+            TrieError::CorruptChangelog { .. } => TrieErrorKind::CorruptChangelog,
+            TrieError::InvalidTimestamp { .. } => TrieErrorKind::InvalidTimestamp,
+            TrieError::TransactionConflict { .. } => TrieErrorKind::TransactionConflict,
+            TrieError::PublishOutOfOrder { .. } => TrieErrorKind::PublishOutOfOrder,
+            TrieError::ChecksumMismatch { .. } => TrieErrorKind::ChecksumMismatch,
+            TrieError::CorruptFile { .. } => TrieErrorKind::CorruptFile,
+            TrieError::FileChecksumMismatch { .. } => TrieErrorKind::FileChecksumMismatch,
+            TrieError::Other(_) => TrieErrorKind::Other,
+            TrieError::Io { .. } => TrieErrorKind::Io,
+        }
+    }
+
+    /// Returns the stable numeric code of the error.
+    pub fn code(&self) -> u16 {
+        self.kind().code()
+    }
+
+    /// Returns the category of the error.
+    pub fn category(&self) -> ErrorCategory {
+        self.kind().category()
+    }
+
+    /// Returns the kind of error with the given code, or `None` if no error has
+    /// the code.
+    pub fn from_code(code: u16) -> Option<TrieErrorKind> {
+        TrieErrorKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.code() == code)
+    }
+}
+
+/// The broad class of a `TrieError`, for mapping errors to the status codes of
+/// a protocol.
+///
+/// Each category owns a range of a thousand error codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorCategory {
+    /// The arguments of the call are invalid. Codes 1000 to 1999.
+    InvalidArgument,
+    /// A key, snapshot or other item does not exist. Codes 2000 to 2999.
+    NotFound,
+    /// The call conflicts with a lock or a concurrent write. Codes 3000 to 3999.
+    Conflict,
+    /// The Trie or snapshot is closed, or in a state that forbids the call.
+    /// Codes 4000 to 4999.
+    Lifecycle,
+    /// The Trie or its input is corrupt. Codes 5000 to 5999.
+    Corruption,
+    /// A limit on resources has been reached. Codes 6000 to 6999.
+    Resource,
+    /// The error is not classified. Codes 9000 to 9999.
+    Unclassified,
+}
+
+impl ErrorCategory {
+    /// Returns the category owning the given code, whether or not an error has
+    /// the code.
+    pub fn of_code(code: u16) -> Option<ErrorCategory> {
+        match code / 1000 {
+            1 => Some(ErrorCategory::InvalidArgument),
+            2 => Some(ErrorCategory::NotFound),
+            3 => Some(ErrorCategory::Conflict),
+            4 => Some(ErrorCategory::Lifecycle),
+            5 => Some(ErrorCategory::Corruption),
+            6 => Some(ErrorCategory::Resource),
+            9 => Some(ErrorCategory::Unclassified),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of a `TrieError`, with one variant per variant of the error.
+///
+/// The code of a kind is fixed once released: codes are never renumbered, and
+/// the codes of removed kinds are never reused. New kinds take the next free
+/// code in the range of their category.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum TrieErrorKind {
+    IllegalArguments,
+    NotFound,
+    KeyNotFound,
+    SnapshotNotFound,
+    SnapshotEmpty,
+    NotSampled,
+    ReaderNotFound,
+    SnapshotNotClosed,
+    SnapshotAlreadyClosed,
+    SnapshotClosing,
+    SnapshotReadersNotClosed,
+    TreeAlreadyClosed,
+    FixedSizeKeyLengthExceeded,
+    InvalidKeyLength,
+    PrefixLocked,
+    ReplayDiverged,
+    PrefixStatsMismatch,
+    SuffixIndexMismatch,
+    DuplicateTimestamp,
+    TimestampConflict,
+    InvalidStructure,
+    Poisoned,
+    CorruptChangelog,
+    InvalidTimestamp,
+    TransactionConflict,
+    PublishOutOfOrder,
+    ChecksumMismatch,
+    CorruptFile,
+    FileChecksumMismatch,
+    Other,
+    Io,
+}
+
+impl TrieErrorKind {
+    /// Every kind, in the order of their codes.
+    pub const ALL: &'static [TrieErrorKind] = &[
+        TrieErrorKind::IllegalArguments,
+        TrieErrorKind::FixedSizeKeyLengthExceeded,
+        TrieErrorKind::InvalidTimestamp,
+        TrieErrorKind::InvalidKeyLength,
+        TrieErrorKind::NotFound,
+        TrieErrorKind::KeyNotFound,
+        TrieErrorKind::SnapshotNotFound,
+        TrieErrorKind::SnapshotEmpty,
+        TrieErrorKind::NotSampled,
+        TrieErrorKind::ReaderNotFound,
+        TrieErrorKind::PrefixLocked,
+        TrieErrorKind::DuplicateTimestamp,
+        TrieErrorKind::TimestampConflict,
+        TrieErrorKind::TransactionConflict,
+        TrieErrorKind::PublishOutOfOrder,
+        TrieErrorKind::SnapshotNotClosed,
+        TrieErrorKind::SnapshotAlreadyClosed,
+        TrieErrorKind::SnapshotClosing,
+        TrieErrorKind::SnapshotReadersNotClosed,
+        TrieErrorKind::TreeAlreadyClosed,
+        TrieErrorKind::ReplayDiverged,
+        TrieErrorKind::PrefixStatsMismatch,
+        TrieErrorKind::SuffixIndexMismatch,
+        TrieErrorKind::InvalidStructure,
+        TrieErrorKind::Poisoned,
+        TrieErrorKind::CorruptChangelog,
+        TrieErrorKind::ChecksumMismatch,
+        TrieErrorKind::CorruptFile,
+        TrieErrorKind::FileChecksumMismatch,
+        TrieErrorKind::Other,
+        TrieErrorKind::Io,
+    ];
+
+    /// Returns the stable numeric code of the kind.
+    pub fn code(self) -> u16 {
+        match self {
+            TrieErrorKind::IllegalArguments => 1000,
+            TrieErrorKind::FixedSizeKeyLengthExceeded => 1001,
+            TrieErrorKind::InvalidTimestamp => 1002,
+            TrieErrorKind::InvalidKeyLength => 1003,
+            TrieErrorKind::NotFound => 2000,
+            TrieErrorKind::KeyNotFound => 2001,
+            TrieErrorKind::SnapshotNotFound => 2002,
+            TrieErrorKind::SnapshotEmpty => 2003,
+            TrieErrorKind::NotSampled => 2004,
+            TrieErrorKind::ReaderNotFound => 2005,
+            TrieErrorKind::PrefixLocked => 3000,
+            TrieErrorKind::DuplicateTimestamp => 3001,
+            TrieErrorKind::TimestampConflict => 3002,
+            TrieErrorKind::TransactionConflict => 3003,
+            TrieErrorKind::PublishOutOfOrder => 3004,
+            TrieErrorKind::SnapshotNotClosed => 4000,
+            TrieErrorKind::SnapshotAlreadyClosed => 4001,
+            TrieErrorKind::SnapshotClosing => 4002,
+            TrieErrorKind::SnapshotReadersNotClosed => 4003,
+            TrieErrorKind::TreeAlreadyClosed => 4004,
+            TrieErrorKind::ReplayDiverged => 5000,
+            TrieErrorKind::PrefixStatsMismatch => 5001,
+            TrieErrorKind::SuffixIndexMismatch => 5002,
+            TrieErrorKind::InvalidStructure => 5003,
+            TrieErrorKind::Poisoned => 5004,
+            TrieErrorKind::CorruptChangelog => 5005,
+            TrieErrorKind::ChecksumMismatch => 5006,
+            TrieErrorKind::CorruptFile => 5007,
+            TrieErrorKind::FileChecksumMismatch => 5008,
+            TrieErrorKind::Other => 9000,
+            TrieErrorKind::Io => 9001,
+        }
+    }
+
+    /// Returns the category of the kind, given by the range of its code.
+    pub fn category(self) -> ErrorCategory {
+        ErrorCategory::of_code(self.code()).expect("error codes are within a category")
+    }
 }
 
 impl Error for TrieError {}
@@ -561,20 +1043,113 @@ impl fmt::Display for TrieError {
             TrieError::SnapshotNotFound => write!(f, "Snapshot not found"),
             TrieError::SnapshotNotClosed => write!(f, "Snapshot not closed"),
             TrieError::SnapshotAlreadyClosed => write!(f, "Snapshot already closed"),
+            TrieError::SnapshotClosing => write!(f, "Snapshot is closing"),
             TrieError::SnapshotReadersNotClosed => {
                 write!(f, "Readers in the snapshot are not closed")
             }
             TrieError::TreeAlreadyClosed => write!(f, "Tree already closed"),
             TrieError::Other(ref message) => write!(f, "Other error: {}", message),
             TrieError::SnapshotEmpty => write!(f, "Snapshot is empty"),
+            TrieError::NotSampled => write!(f, "Key is not in the sample"),
+            TrieError::ReaderNotFound => write!(f, "Reader not found"),
             TrieError::FixedSizeKeyLengthExceeded => write!(f, "Fixed key length exceeded"),
+            TrieError::InvalidKeyLength { expected, found } => write!(
+                f,
+                "Key of {} bytes written where keys have {} bytes",
+                found, expected
+            ),
+            TrieError::PrefixLocked { owner } => {
+                write!(f, "Prefix is locked by owner {}", owner)
+            }
+            TrieError::ReplayDiverged { op } => {
+                write!(f, "Replay diverged from the recording at operation {}", op)
+            }
+            TrieError::PrefixStatsMismatch { ref segment } => {
+                write!(
+                    f,
+                    "Prefix statistics do not match the tree for segment {:?}",
+                    segment
+                )
+            }
+            TrieError::SuffixIndexMismatch { ref key } => {
+                write!(f, "Suffix index does not match the tree for key {:?}", key)
+            }
+            TrieError::DuplicateTimestamp => {
+                write!(f, "Key already has a version at the given timestamp")
+            }
+            TrieError::TimestampConflict { expected, current } => match current {
+                Some(current) => write!(
+                    f,
+                    "Key has timestamp {} instead of the expected {}",
+                    current, expected
+                ),
+                None => write!(f, "Key is absent instead of at timestamp {}", expected),
+            },
+            TrieError::InvalidStructure {
+                ref path,
+                ref reason,
+            } => {
+                write!(f, "Invalid node at path {:?}: {}", path, reason)
+            }
+            TrieError::Poisoned {
+                ref first_violation,
+            } => {
+                write!(f, "Tree is poisoned: {}", first_violation)
+            }
+            TrieError::CorruptChangelog { offset, reason } => {
+                write!(
+                    f,
+                    "Corrupt changelog record at offset {}: {}",
+                    offset, reason
+                )
+            }
+            TrieError::InvalidTimestamp {
+                ref key,
+                position,
+                ts,
+                ref violation,
+            } => {
+                write!(
+                    f,
+                    "Invalid timestamp {} for key {:?} at position {}: {}",
+                    ts, key, position, violation
+                )
+            }
+            TrieError::TransactionConflict { ref key } => {
+                write!(f, "Key {:?} changed since the transaction read it", key)
+            }
+            TrieError::PublishOutOfOrder { expected, found } => {
+                write!(
+                    f,
+                    "Prepared write {} published out of order, expected {}",
+                    found, expected
+                )
+            }
+            TrieError::ChecksumMismatch { ref key, ts } => {
+                write!(
+                    f,
+                    "Value of key {:?} at timestamp {} does not match its checksum",
+                    key, ts
+                )
+            }
+            TrieError::CorruptFile { ref reason } => {
+                write!(f, "Corrupt tree file: {}", reason)
+            }
+            TrieError::FileChecksumMismatch { expected, found } => {
+                write!(
+                    f,
+                    "Tree file checksum is {:#010x}, expected {:#010x}",
+                    found, expected
+                )
+            }
+            TrieError::Io { ref message, .. } => write!(f, "I/O error: {}", message),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::BitArray;
+    use super::{BitArray, ErrorCategory, TrieError, TrieErrorKind};
 
     #[test]
     fn push_and_pop() {
@@ -667,4 +1242,65 @@ mod tests {
         let values: Vec<(usize, &i32)> = v.iter().collect();
         assert_eq!(values, vec![(0, &5), (1, &6)]);
     }
+
+    // The released error codes. Codes are a stable contract: a new kind adds a
+    // row with a fresh code, and no existing row may change.
+    #[test]
+    fn error_codes_are_stable() {
+        use ErrorCategory::*;
+
+        let table = [
+            (TrieErrorKind::IllegalArguments, 1000, InvalidArgument),
+            (
+                TrieErrorKind::FixedSizeKeyLengthExceeded,
+                1001,
+                InvalidArgument,
+            ),
+            (TrieErrorKind::InvalidTimestamp, 1002, InvalidArgument),
+            (TrieErrorKind::InvalidKeyLength, 1003, InvalidArgument),
+            (TrieErrorKind::NotFound, 2000, NotFound),
+            (TrieErrorKind::KeyNotFound, 2001, NotFound),
+            (TrieErrorKind::SnapshotNotFound, 2002, NotFound),
+            (TrieErrorKind::SnapshotEmpty, 2003, NotFound),
+            (TrieErrorKind::NotSampled, 2004, NotFound),
+            (TrieErrorKind::ReaderNotFound, 2005, NotFound),
+            (TrieErrorKind::PrefixLocked, 3000, Conflict),
+            (TrieErrorKind::DuplicateTimestamp, 3001, Conflict),
+            (TrieErrorKind::TimestampConflict, 3002, Conflict),
+            (TrieErrorKind::TransactionConflict, 3003, Conflict),
+            (TrieErrorKind::PublishOutOfOrder, 3004, Conflict),
+            (TrieErrorKind::SnapshotNotClosed, 4000, Lifecycle),
+            (TrieErrorKind::SnapshotAlreadyClosed, 4001, Lifecycle),
+            (TrieErrorKind::SnapshotClosing, 4002, Lifecycle),
+            (TrieErrorKind::SnapshotReadersNotClosed, 4003, Lifecycle),
+            (TrieErrorKind::TreeAlreadyClosed, 4004, Lifecycle),
+            (TrieErrorKind::ReplayDiverged, 5000, Corruption),
+            (TrieErrorKind::PrefixStatsMismatch, 5001, Corruption),
+            (TrieErrorKind::SuffixIndexMismatch, 5002, Corruption),
+            (TrieErrorKind::InvalidStructure, 5003, Corruption),
+            (TrieErrorKind::Poisoned, 5004, Corruption),
+            (TrieErrorKind::CorruptChangelog, 5005, Corruption),
+            (TrieErrorKind::ChecksumMismatch, 5006, Corruption),
+            (TrieErrorKind::CorruptFile, 5007, Corruption),
+            (TrieErrorKind::FileChecksumMismatch, 5008, Corruption),
+            (TrieErrorKind::Other, 9000, Unclassified),
+            (TrieErrorKind::Io, 9001, Unclassified),
+        ];
+
+        // Codes are unique, the table being in the order of its codes.
+        assert!(table.windows(2).all(|rows| rows[0].1 < rows[1].1));
+        assert_eq!(TrieErrorKind::ALL.len(), table.len());
+        for (i, (kind, code, category)) in table.into_iter().enumerate() {
+            assert_eq!(TrieErrorKind::ALL[i], kind);
+            assert_eq!((kind.code(), kind.category()), (code, category));
+            assert_eq!(TrieError::from_code(code), Some(kind));
+        }
+        assert_eq!(TrieError::from_code(1004), None);
+        assert_eq!(TrieError::from_code(0), None);
+
+        let err = TrieError::TransactionConflict { key: b"k".to_vec() };
+        assert_eq!(err.kind(), TrieErrorKind::TransactionConflict);
+        assert_eq!((err.code(), err.category()), (3003, Conflict));
+        assert_eq!(TrieError::Other("full".to_string()).code(), 9000);
+    }
 }
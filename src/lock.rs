@@ -0,0 +1,178 @@
+//! This module defines the advisory prefix lock table used by the Tree to keep
+//! independent writers on disjoint parts of the keyspace.
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::TrieError;
+
+/// A table of advisory locks keyed by the locked prefix.
+///
+/// The table is purely bookkeeping: it does not block threads, it only lets the
+/// Tree reject writes under a prefix that is held by another owner. Prefixes are
+/// kept in a `BTreeMap` so that overlapping prefixes can be found with ordered
+/// range scans instead of a full walk of the table.
+#[derive(Default)]
+pub(crate) struct PrefixLockTable {
+    locks: Mutex<BTreeMap<Vec<u8>, u64>>,
+    // Number of held locks, read without taking the mutex so that writes stay
+    // cheap while no locks exist.
+    count: AtomicUsize,
+}
+
+impl PrefixLockTable {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the owner of a held lock that overlaps `prefix`, if any.
+    ///
+    /// Two prefixes overlap when either one is a prefix of the other.
+    fn find_overlap(locks: &BTreeMap<Vec<u8>, u64>, prefix: &[u8]) -> Option<u64> {
+        // A held lock on an ancestor (including `prefix` itself).
+        if let Some(owner) = Self::find_ancestor(locks, prefix) {
+            return Some(owner);
+        }
+
+        // A held lock on a descendant sorts directly after `prefix`.
+        locks
+            .range(prefix.to_vec()..)
+            .next()
+            .filter(|(held, _)| held.starts_with(prefix))
+            .map(|(_, owner)| *owner)
+    }
+
+    /// Returns the owner of a held lock whose prefix is a prefix of `key`.
+    fn find_ancestor(locks: &BTreeMap<Vec<u8>, u64>, key: &[u8]) -> Option<u64> {
+        (0..=key.len()).find_map(|len| locks.get(&key[..len]).copied())
+    }
+
+    /// Registers a lock on `prefix` for `owner`.
+    ///
+    /// Fails with `TrieError::PrefixLocked` if an overlapping prefix is already held.
+    pub(crate) fn lock(
+        self: &Arc<Self>,
+        prefix: &[u8],
+        owner: u64,
+    ) -> Result<PrefixLock, TrieError> {
+        let mut locks = self.locks.lock().unwrap();
+        if let Some(holder) = Self::find_overlap(&locks, prefix) {
+            return Err(TrieError::PrefixLocked { owner: holder });
+        }
+
+        locks.insert(prefix.to_vec(), owner);
+        self.count.fetch_add(1, Ordering::Release);
+
+        Ok(PrefixLock {
+            table: self.clone(),
+            prefix: prefix.to_vec(),
+            owner,
+        })
+    }
+
+    /// Checks whether `owner` may write `key`.
+    ///
+    /// Writes without an owner are rejected by any lock covering the key.
+    pub(crate) fn check(&self, key: &[u8], owner: Option<u64>) -> Result<(), TrieError> {
+        // Fast path: nothing is locked.
+        if self.count.load(Ordering::Acquire) == 0 {
+            return Ok(());
+        }
+
+        let locks = self.locks.lock().unwrap();
+        match Self::find_ancestor(&locks, key) {
+            Some(holder) if Some(holder) != owner => Err(TrieError::PrefixLocked { owner: holder }),
+            _ => Ok(()),
+        }
+    }
+
+    fn unlock(&self, prefix: &[u8]) {
+        let mut locks = self.locks.lock().unwrap();
+        if locks.remove(prefix).is_some() {
+            self.count.fetch_sub(1, Ordering::Release);
+        }
+    }
+}
+
+/// A guard for an advisory lock on a key prefix.
+///
+/// The lock is released when the guard is dropped.
+pub struct PrefixLock {
+    table: Arc<PrefixLockTable>,
+    prefix: Vec<u8>,
+    owner: u64,
+}
+
+impl PrefixLock {
+    /// Returns the locked prefix.
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Returns the owner holding the lock.
+    pub fn owner(&self) -> u64 {
+        self.owner
+    }
+}
+
+impl Drop for PrefixLock {
+    fn drop(&mut self) {
+        self.table.unlock(&self.prefix);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PrefixLockTable;
+    use crate::TrieError;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    #[test]
+    fn overlapping_prefixes_conflict() {
+        let table = Arc::new(PrefixLockTable::new());
+        let _lock = table.lock(b"user/", 1).unwrap();
+
+        // Ancestor, equal and descendant prefixes all overlap.
+        for prefix in [&b"user"[..], b"user/", b"user/42"] {
+            assert!(matches!(
+                table.lock(prefix, 2),
+                Err(TrieError::PrefixLocked { owner: 1 })
+            ));
+        }
+
+        // Siblings do not.
+        assert!(table.lock(b"users", 2).is_ok());
+    }
+
+    #[test]
+    fn nested_prefix_sorted_after_sibling() {
+        let table = Arc::new(PrefixLockTable::new());
+        let _lock = table.lock(b"a/b/c", 1).unwrap();
+        let _sibling = table.lock(b"a/a", 2).unwrap();
+
+        assert!(matches!(
+            table.lock(b"a/", 3),
+            Err(TrieError::PrefixLocked { .. })
+        ));
+    }
+
+    #[test]
+    fn check_by_owner() {
+        let table = Arc::new(PrefixLockTable::new());
+        assert!(table.check(b"user/1", None).is_ok());
+
+        let lock = table.lock(b"user/", 7).unwrap();
+        assert!(table.check(b"user/1", Some(7)).is_ok());
+        assert!(matches!(
+            table.check(b"user/1", Some(8)),
+            Err(TrieError::PrefixLocked { owner: 7 })
+        ));
+        assert!(table.check(b"user/1", None).is_err());
+        assert!(table.check(b"group/1", None).is_ok());
+
+        drop(lock);
+        assert!(table.check(b"user/1", None).is_ok());
+        assert_eq!(table.count.load(Ordering::Acquire), 0);
+    }
+}
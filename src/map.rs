@@ -0,0 +1,150 @@
+//! This module defines a map trait implemented by both Tree and `BTreeMap`, so
+//! that code generic over ordered maps can use a Tree behind `dyn OrderedMap`.
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use crate::art::Tree;
+use crate::{KeyTrait, TrieError};
+
+/// An ordered map from keys to values.
+///
+/// The trait is object safe: entries are returned by value, and iterators are
+/// boxed. A Tree implements it on the latest version of each key, inserting at
+/// the next version with a timestamp of 0.
+pub trait OrderedMap<K, V> {
+    /// Returns the value of `key`, if any.
+    fn get(&self, key: &K) -> Result<Option<V>, TrieError>;
+
+    /// Sets the value of `key`, returning its previous value, if any.
+    fn insert(&mut self, key: K, value: V) -> Result<Option<V>, TrieError>;
+
+    /// Removes `key`, returning its value, if any.
+    fn remove(&mut self, key: &K) -> Result<Option<V>, TrieError>;
+
+    /// Returns an iterator over the entries with keys between `start` and `end`,
+    /// in key order.
+    fn range<'a>(
+        &'a self,
+        start: Bound<&K>,
+        end: Bound<&K>,
+    ) -> Box<dyn Iterator<Item = (K, V)> + 'a>;
+
+    /// Returns an iterator over all entries, in key order.
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, V)> + '_>;
+}
+
+impl<P: KeyTrait, V: Clone> OrderedMap<P, V> for Tree<P, V> {
+    fn get(&self, key: &P) -> Result<Option<V>, TrieError> {
+        match Tree::get(self, key, 0) {
+            Ok((_, value, _, _)) => Ok(Some(value)),
+            Err(TrieError::KeyNotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn insert(&mut self, key: P, value: V) -> Result<Option<V>, TrieError> {
+        Tree::insert(self, &key, value, 0, 0)
+    }
+
+    fn remove(&mut self, key: &P) -> Result<Option<V>, TrieError> {
+        let old = OrderedMap::get(self, key)?;
+        if old.is_some() {
+            Tree::remove(self, key)?;
+        }
+        Ok(old)
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Bound<&P>,
+        end: Bound<&P>,
+    ) -> Box<dyn Iterator<Item = (P, V)> + 'a> {
+        let bounds = (start.cloned(), end.cloned());
+        Box::new(
+            Tree::range(self, bounds)
+                .map(|(key, value, _, _)| (P::from(key.as_slice()), value.clone())),
+        )
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (P, V)> + '_> {
+        Box::new(
+            Tree::iter(self).map(|(key, value, _, _)| (P::from(key.as_slice()), value.clone())),
+        )
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> OrderedMap<K, V> for BTreeMap<K, V> {
+    fn get(&self, key: &K) -> Result<Option<V>, TrieError> {
+        Ok(BTreeMap::get(self, key).cloned())
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Result<Option<V>, TrieError> {
+        Ok(BTreeMap::insert(self, key, value))
+    }
+
+    fn remove(&mut self, key: &K) -> Result<Option<V>, TrieError> {
+        Ok(BTreeMap::remove(self, key))
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Bound<&K>,
+        end: Bound<&K>,
+    ) -> Box<dyn Iterator<Item = (K, V)> + 'a> {
+        Box::new(
+            BTreeMap::range(self, (start, end)).map(|(key, value)| (key.clone(), value.clone())),
+        )
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, V)> + '_> {
+        Box::new(BTreeMap::iter(self).map(|(key, value)| (key.clone(), value.clone())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OrderedMap;
+    use crate::art::Tree;
+    use crate::VariableSizeKey;
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+    use std::str::FromStr;
+
+    fn exercise(map: &mut dyn OrderedMap<VariableSizeKey, i32>) -> Vec<(VariableSizeKey, i32)> {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        for (i, k) in ["delta", "alpha", "charlie", "bravo", "echo"]
+            .iter()
+            .enumerate()
+        {
+            assert_eq!(map.insert(key(k), i as i32).unwrap(), None);
+        }
+        assert_eq!(map.insert(key("alpha"), 10).unwrap(), Some(1));
+        assert_eq!(map.get(&key("alpha")).unwrap(), Some(10));
+        assert_eq!(map.get(&key("zulu")).unwrap(), None);
+        assert_eq!(map.remove(&key("echo")).unwrap(), Some(4));
+        assert_eq!(map.remove(&key("echo")).unwrap(), None);
+
+        let ranged: Vec<_> = map
+            .range(
+                Bound::Excluded(&key("alpha")),
+                Bound::Included(&key("charlie")),
+            )
+            .map(|(_, v)| v)
+            .collect();
+        assert_eq!(ranged, vec![3, 2]);
+        map.iter().collect()
+    }
+
+    #[test]
+    fn tree_and_btreemap_behave_alike() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        let mut btree = BTreeMap::new();
+        let from_tree = exercise(&mut tree);
+        let from_btree = exercise(&mut btree);
+        assert_eq!(from_tree, from_btree);
+        assert_eq!(
+            from_tree.iter().map(|(_, v)| *v).collect::<Vec<_>>(),
+            vec![10, 3, 2, 0]
+        );
+    }
+}
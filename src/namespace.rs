@@ -0,0 +1,230 @@
+//! This module defines a container of named Trees that share one version domain,
+//! so that a snapshot can be taken across all of them at a single point.
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::art::Tree;
+use crate::snapshot::Snapshot;
+use crate::{KeyTrait, TrieError};
+
+/// A version counter shared by several Trees.
+///
+/// A Tree with a clock takes the versions of its inserts from the clock instead
+/// of from its own root, so the versions written to all Trees sharing the clock
+/// are unique and ordered across them.
+#[derive(Default)]
+pub(crate) struct SharedClock {
+    version: AtomicU64,
+}
+
+impl SharedClock {
+    /// Returns the latest version handed out or observed.
+    pub(crate) fn now(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    /// Hands out the next version.
+    pub(crate) fn tick(&self) -> u64 {
+        self.version.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// Moves the clock forward to `version`, if it is behind it.
+    pub(crate) fn advance_to(&self, version: u64) {
+        self.version.fetch_max(version, Ordering::AcqRel);
+    }
+}
+
+/// A set of named Trees sharing one version domain.
+///
+/// Every member Tree takes the versions of its inserts from a clock owned by the
+/// namespace, so a version identifies a single write across all members and
+/// versions read from different members can be compared. Members are ordinary
+/// Trees and are written to individually through `create_tree` or `tree_mut`.
+///
+pub struct Namespace<P: KeyTrait, V: Clone> {
+    clock: Arc<SharedClock>,
+    trees: BTreeMap<String, Tree<P, V>>,
+}
+
+impl<P: KeyTrait, V: Clone> Namespace<P, V> {
+    /// Creates an empty namespace.
+    pub fn new() -> Self {
+        Namespace {
+            clock: Arc::new(SharedClock::default()),
+            trees: BTreeMap::new(),
+        }
+    }
+
+    /// Creates the member Tree `name`, or returns it if it already exists.
+    pub fn create_tree(&mut self, name: &str) -> &mut Tree<P, V> {
+        let clock = &self.clock;
+        self.trees.entry(name.to_string()).or_insert_with(|| Tree {
+            clock: Some(clock.clone()),
+            ..Tree::new()
+        })
+    }
+
+    /// Returns the member Tree `name`, if it exists.
+    pub fn tree(&self, name: &str) -> Option<&Tree<P, V>> {
+        self.trees.get(name)
+    }
+
+    /// Returns the member Tree `name` for writing, if it exists.
+    pub fn tree_mut(&mut self, name: &str) -> Option<&mut Tree<P, V>> {
+        self.trees.get_mut(name)
+    }
+
+    /// Returns the names of the member Trees, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.trees.keys().map(|name| name.as_str())
+    }
+
+    /// Returns the latest version written to any member Tree.
+    pub fn version(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Takes a snapshot of every member Tree at the current version.
+    ///
+    /// Writes need `&mut self` on the namespace or a member, so none can land
+    /// between the snapshots of two members: together they form a single cut at
+    /// `NamespaceSnapshot::version`. The snapshots are registered with their Trees
+    /// and count towards their snapshot limits until passed to `close_snapshot`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the first member Tree that cannot create a snapshot,
+    /// after closing the snapshots already taken.
+    ///
+    pub fn snapshot_all(&mut self) -> Result<NamespaceSnapshot<P, V>, TrieError> {
+        let version = self.clock.now();
+        let mut snapshots = BTreeMap::new();
+        let mut failed = None;
+        for (name, tree) in self.trees.iter_mut() {
+            match tree.create_snapshot() {
+                Ok(snapshot) => {
+                    snapshots.insert(name.clone(), snapshot);
+                }
+                Err(err) => {
+                    failed = Some(err);
+                    break;
+                }
+            }
+        }
+
+        let snapshot = NamespaceSnapshot { version, snapshots };
+        match failed {
+            None => Ok(snapshot),
+            Some(err) => {
+                self.close_snapshot(snapshot)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Closes the snapshots of a `NamespaceSnapshot` and deregisters them from
+    /// their Trees.
+    pub fn close_snapshot(&mut self, snapshot: NamespaceSnapshot<P, V>) -> Result<(), TrieError> {
+        for (name, mut snapshot) in snapshot.snapshots {
+            snapshot.close()?;
+            if let Some(tree) = self.trees.get_mut(&name) {
+                tree.close_snapshot(snapshot.id())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<P: KeyTrait, V: Clone> Default for Namespace<P, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Snapshots of every member Tree of a namespace, taken at one version.
+pub struct NamespaceSnapshot<P: KeyTrait, V: Clone> {
+    version: u64,
+    snapshots: BTreeMap<String, Snapshot<P, V>>,
+}
+
+impl<P: KeyTrait, V: Clone> NamespaceSnapshot<P, V> {
+    /// Returns the version of the namespace the snapshots were taken at.
+    ///
+    /// Every write with a newer version is missing from all the snapshots, and
+    /// every write with this version or an older one is visible in them.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns the snapshot of the member Tree `name`, if it existed when the
+    /// snapshots were taken.
+    pub fn tree(&self, name: &str) -> Option<&Snapshot<P, V>> {
+        self.snapshots.get(name)
+    }
+
+    /// Returns the snapshot of the member Tree `name` for writing and opening
+    /// readers. Writes to a snapshot stay local to it.
+    pub fn tree_mut(&mut self, name: &str) -> Option<&mut Snapshot<P, V>> {
+        self.snapshots.get_mut(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Namespace;
+    use crate::art::KV;
+    use crate::VariableSizeKey;
+    use std::str::FromStr;
+
+    #[test]
+    fn snapshot_all_is_a_single_cut() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut ns: Namespace<VariableSizeKey, u64> = Namespace::new();
+        ns.create_tree("users");
+        ns.create_tree("orders");
+
+        // Interleaved writes take consecutive versions from the shared clock.
+        for i in 0..10u64 {
+            let name = if i % 2 == 0 { "users" } else { "orders" };
+            let tree = ns.tree_mut(name).unwrap();
+            tree.insert(&key(&format!("k{}", i)), i, 0, i).unwrap();
+            assert_eq!(tree.version(), i + 1);
+        }
+        assert_eq!(ns.version(), 10);
+
+        let mut snapshot = ns.snapshot_all().unwrap();
+        assert_eq!(snapshot.version(), 10);
+
+        // Writes after the cut, including a batch and an explicit version, are
+        // not visible in the snapshots.
+        let users = ns.create_tree("users");
+        users.insert(&key("k0"), 100, 0, 100).unwrap();
+        assert_eq!(users.version(), 11);
+        let orders = ns.tree_mut("orders").unwrap();
+        orders
+            .bulk_insert(&[KV::new(key("late"), 101, 0, 101)])
+            .unwrap();
+        assert_eq!(orders.version(), 12);
+        assert!(orders.insert(&key("k1"), 102, 12, 102).is_err());
+        orders.insert(&key("k1"), 102, 20, 102).unwrap();
+        assert_eq!(ns.version(), 20);
+
+        let users = snapshot.tree("users").unwrap();
+        let orders = snapshot.tree("orders").unwrap();
+        assert_eq!(users.count().unwrap(), 5);
+        assert_eq!(orders.count().unwrap(), 5);
+        for i in 0..10u64 {
+            let snap = if i % 2 == 0 { users } else { orders };
+            // Versions of the two trees interleave in the shared domain.
+            assert_eq!(snap.get(&key(&format!("k{}", i))).unwrap(), (i, i + 1, i));
+        }
+        assert!(orders.get(&key("late")).is_err());
+        assert!(snapshot.tree_mut("missing").is_none());
+
+        assert_eq!(ns.tree("users").unwrap().snapshot_count(), 1);
+        ns.close_snapshot(snapshot).unwrap();
+        assert_eq!(ns.tree("users").unwrap().snapshot_count(), 0);
+        assert_eq!(ns.names().collect::<Vec<_>>(), vec!["orders", "users"]);
+    }
+}
@@ -14,17 +14,25 @@ pub trait NodeTrait<N> {
     fn num_children(&self) -> usize;
     fn size(&self) -> usize;
     fn replace_child(&self, key: u8, node: Arc<N>) -> Self;
+    /// Returns the child slot for the key, to update the child in place.
+    fn child_mut(&mut self, key: u8) -> Option<&mut Arc<N>>;
+    /// Sets the version to the greatest version of the children, after a child
+    /// was updated in place.
+    fn refresh_version(&mut self);
 }
 
 pub trait Version {
     fn version(&self) -> u64;
+    /// Returns the greatest timestamp of the latest versions of the keys below,
+    /// or a timestamp greater than it.
+    fn max_ts(&self) -> u64;
 }
 
 #[derive(Clone)]
 pub struct TwigNode<K: KeyTrait + Clone, V> {
     pub(crate) prefix: K,
     pub(crate) key: K,
-    pub(crate) values: Vec<Arc<LeafValue<V>>>,
+    pub(crate) values: Versions<V>,
     pub(crate) version: u64, // Version for the twig node
 }
 
@@ -41,42 +49,193 @@ impl<V> LeafValue<V> {
     }
 }
 
+// Number of the newest versions of a key that are kept in the copied tail.
+pub(crate) const VERSIONS_TAIL_LEN: usize = 32;
+
+/// The versions of a key, sorted by version.
+///
+/// Every write to a key copies its twig node, and with it the list of versions,
+/// so a key with a long history would make each write linear in its number of
+/// versions. Only the newest versions are kept in a small tail that is copied;
+/// when the tail fills up it is sealed into an immutable chunk that all later
+/// copies of the twig share. Adjacent chunks are merged as long as the older one
+/// is not larger, so chunk sizes at least double towards the oldest, a key has a
+/// logarithmic number of chunks, and every version is copied a logarithmic
+/// number of times by the merges.
+pub(crate) struct Versions<V> {
+    chunks: Vec<Arc<[Arc<LeafValue<V>>]>>,
+    tail: Vec<Arc<LeafValue<V>>>,
+    len: usize,
+}
+
+impl<V> Clone for Versions<V> {
+    fn clone(&self) -> Self {
+        Versions {
+            chunks: self.chunks.clone(),
+            tail: self.tail.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<V> Default for Versions<V> {
+    fn default() -> Self {
+        Versions {
+            chunks: Vec::new(),
+            tail: Vec::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<V> Versions<V> {
+    /// Creates the list from versions that are already sorted.
+    pub(crate) fn from_sorted(values: Vec<Arc<LeafValue<V>>>) -> Self {
+        let len = values.len();
+        if len < VERSIONS_TAIL_LEN {
+            return Versions {
+                chunks: Vec::new(),
+                tail: values,
+                len,
+            };
+        }
+        Versions {
+            chunks: vec![Arc::from(values)],
+            tail: Vec::new(),
+            len,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of versions in each sealed chunk, oldest first.
+    #[allow(dead_code)]
+    pub(crate) fn chunk_lens(&self) -> impl Iterator<Item = usize> + '_ {
+        self.chunks.iter().map(|chunk| chunk.len())
+    }
+
+    /// Returns the versions in order, oldest first, without copying them.
+    pub(crate) fn iter(&self) -> impl DoubleEndedIterator<Item = &Arc<LeafValue<V>>> + Clone {
+        self.chunks
+            .iter()
+            .flat_map(|chunk| chunk.iter())
+            .chain(self.tail.iter())
+    }
+
+    /// Returns the newest version.
+    pub(crate) fn last(&self) -> Option<&Arc<LeafValue<V>>> {
+        self.tail
+            .last()
+            .or_else(|| self.chunks.last().and_then(|chunk| chunk.last()))
+    }
+
+    /// Returns the newest version that is not newer than `version`.
+    ///
+    /// The tail and the chunks are each sorted and ordered among themselves, so
+    /// this is a binary search in the newest part holding an old enough version.
+    pub(crate) fn get_by_version(&self, version: u64) -> Option<&Arc<LeafValue<V>>> {
+        let parts = self
+            .chunks
+            .iter()
+            .map(|chunk| &chunk[..])
+            .chain(std::iter::once(&self.tail[..]));
+        for part in parts.rev() {
+            let end = part.partition_point(|value| value.version <= version);
+            if end > 0 {
+                return Some(&part[end - 1]);
+            }
+        }
+        None
+    }
+
+    /// Inserts `value` after the versions that are not newer than it.
+    pub(crate) fn insert(&mut self, value: Arc<LeafValue<V>>) {
+        let fits_tail = match self.tail.first() {
+            Some(first) => first.version <= value.version,
+            None => self.last().is_none_or(|last| last.version <= value.version),
+        };
+        if !fits_tail {
+            // Older than the whole tail: rebuild the list around it.
+            let mut values: Vec<_> = self.iter().cloned().collect();
+            let index = values.partition_point(|v| v.version <= value.version);
+            values.insert(index, value);
+            *self = Versions::from_sorted(values);
+            return;
+        }
+
+        let index = self.tail.partition_point(|v| v.version <= value.version);
+        self.tail.insert(index, value);
+        self.len += 1;
+        if self.tail.len() >= VERSIONS_TAIL_LEN {
+            self.seal();
+        }
+    }
+
+    /// Moves the tail into a new chunk, merging chunks that grew too small
+    /// compared to the ones after them.
+    fn seal(&mut self) {
+        self.chunks.push(Arc::from(std::mem::take(&mut self.tail)));
+        while let [.., older, newer] = &self.chunks[..] {
+            if older.len() > newer.len() {
+                break;
+            }
+            let merged: Arc<[_]> = older.iter().chain(newer.iter()).cloned().collect();
+            self.chunks.pop();
+            *self.chunks.last_mut().unwrap() = merged;
+        }
+    }
+
+    /// Returns a copy keeping only the versions for which `f` returns true.
+    pub(crate) fn retain<F: FnMut(&LeafValue<V>) -> bool>(&self, mut f: F) -> Self {
+        Versions::from_sorted(self.iter().filter(|v| f(v)).cloned().collect())
+    }
+}
+
+impl<V> std::ops::Index<usize> for Versions<V> {
+    type Output = Arc<LeafValue<V>>;
+
+    fn index(&self, mut index: usize) -> &Self::Output {
+        for chunk in &self.chunks {
+            if index < chunk.len() {
+                return &chunk[index];
+            }
+            index -= chunk.len();
+        }
+        &self.tail[index]
+    }
+}
+
 impl<K: KeyTrait + Clone, V> TwigNode<K, V> {
     pub fn new(prefix: K, key: K) -> Self {
         TwigNode {
             prefix,
             key,
-            values: Vec::new(),
+            values: Versions::default(),
             version: 0,
         }
     }
 
     pub fn version(&self) -> u64 {
         self.values
-            .iter()
-            .map(|value| value.version)
-            .max()
-            .unwrap_or(self.version)
+            .last()
+            .map_or(self.version, |value| value.version)
     }
 
     pub fn insert(&self, value: V, version: u64, ts: u64) -> TwigNode<K, V> {
         let mut new_values = self.values.clone();
 
-        let new_leaf_value = LeafValue::new(value, version, ts);
-
         // Insert new LeafValue in sorted order
-        let insertion_index =
-            match new_values.binary_search_by(|v| v.version.cmp(&new_leaf_value.version)) {
-                Ok(index) => index,
-                Err(index) => index,
-            };
-        new_values.insert(insertion_index, Arc::new(new_leaf_value));
+        new_values.insert(Arc::new(LeafValue::new(value, version, ts)));
 
         let new_version = new_values
-            .iter()
-            .map(|value| value.version)
-            .max()
-            .unwrap_or(self.version);
+            .last()
+            .map_or(self.version, |value| value.version);
 
         TwigNode {
             prefix: self.prefix.clone(),
@@ -87,50 +246,53 @@ impl<K: KeyTrait + Clone, V> TwigNode<K, V> {
     }
 
     pub fn insert_mut(&mut self, value: V, version: u64, ts: u64) {
-        let new_leaf_value = LeafValue::new(value, version, ts);
-
         // Insert new LeafValue in sorted order
-        let insertion_index = match self
-            .values
-            .binary_search_by(|v| v.version.cmp(&new_leaf_value.version))
-        {
-            Ok(index) => index,
-            Err(index) => index,
-        };
         self.values
-            .insert(insertion_index, Arc::new(new_leaf_value));
+            .insert(Arc::new(LeafValue::new(value, version, ts)));
 
         self.version = self.version(); // Update LeafNode's version
     }
 
     pub fn get_latest_leaf(&self) -> Option<&Arc<LeafValue<V>>> {
-        self.values.iter().max_by_key(|value| value.version)
+        self.values.last()
     }
 
     pub fn get_latest_value(&self) -> Option<&V> {
-        self.values
-            .iter()
-            .max_by_key(|value| value.version)
-            .map(|value| &value.value)
+        self.values.last().map(|value| &value.value)
     }
 
     pub fn get_leaf_by_version(&self, version: u64) -> Option<Arc<LeafValue<V>>> {
-        self.values
-            .iter()
-            .filter(|value| value.version <= version)
-            .max_by_key(|value| value.version)
-            .cloned()
+        self.values.get_by_version(version).cloned()
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &Arc<LeafValue<V>>> {
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Arc<LeafValue<V>>> {
         self.values.iter()
     }
+
+    // Returns the number of values written at timestamp `ts`
+    pub(crate) fn count_ts(&self, ts: u64) -> usize {
+        self.values.iter().filter(|value| value.ts == ts).count()
+    }
+
+    // Returns a copy of the twig keeping only the values for which `f` returns true
+    pub(crate) fn retain<F: FnMut(&LeafValue<V>) -> bool>(&self, f: F) -> TwigNode<K, V> {
+        TwigNode {
+            prefix: self.prefix.clone(),
+            key: self.key.clone(),
+            values: self.values.retain(f),
+            version: self.version,
+        }
+    }
 }
 
 impl<K: KeyTrait + Clone, V> Version for TwigNode<K, V> {
     fn version(&self) -> u64 {
         self.version
     }
+
+    fn max_ts(&self) -> u64 {
+        self.values.last().map_or(0, |value| value.ts)
+    }
 }
 
 // Source: https://www.the-paper-trail.org/post/art-paper-notes/
@@ -152,6 +314,7 @@ impl<K: KeyTrait + Clone, V> Version for TwigNode<K, V> {
 pub struct FlatNode<P: KeyTrait + Clone, N: Version, const WIDTH: usize> {
     pub(crate) prefix: P,
     pub(crate) version: u64,
+    pub(crate) max_ts: u64,
     keys: [u8; WIDTH],
     children: Box<[Option<Arc<N>>; WIDTH]>,
     num_children: u8,
@@ -164,6 +327,7 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> FlatNode<P, N, WIDTH>
         Self {
             prefix,
             version: 0,
+            max_ts: 0,
             keys: [0; WIDTH],
             children: Box::new(children),
             num_children: 0,
@@ -171,9 +335,7 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> FlatNode<P, N, WIDTH>
     }
 
     fn find_pos(&self, key: u8) -> Option<usize> {
-        let idx = (0..self.num_children as usize)
-            .rev()
-            .find(|&i| key < self.keys[i]);
+        let idx = (0..self.num_children as usize).find(|&i| key < self.keys[i]);
         idx.or(Some(self.num_children as usize))
     }
 
@@ -190,6 +352,7 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> FlatNode<P, N, WIDTH>
             new_node.children[i] = self.children[i].clone();
         }
         new_node.version = self.version;
+        new_node.max_ts = self.max_ts;
         new_node.num_children = self.num_children;
         new_node.update_version();
         new_node
@@ -211,18 +374,23 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> FlatNode<P, N, WIDTH>
     fn insert_child(&mut self, idx: usize, key: u8, node: Arc<N>) {
         for i in (idx..self.num_children as usize).rev() {
             self.keys[i + 1] = self.keys[i];
-            self.children[i + 1] = std::mem::replace(&mut self.children[i], None);
+            self.children[i + 1] = self.children[i].take();
         }
         self.keys[idx] = key;
         self.children[idx] = Some(node);
         self.num_children += 1;
     }
 
+    // Returns the greatest version and the greatest timestamp bound of the
+    // children.
     #[inline]
-    fn max_child_version(&self) -> u64 {
-        self.children.iter().fold(0, |acc, x| {
+    fn max_child_version(&self) -> (u64, u64) {
+        self.children.iter().fold((0, 0), |acc, x| {
             if let Some(child) = x.as_ref() {
-                std::cmp::max(acc, child.version())
+                (
+                    std::cmp::max(acc.0, child.version()),
+                    std::cmp::max(acc.1, child.max_ts()),
+                )
             } else {
                 acc
             }
@@ -231,25 +399,27 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> FlatNode<P, N, WIDTH>
 
     #[inline]
     fn update_version_to_max_child_version(&mut self) {
-        self.version = self.max_child_version();
+        (self.version, self.max_ts) = self.max_child_version();
     }
 
     #[inline]
     fn update_version(&mut self) {
         // Compute the maximum version among all children
-        let max_child_version = self.max_child_version();
+        let (max_child_version, max_child_ts) = self.max_child_version();
 
         // If self.version is less than the maximum child version, update it.
         if self.version < max_child_version {
             self.version = max_child_version;
         }
+        self.max_ts = self.max_ts.max(max_child_ts);
     }
 
     #[inline]
-    fn update_if_newer(&mut self, new_version: u64) {
-        if new_version > self.version {
-            self.version = new_version;
+    fn update_if_newer(&mut self, node: &N) {
+        if node.version() > self.version {
+            self.version = node.version();
         }
+        self.max_ts = self.max_ts.max(node.max_ts());
     }
 
     #[inline]
@@ -258,13 +428,48 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> FlatNode<P, N, WIDTH>
             .iter()
             .zip(self.children.iter())
             .take(self.num_children as usize)
-            .filter_map(|(&k, c)| {
-                if let Some(child) = c {
-                    Some((k, child))
-                } else {
-                    None
-                }
-            })
+            .filter_map(|(&k, c)| c.as_ref().map(|child| (k, child)))
+    }
+
+    // Like `iter`, but reports empty slots among the first `num_children` as `None`
+    #[inline]
+    pub(crate) fn iter_checked(&self) -> impl Iterator<Item = (u8, Option<&Arc<N>>)> {
+        self.keys
+            .iter()
+            .zip(self.children.iter())
+            .take(self.num_children as usize)
+            .map(|(&k, c)| (k, c.as_ref()))
+    }
+
+    // Returns the first child stored at or after slot `from`, along with its slot
+    #[inline]
+    pub(crate) fn next_child(&self, from: usize) -> Option<(usize, &Arc<N>)> {
+        (from..self.num_children as usize)
+            .find_map(|idx| self.children[idx].as_ref().map(|child| (idx, child)))
+    }
+
+    // Returns the child with the smallest key greater than `key`
+    #[inline]
+    pub(crate) fn child_after(&self, key: u8) -> Option<&Arc<N>> {
+        (0..self.num_children as usize)
+            .filter(|&idx| self.keys[idx] > key)
+            .find_map(|idx| self.children[idx].as_ref())
+    }
+
+    // Returns the child stored in the last occupied slot
+    #[inline]
+    pub(crate) fn last_child(&self) -> Option<&Arc<N>> {
+        self.children[..self.num_children as usize]
+            .iter()
+            .rev()
+            .find_map(|child| child.as_ref())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn clear_child_slot(&mut self, key: u8) {
+        if let Some(idx) = self.index(key) {
+            self.children[idx] = None;
+        }
     }
 }
 
@@ -277,6 +482,7 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> NodeTrait<N> for FlatN
         }
         new_node.num_children = self.num_children;
         new_node.version = self.version;
+        new_node.max_ts = self.max_ts;
         new_node
     }
 
@@ -295,7 +501,7 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> NodeTrait<N> for FlatN
         let idx = self.find_pos(key).expect("node is full");
 
         // Update the version if the new child has a greater version
-        new_node.update_if_newer(node.version());
+        new_node.update_if_newer(&node);
 
         // Convert the node to Arc<N> and insert it
         new_node.insert_child(idx, key, Arc::new(node));
@@ -308,6 +514,15 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> NodeTrait<N> for FlatN
         child
     }
 
+    fn child_mut(&mut self, key: u8) -> Option<&mut Arc<N>> {
+        let idx = self.index(key)?;
+        self.children[idx].as_mut()
+    }
+
+    fn refresh_version(&mut self) {
+        self.update_version_to_max_child_version();
+    }
+
     fn delete_child(&self, key: u8) -> Self {
         let mut new_node = self.clone();
         let idx = self
@@ -345,6 +560,10 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> Version for FlatNode<P
     fn version(&self) -> u64 {
         self.version
     }
+
+    fn max_ts(&self) -> u64 {
+        self.max_ts
+    }
 }
 
 impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> Drop for FlatNode<P, N, WIDTH> {
@@ -369,6 +588,7 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> Drop for FlatNode<P, N
 pub struct Node48<P: KeyTrait + Clone, N: Version> {
     pub(crate) prefix: P,
     pub(crate) version: u64,
+    pub(crate) max_ts: u64,
     keys: BitArray<u8, 256>,
     children: BitArray<Arc<N>, 48>,
     num_children: u8,
@@ -379,6 +599,7 @@ impl<P: KeyTrait + Clone, N: Version> Node48<P, N> {
         Self {
             prefix,
             version: 0,
+            max_ts: 0,
             keys: BitArray::new(),
             children: BitArray::new(),
             num_children: 0,
@@ -415,34 +636,41 @@ impl<P: KeyTrait + Clone, N: Version> Node48<P, N> {
         n256
     }
 
+    // Returns the greatest version and the greatest timestamp bound of the
+    // children.
     #[inline]
-    fn max_child_version(&self) -> u64 {
-        self.children
-            .iter()
-            .fold(0, |acc, x| std::cmp::max(acc, x.1.version()))
+    fn max_child_version(&self) -> (u64, u64) {
+        self.children.iter().fold((0, 0), |acc, x| {
+            (
+                std::cmp::max(acc.0, x.1.version()),
+                std::cmp::max(acc.1, x.1.max_ts()),
+            )
+        })
     }
 
     #[inline]
     fn update_version_to_max_child_version(&mut self) {
-        self.version = self.max_child_version();
+        (self.version, self.max_ts) = self.max_child_version();
     }
 
     #[inline]
     fn update_version(&mut self) {
         // Compute the maximum version among all children
-        let max_child_version = self.max_child_version();
+        let (max_child_version, max_child_ts) = self.max_child_version();
 
         // If self.version is less than the maximum child version, update it.
         if self.version < max_child_version {
             self.version = max_child_version;
         }
+        self.max_ts = self.max_ts.max(max_child_ts);
     }
 
     #[inline]
-    fn update_if_newer(&mut self, new_version: u64) {
-        if new_version > self.version {
-            self.version = new_version;
+    fn update_if_newer(&mut self, node: &N) {
+        if node.version() > self.version {
+            self.version = node.version();
         }
+        self.max_ts = self.max_ts.max(node.max_ts());
     }
 
     pub fn iter(&self) -> impl Iterator<Item = (u8, &Arc<N>)> {
@@ -450,6 +678,37 @@ impl<P: KeyTrait + Clone, N: Version> Node48<P, N> {
             .iter()
             .map(move |(key, pos)| (key as u8, self.children.get(*pos as usize).unwrap()))
     }
+
+    // Like `iter`, but reports keys pointing at an empty child slot as `None`
+    pub(crate) fn iter_checked(&self) -> impl Iterator<Item = (u8, Option<&Arc<N>>)> {
+        self.keys
+            .iter()
+            .map(move |(key, pos)| (key as u8, self.children.get(*pos as usize)))
+    }
+
+    // Returns the first child with a key at or after `from`, along with its key
+    #[inline]
+    pub(crate) fn next_child(&self, from: usize) -> Option<(usize, &Arc<N>)> {
+        (from..256).find_map(|key| {
+            let pos = self.keys.get(key)?;
+            self.children.get(*pos as usize).map(|child| (key, child))
+        })
+    }
+
+    // Returns the child with the smallest key greater than `key`
+    #[inline]
+    pub(crate) fn child_after(&self, key: u8) -> Option<&Arc<N>> {
+        self.next_child(key as usize + 1).map(|(_, child)| child)
+    }
+
+    // Returns the child with the greatest key
+    #[inline]
+    pub(crate) fn last_child(&self) -> Option<&Arc<N>> {
+        (0..256).rev().find_map(|key| {
+            let pos = self.keys.get(key)?;
+            self.children.get(*pos as usize)
+        })
+    }
 }
 
 impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node48<P, N> {
@@ -457,6 +716,7 @@ impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node48<P, N> {
         Node48 {
             prefix: self.prefix.clone(),
             version: self.version,
+            max_ts: self.max_ts,
             keys: self.keys.clone(),
             children: self.children.clone(),
             num_children: self.num_children,
@@ -476,7 +736,7 @@ impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node48<P, N> {
         let mut new_node = self.clone();
 
         // Update the version if the new child has a greater version
-        new_node.update_if_newer(node.version());
+        new_node.update_if_newer(&node);
 
         new_node.insert_child(key, Arc::new(node));
         new_node
@@ -499,6 +759,15 @@ impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node48<P, N> {
         Some(child)
     }
 
+    fn child_mut(&mut self, key: u8) -> Option<&mut Arc<N>> {
+        let idx = *self.keys.get(key as usize)?;
+        self.children.get_mut(idx as usize)
+    }
+
+    fn refresh_version(&mut self) {
+        self.update_version_to_max_child_version();
+    }
+
     fn num_children(&self) -> usize {
         self.num_children as usize
     }
@@ -513,6 +782,10 @@ impl<P: KeyTrait + Clone, N: Version> Version for Node48<P, N> {
     fn version(&self) -> u64 {
         self.version
     }
+
+    fn max_ts(&self) -> u64 {
+        self.max_ts
+    }
 }
 
 impl<P: KeyTrait + Clone, N: Version> Drop for Node48<P, N> {
@@ -535,6 +808,7 @@ impl<P: KeyTrait + Clone, N: Version> Drop for Node48<P, N> {
 pub struct Node256<P: KeyTrait + Clone, N: Version> {
     pub(crate) prefix: P,    // Prefix associated with the node
     pub(crate) version: u64, // Version for node256
+    pub(crate) max_ts: u64,  // Bound on the ts of the latest versions below
 
     children: BitArray<Arc<N>, 256>,
     num_children: usize,
@@ -545,6 +819,7 @@ impl<P: KeyTrait + Clone, N: Version> Node256<P, N> {
         Self {
             prefix,
             version: 0,
+            max_ts: 0,
             children: BitArray::new(),
             num_children: 0,
         }
@@ -567,39 +842,64 @@ impl<P: KeyTrait + Clone, N: Version> Node256<P, N> {
         self.num_children += 1;
     }
 
+    // Returns the greatest version and the greatest timestamp bound of the
+    // children.
     #[inline]
-    fn max_child_version(&self) -> u64 {
-        self.children
-            .iter()
-            .fold(0, |acc, x| std::cmp::max(acc, x.1.version()))
+    fn max_child_version(&self) -> (u64, u64) {
+        self.children.iter().fold((0, 0), |acc, x| {
+            (
+                std::cmp::max(acc.0, x.1.version()),
+                std::cmp::max(acc.1, x.1.max_ts()),
+            )
+        })
     }
 
     #[inline]
     fn update_version_to_max_child_version(&mut self) {
-        self.version = self.max_child_version();
+        (self.version, self.max_ts) = self.max_child_version();
     }
 
     #[inline]
     fn update_version(&mut self) {
         // Compute the maximum version among all children
-        let max_child_version = self.max_child_version();
+        let (max_child_version, max_child_ts) = self.max_child_version();
 
         // If self.version is less than the maximum child version, update it.
         if self.version < max_child_version {
             self.version = max_child_version;
         }
+        self.max_ts = self.max_ts.max(max_child_ts);
     }
 
     #[inline]
-    fn update_if_newer(&mut self, new_version: u64) {
-        if new_version > self.version {
-            self.version = new_version;
+    fn update_if_newer(&mut self, node: &N) {
+        if node.version() > self.version {
+            self.version = node.version();
         }
+        self.max_ts = self.max_ts.max(node.max_ts());
     }
 
     pub fn iter(&self) -> impl Iterator<Item = (u8, &Arc<N>)> {
         self.children.iter().map(|(key, node)| (key as u8, node))
     }
+
+    // Returns the first child with a key at or after `from`, along with its key
+    #[inline]
+    pub(crate) fn next_child(&self, from: usize) -> Option<(usize, &Arc<N>)> {
+        (from..256).find_map(|key| self.children.get(key).map(|child| (key, child)))
+    }
+
+    // Returns the child with the smallest key greater than `key`
+    #[inline]
+    pub(crate) fn child_after(&self, key: u8) -> Option<&Arc<N>> {
+        self.next_child(key as usize + 1).map(|(_, child)| child)
+    }
+
+    // Returns the child with the greatest key
+    #[inline]
+    pub(crate) fn last_child(&self) -> Option<&Arc<N>> {
+        (0..256).rev().find_map(|key| self.children.get(key))
+    }
 }
 
 impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node256<P, N> {
@@ -607,6 +907,7 @@ impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node256<P, N> {
         Self {
             prefix: self.prefix.clone(),
             version: self.version,
+            max_ts: self.max_ts,
             children: self.children.clone(),
             num_children: self.num_children,
         }
@@ -625,7 +926,7 @@ impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node256<P, N> {
         let mut new_node = self.clone();
 
         // Update the version if the new child has a greater version
-        new_node.update_if_newer(node.version());
+        new_node.update_if_newer(&node);
 
         new_node.insert_child(key, Arc::new(node));
         new_node
@@ -637,6 +938,14 @@ impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node256<P, N> {
         Some(child)
     }
 
+    fn child_mut(&mut self, key: u8) -> Option<&mut Arc<N>> {
+        self.children.get_mut(key as usize)
+    }
+
+    fn refresh_version(&mut self) {
+        self.update_version_to_max_child_version();
+    }
+
     #[inline]
     fn delete_child(&self, key: u8) -> Self {
         let mut new_node = self.clone();
@@ -663,6 +972,10 @@ impl<P: KeyTrait + Clone, N: Version> Version for Node256<P, N> {
     fn version(&self) -> u64 {
         self.version
     }
+
+    fn max_ts(&self) -> u64 {
+        self.max_ts
+    }
 }
 
 impl<P: KeyTrait + Clone, N: Version> Drop for Node256<P, N> {
@@ -676,7 +989,7 @@ impl<P: KeyTrait + Clone, N: Version> Drop for Node256<P, N> {
 mod tests {
     use crate::FixedSizeKey;
 
-    use super::{FlatNode, Node256, Node48, NodeTrait, TwigNode, Version};
+    use super::{FlatNode, Node256, Node48, NodeTrait, TwigNode, Version, VERSIONS_TAIL_LEN};
     use std::sync::Arc;
 
     macro_rules! impl_timestamp {
@@ -686,6 +999,10 @@ mod tests {
                     fn version(&self) -> u64 {
                         *self as u64
                     }
+
+                    fn max_ts(&self) -> u64 {
+                        *self as u64
+                    }
                 }
             )*
         };
@@ -793,6 +1110,22 @@ mod tests {
         assert_eq!(node.num_children(), 0);
     }
 
+    #[test]
+    fn flatnode_keeps_children_sorted() {
+        // Children added out of order are placed before the first greater key,
+        // so iteration stays in key order.
+        let dummy_prefix: FixedSizeKey<8> = FixedSizeKey::create_key("foo".as_bytes());
+        let mut node = FlatNode::<FixedSizeKey<8>, usize, 16>::new(dummy_prefix);
+        for i in [9u8, 3, 12, 1, 7, 2, 15, 0] {
+            node = node.add_child(i, i as usize);
+        }
+        let keys: Vec<u8> = node.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![0, 1, 2, 3, 7, 9, 12, 15]);
+        for (k, child) in node.iter() {
+            assert_eq!(**child, k as usize);
+        }
+    }
+
     #[test]
     fn node48() {
         let dummy_prefix: FixedSizeKey<8> = FixedSizeKey::create_key("foo".as_bytes());
@@ -900,6 +1233,7 @@ mod tests {
         let mut parent = FlatNode {
             prefix: dummy_prefix.clone(),
             version: 6,
+            max_ts: 0,
             keys: [0; WIDTH],
             children: Box::new([
                 Some(Arc::new(child1)),
@@ -942,6 +1276,7 @@ mod tests {
             FlatNode {
                 prefix: dummy_prefix,
                 version: 6,
+                max_ts: 0,
                 keys: [0; WIDTH],
                 children: Box::new([Some(Arc::new(child))]),
                 num_children: 1,
@@ -1036,6 +1371,7 @@ mod tests {
         let mut parent = FlatNode {
             prefix: dummy_prefix,
             version: 0,
+            max_ts: 0,
             keys: [0; WIDTH],
             children: Box::new([
                 Some(Arc::new(twig1)),
@@ -1120,6 +1456,63 @@ mod tests {
         assert!(iter.next().is_none());
     }
 
+    #[test]
+    fn twig_versions_share_sealed_chunks() {
+        let dummy_prefix: FixedSizeKey<8> = FixedSizeKey::create_key("foo".as_bytes());
+        let mut node = TwigNode::<FixedSizeKey<8>, u64>::new(dummy_prefix.clone(), dummy_prefix);
+        for version in 1..=10_000 {
+            node.insert_mut(version, version * 2, 0);
+        }
+        assert_eq!(node.values.len(), 10_000);
+        assert_eq!(node.version(), 20_000);
+        assert!(node.values.chunk_lens().count() <= 10);
+        assert!(node.values.tail.len() < VERSIONS_TAIL_LEN);
+        // Chunks shrink towards the newest.
+        assert!(node
+            .values
+            .chunks
+            .windows(2)
+            .all(|w| w[0].len() > w[1].len()));
+
+        // Value `v` is stored at version `2v`. Lookups find the right value on
+        // both sides of every chunk boundary, including between two versions.
+        let mut boundary = 0;
+        for len in node.values.chunks.iter().map(|chunk| chunk.len() as u64) {
+            boundary += len;
+            for at in 2 * boundary - 1..=2 * boundary + 2 {
+                let leaf = node.get_leaf_by_version(at).unwrap();
+                assert_eq!(leaf.value, (at / 2).min(10_000));
+            }
+        }
+        assert!(node.get_leaf_by_version(1).is_none());
+        assert_eq!(node.get_leaf_by_version(u64::MAX).unwrap().value, 10_000);
+        assert_eq!(node.values[5_000].value, 5_001);
+
+        // A copy shares the chunks of the original and copies only the tail.
+        let copy = node.insert(10_001, 20_002, 0);
+        assert!(node
+            .values
+            .chunks
+            .iter()
+            .zip(&copy.values.chunks)
+            .all(|(a, b)| Arc::ptr_eq(a, b)));
+        assert_eq!(copy.values.len(), 10_001);
+        assert_eq!(node.values.len(), 10_000);
+
+        // Versions older than the tail land in order.
+        let older = copy.insert(0, 3, 0);
+        assert_eq!(older.get_leaf_by_version(3).unwrap().value, 0);
+        assert_eq!(older.get_leaf_by_version(4).unwrap().value, 2);
+        assert!(older
+            .iter()
+            .zip(older.iter().skip(1))
+            .all(|(a, b)| a.version <= b.version));
+
+        let even = older.retain(|leaf| leaf.value % 2 == 0);
+        assert_eq!(even.values.len(), 5_001);
+        assert_eq!(even.get_leaf_by_version(19_999).unwrap().value, 9_998);
+    }
+
     #[test]
     fn memory_leak() {
         let dummy_prefix: FixedSizeKey<8> = FixedSizeKey::create_key("foo".as_bytes());
@@ -0,0 +1,62 @@
+//! This module defines the hook a Tree uses to normalize keys, so that keys
+//! which should be equal, such as differently cased spellings of a word, are
+//! stored and looked up as one.
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use crate::KeyTrait;
+
+/// Maps the bytes of a key to their normalized form.
+///
+/// A Tree with a normalizer applies it to every key passed to its writes, reads,
+/// removals and range bounds, and stores only normalized keys. Normalizing a
+/// normalized key must return it unchanged, and normalization must preserve the
+/// terminating NULL byte of a `VariableSizeKey`.
+pub trait KeyNormalizer: Send + Sync {
+    /// Returns the normalized form of `key`.
+    fn normalize(&self, key: &[u8]) -> Vec<u8>;
+}
+
+impl<F: Fn(&[u8]) -> Vec<u8> + Send + Sync> KeyNormalizer for F {
+    fn normalize(&self, key: &[u8]) -> Vec<u8> {
+        self(key)
+    }
+}
+
+/// A normalizer that lowercases ASCII letters and leaves other bytes as they are.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsciiLowercase;
+
+impl KeyNormalizer for AsciiLowercase {
+    fn normalize(&self, key: &[u8]) -> Vec<u8> {
+        key.to_ascii_lowercase()
+    }
+}
+
+/// A normalizer that `TreeOptions` can name, so that it is kept along with the
+/// other options by `Tree::freeze`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyNormalization {
+    /// Lowercases ASCII letters, as `AsciiLowercase` does.
+    AsciiLowercase,
+}
+
+impl KeyNormalization {
+    /// Returns the normalizer applying the normalization.
+    pub(crate) fn normalizer(self) -> Arc<dyn KeyNormalizer> {
+        match self {
+            KeyNormalization::AsciiLowercase => Arc::new(AsciiLowercase),
+        }
+    }
+}
+
+/// Applies `normalizer`, if any, to `key`.
+pub(crate) fn normalize_key<'k, P: KeyTrait>(
+    normalizer: Option<&Arc<dyn KeyNormalizer>>,
+    key: &'k P,
+) -> Cow<'k, P> {
+    match normalizer {
+        Some(normalizer) => Cow::Owned(P::from(normalizer.normalize(key.as_slice()).as_slice())),
+        None => Cow::Borrowed(key),
+    }
+}
@@ -0,0 +1,197 @@
+//! This module defines the files a Tree is saved to and loaded from: the frozen
+//! form of the Tree followed by a checksum of it, so that a file damaged on disk
+//! is rejected instead of loaded.
+//!
+//! Like the frozen form, a file holds the latest value of every key only. The
+//! older versions of the keys, the pinned versions and the tombstones of
+//! removed keys are not saved, so a file is a checkpoint of the latest state
+//! of a Tree rather than of its history.
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::art::Tree;
+use crate::checksum::checksum;
+use crate::codec::ValueCodec;
+use crate::frozen::FrozenTree;
+use crate::{KeyTrait, TrieError};
+
+// The length of the checksum, which follows the frozen Tree.
+const CHECKSUM_LEN: usize = 4;
+
+fn io_error(err: io::Error) -> TrieError {
+    TrieError::Io {
+        kind: err.kind(),
+        message: err.to_string(),
+    }
+}
+
+impl<P: KeyTrait, V: Clone + ValueCodec> Tree<P, V> {
+    /// Saves the latest value of every key of the Trie to the file at `path`,
+    /// replacing it if it exists.
+    ///
+    /// The file holds what `freeze` writes, the latest value of every key with
+    /// its version and timestamp and the options of the Trie, followed by its
+    /// checksum. The older versions of the keys are not saved, nor are the
+    /// pinned versions or the tombstones of removed keys. It is written to a
+    /// temporary file next to `path`, synced, and renamed over `path`, so that
+    /// a crash while saving leaves the previous file in place.
+    ///
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+
+        let bytes = self.freeze();
+        let file = File::create(&temp)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&bytes)?;
+        writer.write_all(&checksum(&bytes).to_le_bytes())?;
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        fs::rename(&temp, path)
+    }
+
+    /// Loads a Trie from the file at `path`, written by `save`.
+    ///
+    /// The Trie is thawed from the file, so each key holds its latest value,
+    /// with the version and timestamp it had when saved, and no older one. The
+    /// options, expiry rules and current timestamp of the saved Trie are
+    /// restored.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::Io` if the file cannot be read,
+    /// `TrieError::FileChecksumMismatch` if it does not match its checksum, and
+    /// `TrieError::CorruptFile` if it is too short or does not hold a frozen
+    /// Tree.
+    ///
+    pub fn load(path: impl AsRef<Path>) -> Result<Tree<P, V>, TrieError> {
+        let bytes = fs::read(path).map_err(io_error)?;
+        let Some((frozen, sum)) = bytes.split_last_chunk::<CHECKSUM_LEN>() else {
+            return Err(TrieError::CorruptFile {
+                reason: "file is too short".to_string(),
+            });
+        };
+        let (expected, found) = (u32::from_le_bytes(*sum), checksum(frozen));
+        if found != expected {
+            return Err(TrieError::FileChecksumMismatch { expected, found });
+        }
+        FrozenTree::open(frozen)
+            .and_then(|frozen| Tree::thaw(&frozen))
+            .map_err(|err| TrieError::CorruptFile {
+                reason: err.to_string(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::art::Tree;
+    use crate::pressure::TreeOptions;
+    use crate::{TrieError, TrieErrorKind, VariableSizeKey};
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    // A path in the temporary directory unique to the test and the process.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vart-{}-{}.tree", name, std::process::id()))
+    }
+
+    #[test]
+    fn saved_trees_load_with_their_contents_and_timestamps() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let options = TreeOptions::default().track_prefix_stats(b'/');
+        let mut tree: Tree<VariableSizeKey, Vec<u8>> = Tree::with_options(options);
+        for i in 0..500u64 {
+            let k = key(&format!("user/{:04}", i));
+            tree.insert(&k, i.to_le_bytes().to_vec(), 0, 10 + i)
+                .unwrap();
+        }
+        tree.insert(&key("user/0007"), b"seven".to_vec(), 0, 1000)
+            .unwrap();
+
+        let path = temp_path("round-trip");
+        tree.save(&path).unwrap();
+        let loaded = Tree::<VariableSizeKey, Vec<u8>>::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.current_ts(), tree.current_ts());
+        assert_eq!(loaded.current_ts(), 1000);
+        assert_eq!(loaded.options(), tree.options());
+        let entries = |tree: &Tree<VariableSizeKey, Vec<u8>>| {
+            tree.iter()
+                .map(|(k, v, version, ts)| (k, v.clone(), *version, *ts))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(entries(&loaded), entries(&tree));
+        assert_eq!(
+            loaded.get(&key("user/0007"), 0).unwrap().1,
+            b"seven".to_vec()
+        );
+    }
+
+    #[test]
+    fn damaged_and_missing_files_are_rejected() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, Vec<u8>> = Tree::new();
+        tree.insert(&key("a"), b"alpha".to_vec(), 0, 1).unwrap();
+
+        let path = temp_path("damaged");
+        tree.save(&path).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        let at = bytes.windows(5).position(|w| w == b"alpha").unwrap();
+        bytes[at] ^= 0x01;
+        std::fs::write(&path, &bytes).unwrap();
+        let loaded = Tree::<VariableSizeKey, Vec<u8>>::load(&path);
+        assert_eq!(
+            loaded.err().map(|err| err.kind()),
+            Some(TrieErrorKind::FileChecksumMismatch)
+        );
+
+        std::fs::write(&path, [1, 2]).unwrap();
+        assert!(matches!(
+            Tree::<VariableSizeKey, Vec<u8>>::load(&path),
+            Err(TrieError::CorruptFile { .. })
+        ));
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            Tree::<VariableSizeKey, Vec<u8>>::load(&path),
+            Err(TrieError::Io {
+                kind: std::io::ErrorKind::NotFound,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn saved_trees_keep_only_the_latest_versions() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, Vec<u8>> = Tree::new();
+        tree.insert(&key("a"), b"one".to_vec(), 0, 10).unwrap();
+        let first = tree.version();
+        tree.insert(&key("a"), b"two".to_vec(), 0, 20).unwrap();
+        tree.insert(&key("b"), b"gone".to_vec(), 0, 30).unwrap();
+        tree.remove(&key("b")).unwrap();
+        assert_eq!(tree.get(&key("a"), first).unwrap().1, b"one".to_vec());
+
+        let path = temp_path("latest-only");
+        tree.save(&path).unwrap();
+        let mut loaded = Tree::<VariableSizeKey, Vec<u8>>::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // The latest value of each key is restored, but not its history.
+        let latest = tree.get(&key("a"), 0).unwrap();
+        assert_eq!(loaded.get(&key("a"), 0).unwrap(), latest);
+        assert_eq!(loaded.history(&key("a")).unwrap().len(), 1);
+        assert_eq!(tree.history(&key("a")).unwrap().len(), 2);
+        assert!(loaded.get(&key("a"), first).is_err());
+        assert!(loaded.get(&key("b"), 0).is_err());
+        let snapshot = loaded.create_snapshot().unwrap();
+        let as_of = snapshot.iter_as_of(15).unwrap().collect::<Vec<_>>();
+        assert!(as_of.is_empty());
+    }
+}
@@ -0,0 +1,85 @@
+//! This module defines the pin table used by the Tree to protect individual
+//! historical versions of a key from pruning.
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// A table of pinned `(key, version)` pairs.
+///
+/// Pins are reference counted, so the same version can be pinned by several
+/// readers and stays protected until the last `VersionPin` is dropped. Entries
+/// are ordered by key bytes and then by version, which lets the pruning walk
+/// merge-join the table against the keys of the trie in a single pass.
+#[derive(Default)]
+pub(crate) struct VersionPinTable {
+    pins: Mutex<BTreeMap<(Vec<u8>, u64), usize>>,
+}
+
+impl VersionPinTable {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pins `version` of `key` and returns the guard that releases it.
+    pub(crate) fn pin(self: &Arc<Self>, key: &[u8], version: u64) -> VersionPin {
+        let mut pins = self.pins.lock().unwrap();
+        *pins.entry((key.to_vec(), version)).or_insert(0) += 1;
+
+        VersionPin {
+            table: self.clone(),
+            key: key.to_vec(),
+            version,
+        }
+    }
+
+    /// Returns the pinned `(key, version)` pairs in key order.
+    pub(crate) fn pinned(&self) -> Vec<(Vec<u8>, u64)> {
+        let pins = self.pins.lock().unwrap();
+        pins.keys().cloned().collect()
+    }
+
+    /// Returns the pinned versions of `key`, oldest first.
+    pub(crate) fn pinned_of(&self, key: &[u8]) -> Vec<u64> {
+        let pins = self.pins.lock().unwrap();
+        pins.range((key.to_vec(), 0)..=(key.to_vec(), u64::MAX))
+            .map(|((_, version), _)| *version)
+            .collect()
+    }
+
+    fn unpin(&self, key: &[u8], version: u64) {
+        let mut pins = self.pins.lock().unwrap();
+        let entry = (key.to_vec(), version);
+        if let Some(count) = pins.get_mut(&entry) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&entry);
+            }
+        }
+    }
+}
+
+/// A guard protecting one version of a key from pruning.
+///
+/// The version is unpinned when the guard is dropped.
+pub struct VersionPin {
+    table: Arc<VersionPinTable>,
+    key: Vec<u8>,
+    version: u64,
+}
+
+impl VersionPin {
+    /// Returns the bytes of the pinned key.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Returns the pinned version.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+impl Drop for VersionPin {
+    fn drop(&mut self) {
+        self.table.unpin(&self.key, self.version);
+    }
+}
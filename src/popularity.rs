@@ -0,0 +1,143 @@
+//! This module defines the read-frequency counters a Tree can keep for the
+//! leading bytes of the keys it serves.
+//!
+//! The counters are a space-saving sketch: a fixed table of prefixes with their
+//! counts. A read of a prefix missing from a full table takes over the slot of
+//! the least read prefix, inheriting its count, so the hottest prefixes stay in
+//! the table and their counts are overestimated by at most the count of the
+//! evicted prefix. The table is allocated up front, so counting a read never
+//! allocates.
+use std::sync::Mutex;
+
+/// The longest prefix, in bytes, read frequencies can be tracked for.
+pub(crate) const MAX_DEPTH: usize = 16;
+
+/// The number of prefixes tracked at once.
+const SLOTS: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Slot {
+    prefix: [u8; MAX_DEPTH],
+    len: usize,
+    count: u64,
+}
+
+impl Slot {
+    fn prefix(&self) -> &[u8] {
+        &self.prefix[..self.len]
+    }
+}
+
+/// Read counts per key prefix, shared by a Tree with the snapshots and readers
+/// that count their reads towards it.
+pub(crate) struct ReadFrequency {
+    depth: usize,
+    slots: Mutex<Vec<Slot>>,
+}
+
+impl ReadFrequency {
+    /// Creates counters for the first `depth` bytes of the keys read, with
+    /// `depth` clamped to `MAX_DEPTH`.
+    pub(crate) fn new(depth: usize) -> Self {
+        ReadFrequency {
+            depth: depth.min(MAX_DEPTH),
+            slots: Mutex::new(Vec::with_capacity(SLOTS)),
+        }
+    }
+
+    /// Returns the number of leading key bytes counted.
+    pub(crate) fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Counts a read of `key`, under its first `depth` bytes or the whole key if
+    /// it is shorter.
+    pub(crate) fn record(&self, key: &[u8]) {
+        let prefix = &key[..key.len().min(self.depth)];
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(slot) = slots.iter_mut().find(|slot| slot.prefix() == prefix) {
+            slot.count += 1;
+            return;
+        }
+
+        let mut slot = Slot {
+            prefix: [0; MAX_DEPTH],
+            len: prefix.len(),
+            count: 1,
+        };
+        slot.prefix[..prefix.len()].copy_from_slice(prefix);
+        if slots.len() < SLOTS {
+            slots.push(slot);
+            return;
+        }
+        let coldest = slots
+            .iter_mut()
+            .min_by_key(|slot| slot.count)
+            .expect("table is full");
+        slot.count += coldest.count;
+        *coldest = slot;
+    }
+
+    /// Returns up to `top_k` prefixes with their read counts, most read first.
+    pub(crate) fn hot_prefixes(&self, top_k: usize) -> Vec<(Vec<u8>, u64)> {
+        let mut hot: Vec<(Vec<u8>, u64)> = self
+            .slots
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|slot| (slot.prefix().to_vec(), slot.count))
+            .collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        hot.truncate(top_k);
+        hot
+    }
+
+    /// Forgets every read counted so far.
+    pub(crate) fn reset(&self) {
+        self.slots.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReadFrequency, MAX_DEPTH, SLOTS};
+
+    #[test]
+    fn hot_prefixes_survive_a_long_tail() {
+        let freq = ReadFrequency::new(2);
+        // Hot prefixes read throughout, interleaved with many more cold ones
+        // than the table can hold.
+        for i in 0..10_000u32 {
+            freq.record(b"aa/hot");
+            if i % 2 == 0 {
+                freq.record(b"bb/warm");
+            }
+            let cold = [b'c', (i % 200) as u8, b'/'];
+            freq.record(&cold);
+        }
+        let hot = freq.hot_prefixes(2);
+        assert_eq!(hot[0], (b"aa".to_vec(), 10_000));
+        assert_eq!(hot[1].0, b"bb".to_vec());
+        assert!(hot[1].1 >= 5_000);
+        assert_eq!(freq.hot_prefixes(usize::MAX).len(), SLOTS);
+
+        // Keys shorter than the depth count under the whole key.
+        freq.reset();
+        freq.record(b"a");
+        freq.record(b"a");
+        freq.record(b"abc");
+        assert_eq!(
+            freq.hot_prefixes(10),
+            vec![(b"a".to_vec(), 2), (b"ab".to_vec(), 1)]
+        );
+    }
+
+    #[test]
+    fn depth_is_clamped_to_the_maximum() {
+        let freq = ReadFrequency::new(MAX_DEPTH + 1);
+        assert_eq!(freq.depth(), MAX_DEPTH);
+        let key = [b'k'; MAX_DEPTH + 4];
+        freq.record(&key);
+        assert_eq!(freq.hot_prefixes(1), vec![(key[..MAX_DEPTH].to_vec(), 1)]);
+    }
+}
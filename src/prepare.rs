@@ -0,0 +1,360 @@
+//! This module defines two-stage writes: a write is prepared aside from the Tree,
+//! with every node it changes already copied, and published later by swapping
+//! the root of the Tree. Between the two, the log record carried by the prepared
+//! write can be made durable, so that no reader observes a write before it is on
+//! disk, while the next writes are prepared.
+use std::collections::VecDeque;
+use std::sync::{Arc, Weak};
+
+use crate::art::{BuiltWrite, Node, NodeType, Tree, KV};
+use crate::codec::ValueCodec;
+use crate::diff::{serialize_delta, Change};
+use crate::pressure::CowWindow;
+use crate::record::OpRecord;
+use crate::{check_key_len, KeyTrait, TrieError};
+
+/// A write prepared by `Tree::prepare_insert` or `Tree::prepare_batch`, to be
+/// applied with `Tree::publish`.
+///
+/// The write holds the root of the Tree as it will be once published, and is
+/// invisible to every read until then. Dropping it discards the write, along
+/// with the writes prepared after it, which were built on top of it.
+pub struct PreparedWrite<P: KeyTrait, V: Clone> {
+    sequence: u64,
+    // The root the write was built on, which must be the root of the Tree when
+    // it is published.
+    parent: Option<Arc<Node<P, V>>>,
+    root: Option<Arc<Node<P, V>>>,
+    writes: Vec<BuiltWrite<P, V>>,
+    record: Vec<u8>,
+    // Tells the Tree whether the write is still pending.
+    _live: Arc<()>,
+}
+
+impl<P: KeyTrait, V: Clone> PreparedWrite<P, V> {
+    /// Returns the position of the write among the writes prepared on the Tree,
+    /// in which they must be published.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Returns the version the write is committed at, or 0 if it writes nothing
+    /// new.
+    pub fn version(&self) -> u64 {
+        self.writes.last().map_or(0, |write| write.version)
+    }
+
+    /// Returns the timestamp the write is committed at, once validated against
+    /// the ingest policy of the Tree; the latest one for a batch.
+    pub fn ts(&self) -> u64 {
+        self.writes.iter().map(|write| write.ts).max().unwrap_or(0)
+    }
+
+    /// Returns the log record of the write, in the format read by
+    /// `Tree::apply_wal`.
+    pub fn log_record(&self) -> &[u8] {
+        &self.record
+    }
+}
+
+/// The writes prepared on a Tree and not yet published.
+pub(crate) struct PreparedChain<P: KeyTrait, V: Clone> {
+    // The root of the Tree the pending writes are built on, which they can no
+    // longer be published on once the Tree is written to by other means.
+    base: Option<Arc<Node<P, V>>>,
+    // The root and version of the last pending write, which the next write is
+    // built on.
+    tip: Option<Arc<Node<P, V>>>,
+    tip_version: u64,
+    // The sequence of the next write prepared, and of the next one published.
+    next_prepare: u64,
+    next_publish: u64,
+    // Whether each pending write is still alive, in preparation order.
+    pending: VecDeque<Weak<()>>,
+}
+
+impl<P: KeyTrait, V: Clone> Default for PreparedChain<P, V> {
+    fn default() -> Self {
+        PreparedChain {
+            base: None,
+            tip: None,
+            tip_version: 0,
+            next_prepare: 0,
+            next_publish: 0,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<P: KeyTrait, V: Clone> PreparedChain<P, V> {
+    /// Drops the pending writes, which can no longer be published.
+    fn reset(&mut self) {
+        self.base = None;
+        self.tip = None;
+        self.pending.clear();
+        self.next_publish = self.next_prepare;
+    }
+}
+
+fn same_root<P: KeyTrait, V: Clone>(
+    a: &Option<Arc<Node<P, V>>>,
+    b: &Option<Arc<Node<P, V>>>,
+) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
+impl<P: KeyTrait, V: Clone + ValueCodec> Tree<P, V> {
+    /// Prepares the insertion of `value` for `key` at timestamp `ts`, to be
+    /// applied by `publish`.
+    ///
+    /// The new root is built aside, copying every node the write changes, and
+    /// no read of the Trie, its snapshots or its iterators sees the write until
+    /// it is published. The write gets the version after the last prepared one,
+    /// and is built on top of the writes prepared before it. See `prepare_batch`.
+    ///
+    /// # Errors
+    ///
+    /// Fails like `insert` does, without preparing anything.
+    ///
+    pub fn prepare_insert(
+        &mut self,
+        key: &P,
+        value: V,
+        ts: u64,
+    ) -> Result<PreparedWrite<P, V>, TrieError> {
+        self.prepare_batch(&[KV::new(key.clone(), value, 0, ts)])
+    }
+
+    /// Prepares the insertion of several key-value pairs, to be applied
+    /// together by one `publish`.
+    ///
+    /// The pairs are inserted one after the other like with `bulk_insert`, but
+    /// a node copied for one pair is updated in place by the next ones instead
+    /// of being copied again. Timestamps are validated against the ingest
+    /// policy of the Trie, and the returned write carries them as resolved.
+    ///
+    /// Writes must be published in the order they were prepared. Dropping a
+    /// prepared write leaves no trace: the writes prepared after it, which were
+    /// built on top of it, can no longer be published, and the next write is
+    /// prepared on the published root. Writing to the Trie by any other means
+    /// also discards the pending writes.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error of the pairs, like `bulk_insert`, without
+    /// preparing any of them.
+    ///
+    pub fn prepare_batch(
+        &mut self,
+        kv_pairs: &[KV<P, V>],
+    ) -> Result<PreparedWrite<P, V>, TrieError> {
+        self.is_closed()?;
+
+        // A dropped write, or a write to the Trie since the pending writes were
+        // prepared, leaves them unpublishable.
+        let chain = &mut self.prepared;
+        let stale = !same_root(&chain.base, &self.root);
+        if stale || chain.pending.iter().any(|live| live.strong_count() == 0) {
+            chain.reset();
+        }
+        let (mut root, curr_version) = match chain.pending.is_empty() {
+            true => (self.root.clone(), self.latest_version()),
+            false => (
+                chain.tip.clone(),
+                chain.tip_version.max(self.latest_version()),
+            ),
+        };
+        let parent = root.clone();
+
+        let mut validator = self.ts_validator();
+        let mut entries = Vec::with_capacity(kv_pairs.len());
+        for (position, kv) in kv_pairs.iter().enumerate() {
+            let key = self.normalize(&kv.key).into_owned();
+            check_key_len(&key)?;
+            self.prefix_locks.check(key.as_slice(), None)?;
+            let ts = match validator.as_mut() {
+                Some(validator) => validator.check(key.as_slice(), position, kv.ts, || {
+                    let twig = Node::find_twig(root.as_ref()?, &key)?;
+                    match &twig.node_type {
+                        NodeType::Twig(twig) => twig.iter().map(|leaf| leaf.ts).max(),
+                        _ => None,
+                    }
+                })?,
+                None => kv.ts,
+            };
+            entries.push(KV::new(key, kv.value.clone(), kv.version, ts));
+        }
+
+        // The values are kept for the log record, and for the recorder if any.
+        let mut window = CowWindow::new(entries.len());
+        let (writes, result) = self.build_writes(
+            &mut root,
+            &entries,
+            curr_version,
+            true,
+            Some(&mut window),
+            "prepare_batch",
+        );
+        result?;
+
+        let mut log = Vec::new();
+        let changes: Vec<_> = writes
+            .iter()
+            .filter_map(|write| {
+                Some(Change::Insert {
+                    key: write.key.clone(),
+                    value: write.recorded_value.clone()?,
+                    version: write.version,
+                    ts: write.ts,
+                })
+            })
+            .collect();
+        serialize_delta(&changes, &mut log).expect("writing to a Vec cannot fail");
+
+        let live = Arc::new(());
+        let chain = &mut self.prepared;
+        let sequence = chain.next_prepare;
+        chain.next_prepare += 1;
+        chain.pending.push_back(Arc::downgrade(&live));
+        chain.tip_version = writes.last().map_or(curr_version, |write| write.version);
+        if chain.pending.len() == 1 {
+            chain.base = self.root.clone();
+        }
+        chain.tip = root.clone();
+        Ok(PreparedWrite {
+            sequence,
+            parent,
+            root,
+            writes,
+            record: log,
+            _live: live,
+        })
+    }
+
+    /// Publishes a write prepared by `prepare_insert` or `prepare_batch`,
+    /// swapping in the root it built.
+    ///
+    /// Nothing is copied: only the root is swapped and the bookkeeping of the
+    /// Trie, such as its indexes and statistics, is updated.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::PublishOutOfOrder` with the sequence of the write
+    /// expected next if the write was not the next prepared one, or if the Trie
+    /// was written to since it was prepared, with the Trie unchanged.
+    ///
+    pub fn publish(&mut self, write: PreparedWrite<P, V>) -> Result<(), TrieError> {
+        self.is_closed()?;
+
+        let chain = &mut self.prepared;
+        if write.sequence == chain.next_publish && !same_root(&write.parent, &self.root) {
+            // The Trie was written to since, so no pending write can be published.
+            chain.reset();
+        }
+        if write.sequence != chain.next_publish {
+            return Err(TrieError::PublishOutOfOrder {
+                expected: chain.next_publish,
+                found: write.sequence,
+            });
+        }
+        chain.next_publish += 1;
+        chain.pending.pop_front();
+
+        let mut applied = self.recorder.as_ref().map(|_| Vec::new());
+        self.commit_writes(write.root, write.writes, applied.as_mut());
+        match self.prepared.pending.is_empty() {
+            true => self.prepared.reset(),
+            false => self.prepared.base = self.root.clone(),
+        }
+        if let Some(entries) = applied.filter(|entries| !entries.is_empty()) {
+            self.record(OpRecord::BulkInsert { entries });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::art::{Tree, KV};
+    use crate::{TrieError, VariableSizeKey};
+    use std::str::FromStr;
+
+    fn key(s: &str) -> VariableSizeKey {
+        VariableSizeKey::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn prepared_writes_are_invisible_until_published() {
+        let mut tree: Tree<VariableSizeKey, Vec<u8>> = Tree::new();
+        tree.insert(&key("a"), b"old".to_vec(), 0, 1).unwrap();
+
+        let first = tree.prepare_insert(&key("a"), b"new".to_vec(), 2).unwrap();
+        let batch = tree
+            .prepare_batch(&[
+                KV::new(key("b"), b"bee".to_vec(), 0, 3),
+                KV::new(key("c"), b"sea".to_vec(), 0, 3),
+            ])
+            .unwrap();
+        assert_eq!((first.version(), first.ts()), (2, 2));
+        assert_eq!((batch.version(), batch.ts()), (3, 3));
+
+        // No read path sees the prepared writes.
+        let snap = tree.create_snapshot().unwrap();
+        assert_eq!(tree.get(&key("a"), 0).unwrap().1, b"old".to_vec());
+        assert!(tree.get(&key("b"), 0).is_err());
+        assert_eq!(snap.get(&key("a")).unwrap().0, b"old".to_vec());
+        assert!(snap.get(&key("c")).is_err());
+        assert_eq!(tree.iter().count(), 1);
+        assert_eq!(tree.version(), 1);
+
+        // The log record replays into the same write.
+        let mut replica: Tree<VariableSizeKey, Vec<u8>> = Tree::new();
+        replica.apply_wal(first.log_record()).unwrap();
+        assert_eq!(replica.get(&key("a"), 0).unwrap().1, b"new".to_vec());
+
+        tree.publish(first).unwrap();
+        assert_eq!(tree.get(&key("a"), 0).unwrap().1, b"new".to_vec());
+        assert!(tree.get(&key("b"), 0).is_err());
+        tree.publish(batch).unwrap();
+        assert_eq!(tree.get(&key("c"), 0).unwrap().1, b"sea".to_vec());
+        assert_eq!(tree.get(&key("a"), 1).unwrap().1, b"old".to_vec());
+        assert_eq!(tree.version(), 3);
+        assert_eq!(snap.get(&key("a")).unwrap().0, b"old".to_vec());
+    }
+
+    #[test]
+    fn out_of_order_and_dropped_writes_are_rejected() {
+        let mut tree: Tree<VariableSizeKey, Vec<u8>> = Tree::new();
+        let first = tree.prepare_insert(&key("a"), b"1".to_vec(), 1).unwrap();
+        let second = tree.prepare_insert(&key("b"), b"2".to_vec(), 2).unwrap();
+        assert!(matches!(
+            tree.publish(second),
+            Err(TrieError::PublishOutOfOrder {
+                expected: 0,
+                found: 1
+            })
+        ));
+        assert_eq!(tree.iter().count(), 0);
+
+        // Dropping a prepared write discards the writes built on top of it.
+        drop(first);
+        let third = tree.prepare_insert(&key("c"), b"3".to_vec(), 3).unwrap();
+        tree.publish(third).unwrap();
+        assert!(tree.get(&key("a"), 0).is_err());
+        assert!(tree.get(&key("b"), 0).is_err());
+        assert_eq!(tree.get(&key("c"), 0).unwrap().2, 1);
+
+        // A write to the Trie in between invalidates the pending writes.
+        let fourth = tree.prepare_insert(&key("d"), b"4".to_vec(), 4).unwrap();
+        tree.insert(&key("e"), b"5".to_vec(), 0, 5).unwrap();
+        assert!(matches!(
+            tree.publish(fourth),
+            Err(TrieError::PublishOutOfOrder { .. })
+        ));
+        assert!(tree.get(&key("d"), 0).is_err());
+        assert_eq!(tree.get(&key("e"), 0).unwrap().1, b"5".to_vec());
+    }
+}
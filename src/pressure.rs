@@ -0,0 +1,950 @@
+//! This module defines the write pressure signal exposed by the Tree.
+//!
+//! Every insert copies the nodes on the path to its key. Without snapshots the
+//! old copies are freed right away, but while a snapshot or reader shares those
+//! nodes they stay alive, so memory grows with the number of copies rather than
+//! with the data. The Tree counts copied and retained nodes on its insert path
+//! and turns them into a coarse level that writers can poll to back off.
+//!
+//! A Tree built with `TreeOptions::cow_batch_window` also keeps the inner nodes
+//! copied by its recent inserts in a window, and updates them in place on the
+//! next inserts instead of copying them again, as long as nothing else holds a
+//! reference to them.
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::art::{NodeKind, DEFAULT_MAX_ACTIVE_SNAPSHOTS};
+use crate::ingest::IngestPolicy;
+use crate::normalize::KeyNormalization;
+use crate::popularity;
+use crate::strict::{InvariantChecks, InvariantViolation};
+use crate::TrieError;
+
+/// Options for a Tree.
+///
+/// Some options can be changed on a live Tree with `Tree::reconfigure`, while
+/// those that shape what the Tree keeps alongside its keys are fixed when it is
+/// built; see `OptionsPatch`. `Tree::options` returns the options a Tree runs
+/// with, and `Tree::freeze` keeps them, so that `Tree::thaw` restores a Tree
+/// that behaves the same.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TreeOptions {
+    /// Width of the window the pressure counters are kept over, in the
+    /// timestamps passed to `insert`. Counters older than two windows are
+    /// dropped.
+    pub pressure_window: u64,
+    /// Fraction of retained node copies at which pressure becomes `Medium`.
+    pub pressure_medium: f64,
+    /// Fraction of retained node copies at which pressure becomes `High`.
+    pub pressure_high: f64,
+    /// Delimiter ending the key segment that per-prefix statistics are kept for,
+    /// or `None` to not keep them.
+    pub prefix_stats_delimiter: Option<u8>,
+    /// Whether to keep a companion index of reversed keys for `scan_suffix`.
+    pub suffix_index: bool,
+    /// What an insert does when the key already has a version with the same
+    /// timestamp.
+    pub duplicate_ts_policy: DuplicateTsPolicy,
+    /// Number of versions of a single key at which the key is reported by
+    /// `Tree::overloaded_keys`, or `None` to not track it.
+    pub version_warn_threshold: Option<usize>,
+    /// Number of leading key bytes that read frequencies are counted for, or
+    /// `None` to not count them.
+    pub read_frequency_depth: Option<usize>,
+    /// Whether reads through snapshots and their readers are counted along with
+    /// the reads through the Tree.
+    pub count_snapshot_reads: bool,
+    /// Number of consecutive inserts that may update the nodes copied within
+    /// the window in place, or `None` to copy the path of every insert.
+    pub cow_batch_window: Option<usize>,
+    /// The invariant checks run on every operation, any failure of which
+    /// poisons the Tree, or `None` to run none.
+    pub invariant_checks: Option<InvariantChecks>,
+    /// The largest timestamp the ingest paths accept, or `None` for no limit.
+    pub max_valid_ts: Option<u64>,
+    /// How the ingest paths validate the timestamps of their entries, or `None`
+    /// to not validate them unless `max_valid_ts` is set.
+    pub ingest_policy: Option<IngestPolicy>,
+    /// Delimiter ending the key segment that separate timestamp counters are
+    /// kept for, or `None` to keep only the counter of the whole Tree.
+    pub ts_domain_delimiter: Option<u8>,
+    /// How many versions of each key inserts keep.
+    pub versioning: VersioningStrategy,
+    /// Whether to keep a hash index over the keys, built with the default
+    /// hasher.
+    pub hash_index: bool,
+    /// Whether inner nodes cache the number of keys below them.
+    pub cached_counts: bool,
+    /// The smallest type inner nodes are created as, or `None` for adaptive
+    /// node types.
+    pub forced_node_type: Option<NodeKind>,
+    /// The normalization applied to every key, or `None` to store keys as
+    /// given.
+    pub key_normalization: Option<KeyNormalization>,
+    /// Number of snapshots that can be active at once.
+    pub max_active_snapshots: u64,
+    /// Whether every value read is checked against the checksum kept with it.
+    pub value_checksums: bool,
+}
+
+impl Default for TreeOptions {
+    fn default() -> Self {
+        TreeOptions {
+            pressure_window: 1024,
+            pressure_medium: 0.25,
+            pressure_high: 0.5,
+            prefix_stats_delimiter: None,
+            suffix_index: false,
+            duplicate_ts_policy: DuplicateTsPolicy::default(),
+            version_warn_threshold: None,
+            read_frequency_depth: None,
+            count_snapshot_reads: false,
+            cow_batch_window: None,
+            invariant_checks: None,
+            max_valid_ts: None,
+            ingest_policy: None,
+            ts_domain_delimiter: None,
+            versioning: VersioningStrategy::default(),
+            hash_index: false,
+            cached_counts: false,
+            forced_node_type: None,
+            key_normalization: None,
+            max_active_snapshots: DEFAULT_MAX_ACTIVE_SNAPSHOTS,
+            value_checksums: false,
+        }
+    }
+}
+
+impl TreeOptions {
+    /// Keeps per-prefix statistics for the key segment ending before the first
+    /// `delimiter`.
+    pub fn track_prefix_stats(mut self, delimiter: u8) -> Self {
+        self.prefix_stats_delimiter = Some(delimiter);
+        self
+    }
+
+    /// Keeps a companion index of the reversed keys, so that `Tree::scan_suffix`
+    /// does not have to scan the whole Tree.
+    ///
+    /// The index stores a second copy of every key, but no values.
+    pub fn with_suffix_index(mut self) -> Self {
+        self.suffix_index = true;
+        self
+    }
+
+    /// Sets what inserts do with a timestamp the key already has a version at.
+    pub fn with_duplicate_ts_policy(mut self, policy: DuplicateTsPolicy) -> Self {
+        self.duplicate_ts_policy = policy;
+        self
+    }
+
+    /// Reports keys with at least `threshold` versions in `Tree::overloaded_keys`.
+    ///
+    /// The limit is soft: writes to an overloaded key still succeed.
+    pub fn with_version_warn_threshold(mut self, threshold: usize) -> Self {
+        self.version_warn_threshold = Some(threshold);
+        self
+    }
+
+    /// Counts the successful reads of every prefix of `depth` bytes, reported by
+    /// `Tree::hot_prefixes`.
+    ///
+    /// The counters keep a fixed number of prefixes, the most read ones, and
+    /// counting a read does not allocate. Keys shorter than `depth` are counted
+    /// under the whole key. `depth` can be at most 16: `Tree::try_with_options`
+    /// rejects a larger one, and `Tree::with_options` clamps it to 16.
+    pub fn track_read_frequency(mut self, depth: usize) -> Self {
+        self.read_frequency_depth = Some(depth);
+        self
+    }
+
+    /// Counts reads through the snapshots of the Tree, and their readers, along
+    /// with the reads through the Tree when read frequencies are tracked.
+    pub fn count_snapshot_reads(mut self) -> Self {
+        self.count_snapshot_reads = true;
+        self
+    }
+
+    /// Lets up to `n` consecutive inserts update the inner nodes copied by the
+    /// earlier inserts of the window in place, rather than copying them again.
+    ///
+    /// Clustered writes then copy the upper nodes of their paths once per window
+    /// instead of once per insert. A node is only updated in place while the
+    /// Tree holds the sole reference to it, and creating a snapshot or view
+    /// closes the window, so readers never see a node change. Writes other than
+    /// `insert` copy their paths as usual.
+    pub fn cow_batch_window(mut self, n: usize) -> Self {
+        self.cow_batch_window = Some(n);
+        self
+    }
+
+    /// Checks the invariants of the nodes each operation walks through, and
+    /// poisons the Tree on the first violation found.
+    ///
+    /// A poisoned Tree fails every later operation with `TrieError::Poisoned`,
+    /// except `Tree::freeze`, `Tree::export_owned_snapshot`, closing the Tree
+    /// and closing its snapshots. The checks only look at the nodes on the path
+    /// to the key of an operation, so they cost a few branches per node for
+    /// reads, and one more descent per written key. Scans are not checked.
+    /// Passing `false` turns strict mode off.
+    pub fn poison_on_corruption(mut self, enabled: bool) -> Self {
+        self.invariant_checks = enabled.then(InvariantChecks::all);
+        self
+    }
+
+    /// Poisons the Tree on the first violation found by the given checks only,
+    /// as with `poison_on_corruption`.
+    pub fn invariant_checks(mut self, checks: InvariantChecks) -> Self {
+        self.invariant_checks = Some(checks);
+        self
+    }
+
+    /// Rejects entries with a timestamp above `ts` on the ingest paths:
+    /// `Tree::bulk_insert`, `Tree::bulk_append` and `Tree::apply_wal`.
+    ///
+    /// Unless `validate_ingest` sets another policy, the timestamps are then
+    /// validated with `IngestPolicy::strict`.
+    pub fn max_valid_ts(mut self, ts: u64) -> Self {
+        self.max_valid_ts = Some(ts);
+        self
+    }
+
+    /// Validates the timestamps of the entries written by the ingest paths
+    /// with `policy`, which rejects or repairs zero timestamps and histories
+    /// going back in time. Direct writes such as `Tree::insert` are not
+    /// validated.
+    pub fn validate_ingest(mut self, policy: IngestPolicy) -> Self {
+        self.ingest_policy = Some(policy);
+        self
+    }
+
+    /// Keeps a separate timestamp counter, a domain, for every key segment
+    /// ending before the first `delimiter`, reported by `Tree::ts_of_domain`.
+    ///
+    /// Each counter follows the timestamps written to the keys of its domain
+    /// only, so writers that own disjoint domains can number their writes
+    /// independently and exchange them with `Tree::splice_subtree` without the
+    /// counter of one moving the other's. Keys without a delimiter belong to no
+    /// domain and follow the counter of the whole Tree, `Tree::current_ts`,
+    /// which keeps following every write. Timestamps of different domains are
+    /// not comparable.
+    pub fn ts_domains(mut self, delimiter: u8) -> Self {
+        self.ts_domain_delimiter = Some(delimiter);
+        self
+    }
+
+    /// Sets how many versions of each key inserts keep.
+    ///
+    /// The strategy applies to the writes made after it is set: versions the
+    /// strategy drops are gone for reads at older versions too, except through
+    /// the snapshots taken before the write.
+    pub fn with_versioning(mut self, versioning: VersioningStrategy) -> Self {
+        self.versioning = versioning;
+        self
+    }
+
+    /// Keeps a hash index over the keys, built with the default hasher; see
+    /// `Tree::with_hash_index`, which takes the hasher to use.
+    pub fn with_hash_index(mut self) -> Self {
+        self.hash_index = true;
+        self
+    }
+
+    /// Caches the number of keys below each inner node; see `Tree::with_counts`.
+    pub fn with_counts(mut self) -> Self {
+        self.cached_counts = true;
+        self
+    }
+
+    /// Creates inner nodes as at least `kind`; see `Tree::with_forced_node_type`.
+    pub fn force_node_type(mut self, kind: NodeKind) -> Self {
+        self.forced_node_type = Some(kind);
+        self
+    }
+
+    /// Normalizes every key with `normalization`; see `Tree::with_normalizer`,
+    /// which also takes normalizers of its own.
+    pub fn normalize_keys(mut self, normalization: KeyNormalization) -> Self {
+        self.key_normalization = Some(normalization);
+        self
+    }
+
+    /// Sets the number of snapshots that can be active at once.
+    pub fn max_active_snapshots(mut self, n: u64) -> Self {
+        self.max_active_snapshots = n;
+        self
+    }
+
+    /// Checks every value read against the checksum kept with it, so that a
+    /// value corrupted in memory is reported instead of returned.
+    ///
+    /// Only values that keep a checksum, such as `Checksummed` values, can be
+    /// checked, so the option is taken by `Tree::with_codec_options` and
+    /// `Tree::thaw`, which read the values through their `ValueCodec`, while
+    /// `Tree::with_options` leaves it off. `get` fails with
+    /// `TrieError::ChecksumMismatch` on a corrupt value, and `iter`, `range`
+    /// and `Snapshot::range_page` leave corrupt values out; `Tree::scrub`
+    /// reports every one of them. Trees without the option pay nothing for it.
+    pub fn value_checksums(mut self, enabled: bool) -> Self {
+        self.value_checksums = enabled;
+        self
+    }
+}
+
+impl TreeOptions {
+    /// Checks that every option has a valid value, and that the options can be
+    /// used together.
+    ///
+    /// `Tree::with_options` accepts invalid options as it always has, and runs
+    /// with the nearest valid ones, see `clamped`; `Tree::try_with_options` and
+    /// `Tree::reconfigure` reject them with this.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ReconfigureError::Invalid` for a zero pressure window, a
+    /// pressure threshold outside `0.0..=1.0`, a read frequency depth above 16
+    /// or a `KeepN` versioning strategy keeping no versions,
+    /// and `ReconfigureError::Conflict` for a medium pressure threshold above the
+    /// high one, or snapshot reads counted without read frequencies.
+    pub fn validate(&self) -> Result<(), ReconfigureError> {
+        if self.pressure_window == 0 {
+            return Err(ReconfigureError::Invalid {
+                option: "pressure_window",
+                reason: "must be positive",
+            });
+        }
+        for (option, threshold) in [
+            ("pressure_medium", self.pressure_medium),
+            ("pressure_high", self.pressure_high),
+        ] {
+            if !(0.0..=1.0).contains(&threshold) {
+                return Err(ReconfigureError::Invalid {
+                    option,
+                    reason: "must be a fraction between 0 and 1",
+                });
+            }
+        }
+        if self.pressure_medium > self.pressure_high {
+            return Err(ReconfigureError::Conflict {
+                options: ["pressure_medium", "pressure_high"],
+                reason: "the medium threshold is above the high one",
+            });
+        }
+        if self
+            .read_frequency_depth
+            .is_some_and(|depth| depth > popularity::MAX_DEPTH)
+        {
+            return Err(ReconfigureError::Invalid {
+                option: "read_frequency_depth",
+                reason: "must be at most 16",
+            });
+        }
+        if self.count_snapshot_reads && self.read_frequency_depth.is_none() {
+            return Err(ReconfigureError::Conflict {
+                options: ["count_snapshot_reads", "read_frequency_depth"],
+                reason: "snapshot reads are counted only along with read frequencies",
+            });
+        }
+        if self.versioning == VersioningStrategy::KeepN(0) {
+            return Err(ReconfigureError::Invalid {
+                option: "versioning",
+                reason: "must keep at least one version",
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the nearest options that validate.
+    ///
+    /// A zero pressure window becomes 1, the pressure thresholds are clamped to
+    /// `0.0..=1.0` with the medium one at most the high one, the read frequency
+    /// depth is clamped to 16, snapshot reads are only counted along with read
+    /// frequencies, and a `KeepN` strategy keeps at least one version.
+    pub fn clamped(mut self) -> Self {
+        let fraction = |threshold: f64, default: f64| match threshold.is_nan() {
+            true => default,
+            false => threshold.clamp(0.0, 1.0),
+        };
+        let defaults = TreeOptions::default();
+        self.pressure_window = self.pressure_window.max(1);
+        self.pressure_high = fraction(self.pressure_high, defaults.pressure_high);
+        self.pressure_medium =
+            fraction(self.pressure_medium, defaults.pressure_medium).min(self.pressure_high);
+        self.read_frequency_depth = self
+            .read_frequency_depth
+            .map(|depth| depth.min(popularity::MAX_DEPTH));
+        self.count_snapshot_reads &= self.read_frequency_depth.is_some();
+        if self.versioning == VersioningStrategy::KeepN(0) {
+            self.versioning = VersioningStrategy::KeepN(1);
+        }
+        self
+    }
+}
+
+/// A change to the options of a live Tree, applied by `Tree::reconfigure`.
+///
+/// Every option left unset keeps its current value. Options that only steer the
+/// behavior of later operations can change at runtime:
+///
+/// * the pressure window and thresholds, which restart the pressure counters
+///   when the window changes,
+/// * the duplicate timestamp policy, and the versioning strategy,
+/// * the version warning threshold, against which every key is checked again,
+/// * whether snapshot reads are counted,
+/// * the copy-on-write batch window, which is closed first,
+/// * the invariant checks, and the ingest validation,
+/// * the number of active snapshots, which only limits later snapshots.
+///
+/// The prefix statistics, the suffix index, the read frequency depth, the
+/// timestamp domains, the hash index, the cached counts, the forced node type,
+/// the key normalization and the value checksums shape what every write since
+/// the Tree was built kept, so changing them fails with `ReconfigureError::RequiresRebuild`:
+/// build a new Tree with the options and copy the entries over instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OptionsPatch {
+    pub pressure_window: Option<u64>,
+    pub pressure_medium: Option<f64>,
+    pub pressure_high: Option<f64>,
+    pub prefix_stats_delimiter: Option<Option<u8>>,
+    pub suffix_index: Option<bool>,
+    pub duplicate_ts_policy: Option<DuplicateTsPolicy>,
+    pub version_warn_threshold: Option<Option<usize>>,
+    pub read_frequency_depth: Option<Option<usize>>,
+    pub count_snapshot_reads: Option<bool>,
+    pub cow_batch_window: Option<Option<usize>>,
+    pub invariant_checks: Option<Option<InvariantChecks>>,
+    pub max_valid_ts: Option<Option<u64>>,
+    pub ingest_policy: Option<Option<IngestPolicy>>,
+    pub ts_domain_delimiter: Option<Option<u8>>,
+    pub versioning: Option<VersioningStrategy>,
+    pub hash_index: Option<bool>,
+    pub cached_counts: Option<bool>,
+    pub forced_node_type: Option<Option<NodeKind>>,
+    pub key_normalization: Option<Option<KeyNormalization>>,
+    pub max_active_snapshots: Option<u64>,
+    pub value_checksums: Option<bool>,
+}
+
+impl OptionsPatch {
+    /// Sets the width of the pressure window.
+    pub fn pressure_window(mut self, window: u64) -> Self {
+        self.pressure_window = Some(window);
+        self
+    }
+
+    /// Sets the fractions of retained node copies at which pressure becomes
+    /// `Medium` and `High`.
+    pub fn pressure_thresholds(mut self, medium: f64, high: f64) -> Self {
+        self.pressure_medium = Some(medium);
+        self.pressure_high = Some(high);
+        self
+    }
+
+    /// Sets the delimiter of the key segments prefix statistics are kept for.
+    pub fn prefix_stats_delimiter(mut self, delimiter: Option<u8>) -> Self {
+        self.prefix_stats_delimiter = Some(delimiter);
+        self
+    }
+
+    /// Sets whether a suffix index is kept.
+    pub fn suffix_index(mut self, enabled: bool) -> Self {
+        self.suffix_index = Some(enabled);
+        self
+    }
+
+    /// Sets what inserts do with a timestamp the key already has a version at.
+    pub fn duplicate_ts_policy(mut self, policy: DuplicateTsPolicy) -> Self {
+        self.duplicate_ts_policy = Some(policy);
+        self
+    }
+
+    /// Sets the number of versions at which keys are reported as overloaded.
+    pub fn version_warn_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.version_warn_threshold = Some(threshold);
+        self
+    }
+
+    /// Sets the number of leading key bytes read frequencies are counted for.
+    pub fn read_frequency_depth(mut self, depth: Option<usize>) -> Self {
+        self.read_frequency_depth = Some(depth);
+        self
+    }
+
+    /// Sets whether reads through snapshots are counted.
+    pub fn count_snapshot_reads(mut self, enabled: bool) -> Self {
+        self.count_snapshot_reads = Some(enabled);
+        self
+    }
+
+    /// Sets the number of inserts that may update nodes in place.
+    pub fn cow_batch_window(mut self, n: Option<usize>) -> Self {
+        self.cow_batch_window = Some(n);
+        self
+    }
+
+    /// Sets the invariant checks run in strict mode.
+    pub fn invariant_checks(mut self, checks: Option<InvariantChecks>) -> Self {
+        self.invariant_checks = Some(checks);
+        self
+    }
+
+    /// Sets the largest timestamp the ingest paths accept.
+    pub fn max_valid_ts(mut self, ts: Option<u64>) -> Self {
+        self.max_valid_ts = Some(ts);
+        self
+    }
+
+    /// Sets how the ingest paths validate timestamps.
+    pub fn ingest_policy(mut self, policy: Option<IngestPolicy>) -> Self {
+        self.ingest_policy = Some(policy);
+        self
+    }
+
+    /// Sets the delimiter of the key segments timestamp domains are kept for.
+    pub fn ts_domain_delimiter(mut self, delimiter: Option<u8>) -> Self {
+        self.ts_domain_delimiter = Some(delimiter);
+        self
+    }
+
+    /// Sets how many versions of each key inserts keep.
+    pub fn versioning(mut self, versioning: VersioningStrategy) -> Self {
+        self.versioning = Some(versioning);
+        self
+    }
+
+    /// Sets whether a hash index is kept.
+    pub fn hash_index(mut self, enabled: bool) -> Self {
+        self.hash_index = Some(enabled);
+        self
+    }
+
+    /// Sets whether inner nodes cache their key counts.
+    pub fn cached_counts(mut self, enabled: bool) -> Self {
+        self.cached_counts = Some(enabled);
+        self
+    }
+
+    /// Sets the smallest type inner nodes are created as.
+    pub fn forced_node_type(mut self, kind: Option<NodeKind>) -> Self {
+        self.forced_node_type = Some(kind);
+        self
+    }
+
+    /// Sets the normalization applied to every key.
+    pub fn key_normalization(mut self, normalization: Option<KeyNormalization>) -> Self {
+        self.key_normalization = Some(normalization);
+        self
+    }
+
+    /// Sets the number of snapshots that can be active at once.
+    pub fn max_active_snapshots(mut self, n: u64) -> Self {
+        self.max_active_snapshots = Some(n);
+        self
+    }
+
+    /// Sets whether every value read is checked against its checksum.
+    pub fn value_checksums(mut self, enabled: bool) -> Self {
+        self.value_checksums = Some(enabled);
+        self
+    }
+
+    /// Returns `options` with the options set in the patch replaced.
+    pub fn apply_to(&self, options: TreeOptions) -> TreeOptions {
+        TreeOptions {
+            pressure_window: self.pressure_window.unwrap_or(options.pressure_window),
+            pressure_medium: self.pressure_medium.unwrap_or(options.pressure_medium),
+            pressure_high: self.pressure_high.unwrap_or(options.pressure_high),
+            prefix_stats_delimiter: self
+                .prefix_stats_delimiter
+                .unwrap_or(options.prefix_stats_delimiter),
+            suffix_index: self.suffix_index.unwrap_or(options.suffix_index),
+            duplicate_ts_policy: self
+                .duplicate_ts_policy
+                .unwrap_or(options.duplicate_ts_policy),
+            version_warn_threshold: self
+                .version_warn_threshold
+                .unwrap_or(options.version_warn_threshold),
+            read_frequency_depth: self
+                .read_frequency_depth
+                .unwrap_or(options.read_frequency_depth),
+            count_snapshot_reads: self
+                .count_snapshot_reads
+                .unwrap_or(options.count_snapshot_reads),
+            cow_batch_window: self.cow_batch_window.unwrap_or(options.cow_batch_window),
+            invariant_checks: self.invariant_checks.unwrap_or(options.invariant_checks),
+            max_valid_ts: self.max_valid_ts.unwrap_or(options.max_valid_ts),
+            ingest_policy: self.ingest_policy.unwrap_or(options.ingest_policy),
+            ts_domain_delimiter: self
+                .ts_domain_delimiter
+                .unwrap_or(options.ts_domain_delimiter),
+            versioning: self.versioning.unwrap_or(options.versioning),
+            hash_index: self.hash_index.unwrap_or(options.hash_index),
+            cached_counts: self.cached_counts.unwrap_or(options.cached_counts),
+            forced_node_type: self.forced_node_type.unwrap_or(options.forced_node_type),
+            key_normalization: self.key_normalization.unwrap_or(options.key_normalization),
+            max_active_snapshots: self
+                .max_active_snapshots
+                .unwrap_or(options.max_active_snapshots),
+            value_checksums: self.value_checksums.unwrap_or(options.value_checksums),
+        }
+    }
+}
+
+/// An error validating or changing the options of a Tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReconfigureError {
+    /// The option has a value it cannot take.
+    Invalid {
+        option: &'static str,
+        reason: &'static str,
+    },
+    /// The two options have values that cannot be used together.
+    Conflict {
+        options: [&'static str; 2],
+        reason: &'static str,
+    },
+    /// The option cannot change on a live Tree, which has to be rebuilt with the
+    /// new value.
+    RequiresRebuild { option: &'static str },
+    /// The Tree is closed.
+    TreeAlreadyClosed,
+}
+
+impl Error for ReconfigureError {}
+
+impl fmt::Display for ReconfigureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReconfigureError::Invalid { option, reason } => {
+                write!(f, "Invalid option {}: {}", option, reason)
+            }
+            ReconfigureError::Conflict { options, reason } => {
+                write!(
+                    f,
+                    "Options {} and {} conflict: {}",
+                    options[0], options[1], reason
+                )
+            }
+            ReconfigureError::RequiresRebuild { option } => {
+                write!(f, "Changing option {} requires rebuilding the tree", option)
+            }
+            ReconfigureError::TreeAlreadyClosed => write!(f, "Tree already closed"),
+        }
+    }
+}
+
+/// What an insert does when the key already has a version with the same timestamp.
+///
+/// Timestamps are passed in by the caller and, unlike versions, are not required
+/// to grow, so two writes of a key derived from a coarse clock can easily share
+/// one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicateTsPolicy {
+    /// Fail the insert with `TrieError::DuplicateTimestamp`.
+    Reject,
+    /// Store the value under a new version and drop the versions of the key with
+    /// the same timestamp, so that the key keeps a single version per timestamp.
+    /// Snapshots taken before the insert still see the dropped versions.
+    Replace,
+    /// Keep every version. Versions grow with every insert, so among the versions
+    /// sharing a timestamp the one inserted last has the highest version, and is
+    /// the one returned by reads that do not ask for an older version.
+    #[default]
+    Stack,
+}
+
+/// How many versions of each key inserts keep.
+///
+/// The strategy is applied as a key is written: the versions it drops are not
+/// copied into the new twig, and stay alive only as long as a snapshot or reader
+/// shares the old one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VersioningStrategy {
+    /// Keep every version, so that reads at any older version see the key as it
+    /// was.
+    #[default]
+    KeepAll,
+    /// Keep only the latest version, overwriting the value in place of adding a
+    /// version, so that a key takes the same memory however often it is written.
+    KeepLatest,
+    /// Keep the given number of latest versions, at least one.
+    KeepN(usize),
+}
+
+impl VersioningStrategy {
+    /// Returns the number of versions kept per key, or `None` to keep them all.
+    pub fn limit(&self) -> Option<usize> {
+        match self {
+            VersioningStrategy::KeepAll => None,
+            VersioningStrategy::KeepLatest => Some(1),
+            VersioningStrategy::KeepN(n) => Some((*n).max(1)),
+        }
+    }
+}
+
+/// A coarse level of write pressure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PressureLevel {
+    Low,
+    Medium,
+    High,
+}
+
+/// The write pressure of a Tree along with the counters it is computed from.
+///
+/// The counters cover the inserts in the current and the previous window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pressure {
+    /// The level derived from `retained_ratio`.
+    pub level: PressureLevel,
+    /// Number of inserts counted.
+    pub inserts: u64,
+    /// Number of nodes copied by those inserts.
+    pub nodes_copied: u64,
+    /// Number of copied nodes whose old version is still shared with a snapshot
+    /// or reader, and so was not freed.
+    pub nodes_retained: u64,
+    /// Number of nodes updated in place instead of copied, under
+    /// `TreeOptions::cow_batch_window`.
+    pub nodes_reused: u64,
+    /// Number of inserts that added a version to an existing key.
+    pub versions_added: u64,
+    /// Number of active snapshots.
+    pub snapshots: usize,
+}
+
+impl Pressure {
+    fn per_insert(&self, count: u64) -> f64 {
+        if self.inserts == 0 {
+            return 0.0;
+        }
+        count as f64 / self.inserts as f64
+    }
+
+    /// Returns the average number of nodes copied per insert.
+    pub fn copies_per_insert(&self) -> f64 {
+        self.per_insert(self.nodes_copied)
+    }
+
+    /// Returns the average number of retained node copies per insert.
+    pub fn retained_per_insert(&self) -> f64 {
+        self.per_insert(self.nodes_retained)
+    }
+
+    /// Returns the fraction of copied nodes that were retained.
+    pub fn retained_ratio(&self) -> f64 {
+        if self.nodes_copied == 0 {
+            return 0.0;
+        }
+        self.nodes_retained as f64 / self.nodes_copied as f64
+    }
+
+    /// Returns the fraction of inserts that added a version to an existing key.
+    pub fn version_growth(&self) -> f64 {
+        self.per_insert(self.versions_added)
+    }
+}
+
+/// Node copies made by a single insert.
+#[derive(Default)]
+pub(crate) struct InsertStats {
+    pub(crate) copied: u64,
+    pub(crate) retained: u64,
+    pub(crate) reused: u64,
+    // The types of the node a child was added to, before and after, if it grew.
+    pub(crate) upgrade: Option<(NodeKind, NodeKind)>,
+    // Whether the prefix of a node was split to make room for the key.
+    pub(crate) prefix_split: bool,
+    // The number of inner nodes above the leaf of the key.
+    pub(crate) depth: usize,
+    // Whether an inner node on the path so far is shared with another tree.
+    path_shared: bool,
+    // The strict mode checks run on the nodes of the insert path, if any.
+    pub(crate) checks: Option<InvariantChecks>,
+    // The violation found by the checks, which stopped the insert.
+    pub(crate) violation: Option<InvariantViolation>,
+    // The pinned versions of the key, which the versioning strategy keeps.
+    pub(crate) pinned: Vec<u64>,
+    // The versions of the key the insert dropped, replaced by the new one or
+    // trimmed by the versioning strategy.
+    pub(crate) replaced: usize,
+}
+
+impl InsertStats {
+    /// Records the copy of a node on the insert path.
+    pub(crate) fn copy(&mut self, ref_count: usize) {
+        if ref_count > 1 {
+            self.path_shared = true;
+        }
+        self.copied += 1;
+        if self.path_shared {
+            self.retained += 1;
+        }
+    }
+
+    /// Records a node on the insert path updated in place.
+    pub(crate) fn reuse(&mut self) {
+        self.reused += 1;
+    }
+
+    /// Runs `check` with the strict mode checks, if any, and keeps the violation
+    /// it finds for the Tree to poison itself with.
+    pub(crate) fn check(
+        &mut self,
+        check: impl FnOnce(InvariantChecks) -> Result<(), InvariantViolation>,
+    ) -> Result<(), TrieError> {
+        let Some(checks) = self.checks else {
+            return Ok(());
+        };
+        check(checks).map_err(|violation| {
+            let err = TrieError::Poisoned {
+                first_violation: violation.clone(),
+            };
+            self.violation = Some(violation);
+            err
+        })
+    }
+
+    /// Records a child added to a node of type `from`, which became `to`.
+    pub(crate) fn add_child(&mut self, from: Option<NodeKind>, to: Option<NodeKind>) {
+        if let (Some(from), Some(to)) = (from, to) {
+            if from != to {
+                self.upgrade = Some((from, to));
+            }
+        }
+    }
+}
+
+/// The inner nodes copied by the recent inserts of a Tree built with
+/// `TreeOptions::cow_batch_window`.
+pub(crate) struct CowWindow {
+    limit: usize,
+    writes: usize,
+    // The addresses of the nodes. An address is only a hint: a node is updated
+    // in place only while the Tree holds the sole reference to it, so a stale
+    // address reused by another node cannot expose a change to a reader.
+    nodes: HashSet<usize>,
+}
+
+impl CowWindow {
+    pub(crate) fn new(limit: usize) -> Self {
+        CowWindow {
+            limit,
+            writes: 0,
+            nodes: HashSet::new(),
+        }
+    }
+
+    /// Returns the number of inserts the window spans.
+    pub(crate) fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Returns true if `node` was copied within the window.
+    pub(crate) fn holds<T>(&self, node: &Arc<T>) -> bool {
+        self.nodes.contains(&(Arc::as_ptr(node) as usize))
+    }
+
+    pub(crate) fn add<T>(&mut self, node: &Arc<T>) {
+        self.nodes.insert(Arc::as_ptr(node) as usize);
+    }
+
+    /// Counts an insert, closing the window once it has taken `limit` of them.
+    pub(crate) fn record_write(&mut self) {
+        self.writes += 1;
+        if self.writes >= self.limit {
+            self.flush();
+        }
+    }
+
+    /// Closes the window: the next insert copies its whole path again.
+    pub(crate) fn flush(&mut self) {
+        self.writes = 0;
+        self.nodes.clear();
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Window {
+    inserts: u64,
+    copied: u64,
+    retained: u64,
+    reused: u64,
+    versions_added: u64,
+}
+
+/// Pressure counters kept over two consecutive windows of logical time.
+pub(crate) struct PressureTracker {
+    options: TreeOptions,
+    epoch: u64,
+    current: Window,
+    previous: Window,
+}
+
+impl PressureTracker {
+    pub(crate) fn new(options: TreeOptions) -> Self {
+        PressureTracker {
+            options,
+            epoch: 0,
+            current: Window::default(),
+            previous: Window::default(),
+        }
+    }
+
+    /// Returns the options the tracker was created or last updated with.
+    pub(crate) fn options(&self) -> &TreeOptions {
+        &self.options
+    }
+
+    /// Switches to the pressure window and thresholds of `options`. A new
+    /// window restarts the counters, which were kept over the old one.
+    pub(crate) fn set_options(&mut self, options: TreeOptions) {
+        if options.pressure_window != self.options.pressure_window {
+            *self = PressureTracker::new(options);
+        } else {
+            self.options = options;
+        }
+    }
+
+    /// Records an insert at timestamp `ts`.
+    pub(crate) fn record(&mut self, ts: u64, stats: &InsertStats, version_added: bool) {
+        let epoch = ts / self.options.pressure_window.max(1);
+        if epoch > self.epoch {
+            self.previous = if epoch == self.epoch + 1 {
+                self.current
+            } else {
+                Window::default()
+            };
+            self.current = Window::default();
+            self.epoch = epoch;
+        }
+
+        self.current.inserts += 1;
+        self.current.copied += stats.copied;
+        self.current.retained += stats.retained;
+        self.current.reused += stats.reused;
+        self.current.versions_added += version_added as u64;
+    }
+
+    pub(crate) fn pressure(&self, snapshots: usize) -> Pressure {
+        let mut pressure = Pressure {
+            level: PressureLevel::Low,
+            inserts: self.current.inserts + self.previous.inserts,
+            nodes_copied: self.current.copied + self.previous.copied,
+            nodes_retained: self.current.retained + self.previous.retained,
+            nodes_reused: self.current.reused + self.previous.reused,
+            versions_added: self.current.versions_added + self.previous.versions_added,
+            snapshots,
+        };
+
+        let retained = pressure.retained_ratio();
+        if retained >= self.options.pressure_high {
+            pressure.level = PressureLevel::High;
+        } else if retained >= self.options.pressure_medium {
+            pressure.level = PressureLevel::Medium;
+        }
+        pressure
+    }
+}
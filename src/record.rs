@@ -0,0 +1,99 @@
+//! This module defines the operation log used to record the mutations of a Tree
+//! and replay them into a fresh one.
+use std::sync::{Arc, Mutex};
+
+/// A mutation of a Tree, as recorded by an `OpSink`.
+///
+/// Versions are recorded as resolved by the Tree rather than as passed by the
+/// caller, so that replaying a log assigns exactly the same versions.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OpRecord<P, V> {
+    /// A key-value pair inserted with `insert`, `insert_as` or `update`.
+    Insert {
+        key: P,
+        value: V,
+        version: u64,
+        ts: u64,
+    },
+    /// The key-value pairs inserted by one `bulk_insert`, each with its
+    /// version and timestamp.
+    BulkInsert { entries: Vec<(P, V, u64, u64)> },
+    /// A key removed with `remove` or `remove_as`.
+    Remove { key: P },
+    /// A call to `prune_versions_older_than`, along with the `(key, version)`
+    /// pairs that were pinned at the time.
+    Prune {
+        version: u64,
+        pinned: Vec<(Vec<u8>, u64)>,
+    },
+    /// A call to `retain_versions`, along with the `(key, version)` pairs that
+    /// were pinned at the time.
+    RetainVersions {
+        max_per_key: usize,
+        pinned: Vec<(Vec<u8>, u64)>,
+    },
+    /// A snapshot created with `create_snapshot`.
+    CreateSnapshot { id: u64 },
+    /// A snapshot closed on the Tree.
+    CloseSnapshot { id: u64 },
+}
+
+/// A destination for the operations recorded by a Tree.
+pub trait OpSink<P, V>: Send + Sync {
+    /// Records an operation that was applied to the Tree.
+    fn record(&mut self, op: OpRecord<P, V>);
+}
+
+/// An in-memory operation log.
+///
+/// Clones share the same log, so one handle can be passed to
+/// `Tree::enable_recording` while another is kept to read the records back.
+pub struct OpLog<P, V> {
+    records: Arc<Mutex<Vec<OpRecord<P, V>>>>,
+}
+
+impl<P, V> OpLog<P, V> {
+    /// Creates an empty log.
+    pub fn new() -> Self {
+        OpLog {
+            records: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Returns the number of recorded operations.
+    pub fn len(&self) -> usize {
+        self.records.lock().unwrap().len()
+    }
+
+    /// Returns true if no operations have been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<P: Clone, V: Clone> OpLog<P, V> {
+    /// Returns a copy of the recorded operations, oldest first.
+    pub fn records(&self) -> Vec<OpRecord<P, V>> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+impl<P, V> Clone for OpLog<P, V> {
+    fn clone(&self) -> Self {
+        OpLog {
+            records: self.records.clone(),
+        }
+    }
+}
+
+impl<P, V> Default for OpLog<P, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: Send, V: Send> OpSink<P, V> for OpLog<P, V> {
+    fn record(&mut self, op: OpRecord<P, V>) {
+        self.records.lock().unwrap().push(op);
+    }
+}
@@ -0,0 +1,286 @@
+//! This module defines sample views, which read a deterministic subset of the
+//! keys of a Tree, selected by a hash of the key bytes, at a fixed version.
+use std::sync::Arc;
+
+use crate::art::Node;
+use crate::checksum::{fnv1a_64, FNV64_OFFSET};
+use crate::expiry::ExpiryTable;
+use crate::iter::Iter;
+use crate::normalize::{normalize_key, KeyNormalizer};
+use crate::{KeyTrait, TrieError};
+
+/// Returns where `key` falls in `[0, 1)` for the given seed.
+///
+/// The bytes are hashed with FNV-1a, so that a key falls at the same point in
+/// every process and on every state of the Tree, and the hash is finalized with
+/// the MurmurHash3 mixer, so that keys differing in their last bytes are spread
+/// over the whole interval.
+fn sample_point(seed: u64, key: &[u8]) -> f64 {
+    let mut hash = fnv1a_64(fnv1a_64(FNV64_OFFSET, &seed.to_le_bytes()), key);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^= hash >> 33;
+    // The top 53 bits, which an f64 holds exactly.
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A read view of a sample of the keys of a Tree, at the version it had when
+/// the view was created.
+///
+/// A key is in the sample if its bytes, hashed with the seed, fall below the
+/// fraction, so the same seed and fraction select the same keys on every state
+/// of every Tree, and samples taken at different times can be compared key by
+/// key. The view holds the root it was created from, which keeps the nodes of
+/// that version alive, but it is not registered as a snapshot.
+pub struct SampleView<P: KeyTrait, V: Clone> {
+    root: Option<Arc<Node<P, V>>>,
+    version: u64,
+    fraction: f64,
+    seed: u64,
+    complement: bool,
+    normalizer: Option<Arc<dyn KeyNormalizer>>,
+    // The expiry rules of the Tree and its timestamp when the view was
+    // created, if it had rules.
+    pub(crate) expiry: Option<(ExpiryTable, u64)>,
+}
+
+impl<P: KeyTrait, V: Clone> SampleView<P, V> {
+    pub(crate) fn new(
+        root: Option<Arc<Node<P, V>>>,
+        version: u64,
+        fraction: f64,
+        seed: u64,
+        normalizer: Option<Arc<dyn KeyNormalizer>>,
+    ) -> Self {
+        let fraction = match fraction.is_nan() {
+            true => 0.0,
+            false => fraction.clamp(0.0, 1.0),
+        };
+        SampleView {
+            root,
+            version,
+            fraction,
+            seed,
+            complement: false,
+            normalizer,
+            expiry: None,
+        }
+    }
+
+    /// Returns the version of the Tree the view reads at.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns the fraction of the keys the view selects, which is the
+    /// fraction it was created with, or one minus it for a complement.
+    pub fn fraction(&self) -> f64 {
+        match self.complement {
+            true => 1.0 - self.fraction,
+            false => self.fraction,
+        }
+    }
+
+    /// Returns the seed the keys are selected with.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Returns a view of the same version selecting exactly the keys this view
+    /// does not.
+    pub fn complement(&self) -> Self {
+        SampleView {
+            root: self.root.clone(),
+            version: self.version,
+            fraction: self.fraction,
+            seed: self.seed,
+            complement: !self.complement,
+            normalizer: self.normalizer.clone(),
+            expiry: self.expiry.clone(),
+        }
+    }
+
+    /// Returns whether the view selects a key with the given stored bytes.
+    fn selects(&self, key: &[u8]) -> bool {
+        (sample_point(self.seed, key) < self.fraction) != self.complement
+    }
+
+    /// Returns whether `key` is in the sample, whether or not the view holds it.
+    pub fn is_sampled(&self, key: &P) -> bool {
+        let key = normalize_key(self.normalizer.as_ref(), key);
+        self.selects(key.as_slice())
+    }
+
+    /// Retrieves the latest value of the given key, with the version and
+    /// timestamp it was written at.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::NotSampled` if the key is not in the sample, without
+    /// looking it up, and `TrieError::KeyNotFound` if it is in the sample but
+    /// the view does not hold it.
+    ///
+    pub fn get(&self, key: &P) -> Result<(V, u64, u64), TrieError> {
+        let key = normalize_key(self.normalizer.as_ref(), key);
+        if !self.selects(key.as_slice()) {
+            return Err(TrieError::NotSampled);
+        }
+        if let Some((rules, now)) = &self.expiry {
+            if rules.is_expired(key.as_slice(), *now) {
+                return Err(TrieError::KeyNotFound);
+            }
+        }
+        Node::resolve_get(self.root.as_ref(), key.as_ref(), 0)
+            .map(|(_, value, version, ts)| (value, version, ts))
+    }
+
+    /// Returns an iterator over the latest key-value pairs of the sampled keys,
+    /// in key order.
+    ///
+    /// Keys are selected as the traversal reaches them, so no list of the
+    /// sampled keys is built, but every key of the view is visited.
+    pub fn iter(&self) -> impl Iterator<Item = (Vec<u8>, &V, &u64, &u64)> + '_ {
+        let entries = match &self.expiry {
+            Some((rules, now)) => Iter::live(self.root.as_ref(), rules, *now),
+            None => Iter::new(self.root.as_ref()),
+        };
+        entries.filter(|(key, ..)| self.selects(key))
+    }
+
+    /// Returns the number of sampled keys the view holds.
+    pub fn count(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Returns the number of keys the view is estimated to hold in total, the
+    /// number of sampled keys scaled up by the fraction.
+    ///
+    /// A view with a fraction of 0 samples nothing and estimates 0.
+    pub fn estimated_total(&self) -> f64 {
+        match self.fraction() {
+            fraction if fraction > 0.0 => self.count() as f64 / fraction,
+            _ => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::art::Tree;
+    use crate::{Key, TrieError, VariableSizeKey};
+    use std::collections::BTreeSet;
+    use std::str::FromStr;
+
+    fn key(n: u64) -> VariableSizeKey {
+        VariableSizeKey::from_str(&format!("key{:05}", n)).unwrap()
+    }
+
+    fn sampled_keys(tree: &Tree<VariableSizeKey, u64>, fraction: f64, seed: u64) -> Vec<Vec<u8>> {
+        let view = tree.sample_view(fraction, seed);
+        view.iter().map(|(key, ..)| key).collect()
+    }
+
+    #[test]
+    fn samples_are_deterministic_across_mutations() {
+        let mut tree: Tree<VariableSizeKey, u64> = Tree::new();
+        for n in 0..5000 {
+            tree.insert(&key(n), n, 0, n).unwrap();
+        }
+        let before = sampled_keys(&tree, 0.1, 7);
+        let view = tree.sample_view(0.1, 7);
+        assert!((350..650).contains(&before.len()));
+        let estimate = view.estimated_total();
+        assert!((3500.0..6500.0).contains(&estimate));
+
+        // Rewriting, removing and adding keys leaves the selection of the
+        // keys that are still there unchanged, and the view at its version.
+        for n in 0..5000 {
+            if n % 3 == 0 {
+                tree.insert(&key(n), n + 1, 0, 5000 + n).unwrap();
+            } else if n % 3 == 1 {
+                tree.remove(&key(n)).unwrap();
+            }
+            tree.insert(&key(n + 5000), n, 0, 10000 + n).unwrap();
+        }
+        let after = sampled_keys(&tree, 0.1, 7);
+        let kept: Vec<&Vec<u8>> = before
+            .iter()
+            .filter(|key| tree.get(&VariableSizeKey::from_slice(key), 0).is_ok())
+            .collect();
+        let after_old: Vec<&Vec<u8>> = after.iter().filter(|key| key[4] < b'5').collect();
+        assert_eq!(after_old, kept);
+        assert_eq!(view.iter().count(), before.len());
+        assert!(view
+            .iter()
+            .all(|(k, value, ..)| k == key(*value).as_slice()));
+
+        // A tree with the same keys, built in another order, selects the same
+        // keys, while another seed selects others.
+        let mut rebuilt: Tree<VariableSizeKey, u64> = Tree::new();
+        for (i, (key, value, ..)) in tree
+            .iter()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .enumerate()
+        {
+            rebuilt
+                .insert(&VariableSizeKey::from_slice(&key), *value, 0, i as u64)
+                .unwrap();
+        }
+        assert_eq!(sampled_keys(&rebuilt, 0.1, 7), after);
+        assert_ne!(sampled_keys(&tree, 0.1, 8), after);
+    }
+
+    #[test]
+    fn unsampled_keys_are_told_apart_from_missing_ones() {
+        let mut tree: Tree<VariableSizeKey, u64> = Tree::new();
+        for n in 0..1000 {
+            tree.insert(&key(n), n, 0, n).unwrap();
+        }
+        let view = tree.sample_view(0.5, 1);
+        let (inside, outside): (Vec<u64>, Vec<u64>) =
+            (0..2000).partition(|n| view.is_sampled(&key(*n)));
+
+        for n in inside {
+            match n < 1000 {
+                true => assert_eq!(view.get(&key(n)).unwrap().0, n),
+                false => assert_eq!(view.get(&key(n)), Err(TrieError::KeyNotFound)),
+            }
+        }
+        for n in outside {
+            assert_eq!(view.get(&key(n)), Err(TrieError::NotSampled));
+        }
+        assert_eq!(tree.sample_view(0.0, 1).count(), 0);
+        assert_eq!(tree.sample_view(0.0, 1).estimated_total(), 0.0);
+        assert_eq!(tree.sample_view(1.0, 1).estimated_total(), 1000.0);
+    }
+
+    #[test]
+    fn complementary_samples_partition_the_keys() {
+        let mut tree: Tree<VariableSizeKey, u64> = Tree::new();
+        for n in 0..3000 {
+            tree.insert(&key(n * 7), n, 0, n).unwrap();
+        }
+        for fraction in [0.0, 0.01, 0.3, 0.5, 0.99, 1.0] {
+            let view = tree.sample_view(fraction, 42);
+            let complement = view.complement();
+            assert_eq!(complement.fraction(), 1.0 - fraction);
+            assert_eq!(complement.complement().fraction(), fraction);
+
+            let sampled: Vec<Vec<u8>> = view.iter().map(|(key, ..)| key).collect();
+            let rest: Vec<Vec<u8>> = complement.iter().map(|(key, ..)| key).collect();
+            let union: BTreeSet<&Vec<u8>> = sampled.iter().chain(&rest).collect();
+            assert_eq!(sampled.len() + rest.len(), 3000);
+            assert_eq!(union.len(), 3000);
+            let all: Vec<Vec<u8>> = tree.iter().map(|(key, ..)| key).collect();
+            assert!(all.iter().all(|key| union.contains(key)));
+            assert!(all
+                .iter()
+                .all(|key| view.is_sampled(&VariableSizeKey::from_slice(key))
+                    != complement.is_sampled(&VariableSizeKey::from_slice(key))));
+        }
+    }
+}
@@ -1,47 +1,608 @@
 //! This module defines the Snapshot struct for managing snapshots within a Trie structure.
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ops::{Bound, RangeBounds};
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use hashbrown::HashSet;
+use hashbrown::{HashMap, HashSet};
 
-use crate::art::Node;
-use crate::iter::IterationPointer;
+use crate::art::{Node, NodeKind, NodeType, Tree};
+use crate::checksum::check_value;
+use crate::codec::{DecodeKey, DecodedIter, EncodeKey};
+use crate::cost;
+use crate::cursor::PageToken;
+use crate::diff::{diff_nodes, Change};
+use crate::domain::TsDomains;
+use crate::expiry::ExpiryTable;
+use crate::gate::ReaderGate;
+use crate::iter::{
+    ChangedSince, FilteredScan, GlobIter, Iter, IterationPointer, Keys, PatternByte, ProjectedScan,
+    Range, ScanDecision, ScanOptions,
+};
 use crate::node::Version;
-use crate::{KeyTrait, TrieError};
+use crate::normalize::{normalize_key, KeyNormalizer};
+use crate::popularity::ReadFrequency;
+use crate::pressure::{DuplicateTsPolicy, InsertStats, VersioningStrategy};
+use crate::tombstone::{value_as_of, Tombstones};
+use crate::{check_key_len, KeyTrait, TrieError};
+
+/// Keeps track of the snapshots created from a Tree.
+///
+/// The registry is shared between the Tree and its snapshots, so that snapshots
+/// derived from other snapshots get unique IDs and are accounted for as well.
+pub(crate) struct SnapshotRegistry {
+    next_id: AtomicU64,
+    // The maximum number of active snapshots and views.
+    max_active: AtomicU64,
+    active: Mutex<HashMap<u64, Registered>>,
+}
+
+// An active snapshot or view in the registry.
+struct Registered {
+    // The version it reads at.
+    version: u64,
+    // The current timestamp of the Tree when it was taken, or last refreshed.
+    ts: u64,
+    // The reader gate of a snapshot. Views have no readers to gate.
+    gate: Option<Arc<ReaderGate>>,
+}
+
+impl SnapshotRegistry {
+    pub(crate) fn new(max_active: u64) -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            max_active: AtomicU64::new(max_active),
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the maximum number of active snapshots and views.
+    pub(crate) fn max_active(&self) -> u64 {
+        self.max_active.load(Ordering::Relaxed)
+    }
+
+    /// Sets the maximum number of active snapshots and views. Those already
+    /// active are not closed.
+    pub(crate) fn set_max_active(&self, max_active: u64) {
+        self.max_active.store(max_active, Ordering::Relaxed);
+    }
+
+    /// Returns whether the maximum number of snapshots and views is active.
+    pub(crate) fn is_full(&self) -> bool {
+        self.len() >= self.max_active() as usize
+    }
+
+    /// Assigns an ID to a new view reading at `version`, taken when the Tree
+    /// was at timestamp `ts`.
+    pub(crate) fn register(&self, version: u64, ts: u64) -> u64 {
+        self.insert(version, ts, None)
+    }
+
+    /// Assigns an ID to a new snapshot reading at `version`, taken when the Tree
+    /// was at timestamp `ts`, whose readers are counted by `gate`.
+    pub(crate) fn register_snapshot(&self, version: u64, ts: u64, gate: Arc<ReaderGate>) -> u64 {
+        self.insert(version, ts, Some(gate))
+    }
+
+    fn insert(&self, version: u64, ts: u64, gate: Option<Arc<ReaderGate>>) -> u64 {
+        // Relaxed: IDs only have to be unique, which the RMW guarantees. The
+        // snapshot's version is published through the `active` mutex.
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.active
+            .lock()
+            .unwrap()
+            .insert(id, Registered { version, ts, gate });
+        id
+    }
+
+    /// Removes a snapshot, returning whether it was active.
+    pub(crate) fn deregister(&self, id: u64) -> bool {
+        self.active.lock().unwrap().remove(&id).is_some()
+    }
+
+    /// Returns the version an active snapshot reads at.
+    pub(crate) fn version_of(&self, id: u64) -> Option<u64> {
+        self.active
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|active| active.version)
+    }
+
+    /// Moves an active snapshot to read at `version`, with the Tree at timestamp
+    /// `ts`, returning whether it was active.
+    pub(crate) fn update(&self, id: u64, version: u64, ts: u64) -> bool {
+        match self.active.lock().unwrap().get_mut(&id) {
+            Some(active) => {
+                active.version = version;
+                active.ts = ts;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the number of active snapshots.
+    pub(crate) fn len(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+
+    /// Returns the oldest version read by an active snapshot.
+    pub(crate) fn min_version(&self) -> Option<u64> {
+        self.active
+            .lock()
+            .unwrap()
+            .values()
+            .map(|active| active.version)
+            .min()
+    }
+
+    /// Returns the oldest timestamp of the Tree an active snapshot was taken at.
+    pub(crate) fn min_ts(&self) -> Option<u64> {
+        self.active
+            .lock()
+            .unwrap()
+            .values()
+            .map(|active| active.ts)
+            .min()
+    }
+
+    /// Returns the reader gates of the active snapshots, ordered by ID.
+    pub(crate) fn gates(&self) -> Vec<(u64, Arc<ReaderGate>)> {
+        let mut gates: Vec<_> = self
+            .active
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(id, active)| Some((*id, active.gate.clone()?)))
+            .collect();
+        gates.sort_unstable_by_key(|(id, _)| *id);
+        gates
+    }
+}
+
+/// A summary of the writes made to a Tree that one of its snapshots cannot see.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StalenessSummary {
+    /// Number of keys in the Tree with a version newer than the snapshot.
+    pub stale_keys: usize,
+    /// The newest timestamp among those keys, or 0 if there are none.
+    pub max_lag_ts: u64,
+}
+
+/// The outcome of closing each snapshot of a Tree, by snapshot ID, as returned by
+/// `Tree::begin_close_all` and `Tree::finish_close_all`.
+pub type CloseOutcomes<T> = Vec<(u64, Result<T, TrieError>)>;
+
+/// The lifecycle state of a snapshot, as returned by `Snapshot::state` and
+/// `Tree::snapshot_states`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnapshotState {
+    /// The snapshot accepts new readers.
+    Open,
+    /// Closing has begun: new readers are turned away, and the snapshot closes
+    /// once the remaining readers are closed.
+    Closing,
+    /// The snapshot is closed.
+    Closed,
+}
+
+impl SnapshotState {
+    pub(crate) fn of(gate: &ReaderGate) -> Self {
+        if gate.is_closed() {
+            SnapshotState::Closed
+        } else if gate.is_closing() {
+            SnapshotState::Closing
+        } else {
+            SnapshotState::Open
+        }
+    }
+}
+
+/// The iterator returned by `Snapshot::range_guarded`, which counts as an
+/// active reader of the snapshot until it is dropped.
+pub struct GuardedRange<'a, P: KeyTrait, V: Clone> {
+    range: Range<'a, P, V, (Bound<P>, Bound<P>)>,
+    // Dropped after the range, once nothing reads the snapshot anymore.
+    _guard: ReaderGuard,
+}
+
+impl<'a, P: KeyTrait, V: Clone> Iterator for GuardedRange<'a, P, V> {
+    type Item = (Vec<u8>, &'a V, &'a u64, &'a u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.range.next()
+    }
+}
+
+// An active reader of a snapshot that is closed when dropped.
+struct ReaderGuard {
+    gate: Arc<ReaderGate>,
+}
+
+impl Drop for ReaderGuard {
+    fn drop(&mut self) {
+        self.gate.deregister();
+    }
+}
+
+/// A node visited by a lookup, as reported by `Snapshot::debug_path`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeDesc {
+    /// The type of the node, as returned by `Node::node_type_name`.
+    pub node_type: String,
+    /// The compressed prefix stored in the node. Below the root it starts with
+    /// the byte the parent took to reach the node.
+    pub prefix: Vec<u8>,
+    /// The offset into the key where `prefix` starts.
+    pub depth: usize,
+    /// The number of leading bytes of `prefix` that match the key.
+    pub matched_len: usize,
+    /// The key byte the lookup took to the next node, or `None` where it
+    /// stopped.
+    pub matched_byte: Option<u8>,
+}
+
+/// How finely `Snapshot::enable_read_tracking` records the keys read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadTrackGranularity {
+    /// Records every key read.
+    ExactKeys,
+    /// Records the first `depth` bytes of every key read, standing for all the
+    /// keys starting with them. The read set then holds at most one entry per
+    /// distinct prefix, but writes to keys that were not read can conflict with
+    /// it.
+    PrefixAt(usize),
+}
+
+/// The keys and ranges of keys read by a snapshot, as recorded once
+/// `Snapshot::enable_read_tracking` is called.
+///
+/// A key is covered by the read set if it was read, starts with a prefix read,
+/// or falls within a range scanned, so that keys inserted into a scanned range
+/// after the scan are covered as well. Every key, prefix and range is recorded
+/// with the number of keys the snapshot held under or within it, so that keys
+/// removed since can be detected. `Tree::writes_intersect` checks a read set
+/// against the writes made to a Tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReadSet {
+    granularity: ReadTrackGranularity,
+    // The keys or prefixes read, each with the number of keys under it when it
+    // was first read.
+    keys: BTreeMap<Vec<u8>, usize>,
+    // The ranges scanned, each with the number of keys within it.
+    ranges: Vec<(ReadRange, usize)>,
+}
+
+/// The bounds of a range of keys scanned.
+pub(crate) type ReadRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// Returns the number of keys below `root` within `range`.
+pub(crate) fn count_in<P: KeyTrait, V: Clone>(
+    root: Option<&Arc<Node<P, V>>>,
+    (start, end): &ReadRange,
+) -> usize {
+    let bound = |bound: &Bound<Vec<u8>>| bound.as_ref().map(|key| P::from(key.as_slice()));
+    Range::new(root, (bound(start), bound(end))).count()
+}
+
+// Returns the smallest key above every key starting with `prefix`, or `None` if
+// there is none.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|byte| *byte != u8::MAX)?;
+    let mut end = prefix[..=last].to_vec();
+    end[last] += 1;
+    Some(end)
+}
+
+impl ReadSet {
+    pub(crate) fn new(granularity: ReadTrackGranularity) -> Self {
+        ReadSet {
+            granularity,
+            keys: BTreeMap::new(),
+            ranges: Vec::new(),
+        }
+    }
+
+    /// Returns the granularity the keys are recorded at.
+    pub fn granularity(&self) -> ReadTrackGranularity {
+        self.granularity
+    }
+
+    /// Returns the number of keys, prefixes and ranges recorded.
+    pub fn len(&self) -> usize {
+        self.keys.len() + self.ranges.len()
+    }
+
+    /// Returns whether nothing has been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns whether a write to `key` would change what was read.
+    pub fn covers(&self, key: &[u8]) -> bool {
+        self.keys.contains_key(self.recorded(key))
+            || self.ranges.iter().any(|((start, end), _)| {
+                (
+                    start.as_ref().map(Vec::as_slice),
+                    end.as_ref().map(Vec::as_slice),
+                )
+                    .contains(key)
+            })
+    }
+
+    /// Records a read of `key`, with the number of keys under the key or the
+    /// prefix recorded for it given by `count`, unless it was read before.
+    pub(crate) fn record_key(&mut self, key: &[u8], count: impl FnOnce(&[u8]) -> usize) {
+        let recorded = self.recorded(key);
+        if !self.keys.contains_key(recorded) {
+            let keys = count(recorded);
+            self.keys.insert(recorded.to_vec(), keys);
+        }
+    }
+
+    /// Records a scan of the keys within a range, with the number of keys within
+    /// it given by `count`, unless it was scanned before.
+    pub(crate) fn record_range(
+        &mut self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        count: impl FnOnce(&ReadRange) -> usize,
+    ) {
+        let range = (start.map(<[u8]>::to_vec), end.map(<[u8]>::to_vec));
+        if !self.ranges.iter().any(|(scanned, _)| *scanned == range) {
+            let keys = count(&range);
+            self.ranges.push((range, keys));
+        }
+    }
+
+    /// Returns a key or prefix read, or the start of a range scanned, under or
+    /// within which fewer keys are left than were recorded, as counted by
+    /// `count_under` and `count_in`.
+    pub(crate) fn find_removal(
+        &self,
+        count_under: impl Fn(&[u8]) -> usize,
+        count_in: impl Fn(&ReadRange) -> usize,
+    ) -> Option<Vec<u8>> {
+        let removed = self
+            .keys
+            .iter()
+            .find(|(key, keys)| **keys > 0 && count_under(key) < **keys);
+        if let Some((key, _)) = removed {
+            return Some(key.clone());
+        }
+        self.ranges
+            .iter()
+            .find(|(range, keys)| *keys > 0 && count_in(range) < *keys)
+            .map(|((start, _), _)| match start {
+                Bound::Included(key) | Bound::Excluded(key) => key.clone(),
+                Bound::Unbounded => Vec::new(),
+            })
+    }
+
+    // The part of `key` recorded at the granularity of the read set.
+    fn recorded<'k>(&self, key: &'k [u8]) -> &'k [u8] {
+        match self.granularity {
+            ReadTrackGranularity::ExactKeys => key,
+            ReadTrackGranularity::PrefixAt(depth) => &key[..depth.min(key.len())],
+        }
+    }
+}
 
 /// Represents a snapshot of the data within the Trie.
+///
+/// Readers are counted by a gate that `close` has to pass: a snapshot only
+/// closes once every reader has been closed, and a reader registering while
+/// the snapshot closes is turned away rather than lost. All methods that change
+/// the reader bookkeeping take `&mut self`, but the gate is shared with the
+/// Tree's snapshot registry, so `Tree::begin_close_all` and
+/// `Tree::finish_close_all` race with them.
 pub struct Snapshot<P: KeyTrait, V: Clone> {
     pub(crate) id: u64,
     pub(crate) ts: u64,
     pub(crate) root: Option<Arc<Node<P, V>>>,
+    // The root of the Tree the snapshot was taken or last rebased at, before any
+    // of the snapshot's own writes.
+    pub(crate) base: Option<Arc<Node<P, V>>>,
     pub(crate) readers: HashSet<u64>,
-    pub(crate) max_active_readers: AtomicU64,
-    pub(crate) closed: bool,
+    // The ID of the last reader opened. IDs start at 1 and are never reused.
+    last_reader_id: u64,
+    pub(crate) gate: Arc<ReaderGate>,
+    pub(crate) registry: Arc<SnapshotRegistry>,
+    pub(crate) normalizer: Option<Arc<dyn KeyNormalizer>>,
+    // See `Tree::value_eq`.
+    pub(crate) value_eq: Option<fn(&V, &V) -> bool>,
+    // See `Tree::value_check`.
+    pub(crate) value_check: Option<fn(&V) -> bool>,
+    // See `Tree::forced_node_type`.
+    pub(crate) forced_node_type: Option<NodeKind>,
+    // The read counters of the Tree, if the snapshot counts its reads.
+    pub(crate) read_frequency: Option<Arc<ReadFrequency>>,
+    // The keys read, once read tracking is enabled.
+    pub(crate) read_set: Option<Mutex<ReadSet>>,
+    // The timestamp domains of the Tree when the snapshot was taken, moved by
+    // the snapshot's own writes.
+    pub(crate) ts_domains: Option<TsDomains>,
+    // The expiry rules of the Tree when the snapshot was taken.
+    pub(crate) expiry: ExpiryTable,
+    // The tombstones of the Tree when the snapshot was taken, along with those
+    // of the snapshot's own removals.
+    pub(crate) tombstones: Tombstones,
+    // The latest timestamp of the Tree when the snapshot was taken, moved by the
+    // snapshot's own writes, which its removals are made at.
+    pub(crate) current_ts: u64,
 }
 
 impl<P: KeyTrait, V: Clone> Snapshot<P, V> {
-    /// Creates a new Snapshot instance with the provided snapshot_id and root node.
-    pub(crate) fn new(id: u64, root: Option<Arc<Node<P, V>>>, ts: u64) -> Self {
+    /// Creates a new Snapshot instance with the provided root node, and registers
+    /// it as reading at `version`, taken when the Tree was at `current_ts`.
+    pub(crate) fn new(
+        root: Option<Arc<Node<P, V>>>,
+        ts: u64,
+        version: u64,
+        current_ts: u64,
+        registry: Arc<SnapshotRegistry>,
+    ) -> Self {
+        let gate = Arc::new(ReaderGate::new());
         Snapshot {
-            id,
+            id: registry.register_snapshot(version, current_ts, gate.clone()),
             ts,
+            base: root.clone(),
             root,
             readers: HashSet::new(),
-            max_active_readers: AtomicU64::new(0),
-            closed: false,
+            last_reader_id: 0,
+            gate,
+            registry,
+            normalizer: None,
+            value_eq: None,
+            value_check: None,
+            forced_node_type: None,
+            read_frequency: None,
+            read_set: None,
+            ts_domains: None,
+            expiry: ExpiryTable::new(),
+            tombstones: Tombstones::new(),
+            current_ts,
+        }
+    }
+
+    /// Returns the ID of the snapshot.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Creates an independent copy of the snapshot.
+    ///
+    /// The copy gets a new ID and is registered with the Tree like any other
+    /// snapshot, at the version this snapshot reads at, and shares its root and
+    /// timestamp. It starts without readers and keeps its own reader
+    /// bookkeeping, so two consumers can open and close readers on the same
+    /// state without affecting each other. Writes made to either snapshot
+    /// afterwards are not visible to the other.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::SnapshotAlreadyClosed` if the snapshot is closed, or
+    /// an error if the Tree has the maximum number of active snapshots.
+    ///
+    pub fn clone_independent(&self) -> Result<Snapshot<P, V>, TrieError> {
+        self.is_closed()?;
+        let version = self
+            .registry
+            .version_of(self.id)
+            .ok_or(TrieError::SnapshotAlreadyClosed)?;
+        if self.registry.is_full() {
+            return Err(TrieError::Other(
+                "max number of snapshots reached".to_string(),
+            ));
+        }
+
+        let mut snapshot = Snapshot::new(
+            self.root.clone(),
+            self.ts,
+            version,
+            self.current_ts,
+            self.registry.clone(),
+        );
+        snapshot.base = self.base.clone();
+        snapshot.normalizer = self.normalizer.clone();
+        snapshot.value_eq = self.value_eq;
+        snapshot.value_check = self.value_check;
+        snapshot.forced_node_type = self.forced_node_type;
+        snapshot.read_frequency = self.read_frequency.clone();
+        snapshot.ts_domains = self.ts_domains.clone();
+        snapshot.expiry = self.expiry.clone();
+        snapshot.tombstones = self.tombstones.clone();
+        Ok(snapshot)
+    }
+
+    /// Converts the snapshot into an `OwnedSnapshot` that is independent of the Tree.
+    ///
+    /// The snapshot is removed from the Tree's snapshot registry, so it no longer
+    /// counts towards the active snapshots. Any readers opened on it keep their
+    /// own references to the nodes and are unaffected.
+    ///
+    pub fn detach_owned(self) -> OwnedSnapshot<P, V> {
+        self.registry.deregister(self.id);
+        OwnedSnapshot::from_entries(Iter::new(self.root.as_ref()), self.version())
+    }
+
+    /// Converts the snapshot into a mutable Tree rooted at the snapshot's root.
+    ///
+    /// The snapshot is removed from the snapshot registry of the Tree it was taken
+    /// from. The new Tree starts out with its own registry, locks and pins, and
+    /// carries on from the snapshot's version, so the snapshot's writes are
+    /// visible in it and later inserts get newer versions.
+    ///
+    pub fn into_tree(self) -> Tree<P, V> {
+        self.registry.deregister(self.id);
+        Tree {
+            root: self.root,
+            normalizer: self.normalizer,
+            value_eq: self.value_eq,
+            value_check: self.value_check,
+            forced_node_type: self.forced_node_type,
+            ts_domains: self.ts_domains,
+            tombstones: self.tombstones,
+            current_ts: self.current_ts,
+            ..Tree::new()
         }
     }
 
+    /// Returns the latest timestamp written to the keys of the domain
+    /// `segment`, as the Tree had it when the snapshot was taken, moved forward
+    /// by the writes to the snapshot since.
+    ///
+    /// The snapshot holds every version of the domain up to this timestamp, so
+    /// reads of its keys with `iter_as_of` at or below it stay the same however
+    /// the Tree and its other domains move on. Returns `None` as
+    /// `Tree::ts_of_domain` does.
+    pub fn ts_of_domain(&self, segment: &[u8]) -> Option<u64> {
+        self.ts_domains.as_ref()?.get(segment)
+    }
+
     /// Inserts a key-value pair into the snapshot.
+    ///
+    /// If the snapshot was taken from a Tree built with
+    /// `dedup_identical_versions`, a value equal to the latest value of its key
+    /// is not written.
     pub fn insert(&mut self, key: &P, value: V, ts: u64) -> Result<(), TrieError> {
         // Check if the snapshot is already closed
         self.is_closed()?;
 
+        let key = normalize_key(self.normalizer.as_ref(), key);
+        let key = key.as_ref();
+        check_key_len(key)?;
+
+        if let Some(value_eq) = self.value_eq {
+            if let Ok((_, latest, _, _)) = Node::resolve_get(self.root.as_ref(), key, 0) {
+                if value_eq(&latest, &value) {
+                    return Ok(());
+                }
+            }
+        }
+
         // Insert the key-value pair into the root node using a recursive function
         match &self.root {
             Some(root) => {
-                let (new_node, _) = match Node::insert_recurse(root, key, value, self.ts, ts, 0) {
+                let (new_node, _) = match Node::insert_recurse(
+                    root,
+                    key,
+                    value,
+                    self.ts,
+                    ts,
+                    0,
+                    // Every write to a snapshot shares its version, so writes
+                    // always stack and are all kept, whatever the policy of the
+                    // Tree, until they are committed.
+                    DuplicateTsPolicy::Stack,
+                    VersioningStrategy::KeepAll,
+                    self.forced_node_type,
+                    &mut InsertStats::default(),
+                ) {
                     Ok((new_node, old_node)) => (new_node, old_node),
                     Err(err) => {
                         return Err(err);
@@ -61,220 +622,2408 @@ impl<P: KeyTrait, V: Clone> Snapshot<P, V> {
                 )))
             }
         };
+        if let Some(domains) = self.ts_domains.as_mut() {
+            domains.advance(key.as_slice(), ts);
+        }
+        self.current_ts = self.current_ts.max(ts);
 
         Ok(())
     }
 
+    /// Moves the snapshot onto the current root of `tree`, re-applying the
+    /// snapshot's own writes on top of it.
+    ///
+    /// The writes re-applied are the net changes between the Tree root the
+    /// snapshot was taken or last rebased at and the snapshot's current root: the
+    /// latest value of every key inserted through the snapshot, and every key
+    /// removed through it. They are written at the version following the Tree's,
+    /// which the snapshot reads at from then on. Keys that were also changed in
+    /// the Tree since then are conflicts; the snapshot's change wins, as a rebase
+    /// replays local work on top of upstream.
+    ///
+    /// # Returns
+    ///
+    /// Returns the conflicting keys, in key order.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::SnapshotAlreadyClosed` if the snapshot is closed,
+    /// `TrieError::TreeAlreadyClosed` if `tree` is, and
+    /// `TrieError::SnapshotNotFound` if the snapshot was not taken from `tree`.
+    ///
+    pub fn rebase(&mut self, tree: &Tree<P, V>) -> Result<Vec<P>, TrieError> {
+        self.is_closed()?;
+        if tree.closed {
+            return Err(TrieError::TreeAlreadyClosed);
+        }
+        if !Arc::ptr_eq(&self.registry, &tree.snapshots) {
+            return Err(TrieError::SnapshotNotFound);
+        }
+
+        let mut local = Vec::new();
+        diff_nodes(self.base.as_ref(), self.root.as_ref(), &mut local);
+        let mut upstream = Vec::new();
+        diff_nodes(self.base.as_ref(), tree.root.as_ref(), &mut upstream);
+
+        // Both lists are in key order, so conflicts are found by merging them.
+        let mut conflicts = Vec::new();
+        let mut upstream_keys = upstream.iter().map(change_key).peekable();
+        for change in &local {
+            let key = change_key(change);
+            while upstream_keys.next_if(|k| *k < key).is_some() {}
+            if upstream_keys.next_if(|k| *k == key).is_some() {
+                conflicts.push(key.clone());
+            }
+        }
+
+        let version = tree.version() + 1;
+        let mut root = tree.root.clone();
+        for change in local {
+            root =
+                match (change, &root) {
+                    (Change::Insert { key, value, ts, .. }, None) => Some(Arc::new(
+                        Node::new_twig(key.clone(), key, value, version, ts),
+                    )),
+                    (Change::Insert { key, value, ts, .. }, Some(node)) => Some(
+                        Node::insert_recurse(
+                            node,
+                            &key,
+                            value,
+                            version,
+                            ts,
+                            0,
+                            DuplicateTsPolicy::Stack,
+                            tree.versioning,
+                            self.forced_node_type,
+                            &mut InsertStats::default(),
+                        )?
+                        .0,
+                    ),
+                    (Change::Remove { .. }, None) => None,
+                    (Change::Remove { key }, Some(node)) => match &node.node_type {
+                        NodeType::Twig(twig) if twig.key == key => None,
+                        NodeType::Twig(_) => root,
+                        _ => match Node::remove_recurse(node, &key, 0, self.forced_node_type) {
+                            (new_root, true) => new_root,
+                            (_, false) => root,
+                        },
+                    },
+                };
+        }
+
+        self.registry.update(self.id, version - 1, tree.current_ts);
+        self.base = tree.root.clone();
+        self.root = root;
+        self.ts = version;
+        Ok(conflicts)
+    }
+
     /// Retrieves the value and timestamp associated with the given key from the snapshot.
+    ///
+    /// Fails with `TrieError::KeyNotFound` and `TrieError::ChecksumMismatch`
+    /// under the same conditions as `Tree::get`, and with
+    /// `TrieError::SnapshotAlreadyClosed` once the snapshot is closed.
     pub fn get(&self, key: &P) -> Result<(V, u64, u64), TrieError> {
         // Check if the snapshot is already closed
         self.is_closed()?;
 
-        // Use a recursive function to get the value and timestamp from the root node
-        match self.root.as_ref() {
-            Some(root) => Node::get_recurse(root, key, root.version())
-                .map(|(_, value, version, ts)| (value, version, ts)),
-            None => Err(TrieError::KeyNotFound),
+        let key = normalize_key(self.normalizer.as_ref(), key);
+        let key = key.as_ref();
+
+        let found = Node::resolve_get(self.root.as_ref(), key, 0);
+        if let (Ok(_), Some(freq)) = (&found, &self.read_frequency) {
+            freq.record(key.as_slice());
         }
+        self.record_read(key);
+        found
+            .and_then(|entry| check_value(self.value_check, entry))
+            .map(|(_, value, version, ts)| (value, version, ts))
     }
 
-    /// Returns the version of the snapshot.
-    pub fn version(&self) -> u64 {
-        self.root.as_ref().map_or(0, |root| root.version())
+    /// Returns whether the snapshot holds a value for the given key.
+    ///
+    /// Fails with `TrieError::SnapshotAlreadyClosed` once the snapshot is closed.
+    pub fn contains(&self, key: &P) -> Result<bool, TrieError> {
+        // Check if the snapshot is already closed
+        self.is_closed()?;
+
+        let key = normalize_key(self.normalizer.as_ref(), key);
+        let key = key.as_ref();
+
+        let found = Node::resolve_get(self.root.as_ref(), key, 0).is_ok();
+        self.record_read(key);
+        Ok(found)
     }
 
-    fn is_closed(&self) -> Result<(), TrieError> {
-        if self.closed {
-            return Err(TrieError::SnapshotAlreadyClosed);
+    /// Returns every version of the given key written at a timestamp within
+    /// `from_ts..=to_ts`, newest first, each with its timestamp.
+    ///
+    /// Versions are ordered by timestamp, and versions sharing a timestamp by
+    /// the version they were written at. The result is empty if the key is
+    /// absent, no version falls within the window, or the snapshot is closed.
+    pub fn get_versions_between(&self, key: &P, from_ts: u64, to_ts: u64) -> Vec<(V, u64)> {
+        if self.is_closed().is_err() {
+            return Vec::new();
         }
-        Ok(())
+        let key = normalize_key(self.normalizer.as_ref(), key);
+        let key = key.as_ref();
+
+        let twig = self
+            .root
+            .as_ref()
+            .and_then(|root| Node::find_twig(root, key));
+        self.record_read(key);
+        let Some(NodeType::Twig(twig)) = twig.map(|twig| &twig.node_type) else {
+            return Vec::new();
+        };
+        let mut versions: Vec<(V, u64)> = twig
+            .iter()
+            .rev()
+            .filter(|leaf| (from_ts..=to_ts).contains(&leaf.ts))
+            .map(|leaf| (leaf.value.clone(), leaf.ts))
+            .collect();
+        // The sort is stable, so versions sharing a timestamp stay newest first.
+        versions.sort_by_key(|(_, ts)| std::cmp::Reverse(*ts));
+        versions
     }
 
-    /// Closes the snapshot, preventing further modifications, and releases associated resources.
-    pub fn close(&mut self) -> Result<(), TrieError> {
-        // Check if the snapshot is already closed
-        self.is_closed()?;
+    /// Starts recording the keys read by the snapshot, at the given granularity,
+    /// into a new read set.
+    ///
+    /// Once enabled, every read records what it reads, and `read_set` returns
+    /// the reads recorded so far. Lookups record their key, and scans, counts
+    /// and readers record the whole range of keys they can reach, so keys
+    /// inserted into it later conflict with it even though they were never read.
+    /// `range_guarded` also records the keys it yields. Enabling tracking again
+    /// discards the reads recorded before.
+    pub fn enable_read_tracking(&mut self, granularity: ReadTrackGranularity) {
+        self.read_set = Some(Mutex::new(ReadSet::new(granularity)));
+    }
+
+    /// Returns the reads recorded since `enable_read_tracking`, or `None` if read
+    /// tracking is not enabled.
+    pub fn read_set(&self) -> Option<ReadSet> {
+        let reads = self.read_set.as_ref()?;
+        Some(reads.lock().unwrap().clone())
+    }
 
-        // Check if there are any active readers for the snapshot
-        if self.max_active_readers.load(Ordering::SeqCst) > 0 {
-            return Err(TrieError::SnapshotReadersNotClosed);
+    fn record_read(&self, key: &P) {
+        if let Some(reads) = &self.read_set {
+            reads.lock().unwrap().record_key(key.as_slice(), |prefix| {
+                Node::count_under(self.root.as_ref(), prefix)
+            });
         }
-        // Mark the snapshot as closed
-        self.closed = true;
+    }
 
-        Ok(())
+    // Records a scan of the keys within `start..end`.
+    fn record_scan(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) {
+        if let Some(reads) = &self.read_set {
+            reads
+                .lock()
+                .unwrap()
+                .record_range(start, end, |range| count_in(self.root.as_ref(), range));
+        }
     }
 
-    pub fn new_reader(&mut self) -> Result<IterationPointer<P, V>, TrieError> {
+    // Records a scan of the keys starting with `prefix`.
+    fn record_prefix_scan(&self, prefix: &[u8]) {
+        let end = prefix_end(prefix);
+        let end = end.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+        self.record_scan(Bound::Included(prefix), end);
+    }
+
+    /// Returns an iterator over the key-value pairs in the snapshot that `filter`
+    /// accepts, letting the filter skip whole subtrees.
+    ///
+    /// See `Tree::scan_with_filter` for how the filter is applied.
+    pub fn scan_filtered<F: Fn(&P) -> ScanDecision>(
+        &self,
+        filter: F,
+    ) -> Result<FilteredScan<'_, P, V, F>, TrieError> {
         // Check if the snapshot is already closed
         self.is_closed()?;
 
-        if self.root.is_none() {
-            return Err(TrieError::SnapshotEmpty);
-        }
-
-        let reader_id = self.max_active_readers.fetch_add(1, Ordering::SeqCst) + 1;
-        self.readers.insert(reader_id);
-        Ok(IterationPointer::new(
-            self.root.as_ref().unwrap().clone(),
-            reader_id,
-        ))
+        self.record_scan(Bound::Unbounded, Bound::Unbounded);
+        Ok(FilteredScan::new(self.root.as_ref(), filter, None))
     }
 
-    pub fn active_readers(&self) -> Result<u64, TrieError> {
+    /// Returns an iterator over the keys in the snapshot accepted by the
+    /// options, each with its projected latest value.
+    ///
+    /// See `Tree::scan` for how the options are applied.
+    pub fn scan<'f, O, B: ?Sized>(
+        &self,
+        options: ScanOptions<'f, P, V, O, B>,
+    ) -> Result<ProjectedScan<'_, 'f, P, V, O, B>, TrieError> {
         // Check if the snapshot is already closed
         self.is_closed()?;
 
-        Ok(self.max_active_readers.load(Ordering::SeqCst))
+        self.record_scan(Bound::Unbounded, Bound::Unbounded);
+        Ok(ProjectedScan::new(self.root.as_ref(), options, None))
     }
 
-    pub fn close_reader(&mut self, reader_id: u64) -> Result<(), TrieError> {
+    /// Returns an iterator over the keys of the snapshot matching `pattern`, with
+    /// their latest values, in key order.
+    ///
+    /// A key matches if its bytes, without a trailing NULL terminator, are matched
+    /// by the pattern from start to end, `PatternByte::parse_glob` building the
+    /// pattern of a glob such as `a?c*`. Subtrees are skipped as soon as the bytes
+    /// on the path to them cannot start a match, so patterns starting with
+    /// literals only walk the matching branches.
+    pub fn glob_iter(&self, pattern: &[PatternByte]) -> Result<GlobIter<'_, P, V>, TrieError> {
         // Check if the snapshot is already closed
         self.is_closed()?;
 
-        self.readers.remove(&reader_id);
-        let _readers = self.max_active_readers.fetch_sub(1, Ordering::SeqCst);
-        Ok(())
+        self.record_scan(Bound::Unbounded, Bound::Unbounded);
+        Ok(GlobIter::new(self.root.as_ref(), pattern))
     }
 
-    pub fn remove(&mut self, key: &P) -> Result<bool, TrieError> {
-        // Check if the tree is already closed
+    /// Returns an iterator over the entries of the snapshot with their keys
+    /// decoded as the composite key type `K`.
+    ///
+    /// See `Tree::iter_decoded`.
+    pub fn iter_decoded<K: DecodeKey>(&self) -> Result<DecodedIter<'_, K, V>, TrieError> {
+        // Check if the snapshot is already closed
         self.is_closed()?;
 
-        let (new_root, is_deleted) = match &self.root {
-            None => (None, false),
-            Some(root) => {
-                if root.is_twig() {
-                    (None, true)
-                } else {
-                    let (new_root, removed) = Node::remove_recurse(root, key, 0);
-                    if removed {
-                        (new_root, true)
-                    } else {
-                        (self.root.clone(), true)
-                    }
-                }
-            }
-        };
-
-        self.root = new_root;
-        Ok(is_deleted)
+        self.record_scan(Bound::Unbounded, Bound::Unbounded);
+        Ok(DecodedIter::new(Iter::new(self.root.as_ref())))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::art::Tree;
-    use crate::iter::IterationPointer;
-    use crate::VariableSizeKey;
-    use std::str::FromStr;
+    /// Returns an iterator over the decoded entries of the snapshot whose keys
+    /// start with the leading components `prefix`.
+    ///
+    /// See `Tree::scan_prefix_decoded`.
+    pub fn scan_prefix_decoded<K: DecodeKey>(
+        &self,
+        prefix: &impl EncodeKey,
+    ) -> Result<DecodedIter<'_, K, V>, TrieError> {
+        // Check if the snapshot is already closed
+        self.is_closed()?;
 
-    #[test]
-    fn snapshot_creation() {
-        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
-        let keys = ["key_1", "key_2", "key_3"];
+        let mut bytes = Vec::new();
+        prefix.encode_prefix(&mut bytes);
+        self.record_prefix_scan(&bytes);
+        Ok(DecodedIter::with_prefix(self.root.as_ref(), prefix, None))
+    }
 
-        for key in keys.iter() {
-            assert!(tree
-                .insert(&VariableSizeKey::from_str(key).unwrap(), 1, 0, 0)
-                .is_ok());
-        }
+    /// Returns an iterator over the keys in the snapshot, in key order.
+    ///
+    /// The keys are cloned from the nodes of the snapshot, without opening a
+    /// reader or cloning values.
+    pub fn keys(&self) -> Result<Keys<'_, P, V>, TrieError> {
+        // Check if the snapshot is already closed
+        self.is_closed()?;
 
-        let mut snap1 = tree.create_snapshot().unwrap();
-        let key_to_insert = "key_1";
-        assert!(snap1
-            .insert(&VariableSizeKey::from_str(key_to_insert).unwrap(), 1, 0)
-            .is_ok());
+        self.record_scan(Bound::Unbounded, Bound::Unbounded);
+        Ok(Keys::new(self.root.as_ref()))
+    }
 
-        let expected_snap_ts = keys.len() as u64 + 1;
-        assert_eq!(snap1.version(), expected_snap_ts);
-        assert_eq!(tree.snapshot_count(), 1);
+    /// Returns an iterator over the keys of the snapshot as of the timestamp `ts`,
+    /// each with its latest value written at or before `ts`, in key order.
+    ///
+    /// Keys whose versions were all written after `ts` are skipped, and so are
+    /// keys under a prefix that the expiry rules of the Tree, as they were when
+    /// the snapshot was taken, had expired by `ts`. Removing a key leaves a
+    /// tombstone at the latest timestamp written before the removal, so a key
+    /// whose newest change at or before `ts` is a removal is skipped too.
+    /// The removal frees the versions of the key, so a key removed before the
+    /// snapshot was taken is only yielded for the versions written since, and
+    /// versions pruned from the snapshot are not reconstructed either.
+    pub fn iter_as_of(&self, ts: u64) -> Result<impl Iterator<Item = (P, V)> + '_, TrieError> {
+        // Check if the snapshot is already closed
+        self.is_closed()?;
 
-        let expected_tree_ts = keys.len() as u64;
-        assert_eq!(tree.version(), expected_tree_ts);
+        self.record_scan(Bound::Unbounded, Bound::Unbounded);
+        let removals = self.tombstones.removed_by(ts);
+        Ok(
+            ChangedSince::all(self.root.as_ref()).filter_map(move |twig| {
+                let key = twig.key.as_slice();
+                if self.expiry.is_expired(key, ts) {
+                    return None;
+                }
+                let removal = removals.get(key).copied();
+                let value = value_as_of(twig, removal, ts)?;
+                Some((twig.key.clone(), value.clone()))
+            }),
+        )
     }
 
-    #[test]
-    fn snapshot_isolation() {
-        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
-        let key_1 = VariableSizeKey::from_str("key_1").unwrap();
-        let key_2 = VariableSizeKey::from_str("key_2").unwrap();
-        let key_3_snap1 = VariableSizeKey::from_str("key_3_snap1").unwrap();
-        let key_3_snap2 = VariableSizeKey::from_str("key_3_snap2").unwrap();
-
-        assert!(tree.insert(&key_1, 1, 0, 0).is_ok());
+    /// Returns the nodes a lookup of `key` visits in the snapshot, from the root
+    /// down to the twig holding the key or to the node where the lookup fails.
+    ///
+    /// This is meant for understanding the shape of the trie around a key. A
+    /// lookup fails at the last node when its prefix does not match the key, or
+    /// when it has no child for the next byte of the key. The path is empty if
+    /// the snapshot is empty or closed.
+    pub fn debug_path(&self, key: &P) -> Vec<NodeDesc> {
+        let (Ok(()), Some(root)) = (self.is_closed(), &self.root) else {
+            return Vec::new();
+        };
+        let key = normalize_key(self.normalizer.as_ref(), key);
+        let key = key.as_ref();
+        let mut path = Vec::new();
+        Node::search_path(root, key, &mut path);
 
-        // Keys inserted before snapshot creation should be visible
-        let mut snap1 = tree.create_snapshot().unwrap();
-        assert_eq!(snap1.id, 0);
-        assert_eq!(snap1.get(&key_1).unwrap(), (1, 1, 0));
+        let last = path.len() - 1;
+        path.iter()
+            .enumerate()
+            .map(|(i, (node, depth))| {
+                let prefix = node.prefix().as_slice();
+                NodeDesc {
+                    node_type: node.node_type_name(),
+                    prefix: prefix.to_vec(),
+                    depth: *depth,
+                    matched_len: node
+                        .prefix()
+                        .longest_common_prefix(key.prefix_after(*depth).as_slice()),
+                    matched_byte: (i != last).then(|| key.at(depth + prefix.len())),
+                }
+            })
+            .collect()
+    }
 
-        let mut snap2 = tree.create_snapshot().unwrap();
-        assert_eq!(snap2.id, 1);
-        assert_eq!(snap2.get(&key_1).unwrap(), (1, 1, 0));
+    /// Returns the number of keys in the snapshot.
+    ///
+    /// The keys are counted by walking the nodes of the snapshot, without opening a
+    /// reader or cloning keys and values.
+    pub fn count(&self) -> Result<usize, TrieError> {
+        // Check if the snapshot is already closed
+        self.is_closed()?;
 
-        assert_eq!(tree.snapshot_count(), 2);
+        self.record_scan(Bound::Unbounded, Bound::Unbounded);
+        Ok(self.root.as_ref().map_or(0, |root| root.count_twigs()))
+    }
 
-        // Keys inserted after snapshot creation should not be visible to other snapshots
-        assert!(tree.insert(&key_2, 1, 0, 0).is_ok());
-        assert!(snap1.get(&key_2).is_err());
-        assert!(snap2.get(&key_2).is_err());
+    /// Returns the heap held by the nodes and values of the snapshot that `base`
+    /// does not share, in bytes.
+    ///
+    /// This is the memory that keeping the snapshot adds to keeping `base`, such
+    /// as the copies of the nodes on the paths of the keys written since `base`
+    /// was taken. Subtrees shared with `base` are skipped without being visited.
+    /// Node sizes are those used by `Tree::estimate_insert_cost`, and heap memory
+    /// owned by the values themselves is not included.
+    pub fn unique_memory(&self, base: &Snapshot<P, V>) -> usize {
+        self.root
+            .as_ref()
+            .map_or(0, |root| cost::unique_bytes(root, base.root.as_ref()))
+    }
 
-        // Keys inserted after snapshot creation should be visible to the snapshot that inserted them
-        assert!(snap1.insert(&key_3_snap1, 2, 0).is_ok());
-        assert_eq!(snap1.get(&key_3_snap1).unwrap(), (2, 2, 0));
+    /// Returns the sum of the latest values of the keys starting with `prefix`.
+    ///
+    /// The bytes of `prefix` are matched as they are, so a `VariableSizeKey`
+    /// prefix should be built without the terminating NULL byte. The subtree under
+    /// the prefix is walked in place, without opening a reader or copying keys;
+    /// only the values being summed are cloned. An empty match sums to the empty
+    /// sum of `V`.
+    pub fn sum_prefix(&self, prefix: &P) -> Result<V, TrieError>
+    where
+        V: std::iter::Sum,
+    {
+        // Check if the snapshot is already closed
+        self.is_closed()?;
 
-        assert!(snap2.insert(&key_3_snap2, 3, 0).is_ok());
-        assert_eq!(snap2.get(&key_3_snap2).unwrap(), (3, 2, 0));
+        let prefix = normalize_key(self.normalizer.as_ref(), prefix);
+        self.record_prefix_scan(prefix.as_slice());
+        let subtree = self
+            .root
+            .as_ref()
+            .and_then(|root| Node::find_prefix_subtree(root, prefix.as_slice()));
+        Ok(subtree
+            .into_iter()
+            .flat_map(|node| node.latest_values())
+            .cloned()
+            .sum())
+    }
 
-        // Keys inserted after snapshot creation should not be visible to other snapshots
-        assert!(snap1.get(&key_3_snap2).is_err());
-        assert!(snap2.get(&key_3_snap1).is_err());
+    /// Returns the number of distinct bytes that follow `prefix` in the keys of
+    /// the snapshot starting with it, such as the number of completions to offer
+    /// after a typed prefix.
+    ///
+    /// The bytes of `prefix` are matched as they are, so a `VariableSizeKey`
+    /// prefix should be built without the terminating NULL byte; a key equal to
+    /// `prefix` then counts its NULL byte as a continuation. Only the path to the
+    /// prefix is walked.
+    pub fn distinct_next_bytes(&self, prefix: &P) -> Result<usize, TrieError> {
+        // Check if the snapshot is already closed
+        self.is_closed()?;
 
-        assert!(snap1.close().is_ok());
-        assert!(snap2.close().is_ok());
+        let prefix = normalize_key(self.normalizer.as_ref(), prefix);
+        self.record_prefix_scan(prefix.as_slice());
+        Ok(self
+            .root
+            .as_ref()
+            .map_or(0, |root| Node::distinct_next_bytes(root, prefix.as_slice())))
+    }
 
-        assert!(tree.close_snapshot(snap1.id).is_ok());
-        assert!(tree.close_snapshot(snap2.id).is_ok());
+    /// Returns the keys of the snapshot within Levenshtein distance `max_dist` of
+    /// `key`, with their latest values, in key order.
+    ///
+    /// Distances count the byte insertions, deletions and substitutions between
+    /// whole keys, so the terminating NULL bytes of two `VariableSizeKey`s built
+    /// with `from_str` match each other. The trie is walked once, computing a row
+    /// of the edit distance table per byte on the path, and subtrees whose rows
+    /// are all above `max_dist` are skipped.
+    pub fn find_within_distance(&self, key: &P, max_dist: usize) -> Result<Vec<(P, V)>, TrieError> {
+        // Check if the snapshot is already closed
+        self.is_closed()?;
 
-        assert_eq!(tree.snapshot_count(), 0);
+        let key = normalize_key(self.normalizer.as_ref(), key);
+        let query = key.as_slice();
+        self.record_scan(Bound::Unbounded, Bound::Unbounded);
+        let row: Vec<usize> = (0..=query.len()).collect();
+        let mut found = Vec::new();
+        if let Some(root) = &self.root {
+            root.collect_within_distance(query, max_dist, &row, &mut found);
+        }
+        Ok(found)
     }
 
-    #[test]
+    /// Returns the number of keys in the snapshot under each distinct
+    /// `prefix_len`-byte prefix.
+    ///
+    /// See `Tree::entry_count_by_prefix`.
+    pub fn group_counts(
+        &self,
+        prefix_len: usize,
+    ) -> Result<std::collections::HashMap<Vec<u8>, usize>, TrieError> {
+        // Check if the snapshot is already closed
+        self.is_closed()?;
+
+        self.record_scan(Bound::Unbounded, Bound::Unbounded);
+        let mut counts = std::collections::HashMap::new();
+        if let Some(root) = &self.root {
+            root.group_counts(prefix_len, false, &mut Vec::new(), &mut counts);
+        }
+        Ok(counts)
+    }
+
+    /// Returns the version of the snapshot.
+    pub fn version(&self) -> u64 {
+        self.root.as_ref().map_or(0, |root| root.version())
+    }
+
+    fn is_closed(&self) -> Result<(), TrieError> {
+        if self.gate.is_closed() {
+            return Err(TrieError::SnapshotAlreadyClosed);
+        }
+        Ok(())
+    }
+
+    /// Closes the snapshot, preventing further modifications, and releases associated resources.
+    ///
+    /// The closed flag is set with a single compare-and-swap that only succeeds
+    /// while no reader is registered, so a close racing with readers being opened
+    /// or closed either sees them all gone or fails with
+    /// `TrieError::SnapshotReadersNotClosed` and leaves the snapshot open.
+    pub fn close(&mut self) -> Result<(), TrieError> {
+        // Mark the snapshot as closed unless it already is, or has active readers
+        self.gate.close()
+    }
+
+    /// Begins closing the snapshot, and returns the number of readers still open.
+    ///
+    /// From then on `new_reader` fails with `TrieError::SnapshotClosing`, while
+    /// the readers already open keep working until they are closed. A reader
+    /// opened concurrently is either counted in the returned number or turned
+    /// away. Call `finish_close` once the readers have drained.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::SnapshotAlreadyClosed` if the snapshot is closed.
+    pub fn begin_close(&mut self) -> Result<u64, TrieError> {
+        self.gate.begin_close()
+    }
+
+    /// Finishes closing the snapshot once its readers have drained, or right away
+    /// if none were open.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::SnapshotReadersNotClosed` while readers are still open,
+    /// leaving the snapshot closing, or `TrieError::SnapshotAlreadyClosed` if it is
+    /// closed.
+    pub fn finish_close(&mut self) -> Result<(), TrieError> {
+        self.gate.close()
+    }
+
+    /// Returns whether the snapshot is open, closing or closed.
+    pub fn state(&self) -> SnapshotState {
+        SnapshotState::of(&self.gate)
+    }
+
+    pub fn new_reader(&mut self) -> Result<IterationPointer<P, V>, TrieError> {
+        // Check if the snapshot is already closed
+        self.is_closed()?;
+
+        if self.root.is_none() {
+            return Err(TrieError::SnapshotEmpty);
+        }
+
+        let reader_id = self.register_reader()?;
+        self.record_scan(Bound::Unbounded, Bound::Unbounded);
+        let mut reader = IterationPointer::new(self.root.as_ref().unwrap().clone(), reader_id);
+        reader.normalizer = self.normalizer.clone();
+        reader.read_frequency = self.read_frequency.clone();
+        Ok(reader)
+    }
+
+    /// Opens a reader over the keys of the snapshot starting with `prefix`.
+    ///
+    /// The reader only holds the subtree of those keys, and reads like a reader
+    /// of a snapshot holding nothing else: its iterators yield the keys under the
+    /// prefix, in full, and its lookups of other keys find nothing. `prefix` is
+    /// matched against the stored keys, after normalization. The reader is
+    /// accounted for like one opened with `new_reader`, and is closed the same way
+    /// with `close_reader`, independently of the other readers.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::NotFound` if no key starts with `prefix`, and
+    /// otherwise fails like `new_reader`.
+    pub fn new_prefix_reader(
+        &mut self,
+        prefix: &[u8],
+    ) -> Result<IterationPointer<P, V>, TrieError> {
+        // Check if the snapshot is already closed
+        self.is_closed()?;
+
+        let root = self.root.as_ref().ok_or(TrieError::SnapshotEmpty)?;
+        let root = Node::prefix_root(root, prefix).ok_or(TrieError::NotFound)?;
+
+        let reader_id = self.register_reader()?;
+        self.record_prefix_scan(prefix);
+        let mut reader = IterationPointer::new(root, reader_id);
+        reader.normalizer = self.normalizer.clone();
+        reader.read_frequency = self.read_frequency.clone();
+        Ok(reader)
+    }
+
+    pub fn active_readers(&self) -> Result<u64, TrieError> {
+        // Check if the snapshot is already closed
+        self.is_closed()?;
+
+        Ok(self.gate.active())
+    }
+
+    /// Returns an iterator over the key-value pairs of the snapshot within the
+    /// given range of keys, which counts as an active reader until it is
+    /// dropped.
+    ///
+    /// Unlike a reader opened with `new_reader`, the iterator needs no explicit
+    /// `close_reader`: the scan is accounted for exactly as long as the iterator
+    /// lives, and the snapshot cannot close before it is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Fails like `new_reader` if the snapshot is closed or closing.
+    pub fn range_guarded<R: RangeBounds<P>>(
+        &self,
+        range: R,
+    ) -> Result<GuardedRange<'_, P, V>, TrieError> {
+        // Check if the snapshot is already closed
+        self.is_closed()?;
+
+        self.gate.register()?;
+        let guard = ReaderGuard {
+            gate: self.gate.clone(),
+        };
+        let normalize = |key: &P| normalize_key(self.normalizer.as_ref(), key).into_owned();
+        let range = (
+            range.start_bound().map(normalize),
+            range.end_bound().map(normalize),
+        );
+        self.record_scan(
+            range.0.as_ref().map(|key| key.as_slice()),
+            range.1.as_ref().map(|key| key.as_slice()),
+        );
+        let range = match &self.read_set {
+            None => Range::new(self.root.as_ref(), range),
+            Some(reads) => {
+                Range::new(self.root.as_ref(), range).recording(reads, self.root.as_ref())
+            }
+        };
+        Ok(GuardedRange {
+            range,
+            _guard: guard,
+        })
+    }
+
+    /// Returns a page of up to `limit` key-value pairs within `start..end`, in
+    /// key order, along with the token of the next page, or `None` if the page
+    /// is the last one.
+    ///
+    /// The first page is read with no token, and each following page with the
+    /// token returned by the page before it, which holds the last key of that
+    /// page. Nothing is kept between pages, so pages can be read by separate
+    /// requests, and together they cover the range without overlap. A limit of
+    /// 0 reads nothing, and returns an empty page with the token it was read
+    /// with.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::SnapshotAlreadyClosed` if the snapshot is closed.
+    ///
+    #[allow(clippy::type_complexity)]
+    pub fn range_page(
+        &self,
+        start: &P,
+        end: &P,
+        after: Option<PageToken>,
+        limit: usize,
+    ) -> Result<(Vec<(P, V)>, Option<PageToken>), TrieError> {
+        // Check if the snapshot is already closed
+        self.is_closed()?;
+        if limit == 0 {
+            return Ok((Vec::new(), after));
+        }
+        let start = normalize_key(self.normalizer.as_ref(), start).into_owned();
+        let end = normalize_key(self.normalizer.as_ref(), end).into_owned();
+        let from = match after {
+            Some(token) if token.last_key() >= start.as_slice() => {
+                Bound::Excluded(P::from(token.last_key()))
+            }
+            _ => Bound::Included(start),
+        };
+
+        let mut range = Range::new(
+            self.root.as_ref(),
+            (from.clone(), Bound::Excluded(end.clone())),
+        );
+        let page: Vec<(P, V)> = range
+            .by_ref()
+            .filter(|(_, value, ..)| self.value_check.is_none_or(|check| check(value)))
+            .take(limit)
+            .map(|(key, value, ..)| (P::from(key.as_slice()), value.clone()))
+            .collect();
+        let next = match (range.next(), page.last()) {
+            (Some(_), Some((last, _))) => Some(PageToken::new(last.as_slice().to_vec())),
+            _ => None,
+        };
+
+        let to = match &next {
+            Some(token) => Bound::Included(token.last_key()),
+            None => Bound::Excluded(end.as_slice()),
+        };
+        self.record_scan(from.as_ref().map(|key| key.as_slice()), to);
+        Ok((page, next))
+    }
+
+    /// Closes a reader opened with `new_reader` or `new_prefix_reader`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::ReaderNotFound` if no reader with the ID is open on
+    /// the snapshot, for instance because it was closed already.
+    pub fn close_reader(&mut self, reader_id: u64) -> Result<(), TrieError> {
+        // Check if the snapshot is already closed
+        self.is_closed()?;
+
+        if !self.readers.remove(&reader_id) {
+            return Err(TrieError::ReaderNotFound);
+        }
+        self.gate.deregister();
+        Ok(())
+    }
+
+    // Registers a reader with the gate and assigns it a new ID.
+    fn register_reader(&mut self) -> Result<u64, TrieError> {
+        self.gate.register()?;
+        self.last_reader_id += 1;
+        self.readers.insert(self.last_reader_id);
+        Ok(self.last_reader_id)
+    }
+
+    /// Removes a key from the snapshot, returning whether it was present.
+    pub fn remove(&mut self, key: &P) -> Result<bool, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let key = normalize_key(self.normalizer.as_ref(), key);
+        let key = key.as_ref();
+
+        let (new_root, is_deleted) = match &self.root {
+            None => (None, false),
+            Some(root) => match &root.node_type {
+                // A root twig holding another key stays in place.
+                NodeType::Twig(twig) if &twig.key == key => (None, true),
+                NodeType::Twig(_) => (self.root.clone(), false),
+                _ => match Node::remove_recurse(root, key, 0, self.forced_node_type) {
+                    (new_root, true) => (new_root, true),
+                    (_, false) => (self.root.clone(), false),
+                },
+            },
+        };
+
+        if is_deleted {
+            let version = self.root.as_ref().map_or(0, |root| root.version());
+            self.tombstones
+                .bury(key.as_slice(), self.current_ts, version);
+        }
+        self.root = new_root;
+        Ok(is_deleted)
+    }
+}
+
+fn change_key<P, V>(change: &Change<P, V>) -> &P {
+    match change {
+        Change::Insert { key, .. } | Change::Remove { key } => key,
+    }
+}
+
+/// A read-only copy of a snapshot that does not share any state with the Tree.
+///
+/// The key-value pairs visible in the snapshot are stored in a compact frozen
+/// form: the key bytes are concatenated into a single buffer indexed by an
+/// offsets array, and the values with their versions and timestamps are kept in
+/// arrays in key order. Lookups binary search the keys. Compared to the trie
+/// this drops the inner nodes, the per-node reference counts and all but the
+/// latest version of each key, so an `OwnedSnapshot` can keep serving reads
+/// after the Tree it was taken from has been dropped, and in less memory.
+pub struct OwnedSnapshot<P: KeyTrait, V: Clone> {
+    version: u64,
+    keys: Vec<u8>,
+    // offsets[i]..offsets[i + 1] is the range of the i-th key in `keys`.
+    offsets: Vec<usize>,
+    values: Vec<V>,
+    // The version and timestamp of each value.
+    meta: Vec<(u64, u64)>,
+    _marker: PhantomData<P>,
+}
+
+impl<P: KeyTrait, V: Clone> OwnedSnapshot<P, V> {
+    /// Copies the latest value of every entry yielded by `entries` into a frozen
+    /// snapshot at `version`.
+    pub(crate) fn from_entries(entries: Iter<'_, P, V>, version: u64) -> Self {
+        let mut keys = Vec::new();
+        let mut offsets = vec![0];
+        let mut values = Vec::new();
+        let mut meta = Vec::new();
+        for (key, value, version, ts) in entries {
+            keys.extend_from_slice(&key);
+            offsets.push(keys.len());
+            values.push(value.clone());
+            meta.push((*version, *ts));
+        }
+
+        keys.shrink_to_fit();
+        offsets.shrink_to_fit();
+        values.shrink_to_fit();
+        meta.shrink_to_fit();
+
+        OwnedSnapshot {
+            version,
+            keys,
+            offsets,
+            values,
+            meta,
+            _marker: PhantomData,
+        }
+    }
+
+    fn key_at(&self, index: usize) -> &[u8] {
+        &self.keys[self.offsets[index]..self.offsets[index + 1]]
+    }
+
+    /// Returns the index of the first key not less than `key`, and whether it is equal.
+    fn search(&self, key: &[u8]) -> (usize, bool) {
+        let index = self.partition_point(|k| k < key);
+        (index, index < self.len() && self.key_at(index) == key)
+    }
+
+    fn partition_point<F: Fn(&[u8]) -> bool>(&self, pred: F) -> usize {
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if pred(self.key_at(mid)) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    /// Retrieves the value, version and timestamp associated with the given key.
+    pub fn get(&self, key: &P) -> Result<(V, u64, u64), TrieError> {
+        match self.search(key.as_slice()) {
+            (index, true) => {
+                let (version, ts) = self.meta[index];
+                Ok((self.values[index].clone(), version, ts))
+            }
+            _ => Err(TrieError::KeyNotFound),
+        }
+    }
+
+    /// Returns true if the snapshot contains the given key.
+    pub fn contains(&self, key: &P) -> bool {
+        self.search(key.as_slice()).1
+    }
+
+    /// Returns the number of keys in the snapshot.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns true if the snapshot holds no keys.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Returns the version of the snapshot it was copied from.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns an iterator over the key-value pairs in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &V, &u64, &u64)> {
+        self.entries(0, self.len())
+    }
+
+    /// Returns an iterator over the key-value pairs within the given range of keys.
+    pub fn range<R: RangeBounds<P>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = (&[u8], &V, &u64, &u64)> {
+        let start = match range.start_bound() {
+            Bound::Included(key) => self.partition_point(|k| k < key.as_slice()),
+            Bound::Excluded(key) => self.partition_point(|k| k <= key.as_slice()),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => self.partition_point(|k| k <= key.as_slice()),
+            Bound::Excluded(key) => self.partition_point(|k| k < key.as_slice()),
+            Bound::Unbounded => self.len(),
+        };
+        self.entries(start, end.max(start))
+    }
+
+    fn entries(&self, start: usize, end: usize) -> impl Iterator<Item = (&[u8], &V, &u64, &u64)> {
+        (start..end).map(move |index| {
+            let (version, ts) = &self.meta[index];
+            (self.key_at(index), &self.values[index], version, ts)
+        })
+    }
+
+    /// Returns the memory held by the snapshot in bytes.
+    ///
+    /// Heap memory owned by the values themselves is not included.
+    pub fn size_bytes(&self) -> usize {
+        size_of::<Self>()
+            + self.keys.capacity()
+            + self.offsets.capacity() * size_of::<usize>()
+            + self.values.capacity() * size_of::<V>()
+            + self.meta.capacity() * size_of::<(u64, u64)>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::art::Tree;
+    use crate::cursor::PageToken;
+    use crate::iter::{IterationPointer, PatternByte, ScanDecision, ScanOptions};
+    use crate::snapshot::{OwnedSnapshot, Snapshot, SnapshotState};
+    use crate::testing::sharing::{report_roots, SharingCounts};
+    use crate::{
+        assert_iter_matches, assert_snapshot_isolated, assert_tree_eq, Key, TrieError,
+        VariableSizeKey,
+    };
+    use std::cell::RefCell;
+    use std::str::FromStr;
+
+    #[test]
+    fn prefix_readers_partition_the_snapshot() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, usize> = Tree::new();
+        let mut keys = Vec::new();
+        for i in 0..200 {
+            keys.push(format!("users/{:03}", i));
+            keys.push(format!("orders/2024/{:03}", i));
+        }
+        keys.extend(["orders/2025/001", "single", "x"].map(String::from));
+        for (i, k) in keys.iter().enumerate() {
+            tree.insert(&key(k), i, 0, 0).unwrap();
+        }
+        let mut snap = tree.create_snapshot().unwrap();
+
+        // "ord" ends within the path shared by the orders, "orders/2024/" at a
+        // node, and "s" and "x" lead to single twigs.
+        let prefixes: [&[u8]; 6] = [b"users/", b"ord", b"s", b"x", b"users/1", b"orders/2024/"];
+        let readers: Vec<_> = prefixes
+            .iter()
+            .map(|prefix| snap.new_prefix_reader(prefix).unwrap())
+            .collect();
+        assert_eq!(snap.active_readers().unwrap(), prefixes.len() as u64);
+
+        let scans: Vec<Vec<Vec<u8>>> = readers
+            .iter()
+            .map(|reader| reader.iter().map(|(k, ..)| k).collect())
+            .collect();
+        for (prefix, scanned) in prefixes.iter().zip(&scans) {
+            assert!(scanned.iter().all(|k| k.starts_with(prefix)));
+            let expected = keys
+                .iter()
+                .filter(|k| k.as_bytes().starts_with(prefix))
+                .count();
+            assert_eq!(scanned.len(), expected);
+        }
+        // The first four prefixes are disjoint and cover every key.
+        let mut seen = scans[..4].concat();
+        let mut all: Vec<Vec<u8>> = snap
+            .keys()
+            .unwrap()
+            .map(|k| k.to_slice().to_vec())
+            .collect();
+        seen.sort();
+        all.sort();
+        assert_eq!(seen, all);
+
+        // Lookups only find the keys under the prefix.
+        assert_eq!(
+            readers[1].get(&key("orders/2025/001")).unwrap().0,
+            keys.len() - 3
+        );
+        assert!(readers[1].get(&key("users/001")).is_err());
+        assert_eq!(readers[2].get(&key("single")).unwrap().0, keys.len() - 2);
+        assert!(matches!(
+            snap.new_prefix_reader(b"missing"),
+            Err(TrieError::NotFound)
+        ));
+
+        // The readers close one by one, in any order, and then the snapshot does.
+        for i in [3, 0, 5, 1, 4, 2] {
+            assert!(snap.close().is_err());
+            snap.close_reader(readers[i].id()).unwrap();
+            assert!(readers[(i + 1) % readers.len()].iter().next().is_some());
+        }
+        assert_eq!(snap.active_readers().unwrap(), 0);
+        assert!(snap.close().is_ok());
+    }
+
+    #[test]
+    fn unique_memory_counts_the_written_paths() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, usize> = Tree::new();
+        for i in 0..1000 {
+            tree.insert(&key(&format!("key/{:04}", i)), i, 0, 0)
+                .unwrap();
+        }
+        let empty = Tree::<VariableSizeKey, usize>::new()
+            .create_snapshot()
+            .unwrap();
+        let base = tree.create_snapshot().unwrap();
+        let written = ["key/0005", "key/0500", "new/key"];
+        for (i, k) in written.iter().enumerate() {
+            tree.insert(&key(k), i, 0, 0).unwrap();
+        }
+        let fork = tree.create_snapshot().unwrap();
+
+        let full = base.unique_memory(&empty);
+        assert_eq!(base.unique_memory(&base), 0);
+        assert_eq!(fork.unique_memory(&fork.clone_independent().unwrap()), 0);
+
+        // Only the nodes on the written paths are new, along with a value per
+        // written key.
+        let mut path_nodes = std::collections::HashSet::new();
+        for k in written {
+            for node in fork.debug_path(&key(k)) {
+                path_nodes.insert((node.depth, node.prefix));
+            }
+        }
+        let unique = fork.unique_memory(&base);
+        assert!(
+            unique
+                >= path_nodes.len()
+                    * std::mem::size_of::<crate::art::Node<VariableSizeKey, usize>>()
+        );
+        assert!(unique * 20 < full, "{} of {}", unique, full);
+        // The base is almost entirely shared with the fork.
+        let removed = base.unique_memory(&fork);
+        assert!(removed > 0 && removed < unique);
+    }
+
+    #[test]
+    fn get_versions_between_returns_the_window_newest_first() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        for ts in 1..=10 {
+            tree.insert(&key("a"), ts as i32 * 10, 0, ts).unwrap();
+        }
+        let snap = tree.create_snapshot().unwrap();
+
+        assert_eq!(
+            snap.get_versions_between(&key("a"), 3, 6),
+            vec![(60, 6), (50, 5), (40, 4), (30, 3)]
+        );
+        assert_eq!(
+            snap.get_versions_between(&key("a"), 10, u64::MAX),
+            vec![(100, 10)]
+        );
+        assert!(snap.get_versions_between(&key("a"), 11, 20).is_empty());
+        assert!(snap.get_versions_between(&key("a"), 6, 3).is_empty());
+        assert!(snap.get_versions_between(&key("b"), 0, u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn iter_as_of_reconstructs_past_keysets() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        tree.insert(&key("a"), 1, 0, 10).unwrap();
+        tree.insert(&key("b"), 1, 0, 20).unwrap();
+        tree.insert(&key("a"), 2, 0, 30).unwrap();
+        tree.insert(&key("c"), 1, 0, 40).unwrap();
+        tree.insert(&key("d"), 1, 0, 50).unwrap();
+        let before_removal = tree.create_snapshot().unwrap();
+        tree.remove(&key("b")).unwrap();
+        let after_removal = tree.create_snapshot().unwrap();
+
+        let as_of = |snap: &Snapshot<VariableSizeKey, i32>, ts: u64| {
+            snap.iter_as_of(ts).unwrap().collect::<Vec<_>>()
+        };
+        let entries = |pairs: &[(&str, i32)]| -> Vec<(VariableSizeKey, i32)> {
+            pairs.iter().map(|(k, v)| (key(k), *v)).collect()
+        };
+
+        assert_iter_matches!(as_of(&before_removal, 5), entries(&[]));
+        assert_iter_matches!(as_of(&before_removal, 10), entries(&[("a", 1)]));
+        assert_iter_matches!(as_of(&before_removal, 25), entries(&[("a", 1), ("b", 1)]));
+        assert_iter_matches!(
+            as_of(&before_removal, 45),
+            entries(&[("a", 2), ("b", 1), ("c", 1)])
+        );
+        // The removal frees the history of the removed key.
+        assert_iter_matches!(as_of(&after_removal, 25), entries(&[("a", 1)]));
+        assert_iter_matches!(
+            as_of(&after_removal, u64::MAX),
+            entries(&[("a", 2), ("c", 1), ("d", 1)])
+        );
+
+        // A key written again after its removal has the versions written since,
+        // and the removals of a snapshot leave tombstones in the snapshot only.
+        tree.insert(&key("b"), 2, 0, 60).unwrap();
+        tree.remove(&key("a")).unwrap();
+        let mut rewritten = tree.create_snapshot().unwrap();
+        assert_iter_matches!(as_of(&rewritten, 55), entries(&[("c", 1), ("d", 1)]));
+        assert_iter_matches!(
+            as_of(&rewritten, 60),
+            entries(&[("b", 2), ("c", 1), ("d", 1)])
+        );
+        rewritten.remove(&key("c")).unwrap();
+        assert_iter_matches!(as_of(&rewritten, 60), entries(&[("b", 2), ("d", 1)]));
+        assert_eq!(tree.tombstones.len(), 2);
+        assert_eq!(rewritten.tombstones.len(), 3);
+
+        // A write at a timestamp before the removal of its key, made after
+        // the removal, is hidden by the tombstone from the removal onwards.
+        tree.insert(&key("a"), 3, 0, 40).unwrap();
+        let late = tree.create_snapshot().unwrap();
+        assert_iter_matches!(as_of(&late, 45), entries(&[("a", 3), ("c", 1)]));
+        assert_iter_matches!(as_of(&late, 60), entries(&[("b", 2), ("c", 1), ("d", 1)]));
+
+        // Pruning drops the tombstones of the removals before the version.
+        tree.prune_versions_older_than(tree.version()).unwrap();
+        assert_eq!(tree.tombstones.len(), 0);
+    }
+
+    #[test]
+    fn debug_path_describes_the_descent() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, usize> = Tree::new();
+        for (i, word) in ["apple", "apply", "banana"].iter().enumerate() {
+            tree.insert(&key(word), i, 0, 0).unwrap();
+        }
+        let snap = tree.create_snapshot().unwrap();
+
+        let path = snap.debug_path(&key("apply"));
+        let twig = path.last().unwrap();
+        assert_eq!(twig.node_type, "twig");
+        assert_eq!(twig.matched_byte, None);
+        assert_eq!(twig.matched_len, twig.prefix.len());
+        for pair in path.windows(2) {
+            let (parent, child) = (&pair[0], &pair[1]);
+            assert_eq!(parent.matched_len, parent.prefix.len());
+            assert_eq!(child.depth, parent.depth + parent.prefix.len());
+            assert_eq!(parent.matched_byte, child.prefix.first().copied());
+        }
+        let bytes = key("apply");
+        let walked: Vec<u8> = path.iter().flat_map(|node| node.prefix.clone()).collect();
+        assert_eq!(walked, bytes.as_slice());
+
+        // A lookup that diverges inside a prefix stops at that node.
+        let path = snap.debug_path(&key("apricot"));
+        let last = path.last().unwrap();
+        assert_eq!(last.matched_byte, None);
+        assert!(last.matched_len < last.prefix.len());
+        assert_eq!(
+            &last.prefix[..last.matched_len],
+            &b"apricot"[last.depth..][..last.matched_len]
+        );
+
+        // A lookup with no child for its next byte stops at the inner node.
+        let path = snap.debug_path(&key("cherry"));
+        assert_eq!(path.len(), 1);
+        assert_ne!(path[0].node_type, "twig");
+        assert_eq!(path[0].matched_byte, None);
+
+        assert!(Tree::<VariableSizeKey, usize>::new()
+            .create_snapshot()
+            .unwrap()
+            .debug_path(&key("apple"))
+            .is_empty());
+    }
+
+    #[test]
+    fn snapshot_creation() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        let keys = ["key_1", "key_2", "key_3"];
+
+        for key in keys.iter() {
+            assert!(tree
+                .insert(&VariableSizeKey::from_str(key).unwrap(), 1, 0, 0)
+                .is_ok());
+        }
+
+        let mut snap1 = tree.create_snapshot().unwrap();
+        let key_to_insert = "key_1";
+        assert!(snap1
+            .insert(&VariableSizeKey::from_str(key_to_insert).unwrap(), 1, 0)
+            .is_ok());
+
+        let expected_snap_ts = keys.len() as u64 + 1;
+        assert_eq!(snap1.version(), expected_snap_ts);
+        assert_eq!(tree.snapshot_count(), 1);
+
+        let expected_tree_ts = keys.len() as u64;
+        assert_eq!(tree.version(), expected_tree_ts);
+    }
+
+    #[test]
+    fn snapshot_isolation() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        let key_1 = VariableSizeKey::from_str("key_1").unwrap();
+        let key_2 = VariableSizeKey::from_str("key_2").unwrap();
+        let key_3_snap1 = VariableSizeKey::from_str("key_3_snap1").unwrap();
+        let key_3_snap2 = VariableSizeKey::from_str("key_3_snap2").unwrap();
+
+        assert!(tree.insert(&key_1, 1, 0, 0).is_ok());
+
+        // Keys inserted before snapshot creation should be visible
+        let mut snap1 = tree.create_snapshot().unwrap();
+        assert_eq!(snap1.id, 0);
+        assert_eq!(snap1.get(&key_1).unwrap(), (1, 1, 0));
+
+        let mut snap2 = tree.create_snapshot().unwrap();
+        assert_eq!(snap2.id, 1);
+        assert_eq!(snap2.get(&key_1).unwrap(), (1, 1, 0));
+
+        assert_eq!(tree.snapshot_count(), 2);
+
+        // Keys inserted after snapshot creation should not be visible to other snapshots
+        assert!(tree.insert(&key_2, 1, 0, 0).is_ok());
+        assert_snapshot_isolated!(snap1, tree, key_2);
+        assert_snapshot_isolated!(snap2, tree, key_2);
+
+        // Keys inserted after snapshot creation should be visible to the snapshot that inserted them
+        assert!(snap1.insert(&key_3_snap1, 2, 0).is_ok());
+        assert_eq!(snap1.get(&key_3_snap1).unwrap(), (2, 2, 0));
+
+        assert!(snap2.insert(&key_3_snap2, 3, 0).is_ok());
+        assert_eq!(snap2.get(&key_3_snap2).unwrap(), (3, 2, 0));
+
+        // Keys inserted after snapshot creation should not be visible to other snapshots
+        assert!(snap1.get(&key_3_snap2).is_err());
+        assert!(snap2.get(&key_3_snap1).is_err());
+
+        // Each write copied the root twig of key_1 under a new root, so the
+        // snapshots share no node, but hold key_1 unchanged.
+        for (a, b) in [(&tree.root, &snap1.root), (&snap1.root, &snap2.root)] {
+            let report = report_roots(a.as_ref(), b.as_ref());
+            assert_eq!(
+                report.total,
+                SharingCounts {
+                    shared: 0,
+                    equal_but_copied: 1,
+                    distinct: 2,
+                }
+            );
+        }
+
+        assert!(snap1.close().is_ok());
+        assert!(snap2.close().is_ok());
+
+        assert!(tree.close_snapshot(snap1.id).is_ok());
+        assert!(tree.close_snapshot(snap2.id).is_ok());
+
+        assert_eq!(tree.snapshot_count(), 0);
+    }
+
+    #[test]
     fn snapshot_readers() {
         let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
         let key_1 = VariableSizeKey::from_str("key_1").unwrap();
         let key_2 = VariableSizeKey::from_str("key_2").unwrap();
-        let key_3 = VariableSizeKey::from_str("key_3").unwrap();
-        let key_4 = VariableSizeKey::from_str("key_4").unwrap();
-
+        let key_3 = VariableSizeKey::from_str("key_3").unwrap();
+        let key_4 = VariableSizeKey::from_str("key_4").unwrap();
+
+        assert!(tree.insert(&key_1, 1, 0, 0).is_ok());
+        assert!(tree.insert(&key_2, 1, 0, 0).is_ok());
+        assert!(tree.insert(&key_3, 1, 0, 0).is_ok());
+
+        let mut snap = tree.create_snapshot().unwrap();
+        assert!(snap.insert(&key_4, 1, 0).is_ok());
+
+        // Reader 1
+        let reader1 = snap.new_reader().unwrap();
+        let reader1_id = reader1.id;
+        assert_eq!(count_items(&reader1), 4);
+        assert_eq!(reader1_id, 1);
+
+        // Reader 2
+        let reader2 = snap.new_reader().unwrap();
+        let reader2_id = reader2.id;
+        assert_eq!(count_items(&reader2), 4);
+        assert_eq!(reader2_id, 2);
+
+        // Counting does not need a reader
+        assert_eq!(snap.count().unwrap(), count_items(&reader1));
+
+        // Active readers
+        assert_eq!(snap.active_readers().unwrap(), 2);
+        assert!(snap.close().is_err());
+
+        // Close readers
+        assert!(snap.close_reader(reader1_id).is_ok());
+        assert!(snap.close_reader(reader2_id).is_ok());
+        assert!(snap.close().is_ok());
+    }
+
+    #[test]
+    fn snapshot_readers_isolated_from_later_removes() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        let key_1 = VariableSizeKey::from_str("key_1").unwrap();
+        let key_2 = VariableSizeKey::from_str("key_2").unwrap();
+        assert!(tree.insert(&key_1, 1, 0, 0).is_ok());
+        assert!(tree.insert(&key_2, 2, 0, 0).is_ok());
+
+        let mut snap = tree.create_snapshot().unwrap();
+        let reader1 = snap.new_reader().unwrap();
+        assert!(snap.remove(&key_1).unwrap());
+        let reader2 = snap.new_reader().unwrap();
+
+        let keys = |reader: &IterationPointer<VariableSizeKey, i32>| {
+            reader.iter().map(|(k, _, _, _)| k).collect::<Vec<_>>()
+        };
+        assert_iter_matches!(keys(&reader1), [key_1.to_slice(), key_2.to_slice()]);
+        assert_iter_matches!(keys(&reader2), [key_2.to_slice()]);
+        assert!(snap.get(&key_1).is_err());
+
+        // The tree is unaffected by the snapshot's remove.
+        assert_eq!(tree.get(&key_1, 0).unwrap().1, 1);
+
+        assert!(snap.close_reader(reader1.id).is_ok());
+        assert!(snap.close_reader(reader2.id).is_ok());
+        assert!(snap.close().is_ok());
+    }
+
+    #[test]
+    fn range_pages_cover_the_range_without_overlap() {
+        let key = |i: usize| VariableSizeKey::from_str(&format!("key_{:04}", i)).unwrap();
+        let mut tree: Tree<VariableSizeKey, usize> = Tree::new();
+        for i in 0..1000 {
+            tree.insert(&key(i), i, 0, 0).unwrap();
+        }
+        let mut snap = tree.create_snapshot().unwrap();
+
+        // Each page is read as a separate request would, from the token bytes.
+        let (start, end) = (key(100), key(900));
+        let mut token: Option<Vec<u8>> = None;
+        let mut pages = Vec::new();
+        loop {
+            let after = token.map(|bytes| PageToken::from_bytes(&bytes).unwrap());
+            let (page, next) = snap.range_page(&start, &end, after, 37).unwrap();
+            assert!(page.len() == 37 || next.is_none());
+            pages.push(page.into_iter().map(|(_, value)| value).collect::<Vec<_>>());
+            let Some(next) = next else { break };
+            assert_eq!(
+                next.last_key(),
+                key(*pages.last().unwrap().last().unwrap()).as_slice()
+            );
+            token = Some(next.to_bytes());
+        }
+        assert_eq!(pages.len(), 22);
+        assert_iter_matches!(pages.concat(), (100..900).collect::<Vec<_>>());
+
+        // A range that is a multiple of the limit ends without an empty page.
+        let (page, next) = snap.range_page(&key(0), &key(10), None, 5).unwrap();
+        assert_eq!(page.len(), 5);
+        let (page, next) = snap.range_page(&key(0), &key(10), next, 5).unwrap();
+        assert_eq!((page.len(), next), (5, None));
+
+        // A limit of 0 reads nothing and hands back the token it was given.
+        let (_, next) = snap.range_page(&start, &end, None, 10).unwrap();
+        assert_eq!(
+            snap.range_page(&start, &end, None, 0),
+            Ok((Vec::new(), None))
+        );
+        assert_eq!(
+            snap.range_page(&start, &end, next.clone(), 0),
+            Ok((Vec::new(), next))
+        );
+
+        // Keys written between pages show up only after the token.
+        let (_, next) = snap.range_page(&start, &end, None, 10).unwrap();
+        snap.insert(&VariableSizeKey::from_str("key_0105x").unwrap(), 0, 0)
+            .unwrap();
+        snap.insert(&VariableSizeKey::from_str("key_0115x").unwrap(), 0, 0)
+            .unwrap();
+        let (page, _) = snap.range_page(&start, &end, next, 10).unwrap();
+        assert_eq!(page[0].0.as_slice(), key(110).as_slice());
+        assert_eq!(page[6].0.as_slice(), b"key_0115x\0");
+
+        assert!(PageToken::from_bytes(b"key_0100").is_err());
+        snap.close().unwrap();
+        assert_eq!(
+            snap.range_page(&start, &end, None, 10),
+            Err(TrieError::SnapshotAlreadyClosed)
+        );
+    }
+
+    #[test]
+    fn scan_filtered_skips_subtrees() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        for group in ["apple/", "banana/", "cherry/"] {
+            for i in 0..20 {
+                let key = VariableSizeKey::from_str(&format!("{}{}", group, i)).unwrap();
+                tree.insert(&key, i, 0, 0).unwrap();
+            }
+        }
+        let mut snap = tree.create_snapshot().unwrap();
+
+        let visited = RefCell::new(Vec::new());
+        let filter = |prefix: &VariableSizeKey| {
+            let bytes = prefix.as_slice();
+            visited.borrow_mut().push(bytes.to_vec());
+            if bytes.starts_with(b"b") {
+                ScanDecision::SkipSubtree
+            } else if bytes.ends_with(&[0]) {
+                ScanDecision::Yield
+            } else {
+                ScanDecision::Descend
+            }
+        };
+        let keys: Vec<Vec<u8>> = snap
+            .scan_filtered(filter)
+            .unwrap()
+            .map(|(k, _)| k.as_slice().to_vec())
+            .collect();
+
+        let expected: Vec<Vec<u8>> = tree
+            .iter()
+            .map(|(k, _, _, _)| k)
+            .filter(|k| !k.starts_with(b"b"))
+            .collect();
+        assert_eq!(keys, expected);
+        // The filter was asked about the banana subtree once, and never about its keys.
+        let visited = visited.into_inner();
+        assert_eq!(visited.iter().filter(|p| p.starts_with(b"b")).count(), 1);
+
+        // Yielding at an inner node yields the subtree without asking again.
+        let calls = RefCell::new(0);
+        let all: Vec<_> = tree
+            .scan_with_filter(|_| {
+                *calls.borrow_mut() += 1;
+                ScanDecision::Yield
+            })
+            .collect();
+        assert_eq!(all.len(), 60);
+        assert_eq!(*calls.borrow(), 1);
+
+        snap.close().unwrap();
+        assert!(snap.scan_filtered(|_| ScanDecision::Yield).is_err());
+    }
+
+    // A value that counts how often it is cloned, encoded as a kind byte and a
+    // two-byte field. Rows of kind 0 are handled by the projection below.
+    #[derive(Debug, PartialEq)]
+    struct Row(Vec<u8>);
+
+    thread_local! {
+        static ROW_CLONES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    impl Clone for Row {
+        fn clone(&self) -> Self {
+            ROW_CLONES.with(|clones| clones.set(clones.get() + 1));
+            Row(self.0.clone())
+        }
+    }
+
+    impl AsRef<[u8]> for Row {
+        fn as_ref(&self) -> &[u8] {
+            &self.0
+        }
+    }
+
+    impl From<Row> for Vec<u8> {
+        fn from(row: Row) -> Self {
+            row.0
+        }
+    }
+
+    #[test]
+    fn scan_projects_values_without_cloning_them() {
+        let mut tree: Tree<VariableSizeKey, Row> = Tree::new();
+        for i in 0..60u8 {
+            let key = VariableSizeKey::from_str(&format!("row/{:02}", i)).unwrap();
+            tree.insert(&key, Row(vec![i % 3, i, 0xAA, 0xBB]), 0, 0)
+                .unwrap();
+        }
+        let snap = tree.create_snapshot().unwrap();
+        let clones = || ROW_CLONES.with(|clones| clones.replace(0));
+        let field = |bytes: &[u8]| (bytes[0] == 0).then(|| bytes[1..3].to_vec());
+        clones();
+
+        // Rows of kind 0 are projected in place, the others are cloned whole.
+        let rows: Vec<(VariableSizeKey, Vec<u8>)> = snap
+            .scan(ScanOptions::new().project_raw(field))
+            .unwrap()
+            .collect();
+        assert_eq!(rows.len(), 60);
+        let unprojected = rows.iter().filter(|(_, value)| value.len() == 4).count();
+        assert_eq!(unprojected, 40);
+        assert_eq!(clones(), unprojected);
+        assert_eq!(rows[3].1, vec![3, 0xAA]);
+
+        // Filtering on the projected field clones nothing for the dropped rows,
+        // and the key filter keeps the rows under row/5 from being visited.
+        let rows: Vec<Vec<u8>> = snap
+            .scan(
+                ScanOptions::new()
+                    .keys(|prefix: &VariableSizeKey| {
+                        let bytes = prefix.as_slice();
+                        if bytes.starts_with(b"row/5") {
+                            ScanDecision::SkipSubtree
+                        } else if bytes.ends_with(&[0]) {
+                            ScanDecision::Yield
+                        } else {
+                            ScanDecision::Descend
+                        }
+                    })
+                    .project_raw(field)
+                    .filter(|value| value.len() == 4 || value[0] % 2 == 0),
+            )
+            .unwrap()
+            .map(|(_, value)| value)
+            .collect();
+        let unprojected = rows.iter().filter(|value| value.len() == 4).count();
+        assert_eq!((rows.len(), unprojected), (42, 33));
+        assert_eq!(clones(), unprojected);
+
+        // The filter sees declined rows in place, so those it drops are never
+        // cloned.
+        let rows: Vec<Vec<u8>> = snap
+            .scan(
+                ScanOptions::new()
+                    .project_raw(field)
+                    .filter(|value| value.len() != 4 || value[1] < 10),
+            )
+            .unwrap()
+            .map(|(_, value)| value)
+            .collect();
+        let unprojected = rows.iter().filter(|value| value.len() == 4).count();
+        assert_eq!((rows.len(), unprojected), (26, 6));
+        assert_eq!(clones(), unprojected);
+
+        // An in-memory projection clones no value at all.
+        let total: u32 = snap
+            .scan(ScanOptions::new().project(|row: &Row| row.0[1] as u32))
+            .unwrap()
+            .filter(|(_, field)| field % 2 == 1)
+            .map(|(_, field)| field)
+            .sum();
+        assert_eq!(total, (0..60).filter(|i| i % 2 == 1).sum());
+        assert_eq!(clones(), 0);
+
+        // Without a projection every yielded value is cloned.
+        assert_eq!(snap.scan(ScanOptions::new()).unwrap().count(), 60);
+        assert_eq!(clones(), 60);
+    }
+
+    #[test]
+    fn scan_filter_sees_full_key_paths() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        for i in 0..500 {
+            let len = rng.gen_range(1..10);
+            let key: Vec<u8> = (0..len).map(|_| rng.gen_range(b'a'..=b'e')).collect();
+            let key = VariableSizeKey::from_slice_with_termination(&key);
+            tree.insert(&key, i, 0, 0).unwrap();
+        }
+        let keys: Vec<Vec<u8>> = tree.iter().map(|(k, _, _, _)| k).collect();
+
+        // Descending everywhere shows the filter every node: inner nodes with the
+        // bytes of the path leading to them, twigs with their full keys.
+        let visited = RefCell::new(Vec::new());
+        let yielded = tree
+            .scan_with_filter(|path: &VariableSizeKey| {
+                visited.borrow_mut().push(path.as_slice().to_vec());
+                ScanDecision::Descend
+            })
+            .count();
+        assert_eq!(yielded, 0);
+
+        let visited = visited.into_inner();
+        let (leaves, inner): (Vec<_>, Vec<_>) =
+            visited.into_iter().partition(|path| path.ends_with(&[0]));
+        assert_eq!(leaves, keys);
+        for path in inner {
+            assert!(
+                keys.iter().filter(|key| key.starts_with(&path)).count() > 1,
+                "{:?}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn snapshot_sum_prefix() {
+        let mut tree: Tree<VariableSizeKey, u64> = Tree::new();
+        for ns in ["cpu/", "disk/", "disk_io/", "mem/"] {
+            for i in 0..50u64 {
+                let key = VariableSizeKey::from_str(&format!("{}{}", ns, i)).unwrap();
+                tree.insert(&key, i * ns.len() as u64, 0, 0).unwrap();
+            }
+        }
+        // Overwritten values only count with their latest version.
+        let cpu0 = VariableSizeKey::from_str("cpu/0").unwrap();
+        tree.insert(&cpu0, 1000, 0, 0).unwrap();
+        let snap = tree.create_snapshot().unwrap();
+
+        for prefix in ["cpu/", "disk", "disk/", "d", "", "mem/4", "net/", "cpu/0"] {
+            let expected: u64 = tree
+                .iter()
+                .filter(|(k, _, _, _)| k.starts_with(prefix.as_bytes()))
+                .fold(0, |sum, (_, v, _, _)| sum + v);
+            let prefix_key = VariableSizeKey::from_slice(prefix.as_bytes());
+            assert_eq!(
+                snap.sum_prefix(&prefix_key).unwrap(),
+                expected,
+                "{}",
+                prefix
+            );
+        }
+        assert_eq!(
+            snap.sum_prefix(&VariableSizeKey::from_slice(b"cpu/0"))
+                .unwrap(),
+            1000
+        );
+    }
+
+    #[test]
+    fn remove_reports_absent_keys() {
+        let a = VariableSizeKey::from_str("a").unwrap();
+        let b = VariableSizeKey::from_str("b").unwrap();
+        let c = VariableSizeKey::from_str("c").unwrap();
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        tree.insert(&a, 1, 0, 0).unwrap();
+        tree.insert(&b, 2, 0, 0).unwrap();
+
+        let mut snap = tree.create_snapshot().unwrap();
+        assert!(!snap.remove(&c).unwrap());
+        assert!(snap.remove(&a).unwrap());
+        assert!(!snap.remove(&a).unwrap());
+        assert_eq!(snap.count().unwrap(), 1);
+    }
+
+    #[test]
+    fn snapshot_keys() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        for (i, key) in ["m", "b", "ba", "z", "a", "bab", "k"].iter().enumerate() {
+            let key = VariableSizeKey::from_str(key).unwrap();
+            tree.insert(&key, i as i32, 0, 0).unwrap();
+        }
+        let mut snap = tree.create_snapshot().unwrap();
+
+        let keys: Vec<VariableSizeKey> = snap.keys().unwrap().collect();
+        let reader = snap.new_reader().unwrap();
+        let pairs: Vec<VariableSizeKey> = reader
+            .iter()
+            .map(|(key, _, _, _)| VariableSizeKey::from(key))
+            .collect();
+        assert_eq!(keys, pairs);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(keys.len(), 7);
+
+        let mut empty: Tree<VariableSizeKey, i32> = Tree::new();
+        let snap = empty.create_snapshot().unwrap();
+        assert_eq!(snap.keys().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn snapshot_distinct_next_bytes() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        let words = [
+            "car", "card", "care", "cared", "careful", "cart", "carton", "cat", "dog",
+        ];
+        for (i, word) in words.iter().enumerate() {
+            tree.insert(&VariableSizeKey::from_str(word).unwrap(), i as i32, 0, 0)
+                .unwrap();
+        }
+        let snap = tree.create_snapshot().unwrap();
+        let next = |prefix: &str| {
+            snap.distinct_next_bytes(&VariableSizeKey::from_slice(prefix.as_bytes()))
+                .unwrap()
+        };
+
+        assert_eq!(next(""), 2); // c, d
+        assert_eq!(next("c"), 1); // a
+        assert_eq!(next("ca"), 2); // r, t
+                                   // "car" itself ends with a NULL byte, besides d, e and t.
+        assert_eq!(next("car"), 4);
+        assert_eq!(next("care"), 3); // NULL, d, f
+        assert_eq!(next("caref"), 1); // within the compressed "ful"
+        assert_eq!(next("careful"), 1); // NULL
+        assert_eq!(next("careful\0"), 0);
+        assert_eq!(next("do"), 1);
+        assert_eq!(next("cb"), 0);
+        assert_eq!(next("x"), 0);
+
+        let mut empty: Tree<VariableSizeKey, i32> = Tree::new();
+        let snap = empty.create_snapshot().unwrap();
+        assert_eq!(
+            snap.distinct_next_bytes(&VariableSizeKey::from_slice(b"a"))
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn snapshot_find_within_distance() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        let words = [
+            "at", "car", "cart", "cast", "cat", "cats", "cut", "dog", "scat",
+        ];
+        for (i, word) in words.iter().enumerate() {
+            tree.insert(&VariableSizeKey::from_str(word).unwrap(), i as i32, 0, 0)
+                .unwrap();
+        }
+        let snap = tree.create_snapshot().unwrap();
+        let find = |query: &str, max_dist: usize| -> Vec<String> {
+            snap.find_within_distance(&VariableSizeKey::from_str(query).unwrap(), max_dist)
+                .unwrap()
+                .into_iter()
+                .map(|(key, value)| {
+                    let word = std::str::from_utf8(&key.to_slice()[..key.len() - 1]).unwrap();
+                    assert_eq!(words[value as usize], word);
+                    word.to_string()
+                })
+                .collect()
+        };
+
+        assert_eq!(find("cat", 0), vec!["cat"]);
+        assert_eq!(
+            find("cat", 1),
+            vec!["at", "car", "cart", "cast", "cat", "cats", "cut", "scat"]
+        );
+        assert_eq!(find("dig", 1), vec!["dog"]);
+        assert!(find("zebra", 1).is_empty());
+
+        // The walk agrees with the distances computed key by key.
+        let distance = |a: &str, b: &str| {
+            let (a, b) = (a.as_bytes(), b.as_bytes());
+            let mut row: Vec<usize> = (0..=b.len()).collect();
+            for (i, x) in a.iter().enumerate() {
+                let mut next = vec![i + 1];
+                for (j, y) in b.iter().enumerate() {
+                    next.push(
+                        (row[j] + usize::from(x != y))
+                            .min(row[j + 1] + 1)
+                            .min(next[j] + 1),
+                    );
+                }
+                row = next;
+            }
+            row[b.len()]
+        };
+        for query in ["cat", "cart", "dog", "sat", "c", ""] {
+            for max_dist in 0..4 {
+                let expected: Vec<&str> = words
+                    .iter()
+                    .copied()
+                    .filter(|word| distance(word, query) <= max_dist)
+                    .collect();
+                assert_eq!(
+                    find(query, max_dist),
+                    expected,
+                    "{} within {}",
+                    query,
+                    max_dist
+                );
+            }
+        }
+
+        let mut empty: Tree<VariableSizeKey, i32> = Tree::new();
+        let snap = empty.create_snapshot().unwrap();
+        let query = VariableSizeKey::from_str("cat").unwrap();
+        assert!(snap.find_within_distance(&query, 5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn snapshot_glob_iter() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        let words = [
+            "abc", "abcd", "abd", "ac", "acc", "adc", "b", "bac", "xabc", "",
+        ];
+        for (i, word) in words.iter().enumerate() {
+            tree.insert(&VariableSizeKey::from_str(word).unwrap(), i as i32, 0, 0)
+                .unwrap();
+        }
+        let snap = tree.create_snapshot().unwrap();
+        let glob = |glob: &str| -> Vec<&str> {
+            snap.glob_iter(&PatternByte::parse_glob(glob.as_bytes()))
+                .unwrap()
+                .map(|(key, value)| {
+                    let word = words[value as usize];
+                    assert_eq!(&key.to_slice()[..key.len() - 1], word.as_bytes());
+                    word
+                })
+                .collect()
+        };
+
+        assert_eq!(glob("abc"), vec!["abc"]);
+        assert!(glob("ab").is_empty());
+        assert_eq!(glob("a?c"), vec!["abc", "acc", "adc"]);
+        assert_eq!(glob("a?c*"), vec!["abc", "abcd", "acc", "adc"]);
+        assert_eq!(glob("*c"), vec!["abc", "ac", "acc", "adc", "bac", "xabc"]);
+        assert_eq!(glob("?"), vec!["b"]);
+        assert_eq!(glob(""), vec![""]);
+        assert_eq!(glob("*").len(), words.len());
+        assert_eq!(glob("a**d"), vec!["abcd", "abd"]);
+
+        // The walk agrees with matching every key on its own.
+        fn matches(pattern: &[u8], key: &[u8]) -> bool {
+            match (pattern.first(), key.first()) {
+                (None, _) => key.is_empty(),
+                (Some(b'*'), _) => {
+                    matches(&pattern[1..], key) || (!key.is_empty() && matches(pattern, &key[1..]))
+                }
+                (Some(_), None) => false,
+                (Some(&p), Some(&k)) => (p == b'?' || p == k) && matches(&pattern[1..], &key[1..]),
+            }
+        }
+        for pattern in ["a*", "*b*", "??", "?a?", "*?c", "x*c", "b*", "*d*"] {
+            let expected: Vec<&str> = words
+                .iter()
+                .copied()
+                .filter(|word| matches(pattern.as_bytes(), word.as_bytes()))
+                .collect::<std::collections::BTreeSet<_>>()
+                .into_iter()
+                .collect();
+            assert_eq!(glob(pattern), expected, "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn snapshot_forks_without_cloning_values() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // A value counting how often it is cloned.
+        struct Counted(u32, Arc<AtomicUsize>);
+        impl PartialEq for Counted {
+            fn eq(&self, other: &Self) -> bool {
+                self.0 == other.0
+            }
+        }
+        impl Clone for Counted {
+            fn clone(&self) -> Self {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                Counted(self.0, self.1.clone())
+            }
+        }
+
+        let clones = Arc::new(AtomicUsize::new(0));
+        let value = |id: u32| Counted(id, clones.clone());
+        let key = |name: &str| VariableSizeKey::from_str(name).unwrap();
+        let names: Vec<String> = ["a", "b"]
+            .iter()
+            .flat_map(|c| (0..10).map(move |i| format!("{}{}", c, i)))
+            .collect();
+        let mut tree: Tree<VariableSizeKey, Counted> = Tree::new();
+        for (i, name) in names.iter().enumerate() {
+            tree.insert(&key(name), value(i as u32), 0, 0).unwrap();
+        }
+        let count = || clones.load(Ordering::SeqCst);
+        let before = count();
+
+        // Forking shares the root, and reads clone only the values returned.
+        let mut snap = tree.create_snapshot().unwrap();
+        assert_eq!(count(), before);
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(snap.get(&key(name)).unwrap().0 .0, i as u32);
+        }
+        assert_eq!(count(), before + names.len());
+        let reader = snap.new_reader().unwrap();
+        assert_eq!(reader.iter().count(), names.len());
+        assert_eq!(snap.keys().unwrap().count(), names.len());
+        assert_eq!(count(), before + names.len());
+
+        // Writes copy the nodes along their path, and share the rest with the
+        // Trie without cloning any stored value.
+        let before = count();
+        snap.insert(&key("a3"), value(100), 0).unwrap();
+        snap.remove(&key("b5")).unwrap();
+        assert_eq!(count(), before);
+
+        let tree_root = tree.root.as_ref().unwrap();
+        let snap_root = snap.root.as_ref().unwrap();
+        assert!(!Arc::ptr_eq(tree_root, snap_root));
+        let tree_a = tree_root.find_child(b'a').unwrap();
+        let snap_a = snap_root.find_child(b'a').unwrap();
+        assert!(!Arc::ptr_eq(tree_a, snap_a));
+        for (byte, child) in snap_a.iter() {
+            let shared = Arc::ptr_eq(child, tree_a.find_child(byte).unwrap());
+            assert_eq!(shared, byte != b'3');
+        }
+        let tree_b = tree_root.find_child(b'b').unwrap();
+        let snap_b = snap_root.find_child(b'b').unwrap();
+        assert_eq!(snap_b.num_children(), tree_b.num_children() - 1);
+        for (byte, child) in snap_b.iter() {
+            assert!(Arc::ptr_eq(child, tree_b.find_child(byte).unwrap()));
+        }
+        let report = report_roots(tree.root.as_ref(), snap.root.as_ref());
+        assert_eq!(report.total.equal_but_copied, 0);
+        assert_eq!(report.total.distinct, 4);
+        assert!(report.shared_fraction() > 0.8, "{}", report);
+
+        // The Trie still reads its own values.
+        assert_eq!(tree.get(&key("a3"), 0).unwrap().1 .0, 3);
+        assert!(tree.get(&key("b5"), 0).is_ok());
+        assert_eq!(snap.get(&key("a3")).unwrap().0 .0, 100);
+    }
+
+    #[test]
+    fn snapshot_reader_iter_low_memory() {
+        use crate::iter::LowMemoryIter;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        // Keys that are prefixes of each other make the trie as deep as the
+        // longest key, mixed with random keys branching off along the way.
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        let mut rng = StdRng::seed_from_u64(3);
+        for i in 1..150 {
+            let key = "a".repeat(i);
+            assert!(tree
+                .insert(&VariableSizeKey::from_str(&key).unwrap(), i as i32, 0, 0)
+                .is_ok());
+            let branch = format!("{}{}", &key[..rng.gen_range(0..i)], rng.gen_range(0..1000));
+            assert!(tree
+                .insert(
+                    &VariableSizeKey::from_str(&branch).unwrap(),
+                    -(i as i32),
+                    0,
+                    0
+                )
+                .is_ok());
+        }
+        for i in (1..150).step_by(7) {
+            let key = VariableSizeKey::from_str(&"a".repeat(i)).unwrap();
+            assert!(tree.remove(&key).unwrap());
+        }
+
+        let mut snap = tree.create_snapshot().unwrap();
+        let reader = snap.new_reader().unwrap();
+        let expected: Vec<_> = reader.iter().collect();
+        let low_memory: Vec<_> = reader.iter_low_memory().collect();
+        assert!(expected.len() > 200);
+        assert!(low_memory == expected);
+
+        // The iterator only holds the root and the last key, whatever the depth.
+        assert!(
+            std::mem::size_of::<LowMemoryIter<VariableSizeKey, i32>>()
+                <= 4 * std::mem::size_of::<usize>()
+        );
+
+        assert!(snap.close_reader(reader.id).is_ok());
+        assert!(snap.close().is_ok());
+    }
+
+    #[test]
+    fn snapshot_reader_iter_chunked() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        for i in 0..10 {
+            let key = VariableSizeKey::from_str(&format!("key_{}", i)).unwrap();
+            assert!(tree.insert(&key, i, 0, 0).is_ok());
+        }
+
+        let mut snap = tree.create_snapshot().unwrap();
+        let reader = snap.new_reader().unwrap();
+
+        let chunks: Vec<Vec<(VariableSizeKey, i32)>> = reader.iter_chunked(4).collect();
+        let sizes: Vec<usize> = chunks.iter().map(|chunk| chunk.len()).collect();
+        assert_eq!(sizes, vec![4, 4, 2]);
+
+        // Flattened chunks should match a full scan
+        let flattened: Vec<(Vec<u8>, i32)> = chunks
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| (key.as_slice().to_vec(), value))
+            .collect();
+        let scanned: Vec<(Vec<u8>, i32)> = reader
+            .iter()
+            .map(|(key, value, _, _)| (key, *value))
+            .collect();
+        assert_eq!(flattened, scanned);
+
+        // A chunk size larger than the snapshot yields a single chunk
+        assert_eq!(reader.iter_chunked(100).count(), 1);
+
+        assert!(snap.close_reader(reader.id).is_ok());
+    }
+
+    #[test]
+    fn snapshot_reader_iter_suspend_and_resume() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        for i in 0..2000 {
+            let len = rng.gen_range(1..8);
+            let key: Vec<u8> = (0..len).map(|_| rng.gen_range(b'a'..=b'e')).collect();
+            tree.insert(&VariableSizeKey::key(&key), i, 0, 0).unwrap();
+        }
+
+        let mut snap = tree.create_snapshot().unwrap();
+        let reader = snap.new_reader().unwrap();
+        let expected: Vec<(Vec<u8>, i32)> = reader
+            .iter()
+            .map(|(key, value, _, _)| (key, *value))
+            .collect();
+
+        for _ in 0..20 {
+            let mut scanned = Vec::new();
+            let mut iter = reader.iter_suspendable();
+            loop {
+                let step = rng.gen_range(0..50);
+                scanned.extend(
+                    iter.by_ref()
+                        .take(step)
+                        .map(|(key, value, _, _)| (key, value)),
+                );
+                let suspended = iter.suspend();
+                assert_eq!(
+                    suspended.last_key(),
+                    scanned.last().map(|(key, _)| key.as_slice())
+                );
+
+                // Writes made while the scan is suspended are not seen by it.
+                let key = VariableSizeKey::key(&[b'a' + rng.gen_range(0..5)]);
+                if rng.gen_bool(0.5) {
+                    snap.insert(&key, -1, 0).unwrap();
+                } else {
+                    snap.remove(&key).unwrap();
+                }
+
+                iter = suspended.resume();
+                if scanned.len() == expected.len() {
+                    break;
+                }
+            }
+            assert_eq!(iter.next(), None);
+            assert_eq!(scanned, expected);
+        }
+
+        assert!(snap.close_reader(reader.id).is_ok());
+    }
+
+    #[test]
+    fn range_guard_counts_as_reader() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        for i in 0..10 {
+            let key = VariableSizeKey::from_str(&format!("key_{}", i)).unwrap();
+            assert!(tree.insert(&key, i, 0, 0).is_ok());
+        }
+
+        let mut snap = tree.create_snapshot().unwrap();
+        let start = VariableSizeKey::from_str("key_3").unwrap();
+        let end = VariableSizeKey::from_str("key_6").unwrap();
+        let mut range = snap.range_guarded(start..end).unwrap();
+        let mut values = Vec::new();
+        for (_, value, _, _) in range.by_ref() {
+            // The scan is an active reader for as long as it runs.
+            assert_eq!(snap.active_readers().unwrap(), 1);
+            values.push(*value);
+        }
+        assert_eq!(values, vec![3, 4, 5]);
+        assert_eq!(snap.active_readers().unwrap(), 1);
+
+        // Dropping the iterator closes the reader.
+        drop(range);
+        assert_eq!(snap.active_readers().unwrap(), 0);
+        assert_eq!(snap.range_guarded(..).unwrap().count(), 10);
+        assert_eq!(snap.active_readers().unwrap(), 0);
+        assert!(snap.close().is_ok());
+        assert!(matches!(
+            snap.range_guarded(..),
+            Err(TrieError::SnapshotAlreadyClosed)
+        ));
+    }
+
+    #[test]
+    fn min_pinned_version_tracks_oldest_snapshot() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        let key = VariableSizeKey::from_str("key").unwrap();
+
+        // Without snapshots, the current version is the cutoff
+        assert_eq!(tree.min_pinned_version(), 0);
+        assert!(tree.insert(&key, 1, 0, 0).is_ok());
+        assert_eq!(tree.min_pinned_version(), 1);
+
+        let snap1 = tree.create_snapshot().unwrap();
+        assert!(tree.insert(&key, 2, 0, 0).is_ok());
+        let snap2 = tree.create_snapshot().unwrap();
+        assert!(tree.insert(&key, 3, 0, 0).is_ok());
+        let snap3 = tree.create_snapshot().unwrap();
+        assert!(tree.insert(&key, 4, 0, 0).is_ok());
+        assert_eq!(tree.min_pinned_version(), 1);
+
+        // Closing snapshots moves the cutoff to the oldest remaining one
+        assert!(tree.close_snapshot(snap1.id).is_ok());
+        assert_eq!(tree.min_pinned_version(), 2);
+        assert!(tree.close_snapshot(snap3.id).is_ok());
+        assert_eq!(tree.min_pinned_version(), 2);
+        assert!(tree.close_snapshot(snap2.id).is_ok());
+        assert_eq!(tree.min_pinned_version(), 4);
+
+        // Pruning at the cutoff keeps the value visible at it
+        let snap = tree.create_snapshot().unwrap();
+        assert!(tree.insert(&key, 5, 0, 0).is_ok());
+        assert_eq!(
+            tree.prune_versions_older_than(tree.min_pinned_version())
+                .unwrap(),
+            3
+        );
+        assert_eq!(tree.get(&key, snap.ts - 1).unwrap().1, 4);
+        assert!(tree.close_snapshot(snap.id).is_ok());
+    }
+
+    #[test]
+    fn min_pinned_ts_tracks_oldest_snapshot() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        let key = VariableSizeKey::from_str("key").unwrap();
+
+        // Without snapshots, the current timestamp is the cutoff
+        assert_eq!(tree.min_pinned_ts(), 0);
+        tree.insert(&key, 1, 0, 10).unwrap();
+        assert_eq!(tree.min_pinned_ts(), 10);
+
+        let snap1 = tree.create_snapshot().unwrap();
+        tree.insert(&key, 2, 0, 20).unwrap();
+        let snap2 = tree.create_snapshot().unwrap();
+        tree.insert(&key, 3, 0, 30).unwrap();
+        let snap3 = tree.create_snapshot().unwrap();
+        tree.insert(&key, 4, 0, 40).unwrap();
+        assert_eq!(tree.min_pinned_ts(), 10);
+
+        // A clone of a snapshot pins the timestamp of the snapshot
+        let clone = snap3.clone_independent().unwrap();
+
+        // Closing snapshots moves the cutoff to the oldest remaining one
+        tree.close_snapshot(snap1.id).unwrap();
+        assert_eq!(tree.min_pinned_ts(), 20);
+        tree.close_snapshot(snap2.id).unwrap();
+        assert_eq!(tree.min_pinned_ts(), 30);
+        tree.close_snapshot(snap3.id).unwrap();
+        assert_eq!(tree.min_pinned_ts(), 30);
+        tree.close_snapshot(clone.id).unwrap();
+        assert_eq!(tree.min_pinned_ts(), 40);
+    }
+
+    #[test]
+    fn snapshot_clone_independent_readers() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        let key_1 = VariableSizeKey::from_str("key_1").unwrap();
+        let key_2 = VariableSizeKey::from_str("key_2").unwrap();
         assert!(tree.insert(&key_1, 1, 0, 0).is_ok());
         assert!(tree.insert(&key_2, 1, 0, 0).is_ok());
-        assert!(tree.insert(&key_3, 1, 0, 0).is_ok());
 
         let mut snap = tree.create_snapshot().unwrap();
-        assert!(snap.insert(&key_4, 1, 0).is_ok());
+        let mut clone = snap.clone_independent().unwrap();
+        assert_ne!(snap.id(), clone.id());
+        assert_eq!(snap.ts, clone.ts);
+        assert_eq!(snap.version(), clone.version());
+        assert_eq!(tree.snapshot_count(), 2);
 
-        // Reader 1
+        // Readers are tracked per snapshot
         let reader1 = snap.new_reader().unwrap();
-        let reader1_id = reader1.id;
-        assert_eq!(count_items(&reader1), 4);
-        assert_eq!(reader1_id, 1);
-
-        // Reader 2
         let reader2 = snap.new_reader().unwrap();
-        let reader2_id = reader2.id;
-        assert_eq!(count_items(&reader2), 4);
-        assert_eq!(reader2_id, 2);
+        let clone_reader = clone.new_reader().unwrap();
+        assert_eq!(snap.active_readers().unwrap(), 2);
+        assert_eq!(clone.active_readers().unwrap(), 1);
+        assert_eq!(count_items(&reader1), count_items(&clone_reader));
 
-        // Active readers
+        assert!(clone.close_reader(clone_reader.id).is_ok());
+        assert!(clone.close().is_ok());
         assert_eq!(snap.active_readers().unwrap(), 2);
         assert!(snap.close().is_err());
 
-        // Close readers
-        assert!(snap.close_reader(reader1_id).is_ok());
-        assert!(snap.close_reader(reader2_id).is_ok());
+        assert!(snap.close_reader(reader1.id).is_ok());
+        assert!(snap.close_reader(reader2.id).is_ok());
+        assert!(snap.close().is_ok());
+
+        assert!(tree.close_snapshot(clone.id()).is_ok());
+        assert!(tree.close_snapshot(snap.id()).is_ok());
+        assert_eq!(tree.snapshot_count(), 0);
+    }
+
+    #[test]
+    fn detached_snapshot_outlives_tree() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        for i in 0..500 {
+            let key = VariableSizeKey::from_str(&format!("key_{:04}", i)).unwrap();
+            tree.insert(&key, i, 0, 0).unwrap();
+        }
+        // Overwrite one key so that the trie holds two versions of it.
+        let key_0 = VariableSizeKey::from_str("key_0000").unwrap();
+        tree.insert(&key_0, -1, 0, 0).unwrap();
+
+        let mut snap = tree.create_snapshot().unwrap();
+        let extra = VariableSizeKey::from_str("extra").unwrap();
+        snap.insert(&extra, 1000, 0).unwrap();
+        let expected: Vec<(Vec<u8>, i32)> = snap
+            .new_reader()
+            .unwrap()
+            .iter()
+            .map(|(k, v, _, _)| (k, *v))
+            .collect();
+        let version = snap.version();
+
+        let owned = snap.detach_owned();
+        assert_eq!(tree.snapshot_count(), 0);
+        let exported = tree.export_owned_snapshot().unwrap();
+        drop(tree);
+
+        // The frozen form can be moved to another thread.
+        let owned = std::thread::spawn(move || owned).join().unwrap();
+
+        assert_eq!(owned.len(), 501);
+        assert_eq!(owned.version(), version);
+        assert_eq!(owned.get(&key_0).unwrap().0, -1);
+        assert_eq!(owned.get(&extra).unwrap().0, 1000);
+        assert!(owned.contains(&VariableSizeKey::from_str("key_0499").unwrap()));
+        assert!(!owned.contains(&VariableSizeKey::from_str("key_0500").unwrap()));
+        assert!(!exported.contains(&extra));
+        assert_eq!(exported.len(), 500);
+
+        let actual: Vec<(Vec<u8>, i32)> =
+            owned.iter().map(|(k, v, _, _)| (k.to_vec(), *v)).collect();
+        assert_eq!(actual, expected);
+
+        let start = VariableSizeKey::from_str("key_0100").unwrap();
+        let end = VariableSizeKey::from_str("key_0200").unwrap();
+        let range: Vec<i32> = owned
+            .range(start..end.clone())
+            .map(|(_, v, _, _)| *v)
+            .collect();
+        assert_eq!(range, (100..200).collect::<Vec<_>>());
+        assert_eq!(owned.range(end.clone()..=end).count(), 1);
+
+        // Key bytes, offsets, values and their versions and timestamps make up
+        // the frozen form, with one offset more than there are keys.
+        let key_bytes: usize = expected.iter().map(|(k, _)| k.len()).sum();
+        let per_key = size_of::<usize>() + size_of::<i32>() + size_of::<(u64, u64)>();
+        assert!(owned.size_bytes() >= key_bytes + 501 * per_key);
+        assert!(
+            owned.size_bytes()
+                <= size_of::<OwnedSnapshot<VariableSizeKey, i32>>() + key_bytes + 502 * per_key
+        );
+    }
+
+    #[test]
+    fn snapshot_into_tree() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        let key_1 = VariableSizeKey::from_str("key_1").unwrap();
+        let key_2 = VariableSizeKey::from_str("key_2").unwrap();
+        let key_3 = VariableSizeKey::from_str("key_3").unwrap();
+        tree.insert(&key_1, 1, 0, 0).unwrap();
+
+        let mut snap = tree.create_snapshot().unwrap();
+        snap.insert(&key_2, 2, 0).unwrap();
+        let snap_version = snap.version();
+        let copy = snap.clone_independent().unwrap().into_tree();
+
+        let mut promoted = snap.into_tree();
+        assert_eq!(tree.snapshot_count(), 0);
+        assert_tree_eq!(promoted, copy);
+        assert_eq!(promoted.version(), snap_version);
+        assert_eq!(promoted.get(&key_1, 0).unwrap().1, 1);
+        assert_eq!(promoted.get(&key_2, 0).unwrap().1, 2);
+
+        // The promoted tree accepts writes at newer versions.
+        promoted.insert(&key_3, 3, 0, 0).unwrap();
+        assert_eq!(promoted.version(), snap_version + 1);
+        assert_eq!(promoted.get(&key_3, 0).unwrap().1, 3);
+        assert_eq!(promoted.iter().count(), 3);
+
+        // The original tree is unaffected.
+        assert!(tree.get(&key_2, 0).is_err());
+        assert!(tree.get(&key_3, 0).is_err());
+    }
+
+    #[test]
+    fn snapshot_reader_iter_into_reuses_buffer() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        for i in 0..200 {
+            let key = VariableSizeKey::from_str(&format!("key_{}", i)).unwrap();
+            tree.insert(&key, i, 0, 0).unwrap();
+        }
+
+        let mut snap = tree.create_snapshot().unwrap();
+        let reader = snap.new_reader().unwrap();
+        let expected: Vec<(Vec<u8>, i32)> = reader.iter().map(|(k, v, _, _)| (k, *v)).collect();
+
+        let mut buf = Vec::with_capacity(64);
+        let ptr = buf.as_ptr();
+        let mut actual = Vec::new();
+        let mut iter = reader.iter_into(&mut buf);
+        while let Some((key, value)) = iter.next_entry() {
+            assert_eq!(key.as_ptr(), ptr);
+            actual.push((key.to_vec(), *value));
+        }
+        assert_eq!(actual, expected);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(buf.as_slice(), expected.last().unwrap().0.as_slice());
+    }
+
+    #[test]
+    fn snapshot_count() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        let snap = tree.create_snapshot().unwrap();
+        assert_eq!(snap.count().unwrap(), 0);
+
+        for i in 0..300 {
+            let key = VariableSizeKey::from_str(&format!("key_{}", i)).unwrap();
+            assert!(tree.insert(&key, i, 0, 0).is_ok());
+            // Older versions of a key do not add to the count.
+            assert!(tree.insert(&key, i + 1, 0, 0).is_ok());
+        }
+
+        let mut snap = tree.create_snapshot().unwrap();
+        let reader = snap.new_reader().unwrap();
+        assert_eq!(snap.count().unwrap(), 300);
+        assert_eq!(snap.count().unwrap(), count_items(&reader));
+
+        for i in 0..100 {
+            let key = VariableSizeKey::from_str(&format!("key_{}", i)).unwrap();
+            assert!(snap.remove(&key).unwrap());
+        }
+        assert_eq!(snap.count().unwrap(), 200);
+
+        assert!(snap.close_reader(reader.id).is_ok());
+        assert!(snap.close().is_ok());
+        assert!(snap.count().is_err());
+    }
+
+    #[test]
+    fn two_phase_close_drains_stragglers() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        let key = VariableSizeKey::from_str("key").unwrap();
+        tree.insert(&key, 1, 0, 0).unwrap();
+
+        let mut idle = tree.create_snapshot().unwrap();
+        let mut busy = tree.create_snapshot().unwrap();
+        let first = busy.new_reader().unwrap();
+        let second = busy.new_reader().unwrap();
+        assert!(tree
+            .snapshot_states()
+            .iter()
+            .all(|(_, state)| *state == SnapshotState::Open));
+
+        let begun = tree.begin_close_all().unwrap();
+        assert_eq!(begun.len(), 2);
+        assert!(matches!(begun[0], (id, Ok(0)) if id == idle.id()));
+        assert!(matches!(begun[1], (id, Ok(2)) if id == busy.id()));
+        assert_eq!(busy.state(), SnapshotState::Closing);
+
+        // No reader gets in once closing has begun, while the stragglers keep
+        // reading.
+        assert!(matches!(idle.new_reader(), Err(TrieError::SnapshotClosing)));
+        assert!(matches!(busy.new_reader(), Err(TrieError::SnapshotClosing)));
+        assert_eq!(count_items(&first), 1);
+        assert_eq!(busy.active_readers().unwrap(), 2);
+
+        let finished = tree.finish_close_all().unwrap();
+        assert!(matches!(finished[0], (id, Ok(())) if id == idle.id()));
+        assert!(matches!(
+            finished[1],
+            (id, Err(TrieError::SnapshotReadersNotClosed)) if id == busy.id()
+        ));
+        assert_eq!(idle.state(), SnapshotState::Closed);
+        assert_eq!(
+            tree.snapshot_states(),
+            vec![(busy.id(), SnapshotState::Closing)]
+        );
+
+        busy.close_reader(first.id).unwrap();
+        assert!(matches!(
+            busy.finish_close(),
+            Err(TrieError::SnapshotReadersNotClosed)
+        ));
+        busy.close_reader(second.id).unwrap();
+        let finished = tree.finish_close_all().unwrap();
+        assert!(matches!(finished[..], [(id, Ok(()))] if id == busy.id()));
+        assert_eq!(busy.state(), SnapshotState::Closed);
+        assert_eq!(tree.snapshot_count(), 0);
+
+        // A snapshot without readers closes in one step.
+        let mut snap = tree.create_snapshot().unwrap();
+        assert_eq!(snap.begin_close().unwrap(), 0);
+        snap.finish_close().unwrap();
+        assert!(matches!(
+            snap.begin_close(),
+            Err(TrieError::SnapshotAlreadyClosed)
+        ));
+        assert_eq!(
+            tree.snapshot_states(),
+            vec![(snap.id(), SnapshotState::Closed)]
+        );
+    }
+
+    #[test]
+    fn snapshot_group_counts() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        let groups = [("aa", 3), ("ab", 10), ("ba", 1), ("zz", 25)];
+        for (prefix, count) in groups {
+            for i in 0..count {
+                let key = VariableSizeKey::from_str(&format!("{}/{}", prefix, i)).unwrap();
+                assert!(tree.insert(&key, i, 0, 0).is_ok());
+            }
+        }
+        // Keys shorter than the prefix are grouped by the whole key.
+        let short = VariableSizeKey::from_str("a").unwrap();
+        assert!(tree.insert(&short, 0, 0, 0).is_ok());
+
+        let mut snap = tree.create_snapshot().unwrap();
+        let counts = snap.group_counts(2).unwrap();
+        let mut expected: std::collections::HashMap<Vec<u8>, usize> = groups
+            .iter()
+            .map(|(prefix, count)| (prefix.as_bytes().to_vec(), *count as usize))
+            .collect();
+        expected.insert(b"a\0".to_vec(), 1);
+        assert_eq!(counts, expected);
+        assert_eq!(tree.entry_count_by_prefix(2), expected);
+
+        // One byte merges the groups sharing it; zero bytes counts everything.
+        let counts = snap.group_counts(1).unwrap();
+        assert_eq!(counts[&b"a".to_vec()], 14);
+        assert_eq!(counts[&b"z".to_vec()], 25);
+        assert_eq!(counts.len(), 3);
+        assert_eq!(snap.group_counts(0).unwrap()[&Vec::new()], 40);
+
         assert!(snap.close().is_ok());
+        assert!(snap.group_counts(2).is_err());
     }
 
     fn count_items(reader: &IterationPointer<VariableSizeKey, i32>) -> usize {
@@ -284,4 +3033,205 @@ mod tests {
         }
         len
     }
+
+    #[test]
+    fn read_failures_match_across_entry_points() {
+        use crate::TrieError;
+        use std::collections::hash_map::RandomState;
+
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        type Read = Result<(i32, u64, u64), TrieError>;
+
+        // An empty trie reports a missing key, except that no reader can be
+        // opened on it.
+        let mut empty: Tree<VariableSizeKey, i32> = Tree::new();
+        let mut snap = empty.create_snapshot().unwrap();
+        assert_eq!(empty.get(&key("a"), 0).err(), Some(TrieError::KeyNotFound));
+        assert_eq!(snap.get(&key("a")).err(), Some(TrieError::KeyNotFound));
+        assert_eq!(snap.new_reader().err(), Some(TrieError::SnapshotEmpty));
+
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        let mut indexed = Tree::with_hash_index(RandomState::new());
+        for (i, k) in ["apple", "apricot", "banana", "b"].iter().enumerate() {
+            tree.insert(&key(k), i as i32, 0, 10 + i as u64).unwrap();
+            indexed.insert(&key(k), i as i32, 0, 10 + i as u64).unwrap();
+        }
+        let mut snap = tree.create_snapshot().unwrap();
+        let reader = snap.new_reader().unwrap();
+
+        // Each condition with the outcome every entry point must agree on.
+        let conditions: [(&str, Read); 8] = [
+            ("apple", Ok((0, 1, 10))),
+            ("b", Ok((3, 4, 13))),
+            // No child for the first byte.
+            ("cherry", Err(TrieError::KeyNotFound)),
+            // Diverges inside the prefix of an inner node.
+            ("axe", Err(TrieError::KeyNotFound)),
+            // Ends inside an inner node.
+            ("ap", Err(TrieError::KeyNotFound)),
+            // Reaches the twig of another key.
+            ("applesauce", Err(TrieError::KeyNotFound)),
+            ("appl", Err(TrieError::KeyNotFound)),
+            // Sorts after every key.
+            ("z", Err(TrieError::KeyNotFound)),
+        ];
+        for (k, expected) in conditions {
+            let k = key(k);
+            let strip = |r: Result<(VariableSizeKey, i32, u64, u64), TrieError>| {
+                r.map(|(_, value, version, ts)| (value, version, ts))
+            };
+            assert_eq!(strip(tree.get(&k, 0)), expected, "tree {:?}", k);
+            assert_eq!(strip(indexed.get(&k, 0)), expected, "index {:?}", k);
+            assert_eq!(snap.get(&k), expected, "snapshot {:?}", k);
+            assert_eq!(reader.get(&k), expected, "reader {:?}", k);
+        }
+
+        // A version before the first write of a key is a missing key too. Only
+        // Trees read at older versions.
+        assert_eq!(
+            tree.get(&key("banana"), 2).err(),
+            Some(TrieError::KeyNotFound)
+        );
+        assert_eq!(
+            indexed.get(&key("banana"), 2).err(),
+            Some(TrieError::KeyNotFound)
+        );
+
+        // Closing fails reads with the error of what was closed.
+        snap.close_reader(reader.id).unwrap();
+        snap.close().unwrap();
+        assert_eq!(
+            snap.get(&key("apple")).err(),
+            Some(TrieError::SnapshotAlreadyClosed)
+        );
+        tree.close_snapshot(snap.id()).unwrap();
+        tree.close().unwrap();
+        assert_eq!(
+            tree.get(&key("apple"), 0).err(),
+            Some(TrieError::TreeAlreadyClosed)
+        );
+    }
+
+    #[test]
+    fn rebase_reports_conflicts() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        for (i, k) in ["a", "b", "c", "d"].iter().enumerate() {
+            tree.insert(&key(k), i as i32, 0, 0).unwrap();
+        }
+        let mut snap = tree.create_snapshot().unwrap();
+
+        // Disjoint changes on both sides rebase without conflicts.
+        snap.insert(&key("a"), 10, 1).unwrap();
+        snap.insert(&key("e"), 14, 1).unwrap();
+        snap.remove(&key("d")).unwrap();
+        tree.insert(&key("b"), 21, 0, 2).unwrap();
+        tree.insert(&key("f"), 25, 0, 2).unwrap();
+        assert_eq!(snap.rebase(&tree).unwrap(), Vec::<VariableSizeKey>::new());
+
+        assert_eq!(snap.version(), tree.version() + 1);
+        let expected = [("a", 10), ("b", 21), ("c", 2), ("e", 14), ("f", 25)];
+        assert_eq!(snap.count().unwrap(), expected.len());
+        for (k, v) in expected {
+            assert_eq!(snap.get(&key(k)).unwrap().0, v, "{}", k);
+        }
+        // The Tree is untouched, and the snapshot is registered at its version.
+        assert_eq!(tree.get(&key("a"), 0).unwrap().1, 0);
+        assert!(tree.get(&key("d"), 0).is_ok());
+        assert_eq!(tree.snapshots.version_of(snap.id()), Some(tree.version()));
+
+        // Keys changed on both sides since the rebase conflict, and the
+        // snapshot's change wins. Rebasing again with no new changes is clean.
+        snap.insert(&key("b"), 31, 3).unwrap();
+        snap.remove(&key("c")).unwrap();
+        snap.insert(&key("g"), 36, 3).unwrap();
+        tree.insert(&key("b"), 41, 0, 4).unwrap();
+        tree.insert(&key("c"), 42, 0, 4).unwrap();
+        tree.insert(&key("h"), 47, 0, 4).unwrap();
+        assert_eq!(snap.rebase(&tree).unwrap(), vec![key("b"), key("c")]);
+        assert_eq!(snap.get(&key("b")).unwrap(), (31, tree.version() + 1, 3));
+        assert!(snap.get(&key("c")).is_err());
+        assert_eq!(snap.get(&key("h")).unwrap().0, 47);
+        assert_eq!(snap.get(&key("g")).unwrap().0, 36);
+        assert_eq!(snap.rebase(&tree).unwrap(), Vec::<VariableSizeKey>::new());
+        assert_eq!(snap.count().unwrap(), 6);
+
+        // A snapshot only rebases onto the Tree it was taken from.
+        let other: Tree<VariableSizeKey, i32> = Tree::new();
+        assert!(snap.rebase(&other).is_err());
+    }
+
+    #[test]
+    fn clone_independent_registers_like_a_snapshot() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        let key = VariableSizeKey::from_str("key").unwrap();
+        assert!(tree.insert(&key, 1, 0, 0).is_ok());
+        tree.set_max_active_snapshots(2);
+
+        // The copy reads at the version of the snapshot
+        let snap = tree.create_snapshot().unwrap();
+        assert!(tree.insert(&key, 2, 0, 0).is_ok());
+        let clone = snap.clone_independent().unwrap();
+        assert_eq!(
+            snap.registry.version_of(clone.id()),
+            snap.registry.version_of(snap.id())
+        );
+        assert_eq!(tree.min_pinned_version(), 1);
+
+        // The copy counts towards the limit of active snapshots
+        assert!(snap.clone_independent().is_err());
+        assert!(tree.create_snapshot().is_err());
+        assert!(tree.close_snapshot(clone.id()).is_ok());
+
+        // A closed snapshot cannot be copied
+        let mut snap = snap;
+        assert!(snap.close().is_ok());
+        assert!(matches!(
+            snap.clone_independent(),
+            Err(TrieError::SnapshotAlreadyClosed)
+        ));
+    }
+
+    #[test]
+    fn reader_ids_are_unique() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::<VariableSizeKey, i32>::new();
+        let key = VariableSizeKey::from_str("key").unwrap();
+        assert!(tree.insert(&key, 1, 0, 0).is_ok());
+        let mut snap = tree.create_snapshot().unwrap();
+
+        // A reader opened after another closed does not reuse its ID
+        let first = snap.new_reader().unwrap();
+        let second = snap.new_reader().unwrap();
+        assert!(snap.close_reader(first.id).is_ok());
+        let third = snap.new_reader().unwrap();
+        assert_ne!(third.id, second.id);
+        assert_ne!(third.id, first.id);
+
+        // Unknown or already closed IDs are rejected without touching the count
+        assert_eq!(snap.close_reader(first.id), Err(TrieError::ReaderNotFound));
+        assert_eq!(snap.close_reader(99), Err(TrieError::ReaderNotFound));
+        assert_eq!(snap.active_readers().unwrap(), 2);
+
+        assert!(snap.close_reader(second.id).is_ok());
+        assert!(snap.close_reader(third.id).is_ok());
+        assert!(snap.close().is_ok());
+    }
+
+    #[test]
+    fn snapshot_remove_keeps_a_root_twig_of_another_key() {
+        let a = VariableSizeKey::from_str("a").unwrap();
+        let b = VariableSizeKey::from_str("b").unwrap();
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        tree.insert(&a, 1, 0, 0).unwrap();
+
+        // The snapshot holds a single key, so its root is the twig of that key
+        let mut snap = tree.create_snapshot().unwrap();
+        assert!(!snap.remove(&b).unwrap());
+        assert_eq!(snap.get(&a).unwrap().0, 1);
+        assert_eq!(snap.count().unwrap(), 1);
+
+        assert!(snap.remove(&a).unwrap());
+        assert!(snap.get(&a).is_err());
+        assert!(snap.root.is_none());
+    }
 }
@@ -0,0 +1,143 @@
+//! This module defines the per-prefix statistics a Tree can maintain for the
+//! first segment of its keys.
+//!
+//! The statistics are kept up to date by every mutation of the Tree, so that
+//! quotas on a keyspace segment can be checked without scanning it.
+use std::collections::BTreeMap;
+use std::mem::size_of;
+
+/// The live keys and approximate size stored under one key segment.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PrefixStats {
+    /// Number of live keys in the segment.
+    pub keys: u64,
+    /// Approximate number of bytes stored in the segment.
+    ///
+    /// Every key counts its length once, and every stored version of it counts
+    /// the in-memory size of the value type. Heap data owned by the values is
+    /// not included.
+    pub bytes: u64,
+}
+
+/// The shape of the paths from the root of a Tree to its keys.
+///
+/// The depth of a key counts the nodes on its path, from the root down to and
+/// including its twig. Every node below the root consumes at least one byte of
+/// the key, so a key of length `n` is never deeper than `n + 1`; a trie where
+/// every inner node branches reaches the optimal depth for its keys.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DepthStats {
+    /// Number of keys.
+    pub keys: usize,
+    /// Depth of the deepest key.
+    pub max_depth: usize,
+    /// Sum of the depths of all keys.
+    pub total_depth: usize,
+    /// Number of keys whose depth is more than their length plus one.
+    pub keys_deeper_than_length: usize,
+    /// Number of inner nodes with a single child, each adding a level to the
+    /// keys below it without branching.
+    pub single_child_nodes: usize,
+}
+
+/// Per-segment statistics, keyed by the first segment of each key.
+pub(crate) struct PrefixStatsTable {
+    delimiter: u8,
+    segments: BTreeMap<Vec<u8>, PrefixStats>,
+}
+
+impl PrefixStatsTable {
+    pub(crate) fn new(delimiter: u8) -> Self {
+        PrefixStatsTable {
+            delimiter,
+            segments: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the delimiter ending the segments.
+    pub(crate) fn delimiter(&self) -> u8 {
+        self.delimiter
+    }
+
+    /// Returns the first segment of `key`.
+    ///
+    /// The segment ends before the first delimiter. Keys without a delimiter form
+    /// a segment of their own, without the trailing NULL terminator if they have
+    /// one.
+    pub(crate) fn segment<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        match key.iter().position(|&b| b == self.delimiter) {
+            Some(end) => &key[..end],
+            None => key.strip_suffix(&[0]).unwrap_or(key),
+        }
+    }
+
+    /// Returns the approximate size of `versions` versions of `key`.
+    pub(crate) fn entry_bytes<V>(key: &[u8], versions: usize) -> u64 {
+        (key.len() + versions * size_of::<V>()) as u64
+    }
+
+    /// Accounts for an insert of `key`, which either added a new key or a new
+    /// version of an existing one.
+    pub(crate) fn on_insert<V>(&mut self, key: &[u8], new_key: bool) {
+        let stats = self.segments.entry(self.segment(key).to_vec()).or_default();
+        if new_key {
+            stats.keys += 1;
+            stats.bytes += Self::entry_bytes::<V>(key, 1);
+        } else {
+            stats.bytes += size_of::<V>() as u64;
+        }
+    }
+
+    /// Accounts for the removal of `key` along with its `versions` versions.
+    pub(crate) fn on_remove<V>(&mut self, key: &[u8], versions: usize) {
+        let segment = self.segment(key);
+        let Some(stats) = self.segments.get_mut(segment) else {
+            return;
+        };
+        stats.keys = stats.keys.saturating_sub(1);
+        stats.bytes = stats
+            .bytes
+            .saturating_sub(Self::entry_bytes::<V>(key, versions));
+        if stats.keys == 0 {
+            self.segments.remove(segment);
+        }
+    }
+
+    /// Accounts for `versions` versions of `key` being pruned.
+    pub(crate) fn on_prune<V>(&mut self, key: &[u8], versions: usize) {
+        if let Some(stats) = self.segments.get_mut(self.segment(key)) {
+            stats.bytes = stats
+                .bytes
+                .saturating_sub((versions * size_of::<V>()) as u64);
+        }
+    }
+
+    pub(crate) fn get(&self, segment: &[u8]) -> Option<PrefixStats> {
+        self.segments.get(segment).copied()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&[u8], &PrefixStats)> {
+        self.segments
+            .iter()
+            .map(|(segment, stats)| (segment.as_slice(), stats))
+    }
+
+    /// Returns an empty table with the same delimiter.
+    pub(crate) fn empty(&self) -> Self {
+        Self::new(self.delimiter)
+    }
+
+    /// Returns the first segment whose statistics differ from `other`.
+    pub(crate) fn first_difference(&self, other: &Self) -> Option<Vec<u8>> {
+        let mut ours = self.segments.iter();
+        let mut theirs = other.segments.iter();
+        loop {
+            match (ours.next(), theirs.next()) {
+                (None, None) => return None,
+                (Some((a, sa)), Some((b, sb))) if a == b && sa == sb => continue,
+                (Some((a, _)), Some((b, _))) => return Some(a.min(b).clone()),
+                (Some((a, _)), None) | (None, Some((a, _))) => return Some(a.clone()),
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! This module defines the inline invariant checks of a Tree in strict mode,
+//! and the report a Tree keeps once a check has failed.
+//!
+//! A Tree built with `TreeOptions::poison_on_corruption` checks the nodes each
+//! operation walks through on its way to a key, looking only at data the walk
+//! loads anyway. The first failed check poisons the Tree: every later operation
+//! fails with `TrieError::Poisoned` instead of answering from a structure known
+//! to be wrong, while `Tree::freeze` and `Tree::export_owned_snapshot` keep
+//! working so that the data can be salvaged.
+use std::fmt;
+
+/// The invariant checks run by a Tree in strict mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvariantChecks {
+    /// Whether the two latest versions of a key are checked to be in order, and
+    /// the key to have a version at all, when it is read or written.
+    pub version_order: bool,
+    /// Whether each child is checked to have a prefix starting with the byte it
+    /// is stored under, when a walk descends into it.
+    pub child_bytes: bool,
+    /// Whether the prefixes on the path to a twig are checked to add up to the
+    /// length of its key.
+    pub prefix_lengths: bool,
+}
+
+impl InvariantChecks {
+    /// Returns every check enabled.
+    pub fn all() -> Self {
+        InvariantChecks {
+            version_order: true,
+            child_bytes: true,
+            prefix_lengths: true,
+        }
+    }
+
+    /// Returns every check disabled.
+    pub fn none() -> Self {
+        InvariantChecks {
+            version_order: false,
+            child_bytes: false,
+            prefix_lengths: false,
+        }
+    }
+}
+
+/// The class of invariant an `InvariantViolation` breaks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvariantCheck {
+    VersionOrder,
+    ChildBytes,
+    PrefixLengths,
+}
+
+/// An invariant found broken by a check.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvariantViolation {
+    /// The check that failed.
+    pub check: InvariantCheck,
+    /// The key bytes spelled by the prefixes leading to the node that breaks
+    /// the invariant.
+    pub path: Vec<u8>,
+    /// What is wrong with the node.
+    pub reason: &'static str,
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} check failed at path {:?}: {}",
+            self.check, self.path, self.reason
+        )
+    }
+}
+
+/// The details of the violation that poisoned a Tree, as returned by
+/// `Tree::poison_report`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PoisonReport {
+    /// The violation, as carried by `TrieError::Poisoned`.
+    pub violation: InvariantViolation,
+    /// The key whose operation found the violation.
+    pub key: Vec<u8>,
+    /// The operation that found the violation.
+    pub operation: &'static str,
+    /// The version of the Tree when the violation was found.
+    pub version: u64,
+}
@@ -0,0 +1,134 @@
+//! This module defines the companion index a Tree can keep over its reversed
+//! keys to answer suffix queries with a prefix scan.
+use crate::art::Tree;
+use crate::iter::ScanBuffer;
+use crate::VariableSizeKey;
+
+// Reversed keys are escaped so that no encoded key is a prefix of another: a NULL
+// byte is written as `[0, ESCAPED_NULL]` and every key ends with `[0, END]`
+// followed by a flag telling whether the original key ended with a NULL
+// terminator, which is left out of the reversed bytes so that suffixes can be
+// matched without it.
+const ESCAPED_NULL: u8 = 0xFF;
+const END: u8 = 0;
+
+/// A set of keys stored reversed in a trie of its own.
+///
+/// The index only records which keys exist. Values stay in the Tree owning it.
+pub(crate) struct SuffixIndex {
+    keys: Box<Tree<VariableSizeKey, ()>>,
+}
+
+impl SuffixIndex {
+    pub(crate) fn new() -> Self {
+        SuffixIndex {
+            keys: Box::new(Tree::new()),
+        }
+    }
+
+    // Appends the escaped reversal of `bytes` to `out`.
+    fn encode_reversed(bytes: &[u8], out: &mut Vec<u8>) {
+        for &b in bytes.iter().rev() {
+            match b {
+                0 => out.extend_from_slice(&[0, ESCAPED_NULL]),
+                b => out.push(b),
+            }
+        }
+    }
+
+    fn encode(key: &[u8]) -> VariableSizeKey {
+        let (body, terminated) = match key.strip_suffix(&[0]) {
+            Some(body) => (body, true),
+            None => (key, false),
+        };
+        let mut encoded = Vec::with_capacity(key.len() + 3);
+        Self::encode_reversed(body, &mut encoded);
+        encoded.extend_from_slice(&[0, END, terminated as u8]);
+        VariableSizeKey::from(encoded)
+    }
+
+    fn decode(encoded: &[u8]) -> Vec<u8> {
+        let (body, flag) = encoded.split_at(encoded.len() - 3);
+        let mut key = Vec::with_capacity(body.len() + 1);
+        let mut bytes = body.iter();
+        while let Some(&b) = bytes.next() {
+            if b == 0 {
+                // Skip the escape following a NULL byte.
+                bytes.next();
+            }
+            key.push(b);
+        }
+        key.reverse();
+        if flag[2] == 1 {
+            key.push(0);
+        }
+        key
+    }
+
+    /// Adds a key to the index.
+    pub(crate) fn insert(&mut self, key: &[u8]) {
+        self.keys
+            .insert(&Self::encode(key), (), 0, 0)
+            .expect("suffix index is never closed");
+    }
+
+    /// Removes a key from the index.
+    pub(crate) fn remove(&mut self, key: &[u8]) {
+        self.keys
+            .remove(&Self::encode(key))
+            .expect("suffix index is never closed");
+    }
+
+    /// Returns the keys ending with `suffix`, ordered by their reversed bytes.
+    ///
+    /// A trailing NULL terminator on the keys is not part of what they end with.
+    pub(crate) fn scan(&self, suffix: &[u8]) -> Vec<Vec<u8>> {
+        let mut prefix = Vec::with_capacity(suffix.len());
+        Self::encode_reversed(suffix, &mut prefix);
+
+        let mut buffer = ScanBuffer::new();
+        let mut scan = self.keys.scan_prefix_with(&prefix, &mut buffer);
+        let mut keys = Vec::new();
+        while let Some((encoded, _, _, _)) = scan.next_entry() {
+            keys.push(Self::decode(encoded));
+        }
+        keys
+    }
+
+    /// Returns every key in the index, in byte order.
+    pub(crate) fn keys(&self) -> Vec<Vec<u8>> {
+        let mut keys = self.scan(&[]);
+        keys.sort();
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SuffixIndex;
+    use crate::Key;
+
+    #[test]
+    fn encoded_keys_round_trip() {
+        let keys: [&[u8]; 6] = [b"abc\0", b"abc", b"a\0c\0", b"\0", b"\xff\0\xff", b""];
+        let mut index = SuffixIndex::new();
+        for key in keys {
+            assert_eq!(
+                SuffixIndex::decode(SuffixIndex::encode(key).as_slice()),
+                key
+            );
+            index.insert(key);
+        }
+
+        let mut expected: Vec<Vec<u8>> = keys.iter().map(|key| key.to_vec()).collect();
+        expected.sort();
+        assert_eq!(index.keys(), expected);
+        assert_eq!(index.scan(b"c").len(), 3);
+
+        index.remove(b"abc");
+        assert_eq!(
+            index.scan(b"c"),
+            vec![b"a\0c\0".to_vec(), b"abc\0".to_vec()]
+        );
+    }
+}
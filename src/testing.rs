@@ -0,0 +1,436 @@
+//! This module provides a conformance harness for custom `KeyTrait` implementations.
+//!
+//! The Trie only ever looks at keys through the `Key` methods, so an implementation
+//! that gets one of them subtly wrong does not fail loudly: lookups miss and
+//! iteration skips keys. `check_key_impl` runs a set of sample keys through the
+//! contract documented on `KeyTrait` and through a Tree built from them, and
+//! reports every violation it finds.
+//!
+//! The `datasets` submodule provides reproducible key sets for benchmarks and
+//! property tests. With the `testing` feature, `sharing_report` checks how much
+//! structure two Tries share, and the `assertions` submodule exports the
+//! `assert_tree_eq!`, `assert_snapshot_isolated!` and `assert_iter_matches!`
+//! macros.
+use std::error::Error;
+use std::fmt;
+
+use crate::art::Tree;
+use crate::KeyTrait;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod assertions;
+pub mod datasets;
+#[cfg(any(test, feature = "testing"))]
+pub mod sharing;
+
+#[cfg(any(test, feature = "testing"))]
+pub use sharing::{assert_min_sharing, sharing_report};
+
+/// A violation of the `KeyTrait` contract found by `check_key_impl`.
+///
+/// Keys are reported by their byte representation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyViolation {
+    /// `len` or `at` disagree with `as_slice`.
+    Representation { key: Vec<u8>, detail: String },
+    /// Converting the bytes of a key back with `From<&[u8]>` gives a different key.
+    RoundTrip { key: Vec<u8> },
+    /// `prefix_before` or `prefix_after` do not return the expected part of the key,
+    /// or the two parts do not compose back into the key.
+    Prefix { key: Vec<u8>, at: usize },
+    /// `longest_common_prefix` disagrees with the common prefix of the bytes, or is
+    /// not symmetric.
+    CommonPrefix {
+        left: Vec<u8>,
+        right: Vec<u8>,
+        expected: usize,
+        actual: usize,
+    },
+    /// `Ord` or `PartialEq` disagree with the order of the byte representations.
+    Ordering { left: Vec<u8>, right: Vec<u8> },
+    /// One key is a proper prefix of another, which the Trie cannot store.
+    NotPrefixFree { prefix: Vec<u8>, key: Vec<u8> },
+    /// A key inserted into a Tree could not be read back with its value.
+    Lookup { key: Vec<u8> },
+    /// Iterating a Tree built from the keys did not return them in byte order.
+    Iteration { expected: usize, actual: usize },
+}
+
+impl Error for KeyViolation {}
+
+impl fmt::Display for KeyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyViolation::Representation { key, detail } => {
+                write!(
+                    f,
+                    "Key {:?} has an inconsistent representation: {}",
+                    key, detail
+                )
+            }
+            KeyViolation::RoundTrip { key } => {
+                write!(f, "Key {:?} does not round-trip through its bytes", key)
+            }
+            KeyViolation::Prefix { key, at } => {
+                write!(f, "Key {:?} does not split correctly at {}", key, at)
+            }
+            KeyViolation::CommonPrefix {
+                left,
+                right,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Longest common prefix of {:?} and {:?} is {}, expected {}",
+                left, right, actual, expected
+            ),
+            KeyViolation::Ordering { left, right } => write!(
+                f,
+                "Keys {:?} and {:?} are not ordered by their bytes",
+                left, right
+            ),
+            KeyViolation::NotPrefixFree { prefix, key } => {
+                write!(f, "Key {:?} is a prefix of key {:?}", prefix, key)
+            }
+            KeyViolation::Lookup { key } => write!(f, "Key {:?} could not be read back", key),
+            KeyViolation::Iteration { expected, actual } => write!(
+                f,
+                "Iteration returned {} keys in byte order, expected {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+/// The result of running `check_key_impl` over a set of sample keys.
+#[derive(Clone, Debug, Default)]
+pub struct ConformanceReport {
+    /// Number of distinct sample keys checked.
+    pub keys_checked: usize,
+    /// The violations found, in the order they were found.
+    pub violations: Vec<KeyViolation>,
+}
+
+impl ConformanceReport {
+    /// Returns true if no violations were found.
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} keys checked, {} violations",
+            self.keys_checked,
+            self.violations.len()
+        )?;
+        for violation in &self.violations {
+            writeln!(f, "  {}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+fn check_representation<K: KeyTrait>(key: &K, violations: &mut Vec<KeyViolation>) {
+    let bytes = key.as_slice();
+    if key.len() != bytes.len() {
+        violations.push(KeyViolation::Representation {
+            key: bytes.to_vec(),
+            detail: format!(
+                "len is {} but as_slice has {} bytes",
+                key.len(),
+                bytes.len()
+            ),
+        });
+        return;
+    }
+    if let Some(pos) = (0..bytes.len()).find(|&pos| key.at(pos) != bytes[pos]) {
+        violations.push(KeyViolation::Representation {
+            key: bytes.to_vec(),
+            detail: format!("at({}) disagrees with as_slice", pos),
+        });
+    }
+
+    let actual = key.longest_common_prefix(bytes);
+    if actual != bytes.len() {
+        violations.push(KeyViolation::CommonPrefix {
+            left: bytes.to_vec(),
+            right: bytes.to_vec(),
+            expected: bytes.len(),
+            actual,
+        });
+    }
+
+    if K::from(bytes) != *key {
+        violations.push(KeyViolation::RoundTrip {
+            key: bytes.to_vec(),
+        });
+    }
+}
+
+fn check_prefixes<K: KeyTrait>(key: &K, violations: &mut Vec<KeyViolation>) {
+    let bytes = key.as_slice();
+    for at in 0..=bytes.len() {
+        let before = key.prefix_before(at);
+        let after = key.prefix_after(at);
+        let composed = [before.as_slice(), after.as_slice()].concat();
+        if before.as_slice() != &bytes[..at]
+            || after.as_slice() != &bytes[at..]
+            || composed != bytes
+        {
+            violations.push(KeyViolation::Prefix {
+                key: bytes.to_vec(),
+                at,
+            });
+            // One report per key is enough to point at the broken method.
+            return;
+        }
+    }
+}
+
+fn check_pair<K: KeyTrait>(left: &K, right: &K, violations: &mut Vec<KeyViolation>) {
+    let (a, b) = (left.as_slice(), right.as_slice());
+
+    let expected = common_prefix_len(a, b);
+    for actual in [
+        left.longest_common_prefix(b),
+        right.longest_common_prefix(a),
+    ] {
+        if actual != expected {
+            violations.push(KeyViolation::CommonPrefix {
+                left: a.to_vec(),
+                right: b.to_vec(),
+                expected,
+                actual,
+            });
+            break;
+        }
+    }
+
+    if left.cmp(right) != a.cmp(b) || (left == right) != (a == b) {
+        violations.push(KeyViolation::Ordering {
+            left: a.to_vec(),
+            right: b.to_vec(),
+        });
+    }
+
+    if a.len() < b.len() && b.starts_with(a) {
+        violations.push(KeyViolation::NotPrefixFree {
+            prefix: a.to_vec(),
+            key: b.to_vec(),
+        });
+    } else if b.len() < a.len() && a.starts_with(b) {
+        violations.push(KeyViolation::NotPrefixFree {
+            prefix: b.to_vec(),
+            key: a.to_vec(),
+        });
+    }
+}
+
+fn check_tree<K: KeyTrait>(keys: &[K], violations: &mut Vec<KeyViolation>) {
+    let mut tree: Tree<K, usize> = Tree::new();
+    for (i, key) in keys.iter().enumerate() {
+        if tree.insert(key, i, 0, 0).is_err() {
+            violations.push(KeyViolation::Lookup {
+                key: key.as_slice().to_vec(),
+            });
+        }
+    }
+
+    for (i, key) in keys.iter().enumerate() {
+        match tree.get(key, 0) {
+            Ok((_, value, _, _)) if value == i => {}
+            _ => violations.push(KeyViolation::Lookup {
+                key: key.as_slice().to_vec(),
+            }),
+        }
+    }
+
+    let mut expected: Vec<&[u8]> = keys.iter().map(|key| key.as_slice()).collect();
+    expected.sort();
+    let actual: Vec<Vec<u8>> = tree.iter().map(|(key, _, _, _)| key).collect();
+    let matching = expected
+        .iter()
+        .zip(&actual)
+        .take_while(|(e, a)| **e == a.as_slice())
+        .count();
+    if matching != expected.len() || actual.len() != expected.len() {
+        violations.push(KeyViolation::Iteration {
+            expected: expected.len(),
+            actual: matching,
+        });
+    }
+}
+
+/// Checks a `KeyTrait` implementation against the contract documented on the trait.
+///
+/// The sample keys are checked one by one (representation, round-trip through
+/// `From<&[u8]>`, `prefix_before`/`prefix_after` at every split point), then
+/// pairwise (`longest_common_prefix` against the bytes in both directions,
+/// ordering against byte order, prefix-freedom), and finally by building a Tree
+/// from them and reading every key back by lookup and by iteration. Duplicate
+/// keys are checked once. The Tree is only built when the earlier checks pass,
+/// since a broken key type can make the Trie itself panic.
+///
+/// The pairwise checks are quadratic in the number of samples, so a few hundred
+/// keys covering the interesting shapes (empty, shared prefixes, boundary bytes)
+/// are a better input than a large random set.
+///
+/// # Example
+///
+/// ```
+/// use std::str::FromStr;
+/// use vart::testing::check_key_impl;
+/// use vart::VariableSizeKey;
+///
+/// let keys: Vec<VariableSizeKey> = ["a", "ab", "b"]
+///     .iter()
+///     .map(|s| VariableSizeKey::from_str(s).unwrap())
+///     .collect();
+/// let report = check_key_impl(&keys);
+/// assert!(report.is_ok(), "{}", report);
+/// ```
+pub fn check_key_impl<K: KeyTrait>(sample_keys: &[K]) -> ConformanceReport {
+    let mut keys: Vec<K> = Vec::with_capacity(sample_keys.len());
+    for key in sample_keys {
+        if !keys.iter().any(|k| k.as_slice() == key.as_slice()) {
+            keys.push(key.clone());
+        }
+    }
+
+    let mut violations = Vec::new();
+    for key in &keys {
+        check_representation(key, &mut violations);
+        check_prefixes(key, &mut violations);
+    }
+    for (i, left) in keys.iter().enumerate() {
+        for right in &keys[i + 1..] {
+            check_pair(left, right, &mut violations);
+        }
+    }
+    if violations.is_empty() {
+        check_tree(&keys, &mut violations);
+    }
+
+    ConformanceReport {
+        keys_checked: keys.len(),
+        violations,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_key_impl, KeyViolation};
+    use crate::{FixedSizeKey, Key, VariableSizeKey};
+    use std::cmp::Ordering;
+    use std::str::FromStr;
+
+    #[test]
+    fn builtin_keys_conform() {
+        let words = ["", "a", "ab", "abc", "b", "ba", "\u{ff}", "zebra"];
+
+        let variable: Vec<VariableSizeKey> = words
+            .iter()
+            .map(|w| VariableSizeKey::from_str(w).unwrap())
+            .collect();
+        let report = check_key_impl(&variable);
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.keys_checked, words.len());
+
+        let fixed: Vec<FixedSizeKey<16>> = (0..300u16).map(FixedSizeKey::from).collect();
+        let report = check_key_impl(&fixed);
+        assert!(report.is_ok(), "{}", report);
+    }
+
+    #[test]
+    fn unterminated_keys_are_not_prefix_free() {
+        let keys = [
+            VariableSizeKey::from_slice(b"ab"),
+            VariableSizeKey::from_slice(b"abc"),
+        ];
+        let report = check_key_impl(&keys);
+        assert_eq!(
+            report.violations,
+            vec![KeyViolation::NotPrefixFree {
+                prefix: b"ab".to_vec(),
+                key: b"abc".to_vec(),
+            }]
+        );
+    }
+
+    // A key that orders by length first, and whose common prefix never covers
+    // the last byte, so that it breaks two of the contract's rules.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    struct BrokenKey(Vec<u8>);
+
+    impl PartialOrd for BrokenKey {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for BrokenKey {
+        fn cmp(&self, other: &Self) -> Ordering {
+            (self.0.len(), &self.0).cmp(&(other.0.len(), &other.0))
+        }
+    }
+
+    impl From<&[u8]> for BrokenKey {
+        fn from(src: &[u8]) -> Self {
+            Self(src.to_vec())
+        }
+    }
+
+    impl Key for BrokenKey {
+        fn at(&self, pos: usize) -> u8 {
+            self.0[pos]
+        }
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+        fn prefix_before(&self, length: usize) -> Self {
+            Self(self.0[..length].to_vec())
+        }
+        fn prefix_after(&self, start: usize) -> Self {
+            Self(self.0[start..].to_vec())
+        }
+        fn longest_common_prefix(&self, slice: &[u8]) -> usize {
+            let n = self.0.iter().zip(slice).take_while(|(a, b)| a == b).count();
+            n.min(self.0.len().saturating_sub(1))
+        }
+        fn as_slice(&self) -> &[u8] {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn broken_key_is_reported() {
+        let keys = [BrokenKey(b"zz\0".to_vec()), BrokenKey(b"aaaa\0".to_vec())];
+        let report = check_key_impl(&keys);
+        assert!(report
+            .violations
+            .iter()
+            .any(|v| matches!(v, KeyViolation::Ordering { .. })));
+
+        // Duplicates are checked once, and a key must share all of its bytes
+        // with itself.
+        let keys = [BrokenKey(b"ab\0".to_vec()), BrokenKey(b"ab\0".to_vec())];
+        let report = check_key_impl(&keys);
+        assert_eq!(report.keys_checked, 1);
+        assert_eq!(
+            report.violations,
+            vec![KeyViolation::CommonPrefix {
+                left: b"ab\0".to_vec(),
+                right: b"ab\0".to_vec(),
+                expected: 3,
+                actual: 2,
+            }]
+        );
+    }
+}
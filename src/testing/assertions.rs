@@ -0,0 +1,427 @@
+//! Assertions for test suites built on the Trie, each exported as a macro.
+//!
+//! * `assert_tree_eq!(a, b)` checks that two Tries hold the same keys with the
+//!   same histories, and reports how they differ otherwise.
+//! * `assert_snapshot_isolated!(snapshot, tree, key)` checks that the write of
+//!   a key to a Tree after a snapshot was taken is invisible through it.
+//! * `assert_iter_matches!(iterable, fixture)` checks that an iteration yields
+//!   exactly the items of a slice, and reports where it diverges otherwise.
+//!
+//! Each macro panics with a report meant to be read in a test log: keys are
+//! rendered as text when they are printable and as hex otherwise, and long
+//! lists of keys are cut short, so that a failure on a large Trie stays
+//! readable. The functions behind the macros return the reports instead of
+//! panicking.
+//!
+//! Available with the `testing` feature.
+use std::fmt::{self, Debug};
+
+use crate::art::Tree;
+use crate::diff::zip_twigs;
+use crate::node::TwigNode;
+use crate::snapshot::Snapshot;
+use crate::KeyTrait;
+
+// The number of keys of each kind listed in a report, and the number of bytes
+// of a key rendered.
+const MAX_LISTED: usize = 8;
+const MAX_KEY_BYTES: usize = 64;
+
+/// Renders the bytes of a key for a report.
+///
+/// A key whose bytes are printable ASCII, once a trailing NUL terminator is
+/// dropped, is rendered as a quoted string, and any other key in hex. Only the
+/// first 64 bytes are rendered, followed by the length of the key.
+pub fn render_key(key: &[u8]) -> String {
+    let text = key.strip_suffix(&[0]).unwrap_or(key);
+    let shown = &text[..text.len().min(MAX_KEY_BYTES)];
+    let mut out = match shown.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+        true => format!("{:?}", String::from_utf8_lossy(shown)),
+        false => shown.iter().fold(String::from("0x"), |mut out, b| {
+            out.push_str(&format!("{:02x}", b));
+            out
+        }),
+    };
+    if shown.len() < text.len() {
+        out.push_str(&format!("... ({} bytes)", key.len()));
+    }
+    out
+}
+
+/// How the histories of two Tries differ, as found by `tree_diff`.
+///
+/// Keys are listed in key order. The histories of the first keys that differ
+/// are described in `details`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeDiff {
+    /// The keys only the left Trie holds.
+    pub only_left: Vec<Vec<u8>>,
+    /// The keys only the right Trie holds.
+    pub only_right: Vec<Vec<u8>>,
+    /// The keys both Tries hold with different histories.
+    pub mismatched: Vec<Vec<u8>>,
+    /// Where the histories of the first mismatched keys diverge.
+    pub details: Vec<String>,
+}
+
+impl TreeDiff {
+    /// Returns whether the two Tries hold the same histories.
+    pub fn is_empty(&self) -> bool {
+        self.only_left.is_empty() && self.only_right.is_empty() && self.mismatched.is_empty()
+    }
+
+    /// Returns the first key, in key order, at which the two Tries differ.
+    pub fn first_difference(&self) -> Option<&[u8]> {
+        [&self.only_left, &self.only_right, &self.mismatched]
+            .into_iter()
+            .filter_map(|keys| keys.first())
+            .min()
+            .map(Vec::as_slice)
+    }
+}
+
+// Lists up to `MAX_LISTED` keys on one line, with the number of keys left out.
+fn write_keys(f: &mut fmt::Formatter, label: &str, keys: &[Vec<u8>]) -> fmt::Result {
+    if keys.is_empty() {
+        return Ok(());
+    }
+    write!(f, "  {} {}:", keys.len(), label)?;
+    for key in keys.iter().take(MAX_LISTED) {
+        write!(f, " {}", render_key(key))?;
+    }
+    if keys.len() > MAX_LISTED {
+        write!(f, " and {} more", keys.len() - MAX_LISTED)?;
+    }
+    writeln!(f)
+}
+
+impl fmt::Display for TreeDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some(first) = self.first_difference() else {
+            return writeln!(f, "the trees are equal");
+        };
+        writeln!(f, "the trees first differ at key {}", render_key(first))?;
+        write_keys(f, "keys only in the left tree", &self.only_left)?;
+        write_keys(f, "keys only in the right tree", &self.only_right)?;
+        write_keys(f, "keys with different histories", &self.mismatched)?;
+        for detail in &self.details {
+            writeln!(f, "    {}", detail)?;
+        }
+        Ok(())
+    }
+}
+
+// Describes where the histories of a key diverge, by version position.
+fn describe_mismatch<P: KeyTrait, V: Clone + Debug + PartialEq>(
+    left: &TwigNode<P, V>,
+    right: &TwigNode<P, V>,
+) -> String {
+    let key = render_key(left.key.as_slice());
+    let (lefts, rights) = (left.iter().count(), right.iter().count());
+    for (i, (l, r)) in left.iter().zip(right.iter()).enumerate() {
+        if (l.version, l.ts) != (r.version, r.ts) || l.value != r.value {
+            return format!(
+                "{}: version {} of {} is {:?} at version {}, ts {} on the left, \
+                 and {:?} at version {}, ts {} on the right",
+                key,
+                i + 1,
+                lefts.max(rights),
+                l.value,
+                l.version,
+                l.ts,
+                r.value,
+                r.version,
+                r.ts
+            );
+        }
+    }
+    format!(
+        "{}: the left history has {} versions and the right one {}",
+        key, lefts, rights
+    )
+}
+
+fn same_history<P: KeyTrait, V: Clone + PartialEq>(
+    left: &TwigNode<P, V>,
+    right: &TwigNode<P, V>,
+) -> bool {
+    left.iter().count() == right.iter().count()
+        && left
+            .iter()
+            .zip(right.iter())
+            .all(|(l, r)| (l.version, l.ts) == (r.version, r.ts) && l.value == r.value)
+}
+
+/// Compares the keys of two Tries along with every version of their histories:
+/// the value, version and timestamp of each.
+///
+/// Both Tries are walked once, in key order, with the walk of `Tree::diff_since`,
+/// so the subtrees they share are equal without being walked.
+pub fn tree_diff<P: KeyTrait, V: Clone + Debug + PartialEq>(
+    left: &Tree<P, V>,
+    right: &Tree<P, V>,
+) -> TreeDiff {
+    let mut diff = TreeDiff::default();
+    zip_twigs(
+        left.root.as_ref(),
+        right.root.as_ref(),
+        &mut |l, r| match (l, r) {
+            (None, None) => {}
+            (Some(l), None) => diff.only_left.push(l.key.as_slice().to_vec()),
+            (None, Some(r)) => diff.only_right.push(r.key.as_slice().to_vec()),
+            (Some(l), Some(r)) => {
+                if !same_history(l, r) {
+                    if diff.details.len() < MAX_LISTED {
+                        diff.details.push(describe_mismatch(l, r));
+                    }
+                    diff.mismatched.push(l.key.as_slice().to_vec());
+                }
+            }
+        },
+    );
+    diff
+}
+
+/// Returns the `tree_diff` of two Tries if they differ.
+pub fn check_tree_eq<P: KeyTrait, V: Clone + Debug + PartialEq>(
+    left: &Tree<P, V>,
+    right: &Tree<P, V>,
+) -> Result<(), TreeDiff> {
+    let diff = tree_diff(left, right);
+    match diff.is_empty() {
+        true => Ok(()),
+        false => Err(diff),
+    }
+}
+
+/// Checks that the latest write of `key` to `tree` is not visible through
+/// `snapshot`.
+///
+/// The write is the latest version of the key in the Tree if it was written
+/// after the snapshot was taken, which the snapshot must not return, or the
+/// removal of the key if the Tree no longer holds it while the snapshot does.
+///
+/// # Errors
+///
+/// Returns a description of the failure if the snapshot sees the write, or if
+/// the Tree holds no write of the key made after the snapshot was taken, in
+/// which case there is nothing to be isolated from.
+pub fn check_snapshot_isolated<P: KeyTrait, V: Clone + Debug + PartialEq>(
+    snapshot: &Snapshot<P, V>,
+    tree: &Tree<P, V>,
+    key: &P,
+) -> Result<(), String> {
+    let rendered = render_key(key.as_slice());
+    let seen = snapshot.get(key).ok();
+    match tree.get(key, 0) {
+        // Versions written to the Tree after the snapshot was taken are at
+        // least the version the snapshot writes at.
+        Ok((_, value, version, ts)) if version >= snapshot.ts => match seen {
+            Some(seen) if seen == (value.clone(), version, ts) => Err(format!(
+                "the snapshot sees the write of key {} made to the tree after it was \
+                 taken: {:?} at version {}, ts {}",
+                rendered, value, version, ts
+            )),
+            _ => Ok(()),
+        },
+        Ok((_, value, version, ts)) => Err(format!(
+            "key {} was not written to the tree after the snapshot was taken: its latest \
+             value {:?} is at version {}, ts {}",
+            rendered, value, version, ts
+        )),
+        Err(_) if seen.is_some() => Ok(()),
+        Err(_) => Err(format!(
+            "key {} is in neither the tree nor the snapshot",
+            rendered
+        )),
+    }
+}
+
+/// Checks that `actual` yields exactly the items of `expected`, in order.
+///
+/// # Errors
+///
+/// Returns a description of the first divergence: the index of the first item
+/// that differs from the fixture, or the point where one of the two ends
+/// before the other.
+pub fn check_iter_matches<I, T>(actual: I, expected: &[T]) -> Result<(), String>
+where
+    I: IntoIterator,
+    I::Item: PartialEq<T> + Debug,
+    T: Debug,
+{
+    let mut actual = actual.into_iter();
+    for (index, want) in expected.iter().enumerate() {
+        match actual.next() {
+            Some(got) if got == *want => {}
+            Some(got) => {
+                return Err(format!(
+                    "iteration diverges from the fixture at index {}: got {:?}, expected {:?}",
+                    index, got, want
+                ))
+            }
+            None => {
+                return Err(format!(
+                    "iteration ended after {} items, but the fixture has {}; the first \
+                     missing item is {:?}",
+                    index,
+                    expected.len(),
+                    want
+                ))
+            }
+        }
+    }
+    match actual.next() {
+        Some(extra) => Err(format!(
+            "iteration yielded more than the {} items of the fixture; the first extra \
+             item is {:?}",
+            expected.len(),
+            extra
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Asserts that two Tries hold the same keys with the same histories.
+///
+/// On failure, panics with the `TreeDiff` of the two: the first differing
+/// key, the keys missing from either side, and where the histories of the
+/// first mismatched keys diverge.
+#[macro_export]
+macro_rules! assert_tree_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        if let Err(diff) = $crate::testing::assertions::check_tree_eq(&$left, &$right) {
+            panic!("assertion failed: the trees are not equal\n{}", diff);
+        }
+    };
+}
+
+/// Asserts that the latest write of a key to a Tree, made after a snapshot
+/// was taken, is not visible through the snapshot.
+///
+/// See `testing::assertions::check_snapshot_isolated`.
+#[macro_export]
+macro_rules! assert_snapshot_isolated {
+    ($snapshot:expr, $tree:expr, $key:expr $(,)?) => {
+        if let Err(failure) =
+            $crate::testing::assertions::check_snapshot_isolated(&$snapshot, &$tree, &$key)
+        {
+            panic!(
+                "assertion failed: the snapshot is not isolated\n  {}",
+                failure
+            );
+        }
+    };
+}
+
+/// Asserts that an iteration yields exactly the items of a fixture, which
+/// can be anything that slices, such as an array or a `Vec`.
+///
+/// On failure, panics with the index of the first divergence.
+#[macro_export]
+macro_rules! assert_iter_matches {
+    ($iterable:expr, $fixture:expr $(,)?) => {
+        if let Err(failure) =
+            $crate::testing::assertions::check_iter_matches($iterable, &$fixture[..])
+        {
+            panic!(
+                "assertion failed: the iteration does not match\n  {}",
+                failure
+            );
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_iter_matches, check_snapshot_isolated, render_key, tree_diff};
+    use crate::art::Tree;
+    use crate::{Key, VariableSizeKey};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::str::FromStr;
+
+    fn key(s: &str) -> VariableSizeKey {
+        VariableSizeKey::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn keys_render_as_text_or_hex() {
+        assert_eq!(render_key(b"user/42\0"), "\"user/42\"");
+        assert_eq!(render_key(&[0x00, 0xff, 0x10]), "0x00ff10");
+        let long = vec![b'a'; 100];
+        assert_eq!(
+            render_key(&long),
+            format!("\"{}\"... (100 bytes)", "a".repeat(64))
+        );
+    }
+
+    #[test]
+    fn tree_diffs_report_missing_extra_and_diverging_keys() {
+        let mut left: Tree<VariableSizeKey, i32> = Tree::new();
+        for i in 0..20 {
+            left.insert(&key(&format!("k{:02}", i)), i, 0, 1).unwrap();
+        }
+        let mut right = left.create_snapshot().unwrap().into_tree();
+        assert!(tree_diff(&left, &right).is_empty());
+        crate::assert_tree_eq!(left, right);
+
+        right.insert(&key("k05"), 50, 0, 2).unwrap();
+        right.remove(&key("k07")).unwrap();
+        for i in 20..40 {
+            right.insert(&key(&format!("k{:02}", i)), i, 0, 3).unwrap();
+        }
+        let diff = tree_diff(&left, &right);
+        assert_eq!(diff.first_difference(), Some(key("k05").as_slice()));
+        assert_eq!(diff.only_left, vec![key("k07").as_slice().to_vec()]);
+        assert_eq!(diff.only_right.len(), 20);
+        assert_eq!(diff.mismatched, vec![key("k05").as_slice().to_vec()]);
+
+        let report = diff.to_string();
+        assert!(report.starts_with("the trees first differ at key \"k05\"\n"));
+        assert!(report.contains("20 keys only in the right tree: \"k20\""));
+        assert!(report.contains("and 12 more"));
+        assert!(report.contains("\"k05\": the left history has 1 versions and the right one 2"));
+
+        let result = catch_unwind(AssertUnwindSafe(|| crate::assert_tree_eq!(left, right)));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn snapshot_isolation_needs_an_invisible_write() {
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        tree.insert(&key("a"), 1, 0, 1).unwrap();
+        tree.insert(&key("b"), 1, 0, 1).unwrap();
+        let snapshot = tree.create_snapshot().unwrap();
+        assert!(check_snapshot_isolated(&snapshot, &tree, &key("a")).is_err());
+
+        tree.insert(&key("a"), 2, 0, 2).unwrap();
+        tree.insert(&key("c"), 3, 0, 2).unwrap();
+        tree.remove(&key("b")).unwrap();
+        for k in ["a", "b", "c"] {
+            crate::assert_snapshot_isolated!(snapshot, tree, key(k));
+        }
+        assert!(check_snapshot_isolated(&snapshot, &tree, &key("d")).is_err());
+
+        // A snapshot taken after the writes sees them.
+        let later = tree.create_snapshot().unwrap();
+        tree.insert(&key("d"), 4, 0, 3).unwrap();
+        let failure = check_snapshot_isolated(&later, &tree, &key("a")).unwrap_err();
+        assert!(failure.starts_with("key \"a\" was not written to the tree"));
+    }
+
+    #[test]
+    fn iteration_mismatches_name_the_first_divergence() {
+        assert!(check_iter_matches(vec![1, 2, 3], &[1, 2, 3]).is_ok());
+        assert_eq!(
+            check_iter_matches(vec![1, 5, 3], &[1, 2, 3]).unwrap_err(),
+            "iteration diverges from the fixture at index 1: got 5, expected 2"
+        );
+        assert!(check_iter_matches(vec![1], &[1, 2])
+            .unwrap_err()
+            .starts_with("iteration ended after 1 items"));
+        assert!(check_iter_matches(vec![1, 2, 3], &[1, 2])
+            .unwrap_err()
+            .ends_with("the first extra item is 3"));
+        crate::assert_iter_matches!(["a", "b"].iter().map(|s| s.to_string()), ["a", "b"]);
+    }
+}
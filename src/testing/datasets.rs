@@ -0,0 +1,193 @@
+//! Reproducible synthetic key sets for benchmarks and property tests.
+//!
+//! Every dataset is generated from a seed with `SplitMix64`, so the same seed
+//! gives the same keys on every platform and run without depending on an
+//! external random number crate. Keys are NULL terminated, which keeps every
+//! dataset prefix free, and unique within a dataset.
+use crate::VariableSizeKey;
+
+/// A small, fast, seedable random number generator.
+///
+/// This is SplitMix64, which is good enough to generate benchmark data and is
+/// stable across versions of the crate, unlike the generators of external crates.
+#[derive(Clone, Debug)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    /// Creates a generator from a seed.
+    pub fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+
+    /// Returns the next random number.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a random number in `0..bound`. `bound` must not be 0.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        // Multiply-shift maps the full range onto `0..bound` with a bias too small
+        // to matter for generated data.
+        ((self.next_u64() as u128 * bound as u128) >> 64) as u64
+    }
+
+    /// Returns a random float in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Fills `buf` with random bytes.
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+// Number of hosts the URL-like keys are spread over.
+const URL_HOSTS: u64 = 10_000;
+// Length of the prefix shared by all keys of `Dataset::SharedPrefix`.
+const SHARED_PREFIX_LEN: usize = 200;
+
+/// A family of synthetic key sets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Dataset {
+    /// Uniformly random 16-byte keys.
+    UniformRandom,
+    /// Keys counting up from 0, written as the nibbles of a big-endian `u64` so
+    /// that they sort in numeric order and contain no NULL bytes.
+    Sequential,
+    /// URL-like keys whose hosts follow a Zipf distribution, so a few hosts share
+    /// most of the keys while a long tail of hosts has few.
+    ZipfUrls,
+    /// Keys sharing a long common prefix, differing only in a short numeric tail.
+    SharedPrefix,
+}
+
+impl Dataset {
+    /// All datasets, in a fixed order.
+    pub const ALL: [Dataset; 4] = [
+        Dataset::UniformRandom,
+        Dataset::Sequential,
+        Dataset::ZipfUrls,
+        Dataset::SharedPrefix,
+    ];
+
+    /// Returns a short name for the dataset, for benchmark and report labels.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Dataset::UniformRandom => "uniform",
+            Dataset::Sequential => "sequential",
+            Dataset::ZipfUrls => "zipf_urls",
+            Dataset::SharedPrefix => "shared_prefix",
+        }
+    }
+
+    /// Generates `n` distinct keys from `seed`, in generation order.
+    pub fn keys(&self, n: usize, seed: u64) -> Vec<VariableSizeKey> {
+        self.generate(0..n as u64, seed)
+    }
+
+    /// Generates `n` distinct keys that are not among `self.keys(m, seed)` for
+    /// any `m`, to look up misses.
+    pub fn missing_keys(&self, n: usize, seed: u64) -> Vec<VariableSizeKey> {
+        match self {
+            // Random keys from another stream; a collision among 16-byte keys is
+            // as likely as guessing a 128-bit number.
+            Dataset::UniformRandom => self.generate(0..n as u64, !seed),
+            // The other datasets number their keys, so misses use numbers that are
+            // never handed out for hits.
+            _ => self.generate(u64::MAX - n as u64..u64::MAX, seed),
+        }
+    }
+
+    /// Returns the prefix shared by every key of the dataset, if any.
+    ///
+    /// This is a prefix that prefix scans over the dataset can use.
+    pub fn common_prefix(&self) -> &'static [u8] {
+        match self {
+            Dataset::UniformRandom | Dataset::Sequential => b"",
+            Dataset::ZipfUrls => b"https://",
+            Dataset::SharedPrefix => &SHARED_PREFIX[..SHARED_PREFIX_LEN],
+        }
+    }
+
+    fn generate(&self, ids: std::ops::Range<u64>, seed: u64) -> Vec<VariableSizeKey> {
+        let mut rng = SplitMix64::new(seed);
+        let mut buf = Vec::new();
+        ids.map(|id| {
+            buf.clear();
+            match self {
+                Dataset::UniformRandom => {
+                    buf.resize(16, 0);
+                    rng.fill(&mut buf);
+                    // Keys are NULL terminated, so they must not contain NULL bytes.
+                    for b in buf.iter_mut().filter(|b| **b == 0) {
+                        *b = 1;
+                    }
+                }
+                Dataset::Sequential => {
+                    buf.extend(
+                        id.to_be_bytes()
+                            .iter()
+                            .flat_map(|&b| [1 + (b >> 4), 1 + (b & 0xF)]),
+                    );
+                }
+                Dataset::ZipfUrls => {
+                    // Inverting the CDF of a Zipf distribution with exponent 1 gives
+                    // ranks that are uniform in log space.
+                    let host = (URL_HOSTS as f64).powf(rng.next_f64()) as u64;
+                    let section = rng.below(16);
+                    buf.extend_from_slice(
+                        format!("https://host{}.example.com/s{}/item/{}", host, section, id)
+                            .as_bytes(),
+                    );
+                }
+                Dataset::SharedPrefix => {
+                    buf.extend_from_slice(&SHARED_PREFIX[..SHARED_PREFIX_LEN]);
+                    buf.extend_from_slice(id.to_string().as_bytes());
+                }
+            }
+            VariableSizeKey::from_slice_with_termination(&buf)
+        })
+        .collect()
+    }
+}
+
+static SHARED_PREFIX: [u8; SHARED_PREFIX_LEN] = [b'p'; SHARED_PREFIX_LEN];
+
+#[cfg(test)]
+mod tests {
+    use super::Dataset;
+    use crate::Key;
+    use std::collections::HashSet;
+
+    #[test]
+    fn datasets_are_reproducible_and_distinct() {
+        for dataset in Dataset::ALL {
+            let keys = dataset.keys(2000, 7);
+            assert_eq!(keys, dataset.keys(2000, 7), "{}", dataset.name());
+
+            let unique: HashSet<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+            assert_eq!(unique.len(), keys.len(), "{}", dataset.name());
+            for key in &keys {
+                assert!(key.as_slice().starts_with(dataset.common_prefix()));
+                assert!(!key.as_slice()[..key.len() - 1].contains(&0));
+            }
+
+            let missing = dataset.missing_keys(2000, 7);
+            assert!(missing.iter().all(|key| !unique.contains(key.as_slice())));
+        }
+
+        // Sequential keys are generated in key order.
+        let keys = Dataset::Sequential.keys(1000, 0);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+    }
+}
@@ -0,0 +1,331 @@
+//! Structural sharing between two Tries, for tests of operations that promise
+//! to preserve it.
+//!
+//! A copy-on-write write copies the nodes along the path it changes and shares
+//! every other node with the Trie it was applied to. `sharing_report` walks two
+//! Tries and sorts the nodes of the second into those shared with the first,
+//! those rebuilt with the same contents, and those whose contents differ. Nodes
+//! rebuilt with the same contents are the regression to look for: they cost
+//! memory without the Trie holding anything new.
+//!
+//! Available with the `testing` feature.
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::art::{Node, NodeType, Tree};
+use crate::node::TwigNode;
+use crate::KeyTrait;
+
+/// Node counts of one depth, or of a whole Trie, in a `SharingReport`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SharingCounts {
+    /// Nodes that are the same allocation in both Tries.
+    pub shared: usize,
+    /// Nodes that are separate allocations, holding the same keys and versions as
+    /// the other Trie under the same path.
+    pub equal_but_copied: usize,
+    /// Nodes holding keys or versions that differ from the other Trie under the
+    /// same path.
+    pub distinct: usize,
+}
+
+impl SharingCounts {
+    /// Returns the number of nodes counted.
+    pub fn total(&self) -> usize {
+        self.shared + self.equal_but_copied + self.distinct
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Sharing {
+    Shared,
+    EqualButCopied,
+    Distinct,
+}
+
+/// How the nodes of a Trie are shared with another one, as returned by
+/// `sharing_report`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SharingReport {
+    /// Counts over the whole Trie.
+    pub total: SharingCounts,
+    /// Counts per depth, the root being at depth 0.
+    pub by_depth: Vec<SharingCounts>,
+}
+
+impl SharingReport {
+    /// Returns the fraction of the nodes that are shared, 1 for an empty Trie.
+    pub fn shared_fraction(&self) -> f64 {
+        match self.total.total() {
+            0 => 1.0,
+            total => self.total.shared as f64 / total as f64,
+        }
+    }
+
+    fn count(&mut self, depth: usize, sharing: Sharing) {
+        if self.by_depth.len() <= depth {
+            self.by_depth.resize(depth + 1, SharingCounts::default());
+        }
+        for counts in [&mut self.total, &mut self.by_depth[depth]] {
+            match sharing {
+                Sharing::Shared => counts.shared += 1,
+                Sharing::EqualButCopied => counts.equal_but_copied += 1,
+                Sharing::Distinct => counts.distinct += 1,
+            }
+        }
+    }
+}
+
+impl fmt::Display for SharingReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} nodes: {} shared, {} equal but copied, {} distinct",
+            self.total.total(),
+            self.total.shared,
+            self.total.equal_but_copied,
+            self.total.distinct
+        )?;
+        for (depth, counts) in self.by_depth.iter().enumerate() {
+            writeln!(
+                f,
+                "  depth {}: {} shared, {} equal but copied, {} distinct",
+                depth, counts.shared, counts.equal_but_copied, counts.distinct
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn collect_nodes<P: KeyTrait, V: Clone>(
+    node: &Arc<Node<P, V>>,
+    nodes: &mut HashSet<*const Node<P, V>>,
+) {
+    nodes.insert(Arc::as_ptr(node));
+    for (_, child) in node.iter() {
+        collect_nodes(child, nodes);
+    }
+}
+
+fn collect_twigs<'a, P: KeyTrait, V: Clone>(
+    node: &'a Node<P, V>,
+    twigs: &mut Vec<&'a TwigNode<P, V>>,
+) {
+    match &node.node_type {
+        NodeType::Twig(twig) => twigs.push(twig),
+        _ => {
+            for (_, child) in node.iter() {
+                collect_twigs(child, twigs);
+            }
+        }
+    }
+}
+
+/// Returns true if both subtrees hold the same keys with the same versions,
+/// whatever their shape.
+fn same_contents<P: KeyTrait, V: Clone + PartialEq>(a: &Node<P, V>, b: &Node<P, V>) -> bool {
+    let (mut a_twigs, mut b_twigs) = (Vec::new(), Vec::new());
+    collect_twigs(a, &mut a_twigs);
+    collect_twigs(b, &mut b_twigs);
+    a_twigs.len() == b_twigs.len()
+        && a_twigs.iter().zip(&b_twigs).all(|(a, b)| {
+            a.key.as_slice() == b.key.as_slice()
+                && a.iter().count() == b.iter().count()
+                && a.iter().zip(b.iter()).all(|(a, b)| {
+                    Arc::ptr_eq(a, b)
+                        || (a.version == b.version && a.ts == b.ts && a.value == b.value)
+                })
+        })
+}
+
+fn count_shared<P: KeyTrait, V: Clone>(
+    node: &Node<P, V>,
+    depth: usize,
+    report: &mut SharingReport,
+) {
+    report.count(depth, Sharing::Shared);
+    for (_, child) in node.iter() {
+        count_shared(child, depth + 1, report);
+    }
+}
+
+struct Walk<'a, P: KeyTrait, V: Clone> {
+    other_root: Option<&'a Arc<Node<P, V>>>,
+    other_nodes: HashSet<*const Node<P, V>>,
+    path: Vec<u8>,
+    report: SharingReport,
+}
+
+impl<P: KeyTrait, V: Clone + PartialEq> Walk<'_, P, V> {
+    // The contents of a node match the other Trie if the subtree holding the
+    // keys under the same path there holds the same keys, and then so do the
+    // contents of every node below it.
+    fn classify(&mut self, node: &Arc<Node<P, V>>, depth: usize, known_equal: bool) {
+        if self.other_nodes.contains(&Arc::as_ptr(node)) {
+            count_shared(node, depth, &mut self.report);
+            return;
+        }
+
+        let start = self.path.len();
+        self.path.extend_from_slice(node.prefix().as_slice());
+        let equal = known_equal
+            || self
+                .other_root
+                .and_then(|root| Node::find_prefix_subtree(root, &self.path))
+                .is_some_and(|other| same_contents(other, node));
+        let sharing = match equal {
+            true => Sharing::EqualButCopied,
+            false => Sharing::Distinct,
+        };
+        self.report.count(depth, sharing);
+        for (_, child) in node.iter() {
+            self.classify(child, depth + 1, equal);
+        }
+        self.path.truncate(start);
+    }
+}
+
+/// Reports how the nodes under root `b` are shared with the Trie under root `a`.
+pub(crate) fn report_roots<P: KeyTrait, V: Clone + PartialEq>(
+    a: Option<&Arc<Node<P, V>>>,
+    b: Option<&Arc<Node<P, V>>>,
+) -> SharingReport {
+    let mut other_nodes = HashSet::new();
+    if let Some(a) = a {
+        collect_nodes(a, &mut other_nodes);
+    }
+    let mut walk = Walk {
+        other_root: a,
+        other_nodes,
+        path: Vec::new(),
+        report: SharingReport::default(),
+    };
+    if let Some(b) = b {
+        walk.classify(b, 0, false);
+    }
+    walk.report
+}
+
+/// Reports how the nodes of `b` are shared with `a`.
+///
+/// Every node of `b`, inner nodes and twigs alike, is counted once: as shared
+/// if it is the same allocation as a node of `a`, along with its whole subtree;
+/// as equal but copied if the keys below it, with all of their versions, are
+/// the same as the keys of `a` under the same path, whatever the node types
+/// holding them; and as distinct otherwise. Values are compared with
+/// `PartialEq` only for versions that are not shared.
+///
+/// # Example
+///
+/// ```
+/// use std::str::FromStr;
+/// use vart::art::Tree;
+/// use vart::testing::sharing_report;
+/// use vart::VariableSizeKey;
+///
+/// let mut a: Tree<VariableSizeKey, u32> = Tree::new();
+/// for (i, key) in ["apple", "apricot", "banana"].iter().enumerate() {
+///     a.insert(&VariableSizeKey::from_str(key).unwrap(), i as u32, 0, 0).unwrap();
+/// }
+/// let mut b = a.create_snapshot().unwrap().into_tree();
+/// b.insert(&VariableSizeKey::from_str("banana").unwrap(), 10, 0, 0).unwrap();
+///
+/// let report = sharing_report(&a, &b);
+/// // The root and the banana twig were copied, the apples are shared.
+/// assert_eq!(report.total.distinct, 2);
+/// assert_eq!(report.total.shared, 3);
+/// ```
+pub fn sharing_report<P: KeyTrait, V: Clone + PartialEq>(
+    a: &Tree<P, V>,
+    b: &Tree<P, V>,
+) -> SharingReport {
+    report_roots(a.root.as_ref(), b.root.as_ref())
+}
+
+/// Asserts that at least `fraction` of the nodes of `b` are shared with `a`.
+///
+/// # Panics
+///
+/// Panics with the `sharing_report` of the two Tries if fewer nodes are shared.
+pub fn assert_min_sharing<P: KeyTrait, V: Clone + PartialEq>(
+    a: &Tree<P, V>,
+    b: &Tree<P, V>,
+    fraction: f64,
+) {
+    let report = sharing_report(a, b);
+    assert!(
+        report.shared_fraction() >= fraction,
+        "expected at least {} of the nodes to be shared, got {}\n{}",
+        fraction,
+        report.shared_fraction(),
+        report
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assert_min_sharing, sharing_report, SharingCounts};
+    use crate::art::{NodeKind, Tree};
+    use crate::VariableSizeKey;
+    use std::str::FromStr;
+
+    fn key(i: usize) -> VariableSizeKey {
+        VariableSizeKey::from_str(&format!("k{:03}", i)).unwrap()
+    }
+
+    fn build(mut tree: Tree<VariableSizeKey, usize>) -> Tree<VariableSizeKey, usize> {
+        for i in 0..100 {
+            tree.insert(&key(i), i, 0, 0).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn rebuilt_subtrees_are_equal_but_copied() {
+        let a = build(Tree::new());
+
+        // Forced node types give the same contents a different shape, with no
+        // node shared.
+        let b = build(Tree::with_forced_node_type(NodeKind::Node256));
+        let report = sharing_report(&a, &b);
+        assert_eq!(report.total.shared, 0);
+        assert_eq!(report.total.distinct, 0);
+        assert_eq!(report.total.equal_but_copied, report.total.total());
+        assert_eq!(report.shared_fraction(), 0.0);
+
+        // Changing a key makes its path distinct, and only its path.
+        let mut c = build(Tree::with_forced_node_type(NodeKind::Node256));
+        c.insert(&key(42), 0, 0, 0).unwrap();
+        let report = sharing_report(&a, &c);
+        let depth = report.by_depth.len();
+        assert_eq!(report.total.distinct, depth);
+        assert!(report
+            .by_depth
+            .iter()
+            .all(|counts| counts.distinct == 1 && counts.shared == 0));
+
+        // A Trie is fully shared with itself, and an empty one with anything.
+        let report = sharing_report(&a, &a);
+        assert_eq!(report.total.shared, report.total.total());
+        assert_eq!(
+            report.by_depth[0],
+            SharingCounts {
+                shared: 1,
+                ..SharingCounts::default()
+            }
+        );
+        assert_min_sharing(&a, &a, 1.0);
+        assert_eq!(sharing_report(&a, &Tree::new()).total.total(), 0);
+        // The root, ten inner nodes and the twigs.
+        assert_eq!(sharing_report(&Tree::new(), &a).total.distinct, 111);
+    }
+
+    #[test]
+    #[should_panic(expected = "equal but copied")]
+    fn assert_min_sharing_reports_copies() {
+        let a = build(Tree::new());
+        let b = build(Tree::new());
+        assert_min_sharing(&a, &b, 0.5);
+    }
+}
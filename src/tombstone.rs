@@ -0,0 +1,127 @@
+//! This module defines the tombstones a Tree keeps for the keys it removes.
+//!
+//! Removing a key drops its twig, with every version of the key, from the
+//! trie. The tombstone of the removal keeps only the key, the timestamp the
+//! key was removed at and the version of the Trie, so that
+//! `Snapshot::iter_as_of` can tell whether the newest change of a key at or
+//! before a timestamp is a write or a removal.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::node::TwigNode;
+use crate::KeyTrait;
+
+/// The removal of a key, linked to the removal made before it.
+struct Tombstone {
+    /// The removed key.
+    key: Vec<u8>,
+    /// The timestamp the key was removed at.
+    ts: u64,
+    /// The version of the Trie when the key was removed.
+    version: u64,
+    /// The removal made before this one.
+    older: Option<Arc<Tombstone>>,
+}
+
+/// The tombstones of the removed keys, newest removal first.
+///
+/// The tombstones form a list shared by the Tree and the snapshots taken from
+/// it: a removal links a new tombstone in front of the list, so neither taking
+/// a snapshot nor removing a key copies the tombstones of the earlier removals.
+#[derive(Clone)]
+pub(crate) struct Tombstones {
+    newest: Option<Arc<Tombstone>>,
+}
+
+impl Tombstones {
+    pub(crate) fn new() -> Self {
+        Tombstones { newest: None }
+    }
+
+    /// Returns the number of tombstones.
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Records the removal of `key` at timestamp `ts`, with the Trie at
+    /// `version`.
+    pub(crate) fn bury(&mut self, key: &[u8], ts: u64, version: u64) {
+        self.newest = Some(Arc::new(Tombstone {
+            key: key.to_vec(),
+            ts,
+            version,
+            older: self.newest.take(),
+        }));
+    }
+
+    /// Drops the tombstones of the removals made while the Trie was at
+    /// `version` or earlier.
+    pub(crate) fn drop_removed_by(&mut self, version: u64) {
+        if self.iter().all(|grave| grave.version > version) {
+            return;
+        }
+        let kept: Vec<_> = self
+            .iter()
+            .filter(|grave| grave.version > version)
+            .map(|grave| (grave.key.clone(), grave.ts, grave.version))
+            .collect();
+        self.clear();
+        for (key, ts, version) in kept.into_iter().rev() {
+            self.bury(&key, ts, version);
+        }
+    }
+
+    /// Drops every tombstone.
+    pub(crate) fn clear(&mut self) {
+        *self = Tombstones::new();
+    }
+
+    /// Returns, for each key removed at or before timestamp `ts`, the timestamp
+    /// and version of its latest such removal.
+    pub(crate) fn removed_by(&self, ts: u64) -> BTreeMap<&[u8], (u64, u64)> {
+        let mut removals = BTreeMap::new();
+        for grave in self.iter().filter(|grave| grave.ts <= ts) {
+            let latest = removals
+                .entry(grave.key.as_slice())
+                .or_insert((grave.ts, grave.version));
+            if (grave.ts, grave.version) > *latest {
+                *latest = (grave.ts, grave.version);
+            }
+        }
+        removals
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Tombstone> {
+        std::iter::successors(self.newest.as_deref(), |grave| grave.older.as_deref())
+    }
+}
+
+impl Drop for Tombstones {
+    // Unlinks the list one tombstone at a time, as dropping it recursively
+    // could overflow the stack after many removals.
+    fn drop(&mut self) {
+        let mut next = self.newest.take();
+        while let Some(grave) = next {
+            next = match Arc::try_unwrap(grave) {
+                Ok(mut grave) => grave.older.take(),
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+/// Returns the latest value `twig`, the twig of a key in the trie, gives it at
+/// timestamp `ts`, unless `removal`, the timestamp and version of the latest
+/// removal of the key at or before `ts`, comes after it.
+pub(crate) fn value_as_of<P: KeyTrait, V: Clone>(
+    twig: &TwigNode<P, V>,
+    removal: Option<(u64, u64)>,
+    ts: u64,
+) -> Option<&V> {
+    let leaf = twig.iter().rev().find(|leaf| leaf.ts <= ts)?;
+    match removal {
+        Some(removal) if removal >= (leaf.ts, leaf.version) => None,
+        _ => Some(&leaf.value),
+    }
+}
@@ -0,0 +1,147 @@
+//! This module defines transactions, which group reads and writes of several keys
+//! of a Tree and apply the writes together if none of the keys read has changed.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::art::{Node, Tree, KV};
+use crate::normalize::{normalize_key, KeyNormalizer};
+use crate::snapshot::SnapshotRegistry;
+use crate::{KeyTrait, TrieError};
+
+/// A group of reads and writes applied to a Tree as a whole by `commit`.
+///
+/// Reads see the Tree as of the start of the transaction, along with the
+/// transaction's own writes, which are buffered until the commit. The version of
+/// every key read is remembered, and the commit fails with
+/// `TrieError::TransactionConflict` if any of them has changed in the Tree since,
+/// so that committed transactions behave as if they ran one after another. Only
+/// the keys read are checked: keys written without being read are overwritten.
+///
+/// The transaction is registered with the Tree like a snapshot, and counts
+/// towards its snapshot limit until it is committed or dropped. Dropping it
+/// discards its writes.
+pub struct Transaction<P: KeyTrait, V: Clone> {
+    id: u64,
+    root: Option<Arc<Node<P, V>>>,
+    registry: Arc<SnapshotRegistry>,
+    normalizer: Option<Arc<dyn KeyNormalizer>>,
+    /// The latest version of each key read, or `None` if it was absent.
+    reads: BTreeMap<P, Option<u64>>,
+    /// The last value and timestamp written to each key, or `None` if it was
+    /// removed.
+    writes: BTreeMap<P, Option<(V, u64)>>,
+}
+
+impl<P: KeyTrait, V: Clone> Transaction<P, V> {
+    pub(crate) fn new(tree: &Tree<P, V>) -> Self {
+        Transaction {
+            id: tree.snapshots.register(tree.version(), tree.current_ts),
+            root: tree.root.clone(),
+            registry: tree.snapshots.clone(),
+            normalizer: tree.normalizer.clone(),
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Retrieves the latest value of the given key, as written by the
+    /// transaction or, if it has not written the key, as of the start of the
+    /// transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::KeyNotFound` if the key is absent or was removed by the
+    /// transaction.
+    pub fn get(&mut self, key: &P) -> Result<V, TrieError> {
+        let key = normalize_key(self.normalizer.as_ref(), key).into_owned();
+        if let Some(write) = self.writes.get(&key) {
+            return write
+                .as_ref()
+                .map(|(value, _)| value.clone())
+                .ok_or(TrieError::KeyNotFound);
+        }
+
+        let found = Node::resolve_get(self.root.as_ref(), &key, 0);
+        self.reads
+            .entry(key)
+            .or_insert_with(|| found.as_ref().ok().map(|(_, _, version, _)| *version));
+        found.map(|(_, value, _, _)| value)
+    }
+
+    /// Buffers the insertion of a value for the given key at timestamp `ts`.
+    ///
+    /// A later write to the same key in the transaction replaces this one.
+    pub fn insert(&mut self, key: &P, value: V, ts: u64) {
+        let key = normalize_key(self.normalizer.as_ref(), key).into_owned();
+        self.writes.insert(key, Some((value, ts)));
+    }
+
+    /// Buffers the removal of the given key.
+    ///
+    /// A later write to the same key in the transaction replaces this one.
+    pub fn remove(&mut self, key: &P) {
+        let key = normalize_key(self.normalizer.as_ref(), key).into_owned();
+        self.writes.insert(key, None);
+    }
+
+    /// Applies the writes of the transaction to `tree`, if none of the keys read
+    /// by the transaction has changed since it started.
+    ///
+    /// The inserted values get the next versions of the Tree, like with
+    /// `Tree::bulk_insert`. The inserts are built aside from the Tree and
+    /// swapped in together once all of them succeed, and the removals only fail
+    /// on the checks made before any write or once strict mode poisons the
+    /// Tree, so on error the Tree is left unchanged.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::TransactionConflict` with the first key read whose
+    /// latest version differs, `TrieError::TreeAlreadyClosed` if `tree` is
+    /// closed, `TrieError::SnapshotNotFound` if the transaction was not started
+    /// on `tree`, and `TrieError::PrefixLocked` if a key written is under a
+    /// locked prefix. Otherwise fails like `Tree::bulk_insert`.
+    pub fn commit(self, tree: &mut Tree<P, V>) -> Result<(), TrieError> {
+        if tree.closed {
+            return Err(TrieError::TreeAlreadyClosed);
+        }
+        if !Arc::ptr_eq(&self.registry, &tree.snapshots) {
+            return Err(TrieError::SnapshotNotFound);
+        }
+
+        for (key, read) in &self.reads {
+            let current = Node::resolve_get(tree.root.as_ref(), key, 0)
+                .ok()
+                .map(|(_, _, version, _)| version);
+            if current != *read {
+                return Err(TrieError::TransactionConflict {
+                    key: key.as_slice().to_vec(),
+                });
+            }
+        }
+        for key in self.writes.keys() {
+            tree.prefix_locks.check(key.as_slice(), None)?;
+        }
+
+        let mut inserts = Vec::new();
+        let mut removes = Vec::new();
+        for (key, write) in &self.writes {
+            match write {
+                Some((value, ts)) => inserts.push(KV::new(key.clone(), value.clone(), 0, *ts)),
+                None => removes.push(key),
+            }
+        }
+        if !inserts.is_empty() {
+            tree.bulk_insert_with(&inserts, true)?;
+        }
+        for key in removes {
+            tree.remove(key)?;
+        }
+        Ok(())
+    }
+}
+
+impl<P: KeyTrait, V: Clone> Drop for Transaction<P, V> {
+    fn drop(&mut self) {
+        self.registry.deregister(self.id);
+    }
+}
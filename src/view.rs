@@ -0,0 +1,197 @@
+//! This module defines a read view over a Tree that is refreshed in place, so that
+//! a reader serving slightly stale data can catch up with the Tree without
+//! managing the lifecycle of snapshots itself.
+use std::sync::Arc;
+
+use crate::art::{Node, Tree};
+use crate::iter::Iter;
+use crate::normalize::{normalize_key, KeyNormalizer};
+use crate::snapshot::{SnapshotRegistry, StalenessSummary};
+use crate::{KeyTrait, TrieError};
+
+/// How far a `RefreshingView::refresh` moved the view.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RefreshSummary {
+    /// The version the view read at before the refresh.
+    pub from_version: u64,
+    /// The version the view reads at after the refresh.
+    pub to_version: u64,
+    /// The keys written between the two versions, as counted by
+    /// `Tree::staleness`. Removed keys are not counted.
+    pub changed: StalenessSummary,
+}
+
+/// A read view of a Tree at a version, which can be moved to the Tree's latest
+/// version.
+///
+/// The view is registered with the Tree like a snapshot, and counts towards its
+/// snapshot limit until dropped. Reads go through guards returned by `read`,
+/// each pinning the root the view held when it was created: a guard keeps
+/// reading the same data across refreshes, and the nodes of a root are freed
+/// once the view and every guard on it have moved on.
+pub struct RefreshingView<P: KeyTrait, V: Clone> {
+    id: u64,
+    root: Option<Arc<Node<P, V>>>,
+    version: u64,
+    registry: Arc<SnapshotRegistry>,
+    normalizer: Option<Arc<dyn KeyNormalizer>>,
+}
+
+impl<P: KeyTrait, V: Clone> RefreshingView<P, V> {
+    pub(crate) fn new(tree: &Tree<P, V>) -> Self {
+        let version = tree.version();
+        RefreshingView {
+            id: tree.snapshots.register(version, tree.current_ts),
+            root: tree.root.clone(),
+            version,
+            registry: tree.snapshots.clone(),
+            normalizer: tree.normalizer.clone(),
+        }
+    }
+
+    /// Returns the version of the Tree the view reads at.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns a guard reading the view at its current version.
+    ///
+    /// Creating a guard only clones the reference to the root.
+    pub fn read(&self) -> ViewGuard<P, V> {
+        ViewGuard {
+            root: self.root.clone(),
+            version: self.version,
+            normalizer: self.normalizer.clone(),
+        }
+    }
+
+    /// Moves the view to the latest version of `tree`.
+    ///
+    /// Guards created before the refresh keep reading the old version.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TrieError::TreeAlreadyClosed` if `tree` is closed, and
+    /// `TrieError::SnapshotNotFound` if the view was not created by `tree`.
+    pub fn refresh(&mut self, tree: &mut Tree<P, V>) -> Result<RefreshSummary, TrieError> {
+        if tree.closed {
+            return Err(TrieError::TreeAlreadyClosed);
+        }
+        if !Arc::ptr_eq(&self.registry, &tree.snapshots) {
+            return Err(TrieError::SnapshotNotFound);
+        }
+
+        let changed = tree.staleness(self.id)?;
+        let summary = RefreshSummary {
+            from_version: self.version,
+            to_version: tree.version(),
+            changed,
+        };
+        self.registry
+            .update(self.id, summary.to_version, tree.current_ts);
+        tree.close_cow_window();
+        self.root = tree.root.clone();
+        self.version = summary.to_version;
+        Ok(summary)
+    }
+}
+
+impl<P: KeyTrait, V: Clone> Drop for RefreshingView<P, V> {
+    fn drop(&mut self) {
+        self.registry.deregister(self.id);
+    }
+}
+
+/// A read of a `RefreshingView` at the version it had when the guard was
+/// created.
+pub struct ViewGuard<P: KeyTrait, V: Clone> {
+    root: Option<Arc<Node<P, V>>>,
+    version: u64,
+    normalizer: Option<Arc<dyn KeyNormalizer>>,
+}
+
+impl<P: KeyTrait, V: Clone> ViewGuard<P, V> {
+    /// Returns the version the guard reads at.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Retrieves the latest value of the given key, with the version and timestamp
+    /// it was written at.
+    ///
+    /// Fails with `TrieError::KeyNotFound` under the same conditions as
+    /// `Tree::get`.
+    pub fn get(&self, key: &P) -> Result<(V, u64, u64), TrieError> {
+        let key = normalize_key(self.normalizer.as_ref(), key);
+        Node::resolve_get(self.root.as_ref(), key.as_ref(), 0)
+            .map(|(_, value, version, ts)| (value, version, ts))
+    }
+
+    /// Returns an iterator over the key-value pairs of the guard's version.
+    pub fn iter(&self) -> Iter<'_, P, V> {
+        Iter::new(self.root.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::art::Tree;
+    use crate::VariableSizeKey;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    #[test]
+    fn guards_keep_their_version_across_refreshes() {
+        let key = |s: &str| VariableSizeKey::from_str(s).unwrap();
+        let mut tree: Tree<VariableSizeKey, i32> = Tree::new();
+        tree.insert(&key("a"), 1, 0, 100).unwrap();
+        tree.insert(&key("b"), 2, 0, 110).unwrap();
+
+        let mut view = tree.refreshing_view().unwrap();
+        assert_eq!(tree.snapshot_count(), 1);
+        let old = view.read();
+        let old_root = Arc::downgrade(old.root.as_ref().unwrap());
+
+        tree.insert(&key("a"), 10, 0, 120).unwrap();
+        tree.insert(&key("c"), 3, 0, 130).unwrap();
+        tree.remove(&key("b")).unwrap();
+
+        // Reads interleaved with writes stay at the view's version.
+        let before = view.read();
+        assert_eq!(before.get(&key("a")).unwrap(), (1, 1, 100));
+        assert!(before.get(&key("c")).is_err());
+
+        let summary = view.refresh(&mut tree).unwrap();
+        assert_eq!((summary.from_version, summary.to_version), (2, 4));
+        assert_eq!(summary.changed.stale_keys, 2);
+        assert_eq!(summary.changed.max_lag_ts, 130);
+        assert_eq!(view.version(), 4);
+
+        // Old guards keep the old data, new reads see the new data.
+        let new = view.read();
+        for guard in [&old, &before] {
+            assert_eq!(guard.version(), 2);
+            assert_eq!(guard.get(&key("b")).unwrap().0, 2);
+            assert_eq!(guard.iter().count(), 2);
+        }
+        assert_eq!(new.get(&key("a")).unwrap(), (10, 3, 120));
+        assert!(new.get(&key("b")).is_err());
+        assert_eq!(new.iter().count(), 2);
+
+        // The old root goes once its last guard does.
+        drop(old);
+        assert!(old_root.upgrade().is_some());
+        drop(before);
+        assert!(old_root.upgrade().is_none());
+
+        // Refreshing without writes advances nothing.
+        let summary = view.refresh(&mut tree).unwrap();
+        assert_eq!((summary.from_version, summary.to_version), (4, 4));
+        assert_eq!(summary.changed.stale_keys, 0);
+
+        let mut other: Tree<VariableSizeKey, i32> = Tree::new();
+        assert!(view.refresh(&mut other).is_err());
+        drop(view);
+        assert_eq!(tree.snapshot_count(), 0);
+    }
+}
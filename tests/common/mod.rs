@@ -0,0 +1,50 @@
+//! A counting global allocator shared by the allocation tests.
+//!
+//! A test binary installs it by declaring `mod common;`, which is why each
+//! allocation test lives in its own binary. The counts are kept per thread, so
+//! tests running in parallel do not see each other's allocations.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    // Bytes allocated minus bytes freed.
+    static LIVE_BYTES: Cell<isize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        LIVE_BYTES.with(|bytes| bytes.set(bytes.get() + layout.size() as isize));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE_BYTES.with(|bytes| bytes.set(bytes.get() - layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        LIVE_BYTES
+            .with(|bytes| bytes.set(bytes.get() + new_size as isize - layout.size() as isize));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Returns the number of allocations and reallocations made by this thread.
+pub fn allocations() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+/// Returns the bytes allocated by this thread that are still live, counting
+/// frees of memory it did not allocate against it.
+#[allow(dead_code)]
+pub fn live_bytes() -> isize {
+    LIVE_BYTES.with(|bytes| bytes.get())
+}
@@ -0,0 +1,99 @@
+//! Checks `Tree::estimate_insert_cost` against the heap inserts actually add,
+//! and that estimating does not allocate.
+//!
+//! This lives in its own test binary because it installs the counting global
+//! allocator of `common`.
+mod common;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use vart::art::Tree;
+use vart::VariableSizeKey;
+
+use common::{allocations, live_bytes};
+
+fn random_key(rng: &mut StdRng) -> VariableSizeKey {
+    let len = rng.gen_range(1..10);
+    let bytes: Vec<u8> = (0..len).map(|_| b"abcd"[rng.gen_range(0..4)]).collect();
+    VariableSizeKey::key(&bytes)
+}
+
+#[test]
+fn estimates_track_the_heap_inserts_add() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut tree: Tree<VariableSizeKey, Vec<u8>> = Tree::new();
+    let mut snapshots = Vec::new();
+    let (mut estimated, mut actual) = (0usize, 0isize);
+    for i in 0..5000 {
+        if i % 500 == 250 {
+            // Snapshots keep the nodes later inserts copy alive.
+            snapshots.push(tree.create_snapshot().unwrap());
+        }
+        let key = random_key(&mut rng);
+        let value = vec![0u8; rng.gen_range(0..64)];
+
+        let estimate = tree.estimate_insert_cost(&key, value.len());
+        estimated += estimate.heap_bytes();
+        // The value's heap is allocated by the caller and moved in.
+        let before = live_bytes() - value.len() as isize;
+        tree.insert(&key, value, 0, 0).unwrap();
+        actual += live_bytes() - before;
+    }
+
+    assert!(actual > 0);
+    let ratio = estimated as f64 / actual as f64;
+    assert!((0.5..=2.0).contains(&ratio), "estimate off by {}", ratio);
+}
+
+#[test]
+fn batch_estimates_price_shared_paths_once() {
+    let mut rng = StdRng::seed_from_u64(11);
+    let mut tree: Tree<VariableSizeKey, Vec<u8>> = Tree::new();
+    for _ in 0..1000 {
+        tree.insert(&random_key(&mut rng), vec![0; 16], 0, 0)
+            .unwrap();
+    }
+    let _snapshot = tree.create_snapshot().unwrap();
+
+    let keys: Vec<_> = (0..100).map(|_| random_key(&mut rng)).collect();
+    let single: usize = keys
+        .iter()
+        .map(|key| tree.estimate_insert_cost(key, 16).retained_bytes)
+        .sum();
+    let batch = tree.estimate_batch_cost(keys.iter().map(|key| (key, 16)));
+    // Every key copies the shared root, but the batch retains it once.
+    assert!(batch.retained_bytes < single);
+    assert_eq!(
+        batch.leaf_bytes,
+        keys.iter()
+            .map(|key| tree.estimate_insert_cost(key, 16).leaf_bytes)
+            .sum::<usize>()
+    );
+
+    // The retained copies are what the batch adds beyond its leaves and new
+    // nodes while the snapshot holds the old paths.
+    let before = live_bytes();
+    for key in &keys {
+        tree.insert(key, vec![0; 16], 0, 0).unwrap();
+    }
+    let added = (live_bytes() - before) as f64;
+    let ratio = batch.heap_bytes() as f64 / added;
+    assert!((0.5..=2.0).contains(&ratio), "estimate off by {}", ratio);
+}
+
+#[test]
+fn estimating_does_not_allocate() {
+    let mut rng = StdRng::seed_from_u64(3);
+    let mut tree: Tree<VariableSizeKey, Vec<u8>> = Tree::new();
+    let keys: Vec<_> = (0..500).map(|_| random_key(&mut rng)).collect();
+    for key in &keys[..250] {
+        tree.insert(key, vec![0; 8], 0, 0).unwrap();
+    }
+    let _snapshot = tree.create_snapshot().unwrap();
+
+    let before = allocations();
+    for key in &keys {
+        tree.estimate_insert_cost(key, 8);
+    }
+    assert_eq!(allocations() - before, 0);
+}
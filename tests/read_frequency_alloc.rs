@@ -0,0 +1,38 @@
+//! Checks that counting reads for `Tree::hot_prefixes` does not allocate.
+//!
+//! This lives in its own test binary because it installs the counting global
+//! allocator of `common`.
+mod common;
+
+use vart::art::Tree;
+use vart::pressure::TreeOptions;
+use vart::FixedSizeKey;
+
+use common::allocations;
+
+#[test]
+fn counting_reads_does_not_allocate() {
+    let keys: Vec<FixedSizeKey<16>> = (0..1000u64)
+        .map(|i| FixedSizeKey::from(format!("tenant{}/{}", i % 10, i).as_str()))
+        .collect();
+    let build = |options: TreeOptions| {
+        let mut tree: Tree<FixedSizeKey<16>, u64> = Tree::with_options(options);
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(key, i as u64, 0, 0).unwrap();
+        }
+        tree
+    };
+    let plain = build(TreeOptions::default());
+    let tracked = build(TreeOptions::default().track_read_frequency(7));
+
+    let read_all = |tree: &Tree<FixedSizeKey<16>, u64>| {
+        let before = allocations();
+        for key in &keys {
+            tree.get(key, 0).unwrap();
+        }
+        allocations() - before
+    };
+    assert_eq!(read_all(&plain), 0);
+    assert_eq!(read_all(&tracked), 0);
+    assert_eq!(tracked.hot_prefixes(1)[0].1, 100);
+}
@@ -0,0 +1,78 @@
+//! Checks that prefix scans with a reused `ScanBuffer`, and counting the keys
+//! of a Tree, do not allocate per key.
+//!
+//! This lives in its own test binary because it installs the counting global
+//! allocator of `common`.
+mod common;
+
+use std::str::FromStr;
+
+use vart::art::Tree;
+use vart::iter::ScanBuffer;
+use vart::VariableSizeKey;
+
+use common::allocations;
+
+#[test]
+fn reused_scan_buffer_does_not_allocate() {
+    let mut tree: Tree<VariableSizeKey, u64> = Tree::new();
+    for user in 0..500u64 {
+        for item in 0..10u64 {
+            let key = VariableSizeKey::from_str(&format!("user/{}/item/{}", user, item)).unwrap();
+            tree.insert(&key, user * 10 + item, 0, 0).unwrap();
+        }
+    }
+    let prefixes: Vec<Vec<u8>> = (0..500)
+        .map(|user| format!("user/{}/", user).into_bytes())
+        .collect();
+
+    let mut buffer = ScanBuffer::new();
+    let scan_all = |buffer: &mut ScanBuffer<VariableSizeKey, u64>| {
+        let mut sum = 0;
+        for prefix in &prefixes {
+            let mut scan = tree.scan_prefix_with(prefix, buffer);
+            while let Some((_, value, _, _)) = scan.next_entry() {
+                sum += *value;
+            }
+        }
+        sum
+    };
+
+    // The first round grows the buffer to the deepest traversal.
+    let expected: u64 = (0..5000).sum();
+    assert_eq!(scan_all(&mut buffer), expected);
+    let capacity = buffer.capacity();
+
+    let before = allocations();
+    for _ in 0..10 {
+        assert_eq!(scan_all(&mut buffer), expected);
+    }
+    assert_eq!(allocations() - before, 0);
+    assert_eq!(buffer.capacity(), capacity);
+}
+
+#[test]
+fn counting_keys_does_not_allocate_per_key() {
+    let mut small: Tree<VariableSizeKey, u64> = Tree::new();
+    let mut large: Tree<VariableSizeKey, u64> = Tree::new();
+    for i in 0..50_000u64 {
+        let key = VariableSizeKey::from_str(&format!("key/{}", i)).unwrap();
+        if i < 500 {
+            small.insert(&key, i, 0, 0).unwrap();
+        }
+        large.insert(&key, i, 0, 0).unwrap();
+    }
+    assert_eq!(small.iter_all_count(), small.iter().count());
+    assert_eq!(large.iter_all_count(), large.iter().count());
+    assert_eq!(large.iter_all_count(), 50_000);
+    assert_eq!(Tree::<VariableSizeKey, u64>::new().iter_all_count(), 0);
+
+    // Only the stack of pending nodes allocates, as it grows.
+    let count = |tree: &Tree<VariableSizeKey, u64>| {
+        let before = allocations();
+        tree.iter_all_count();
+        allocations() - before
+    };
+    assert!(count(&large) < 20);
+    assert!(count(&large) <= count(&small) + 4);
+}
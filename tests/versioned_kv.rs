@@ -0,0 +1,166 @@
+//! End-to-end flows of the versioned KV store example, so that the example
+//! keeps building and behaving as documented.
+#[path = "../examples/versioned_kv/store.rs"]
+mod store;
+
+use store::{Entry, KvError, KvStore, Page};
+use vart::TrieError;
+
+fn entries(page: &Page) -> Vec<(&str, &[u8])> {
+    page.entries
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_slice()))
+        .collect()
+}
+
+#[test]
+fn writes_and_history() {
+    let mut store = KvStore::new();
+    assert_eq!(store.get("a").unwrap(), None);
+    assert_eq!(store.put("a", b"1").unwrap(), 1);
+    assert_eq!(store.put("b", b"2").unwrap(), 2);
+    assert_eq!(store.put("a", b"3").unwrap(), 3);
+
+    assert_eq!(
+        store.get("a").unwrap(),
+        Some(Entry {
+            value: b"3".to_vec(),
+            version: 3,
+            ts: 3
+        })
+    );
+    let history: Vec<(Vec<u8>, u64)> = store
+        .history("a")
+        .unwrap()
+        .into_iter()
+        .map(|entry| (entry.value, entry.ts))
+        .collect();
+    assert_eq!(history, vec![(b"1".to_vec(), 1), (b"3".to_vec(), 3)]);
+
+    // Conditional writes only land against the latest timestamp.
+    assert_eq!(
+        store.put_if("a", b"4", 1),
+        Err(KvError::Trie(TrieError::TimestampConflict {
+            expected: 1,
+            current: Some(3)
+        }))
+    );
+    assert_eq!(store.put_if("a", b"4", 3).unwrap(), 4);
+    assert_eq!(store.put_if("c", b"5", 0).unwrap(), 5);
+
+    assert!(store.delete("b").unwrap());
+    assert!(!store.delete("b").unwrap());
+    assert_eq!(store.get("b").unwrap(), None);
+    assert!(store.history("b").unwrap().is_empty());
+}
+
+#[test]
+fn transactions_detect_conflicts() {
+    let mut store = KvStore::new();
+    store.put("x", b"0").unwrap();
+    store.put("y", b"0").unwrap();
+
+    // A transaction reads its own writes, and is invisible until it commits.
+    let t1 = store.begin().unwrap();
+    store.txn_put(t1, "x", b"1").unwrap();
+    store.txn_delete(t1, "y").unwrap();
+    assert_eq!(store.txn_get(t1, "x").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(store.txn_get(t1, "y").unwrap(), None);
+    assert_eq!(store.get("x").unwrap().unwrap().value, b"0");
+
+    // A concurrent transaction writing one of the same keys loses.
+    let t2 = store.begin().unwrap();
+    store.txn_put(t2, "x", b"2").unwrap();
+    store.txn_put(t2, "z", b"2").unwrap();
+
+    let ts = store.commit(t1).unwrap();
+    assert_eq!(store.get("x").unwrap().unwrap().ts, ts);
+    assert_eq!(store.get("y").unwrap(), None);
+    assert_eq!(
+        store.commit(t2),
+        Err(KvError::Conflict(vec!["x".to_string()]))
+    );
+    assert_eq!(store.get("x").unwrap().unwrap().value, b"1");
+    assert_eq!(store.get("z").unwrap(), None);
+    assert_eq!(store.commit(t2), Err(KvError::NoSuchTxn(t2)));
+
+    // Writes to other keys do not conflict.
+    let t3 = store.begin().unwrap();
+    store.txn_put(t3, "z", b"3").unwrap();
+    store.put("x", b"4").unwrap();
+    store.commit(t3).unwrap();
+    assert_eq!(store.get("z").unwrap().unwrap().value, b"3");
+
+    let t4 = store.begin().unwrap();
+    store.txn_put(t4, "x", b"5").unwrap();
+    store.abort(t4).unwrap();
+    assert_eq!(store.get("x").unwrap().unwrap().value, b"4");
+
+    // Finished transactions release their snapshots.
+    let status = store.status();
+    assert_eq!((status.snapshots, status.open_txns), (0, 0));
+}
+
+#[test]
+fn paginated_scans_read_a_stable_view() {
+    let mut store = KvStore::new();
+    let empty = store.scan("", 10).unwrap();
+    assert!(empty.entries.is_empty() && empty.cursor.is_none());
+
+    for key in ["a", "b", "c", "d", "e"] {
+        store.put(key, key.as_bytes()).unwrap();
+    }
+
+    let page = store.scan("b", 2).unwrap();
+    assert_eq!(entries(&page), vec![("b", &b"b"[..]), ("c", &b"c"[..])]);
+    let cursor = page.cursor.unwrap();
+
+    // Writes made while the scan is open are not seen by it.
+    store.put("cc", b"new").unwrap();
+    store.delete("e").unwrap();
+
+    let page = store.next_page(cursor, 2).unwrap();
+    assert_eq!(entries(&page), vec![("d", &b"d"[..]), ("e", &b"e"[..])]);
+    assert_eq!(page.cursor, None);
+    assert_eq!(
+        store.next_page(cursor, 2),
+        Err(KvError::NoSuchCursor(cursor))
+    );
+
+    // An abandoned scan releases its reader and snapshot.
+    let page = store.scan("", 1).unwrap();
+    assert_eq!(entries(&page), vec![("a", &b"a"[..])]);
+    assert_eq!(store.status().open_cursors, 1);
+    store.close_cursor(page.cursor.unwrap()).unwrap();
+    let status = store.status();
+    assert_eq!((status.snapshots, status.open_cursors), (0, 0));
+}
+
+#[test]
+fn save_and_load() {
+    let mut store = KvStore::new();
+    for (i, key) in ["k1", "k2", "k3"].iter().enumerate() {
+        store.put(key, format!("v{}", i).as_bytes()).unwrap();
+    }
+    store.put("k1", b"latest").unwrap();
+    store.delete("k2").unwrap();
+
+    let mut loaded = KvStore::load(&store.save()).unwrap();
+    let status = loaded.status();
+    assert_eq!(status.keys, 2);
+    assert_eq!(status.version, store.status().version);
+    assert_eq!(status.clock, 4);
+    assert!(status.max_depth >= 2);
+    assert_eq!(loaded.get("k1").unwrap(), store.get("k1").unwrap());
+    assert_eq!(loaded.get("k2").unwrap(), None);
+    // Only the latest versions are saved.
+    assert_eq!(loaded.history("k1").unwrap().len(), 1);
+
+    // The clock carries on from the saved timestamps.
+    assert_eq!(loaded.put("k4", b"v").unwrap(), 5);
+
+    assert!(matches!(
+        KvStore::load(b"not a store"),
+        Err(KvError::Load(_))
+    ));
+}